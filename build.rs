@@ -0,0 +1,29 @@
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    #[cfg(feature = "ffi")]
+    header();
+    // Lets a Node addon leave its napi symbols for the host to provide.
+    #[cfg(feature = "node")]
+    napi_build::setup();
+}
+
+// Keeps `include/bittorrent_rs.h` in step with the C API in `src/ffi.rs`.
+#[cfg(feature = "ffi")]
+fn header() {
+    println!("cargo:rerun-if-changed=src/ffi.rs");
+    let dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
+    let config = cbindgen::Config {
+        language: cbindgen::Language::C,
+        include_guard: Some("BITTORRENT_RS_H".to_string()),
+        autogen_warning: Some("/* Generated from src/ffi.rs by build.rs; don't edit. */".to_string()),
+        cpp_compat: true,
+        usize_is_size_t: true,
+        ..Default::default()
+    };
+    cbindgen::Builder::new()
+        .with_config(config)
+        .with_src(format!("{}/src/ffi.rs", dir))
+        .generate()
+        .expect("cannot generate the C header")
+        .write_to_file(format!("{}/include/bittorrent_rs.h", dir));
+}
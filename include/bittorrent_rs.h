@@ -0,0 +1,170 @@
+#ifndef BITTORRENT_RS_H
+#define BITTORRENT_RS_H
+
+/* Generated from src/ffi.rs by build.rs; don't edit. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/**
+ * A running session and the runtime it runs on.
+ */
+typedef struct BtSession BtSession;
+
+/**
+ * A parsed .torrent file.
+ */
+typedef struct BtTorrent BtTorrent;
+
+/**
+ * A torrent's progress, as `bt_session_stats` fills it in.
+ */
+typedef struct BtStats {
+  uint64_t bytes_done;
+  uint64_t bytes_total;
+  uint64_t uploaded;
+  uint64_t downloaded;
+  /**
+   * Bytes per second.
+   */
+  uint64_t upload_rate;
+  uint64_t download_rate;
+  uint32_t connected_peers;
+  bool paused;
+  bool queued;
+} BtStats;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ * Frees a string this library returned.
+ *
+ * # Safety
+ * `text` must be null or a string from this library, not freed before.
+ */
+void bt_string_free(char *text);
+
+/**
+ * Decodes `len` bytes of bencode into JSON, with binary strings as hex.
+ * Null if they aren't a single bencoded value.
+ *
+ * # Safety
+ * `data` must point to `len` readable bytes.
+ */
+char *bt_bencode_to_json(const uint8_t *data, size_t len);
+
+/**
+ * Writes the 20 byte info hash of a .torrent file's `len` bytes to `out`.
+ *
+ * # Safety
+ * `data` must point to `len` readable bytes and `out` to 20 writable ones.
+ */
+int bt_info_hash(const uint8_t *data, size_t len, uint8_t *out);
+
+/**
+ * Parses `len` bytes of a .torrent file. Null if they aren't one.
+ *
+ * # Safety
+ * `data` must point to `len` readable bytes.
+ */
+struct BtTorrent *bt_torrent_parse(const uint8_t *data, size_t len);
+
+/**
+ * # Safety
+ * `torrent` must be null or from `bt_torrent_parse`, not freed before.
+ */
+void bt_torrent_free(struct BtTorrent *torrent);
+
+/**
+ * The torrent's name, valid until the torrent is freed.
+ *
+ * # Safety
+ * `torrent` must be from `bt_torrent_parse`.
+ */
+const char *bt_torrent_name(const struct BtTorrent *torrent);
+
+/**
+ * Writes the torrent's 20 byte info hash to `out`.
+ *
+ * # Safety
+ * `torrent` must be from `bt_torrent_parse` and `out` point to 20
+ * writable bytes.
+ */
+void bt_torrent_info_hash(const struct BtTorrent *torrent, uint8_t *out);
+
+/**
+ * # Safety
+ * `torrent` must be from `bt_torrent_parse`.
+ */
+uint64_t bt_torrent_total_length(const struct BtTorrent *torrent);
+
+/**
+ * # Safety
+ * `torrent` must be from `bt_torrent_parse`.
+ */
+size_t bt_torrent_num_pieces(const struct BtTorrent *torrent);
+
+/**
+ * # Safety
+ * `torrent` must be from `bt_torrent_parse`.
+ */
+size_t bt_torrent_num_files(const struct BtTorrent *torrent);
+
+/**
+ * Starts a session accepting peers on `port`, 0 for any. Null if it
+ * can't bind.
+ */
+struct BtSession *bt_session_start(uint16_t port);
+
+/**
+ * Starts downloading and seeding `torrent` into `download_dir`, carrying
+ * on from whatever is already there, which is hashed first. The torrent
+ * can be freed afterwards.
+ *
+ * # Safety
+ * `session` must be from `bt_session_start`, `torrent` from
+ * `bt_torrent_parse`, and `download_dir` a C string.
+ */
+int bt_session_add(struct BtSession *session,
+                   const struct BtTorrent *torrent,
+                   const char *download_dir);
+
+/**
+ * Gives a running torrent a peer to connect to, as `host:port`.
+ *
+ * # Safety
+ * `session` must be from `bt_session_start`, `info_hash` point to 20
+ * readable bytes and `addr` be a C string.
+ */
+int bt_session_add_peer(struct BtSession *session, const uint8_t *info_hash, const char *addr);
+
+/**
+ * Fills in `out` with a running torrent's progress.
+ *
+ * # Safety
+ * `session` must be from `bt_session_start`, `info_hash` point to 20
+ * readable bytes and `out` to a `BtStats`.
+ */
+int bt_session_stats(const struct BtSession *session,
+                     const uint8_t *info_hash,
+                     struct BtStats *out);
+
+/**
+ * Stops every torrent, saving what they need to resume, and frees the
+ * session. 0 if they all stopped cleanly.
+ *
+ * # Safety
+ * `session` must be from `bt_session_start`, not stopped before.
+ */
+int bt_session_stop(struct BtSession *session);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* BITTORRENT_RS_H */
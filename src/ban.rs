@@ -0,0 +1,157 @@
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+
+pub const DEFAULT_CORRUPT_THRESHOLD: u64 = 64 * 1024;
+
+// A piece of one torrent: its info hash and index.
+type PieceKey = ([u8; 20], u32);
+
+// Tracks which peers supplied the blocks of each in-flight piece so that a
+// failed hash check can be blamed on them. Every contributor of a failed
+// piece is charged the bytes it supplied; once a peer's corrupt bytes exceed
+// the threshold its IP is banned for the rest of the session. Pieces are
+// told apart by their torrent's info hash, so one list serves a session.
+#[derive(Debug)]
+pub struct BanList {
+    threshold: u64,
+    contributions: HashMap<PieceKey, Vec<(IpAddr, u32)>>,
+    corrupt: HashMap<IpAddr, u64>,
+    banned: HashSet<IpAddr>
+}
+
+impl Default for BanList {
+    fn default() -> Self {
+        Self::new(DEFAULT_CORRUPT_THRESHOLD)
+    }
+}
+
+impl BanList {
+    pub fn new(threshold: u64) -> Self {
+        Self {
+            threshold,
+            contributions: HashMap::new(),
+            corrupt: HashMap::new(),
+            banned: HashSet::new()
+        }
+    }
+
+    pub fn threshold(&self) -> u64 {
+        self.threshold
+    }
+
+    pub fn block_received(&mut self, info_hash: [u8; 20], piece: u32, from: IpAddr, length: u32) {
+        self.contributions
+            .entry((info_hash, piece))
+            .or_default()
+            .push((from, length));
+    }
+
+    pub fn piece_passed(&mut self, info_hash: [u8; 20], piece: u32) {
+        self.contributions.remove(&(info_hash, piece));
+    }
+
+    // Returns the peers that crossed the threshold because of this failure.
+    pub fn piece_failed(&mut self, info_hash: [u8; 20], piece: u32) -> Vec<IpAddr> {
+        let contributions = self
+            .contributions
+            .remove(&(info_hash, piece))
+            .unwrap_or_default();
+
+        let mut newly_banned = Vec::new();
+        for (ip, length) in contributions {
+            let corrupt = self.corrupt.entry(ip).or_insert(0);
+            *corrupt += length as u64;
+
+            if *corrupt > self.threshold && self.banned.insert(ip) {
+                newly_banned.push(ip);
+            }
+        }
+        newly_banned
+    }
+
+    pub fn corrupt_bytes(&self, ip: IpAddr) -> u64 {
+        self.corrupt.get(&ip).copied().unwrap_or(0)
+    }
+
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        self.banned.contains(&ip)
+    }
+
+    pub fn ban(&mut self, ip: IpAddr) -> bool {
+        self.banned.insert(ip)
+    }
+
+    pub fn unban(&mut self, ip: IpAddr) -> bool {
+        self.corrupt.remove(&ip);
+        self.banned.remove(&ip)
+    }
+
+    pub fn banned(&self) -> impl Iterator<Item = IpAddr> + '_ {
+        self.banned.iter().copied()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::ban::BanList;
+    use std::net::{IpAddr, Ipv4Addr};
+
+    const TORRENT: [u8; 20] = [1; 20];
+
+    fn ip(last: u8) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::new(10, 0, 0, last))
+    }
+
+    #[test]
+    fn test_ban_after_threshold() {
+        let mut bans = BanList::new(32 * 1024);
+
+        bans.block_received(TORRENT, 0, ip(1), 16 * 1024);
+        bans.block_received(TORRENT, 0, ip(1), 16 * 1024);
+        assert!(bans.piece_failed(TORRENT, 0).is_empty());
+        assert_eq!(bans.corrupt_bytes(ip(1)), 32 * 1024);
+
+        bans.block_received(TORRENT, 1, ip(1), 16 * 1024);
+        assert_eq!(bans.piece_failed(TORRENT, 1), vec![ip(1)]);
+        assert!(bans.is_banned(ip(1)));
+
+        bans.block_received(TORRENT, 2, ip(1), 16 * 1024);
+        assert!(bans.piece_failed(TORRENT, 2).is_empty());
+    }
+
+    #[test]
+    fn test_passed_piece_is_not_blamed() {
+        let mut bans = BanList::new(0);
+
+        bans.block_received(TORRENT, 0, ip(1), 16 * 1024);
+        bans.block_received(TORRENT, 0, ip(2), 16 * 1024);
+        bans.piece_passed(TORRENT, 0);
+        assert!(bans.piece_failed(TORRENT, 0).is_empty());
+        assert_eq!(bans.banned().count(), 0);
+    }
+
+    #[test]
+    fn test_pieces_of_other_torrents() {
+        let mut bans = BanList::new(0);
+
+        bans.block_received(TORRENT, 0, ip(1), 16 * 1024);
+        bans.block_received([2; 20], 0, ip(2), 16 * 1024);
+        assert_eq!(bans.piece_failed(TORRENT, 0), vec![ip(1)]);
+        assert!(!bans.is_banned(ip(2)));
+    }
+
+    #[test]
+    fn test_shared_blame_and_unban() {
+        let mut bans = BanList::new(16 * 1024);
+
+        bans.block_received(TORRENT, 0, ip(1), 16 * 1024);
+        bans.block_received(TORRENT, 0, ip(2), 16 * 1024);
+        bans.block_received(TORRENT, 0, ip(2), 16 * 1024);
+        assert_eq!(bans.piece_failed(TORRENT, 0), vec![ip(2)]);
+        assert!(!bans.is_banned(ip(1)));
+
+        assert!(bans.unban(ip(2)));
+        assert_eq!(bans.corrupt_bytes(ip(2)), 0);
+        assert!(!bans.is_banned(ip(2)));
+    }
+}
@@ -0,0 +1,311 @@
+use crate::hash::hex;
+use std::collections::BTreeMap;
+
+// Nesting limit for untrusted input such as DHT packets.
+const MAX_DEPTH: usize = 64;
+
+// Byte-oriented bencode value. Unlike the `serde_json` based decoder this
+// keeps strings as raw bytes, so it can carry info hashes, node ids and
+// compact peer lists, and it can be encoded back.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    Integer(i64),
+    Bytes(Vec<u8>),
+    List(Vec<Value>),
+    Dict(BTreeMap<Vec<u8>, Value>)
+}
+
+impl From<i64> for Value {
+    fn from(value: i64) -> Self {
+        Value::Integer(value)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(value: &str) -> Self {
+        Value::Bytes(value.as_bytes().to_vec())
+    }
+}
+
+impl From<&[u8]> for Value {
+    fn from(value: &[u8]) -> Self {
+        Value::Bytes(value.to_vec())
+    }
+}
+
+impl From<Vec<u8>> for Value {
+    fn from(value: Vec<u8>) -> Self {
+        Value::Bytes(value)
+    }
+}
+
+impl From<Vec<Value>> for Value {
+    fn from(value: Vec<Value>) -> Self {
+        Value::List(value)
+    }
+}
+
+impl Value {
+    pub fn dict<'a>(entries: impl IntoIterator<Item = (&'a str, Value)>) -> Self {
+        Value::Dict(
+            entries
+                .into_iter()
+                .map(|(key, value)| (key.as_bytes().to_vec(), value))
+                .collect()
+        )
+    }
+
+    pub fn as_int(&self) -> Option<i64> {
+        match self {
+            Value::Integer(value) => Some(*value),
+            _ => None
+        }
+    }
+
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Value::Bytes(value) => Some(value),
+            _ => None
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        std::str::from_utf8(self.as_bytes()?).ok()
+    }
+
+    pub fn as_list(&self) -> Option<&[Value]> {
+        match self {
+            Value::List(value) => Some(value),
+            _ => None
+        }
+    }
+
+    pub fn as_dict(&self) -> Option<&BTreeMap<Vec<u8>, Value>> {
+        match self {
+            Value::Dict(value) => Some(value),
+            _ => None
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        self.as_dict()?.get(key.as_bytes())
+    }
+
+    // A JSON rendering for people and scripts to read.
+    pub fn to_json(&self) -> serde_json::Value {
+        match self {
+            Value::Integer(value) => (*value).into(),
+            // Binary strings such as piece hashes come out as hex.
+            Value::Bytes(bytes) => match std::str::from_utf8(bytes) {
+                Ok(text) => text.into(),
+                Err(_) => hex(bytes).into()
+            },
+            Value::List(values) => values.iter().map(Value::to_json).collect(),
+            Value::Dict(entries) => entries
+                .iter()
+                .map(|(key, value)| (String::from_utf8_lossy(key).into_owned(), value.to_json()))
+                .collect::<serde_json::Map<_, _>>()
+                .into()
+        }
+    }
+
+    // The inverse of `to_json`, except that hex stays text.
+    pub fn from_json(value: &serde_json::Value) -> Option<Self> {
+        match value {
+            serde_json::Value::Number(number) => number.as_i64().map(Value::Integer),
+            serde_json::Value::String(text) => Some(Value::from(text.as_str())),
+            serde_json::Value::Array(values) => values.iter().map(Value::from_json).collect::<Option<Vec<_>>>().map(Value::List),
+            serde_json::Value::Object(entries) => entries
+                .iter()
+                .map(|(key, value)| Some((key.as_bytes().to_vec(), Value::from_json(value)?)))
+                .collect::<Option<_>>()
+                .map(Value::Dict),
+            // Bencode has no booleans, nulls or fractions.
+            _ => None
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.encode_into(&mut out);
+        out
+    }
+
+    pub fn encode_into(&self, out: &mut Vec<u8>) {
+        match self {
+            Value::Integer(value) => {
+                out.push(b'i');
+                out.extend_from_slice(value.to_string().as_bytes());
+                out.push(b'e');
+            },
+            Value::Bytes(value) => encode_bytes(value, out),
+            Value::List(values) => {
+                out.push(b'l');
+                values.iter().for_each(|value| value.encode_into(out));
+                out.push(b'e');
+            },
+            Value::Dict(entries) => {
+                out.push(b'd');
+                for (key, value) in entries {
+                    encode_bytes(key, out);
+                    value.encode_into(out);
+                }
+                out.push(b'e');
+            }
+        }
+    }
+}
+
+fn encode_bytes(bytes: &[u8], out: &mut Vec<u8>) {
+    out.extend_from_slice(bytes.len().to_string().as_bytes());
+    out.push(b':');
+    out.extend_from_slice(bytes);
+}
+
+// Decodes a single value that must span the whole input.
+pub fn decode(input: &[u8]) -> Option<Value> {
+    match decode_prefix(input)? {
+        (value, []) => Some(value),
+        _ => None
+    }
+}
+
+// Decodes one value and returns it together with the unconsumed input.
+pub fn decode_prefix(input: &[u8]) -> Option<(Value, &[u8])> {
+    decode_value(input, 0)
+}
+
+// The undecoded bytes of one entry of a top-level dictionary, such as a
+// metainfo file's `info`, which must be hashed exactly as it was sent.
+pub fn raw_value<'a>(input: &'a [u8], key: &str) -> Option<&'a [u8]> {
+    let mut rest = input.strip_prefix(b"d")?;
+    while *rest.first()? != b'e' {
+        let (name, next) = decode_bytes(rest)?;
+        let (_, after) = decode_value(next, 1)?;
+        if name == key.as_bytes() {
+            return Some(&next[..next.len() - after.len()]);
+        }
+        rest = after;
+    }
+    None
+}
+
+fn decode_value(input: &[u8], depth: usize) -> Option<(Value, &[u8])> {
+    if depth > MAX_DEPTH {
+        return None;
+    }
+
+    match input.first()? {
+        b'i' => {
+            let end = input.iter().position(|&ch| ch == b'e')?;
+            let digits = std::str::from_utf8(&input[1..end]).ok()?;
+            let canonical = digits == "0"
+                || (!digits.starts_with('0') && !digits.starts_with("-0") && !digits.is_empty());
+            if !canonical {
+                return None;
+            }
+            Some((Value::Integer(digits.parse().ok()?), &input[end + 1..]))
+        },
+        b'0'..=b'9' => {
+            let (bytes, rest) = decode_bytes(input)?;
+            Some((Value::Bytes(bytes.to_vec()), rest))
+        },
+        b'l' => {
+            let mut rest = &input[1..];
+            let mut values = Vec::new();
+            while *rest.first()? != b'e' {
+                let (value, next) = decode_value(rest, depth + 1)?;
+                values.push(value);
+                rest = next;
+            }
+            Some((Value::List(values), &rest[1..]))
+        },
+        b'd' => {
+            let mut rest = &input[1..];
+            let mut entries = BTreeMap::new();
+            while *rest.first()? != b'e' {
+                let (key, next) = decode_bytes(rest)?;
+                let (value, next) = decode_value(next, depth + 1)?;
+                entries.insert(key.to_vec(), value);
+                rest = next;
+            }
+            Some((Value::Dict(entries), &rest[1..]))
+        },
+        _ => None
+    }
+}
+
+fn decode_bytes(input: &[u8]) -> Option<(&[u8], &[u8])> {
+    let colon = input.iter().position(|&ch| ch == b':')?;
+    let len: usize = std::str::from_utf8(&input[..colon]).ok()?.parse().ok()?;
+    let rest = &input[colon + 1..];
+    if rest.len() < len {
+        return None;
+    }
+    Some(rest.split_at(len))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::bencode::{decode, decode_prefix, raw_value, Value};
+
+    #[test]
+    fn test_decode() {
+        assert_eq!(decode(b"i-3e"), Some(Value::Integer(-3)));
+        assert_eq!(decode(b"4:\x00\xff\x10a"), Some(Value::Bytes(vec![0, 0xff, 0x10, b'a'])));
+        assert_eq!(
+            decode(b"d3:cowl3:mooi1eee"),
+            Some(Value::dict([("cow", Value::List(vec!["moo".into(), 1.into()]))]))
+        );
+        assert_eq!(decode_prefix(b"i1eXY"), Some((Value::Integer(1), &b"XY"[..])));
+    }
+
+    #[test]
+    fn test_decode_invalid() {
+        assert_eq!(decode(b"i1eX"), None);
+        assert_eq!(decode(b"i03e"), None);
+        assert_eq!(decode(b"i-0e"), None);
+        assert_eq!(decode(b"ie"), None);
+        assert_eq!(decode(b"5:abc"), None);
+        assert_eq!(decode(b"l4:spam"), None);
+        assert_eq!(decode(&[b'l'; 100]), None);
+    }
+
+    #[test]
+    fn test_encode() {
+        let value = Value::dict([
+            ("spam", Value::List(vec!["a".into(), Value::Integer(-42)])),
+            ("cow", Value::Bytes(vec![0, 1]))
+        ]);
+        assert_eq!(value.encode(), b"d3:cow2:\x00\x014:spaml1:ai-42eee");
+        assert_eq!(decode(&value.encode()), Some(value));
+    }
+
+    #[test]
+    fn test_json() {
+        let json = |input: &[u8]| decode(input).unwrap().to_json();
+        assert_eq!(json(b"4:spam"), serde_json::json!("spam"));
+        assert_eq!(json(b"0:"), serde_json::json!(""));
+        assert_eq!(json(b"i-3e"), serde_json::json!(-3));
+        assert_eq!(json(b"li32elei2e1:se"), serde_json::json!([32, [], 2, "s"]));
+        assert_eq!(json(b"d3:cow3:moo4:spaml1:ai-5eee"), serde_json::json!({ "cow": "moo", "spam": ["a", -5] }));
+        assert_eq!(
+            json(b"d9:publisher3:bob17:publisher-webpage15:www.example.com18:publisher.location4:homee"),
+            serde_json::json!({ "publisher": "bob", "publisher-webpage": "www.example.com", "publisher.location": "home" })
+        );
+        assert_eq!(json(b"2:\xff\x00"), serde_json::json!("ff00"));
+
+        let value = decode(b"d3:cow3:moo4:spaml1:ai-5eee").unwrap();
+        assert_eq!(Value::from_json(&value.to_json()), Some(value));
+        assert_eq!(Value::from_json(&serde_json::json!([1, true])), None);
+        assert_eq!(Value::from_json(&serde_json::json!(1.5)), None);
+    }
+
+    #[test]
+    fn test_raw_value() {
+        // Keys out of order survive untouched.
+        let input = b"d8:announce3:url4:infod4:name1:x6:lengthi1eee";
+        assert_eq!(raw_value(input, "info"), Some(&b"d4:name1:x6:lengthi1ee"[..]));
+        assert_eq!(raw_value(input, "missing"), None);
+    }
+}
@@ -0,0 +1,118 @@
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Bitfield {
+    bits: Vec<u8>,
+    len: usize
+}
+
+impl Bitfield {
+    pub fn new(len: usize) -> Self {
+        Self { bits: vec![0; len.div_ceil(8)], len }
+    }
+
+    pub fn full(len: usize) -> Self {
+        let mut bitfield = Self::new(len);
+        for index in 0..len {
+            bitfield.set(index);
+        }
+        bitfield
+    }
+
+    // Parses the payload of a `bitfield` message. The spare bits at the end
+    // of the last byte must be cleared.
+    pub fn from_bytes(bytes: &[u8], len: usize) -> Option<Self> {
+        if bytes.len() != len.div_ceil(8) {
+            return None;
+        }
+
+        let bitfield = Self { bits: bytes.to_vec(), len };
+        let spare = (len..bytes.len() * 8).any(|index| bitfield.bit(index));
+        (!spare).then_some(bitfield)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bits
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn bit(&self, index: usize) -> bool {
+        self.bits[index / 8] & (0x80 >> (index % 8)) != 0
+    }
+
+    pub fn get(&self, index: usize) -> bool {
+        index < self.len && self.bit(index)
+    }
+
+    // Returns whether the bit was newly set.
+    pub fn set(&mut self, index: usize) -> bool {
+        if index >= self.len || self.bit(index) {
+            return false;
+        }
+        self.bits[index / 8] |= 0x80 >> (index % 8);
+        true
+    }
+
+    pub fn clear(&mut self, index: usize) {
+        if index < self.len {
+            self.bits[index / 8] &= !(0x80 >> (index % 8));
+        }
+    }
+
+    pub fn count_ones(&self) -> usize {
+        self.bits
+            .iter()
+            .map(|byte| byte.count_ones() as usize)
+            .sum()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.count_ones() == self.len
+    }
+
+    pub fn ones(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.len).filter(|&index| self.bit(index))
+    }
+
+    pub fn zeros(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.len).filter(|&index| !self.bit(index))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::bitfield::Bitfield;
+
+    #[test]
+    fn test_set_get() {
+        let mut bitfield = Bitfield::new(10);
+        assert!(bitfield.set(0));
+        assert!(bitfield.set(9));
+        assert!(!bitfield.set(9));
+        assert!(!bitfield.set(10));
+
+        assert_eq!(bitfield.as_bytes(), &[0x80, 0x40]);
+        assert_eq!(bitfield.ones().collect::<Vec<_>>(), vec![0, 9]);
+        assert_eq!(bitfield.count_ones(), 2);
+
+        bitfield.clear(0);
+        assert!(!bitfield.get(0));
+        assert!(!bitfield.get(100));
+    }
+
+    #[test]
+    fn test_from_bytes() {
+        assert_eq!(
+            Bitfield::from_bytes(&[0xff, 0xc0], 10),
+            Some(Bitfield::full(10))
+        );
+        assert_eq!(Bitfield::from_bytes(&[0xff, 0xe0], 10), None);
+        assert_eq!(Bitfield::from_bytes(&[0xff], 10), None);
+        assert!(Bitfield::full(10).is_complete());
+    }
+}
@@ -0,0 +1,186 @@
+use std::collections::HashSet;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct BlockRequest {
+    pub index: u32,
+    pub begin: u32,
+    pub length: u32
+}
+
+impl BlockRequest {
+    pub fn new(index: u32, begin: u32, length: u32) -> Self {
+        Self { index, begin, length }
+    }
+}
+
+// What to do with a `piece` message that arrives for a request we already
+// cancelled (or dropped because we got choked).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LatePiecePolicy {
+    #[default]
+    Accept,
+    Discard
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Received {
+    Requested,
+    LateAccepted,
+    LateDiscarded,
+    Unrequested
+}
+
+// Per-peer bookkeeping of the requests we have sent and not yet seen answered.
+#[derive(Debug, Default)]
+pub struct PendingRequests {
+    in_flight: Vec<BlockRequest>,
+    cancelled: HashSet<BlockRequest>,
+    policy: LatePiecePolicy,
+    late_accepted: u64,
+    late_discarded: u64
+}
+
+impl PendingRequests {
+    pub fn new(policy: LatePiecePolicy) -> Self {
+        Self { policy, ..Default::default() }
+    }
+
+    pub fn len(&self) -> usize {
+        self.in_flight.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.in_flight.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &BlockRequest> {
+        self.in_flight.iter()
+    }
+
+    pub fn contains(&self, request: &BlockRequest) -> bool {
+        self.in_flight.contains(request)
+    }
+
+    pub fn request(&mut self, request: BlockRequest) -> bool {
+        if self.contains(&request) {
+            return false;
+        }
+        self.cancelled.remove(&request);
+        self.in_flight.push(request);
+        true
+    }
+
+    // Returns true if the request was in flight and a `cancel` should be sent.
+    pub fn cancel(&mut self, request: &BlockRequest) -> bool {
+        match self.in_flight.iter().position(|r| r == request) {
+            Some(pos) => {
+                let request = self.in_flight.remove(pos);
+                self.cancelled.insert(request);
+                true
+            },
+            None => false
+        }
+    }
+
+    // Drops every in-flight request, returning them so the caller can send
+    // `cancel`s and hand the blocks back to the scheduler.
+    pub fn cancel_all(&mut self) -> Vec<BlockRequest> {
+        let requests = std::mem::take(&mut self.in_flight);
+        self.cancelled.extend(requests.iter().copied());
+        requests
+    }
+
+    pub fn choked(&mut self) -> Vec<BlockRequest> {
+        self.cancel_all()
+    }
+
+    // A peer unchoking us again dropped what we asked before it choked us,
+    // so nothing cancelled back then is still coming.
+    pub fn unchoked(&mut self) {
+        self.cancelled.clear();
+    }
+
+    pub fn piece_received(&mut self, index: u32, begin: u32, length: u32) -> Received {
+        let request = BlockRequest::new(index, begin, length);
+
+        if let Some(pos) = self.in_flight.iter().position(|r| *r == request) {
+            self.in_flight.remove(pos);
+            return Received::Requested;
+        }
+
+        if !self.cancelled.remove(&request) {
+            return Received::Unrequested;
+        }
+
+        match self.policy {
+            LatePiecePolicy::Accept => {
+                self.late_accepted += length as u64;
+                Received::LateAccepted
+            },
+            LatePiecePolicy::Discard => {
+                self.late_discarded += length as u64;
+                Received::LateDiscarded
+            }
+        }
+    }
+
+    pub fn late_accepted_bytes(&self) -> u64 {
+        self.late_accepted
+    }
+
+    pub fn late_discarded_bytes(&self) -> u64 {
+        self.late_discarded
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::block::{BlockRequest, LatePiecePolicy, PendingRequests, Received};
+
+    #[test]
+    fn test_cancel() {
+        let mut pending = PendingRequests::new(LatePiecePolicy::Accept);
+        let req = BlockRequest::new(0, 0, 16384);
+
+        assert!(pending.request(req));
+        assert!(!pending.request(req));
+        assert!(pending.cancel(&req));
+        assert!(!pending.cancel(&req));
+        assert!(pending.is_empty());
+
+        assert_eq!(pending.piece_received(0, 0, 16384), Received::LateAccepted);
+        assert_eq!(pending.piece_received(0, 0, 16384), Received::Unrequested);
+        assert_eq!(pending.late_accepted_bytes(), 16384);
+    }
+
+    #[test]
+    fn test_choke() {
+        let mut pending = PendingRequests::new(LatePiecePolicy::Discard);
+        pending.request(BlockRequest::new(1, 0, 16384));
+        pending.request(BlockRequest::new(1, 16384, 16384));
+
+        assert_eq!(
+            pending.choked(),
+            vec![BlockRequest::new(1, 0, 16384), BlockRequest::new(1, 16384, 16384)]
+        );
+        assert!(pending.is_empty());
+
+        assert_eq!(pending.piece_received(1, 16384, 16384), Received::LateDiscarded);
+        assert_eq!(pending.late_discarded_bytes(), 16384);
+
+        pending.unchoked();
+        assert_eq!(pending.piece_received(1, 0, 16384), Received::Unrequested);
+    }
+
+    #[test]
+    fn test_rerequest_after_cancel() {
+        let mut pending = PendingRequests::default();
+        let req = BlockRequest::new(2, 0, 100);
+
+        pending.request(req);
+        pending.cancel(&req);
+        pending.request(req);
+        assert_eq!(pending.piece_received(2, 0, 100), Received::Requested);
+        assert_eq!(pending.piece_received(2, 0, 100), Received::Unrequested);
+    }
+}
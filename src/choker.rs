@@ -0,0 +1,191 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::SocketAddr;
+
+pub const DEFAULT_UPLOAD_SLOTS: usize = 4;
+pub const OPTIMISTIC_UNCHOKE_ROUNDS: u32 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChokeCandidate {
+    pub addr: SocketAddr,
+    pub interested: bool,
+    // Bytes per second we get from them while downloading, or send to them
+    // while seeding.
+    pub rate: u64
+}
+
+// Tit-for-tat choker for one torrent. Every round the fastest interested
+// peers are unchoked, and every few rounds one more peer is picked in turn
+// for the optimistic unchoke. Both share the torrent's slot budget.
+#[derive(Debug, Default)]
+pub struct Choker {
+    optimistic: Option<SocketAddr>,
+    round: u32
+}
+
+impl Choker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn optimistic(&self) -> Option<SocketAddr> {
+        self.optimistic
+    }
+
+    // Returns the peers to keep unchoked; everyone else gets choked.
+    pub fn rechoke(&mut self, peers: &[ChokeCandidate], slots: usize) -> Vec<SocketAddr> {
+        let mut interested: Vec<_> = peers
+            .iter()
+            .filter(|peer| peer.interested)
+            .collect();
+        interested.sort_by_key(|peer| (std::cmp::Reverse(peer.rate), peer.addr));
+
+        if slots == 0 || interested.is_empty() {
+            self.optimistic = None;
+            self.round += 1;
+            return Vec::new();
+        }
+
+        // The optimistic slot only exists when there is more than one slot
+        // and more interested peers than regular slots.
+        let regular = if slots > 1 && interested.len() > slots - 1 {
+            slots - 1
+        } else {
+            slots
+        };
+        let mut unchoked: Vec<_> = interested
+            .iter()
+            .take(regular)
+            .map(|peer| peer.addr)
+            .collect();
+
+        if regular < slots {
+            let still_valid = self
+                .optimistic
+                .filter(|addr| !unchoked.contains(addr))
+                .filter(|addr| interested.iter().any(|peer| peer.addr == *addr));
+
+            let optimistic = match still_valid {
+                Some(addr) if !self.round.is_multiple_of(OPTIMISTIC_UNCHOKE_ROUNDS) => Some(addr),
+                _ => self.next_optimistic(&interested, &unchoked)
+            };
+            self.optimistic = optimistic;
+            unchoked.extend(optimistic);
+        } else {
+            self.optimistic = None;
+        }
+
+        self.round += 1;
+        unchoked
+    }
+
+    fn next_optimistic(&self, interested: &[&ChokeCandidate], unchoked: &[SocketAddr]) -> Option<SocketAddr> {
+        let mut choked: Vec<_> = interested
+            .iter()
+            .map(|peer| peer.addr)
+            .filter(|addr| !unchoked.contains(addr))
+            .collect();
+        choked.sort();
+
+        let after = self.optimistic;
+        choked
+            .iter()
+            .find(|&&addr| Some(addr) > after)
+            .or_else(|| choked.first())
+            .copied()
+    }
+}
+
+// Splits a global cap on unchoked peers between torrents, each of which
+// also has its own cap. Slots are handed out one at a time in turn so a
+// popular torrent cannot starve the others.
+#[derive(Debug, Clone, Copy)]
+pub struct UploadSlots {
+    pub global: Option<usize>,
+    pub per_torrent: usize
+}
+
+impl Default for UploadSlots {
+    fn default() -> Self {
+        Self { global: None, per_torrent: DEFAULT_UPLOAD_SLOTS }
+    }
+}
+
+impl UploadSlots {
+    // `demand` is the number of interested peers per torrent.
+    pub fn allocate<K: Copy + Eq + Hash>(&self, demand: &[(K, usize)]) -> HashMap<K, usize> {
+        let mut allocated: HashMap<K, usize> = demand
+            .iter()
+            .map(|&(key, _)| (key, 0))
+            .collect();
+        let mut remaining = self.global.unwrap_or(usize::MAX);
+
+        loop {
+            let mut progressed = false;
+            for &(key, wanted) in demand {
+                if remaining == 0 {
+                    return allocated;
+                }
+                let slots = allocated.get_mut(&key).unwrap();
+                if *slots < wanted.min(self.per_torrent) {
+                    *slots += 1;
+                    remaining -= 1;
+                    progressed = true;
+                }
+            }
+            if !progressed {
+                return allocated;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::choker::{ChokeCandidate, Choker, UploadSlots};
+    use std::net::SocketAddr;
+
+    fn peer(port: u16, rate: u64) -> ChokeCandidate {
+        ChokeCandidate { addr: SocketAddr::from(([127, 0, 0, 1], port)), interested: true, rate }
+    }
+
+    #[test]
+    fn test_fastest_plus_optimistic() {
+        let peers = [peer(1, 10), peer(2, 50), peer(3, 30), peer(4, 0), peer(5, 0)];
+        let mut choker = Choker::new();
+
+        let unchoked = choker.rechoke(&peers, 3);
+        assert_eq!(unchoked, vec![peers[1].addr, peers[2].addr, peers[0].addr]);
+        assert_eq!(choker.optimistic(), Some(peers[0].addr));
+
+        // The optimistic peer stays put until its period is over.
+        assert_eq!(choker.rechoke(&peers, 3)[2], peers[0].addr);
+        assert_eq!(choker.rechoke(&peers, 3)[2], peers[0].addr);
+        assert_eq!(choker.rechoke(&peers, 3)[2], peers[3].addr);
+    }
+
+    #[test]
+    fn test_slot_caps() {
+        let mut peers = vec![peer(1, 10), peer(2, 20)];
+        peers[0].interested = false;
+        let mut choker = Choker::new();
+
+        assert_eq!(choker.rechoke(&peers, 4), vec![peers[1].addr]);
+        assert!(choker.rechoke(&peers, 0).is_empty());
+        assert_eq!(choker.optimistic(), None);
+    }
+
+    #[test]
+    fn test_global_allocation() {
+        let slots = UploadSlots { global: Some(5), per_torrent: 4 };
+        let allocated = slots.allocate(&[(1, 100), (2, 1), (3, 100)]);
+
+        assert_eq!(allocated[&1], 2);
+        assert_eq!(allocated[&2], 1);
+        assert_eq!(allocated[&3], 2);
+
+        let allocated = UploadSlots::default().allocate(&[(1, 100), (2, 2)]);
+        assert_eq!(allocated[&1], 4);
+        assert_eq!(allocated[&2], 2);
+    }
+}
@@ -0,0 +1,55 @@
+// BEP 23's compact peers, and BEP 7's for IPv6: the address then the port,
+// big-endian, as trackers and the DHT send them.
+
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+
+pub const COMPACT_PEER_LEN: usize = 6;
+pub const COMPACT_PEER6_LEN: usize = 18;
+
+pub fn encode_peer(addr: SocketAddr) -> Option<[u8; COMPACT_PEER_LEN]> {
+    match addr {
+        SocketAddr::V4(addr) => {
+            let mut bytes = [0; COMPACT_PEER_LEN];
+            bytes[..4].copy_from_slice(&addr.ip().octets());
+            bytes[4..].copy_from_slice(&addr.port().to_be_bytes());
+            Some(bytes)
+        },
+        SocketAddr::V6(_) => None
+    }
+}
+
+pub fn encode_peer6(addr: SocketAddr) -> Option<[u8; COMPACT_PEER6_LEN]> {
+    match addr {
+        SocketAddr::V6(addr) => {
+            let mut bytes = [0; COMPACT_PEER6_LEN];
+            bytes[..16].copy_from_slice(&addr.ip().octets());
+            bytes[16..].copy_from_slice(&addr.port().to_be_bytes());
+            Some(bytes)
+        },
+        SocketAddr::V4(_) => None
+    }
+}
+
+// Either family, told apart by length.
+pub fn encode_compact(addr: SocketAddr) -> Vec<u8> {
+    match encode_peer(addr) {
+        Some(bytes) => bytes.to_vec(),
+        None => encode_peer6(addr).map(|bytes| bytes.to_vec()).unwrap_or_default()
+    }
+}
+
+pub fn decode_peer(bytes: &[u8]) -> Option<SocketAddr> {
+    match bytes.len() {
+        COMPACT_PEER_LEN => {
+            let ip = Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3]);
+            let port = u16::from_be_bytes([bytes[4], bytes[5]]);
+            Some(SocketAddr::V4(SocketAddrV4::new(ip, port)))
+        },
+        COMPACT_PEER6_LEN => {
+            let ip: [u8; 16] = bytes[..16].try_into().ok()?;
+            let port = u16::from_be_bytes([bytes[16], bytes[17]]);
+            Some(SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::from(ip), port, 0, 0)))
+        },
+        _ => None
+    }
+}
@@ -0,0 +1,123 @@
+use crate::dht::{Dht, NodeId, NodeInfo};
+use crate::dial::{DialQueue, PeerSource};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+pub const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(15 * 60);
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PeerLookup {
+    pub peers: Vec<SocketAddr>,
+    // The closest nodes that answered, with the token each handed out.
+    pub nodes: Vec<(NodeInfo, Vec<u8>)>
+}
+
+impl Dht {
+    // Iterative get_peers towards the info hash, collecting peers from
+    // every node on the way.
+    pub fn lookup_peers(&mut self, info_hash: [u8; 20]) -> PeerLookup {
+        let mut peers = Vec::new();
+        let nodes = self.lookup_tokens(NodeId(info_hash), |dht, addr| {
+            let response = dht.get_peers(addr, info_hash)?;
+            peers.extend(response.peers);
+            Ok((response.nodes, response.token))
+        });
+
+        peers.sort();
+        peers.dedup();
+        PeerLookup { peers, nodes }
+    }
+
+    // Looks up peers and announces ourselves to the closest nodes. A port of
+    // `None` announces our DHT port (`implied_port`).
+    pub fn announce(&mut self, info_hash: [u8; 20], port: Option<u16>) -> PeerLookup {
+        let lookup = self.lookup_peers(info_hash);
+        for (node, token) in &lookup.nodes {
+            let _ = self.announce_peer(node.addr, info_hash, port, token);
+        }
+        lookup
+    }
+}
+
+// DHT peer discovery for one torrent: announces when the torrent starts
+// and again every interval, feeding what it finds into the torrent's dial
+// queue next to the tracker peers. Private torrents never use the DHT.
+#[derive(Debug, Clone)]
+pub struct TorrentAnnouncer {
+    info_hash: [u8; 20],
+    port: u16,
+    private: bool,
+    next: Option<Instant>
+}
+
+impl TorrentAnnouncer {
+    pub fn new(info_hash: [u8; 20], port: u16, private: bool) -> Self {
+        Self { info_hash, port, private, next: None }
+    }
+
+    pub fn info_hash(&self) -> [u8; 20] {
+        self.info_hash
+    }
+
+    pub fn is_due(&self, now: Instant) -> bool {
+        !self.private && self.next.is_none_or(|next| now >= next)
+    }
+
+    // Returns the peers found, or `None` if no announce was due.
+    pub fn announce(&mut self, dht: &mut Dht, now: Instant) -> Option<Vec<SocketAddr>> {
+        if !self.is_due(now) {
+            return None;
+        }
+        self.next = Some(now + ANNOUNCE_INTERVAL);
+        Some(dht.announce(self.info_hash, Some(self.port)).peers)
+    }
+
+    // Returns the number of peers found, or `None` if no announce was due.
+    pub fn poll(&mut self, dht: &mut Dht, peers: &mut DialQueue, now: Instant) -> Option<usize> {
+        let found = self.announce(dht, now)?;
+        found
+            .iter()
+            .for_each(|&peer| peers.add(peer, PeerSource::Dht));
+        Some(found.len())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::dht::announce::{TorrentAnnouncer, ANNOUNCE_INTERVAL};
+    use crate::dht::{Dht, NodeId};
+    use crate::dial::{DialConfig, DialQueue};
+    use std::thread;
+    use std::time::Instant;
+
+    #[test]
+    fn test_announce_and_find() {
+        let mut remote = Dht::with_id("127.0.0.1:0", NodeId([9; 20])).unwrap();
+        let remote_addr = remote.local_addr().unwrap();
+        thread::spawn(move || remote.run());
+
+        let info_hash = [7; 20];
+        let mut first = Dht::bind("127.0.0.1:0").unwrap();
+        first.ping(remote_addr).unwrap();
+        let lookup = first.announce(info_hash, Some(5000));
+        assert!(lookup.peers.is_empty());
+        assert_eq!(lookup.nodes.len(), 1);
+
+        let mut second = Dht::bind("127.0.0.1:0").unwrap();
+        second.ping(remote_addr).unwrap();
+        let mut queue = DialQueue::new(DialConfig::default());
+        let mut announcer = TorrentAnnouncer::new(info_hash, 6000, false);
+        let now = Instant::now();
+
+        assert_eq!(announcer.poll(&mut second, &mut queue, now), Some(1));
+        assert_eq!(queue.queued(), 1);
+        assert_eq!(announcer.poll(&mut second, &mut queue, now), None);
+        assert!(announcer.is_due(now + ANNOUNCE_INTERVAL));
+    }
+
+    #[test]
+    fn test_private_torrent() {
+        let announcer = TorrentAnnouncer::new([7; 20], 6000, true);
+        assert!(!announcer.is_due(Instant::now()));
+    }
+}
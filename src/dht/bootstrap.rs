@@ -0,0 +1,123 @@
+use crate::bencode::Value;
+use crate::dht::{Dht, Family, K};
+use std::net::{SocketAddr, ToSocketAddrs};
+use tracing::{debug, info};
+
+pub const DEFAULT_ROUTERS: &[&str] = &[
+    "router.bittorrent.com:6881",
+    "router.utorrent.com:6881",
+    "dht.transmissionbt.com:6881",
+    "dht.libtorrent.org:25401"
+];
+
+pub const MAX_BOOTSTRAP_ROUNDS: usize = 4;
+
+// The `nodes` key of a metainfo file: a list of `[host, port]` pairs.
+pub fn torrent_nodes(metainfo: &Value) -> Vec<String> {
+    metainfo
+        .get("nodes")
+        .and_then(Value::as_list)
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|node| {
+            let node = node.as_list()?;
+            let host = node.first()?.as_str()?;
+            let port = u16::try_from(node.get(1)?.as_int()?).ok()?;
+            Some(format!("{}:{}", host, port))
+        })
+        .collect()
+}
+
+fn resolve(hosts: &[String], family: Family) -> Vec<SocketAddr> {
+    hosts
+        .iter()
+        .filter_map(|host| host.to_socket_addrs().ok())
+        .flatten()
+        .filter(|addr| Family::of(addr) == family)
+        .collect()
+}
+
+impl Dht {
+    // Fills the routing table by looking up our own id, starting from the
+    // given routers and nodes, until it holds at least K nodes or a round
+    // finds nothing new. Routers only hand out nodes and are not kept in
+    // the table. Once a node has told us our external address we switch to
+    // a BEP 42 id for it and look that up instead. Returns the number of
+    // nodes in the table.
+    pub fn bootstrap(&mut self, routers: &[String], nodes: &[String]) -> usize {
+        let routers = resolve(routers, self.family());
+        let mut start = routers.clone();
+        start.extend(resolve(nodes, self.family()));
+
+        for _ in 0..MAX_BOOTSTRAP_ROUNDS {
+            let before = self.routing_table().len();
+            self.lookup_nodes(self.id(), &start);
+            self.remove_routers(&routers);
+            let moved = self.adopt_secure_id();
+
+            let after = self.routing_table().len();
+            debug!(before, after, moved, "bootstrap round");
+            if !moved && (after >= K || after == before) {
+                break;
+            }
+            start.clear();
+        }
+        info!(nodes = self.routing_table().len(), family = ?self.family(), "bootstrapped");
+        self.routing_table().len()
+    }
+
+    fn remove_routers(&mut self, routers: &[SocketAddr]) {
+        let ids: Vec<_> = self.nodes()
+            .into_iter()
+            .filter(|node| routers.contains(&node.addr))
+            .map(|node| node.id)
+            .collect();
+        ids.iter().for_each(|id| self.routing_table_mut().remove(id));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::bencode::Value;
+    use crate::dht::bootstrap::torrent_nodes;
+    use crate::dht::{Dht, NodeId};
+    use std::net::SocketAddr;
+    use std::thread;
+
+    fn spawn(mut node: Dht) -> SocketAddr {
+        let addr = node.local_addr().unwrap();
+        thread::spawn(move || node.run());
+        addr
+    }
+
+    #[test]
+    fn test_torrent_nodes() {
+        let metainfo = Value::dict([(
+            "nodes",
+            Value::List(vec![
+                Value::List(vec!["127.0.0.1".into(), 6881.into()]),
+                Value::List(vec!["bad".into()])
+            ])
+        )]);
+        assert_eq!(torrent_nodes(&metainfo), vec!["127.0.0.1:6881".to_string()]);
+    }
+
+    #[test]
+    fn test_bootstrap() {
+        let b = spawn(Dht::with_id("127.0.0.1:0", NodeId([0xb0; 20])).unwrap());
+        let c = spawn(Dht::with_id("127.0.0.1:0", NodeId([0xc0; 20])).unwrap());
+
+        let mut router = Dht::with_id("127.0.0.1:0", NodeId([0xa0; 20])).unwrap();
+        router.ping(b).unwrap();
+        router.ping(c).unwrap();
+        let router = spawn(router);
+
+        let mut dht = Dht::with_id("127.0.0.1:0", NodeId([0x01; 20])).unwrap();
+        let count = dht.bootstrap(&[router.to_string()], &[]);
+
+        let mut ids: Vec<_> = dht.nodes().iter().map(|node| node.id).collect();
+        ids.sort();
+        assert_eq!(ids, vec![NodeId([0xb0; 20]), NodeId([0xc0; 20])]);
+        assert_eq!(count, 2);
+    }
+}
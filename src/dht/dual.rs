@@ -0,0 +1,117 @@
+use crate::dht::{Dht, Family};
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+
+// An IPv4 and an IPv6 DHT node side by side (BEP 32). Both ask for nodes
+// of either family, and what one learns about the other's DHT seeds it.
+// The IPv6 node is optional; not every host has IPv6.
+pub struct DualStack {
+    v4: Dht,
+    v6: Option<Dht>
+}
+
+impl DualStack {
+    pub fn bind(port: u16) -> io::Result<Self> {
+        let v4 = Dht::bind((Ipv4Addr::UNSPECIFIED, port))?;
+        // Where IPv6 sockets also take IPv4 traffic the port is already
+        // taken, so fall back to any port.
+        let v6 = Dht::with_id((Ipv6Addr::UNSPECIFIED, port), v4.id())
+            .or_else(|_| Dht::with_id((Ipv6Addr::UNSPECIFIED, 0), v4.id()))
+            .ok();
+        Ok(Self::new(v4, v6))
+    }
+
+    pub fn new(mut v4: Dht, mut v6: Option<Dht>) -> Self {
+        v4.set_want(vec![Family::V4, Family::V6]);
+        if let Some(v6) = &mut v6 {
+            v6.set_want(vec![Family::V4, Family::V6]);
+        }
+        Self { v4, v6 }
+    }
+
+    pub fn v4(&mut self) -> &mut Dht {
+        &mut self.v4
+    }
+
+    pub fn v6(&mut self) -> Option<&mut Dht> {
+        self.v6.as_mut()
+    }
+
+    fn nodes(&mut self) -> impl Iterator<Item = &mut Dht> {
+        std::iter::once(&mut self.v4).chain(self.v6.as_mut())
+    }
+
+    // Passes on the nodes each side was told about for the other.
+    fn exchange(&mut self) {
+        let Some(v6) = &mut self.v6 else {
+            return;
+        };
+        self.v4.take_foreign_nodes().into_iter().for_each(|node| v6.add_node(node));
+        v6.take_foreign_nodes().into_iter().for_each(|node| self.v4.add_node(node));
+    }
+
+    // Returns the number of nodes in both routing tables.
+    pub fn bootstrap(&mut self, routers: &[String], nodes: &[String]) -> usize {
+        self.nodes().for_each(|dht| { dht.bootstrap(routers, nodes); });
+        self.exchange();
+        self.nodes().map(|dht| dht.routing_table().len()).sum()
+    }
+
+    pub fn lookup_peers(&mut self, info_hash: [u8; 20]) -> Vec<SocketAddr> {
+        let peers = self.nodes().flat_map(|dht| dht.lookup_peers(info_hash).peers).collect();
+        self.exchange();
+        merge(peers)
+    }
+
+    pub fn announce(&mut self, info_hash: [u8; 20], port: Option<u16>) -> Vec<SocketAddr> {
+        let peers = self.nodes().flat_map(|dht| dht.announce(info_hash, port).peers).collect();
+        self.exchange();
+        merge(peers)
+    }
+}
+
+fn merge(mut peers: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    peers.sort();
+    peers.dedup();
+    peers
+}
+
+#[cfg(test)]
+mod test {
+    use crate::dht::dual::DualStack;
+    use crate::dht::{Dht, NodeId};
+    use std::net::SocketAddr;
+    use std::thread;
+
+    fn spawn(addr: &str) -> Option<SocketAddr> {
+        let mut node = Dht::with_id(addr, NodeId([9; 20])).ok()?;
+        let addr = node.local_addr().ok()?;
+        thread::spawn(move || node.run());
+        Some(addr)
+    }
+
+    #[test]
+    fn test_merged_peers() {
+        let info_hash = [7; 20];
+        let remote4 = spawn("127.0.0.1:0").unwrap();
+        // Skip where the sandbox has no IPv6 loopback.
+        let Some(remote6) = spawn("[::1]:0") else {
+            return;
+        };
+
+        for (local, remote, port) in [("127.0.0.1:0", remote4, 1000), ("[::1]:0", remote6, 2000)] {
+            let mut peer = Dht::bind(local).unwrap();
+            peer.ping(remote).unwrap();
+            peer.announce(info_hash, Some(port));
+        }
+
+        let mut v4 = Dht::bind("127.0.0.1:0").unwrap();
+        let mut v6 = Dht::bind("[::1]:0").unwrap();
+        v4.ping(remote4).unwrap();
+        v6.ping(remote6).unwrap();
+
+        let mut dual = DualStack::new(v4, Some(v6));
+        let peers = dual.lookup_peers(info_hash);
+        assert_eq!(peers, vec!["127.0.0.1:1000".parse().unwrap(), "[::1]:2000".parse().unwrap()]);
+    }
+}
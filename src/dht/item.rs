@@ -0,0 +1,261 @@
+use crate::bencode::Value;
+use crate::dht::krpc::{
+    KrpcError, Response, CAS_MISMATCH, INVALID_SIGNATURE, MESSAGE_TOO_BIG, SALT_TOO_BIG, SEQUENCE_TOO_LOW
+};
+use crate::dht::{Dht, NodeId, NodeInfo};
+use crate::hash::sha1;
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use std::time::Duration;
+
+pub const MAX_VALUE_LEN: usize = 1000;
+pub const MAX_SALT_LEN: usize = 64;
+pub const ITEM_EXPIRY: Duration = Duration::from_secs(2 * 60 * 60);
+
+// A BEP 44 item. Immutable items are stored under the SHA-1 of their
+// bencoded value, mutable ones under the SHA-1 of their public key and salt.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Item {
+    Immutable(Value),
+    Mutable(MutableItem)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MutableItem {
+    pub key: [u8; 32],
+    pub salt: Vec<u8>,
+    pub seq: i64,
+    pub value: Value,
+    pub signature: [u8; 64]
+}
+
+pub fn immutable_target(value: &Value) -> NodeId {
+    NodeId(sha1(&value.encode()))
+}
+
+pub fn mutable_target(key: &[u8; 32], salt: &[u8]) -> NodeId {
+    NodeId(sha1(&[&key[..], salt].concat()))
+}
+
+// What the signature covers: the salt (if any), seq and value as they
+// would appear in a bencoded dictionary, without the surrounding `d` and `e`.
+fn signed_bytes(salt: &[u8], seq: i64, value: &Value) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    if !salt.is_empty() {
+        bytes.extend(format!("4:salt{}:", salt.len()).bytes());
+        bytes.extend(salt);
+    }
+    bytes.extend(format!("3:seqi{}e1:v", seq).bytes());
+    value.encode_into(&mut bytes);
+    bytes
+}
+
+impl MutableItem {
+    pub fn public_key(secret: &[u8; 32]) -> [u8; 32] {
+        SigningKey::from_bytes(secret).verifying_key().to_bytes()
+    }
+
+    pub fn sign(secret: &[u8; 32], salt: &[u8], seq: i64, value: Value) -> Self {
+        let signing = SigningKey::from_bytes(secret);
+        let signature = signing.sign(&signed_bytes(salt, seq, &value));
+        Self {
+            key: signing.verifying_key().to_bytes(),
+            salt: salt.to_vec(),
+            seq,
+            value,
+            signature: signature.to_bytes()
+        }
+    }
+
+    pub fn verify(&self) -> bool {
+        VerifyingKey::from_bytes(&self.key)
+            .and_then(|key| key.verify(&signed_bytes(&self.salt, self.seq, &self.value), &Signature::from_bytes(&self.signature)))
+            .is_ok()
+    }
+
+    pub fn target(&self) -> NodeId {
+        mutable_target(&self.key, &self.salt)
+    }
+}
+
+impl Item {
+    pub fn value(&self) -> &Value {
+        match self {
+            Item::Immutable(value) => value,
+            Item::Mutable(item) => &item.value
+        }
+    }
+
+    pub fn target(&self) -> NodeId {
+        match self {
+            Item::Immutable(value) => immutable_target(value),
+            Item::Mutable(item) => item.target()
+        }
+    }
+
+    pub fn seq(&self) -> Option<i64> {
+        match self {
+            Item::Immutable(_) => None,
+            Item::Mutable(item) => Some(item.seq)
+        }
+    }
+
+    // The checks a node makes before storing a put.
+    pub fn validate(&self) -> Result<(), KrpcError> {
+        if self.value().encode().len() > MAX_VALUE_LEN {
+            return Err(KrpcError::new(MESSAGE_TOO_BIG, "message (v field) too big"));
+        }
+        match self {
+            Item::Immutable(_) => Ok(()),
+            Item::Mutable(item) if item.salt.len() > MAX_SALT_LEN => Err(KrpcError::new(SALT_TOO_BIG, "salt too big")),
+            Item::Mutable(item) if !item.verify() => Err(KrpcError::new(INVALID_SIGNATURE, "invalid signature")),
+            Item::Mutable(_) => Ok(())
+        }
+    }
+
+    // Whether a put of `self` may overwrite `stored`. With `cas`, the put
+    // only succeeds if the stored sequence number is still the expected one.
+    pub fn replaces(&self, stored: &Item, cas: Option<i64>) -> Result<(), KrpcError> {
+        let (Item::Mutable(new), Item::Mutable(old)) = (self, stored) else {
+            return Ok(());
+        };
+        if cas.is_some_and(|cas| cas != old.seq) {
+            return Err(KrpcError::new(CAS_MISMATCH, "CAS mismatch"));
+        }
+        if new.seq < old.seq || (new.seq == old.seq && new.value != old.value) {
+            return Err(KrpcError::new(SEQUENCE_TOO_LOW, "sequence number less than current"));
+        }
+        Ok(())
+    }
+
+    // The item in a get response, if it is the one we asked for and its
+    // signature checks out.
+    pub fn from_response(response: &Response, target: &NodeId, salt: &[u8]) -> Option<Self> {
+        let value = response.value.clone()?;
+        let item = match response.key {
+            Some(key) => Item::Mutable(MutableItem {
+                key,
+                salt: salt.to_vec(),
+                seq: response.seq?,
+                value,
+                signature: response.signature?
+            }),
+            None => Item::Immutable(value)
+        };
+        (item.target() == *target && item.validate().is_ok()).then_some(item)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ItemLookup {
+    pub item: Option<Item>,
+    // The closest nodes that answered, with the token each handed out.
+    pub nodes: Vec<(NodeInfo, Vec<u8>)>
+}
+
+impl Dht {
+    // Iterative get. When nodes disagree about a mutable item, the one with
+    // the highest sequence number wins.
+    pub fn get(&mut self, target: NodeId, salt: &[u8]) -> ItemLookup {
+        let mut found: Option<Item> = None;
+        let nodes = self.lookup_tokens(target, |dht, addr| {
+            let response = dht.get_item(addr, target, salt, None)?;
+            if let Some(item) = response.item {
+                if found.as_ref().is_none_or(|best| item.seq() > best.seq()) {
+                    found = Some(item);
+                }
+            }
+            Ok((response.nodes, response.token))
+        });
+        ItemLookup { item: found, nodes }
+    }
+
+    // Stores the item on the closest nodes to its target. Returns how many
+    // of them accepted it.
+    pub fn put(&mut self, item: &Item, cas: Option<i64>) -> usize {
+        let lookup = self.get(item.target(), &[]);
+        lookup.nodes
+            .iter()
+            .filter(|(node, token)| self.put_item(node.addr, token, item, cas).is_ok())
+            .count()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::bencode::Value;
+    use crate::dht::item::{immutable_target, signed_bytes, Item, MutableItem};
+    use crate::dht::krpc::{Response, CAS_MISMATCH, INVALID_SIGNATURE, MESSAGE_TOO_BIG, SEQUENCE_TOO_LOW};
+    use crate::dht::{Dht, NodeId};
+    use std::thread;
+
+    #[test]
+    fn test_immutable_target() {
+        // The example from BEP 44.
+        let target = immutable_target(&Value::from("Hello World!"));
+        assert_eq!(format!("{:?}", target), "e5f96f6f38320f0f33959cb4d3d656452117aadb");
+
+        let big = Item::Immutable(Value::from(&[0; 1000][..]));
+        assert_eq!(big.validate().unwrap_err().code, MESSAGE_TOO_BIG);
+    }
+
+    #[test]
+    fn test_signed_mutable_item() {
+        assert_eq!(signed_bytes(b"foobar", 1, &"Hello World!".into()), b"4:salt6:foobar3:seqi1e1:v12:Hello World!");
+
+        let secret = [7; 32];
+        let item = MutableItem::sign(&secret, b"foobar", 1, "Hello World!".into());
+        assert_eq!(item.key, MutableItem::public_key(&secret));
+        assert!(item.verify());
+        assert!(Item::Mutable(item.clone()).validate().is_ok());
+
+        let mut forged = item.clone();
+        forged.seq = 2;
+        assert_eq!(Item::Mutable(forged).validate().unwrap_err().code, INVALID_SIGNATURE);
+
+        let mut response = Response::new(NodeId([1; 20]));
+        response.value = Some(item.value.clone());
+        response.key = Some(item.key);
+        response.signature = Some(item.signature);
+        response.seq = Some(item.seq);
+        let target = item.target();
+        assert_eq!(Item::from_response(&response, &target, b"foobar"), Some(Item::Mutable(item)));
+        assert_eq!(Item::from_response(&response, &target, b"other"), None);
+    }
+
+    #[test]
+    fn test_replaces() {
+        let secret = [7; 32];
+        let old = Item::Mutable(MutableItem::sign(&secret, b"", 2, "old".into()));
+        let newer = Item::Mutable(MutableItem::sign(&secret, b"", 3, "new".into()));
+        let older = Item::Mutable(MutableItem::sign(&secret, b"", 1, "new".into()));
+
+        assert!(newer.replaces(&old, None).is_ok());
+        assert!(newer.replaces(&old, Some(2)).is_ok());
+        assert_eq!(newer.replaces(&old, Some(1)).unwrap_err().code, CAS_MISMATCH);
+        assert_eq!(older.replaces(&old, None).unwrap_err().code, SEQUENCE_TOO_LOW);
+        assert!(old.replaces(&old, None).is_ok());
+    }
+
+    #[test]
+    fn test_put_and_get() {
+        let mut remote = Dht::with_id("127.0.0.1:0", NodeId([9; 20])).unwrap();
+        let remote_addr = remote.local_addr().unwrap();
+        thread::spawn(move || remote.run());
+
+        let mut dht = Dht::bind("127.0.0.1:0").unwrap();
+        dht.ping(remote_addr).unwrap();
+
+        let immutable = Item::Immutable("Hello World!".into());
+        assert_eq!(dht.put(&immutable, None), 1);
+        assert_eq!(dht.get(immutable.target(), b"").item, Some(immutable));
+
+        let secret = [7; 32];
+        let first = Item::Mutable(MutableItem::sign(&secret, b"salt", 1, "first".into()));
+        let second = Item::Mutable(MutableItem::sign(&secret, b"salt", 2, "second".into()));
+        assert_eq!(dht.put(&first, None), 1);
+        assert_eq!(dht.put(&second, Some(5)), 0);
+        assert_eq!(dht.put(&second, Some(1)), 1);
+        assert_eq!(dht.put(&first, None), 0);
+        assert_eq!(dht.get(second.target(), b"salt").item, Some(second));
+    }
+}
@@ -0,0 +1,624 @@
+use crate::bencode::{self, Value};
+use crate::dht::item::{Item, MutableItem};
+use crate::dht::{
+    decode_nodes, decode_nodes6, decode_peer, encode_compact, encode_nodes, encode_nodes6, Family, NodeId, NodeInfo
+};
+use std::collections::HashMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+pub const GENERIC_ERROR: i64 = 201;
+pub const SERVER_ERROR: i64 = 202;
+pub const PROTOCOL_ERROR: i64 = 203;
+pub const METHOD_UNKNOWN: i64 = 204;
+pub const MESSAGE_TOO_BIG: i64 = 205;
+pub const INVALID_SIGNATURE: i64 = 206;
+pub const SALT_TOO_BIG: i64 = 207;
+pub const CAS_MISMATCH: i64 = 301;
+pub const SEQUENCE_TOO_LOW: i64 = 302;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Query {
+    Ping { id: NodeId },
+    // `want` (BEP 32) asks for nodes of these families. Left empty, the
+    // answer has nodes of the family the query came in on.
+    FindNode { id: NodeId, target: NodeId, want: Vec<Family> },
+    GetPeers { id: NodeId, info_hash: [u8; 20], want: Vec<Family> },
+    // A `port` of `None` means `implied_port`: use the UDP source port.
+    AnnouncePeer { id: NodeId, info_hash: [u8; 20], port: Option<u16>, token: Vec<u8> },
+    // BEP 44. `seq` asks for a mutable item only if it is newer than that.
+    Get { id: NodeId, target: NodeId, seq: Option<i64> },
+    Put { id: NodeId, token: Vec<u8>, item: Item, cas: Option<i64> },
+    // BEP 51.
+    SampleInfohashes { id: NodeId, target: NodeId }
+}
+
+impl Query {
+    pub fn method(&self) -> &'static str {
+        match self {
+            Query::Ping { .. } => "ping",
+            Query::FindNode { .. } => "find_node",
+            Query::GetPeers { .. } => "get_peers",
+            Query::AnnouncePeer { .. } => "announce_peer",
+            Query::Get { .. } => "get",
+            Query::Put { .. } => "put",
+            Query::SampleInfohashes { .. } => "sample_infohashes"
+        }
+    }
+
+    pub fn id(&self) -> NodeId {
+        match self {
+            Query::Ping { id }
+                | Query::FindNode { id, .. }
+                | Query::GetPeers { id, .. }
+                | Query::AnnouncePeer { id, .. }
+                | Query::Get { id, .. }
+                | Query::Put { id, .. }
+                | Query::SampleInfohashes { id, .. } => *id
+        }
+    }
+
+    fn args(&self) -> Value {
+        let mut args = vec![("id", Value::from(&self.id().0[..]))];
+        match self {
+            Query::Ping { .. } => {},
+            Query::SampleInfohashes { target, .. } => args.push(("target", Value::from(&target.0[..]))),
+            Query::FindNode { target, want, .. } => {
+                args.push(("target", Value::from(&target.0[..])));
+                push_want(&mut args, want);
+            },
+            Query::GetPeers { info_hash, want, .. } => {
+                args.push(("info_hash", Value::from(&info_hash[..])));
+                push_want(&mut args, want);
+            },
+            Query::AnnouncePeer { info_hash, port, token, .. } => {
+                args.push(("info_hash", Value::from(&info_hash[..])));
+                args.push(("token", Value::from(&token[..])));
+                match port {
+                    Some(port) => args.push(("port", Value::Integer(*port as i64))),
+                    None => args.push(("implied_port", Value::Integer(1)))
+                }
+            },
+            Query::Get { target, seq, .. } => {
+                args.push(("target", Value::from(&target.0[..])));
+                if let Some(seq) = seq {
+                    args.push(("seq", Value::Integer(*seq)));
+                }
+            },
+            Query::Put { token, item, cas, .. } => {
+                args.push(("token", Value::from(&token[..])));
+                args.push(("v", item.value().clone()));
+                if let Item::Mutable(item) = item {
+                    args.push(("k", Value::from(&item.key[..])));
+                    args.push(("seq", Value::Integer(item.seq)));
+                    args.push(("sig", Value::from(&item.signature[..])));
+                    if !item.salt.is_empty() {
+                        args.push(("salt", Value::from(&item.salt[..])));
+                    }
+                }
+                if let Some(cas) = cas {
+                    args.push(("cas", Value::Integer(*cas)));
+                }
+            }
+        }
+        Value::dict(args)
+    }
+
+    fn from_args(method: &[u8], args: &Value) -> Result<Self, KrpcError> {
+        let id = node_id(args, "id")?;
+        match method {
+            b"ping" => Ok(Query::Ping { id }),
+            b"find_node" => Ok(Query::FindNode { id, target: node_id(args, "target")?, want: want(args) }),
+            b"get_peers" => Ok(Query::GetPeers { id, info_hash: node_id(args, "info_hash")?.0, want: want(args) }),
+            b"announce_peer" => {
+                let implied_port = args.get("implied_port").and_then(Value::as_int) == Some(1);
+                let port = match implied_port {
+                    true => None,
+                    false => Some(
+                        args.get("port")
+                            .and_then(Value::as_int)
+                            .and_then(|port| u16::try_from(port).ok())
+                            .ok_or_else(|| KrpcError::protocol("missing port"))?
+                    )
+                };
+                Ok(Query::AnnouncePeer { id, info_hash: node_id(args, "info_hash")?.0, port, token: token(args)? })
+            },
+            b"sample_infohashes" => Ok(Query::SampleInfohashes { id, target: node_id(args, "target")? }),
+            b"get" => Ok(Query::Get {
+                id,
+                target: node_id(args, "target")?,
+                seq: args.get("seq").and_then(Value::as_int)
+            }),
+            b"put" => {
+                let value = args
+                    .get("v")
+                    .ok_or_else(|| KrpcError::protocol("missing v"))?
+                    .clone();
+                let item = match args.get("k") {
+                    None => Item::Immutable(value),
+                    Some(key) => Item::Mutable(MutableItem {
+                        key: fixed(key, "k")?,
+                        salt: args.get("salt").and_then(Value::as_bytes).unwrap_or_default().to_vec(),
+                        seq: args
+                            .get("seq")
+                            .and_then(Value::as_int)
+                            .ok_or_else(|| KrpcError::protocol("missing seq"))?,
+                        value,
+                        signature: fixed(args.get("sig").ok_or_else(|| KrpcError::protocol("missing sig"))?, "sig")?
+                    })
+                };
+                let cas = args.get("cas").and_then(Value::as_int);
+                Ok(Query::Put { id, token: token(args)?, item, cas })
+            },
+            _ => Err(KrpcError::new(METHOD_UNKNOWN, "method unknown"))
+        }
+    }
+}
+
+fn push_want(args: &mut Vec<(&str, Value)>, want: &[Family]) {
+    if want.is_empty() {
+        return;
+    }
+    let want = want
+        .iter()
+        .map(|family| match family {
+            Family::V4 => Value::from("n4"),
+            Family::V6 => Value::from("n6")
+        })
+        .collect::<Vec<_>>();
+    args.push(("want", want.into()));
+}
+
+fn want(args: &Value) -> Vec<Family> {
+    args.get("want")
+        .and_then(Value::as_list)
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|family| match family.as_bytes()? {
+            b"n4" => Some(Family::V4),
+            b"n6" => Some(Family::V6),
+            _ => None
+        })
+        .collect()
+}
+
+// Responses are not self-describing; which fields are present depends on
+// the query they answer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Response {
+    pub id: NodeId,
+    // Both families; they go out as `nodes` and `nodes6`.
+    pub nodes: Vec<NodeInfo>,
+    pub values: Vec<SocketAddr>,
+    pub token: Option<Vec<u8>>,
+    // A stored BEP 44 item. The salt is not sent back; the caller knows it.
+    pub value: Option<Value>,
+    pub key: Option<[u8; 32]>,
+    pub signature: Option<[u8; 64]>,
+    pub seq: Option<i64>,
+    // BEP 51: seconds until the samples change, the number of info hashes
+    // stored and a sample of them.
+    pub interval: Option<i64>,
+    pub num: Option<i64>,
+    pub samples: Option<Vec<[u8; 20]>>
+}
+
+impl Response {
+    pub fn new(id: NodeId) -> Self {
+        Self {
+            id,
+            nodes: Vec::new(),
+            values: Vec::new(),
+            token: None,
+            value: None,
+            key: None,
+            signature: None,
+            seq: None,
+            interval: None,
+            num: None,
+            samples: None
+        }
+    }
+
+    fn to_value(&self) -> Value {
+        let mut fields = vec![("id", Value::from(&self.id.0[..]))];
+        let nodes = encode_nodes(&self.nodes);
+        if !nodes.is_empty() {
+            fields.push(("nodes", nodes.into()));
+        }
+        let nodes6 = encode_nodes6(&self.nodes);
+        if !nodes6.is_empty() {
+            fields.push(("nodes6", nodes6.into()));
+        }
+        if !self.values.is_empty() {
+            let values = self.values
+                .iter()
+                .map(|&peer| Value::from(encode_compact(peer)))
+                .collect::<Vec<_>>();
+            fields.push(("values", values.into()));
+        }
+        if let Some(token) = &self.token {
+            fields.push(("token", Value::from(&token[..])));
+        }
+        if let Some(value) = &self.value {
+            fields.push(("v", value.clone()));
+        }
+        if let Some(key) = &self.key {
+            fields.push(("k", Value::from(&key[..])));
+        }
+        if let Some(signature) = &self.signature {
+            fields.push(("sig", Value::from(&signature[..])));
+        }
+        if let Some(seq) = self.seq {
+            fields.push(("seq", Value::Integer(seq)));
+        }
+        if let Some(interval) = self.interval {
+            fields.push(("interval", Value::Integer(interval)));
+        }
+        if let Some(num) = self.num {
+            fields.push(("num", Value::Integer(num)));
+        }
+        if let Some(samples) = &self.samples {
+            fields.push(("samples", samples.concat().into()));
+        }
+        Value::dict(fields)
+    }
+
+    fn from_value(value: &Value) -> Result<Self, KrpcError> {
+        let mut nodes = Vec::new();
+        for (key, decode) in [("nodes", decode_nodes as fn(&[u8]) -> _), ("nodes6", decode_nodes6)] {
+            if let Some(list) = value.get(key) {
+                nodes.extend(
+                    list.as_bytes()
+                        .and_then(decode)
+                        .ok_or_else(|| KrpcError::protocol(&format!("malformed {}", key)))?
+                );
+            }
+        }
+        let values = value
+            .get("values")
+            .and_then(Value::as_list)
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|peer| decode_peer(peer.as_bytes()?))
+            .collect();
+
+        Ok(Self {
+            id: node_id(value, "id")?,
+            nodes,
+            values,
+            token: value.get("token").and_then(Value::as_bytes).map(<[u8]>::to_vec),
+            value: value.get("v").cloned(),
+            key: value.get("k").map(|key| fixed(key, "k")).transpose()?,
+            signature: value.get("sig").map(|signature| fixed(signature, "sig")).transpose()?,
+            seq: value.get("seq").and_then(Value::as_int),
+            interval: value.get("interval").and_then(Value::as_int),
+            num: value.get("num").and_then(Value::as_int),
+            samples: value.get("samples").map(samples).transpose()?
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KrpcError {
+    pub code: i64,
+    pub message: String
+}
+
+impl KrpcError {
+    pub fn new(code: i64, message: &str) -> Self {
+        Self { code, message: message.into() }
+    }
+
+    pub fn protocol(message: &str) -> Self {
+        Self::new(PROTOCOL_ERROR, message)
+    }
+}
+
+impl fmt::Display for KrpcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({})", self.message, self.code)
+    }
+}
+
+impl std::error::Error for KrpcError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Body {
+    Query(Query),
+    Response(Response),
+    Error(KrpcError)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    pub transaction: Vec<u8>,
+    pub body: Body,
+    // BEP 42: the address the responder saw our query come from.
+    pub ip: Option<SocketAddr>
+}
+
+// A packet we could not make sense of. If it carried a transaction id the
+// sender can be told what was wrong with it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecodeError {
+    pub transaction: Option<Vec<u8>>,
+    pub error: KrpcError
+}
+
+fn node_id(value: &Value, key: &str) -> Result<NodeId, KrpcError> {
+    value
+        .get(key)
+        .and_then(Value::as_bytes)
+        .and_then(NodeId::from_bytes)
+        .ok_or_else(|| KrpcError::protocol(&format!("missing {}", key)))
+}
+
+fn samples(value: &Value) -> Result<Vec<[u8; 20]>, KrpcError> {
+    value
+        .as_bytes()
+        .filter(|bytes| bytes.len().is_multiple_of(20))
+        .map(|bytes| bytes.chunks(20).map(|hash| hash.try_into().unwrap()).collect())
+        .ok_or_else(|| KrpcError::protocol("malformed samples"))
+}
+
+fn token(args: &Value) -> Result<Vec<u8>, KrpcError> {
+    args.get("token")
+        .and_then(Value::as_bytes)
+        .map(<[u8]>::to_vec)
+        .ok_or_else(|| KrpcError::protocol("missing token"))
+}
+
+fn fixed<const N: usize>(value: &Value, key: &str) -> Result<[u8; N], KrpcError> {
+    value
+        .as_bytes()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| KrpcError::protocol(&format!("malformed {}", key)))
+}
+
+impl Message {
+    pub fn query(transaction: Vec<u8>, query: Query) -> Self {
+        Self { transaction, body: Body::Query(query), ip: None }
+    }
+
+    pub fn response(transaction: Vec<u8>, response: Response) -> Self {
+        Self { transaction, body: Body::Response(response), ip: None }
+    }
+
+    pub fn error(transaction: Vec<u8>, error: KrpcError) -> Self {
+        Self { transaction, body: Body::Error(error), ip: None }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut fields = vec![("t", Value::from(&self.transaction[..]))];
+        match &self.body {
+            Body::Query(query) => {
+                fields.push(("y", "q".into()));
+                fields.push(("q", query.method().into()));
+                fields.push(("a", query.args()));
+            },
+            Body::Response(response) => {
+                fields.push(("y", "r".into()));
+                fields.push(("r", response.to_value()));
+            },
+            Body::Error(error) => {
+                fields.push(("y", "e".into()));
+                fields.push(("e", Value::List(vec![error.code.into(), error.message.as_str().into()])));
+            }
+        }
+        if let Some(ip) = self.ip {
+            fields.push(("ip", encode_compact(ip).into()));
+        }
+        Value::dict(fields).encode()
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let invalid = |transaction: Option<Vec<u8>>, message: &str| DecodeError {
+            transaction,
+            error: KrpcError::protocol(message)
+        };
+
+        let value = bencode::decode(bytes)
+            .filter(|value| value.as_dict().is_some())
+            .ok_or_else(|| invalid(None, "invalid bencode"))?;
+        let transaction = value
+            .get("t")
+            .and_then(Value::as_bytes)
+            .map(<[u8]>::to_vec)
+            .ok_or_else(|| invalid(None, "missing transaction id"))?;
+        let fail = |error: KrpcError| DecodeError { transaction: Some(transaction.clone()), error };
+
+        let body = match value.get("y").and_then(Value::as_bytes) {
+            Some(b"q") => {
+                let method = value
+                    .get("q")
+                    .and_then(Value::as_bytes)
+                    .ok_or_else(|| fail(KrpcError::protocol("missing method")))?;
+                let args = value
+                    .get("a")
+                    .filter(|args| args.as_dict().is_some())
+                    .ok_or_else(|| fail(KrpcError::protocol("missing arguments")))?;
+                Body::Query(Query::from_args(method, args).map_err(fail)?)
+            },
+            Some(b"r") => {
+                let response = value
+                    .get("r")
+                    .ok_or_else(|| fail(KrpcError::protocol("missing response")))?;
+                Body::Response(Response::from_value(response).map_err(fail)?)
+            },
+            Some(b"e") => {
+                let error = value.get("e").and_then(Value::as_list).unwrap_or_default();
+                Body::Error(KrpcError {
+                    code: error.first().and_then(Value::as_int).unwrap_or(GENERIC_ERROR),
+                    message: error.get(1).and_then(Value::as_str).unwrap_or_default().into()
+                })
+            },
+            _ => return Err(fail(KrpcError::protocol("invalid message type")))
+        };
+
+        let ip = value.get("ip").and_then(Value::as_bytes).and_then(decode_peer);
+        Ok(Self { transaction, body, ip })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Pending {
+    pub addr: SocketAddr,
+    pub sent: Instant
+}
+
+// Hands out transaction ids for outgoing queries and matches responses to
+// them. A response only matches if it comes from the node we asked.
+pub struct Transactions {
+    next: u16,
+    pending: HashMap<Vec<u8>, Pending>,
+    timeout: Duration
+}
+
+impl Transactions {
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            next: u16::from_be_bytes(crate::dht::random_bytes()),
+            pending: HashMap::new(),
+            timeout
+        }
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    pub fn contains(&self, transaction: &[u8]) -> bool {
+        self.pending.contains_key(transaction)
+    }
+
+    pub fn start(&mut self, addr: SocketAddr, now: Instant) -> Vec<u8> {
+        loop {
+            self.next = self.next.wrapping_add(1);
+            let transaction = self.next.to_be_bytes().to_vec();
+            if !self.pending.contains_key(&transaction) {
+                self.pending.insert(transaction.clone(), Pending { addr, sent: now });
+                return transaction;
+            }
+        }
+    }
+
+    pub fn finish(&mut self, transaction: &[u8], from: SocketAddr) -> Option<Pending> {
+        match self.pending.get(transaction) {
+            Some(pending) if pending.addr == from => self.pending.remove(transaction),
+            _ => None
+        }
+    }
+
+    // Drops and returns the transactions that have timed out.
+    pub fn expire(&mut self, now: Instant) -> Vec<(Vec<u8>, Pending)> {
+        let expired: Vec<_> = self.pending
+            .iter()
+            .filter(|(_, pending)| now.duration_since(pending.sent) >= self.timeout)
+            .map(|(transaction, _)| transaction.clone())
+            .collect();
+
+        expired
+            .into_iter()
+            .filter_map(|transaction| {
+                let pending = self.pending.remove(&transaction)?;
+                Some((transaction, pending))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::dht::krpc::{Body, KrpcError, Message, Query, Response, Transactions, METHOD_UNKNOWN, PROTOCOL_ERROR};
+    use crate::dht::{Family, NodeId, NodeInfo};
+    use std::net::SocketAddr;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_query_roundtrip() {
+        let message = Message::query(
+            b"aa".to_vec(),
+            Query::AnnouncePeer { id: NodeId([1; 20]), info_hash: [2; 20], port: None, token: b"tok".to_vec() }
+        );
+        let bytes = message.encode();
+        assert!(bytes.starts_with(b"d1:ad2:id20:"));
+        assert_eq!(Message::decode(&bytes), Ok(message));
+
+        let ping = b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe";
+        assert_eq!(
+            Message::decode(ping).unwrap().body,
+            Body::Query(Query::Ping { id: NodeId(*b"abcdefghij0123456789") })
+        );
+        let find_node = Message::query(
+            b"ab".to_vec(),
+            Query::FindNode { id: NodeId([1; 20]), target: NodeId([2; 20]), want: vec![Family::V4, Family::V6] }
+        );
+        let bytes = find_node.encode();
+        assert!(bytes.windows(16).any(|window| window == b"4:wantl2:n42:n6e"));
+        assert_eq!(Message::decode(&bytes), Ok(find_node));
+    }
+
+    #[test]
+    fn test_response_and_error_roundtrip() {
+        let mut response = Response::new(NodeId([3; 20]));
+        response.nodes.push(NodeInfo { id: NodeId([4; 20]), addr: SocketAddr::from(([1, 2, 3, 4], 5)) });
+        response.nodes.push(NodeInfo { id: NodeId([5; 20]), addr: "[2001:db8::1]:5".parse().unwrap() });
+        response.values.push(SocketAddr::from(([5, 6, 7, 8], 9)));
+        response.values.push("[2001:db8::2]:9".parse().unwrap());
+        response.token = Some(b"xyz".to_vec());
+        response.interval = Some(300);
+        response.num = Some(2);
+        response.samples = Some(vec![[6; 20], [7; 20]]);
+
+        let mut message = Message::response(b"t1".to_vec(), response);
+        message.ip = Some(SocketAddr::from(([9, 9, 9, 9], 6881)));
+        assert_eq!(Message::decode(&message.encode()), Ok(message));
+
+        let error = Message::error(b"t2".to_vec(), KrpcError::new(201, "A Generic Error Ocurred"));
+        assert_eq!(error.encode(), b"d1:eli201e23:A Generic Error Ocurrede1:t2:t21:y1:ee");
+        assert_eq!(Message::decode(&error.encode()), Ok(error));
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        let unknown = b"d1:ad2:id20:abcdefghij0123456789e1:q4:nope1:t2:aa1:y1:qe";
+        let error = Message::decode(unknown).unwrap_err();
+        assert_eq!(error.transaction, Some(b"aa".to_vec()));
+        assert_eq!(error.error.code, METHOD_UNKNOWN);
+
+        let no_id = b"d1:ade1:q4:ping1:t2:aa1:y1:qe";
+        assert_eq!(Message::decode(no_id).unwrap_err().error.code, PROTOCOL_ERROR);
+        assert_eq!(Message::decode(b"garbage").unwrap_err().transaction, None);
+    }
+
+    #[test]
+    fn test_transactions() {
+        let mut transactions = Transactions::new(Duration::from_secs(5));
+        let a = SocketAddr::from(([127, 0, 0, 1], 1));
+        let b = SocketAddr::from(([127, 0, 0, 1], 2));
+        let now = Instant::now();
+
+        let first = transactions.start(a, now);
+        let second = transactions.start(b, now);
+        assert_ne!(first, second);
+
+        assert_eq!(transactions.finish(&first, b), None);
+        assert!(transactions.finish(&first, a).is_some());
+        assert_eq!(transactions.finish(&first, a), None);
+
+        let expired = transactions.expire(now + Duration::from_secs(5));
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].0, second);
+        assert!(transactions.is_empty());
+    }
+}
@@ -0,0 +1,100 @@
+use crate::dht::{Dht, NodeId, NodeInfo, K};
+use std::collections::HashSet;
+use std::io;
+use std::net::SocketAddr;
+
+pub const ALPHA: usize = 3;
+
+impl Dht {
+    // Iterative find_node: keep asking the closest nodes we know of that
+    // have not been asked yet, until the K closest have all answered or
+    // failed. `start` are extra addresses to ask first, e.g. routers whose
+    // ids we do not know.
+    pub fn lookup_nodes(&mut self, target: NodeId, start: &[SocketAddr]) -> Vec<NodeInfo> {
+        let mut queried = HashSet::new();
+        let mut shortlist = self.closest_nodes(&target, K);
+
+        for &addr in start {
+            queried.insert(addr);
+            if let Ok(nodes) = self.find_node(addr, target) {
+                shortlist.extend(nodes);
+            }
+        }
+
+        let own = self.id();
+        loop {
+            shortlist.retain(|node| node.id != own);
+            shortlist.sort_by_key(|node| node.id.distance(&target));
+            shortlist.dedup_by_key(|node| node.id);
+
+            let next: Vec<_> = shortlist
+                .iter()
+                .take(K)
+                .filter(|node| !queried.contains(&node.addr))
+                .take(ALPHA)
+                .copied()
+                .collect();
+            if next.is_empty() {
+                break;
+            }
+
+            for node in next {
+                queried.insert(node.addr);
+                match self.find_node(node.addr, target) {
+                    Ok(nodes) => shortlist.extend(nodes),
+                    Err(_) => shortlist.retain(|known| known.addr != node.addr)
+                }
+            }
+        }
+
+        shortlist.truncate(K);
+        shortlist
+    }
+
+    // The iterative lookup behind get_peers and get: `query` asks one node
+    // and returns the nodes it told us about and its write token. Returns
+    // the K closest nodes that handed out a token, closest first.
+    pub(crate) fn lookup_tokens<F>(&mut self, target: NodeId, mut query: F) -> Vec<(NodeInfo, Vec<u8>)>
+    where
+        F: FnMut(&mut Self, SocketAddr) -> io::Result<(Vec<NodeInfo>, Option<Vec<u8>>)>
+    {
+        let mut queried = HashSet::new();
+        let mut shortlist = self.closest_nodes(&target, K);
+        let mut answered = Vec::new();
+
+        let own = self.id();
+        loop {
+            shortlist.retain(|node| node.id != own);
+            shortlist.sort_by_key(|node| node.id.distance(&target));
+            shortlist.dedup_by_key(|node| node.id);
+
+            let next: Vec<_> = shortlist
+                .iter()
+                .take(K)
+                .filter(|node| !queried.contains(&node.addr))
+                .take(ALPHA)
+                .copied()
+                .collect();
+            if next.is_empty() {
+                break;
+            }
+
+            for node in next {
+                queried.insert(node.addr);
+                match query(self, node.addr) {
+                    Ok((nodes, token)) => {
+                        shortlist.extend(nodes);
+                        if let Some(token) = token {
+                            answered.push((node, token));
+                        }
+                    },
+                    Err(_) => shortlist.retain(|known| known.addr != node.addr)
+                }
+            }
+        }
+
+        answered.sort_by_key(|(node, _): &(NodeInfo, Vec<u8>)| node.id.distance(&target));
+        answered.truncate(K);
+        answered
+    }
+}
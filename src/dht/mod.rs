@@ -0,0 +1,154 @@
+pub mod announce;
+pub mod bootstrap;
+pub mod dual;
+pub mod item;
+pub mod krpc;
+mod lookup;
+mod node;
+pub mod routing;
+pub mod security;
+pub mod state;
+
+pub use crate::compact::{decode_peer, encode_compact, encode_peer, encode_peer6, COMPACT_PEER6_LEN, COMPACT_PEER_LEN};
+pub(crate) use crate::hash::random_bytes;
+pub use node::{Dht, GetItem, GetPeers, Samples};
+
+use std::fmt;
+use std::net::SocketAddr;
+
+pub const K: usize = 8;
+pub const COMPACT_NODE_LEN: usize = 26;
+pub const COMPACT_NODE6_LEN: usize = 38;
+
+// BEP 32: IPv4 and IPv6 nodes live in separate DHTs, one per socket.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Family {
+    V4,
+    V6
+}
+
+impl Family {
+    pub fn of(addr: &SocketAddr) -> Self {
+        match addr {
+            SocketAddr::V4(_) => Family::V4,
+            SocketAddr::V6(_) => Family::V6
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct NodeId(pub [u8; 20]);
+
+impl fmt::Debug for NodeId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0
+            .iter()
+            .try_for_each(|byte| write!(f, "{:02x}", byte))
+    }
+}
+
+impl NodeId {
+    pub fn random() -> Self {
+        NodeId(random_bytes())
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        Some(NodeId(bytes.try_into().ok()?))
+    }
+
+    pub fn distance(&self, other: &NodeId) -> [u8; 20] {
+        let mut distance = [0; 20];
+        for (i, byte) in distance.iter_mut().enumerate() {
+            *byte = self.0[i] ^ other.0[i];
+        }
+        distance
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct NodeInfo {
+    pub id: NodeId,
+    pub addr: SocketAddr
+}
+
+// Compact node info: 20 byte id, then the IPv4 address and port.
+pub fn encode_nodes(nodes: &[NodeInfo]) -> Vec<u8> {
+    nodes
+        .iter()
+        .filter_map(|node| {
+            let peer = encode_peer(node.addr)?;
+            Some(node.id.0.iter().copied().chain(peer).collect::<Vec<_>>())
+        })
+        .flatten()
+        .collect()
+}
+
+pub fn decode_nodes(bytes: &[u8]) -> Option<Vec<NodeInfo>> {
+    decode_nodes_of(bytes, COMPACT_NODE_LEN)
+}
+
+// The `nodes6` key: the same with 16 byte addresses.
+pub fn encode_nodes6(nodes: &[NodeInfo]) -> Vec<u8> {
+    nodes
+        .iter()
+        .filter_map(|node| {
+            let peer = encode_peer6(node.addr)?;
+            Some(node.id.0.iter().copied().chain(peer).collect::<Vec<_>>())
+        })
+        .flatten()
+        .collect()
+}
+
+pub fn decode_nodes6(bytes: &[u8]) -> Option<Vec<NodeInfo>> {
+    decode_nodes_of(bytes, COMPACT_NODE6_LEN)
+}
+
+fn decode_nodes_of(bytes: &[u8], len: usize) -> Option<Vec<NodeInfo>> {
+    if !bytes.len().is_multiple_of(len) {
+        return None;
+    }
+    bytes
+        .chunks(len)
+        .map(|chunk| {
+            Some(NodeInfo {
+                id: NodeId::from_bytes(&chunk[..20])?,
+                addr: decode_peer(&chunk[20..])?
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use crate::dht::{decode_nodes, decode_nodes6, decode_peer, encode_nodes, encode_nodes6, encode_peer, NodeId, NodeInfo};
+    use std::net::SocketAddr;
+
+    #[test]
+    fn test_compact() {
+        let addr = SocketAddr::from(([10, 1, 2, 3], 6881));
+        assert_eq!(encode_peer(addr), Some([10, 1, 2, 3, 0x1a, 0xe1]));
+        assert_eq!(decode_peer(&[10, 1, 2, 3, 0x1a, 0xe1]), Some(addr));
+
+        let nodes = vec![NodeInfo { id: NodeId([9; 20]), addr }];
+        let bytes = encode_nodes(&nodes);
+        assert_eq!(bytes.len(), 26);
+        assert_eq!(decode_nodes(&bytes), Some(nodes));
+        assert_eq!(decode_nodes(&bytes[1..]), None);
+        assert!(encode_nodes6(&[NodeInfo { id: NodeId([9; 20]), addr }]).is_empty());
+
+        let addr6: SocketAddr = "[2001:db8::1]:6881".parse().unwrap();
+        let nodes6 = vec![NodeInfo { id: NodeId([9; 20]), addr: addr6 }];
+        let bytes = encode_nodes6(&nodes6);
+        assert_eq!(bytes.len(), 38);
+        assert_eq!(decode_nodes6(&bytes), Some(nodes6));
+        assert_eq!(decode_peer(&bytes[20..]), Some(addr6));
+    }
+
+    #[test]
+    fn test_distance() {
+        let a = NodeId([0xff; 20]);
+        let b = NodeId([0x0f; 20]);
+        assert_eq!(a.distance(&b), [0xf0; 20]);
+        assert_ne!(NodeId::random(), NodeId::random());
+    }
+}
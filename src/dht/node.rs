@@ -0,0 +1,647 @@
+use crate::dht::item::{Item, ITEM_EXPIRY};
+use crate::dht::krpc::{Body, DecodeError, KrpcError, Message, Query, Response, Transactions};
+use crate::dht::routing::RoutingTable;
+use crate::dht::security::{is_secure, secure_id};
+use crate::dht::{Family, NodeId, NodeInfo, K};
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+use std::io;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::time::{Duration, Instant};
+use tracing::{debug, trace};
+
+pub const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+pub const TOKEN_ROTATION: Duration = Duration::from_secs(5 * 60);
+pub const PEER_EXPIRY: Duration = Duration::from_secs(30 * 60);
+// What announces can make us store: info hashes, and peers for each.
+pub const MAX_TORRENTS: usize = 2000;
+pub const MAX_PEERS: usize = 500;
+// BEP 44 items stored for others.
+pub const MAX_ITEMS: usize = 700;
+pub const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+pub const SAMPLE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+pub const MAX_SAMPLES: usize = 20;
+pub const MAX_SAMPLE_INTERVAL: Duration = Duration::from_secs(6 * 60 * 60);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GetPeers {
+    pub id: NodeId,
+    pub token: Option<Vec<u8>>,
+    pub peers: Vec<SocketAddr>,
+    pub nodes: Vec<NodeInfo>
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Samples {
+    pub id: NodeId,
+    pub interval: Duration,
+    pub num: usize,
+    pub samples: Vec<[u8; 20]>,
+    pub nodes: Vec<NodeInfo>
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GetItem {
+    pub id: NodeId,
+    pub token: Option<Vec<u8>>,
+    pub nodes: Vec<NodeInfo>,
+    pub item: Option<Item>
+}
+
+// Write tokens are a keyed hash of the querying IP. The key rotates every
+// few minutes and tokens from the previous key are still accepted.
+struct Tokens {
+    current: RandomState,
+    previous: RandomState,
+    rotated: Instant
+}
+
+impl Tokens {
+    fn new() -> Self {
+        Self { current: RandomState::new(), previous: RandomState::new(), rotated: Instant::now() }
+    }
+
+    fn rotate_if_due(&mut self) {
+        if self.rotated.elapsed() >= TOKEN_ROTATION {
+            self.previous = std::mem::replace(&mut self.current, RandomState::new());
+            self.rotated = Instant::now();
+        }
+    }
+
+    fn token(&self, ip: IpAddr) -> Vec<u8> {
+        self.current.hash_one(ip).to_be_bytes().to_vec()
+    }
+
+    fn is_valid(&self, ip: IpAddr, token: &[u8]) -> bool {
+        [&self.current, &self.previous]
+            .iter()
+            .any(|key| key.hash_one(ip).to_be_bytes() == token)
+    }
+}
+
+fn to_io_error(error: KrpcError) -> io::Error {
+    io::Error::other(error)
+}
+
+// A DHT node (BEP 5) on one UDP socket. It answers ping, find_node,
+// get_peers and announce_peer, and the get and put of BEP 44, and can send
+// the same queries itself.
+// Queries that arrive while we wait for a response are answered too.
+// The routing table only holds nodes of the socket's address family.
+pub struct Dht {
+    socket: UdpSocket,
+    family: Family,
+    id: NodeId,
+    table: RoutingTable,
+    peers: HashMap<[u8; 20], HashMap<SocketAddr, Instant>>,
+    items: HashMap<NodeId, (Item, Instant)>,
+    tokens: Tokens,
+    // When expired peers were last dropped.
+    pruned: Instant,
+    transactions: Transactions,
+    external_ip: Option<IpAddr>,
+    want: Vec<Family>,
+    // Nodes of the other family that responders sent because of `want`.
+    foreign: Vec<NodeInfo>
+}
+
+impl Dht {
+    pub fn bind(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        Self::with_id(addr, NodeId::random())
+    }
+
+    pub fn with_id(addr: impl ToSocketAddrs, id: NodeId) -> io::Result<Self> {
+        let socket = UdpSocket::bind(addr)?;
+        socket.set_read_timeout(Some(QUERY_TIMEOUT))?;
+
+        Ok(Self {
+            family: Family::of(&socket.local_addr()?),
+            socket,
+            id,
+            table: RoutingTable::new(id),
+            peers: HashMap::new(),
+            items: HashMap::new(),
+            tokens: Tokens::new(),
+            pruned: Instant::now(),
+            transactions: Transactions::new(QUERY_TIMEOUT),
+            external_ip: None,
+            want: Vec::new(),
+            foreign: Vec::new()
+        })
+    }
+
+    pub fn id(&self) -> NodeId {
+        self.id
+    }
+
+    pub fn family(&self) -> Family {
+        self.family
+    }
+
+    // The families to ask for in find_node and get_peers.
+    pub fn set_want(&mut self, want: Vec<Family>) {
+        self.want = want;
+    }
+
+    pub fn take_foreign_nodes(&mut self) -> Vec<NodeInfo> {
+        std::mem::take(&mut self.foreign)
+    }
+
+    // Our address as the last node to answer us saw it.
+    pub fn external_ip(&self) -> Option<IpAddr> {
+        self.external_ip
+    }
+
+    // Switches to a BEP 42 id for our external address if the current one
+    // does not match it. Known nodes are kept. Returns whether the id
+    // changed.
+    pub fn adopt_secure_id(&mut self) -> bool {
+        let ip = match self.external_ip {
+            Some(ip) if !is_secure(&self.id, ip) => ip,
+            _ => return false
+        };
+        let nodes = self.nodes();
+        self.id = secure_id(ip);
+        debug!(%ip, id = ?self.id, "switching to a secure node id");
+        self.table = RoutingTable::new(self.id);
+        nodes.into_iter().for_each(|node| self.add_node(node));
+        true
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    pub fn routing_table(&self) -> &RoutingTable {
+        &self.table
+    }
+
+    pub fn routing_table_mut(&mut self) -> &mut RoutingTable {
+        &mut self.table
+    }
+
+    pub fn nodes(&self) -> Vec<NodeInfo> {
+        self.table
+            .nodes(Instant::now())
+            .map(|(node, _)| node)
+            .collect()
+    }
+
+    // Adds a node we were told about but have not talked to yet.
+    pub fn add_node(&mut self, node: NodeInfo) {
+        if Family::of(&node.addr) == self.family {
+            self.table.heard_from(node, false, Instant::now());
+        }
+    }
+
+    pub fn closest_nodes(&self, target: &NodeId, count: usize) -> Vec<NodeInfo> {
+        self.table.closest(target, count, Instant::now())
+    }
+
+    fn node_failed(&mut self, addr: SocketAddr) {
+        let failed = self.table
+            .nodes(Instant::now())
+            .find(|(node, _)| node.addr == addr)
+            .map(|(node, _)| node.id);
+        if let Some(id) = failed {
+            trace!(%addr, ?id, "node failed to answer");
+            self.table.failed(&id);
+        }
+    }
+
+    pub fn stored_peers(&self, info_hash: &[u8; 20]) -> Vec<SocketAddr> {
+        self.peers
+            .get(info_hash)
+            .map(|peers| {
+                peers
+                    .iter()
+                    .filter(|(_, announced)| announced.elapsed() < PEER_EXPIRY)
+                    .map(|(&addr, _)| addr)
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    // Up to MAX_SAMPLES of the info hashes we store peers for, picked at
+    // random, and how many there are in all.
+    pub fn sampled_infohashes(&self) -> (Vec<[u8; 20]>, usize) {
+        let mut stored: Vec<_> = self.peers
+            .iter()
+            .filter(|(_, peers)| peers.values().any(|announced| announced.elapsed() < PEER_EXPIRY))
+            .map(|(&info_hash, _)| info_hash)
+            .collect();
+        let order = RandomState::new();
+        stored.sort_by_key(|info_hash| order.hash_one(info_hash));
+        let num = stored.len();
+        stored.truncate(MAX_SAMPLES);
+        (stored, num)
+    }
+
+    // Remembers a peer announced for `info_hash`. Past the caps, the peer
+    // announced longest ago, or the info hash with the fewest peers, makes
+    // way for it, so announces can't grow the store without bound.
+    fn store_peer(&mut self, info_hash: [u8; 20], addr: SocketAddr, now: Instant) {
+        if !self.peers.contains_key(&info_hash) && self.peers.len() >= MAX_TORRENTS {
+            self.prune_if_due(now);
+            let fewest = self.peers
+                .iter()
+                .min_by_key(|(_, peers)| peers.len())
+                .map(|(&info_hash, _)| info_hash);
+            if let Some(fewest) = fewest.filter(|_| self.peers.len() >= MAX_TORRENTS) {
+                self.peers.remove(&fewest);
+            }
+        }
+        let peers = self.peers.entry(info_hash).or_default();
+        if !peers.contains_key(&addr) && peers.len() >= MAX_PEERS {
+            let oldest = peers
+                .iter()
+                .min_by_key(|(_, &announced)| announced)
+                .map(|(&addr, _)| addr);
+            if let Some(oldest) = oldest {
+                peers.remove(&oldest);
+            }
+        }
+        peers.insert(addr, now);
+    }
+
+    fn prune_if_due(&mut self, now: Instant) {
+        if now >= self.pruned + PRUNE_INTERVAL {
+            self.prune(now);
+        }
+    }
+
+    // Drops what has expired, rather than only skipping it when asked.
+    fn prune(&mut self, now: Instant) {
+        self.pruned = now;
+        self.peers.retain(|_, peers| {
+            peers.retain(|_, announced| now.saturating_duration_since(*announced) < PEER_EXPIRY);
+            !peers.is_empty()
+        });
+        self.items.retain(|_, (_, stored)| now.saturating_duration_since(*stored) < ITEM_EXPIRY);
+    }
+
+    pub fn stored_item(&self, target: &NodeId) -> Option<&Item> {
+        self.items
+            .get(target)
+            .filter(|(_, stored)| stored.elapsed() < ITEM_EXPIRY)
+            .map(|(item, _)| item)
+    }
+
+    fn store_item(&mut self, item: Item, cas: Option<i64>) -> Result<(), KrpcError> {
+        item.validate()?;
+        let target = item.target();
+        if let Some(stored) = self.stored_item(&target) {
+            item.replaces(stored, cas)?;
+        }
+        let now = Instant::now();
+        // Full up, the item stored longest ago makes way.
+        if !self.items.contains_key(&target) && self.items.len() >= MAX_ITEMS {
+            self.prune_if_due(now);
+            let oldest = self.items
+                .iter()
+                .min_by_key(|(_, (_, stored))| *stored)
+                .map(|(&target, _)| target);
+            if let Some(oldest) = oldest.filter(|_| self.items.len() >= MAX_ITEMS) {
+                self.items.remove(&oldest);
+            }
+        }
+        self.items.insert(target, (item, now));
+        Ok(())
+    }
+
+    fn recv(&mut self) -> io::Result<(Result<Message, DecodeError>, SocketAddr)> {
+        let mut buf = [0; 2048];
+        let (len, from) = self.socket.recv_from(&mut buf)?;
+        Ok((Message::decode(&buf[..len]), from))
+    }
+
+    fn send(&self, message: &Message, addr: SocketAddr) -> io::Result<()> {
+        self.socket.send_to(&message.encode(), addr)?;
+        Ok(())
+    }
+
+    // Waits for one packet (up to the query timeout) and answers it if it is
+    // a query.
+    pub fn serve_once(&mut self) -> io::Result<()> {
+        let (message, from) = self.recv()?;
+        self.handle_incoming(message, from)?;
+        Ok(())
+    }
+
+    pub fn run(&mut self) -> io::Result<()> {
+        loop {
+            match self.serve_once() {
+                Err(err) if matches!(err.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => continue,
+                result => result?
+            }
+        }
+    }
+
+    // Answers queries and malformed queries. Responses and errors are
+    // handed back to the caller.
+    fn handle_incoming(&mut self, message: Result<Message, DecodeError>, from: SocketAddr) -> io::Result<Option<Message>> {
+        match message {
+            Ok(Message { transaction, body: Body::Query(query), .. }) => {
+                let mut reply = match self.handle_query(from, query) {
+                    Ok(response) => Message::response(transaction, response),
+                    Err(error) => Message::error(transaction, error)
+                };
+                reply.ip = Some(from);
+                self.send(&reply, from)?;
+                Ok(None)
+            },
+            Ok(message) => Ok(Some(message)),
+            // Malformed replies to our own queries are not answered.
+            Err(DecodeError { transaction: Some(transaction), error }) if !self.transactions.contains(&transaction) => {
+                self.send(&Message::error(transaction, error), from)?;
+                Ok(None)
+            },
+            Err(_) => Ok(None)
+        }
+    }
+
+    fn handle_query(&mut self, from: SocketAddr, query: Query) -> Result<Response, KrpcError> {
+        trace!(%from, ?query, "query");
+        self.table.heard_from(NodeInfo { id: query.id(), addr: from }, false, Instant::now());
+        self.tokens.rotate_if_due();
+        self.prune_if_due(Instant::now());
+
+        let mut response = Response::new(self.id);
+        match query {
+            Query::Ping { .. } => {},
+            Query::FindNode { target, want, .. } => {
+                if self.wants_own_family(&want, from) {
+                    response.nodes = self.closest_nodes(&target, K);
+                }
+            },
+            Query::GetPeers { info_hash, want, .. } => {
+                response.token = Some(self.tokens.token(from.ip()));
+                response.values = self.stored_peers(&info_hash);
+                if response.values.is_empty() && self.wants_own_family(&want, from) {
+                    response.nodes = self.closest_nodes(&NodeId(info_hash), K);
+                }
+            },
+            Query::AnnouncePeer { info_hash, port, token, .. } => {
+                if !self.tokens.is_valid(from.ip(), &token) {
+                    return Err(KrpcError::protocol("bad token"));
+                }
+                let port = port.unwrap_or(from.port());
+                self.store_peer(info_hash, SocketAddr::new(from.ip(), port), Instant::now());
+            },
+            Query::SampleInfohashes { target, .. } => {
+                let (samples, num) = self.sampled_infohashes();
+                response.interval = Some(SAMPLE_INTERVAL.as_secs() as i64);
+                response.num = Some(num as i64);
+                response.samples = Some(samples);
+                response.nodes = self.closest_nodes(&target, K);
+            },
+            Query::Get { target, seq, .. } => {
+                response.token = Some(self.tokens.token(from.ip()));
+                response.nodes = self.closest_nodes(&target, K);
+                match self.stored_item(&target) {
+                    Some(Item::Immutable(value)) => response.value = Some(value.clone()),
+                    Some(Item::Mutable(item)) => {
+                        response.seq = Some(item.seq);
+                        if seq.is_none_or(|seq| item.seq > seq) {
+                            response.value = Some(item.value.clone());
+                            response.key = Some(item.key);
+                            response.signature = Some(item.signature);
+                        }
+                    },
+                    None => {}
+                }
+            },
+            Query::Put { token, item, cas, .. } => {
+                if !self.tokens.is_valid(from.ip(), &token) {
+                    return Err(KrpcError::protocol("bad token"));
+                }
+                self.store_item(item, cas)?;
+            }
+        }
+        Ok(response)
+    }
+
+    fn wants_own_family(&self, want: &[Family], from: SocketAddr) -> bool {
+        match want.is_empty() {
+            true => Family::of(&from) == self.family,
+            false => want.contains(&self.family)
+        }
+    }
+
+    // Keeps the nodes of our own family and puts the rest aside.
+    fn own_family(&mut self, nodes: Vec<NodeInfo>) -> Vec<NodeInfo> {
+        let (own, foreign): (Vec<_>, Vec<_>) = nodes
+            .into_iter()
+            .partition(|node| Family::of(&node.addr) == self.family);
+        foreign
+            .into_iter()
+            .for_each(|node| if !self.foreign.contains(&node) { self.foreign.push(node) });
+        own
+    }
+
+    fn query(&mut self, addr: SocketAddr, query: Query) -> io::Result<Response> {
+        let transaction = self.transactions.start(addr, Instant::now());
+        self.send(&Message::query(transaction.clone(), query), addr)?;
+
+        let deadline = Instant::now() + self.transactions.timeout();
+        let result = loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                break Err(io::Error::new(io::ErrorKind::TimedOut, "query timed out"));
+            }
+            self.socket.set_read_timeout(Some(remaining))?;
+            let received = self.recv();
+            self.socket.set_read_timeout(Some(QUERY_TIMEOUT))?;
+
+            let (message, from) = match received {
+                Ok(received) => received,
+                Err(err) if matches!(err.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => continue,
+                Err(err) => break Err(err)
+            };
+            let message = match self.handle_incoming(message, from)? {
+                Some(message) if message.transaction == transaction => message,
+                _ => continue
+            };
+            if self.transactions.finish(&transaction, from).is_none() {
+                continue;
+            }
+
+            if let Some(ip) = message.ip {
+                if self.external_ip != Some(ip.ip()) {
+                    debug!(ip = %ip.ip(), "learned external address");
+                }
+                self.external_ip = Some(ip.ip());
+            }
+            break match message.body {
+                Body::Response(mut response) => {
+                    response.nodes = self.own_family(response.nodes);
+                    self.table.heard_from(NodeInfo { id: response.id, addr }, true, Instant::now());
+                    Ok(response)
+                },
+                Body::Error(error) => Err(to_io_error(error)),
+                Body::Query(_) => unreachable!()
+            };
+        };
+
+        self.transactions.expire(Instant::now());
+        if self.transactions.finish(&transaction, addr).is_some() {
+            self.node_failed(addr);
+        }
+        result
+    }
+
+    pub fn ping(&mut self, addr: SocketAddr) -> io::Result<NodeId> {
+        let response = self.query(addr, Query::Ping { id: self.id })?;
+        Ok(response.id)
+    }
+
+    pub fn find_node(&mut self, addr: SocketAddr, target: NodeId) -> io::Result<Vec<NodeInfo>> {
+        let response = self.query(addr, Query::FindNode { id: self.id, target, want: self.want.clone() })?;
+        response.nodes.iter().for_each(|&node| self.add_node(node));
+        Ok(response.nodes)
+    }
+
+    pub fn get_peers(&mut self, addr: SocketAddr, info_hash: [u8; 20]) -> io::Result<GetPeers> {
+        let response = self.query(addr, Query::GetPeers { id: self.id, info_hash, want: self.want.clone() })?;
+        Ok(GetPeers {
+            id: response.id,
+            token: response.token,
+            peers: response.values,
+            nodes: response.nodes
+        })
+    }
+
+    // `port` of `None` asks the node to use our UDP source port instead.
+    pub fn announce_peer(&mut self, addr: SocketAddr, info_hash: [u8; 20], port: Option<u16>, token: &[u8]) -> io::Result<()> {
+        let query = Query::AnnouncePeer { id: self.id, info_hash, port, token: token.to_vec() };
+        self.query(addr, query)?;
+        Ok(())
+    }
+
+    pub fn sample_infohashes(&mut self, addr: SocketAddr, target: NodeId) -> io::Result<Samples> {
+        let response = self.query(addr, Query::SampleInfohashes { id: self.id, target })?;
+        Ok(Samples {
+            id: response.id,
+            interval: Duration::from_secs(response.interval.unwrap_or_default().max(0) as u64).min(MAX_SAMPLE_INTERVAL),
+            num: response.num.unwrap_or_default().max(0) as usize,
+            samples: response.samples.unwrap_or_default(),
+            nodes: response.nodes
+        })
+    }
+
+    // `salt` is only used to check a mutable item against the target; it
+    // is not sent.
+    pub fn get_item(&mut self, addr: SocketAddr, target: NodeId, salt: &[u8], seq: Option<i64>) -> io::Result<GetItem> {
+        let response = self.query(addr, Query::Get { id: self.id, target, seq })?;
+        Ok(GetItem {
+            id: response.id,
+            item: Item::from_response(&response, &target, salt),
+            token: response.token,
+            nodes: response.nodes
+        })
+    }
+
+    pub fn put_item(&mut self, addr: SocketAddr, token: &[u8], item: &Item, cas: Option<i64>) -> io::Result<()> {
+        let query = Query::Put { id: self.id, token: token.to_vec(), item: item.clone(), cas };
+        self.query(addr, query)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::dht::item::{Item, ITEM_EXPIRY};
+    use crate::dht::node::{MAX_ITEMS, MAX_PEERS, MAX_TORRENTS, PEER_EXPIRY};
+    use crate::dht::{Dht, NodeId};
+    use std::net::SocketAddr;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    fn spawn_node() -> std::net::SocketAddr {
+        let mut node = Dht::with_id("127.0.0.1:0", NodeId([1; 20])).unwrap();
+        let addr = node.local_addr().unwrap();
+        thread::spawn(move || node.run());
+        addr
+    }
+
+    #[test]
+    fn test_ping_and_find_node() {
+        let remote = spawn_node();
+        let mut dht = Dht::with_id("127.0.0.1:0", NodeId([2; 20])).unwrap();
+
+        assert_eq!(dht.ping(remote).unwrap(), NodeId([1; 20]));
+        assert_eq!(dht.nodes().len(), 1);
+        assert_eq!(dht.external_ip(), Some("127.0.0.1".parse().unwrap()));
+        // Loopback addresses are exempt from BEP 42.
+        assert!(!dht.adopt_secure_id());
+
+        // The remote learned about us from the ping.
+        let nodes = dht.find_node(remote, NodeId([2; 20])).unwrap();
+        assert_eq!(nodes.len(), 1);
+        assert_eq!(nodes[0].id, NodeId([2; 20]));
+        assert_eq!(nodes[0].addr, dht.local_addr().unwrap());
+    }
+
+    #[test]
+    fn test_announce_and_get_peers() {
+        let remote = spawn_node();
+        let mut dht = Dht::bind("127.0.0.1:0").unwrap();
+        let info_hash = [5; 20];
+
+        let response = dht.get_peers(remote, info_hash).unwrap();
+        assert!(response.peers.is_empty());
+        let token = response.token.unwrap();
+
+        assert!(dht.announce_peer(remote, info_hash, Some(6881), b"bogus").is_err());
+        dht.announce_peer(remote, info_hash, Some(6881), &token).unwrap();
+
+        let response = dht.get_peers(remote, info_hash).unwrap();
+        assert_eq!(response.peers, vec!["127.0.0.1:6881".parse().unwrap()]);
+
+        let samples = dht.sample_infohashes(remote, NodeId([0; 20])).unwrap();
+        assert_eq!(samples.num, 1);
+        assert_eq!(samples.samples, vec![info_hash]);
+        assert_eq!(samples.nodes.len(), 1);
+    }
+
+    #[test]
+    fn test_peer_store_limits() {
+        let mut dht = Dht::with_id("127.0.0.1:0", NodeId([3; 20])).unwrap();
+        let now = Instant::now();
+        let peer = |n: u32| SocketAddr::from(([10, 0, (n >> 8) as u8, n as u8], 6881));
+        for n in 0..MAX_PEERS as u32 + 10 {
+            dht.store_peer([1; 20], peer(n), now + Duration::from_millis(n as u64));
+        }
+        // The first announced went first.
+        assert_eq!(dht.stored_peers(&[1; 20]).len(), MAX_PEERS);
+        assert!(!dht.stored_peers(&[1; 20]).contains(&peer(9)));
+        assert!(dht.stored_peers(&[1; 20]).contains(&peer(10)));
+
+        for n in 0..MAX_TORRENTS as u32 + 10 {
+            let mut info_hash = [0; 20];
+            info_hash[..4].copy_from_slice(&n.to_be_bytes());
+            dht.store_peer(info_hash, peer(0), now);
+        }
+        assert_eq!(dht.peers.len(), MAX_TORRENTS);
+        assert_eq!(dht.stored_peers(&[1; 20]).len(), MAX_PEERS);
+
+        dht.prune(now + Duration::from_secs(1) + PEER_EXPIRY);
+        assert!(dht.peers.is_empty());
+        assert_eq!(dht.sampled_infohashes().1, 0);
+    }
+
+    #[test]
+    fn test_item_store_limits() {
+        let mut dht = Dht::with_id("127.0.0.1:0", NodeId([4; 20])).unwrap();
+        let item = |n: usize| Item::Immutable(n.to_string().as_str().into());
+        for n in 0..MAX_ITEMS + 5 {
+            dht.store_item(item(n), None).unwrap();
+        }
+        assert_eq!(dht.items.len(), MAX_ITEMS);
+        assert!(dht.stored_item(&item(MAX_ITEMS + 4).target()).is_some());
+
+        dht.prune(Instant::now() + ITEM_EXPIRY);
+        assert!(dht.items.is_empty());
+    }
+}
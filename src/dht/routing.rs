@@ -0,0 +1,363 @@
+use crate::dht::security::is_secure;
+use crate::dht::{random_bytes, NodeId, NodeInfo, K};
+use std::time::{Duration, Instant};
+
+pub const NODE_TIMEOUT: Duration = Duration::from_secs(15 * 60);
+pub const BUCKET_REFRESH: Duration = Duration::from_secs(15 * 60);
+pub const MAX_FAILURES: u32 = 2;
+const ID_BITS: usize = 160;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NodeState {
+    Good,
+    Questionable,
+    Bad
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    info: NodeInfo,
+    last_seen: Instant,
+    last_response: Option<Instant>,
+    failures: u32,
+    // Whether the id matches the address under BEP 42.
+    secure: bool
+}
+
+impl Entry {
+    // BEP 5: a node is good if it answered one of our queries within the
+    // last 15 minutes, or has answered before and queried us within that
+    // time. Nodes that failed several queries in a row are bad.
+    fn state(&self, now: Instant) -> NodeState {
+        if self.failures >= MAX_FAILURES {
+            return NodeState::Bad;
+        }
+        let recent = |at: Instant| now.duration_since(at) < NODE_TIMEOUT;
+        match self.last_response {
+            Some(response) if recent(response) || recent(self.last_seen) => NodeState::Good,
+            _ => NodeState::Questionable
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Bucket {
+    entries: Vec<Entry>,
+    last_changed: Instant
+}
+
+impl Bucket {
+    fn new(now: Instant) -> Self {
+        Self { entries: Vec::new(), last_changed: now }
+    }
+}
+
+fn common_prefix(a: &NodeId, b: &NodeId) -> usize {
+    let distance = a.distance(b);
+    distance
+        .iter()
+        .position(|&byte| byte != 0)
+        .map(|i| i * 8 + distance[i].leading_zeros() as usize)
+        .unwrap_or(ID_BITS)
+}
+
+// The 160-bit routing table of BEP 5. Bucket `i` holds nodes sharing
+// exactly `i` leading bits with our id, except the last bucket, which holds
+// everything closer. When the last bucket is full it is split, so the table
+// gets finer around our own id.
+pub struct RoutingTable {
+    own: NodeId,
+    buckets: Vec<Bucket>
+}
+
+impl RoutingTable {
+    pub fn new(own: NodeId) -> Self {
+        Self { own, buckets: vec![Bucket::new(Instant::now())] }
+    }
+
+    pub fn own_id(&self) -> NodeId {
+        self.own
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets
+            .iter()
+            .map(|bucket| bucket.entries.len())
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn num_buckets(&self) -> usize {
+        self.buckets.len()
+    }
+
+    fn bucket_index(&self, id: &NodeId) -> usize {
+        common_prefix(&self.own, id).min(self.buckets.len() - 1)
+    }
+
+    fn find(&mut self, id: &NodeId) -> Option<&mut Entry> {
+        let index = self.bucket_index(id);
+        self.buckets[index]
+            .entries
+            .iter_mut()
+            .find(|entry| entry.info.id == *id)
+    }
+
+    pub fn state(&self, id: &NodeId, now: Instant) -> Option<NodeState> {
+        self.buckets[self.bucket_index(id)]
+            .entries
+            .iter()
+            .find(|entry| entry.info.id == *id)
+            .map(|entry| entry.state(now))
+    }
+
+    pub fn nodes(&self, now: Instant) -> impl Iterator<Item = (NodeInfo, NodeState)> + '_ {
+        self.buckets
+            .iter()
+            .flat_map(|bucket| bucket.entries.iter())
+            .map(move |entry| (entry.info, entry.state(now)))
+    }
+
+    // Records that a node sent us a query (`responded == false`) or answered
+    // one of ours. Returns whether the node is in the table afterwards.
+    pub fn heard_from(&mut self, node: NodeInfo, responded: bool, now: Instant) -> bool {
+        if node.id == self.own {
+            return false;
+        }
+
+        if let Some(entry) = self.find(&node.id) {
+            entry.info.addr = node.addr;
+            entry.secure = is_secure(&node.id, node.addr.ip());
+            entry.last_seen = now;
+            if responded {
+                entry.last_response = Some(now);
+                entry.failures = 0;
+            }
+            let index = self.bucket_index(&node.id);
+            self.buckets[index].last_changed = now;
+            return true;
+        }
+
+        let entry = Entry {
+            info: node,
+            last_seen: now,
+            last_response: responded.then_some(now),
+            failures: 0,
+            secure: is_secure(&node.id, node.addr.ip())
+        };
+        loop {
+            let index = self.bucket_index(&node.id);
+            let splittable = index == self.buckets.len() - 1 && self.buckets.len() < ID_BITS;
+            let bucket = &mut self.buckets[index];
+
+            if bucket.entries.len() < K {
+                bucket.entries.push(entry);
+                bucket.last_changed = now;
+                return true;
+            }
+            // Bad nodes make room first, then nodes whose id does not match
+            // their address if the newcomer's does.
+            let replace = bucket.entries
+                .iter()
+                .position(|old| old.state(now) == NodeState::Bad)
+                .or_else(|| match entry.secure {
+                    true => bucket.entries.iter().position(|old| !old.secure),
+                    false => None
+                });
+            if let Some(replace) = replace {
+                bucket.entries[replace] = entry;
+                bucket.last_changed = now;
+                return true;
+            }
+            if !splittable {
+                return false;
+            }
+            self.split_last(now);
+        }
+    }
+
+    fn split_last(&mut self, now: Instant) {
+        let depth = self.buckets.len();
+        let last = self.buckets.last_mut().unwrap();
+        let (closer, stay): (Vec<_>, Vec<_>) = last.entries
+            .drain(..)
+            .partition(|entry| common_prefix(&self.own, &entry.info.id) >= depth);
+        last.entries = stay;
+
+        let mut bucket = Bucket::new(now);
+        bucket.entries = closer;
+        self.buckets.push(bucket);
+    }
+
+    // A query to the node timed out.
+    pub fn failed(&mut self, id: &NodeId) {
+        if let Some(entry) = self.find(id) {
+            entry.failures += 1;
+        }
+    }
+
+    pub fn remove(&mut self, id: &NodeId) {
+        let index = self.bucket_index(id);
+        self.buckets[index].entries.retain(|entry| entry.info.id != *id);
+    }
+
+    // Nodes worth pinging to find out whether they are still alive.
+    pub fn questionable(&self, now: Instant) -> Vec<NodeInfo> {
+        self.nodes(now)
+            .filter(|(_, state)| *state == NodeState::Questionable)
+            .map(|(info, _)| info)
+            .collect()
+    }
+
+    pub fn closest(&self, target: &NodeId, count: usize, now: Instant) -> Vec<NodeInfo> {
+        let mut nodes: Vec<_> = self.nodes(now)
+            .filter(|(_, state)| *state != NodeState::Bad)
+            .map(|(info, _)| info)
+            .collect();
+        nodes.sort_by_key(|node| node.id.distance(target));
+        nodes.truncate(count);
+        nodes
+    }
+
+    // Random targets inside every bucket that has not changed for a while;
+    // a find_node for each keeps the bucket fresh.
+    pub fn refresh_targets(&mut self, now: Instant) -> Vec<NodeId> {
+        let last = self.buckets.len() - 1;
+        let own = self.own;
+        self.buckets
+            .iter_mut()
+            .enumerate()
+            .filter(|(_, bucket)| now.duration_since(bucket.last_changed) >= BUCKET_REFRESH)
+            .map(|(index, bucket)| {
+                bucket.last_changed = now;
+                random_id_in_bucket(&own, index, index == last)
+            })
+            .collect()
+    }
+}
+
+// An id sharing exactly `prefix` leading bits with `own` (or at least
+// `prefix` bits for the last bucket).
+fn random_id_in_bucket(own: &NodeId, prefix: usize, last: bool) -> NodeId {
+    let mut id: [u8; 20] = random_bytes();
+    for bit in 0..prefix.min(ID_BITS) {
+        let mask = 0x80 >> (bit % 8);
+        id[bit / 8] = (id[bit / 8] & !mask) | (own.0[bit / 8] & mask);
+    }
+    if !last && prefix < ID_BITS {
+        let mask = 0x80 >> (prefix % 8);
+        id[prefix / 8] = (id[prefix / 8] & !mask) | (!own.0[prefix / 8] & mask);
+    }
+    NodeId(id)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::dht::routing::{common_prefix, NodeState, RoutingTable, NODE_TIMEOUT};
+    use crate::dht::security::{is_secure, secure_id};
+    use crate::dht::{NodeId, NodeInfo, K};
+    use std::net::SocketAddr;
+    use std::time::Instant;
+
+    fn node(first: u8, last: u8) -> NodeInfo {
+        let mut id = [0; 20];
+        id[0] = first;
+        id[19] = last;
+        NodeInfo { id: NodeId(id), addr: SocketAddr::from(([10, 0, first, last], 6881)) }
+    }
+
+    #[test]
+    fn test_split_near_own_id() {
+        let mut table = RoutingTable::new(NodeId([0; 20]));
+        let now = Instant::now();
+
+        // Each step closer to our id splits the last bucket again.
+        for first in [0x80, 0x40, 0x20] {
+            for i in 0..K as u8 {
+                assert!(table.heard_from(node(first, i), true, now));
+            }
+        }
+        assert_eq!(table.len(), 3 * K);
+        assert_eq!(table.num_buckets(), 3);
+
+        // The far half of the id space does not split any more.
+        assert!(!table.heard_from(node(0x80, 100), true, now));
+
+        let closest = table.closest(&NodeId([0; 20]), 3, now);
+        assert!(closest.iter().all(|info| info.id.0[0] == 0x20));
+    }
+
+    #[test]
+    fn test_node_states() {
+        let mut table = RoutingTable::new(NodeId([0; 20]));
+        let now = Instant::now();
+        let a = node(0x80, 1);
+
+        table.heard_from(a, false, now);
+        assert_eq!(table.state(&a.id, now), Some(NodeState::Questionable));
+        table.heard_from(a, true, now);
+        assert_eq!(table.state(&a.id, now), Some(NodeState::Good));
+        assert_eq!(table.state(&a.id, now + NODE_TIMEOUT), Some(NodeState::Questionable));
+
+        table.failed(&a.id);
+        table.failed(&a.id);
+        assert_eq!(table.state(&a.id, now), Some(NodeState::Bad));
+        assert!(table.closest(&a.id, 8, now).is_empty());
+    }
+
+    #[test]
+    fn test_bad_nodes_are_replaced() {
+        let mut table = RoutingTable::new(NodeId([0; 20]));
+        let now = Instant::now();
+        (0..K as u8).for_each(|i| { table.heard_from(node(0x80, i), true, now); });
+        table.heard_from(node(0x01, 0), true, now);
+
+        assert!(!table.heard_from(node(0x80, 100), true, now));
+        table.failed(&node(0x80, 3).id);
+        table.failed(&node(0x80, 3).id);
+        assert!(table.heard_from(node(0x80, 100), true, now));
+        assert_eq!(table.state(&node(0x80, 3).id, now), None);
+    }
+
+    #[test]
+    fn test_secure_nodes_preferred() {
+        let ip = "124.31.75.21".parse().unwrap();
+        let secure = secure_id(ip);
+        // Our id differs in the first bit, so everything below shares bucket 0.
+        let mut table = RoutingTable::new(NodeId([!secure.0[0]; 20]));
+        let now = Instant::now();
+        let public = |id, port| NodeInfo { id, addr: SocketAddr::new(ip, port) };
+
+        // Ids that do not match the address only fill free slots.
+        let insecure = |i: u8| {
+            let mut id = [i; 20];
+            id[0] = secure.0[0];
+            id[1] = !secure.0[1];
+            NodeId(id)
+        };
+        (0..K as u8).for_each(|i| { table.heard_from(public(insecure(i), i as u16), true, now); });
+        assert!(!table.heard_from(public(insecure(100), 100), true, now));
+
+        assert!(is_secure(&secure, ip));
+        assert!(table.heard_from(public(secure, 200), true, now));
+        assert_eq!(table.len(), K);
+    }
+
+    #[test]
+    fn test_refresh_targets() {
+        let own = NodeId([0; 20]);
+        let mut table = RoutingTable::new(own);
+        let now = Instant::now();
+        (0..K as u8).for_each(|i| { table.heard_from(node(0x80, i), true, now); });
+        table.heard_from(node(0x01, 0), true, now);
+
+        let targets = table.refresh_targets(now + NODE_TIMEOUT);
+        assert_eq!(targets.len(), 2);
+        assert_eq!(common_prefix(&own, &targets[0]), 0);
+        assert!(common_prefix(&own, &targets[1]) >= 1);
+        assert!(table.refresh_targets(now + NODE_TIMEOUT).is_empty());
+    }
+}
@@ -0,0 +1,89 @@
+use crate::dht::{random_bytes, NodeId};
+use crate::hash::crc32c;
+use std::net::IpAddr;
+
+const V4_MASK: [u8; 4] = [0x03, 0x0f, 0x3f, 0xff];
+const V6_MASK: [u8; 8] = [0x01, 0x03, 0x07, 0x0f, 0x1f, 0x3f, 0x7f, 0xff];
+
+// The CRC32-C of the masked address with `r` in its top bits, which the
+// first 21 bits of a node id must match.
+fn id_prefix(ip: IpAddr, r: u8) -> u32 {
+    let mut masked = match ip {
+        IpAddr::V4(ip) => ip.octets().iter().zip(V4_MASK).map(|(byte, mask)| byte & mask).collect::<Vec<_>>(),
+        IpAddr::V6(ip) => ip.octets().iter().zip(V6_MASK).map(|(byte, mask)| byte & mask).collect::<Vec<_>>()
+    };
+    masked[0] |= (r & 0x07) << 5;
+    crc32c(&masked)
+}
+
+// Local and private addresses cannot be checked, so any id goes.
+fn is_exempt(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_private() || ip.is_loopback() || ip.is_link_local() || ip.is_unspecified(),
+        IpAddr::V6(ip) => ip.is_loopback() || ip.is_unspecified() || (ip.segments()[0] & 0xfe00) == 0xfc00
+    }
+}
+
+fn secure_id_with(ip: IpAddr, r: u8, random: [u8; 20]) -> NodeId {
+    let prefix = id_prefix(ip, r).to_be_bytes();
+    let mut id = random;
+    id[0] = prefix[0];
+    id[1] = prefix[1];
+    id[2] = (prefix[2] & 0xf8) | (random[2] & 0x07);
+    id[19] = r;
+    NodeId(id)
+}
+
+// BEP 42: a node id tied to our external address, so nobody can pick ids
+// at will to surround a target.
+pub fn secure_id(ip: IpAddr) -> NodeId {
+    let random: [u8; 20] = random_bytes();
+    secure_id_with(ip, random[19], random)
+}
+
+pub fn is_secure(id: &NodeId, ip: IpAddr) -> bool {
+    if is_exempt(ip) {
+        return true;
+    }
+    let expected = id_prefix(ip, id.0[19]).to_be_bytes();
+    id.0[0] == expected[0] && id.0[1] == expected[1] && (id.0[2] & 0xf8) == (expected[2] & 0xf8)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::dht::security::{is_secure, secure_id, secure_id_with};
+    use crate::dht::NodeId;
+    use crate::hash::crc32c;
+    use std::net::IpAddr;
+
+    #[test]
+    fn test_vectors() {
+        assert_eq!(crc32c(b"123456789"), 0xe306_9283);
+
+        // The examples from BEP 42, compared on the 21 bits that are fixed.
+        let vectors = [
+            ("124.31.75.21", 1, [0x5f, 0xbf, 0xbf]),
+            ("21.75.31.124", 86, [0x5a, 0x3c, 0xe9]),
+            ("65.23.51.170", 22, [0xa5, 0xd4, 0x32]),
+            ("84.124.73.14", 65, [0x1b, 0x03, 0x21]),
+            ("43.213.53.83", 90, [0xe5, 0x6f, 0x6c])
+        ];
+        for (ip, r, prefix) in vectors {
+            let ip: IpAddr = ip.parse().unwrap();
+            let id = secure_id_with(ip, r, [0; 20]);
+            assert_eq!(id.0[..2], prefix[..2]);
+            assert_eq!(id.0[2], prefix[2] & 0xf8);
+            assert_eq!(id.0[19], r);
+            assert!(is_secure(&id, ip));
+        }
+    }
+
+    #[test]
+    fn test_is_secure() {
+        let ip: IpAddr = "124.31.75.21".parse().unwrap();
+        assert!(is_secure(&secure_id(ip), ip));
+        assert!(!is_secure(&NodeId([0; 20]), ip));
+        assert!(!is_secure(&secure_id(ip), "124.31.75.22".parse().unwrap()));
+        assert!(is_secure(&NodeId([0; 20]), "192.168.1.1".parse().unwrap()));
+    }
+}
@@ -0,0 +1,112 @@
+use crate::bencode::{self, Value};
+use crate::dht::routing::NodeState;
+use crate::dht::{decode_nodes, encode_nodes, Dht, NodeId, NodeInfo};
+use std::fs;
+use std::io;
+use std::net::ToSocketAddrs;
+use std::path::Path;
+use std::time::Instant;
+
+// What survives a restart: our node id, so we keep our place in the DHT,
+// and the nodes that were not known to be bad when we shut down. Nodes we
+// restored but have not talked to since are kept as well, so a short run
+// does not throw the table away.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DhtState {
+    pub id: NodeId,
+    pub nodes: Vec<NodeInfo>
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+impl DhtState {
+    pub fn encode(&self) -> Vec<u8> {
+        Value::dict([
+            ("id", Value::from(&self.id.0[..])),
+            ("nodes", encode_nodes(&self.nodes).into())
+        ])
+        .encode()
+    }
+
+    pub fn decode(bytes: &[u8]) -> Option<Self> {
+        let value = bencode::decode(bytes)?;
+        Some(Self {
+            id: NodeId::from_bytes(value.get("id")?.as_bytes()?)?,
+            nodes: decode_nodes(value.get("nodes")?.as_bytes()?)?
+        })
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let bytes = fs::read(path)?;
+        Self::decode(&bytes).ok_or_else(|| invalid("corrupt DHT state file"))
+    }
+
+    // Written to a temporary file first so a crash never leaves a
+    // truncated state file behind.
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let path = path.as_ref();
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, self.encode())?;
+        fs::rename(tmp, path)
+    }
+}
+
+impl Dht {
+    pub fn state(&self) -> DhtState {
+        let nodes = self.routing_table()
+            .nodes(Instant::now())
+            .filter(|(_, state)| *state != NodeState::Bad)
+            .map(|(node, _)| node)
+            .collect();
+        DhtState { id: self.id(), nodes }
+    }
+
+    // Binds with the saved node id and seeds the routing table with the
+    // saved nodes. They count as questionable until they answer us again.
+    pub fn with_state(addr: impl ToSocketAddrs, state: &DhtState) -> io::Result<Self> {
+        let mut dht = Self::with_id(addr, state.id)?;
+        state.nodes
+            .iter()
+            .for_each(|&node| dht.add_node(node));
+        Ok(dht)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::dht::state::DhtState;
+    use crate::dht::{Dht, NodeId, NodeInfo};
+    use std::net::SocketAddr;
+
+    #[test]
+    fn test_save_and_load() {
+        let state = DhtState {
+            id: NodeId([1; 20]),
+            nodes: vec![NodeInfo { id: NodeId([2; 20]), addr: SocketAddr::from(([10, 0, 0, 2], 6881)) }]
+        };
+        let path = std::env::temp_dir().join(format!("dht-state-{}.dat", std::process::id()));
+
+        state.save(&path).unwrap();
+        assert_eq!(DhtState::load(&path).unwrap(), state);
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(DhtState::decode(b"de"), None);
+    }
+
+    #[test]
+    fn test_restore() {
+        let node = NodeInfo { id: NodeId([2; 20]), addr: SocketAddr::from(([10, 0, 0, 2], 6881)) };
+        let state = DhtState { id: NodeId([1; 20]), nodes: vec![node] };
+
+        let mut dht = Dht::with_state("127.0.0.1:0", &state).unwrap();
+        assert_eq!(dht.id(), NodeId([1; 20]));
+        assert_eq!(dht.nodes(), vec![node]);
+
+        assert_eq!(dht.state().nodes, vec![node]);
+        dht.routing_table_mut().failed(&node.id);
+        dht.routing_table_mut().failed(&node.id);
+        assert!(dht.state().nodes.is_empty());
+    }
+}
@@ -0,0 +1,218 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_MAX_HALF_OPEN: usize = 8;
+pub const DEFAULT_DIALS_PER_SECOND: u32 = 10;
+pub const MAX_DIAL_FAILURES: u32 = 3;
+
+// Where we heard about a peer, from least to most trusted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum PeerSource {
+    Pex,
+    Lsd,
+    Dht,
+    Tracker,
+    Manual
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DialState {
+    Queued,
+    Dialing,
+    Connected
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Candidate {
+    source: PeerSource,
+    state: DialState,
+    failures: u32,
+    successes: u32
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct DialConfig {
+    pub max_half_open: usize,
+    pub dials_per_second: u32
+}
+
+impl Default for DialConfig {
+    fn default() -> Self {
+        Self {
+            max_half_open: DEFAULT_MAX_HALF_OPEN,
+            dials_per_second: DEFAULT_DIALS_PER_SECOND
+        }
+    }
+}
+
+// Candidate peers waiting to be connected to. Dials are limited both in how
+// many may be in progress at once and in how many may start per second.
+// Peers that connected fine before are tried first, then by source, and
+// peers that keep failing are dropped.
+pub struct DialQueue {
+    config: DialConfig,
+    candidates: HashMap<SocketAddr, Candidate>,
+    window_start: Option<Instant>,
+    window_dials: u32
+}
+
+impl DialQueue {
+    pub fn new(config: DialConfig) -> Self {
+        Self {
+            config,
+            candidates: HashMap::new(),
+            window_start: None,
+            window_dials: 0
+        }
+    }
+
+    pub fn config(&self) -> DialConfig {
+        self.config
+    }
+
+    pub fn set_config(&mut self, config: DialConfig) {
+        self.config = config;
+    }
+
+    pub fn add(&mut self, addr: SocketAddr, source: PeerSource) {
+        let candidate = self.candidates
+            .entry(addr)
+            .or_insert(Candidate { source, state: DialState::Queued, failures: 0, successes: 0 });
+        candidate.source = candidate.source.max(source);
+    }
+
+    pub fn remove(&mut self, addr: SocketAddr) {
+        self.candidates.remove(&addr);
+    }
+
+    pub fn queued(&self) -> usize {
+        self.count(DialState::Queued)
+    }
+
+    pub fn half_open(&self) -> usize {
+        self.count(DialState::Dialing)
+    }
+
+    fn count(&self, state: DialState) -> usize {
+        self.candidates
+            .values()
+            .filter(|candidate| candidate.state == state)
+            .count()
+    }
+
+    // Picks the next peer to dial, if the limits allow one right now.
+    pub fn next_dial(&mut self, now: Instant) -> Option<SocketAddr> {
+        if self.half_open() >= self.config.max_half_open {
+            return None;
+        }
+
+        let window_over = self.window_start
+            .map(|start| now.duration_since(start) >= Duration::from_secs(1))
+            .unwrap_or(true);
+        if window_over {
+            self.window_start = Some(now);
+            self.window_dials = 0;
+        }
+        if self.window_dials >= self.config.dials_per_second {
+            return None;
+        }
+
+        let (&addr, _) = self.candidates
+            .iter()
+            .filter(|(_, candidate)| candidate.state == DialState::Queued)
+            .min_by_key(|(&addr, candidate)| {
+                (
+                    candidate.failures,
+                    std::cmp::Reverse(candidate.successes),
+                    std::cmp::Reverse(candidate.source),
+                    addr
+                )
+            })?;
+
+        self.candidates.get_mut(&addr)?.state = DialState::Dialing;
+        self.window_dials += 1;
+        Some(addr)
+    }
+
+    pub fn connected(&mut self, addr: SocketAddr) {
+        if let Some(candidate) = self.candidates.get_mut(&addr) {
+            candidate.state = DialState::Connected;
+            candidate.successes += 1;
+            candidate.failures = 0;
+        }
+    }
+
+    pub fn failed(&mut self, addr: SocketAddr) {
+        let give_up = match self.candidates.get_mut(&addr) {
+            Some(candidate) => {
+                candidate.state = DialState::Queued;
+                candidate.failures += 1;
+                candidate.failures >= MAX_DIAL_FAILURES
+            },
+            None => false
+        };
+        if give_up {
+            self.candidates.remove(&addr);
+        }
+    }
+
+    // A connected peer that went away can be dialed again later.
+    pub fn disconnected(&mut self, addr: SocketAddr) {
+        if let Some(candidate) = self.candidates.get_mut(&addr) {
+            candidate.state = DialState::Queued;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::dial::{DialConfig, DialQueue, PeerSource};
+    use std::net::SocketAddr;
+    use std::time::{Duration, Instant};
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn test_limits() {
+        let mut queue = DialQueue::new(DialConfig { max_half_open: 2, dials_per_second: 3 });
+        (1..=5).for_each(|port| queue.add(addr(port), PeerSource::Tracker));
+        let now = Instant::now();
+
+        assert_eq!(queue.next_dial(now), Some(addr(1)));
+        assert_eq!(queue.next_dial(now), Some(addr(2)));
+        assert_eq!(queue.next_dial(now), None);
+        assert_eq!(queue.half_open(), 2);
+
+        queue.connected(addr(1));
+        queue.failed(addr(2));
+        assert_eq!(queue.next_dial(now), Some(addr(3)));
+        assert_eq!(queue.next_dial(now), None);
+
+        queue.connected(addr(3));
+        assert_eq!(queue.next_dial(now + Duration::from_secs(1)), Some(addr(4)));
+    }
+
+    #[test]
+    fn test_ranking() {
+        let mut queue = DialQueue::new(DialConfig::default());
+        queue.add(addr(1), PeerSource::Pex);
+        queue.add(addr(2), PeerSource::Dht);
+        queue.add(addr(3), PeerSource::Pex);
+        queue.add(addr(3), PeerSource::Manual);
+        let now = Instant::now();
+
+        assert_eq!(queue.next_dial(now), Some(addr(3)));
+        queue.failed(addr(3));
+        assert_eq!(queue.next_dial(now), Some(addr(2)));
+        assert_eq!(queue.next_dial(now), Some(addr(1)));
+        assert_eq!(queue.next_dial(now), Some(addr(3)));
+
+        queue.failed(addr(3));
+        queue.failed(addr(3));
+        assert_eq!(queue.queued(), 0);
+        assert_eq!(queue.half_open(), 2);
+    }
+}
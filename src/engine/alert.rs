@@ -0,0 +1,54 @@
+use std::net::SocketAddr;
+use tokio::sync::broadcast;
+
+// Alerts buffered per subscriber; one that falls further behind than this
+// misses the oldest and is told how many it missed.
+pub const ALERT_CAPACITY: usize = 1024;
+
+// Something that happened to a torrent, for embedders to react to without
+// polling.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Alert {
+    PeerConnected { info_hash: [u8; 20], addr: SocketAddr },
+    PeerDisconnected { info_hash: [u8; 20], addr: SocketAddr },
+    PieceVerified { info_hash: [u8; 20], piece: u32 },
+    HashFailed { info_hash: [u8; 20], piece: u32 },
+    TorrentCompleted { info_hash: [u8; 20] },
+    TorrentPaused { info_hash: [u8; 20] },
+    TorrentResumed { info_hash: [u8; 20] },
+    // Seeded up to the ratio or time goal; a TorrentPaused follows.
+    SeedingGoalReached { info_hash: [u8; 20] },
+    // Flushing to disk or saving resume data failed.
+    StorageError { info_hash: [u8; 20], message: String },
+    // Too little room left for the rest of the torrent; a TorrentPaused
+    // follows, and a TorrentResumed once there is room again.
+    LowDiskSpace { info_hash: [u8; 20], available: u64, needed: u64 },
+    // An announce that couldn't reach the tracker or that it refused. It's
+    // tried again later.
+    TrackerError { info_hash: [u8; 20], url: String, message: String },
+    // A magnet link's torrent arrived from a peer.
+    MetadataReceived { info_hash: [u8; 20], addr: SocketAddr }
+}
+
+impl Alert {
+    pub fn info_hash(&self) -> [u8; 20] {
+        match *self {
+            Self::PeerConnected { info_hash, .. }
+            | Self::PeerDisconnected { info_hash, .. }
+            | Self::PieceVerified { info_hash, .. }
+            | Self::HashFailed { info_hash, .. }
+            | Self::TorrentCompleted { info_hash }
+            | Self::TorrentPaused { info_hash }
+            | Self::TorrentResumed { info_hash }
+            | Self::SeedingGoalReached { info_hash }
+            | Self::StorageError { info_hash, .. }
+            | Self::LowDiskSpace { info_hash, .. }
+            | Self::TrackerError { info_hash, .. }
+            | Self::MetadataReceived { info_hash, .. } => info_hash
+        }
+    }
+}
+
+pub fn channel() -> broadcast::Sender<Alert> {
+    broadcast::channel(ALERT_CAPACITY).0
+}
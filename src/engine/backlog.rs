@@ -0,0 +1,102 @@
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::sync::Notify;
+
+pub const DEFAULT_BACKLOG: u64 = 32 * 1024 * 1024;
+
+// Blocks read off a torrent's peers that it hasn't written and hashed yet.
+// Past the limit, the peers' readers wait before reading more, so their
+// sockets' receive windows fill and the peers slow down, and the torrent
+// asks for no more blocks until it catches up; the data piles up nowhere
+// in between.
+#[derive(Debug, Clone)]
+pub struct Backlog {
+    inner: Arc<Inner>
+}
+
+#[derive(Debug)]
+struct Inner {
+    bytes: AtomicU64,
+    blocks: AtomicUsize,
+    limit: u64,
+    drained: Notify
+}
+
+impl Default for Backlog {
+    fn default() -> Self {
+        Self::new(DEFAULT_BACKLOG)
+    }
+}
+
+impl Backlog {
+    pub fn new(limit: u64) -> Self {
+        let inner = Inner { bytes: AtomicU64::new(0), blocks: AtomicUsize::new(0), limit, drained: Notify::new() };
+        Self { inner: Arc::new(inner) }
+    }
+
+    // A block read, on its way to the torrent.
+    pub fn push(&self, len: usize) {
+        self.inner.bytes.fetch_add(len as u64, Ordering::Relaxed);
+        self.inner.blocks.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // A block the torrent is done with, written or not.
+    pub fn pop(&self, len: usize) {
+        self.inner.bytes.fetch_sub(len as u64, Ordering::Relaxed);
+        self.inner.blocks.fetch_sub(1, Ordering::Relaxed);
+        if !self.is_full() {
+            self.inner.drained.notify_waiters();
+        }
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.bytes() >= self.inner.limit
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.inner.bytes.load(Ordering::Relaxed)
+    }
+
+    pub fn blocks(&self) -> usize {
+        self.inner.blocks.load(Ordering::Relaxed)
+    }
+
+    // Waits for room.
+    pub async fn wait(&self) {
+        loop {
+            // Made before checking, so a `pop` in between still wakes it.
+            let drained = self.inner.drained.notified();
+            if !self.is_full() {
+                return;
+            }
+            drained.await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::engine::backlog::Backlog;
+    use std::time::Duration;
+    use tokio::time;
+
+    #[tokio::test]
+    async fn test_backlog() {
+        let backlog = Backlog::new(100);
+        backlog.push(60);
+        backlog.wait().await;
+        backlog.push(60);
+        assert!(backlog.is_full());
+        assert_eq!((backlog.blocks(), backlog.bytes()), (2, 120));
+
+        let waiting = tokio::spawn({
+            let backlog = backlog.clone();
+            async move { backlog.wait().await }
+        });
+        time::sleep(Duration::from_millis(20)).await;
+        assert!(!waiting.is_finished());
+        backlog.pop(60);
+        time::timeout(Duration::from_secs(1), waiting).await.unwrap().unwrap();
+        assert_eq!((backlog.blocks(), backlog.bytes()), (1, 60));
+    }
+}
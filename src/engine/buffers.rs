@@ -0,0 +1,92 @@
+use crate::piece::DEFAULT_BLOCK_SIZE;
+use bytes::{Bytes, BytesMut};
+use std::sync::{Arc, Mutex};
+
+const BLOCK_SIZE: usize = DEFAULT_BLOCK_SIZE as usize;
+
+// Buffers kept for reuse when nothing asks for them; beyond this they are
+// freed, so a burst doesn't pin its memory for good.
+const DEFAULT_KEEP: usize = 1024;
+
+// Block buffers shared by a session's peers. Every block a peer sends is
+// read into one and given back once written to storage, so hundreds of
+// peers streaming blocks reuse a set of equal-size allocations rather than
+// making and freeing one per message. A block is handed on frozen, as
+// `Bytes`, and comes back once nothing else holds on to it.
+#[derive(Debug, Clone)]
+pub struct BufferPool {
+    free: Arc<Mutex<Vec<BytesMut>>>,
+    keep: usize
+}
+
+impl Default for BufferPool {
+    fn default() -> Self {
+        Self::new(DEFAULT_KEEP)
+    }
+}
+
+impl BufferPool {
+    pub fn new(keep: usize) -> Self {
+        Self { free: Arc::default(), keep }
+    }
+
+    // `len` zeroed bytes. Anything up to a block comes from the pool.
+    pub fn take(&self, len: usize) -> BytesMut {
+        let reused = (len <= BLOCK_SIZE).then(|| self.free.lock().unwrap().pop()).flatten();
+        let mut buffer = reused.unwrap_or_else(|| BytesMut::with_capacity(len.max(BLOCK_SIZE)));
+        buffer.resize(len, 0);
+        buffer
+    }
+
+    // Only block-size buffers are kept, so `take` never hands out one that
+    // must grow, and only those no other clone of `data` still reads.
+    pub fn give(&self, data: Bytes) {
+        let Ok(mut buffer) = data.try_into_mut() else {
+            return;
+        };
+        if buffer.capacity() != BLOCK_SIZE {
+            return;
+        }
+        let mut free = self.free.lock().unwrap();
+        if free.len() < self.keep {
+            buffer.clear();
+            free.push(buffer);
+        }
+    }
+
+    pub fn available(&self) -> usize {
+        self.free.lock().unwrap().len()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::engine::buffers::{BufferPool, BLOCK_SIZE};
+    use bytes::Bytes;
+
+    #[test]
+    fn test_reuse() {
+        let pool = BufferPool::new(1);
+        let mut buffer = pool.take(100);
+        assert_eq!(buffer[..], [0; 100]);
+        buffer.fill(7);
+        let pointer = buffer.as_ptr();
+        let buffer = buffer.freeze();
+        // Not while a clone of it is still around.
+        let clone = buffer.clone();
+        pool.give(buffer);
+        assert_eq!(pool.available(), 0);
+        pool.give(clone);
+        assert_eq!(pool.available(), 1);
+
+        let buffer = pool.take(BLOCK_SIZE);
+        assert_eq!((buffer.as_ptr(), buffer.iter().all(|&byte| byte == 0)), (pointer, true));
+        // Past what's kept, and not block-size, go back to the allocator.
+        pool.give(pool.take(10).freeze());
+        pool.give(buffer.freeze());
+        pool.give(Bytes::from(vec![0; 10]));
+        assert_eq!(pool.available(), 1);
+        assert_eq!(pool.take(2 * BLOCK_SIZE).len(), 2 * BLOCK_SIZE);
+        assert_eq!(pool.available(), 1);
+    }
+}
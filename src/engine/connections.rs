@@ -0,0 +1,163 @@
+use crate::swarm::Peer;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+pub const DEFAULT_MAX_CONNECTIONS: usize = 200;
+pub const DEFAULT_MAX_PEERS_PER_TORRENT: usize = 50;
+pub const DEFAULT_MAX_HALF_OPEN_TOTAL: usize = 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectionLimits {
+    // Established peer connections across every torrent.
+    pub max_connections: usize,
+    // Dials in progress across every torrent. Each torrent's dial queue
+    // has a lower limit of its own.
+    pub max_half_open: usize,
+    pub per_torrent: usize
+}
+
+impl Default for ConnectionLimits {
+    fn default() -> Self {
+        Self {
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            max_half_open: DEFAULT_MAX_HALF_OPEN_TOTAL,
+            per_torrent: DEFAULT_MAX_PEERS_PER_TORRENT
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Counts {
+    limits: ConnectionLimits,
+    connections: usize,
+    half_open: usize
+}
+
+#[derive(Debug, Clone, Copy)]
+enum SlotKind {
+    Connection,
+    HalfOpen
+}
+
+// Counts connections across a session's torrents. Torrents take a slot
+// for every dial and every connection and hold it for as long as that
+// lasts; dropping the slot gives it back.
+#[derive(Debug, Clone)]
+pub struct ConnectionSlots {
+    counts: Arc<Mutex<Counts>>
+}
+
+impl ConnectionSlots {
+    pub fn new(limits: ConnectionLimits) -> Self {
+        Self { counts: Arc::new(Mutex::new(Counts { limits, connections: 0, half_open: 0 })) }
+    }
+
+    pub fn limits(&self) -> ConnectionLimits {
+        self.counts.lock().unwrap().limits
+    }
+
+    // Lower limits only stop new connections; nothing is closed.
+    pub fn set_limits(&self, limits: ConnectionLimits) {
+        self.counts.lock().unwrap().limits = limits;
+    }
+
+    pub fn connections(&self) -> usize {
+        self.counts.lock().unwrap().connections
+    }
+
+    pub fn half_open(&self) -> usize {
+        self.counts.lock().unwrap().half_open
+    }
+
+    pub fn try_connect(&self) -> Option<Slot> {
+        self.take(SlotKind::Connection)
+    }
+
+    pub fn try_dial(&self) -> Option<Slot> {
+        self.take(SlotKind::HalfOpen)
+    }
+
+    fn take(&self, kind: SlotKind) -> Option<Slot> {
+        let mut counts = self.counts.lock().unwrap();
+        let (count, max) = match kind {
+            SlotKind::Connection => (counts.connections, counts.limits.max_connections),
+            SlotKind::HalfOpen => (counts.half_open, counts.limits.max_half_open)
+        };
+        if count >= max {
+            return None;
+        }
+        match kind {
+            SlotKind::Connection => counts.connections += 1,
+            SlotKind::HalfOpen => counts.half_open += 1
+        }
+        Some(Slot { counts: self.counts.clone(), kind })
+    }
+}
+
+impl Default for ConnectionSlots {
+    fn default() -> Self {
+        Self::new(ConnectionLimits::default())
+    }
+}
+
+#[derive(Debug)]
+pub struct Slot {
+    counts: Arc<Mutex<Counts>>,
+    kind: SlotKind
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        let mut counts = self.counts.lock().unwrap();
+        match self.kind {
+            SlotKind::Connection => counts.connections -= 1,
+            SlotKind::HalfOpen => counts.half_open -= 1
+        }
+    }
+}
+
+// The peer to close to make room for a new one: one that neither side is
+// interested in, preferring the one with the most pieces, who is the least
+// likely to want anything from us later. `None` if every peer is in use.
+pub fn least_useful<'a>(peers: impl Iterator<Item = &'a Peer>) -> Option<SocketAddr> {
+    peers
+        .filter(|peer| !peer.am_interested && !peer.peer_interested)
+        .max_by_key(|peer| (peer.has.count_ones(), peer.addr))
+        .map(|peer| peer.addr)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::engine::connections::{least_useful, ConnectionLimits, ConnectionSlots};
+    use crate::swarm::Peer;
+    use std::net::SocketAddr;
+
+    #[test]
+    fn test_slots() {
+        let slots = ConnectionSlots::new(ConnectionLimits { max_connections: 2, max_half_open: 1, per_torrent: 2 });
+        let first = slots.try_connect().unwrap();
+        let _second = slots.clone().try_connect().unwrap();
+        assert!(slots.try_connect().is_none());
+        drop(first);
+        assert_eq!(slots.connections(), 1);
+        assert!(slots.try_connect().is_some());
+
+        let dial = slots.try_dial().unwrap();
+        assert!(slots.try_dial().is_none());
+        drop(dial);
+        assert_eq!(slots.half_open(), 0);
+    }
+
+    #[test]
+    fn test_least_useful() {
+        let addr = |port| SocketAddr::from(([127, 0, 0, 1], port));
+        let mut busy = Peer::new(addr(1), 8);
+        busy.peer_interested = true;
+        let mut idle = Peer::new(addr(2), 8);
+        idle.has.set(3);
+        let empty = Peer::new(addr(3), 8);
+
+        assert_eq!(least_useful([&busy, &idle, &empty].into_iter()), Some(addr(2)));
+        assert_eq!(least_useful([&busy].into_iter()), None);
+    }
+}
@@ -0,0 +1,735 @@
+#[cfg(feature = "dht")]
+use crate::dht::announce::TorrentAnnouncer;
+#[cfg(feature = "dht")]
+use crate::dht::Dht;
+#[cfg(feature = "dht")]
+use crate::engine::metrics::Metrics;
+use crate::engine::rpc::{self, EventLog};
+use crate::engine::transmission;
+use crate::engine::watch::{self, WatchFolder};
+use crate::engine::{BandwidthSchedule, SeedGoal, Session, TorrentHandle};
+use crate::hash::{hex, unhex, unhex_bytes};
+use crate::magnet::Magnet;
+use crate::metainfo::{Limits, Metainfo};
+use crate::torrent::Torrent;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+#[cfg(feature = "dht")]
+use std::sync::{mpsc as std_mpsc, Mutex};
+#[cfg(feature = "dht")]
+use std::thread;
+use std::time::Duration;
+#[cfg(feature = "dht")]
+use std::time::Instant;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{mpsc, oneshot};
+use tracing::{debug, info};
+
+// Longest request line accepted, to bound what a client can make us buffer.
+const MAX_LINE_LEN: usize = 64 * 1024;
+// How often the DHT thread looks for torrents due to announce again.
+#[cfg(feature = "dht")]
+const DHT_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+// What a control client can ask a running session to do. Requests and
+// responses are JSON objects, one per line; a connection can carry any
+// number of them.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Request {
+    // Paths are read by the daemon, so they should be absolute.
+    Add { torrent: PathBuf, dir: PathBuf, peers: Vec<SocketAddr> },
+    // A .torrent sent along rather than read from disk.
+    AddMetainfo { metainfo: Vec<u8>, dir: PathBuf },
+    // A magnet link, whose torrent is fetched from its peers and those its
+    // trackers give before it's added.
+    AddMagnet { magnet: String, dir: PathBuf, peers: Vec<SocketAddr> },
+    Remove { info_hash: [u8; 20] },
+    Pause { info_hash: [u8; 20] },
+    Resume { info_hash: [u8; 20] },
+    Stats,
+    // Global limits in bytes per second, zero for unlimited.
+    SetLimits { upload: u64, download: u64 },
+    // A bandwidth schedule's rules, as `BandwidthSchedule::parse` reads
+    // them, and the limits outside them. No rules stops scheduling.
+    SetSchedule { rules: Option<String>, upload: u64, download: u64 },
+    // The second pair of global limits, and whether they hold instead.
+    SetAlternateLimits { upload: u64, download: u64 },
+    SetAlternateSpeed { enabled: bool },
+    // When finished torrents without a goal of their own stop seeding;
+    // neither set seeds them forever.
+    SetSeedGoal { ratio: Option<f64>, time: Option<Duration> },
+    Shutdown
+}
+
+impl Request {
+    pub fn to_json(&self) -> Value {
+        match self {
+            Self::Add { torrent, dir, peers } => json!({ "command": "add", "torrent": torrent, "dir": dir, "peers": peers }),
+            Self::AddMetainfo { metainfo, dir } => json!({ "command": "add_metainfo", "metainfo": hex(metainfo), "dir": dir }),
+            Self::AddMagnet { magnet, dir, peers } => json!({ "command": "add_magnet", "magnet": magnet, "dir": dir, "peers": peers }),
+            Self::Remove { info_hash } => json!({ "command": "remove", "info_hash": hex(info_hash) }),
+            Self::Pause { info_hash } => json!({ "command": "pause", "info_hash": hex(info_hash) }),
+            Self::Resume { info_hash } => json!({ "command": "resume", "info_hash": hex(info_hash) }),
+            Self::Stats => json!({ "command": "stats" }),
+            Self::SetLimits { upload, download } => json!({ "command": "set_limits", "upload": upload, "download": download }),
+            Self::SetSchedule { rules, upload, download } => {
+                json!({ "command": "set_schedule", "rules": rules, "upload": upload, "download": download })
+            },
+            Self::SetAlternateLimits { upload, download } => {
+                json!({ "command": "set_alternate_limits", "upload": upload, "download": download })
+            },
+            Self::SetAlternateSpeed { enabled } => json!({ "command": "set_alternate_speed", "enabled": enabled }),
+            Self::SetSeedGoal { ratio, time } => {
+                json!({ "command": "set_seed_goal", "ratio": ratio, "time": time.map(|time| time.as_secs()) })
+            },
+            Self::Shutdown => json!({ "command": "shutdown" })
+        }
+    }
+
+    pub fn from_json(value: &Value) -> Option<Self> {
+        let info_hash = || unhex(value.get("info_hash")?.as_str()?);
+        let peers = || match value.get("peers") {
+            Some(peers) => peers.as_array()?.iter().map(|peer| peer.as_str()?.parse().ok()).collect(),
+            None => Some(Vec::new())
+        };
+        let request = match value.get("command")?.as_str()? {
+            "add" => Self::Add { torrent: value.get("torrent")?.as_str()?.into(), dir: value.get("dir")?.as_str()?.into(), peers: peers()? },
+            "add_metainfo" => Self::AddMetainfo {
+                metainfo: unhex_bytes(value.get("metainfo")?.as_str()?)?,
+                dir: value.get("dir")?.as_str()?.into()
+            },
+            "add_magnet" => Self::AddMagnet {
+                magnet: value.get("magnet")?.as_str().filter(|magnet| Magnet::parse(magnet).is_some())?.to_string(),
+                dir: value.get("dir")?.as_str()?.into(),
+                peers: peers()?
+            },
+            "remove" => Self::Remove { info_hash: info_hash()? },
+            "pause" => Self::Pause { info_hash: info_hash()? },
+            "resume" => Self::Resume { info_hash: info_hash()? },
+            "stats" => Self::Stats,
+            "set_limits" => Self::SetLimits { upload: value.get("upload")?.as_u64()?, download: value.get("download")?.as_u64()? },
+            "set_schedule" => Self::SetSchedule {
+                rules: match value.get("rules") {
+                    Some(Value::Null) | None => None,
+                    Some(rules) => Some(rules.as_str()?.to_string())
+                },
+                upload: value.get("upload").map_or(Some(0), Value::as_u64)?,
+                download: value.get("download").map_or(Some(0), Value::as_u64)?
+            },
+            "set_alternate_limits" => Self::SetAlternateLimits {
+                upload: value.get("upload")?.as_u64()?,
+                download: value.get("download")?.as_u64()?
+            },
+            "set_alternate_speed" => Self::SetAlternateSpeed { enabled: value.get("enabled")?.as_bool()? },
+            "set_seed_goal" => Self::SetSeedGoal {
+                ratio: match value.get("ratio") {
+                    Some(Value::Null) | None => None,
+                    Some(ratio) => Some(ratio.as_f64()?)
+                },
+                time: match value.get("time") {
+                    Some(Value::Null) | None => None,
+                    Some(time) => Some(Duration::from_secs(time.as_u64()?))
+                }
+            },
+            "shutdown" => Self::Shutdown,
+            _ => return None
+        };
+        Some(request)
+    }
+}
+
+fn error(message: impl ToString) -> Value {
+    json!({ "error": message.to_string() })
+}
+
+fn stats(handle: &TorrentHandle, dir: Option<&PathBuf>) -> Value {
+    let stats = handle.stats();
+    json!({
+        "info_hash": hex(&handle.info_hash()),
+        "name": handle.name(),
+        "dir": dir,
+        "percent": stats.percent(),
+        "bytes_done": stats.bytes_done,
+        "bytes_total": stats.bytes_total,
+        "uploaded": stats.uploaded,
+        "downloaded": stats.downloaded,
+        "upload_rate": stats.upload_rate,
+        "download_rate": stats.download_rate,
+        "peers": stats.connected_peers,
+        "paused": handle.is_paused(),
+        "queued": stats.queued,
+        "backlog_blocks": stats.backlog_blocks,
+        "backlog_bytes": stats.backlog_bytes
+    })
+}
+
+fn read(torrent: &Path) -> Result<Metainfo, String> {
+    let bytes = std::fs::read(torrent).map_err(|err| format!("cannot read {}: {}", torrent.display(), err))?;
+    Metainfo::from_bytes(&bytes).ok_or_else(|| format!("{} is not a valid torrent", torrent.display()))
+}
+
+// Runs `f` on a thread that may block.
+async fn blocking<T: Send + 'static>(f: impl FnOnce() -> Result<T, String> + Send + 'static) -> Result<T, String> {
+    tokio::task::spawn_blocking(f).await.unwrap_or_else(|err| Err(err.to_string()))
+}
+
+// Checks what of the torrent is already on disk, which is too slow to do
+// on the runtime. Resume data left next to it, as `import` and `download`
+// leave it, spares hashing the files that haven't changed.
+fn open(metainfo: Metainfo, dir: &Path) -> Result<Torrent, String> {
+    let resume_path = dir.join(format!(".{}.resume", hex(&metainfo.info_hash)));
+    let mut torrent = Torrent::new(metainfo, dir).ok_or("the torrent's pieces don't match its files")?;
+    torrent.resume_from(&resume_path);
+    Ok(torrent)
+}
+
+pub(super) enum Command {
+    Request(Request, oneshot::Sender<Value>),
+    // A torrent opened off the loop, for it to add to the session.
+    Opened(Result<Box<Torrent>, String>, PathBuf, Vec<SocketAddr>, oneshot::Sender<Value>),
+    // Peers a DHT lookup turned up for a torrent.
+    #[cfg(feature = "dht")]
+    Peers([u8; 20], Vec<SocketAddr>)
+}
+
+// Runs a session on behalf of control clients, until one asks it to shut
+// down.
+pub struct ControlServer {
+    session: Session,
+    #[cfg(feature = "dht")]
+    dht: Option<Dht>,
+    // Public torrents, announced on the DHT by a thread of its own, which
+    // `wake_dht` has look for due torrents straight away.
+    #[cfg(feature = "dht")]
+    announcers: Arc<Mutex<HashMap<[u8; 20], TorrentAnnouncer>>>,
+    #[cfg(feature = "dht")]
+    wake_dht: Option<std_mpsc::Sender<()>>,
+    rpc: Option<TcpListener>,
+    transmission: Option<(TcpListener, PathBuf)>,
+    watch: Option<WatchFolder>,
+    limits: Option<Limits>,
+    // Where each torrent was added to download.
+    dirs: HashMap<[u8; 20], PathBuf>
+}
+
+impl ControlServer {
+    pub fn new(session: Session) -> Self {
+        Self {
+            session,
+            #[cfg(feature = "dht")]
+            dht: None,
+            #[cfg(feature = "dht")]
+            announcers: Arc::default(),
+            #[cfg(feature = "dht")]
+            wake_dht: None,
+            rpc: None,
+            transmission: None,
+            watch: None,
+            limits: Some(Limits::default()),
+            dirs: HashMap::new()
+        }
+    }
+
+    // Refuses torrents outside `limits`, however they're added, before
+    // their files are looked at; `None` takes any torrent.
+    pub fn with_limits(mut self, limits: Option<Limits>) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    // Also takes JSON-RPC 2.0 calls over HTTP on `listener`, for scripts and
+    // front ends elsewhere. Anyone who can reach it can control the
+    // session, so it is best kept on localhost. Calls must be posted as
+    // `application/json`, and a browser's from any web page are refused,
+    // so that no site the user visits can make them.
+    pub fn with_rpc(mut self, listener: TcpListener) -> Self {
+        self.rpc = Some(listener);
+        self
+    }
+
+    // Also speaks enough of Transmission's RPC on `listener` for its
+    // remotes and the *arr apps. Torrents they add without a directory go
+    // to `download_dir`.
+    pub fn with_transmission(mut self, listener: TcpListener, download_dir: PathBuf) -> Self {
+        self.transmission = Some((listener, download_dir));
+        self
+    }
+
+    // Also adds the torrents dropped into `watch`.
+    pub fn with_watch(mut self, watch: WatchFolder) -> Self {
+        self.watch = Some(watch);
+        self
+    }
+
+    // Public torrents are announced on `dht` when added and again every
+    // `ANNOUNCE_INTERVAL`, and given the peers found there.
+    #[cfg(feature = "dht")]
+    pub fn with_dht(mut self, dht: Dht) -> Self {
+        self.dht = Some(dht);
+        self
+    }
+
+    // Serves clients on the control socket at `path` and shuts the session
+    // down once asked to, or on Ctrl+C or SIGTERM. Returns whether the
+    // shutdown was clean.
+    pub async fn serve(mut self, path: &Path) -> io::Result<bool> {
+        let mut listener = ControlListener::bind(path)?;
+        let (commands, mut receiver) = mpsc::unbounded_channel();
+        let rpc = self.rpc.take().map(|listener| {
+            let log = Arc::new(EventLog::default());
+            let mut alerts = self.session.subscribe();
+            let events = tokio::spawn({
+                let log = log.clone();
+                async move {
+                    loop {
+                        match alerts.recv().await {
+                            Ok(alert) => log.push(alert),
+                            Err(RecvError::Lagged(_)) => continue,
+                            Err(RecvError::Closed) => break
+                        }
+                    }
+                }
+            });
+            [events, tokio::spawn(rpc::serve(listener, commands.clone(), log))]
+        });
+        let transmission = self
+            .transmission
+            .take()
+            .map(|(listener, dir)| tokio::spawn(transmission::serve(listener, commands.clone(), dir)));
+        let watch = self.watch.take().map(|watch| tokio::spawn(watch::serve(watch, commands.clone())));
+        #[cfg(feature = "dht")]
+        if let Some(dht) = self.dht.take() {
+            let (wake, woken) = std_mpsc::channel();
+            let (announcers, commands) = (self.announcers.clone(), commands.clone());
+            let metrics = self.session.metrics().clone();
+            thread::spawn(move || announce_dht(dht, announcers, woken, commands, metrics));
+            self.wake_dht = Some(wake);
+        }
+        let interrupted = interrupted();
+        tokio::pin!(interrupted);
+        loop {
+            tokio::select! {
+                _ = &mut interrupted => {
+                    info!("interrupted, shutting down");
+                    break;
+                },
+                accepted = listener.accept() => match accepted {
+                    Ok(stream) => {
+                        tokio::spawn(connection(stream, commands.clone()));
+                    },
+                    Err(err) => debug!(error = %err, "control accept failed")
+                },
+                Some(command) = receiver.recv() => match command {
+                    Command::Request(Request::Shutdown, reply) => {
+                        let _ = reply.send(json!({ "ok": true }));
+                        break;
+                    },
+                    Command::Request(request, reply) => self.handle(request, reply, &commands).await,
+                    Command::Opened(opened, dir, peers, reply) => {
+                        let _ = reply.send(self.added(opened, dir, peers));
+                    },
+                    #[cfg(feature = "dht")]
+                    Command::Peers(info_hash, peers) => {
+                        if let Some(handle) = self.session.torrent(&info_hash) {
+                            peers.into_iter().for_each(|peer| handle.add_peer(peer));
+                        }
+                    }
+                }
+            }
+        }
+        drop(listener);
+        rpc.into_iter().flatten().chain(transmission).chain(watch).for_each(|task| task.abort());
+        Ok(self.session.shutdown().await)
+    }
+
+    // Opens the torrent on a task of its own, since hashing what's on disk
+    // can take minutes, and has the loop add it once that's done. Other
+    // requests are served meanwhile.
+    fn add(
+        &self,
+        metainfo: impl Future<Output = Result<Metainfo, String>> + Send + 'static,
+        dir: PathBuf,
+        peers: Vec<SocketAddr>,
+        reply: oneshot::Sender<Value>,
+        commands: &mpsc::UnboundedSender<Command>
+    ) {
+        let (limits, commands) = (self.limits, commands.clone());
+        tokio::spawn(async move {
+            let opened = async {
+                let (metainfo, dir) = (metainfo.await?, dir.clone());
+                blocking(move || {
+                    if let Some(limits) = limits {
+                        metainfo.check_limits(&limits).map_err(|err| format!("refusing the torrent: {}", err))?;
+                    }
+                    open(metainfo, &dir).map(Box::new)
+                })
+                .await
+            };
+            let opened = opened.await;
+            let _ = commands.send(Command::Opened(opened, dir, peers, reply));
+        });
+    }
+
+    fn added(&mut self, opened: Result<Box<Torrent>, String>, dir: PathBuf, peers: Vec<SocketAddr>) -> Value {
+        let torrent = match opened {
+            Ok(torrent) => torrent,
+            Err(err) => return error(err)
+        };
+        #[cfg(feature = "dht")]
+        let (port, private) = (self.session.listen_port(), torrent.metainfo().private);
+        let Some(handle) = self.session.add_torrent(*torrent) else {
+            return error("the torrent is already running");
+        };
+        let info_hash = handle.info_hash();
+        let response = json!({ "ok": true, "info_hash": hex(&info_hash), "name": handle.name() });
+        peers.into_iter().for_each(|peer| handle.add_peer(peer));
+        #[cfg(feature = "dht")]
+        if let (false, Some(wake)) = (private, &self.wake_dht) {
+            self.announcers.lock().unwrap().insert(info_hash, TorrentAnnouncer::new(info_hash, port, private));
+            let _ = wake.send(());
+        }
+        self.dirs.insert(info_hash, dir);
+        response
+    }
+
+    async fn handle(&mut self, request: Request, reply: oneshot::Sender<Value>, commands: &mpsc::UnboundedSender<Command>) {
+        let found = |found: bool| match found {
+            true => json!({ "ok": true }),
+            false => error("no such torrent")
+        };
+        let response = match request {
+            Request::Add { torrent, dir, peers } => return self.add(blocking(move || read(&torrent)), dir, peers, reply, commands),
+            Request::AddMetainfo { metainfo, dir } => {
+                let metainfo = blocking(move || Metainfo::from_bytes(&metainfo).ok_or_else(|| "not a valid torrent".to_string()));
+                return self.add(metainfo, dir, Vec::new(), reply, commands);
+            },
+            Request::AddMagnet { magnet, dir, peers } => match Magnet::parse(&magnet) {
+                None => error("not a valid magnet link"),
+                Some(magnet) if self.session.torrent(&magnet.info_hash).is_some() => error("the torrent is already running"),
+                Some(magnet) => {
+                    let (info_hash, fetched) = (magnet.info_hash, self.session.fetch_metadata(&magnet, &peers));
+                    let metainfo = async move { fetched.await.map_err(|err| format!("cannot fetch the metadata for {}: {}", hex(&info_hash), err)) };
+                    return self.add(metainfo, dir, peers, reply, commands);
+                }
+            },
+            Request::Remove { info_hash } => {
+                self.dirs.remove(&info_hash);
+                #[cfg(feature = "dht")]
+                self.announcers.lock().unwrap().remove(&info_hash);
+                found(self.session.remove_torrent(&info_hash).await)
+            },
+            Request::Pause { info_hash } => found(self.session.pause(&info_hash)),
+            Request::Resume { info_hash } => found(self.session.resume(&info_hash)),
+            Request::Stats => {
+                let torrents: Vec<_> = self.session.torrents().map(|handle| stats(handle, self.dirs.get(&handle.info_hash()))).collect();
+                json!({ "ok": true, "torrents": torrents })
+            },
+            Request::SetLimits { upload, download } => {
+                self.session.set_limits(upload, download);
+                json!({ "ok": true })
+            },
+            Request::SetSchedule { rules, upload, download } => {
+                match rules.as_deref().map(BandwidthSchedule::parse) {
+                    Some(None) => error("not a valid schedule"),
+                    schedule => {
+                        let schedule = schedule.flatten().map(|schedule| BandwidthSchedule { default_upload: upload, default_download: download, ..schedule });
+                        self.session.set_bandwidth_schedule(schedule);
+                        json!({ "ok": true })
+                    }
+                }
+            },
+            Request::SetAlternateLimits { upload, download } => {
+                self.session.set_alternate_limits(upload, download);
+                json!({ "ok": true })
+            },
+            Request::SetAlternateSpeed { enabled } => {
+                self.session.set_alternate_speed(enabled);
+                json!({ "ok": true })
+            },
+            Request::SetSeedGoal { ratio, time } => {
+                self.session.set_seed_goal(SeedGoal { ratio, time });
+                json!({ "ok": true })
+            },
+            Request::Shutdown => json!({ "ok": true })
+        };
+        let _ = reply.send(response);
+    }
+}
+
+// Resolves on Ctrl+C, or on SIGTERM, which is how service managers stop a
+// daemon. Where neither can be listened for, never.
+async fn interrupted() {
+    let interrupt = async {
+        if tokio::signal::ctrl_c().await.is_err() {
+            std::future::pending::<()>().await;
+        }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            },
+            Err(_) => std::future::pending().await
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+    tokio::select! {
+        _ = interrupt => {},
+        _ = terminate => {}
+    }
+}
+
+// Announces each torrent as it falls due, and sends the peers found back to
+// the server. Lookups block, and the lock is only held to see what's due.
+// The session's metrics get the routing table's size as it changes. Stops
+// once the server is gone.
+#[cfg(feature = "dht")]
+fn announce_dht(
+    mut dht: Dht,
+    announcers: Arc<Mutex<HashMap<[u8; 20], TorrentAnnouncer>>>,
+    woken: std_mpsc::Receiver<()>,
+    commands: mpsc::UnboundedSender<Command>,
+    metrics: Arc<Metrics>
+) {
+    metrics.set_dht_nodes(dht.routing_table().len());
+    while let Ok(()) | Err(std_mpsc::RecvTimeoutError::Timeout) = woken.recv_timeout(DHT_CHECK_INTERVAL) {
+        let now = Instant::now();
+        let due: Vec<_> = announcers.lock().unwrap().values().filter(|announcer| announcer.is_due(now)).cloned().collect();
+        for mut announcer in due {
+            let info_hash = announcer.info_hash();
+            let Some(peers) = announcer.announce(&mut dht, now) else {
+                continue;
+            };
+            debug!(info_hash = %hex(&info_hash), peers = peers.len(), "announced on the DHT");
+            // Unless it was removed meanwhile.
+            if let Some(entry) = announcers.lock().unwrap().get_mut(&info_hash) {
+                *entry = announcer;
+            }
+            metrics.set_dht_nodes(dht.routing_table().len());
+            if commands.send(Command::Peers(info_hash, peers)).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+// Has the server carry out `request` and waits for its response.
+pub(super) async fn execute(commands: &mpsc::UnboundedSender<Command>, request: Request) -> Value {
+    let (reply, response) = oneshot::channel();
+    let _ = commands.send(Command::Request(request, reply));
+    response.await.unwrap_or_else(|_| error("shutting down"))
+}
+
+async fn connection(stream: impl AsyncRead + AsyncWrite, commands: mpsc::UnboundedSender<Command>) {
+    let (reader, mut writer) = tokio::io::split(stream);
+    let mut lines = BufReader::new(reader.take(MAX_LINE_LEN as u64)).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        lines.get_mut().get_mut().set_limit(MAX_LINE_LEN as u64);
+        let response = match serde_json::from_str(&line).ok().as_ref().and_then(Request::from_json) {
+            Some(request) => execute(&commands, request).await,
+            None => error("invalid request")
+        };
+        if writer.write_all(format!("{}\n", response).as_bytes()).await.is_err() {
+            break;
+        }
+    }
+}
+
+// Sends one request to the daemon listening on `path` and returns its
+// response. Errors it reports come back as `io::ErrorKind::Other`.
+pub async fn send(path: &Path, request: &Request) -> io::Result<Value> {
+    let stream = connect(path).await?;
+    let (reader, mut writer) = tokio::io::split(stream);
+    writer.write_all(format!("{}\n", request.to_json()).as_bytes()).await?;
+    let mut line = String::new();
+    BufReader::new(reader).read_line(&mut line).await?;
+    let response: Value = serde_json::from_str(&line).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    match response.get("error").and_then(Value::as_str) {
+        Some(message) => Err(io::Error::other(message)),
+        None => Ok(response)
+    }
+}
+
+// Where the daemon listens unless told otherwise.
+#[cfg(unix)]
+pub fn default_socket() -> PathBuf {
+    match std::env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) => PathBuf::from(dir).join("bittorrent-rs.sock"),
+        None => std::env::temp_dir().join(format!("bittorrent-rs-{}.sock", unsafe { libc::getuid() }))
+    }
+}
+
+#[cfg(windows)]
+pub fn default_socket() -> PathBuf {
+    PathBuf::from(r"\\.\pipe\bittorrent-rs")
+}
+
+// A Unix domain socket, removed again when dropped.
+#[cfg(unix)]
+struct ControlListener {
+    listener: tokio::net::UnixListener,
+    path: PathBuf
+}
+
+#[cfg(unix)]
+impl ControlListener {
+    // A socket left behind by a daemon that died is replaced; one that
+    // still answers is not.
+    fn bind(path: &Path) -> io::Result<Self> {
+        if path.exists() {
+            if std::os::unix::net::UnixStream::connect(path).is_ok() {
+                return Err(io::Error::new(io::ErrorKind::AddrInUse, "a daemon is already listening there"));
+            }
+            std::fs::remove_file(path)?;
+        }
+        Ok(Self { listener: tokio::net::UnixListener::bind(path)?, path: path.to_path_buf() })
+    }
+
+    async fn accept(&mut self) -> io::Result<tokio::net::UnixStream> {
+        Ok(self.listener.accept().await?.0)
+    }
+}
+
+#[cfg(unix)]
+impl Drop for ControlListener {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(unix)]
+async fn connect(path: &Path) -> io::Result<tokio::net::UnixStream> {
+    tokio::net::UnixStream::connect(path).await
+}
+
+// A named pipe, with a fresh instance waiting for the next client
+// whenever one connects.
+#[cfg(windows)]
+struct ControlListener {
+    server: tokio::net::windows::named_pipe::NamedPipeServer,
+    path: PathBuf
+}
+
+#[cfg(windows)]
+impl ControlListener {
+    fn bind(path: &Path) -> io::Result<Self> {
+        let server = tokio::net::windows::named_pipe::ServerOptions::new().first_pipe_instance(true).create(path)?;
+        Ok(Self { server, path: path.to_path_buf() })
+    }
+
+    async fn accept(&mut self) -> io::Result<tokio::net::windows::named_pipe::NamedPipeServer> {
+        self.server.connect().await?;
+        let next = tokio::net::windows::named_pipe::ServerOptions::new().create(&self.path)?;
+        Ok(std::mem::replace(&mut self.server, next))
+    }
+}
+
+#[cfg(windows)]
+async fn connect(path: &Path) -> io::Result<tokio::net::windows::named_pipe::NamedPipeClient> {
+    tokio::net::windows::named_pipe::ClientOptions::new().open(path)
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use crate::engine::control::{send, ControlServer, Request};
+    use crate::engine::test::metainfo;
+    use crate::engine::Session;
+    use crate::hash::hex;
+    use crate::nat::natpmp::test::gateway;
+    use crate::nat::Gateway;
+    use std::time::Duration;
+    use tokio::time;
+
+    #[test]
+    fn test_request_json() {
+        let requests = [
+            Request::Add { torrent: "/a.torrent".into(), dir: "/downloads".into(), peers: vec!["10.0.0.1:6881".parse().unwrap()] },
+            Request::AddMetainfo { metainfo: b"d4:infode".to_vec(), dir: "/downloads".into() },
+            Request::AddMagnet {
+                magnet: "magnet:?xt=urn:btih:c12fe1c06bba254a9dc9f519b335aa7c1367a88a".into(),
+                dir: "/downloads".into(),
+                peers: Vec::new()
+            },
+            Request::Remove { info_hash: [0xab; 20] },
+            Request::Pause { info_hash: [1; 20] },
+            Request::Stats,
+            Request::SetLimits { upload: 1 << 20, download: 0 },
+            Request::SetSchedule { rules: Some("mon-fri 09:00-17:00 100K 1M".into()), upload: 0, download: 1 << 20 },
+            Request::SetSchedule { rules: None, upload: 0, download: 0 },
+            Request::SetAlternateLimits { upload: 100_000, download: 0 },
+            Request::SetAlternateSpeed { enabled: true },
+            Request::SetSeedGoal { ratio: Some(1.5), time: Some(Duration::from_secs(3600)) },
+            Request::SetSeedGoal { ratio: None, time: None },
+            Request::Shutdown
+        ];
+        for request in requests {
+            assert_eq!(Request::from_json(&request.to_json()), Some(request));
+        }
+        assert_eq!(Request::from_json(&serde_json::json!({ "command": "pause", "info_hash": "ab" })), None);
+        assert_eq!(Request::from_json(&serde_json::json!({ "command": "reboot" })), None);
+        assert_eq!(Request::from_json(&serde_json::json!({ "command": "add_magnet", "magnet": "magnet:?dn=x", "dir": "/" })), None);
+    }
+
+    #[tokio::test]
+    async fn test_control() {
+        let data: Vec<u8> = (0..40_000u32).map(|i| (i % 7) as u8).collect();
+        let metainfo = metainfo(&data, 16 * 1024);
+        let info_hash = metainfo.info_hash;
+        let dir = std::env::temp_dir().join(format!("control-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("file"), &data).unwrap();
+        let torrent = dir.join("file.torrent");
+        std::fs::write(&torrent, metainfo.raw.encode()).unwrap();
+        let socket = dir.join("control.sock");
+
+        let mut session = Session::bind("127.0.0.1:0").await.unwrap();
+        let (router, mappings) = gateway().await;
+        session.forward_port_via(Gateway::NatPmp(router));
+        let server = tokio::spawn({
+            let socket = socket.clone();
+            async move { ControlServer::new(session).serve(&socket).await }
+        });
+        time::timeout(Duration::from_secs(5), async {
+            while !socket.exists() || mappings.lock().unwrap().len() < 2 {
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        let add = Request::Add { torrent: torrent.clone(), dir: dir.clone(), peers: Vec::new() };
+        let added = send(&socket, &add).await.unwrap();
+        assert_eq!(added["info_hash"], hex(&info_hash));
+        assert_eq!(send(&socket, &add).await.unwrap_err().to_string(), "the torrent is already running");
+        let tiny = Request::AddMetainfo { metainfo: crate::engine::test::metainfo(&data[..10], 4).raw.encode(), dir: dir.clone() };
+        assert_eq!(send(&socket, &tiny).await.unwrap_err().to_string(), "refusing the torrent: piece length 4 is too small");
+        send(&socket, &Request::Pause { info_hash }).await.unwrap();
+
+        let stats = send(&socket, &Request::Stats).await.unwrap();
+        assert_eq!(stats["torrents"][0]["name"], "file");
+        assert_eq!(stats["torrents"][0]["paused"], true);
+        assert_eq!(stats["torrents"][0]["bytes_done"], 40_000);
+        assert_eq!(stats["torrents"][0]["dir"], dir.to_str().unwrap());
+
+        send(&socket, &Request::Remove { info_hash }).await.unwrap();
+        assert!(send(&socket, &Request::Resume { info_hash }).await.is_err());
+        assert_eq!(send(&socket, &Request::Stats).await.unwrap()["torrents"], serde_json::json!([]));
+
+        send(&socket, &Request::Shutdown).await.unwrap();
+        assert!(server.await.unwrap().unwrap());
+        assert!(!socket.exists());
+        // Both mappings are gone again, as after Ctrl+C or SIGTERM.
+        let lifetimes: Vec<_> = mappings.lock().unwrap().iter().map(|request| request[8..12].to_vec()).collect();
+        assert_eq!(lifetimes[2..], [[0; 4], [0; 4]]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,470 @@
+use crate::block::BlockRequest;
+use crate::engine::alert::Alert;
+use crate::engine::backlog::Backlog;
+use crate::engine::buffers::BufferPool;
+use crate::engine::metrics::Metrics;
+use crate::engine::torrent::{Event, Shared, TorrentOptions};
+use crate::merkle::BlockVerifier;
+use crate::storage::cache::WriteCache;
+use crate::storage::durability::{Durability, SyncPolicy};
+use crate::storage::journal::{self, Journal, JournalEntry};
+use crate::storage::resume::ResumeData;
+use crate::storage::sanitize;
+use crate::storage::space::SpaceMonitor;
+use crate::storage::Storage;
+use crate::torrent::Torrent;
+use bytes::Bytes;
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Instant;
+use tokio::runtime::Handle;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::{broadcast, oneshot};
+use tracing::{debug, error, info, warn, Span};
+
+// Work for a torrent's disk thread, done in the order it was sent, so a
+// piece is only hashed once the writes before it are done.
+#[derive(Debug)]
+pub(crate) enum DiskJob {
+    // Checked against the piece's hash tree, if there is one, then written.
+    // Answered with `Event::Written`.
+    Write { addr: SocketAddr, request: BlockRequest, data: Bytes },
+    // A piece with every block written, answered with `Event::Hashed`.
+    Hash(u32),
+    Verified(u32),
+    // A block a peer asked for, answered with `Event::Served`.
+    Serve { addr: SocketAddr, request: BlockRequest },
+    Read { offset: u64, len: usize, reply: oneshot::Sender<io::Result<Vec<u8>>> },
+    // Flushes the storage and saves resume data, then says so.
+    Save { uploaded: u64, downloaded: u64, done: oneshot::Sender<()> },
+    // How many bytes the wanted pieces still need, sent every tick. Checked
+    // against the free space when a check is due, and answered with
+    // `Event::Space` when that changes.
+    CheckSpace { needed: u64 }
+}
+
+// Hands a torrent's reads and writes to a thread of its own, so a slow disk
+// holds up neither its peers nor the other torrents on the runtime. The
+// thread stops once every handle is dropped.
+#[derive(Debug, Clone)]
+pub(crate) struct Disk {
+    jobs: Sender<DiskJob>
+}
+
+impl Disk {
+    pub fn send(&self, job: DiskJob) {
+        let _ = self.jobs.send(job);
+    }
+}
+
+// What the disk thread owns, and where its answers go.
+pub(crate) struct DiskThread<S: Storage> {
+    torrent: Torrent<S>,
+    events: UnboundedSender<Event>,
+    merkle: Option<Arc<Mutex<BlockVerifier>>>,
+    resume_path: Option<PathBuf>,
+    // Where the files go once complete, until they've gone.
+    destination: Option<PathBuf>,
+    // Blocks wait here until their piece is complete, to be written in as
+    // few calls as they can.
+    cache: WriteCache,
+    durability: Durability,
+    // Pieces verified since the resume data was saved, in case it never is
+    // again. A piece only goes in once its data is synced, so there is only
+    // a journal under a policy that syncs, and the pieces verified since
+    // the last sync wait for the next.
+    journal: Option<Journal>,
+    unsynced: Vec<u32>,
+    space: SpaceMonitor,
+    // Blocks are let go of here once written.
+    buffers: BufferPool,
+    backlog: Backlog,
+    metrics: Arc<Metrics>,
+    alerts: broadcast::Sender<Alert>
+}
+
+impl<S: Storage + Send + 'static> DiskThread<S> {
+    pub fn new(
+        torrent: Torrent<S>,
+        options: &TorrentOptions,
+        shared: &Shared,
+        events: UnboundedSender<Event>,
+        merkle: Option<Arc<Mutex<BlockVerifier>>>,
+        backlog: Backlog
+    ) -> Self {
+        Self {
+            torrent,
+            events,
+            merkle,
+            resume_path: options.resume_path().cloned(),
+            destination: options.destination().cloned(),
+            cache: WriteCache::new(options.cache_size()),
+            durability: Durability::new(options.sync_policy()),
+            journal: None,
+            unsynced: Vec::new(),
+            space: SpaceMonitor::default(),
+            buffers: shared.buffers.clone(),
+            backlog,
+            metrics: shared.metrics.clone(),
+            alerts: shared.alerts.clone()
+        }
+    }
+
+    // Must be called on a tokio runtime, whose blocking threads hash the
+    // pieces while this one carries on writing.
+    pub fn spawn(mut self, span: Span) -> Disk {
+        let (jobs, receiver) = mpsc::channel();
+        let runtime = Handle::current();
+        thread::spawn(move || {
+            let _entered = span.enter();
+            self.open_journal();
+            // In case the torrent was stopped before it could.
+            self.finish_files();
+            if self.torrent.have().is_complete() {
+                self.move_to_destination();
+            }
+            self.run(receiver, runtime);
+        });
+        Disk { jobs }
+    }
+
+    fn open_journal(&mut self) {
+        let Some(path) = self.resume_path.as_deref().filter(|_| self.durability.policy() != SyncPolicy::Never) else {
+            return;
+        };
+        match Journal::open(journal::path_for(path)) {
+            Ok(journal) => self.journal = Some(journal),
+            Err(err) => warn!(error = %err, "cannot open the journal")
+        }
+    }
+
+    fn run(mut self, jobs: Receiver<DiskJob>, runtime: Handle) {
+        for job in jobs {
+            match job {
+                DiskJob::Write { addr, request, data } => self.write(addr, request, data),
+                DiskJob::Hash(piece) => self.hash(piece, &runtime),
+                DiskJob::Verified(piece) => self.verified(piece),
+                DiskJob::Serve { addr, request } => {
+                    if let Ok(data) = self.torrent.storage().read(request.index, request.begin, request.length as usize) {
+                        let _ = self.events.send(Event::Served { addr, request, data: data.into() });
+                    }
+                },
+                DiskJob::Read { offset, len, reply } => {
+                    let _ = reply.send(self.torrent.read_range(offset, len));
+                },
+                DiskJob::Save { uploaded, downloaded, done } => {
+                    self.save(uploaded, downloaded);
+                    let _ = done.send(());
+                },
+                DiskJob::CheckSpace { needed } => self.check_space(needed)
+            }
+        }
+    }
+
+    fn write(&mut self, addr: SocketAddr, request: BlockRequest, data: Bytes) {
+        // A block the piece's tree proves bad goes again on its own, rather
+        // than failing its whole piece once that's in.
+        let checked = self.merkle
+            .as_ref()
+            .and_then(|merkle| merkle.lock().unwrap().verify_block(request.index, request.begin, &data));
+        let written = match checked {
+            Some(false) => {
+                warn!(piece = request.index, begin = request.begin, %addr, "block failed its hash check");
+                self.metrics.hash_failed();
+                false
+            },
+            _ => match self.cache.write(self.torrent.storage_mut(), request.index, request.begin, data.to_vec()) {
+                Ok(()) => true,
+                Err(err) => {
+                    self.storage_error(err);
+                    false
+                }
+            }
+        };
+        self.backlog.pop(data.len());
+        self.buffers.give(data);
+        let _ = self.events.send(Event::Written { addr, request, written });
+    }
+
+    // Pieces that can't be written out or read fail without being hashed.
+    fn hash(&mut self, piece: u32, runtime: &Handle) {
+        if let Err(err) = self.cache.flush_piece(self.torrent.storage_mut(), piece) {
+            self.storage_error(err);
+        }
+        let expected = self.torrent.metainfo().piece_hash(piece);
+        let (Some(expected), Ok(data)) = (expected, self.torrent.storage().read_piece(piece)) else {
+            let _ = self.events.send(Event::Hashed { piece, valid: false });
+            return;
+        };
+        let events = self.events.clone();
+        runtime.spawn_blocking(move || {
+            let _ = events.send(Event::Hashed { piece, valid: expected.matches(&data) });
+        });
+    }
+
+    // The piece was written out before it was hashed, so it can be synced
+    // as it is.
+    fn verified(&mut self, piece: u32) {
+        let completes = !self.torrent.have().is_complete();
+        self.torrent.mark_have(piece);
+        let completes = completes && self.torrent.have().is_complete();
+        if self.journal.is_some() {
+            self.unsynced.push(piece);
+        }
+        let storage = self.torrent.storage_mut();
+        let synced = storage
+            .piece_verified(piece)
+            .and_then(|_| self.durability.piece_verified(storage, piece, Instant::now()))
+            .and_then(|synced| match completes {
+                true => self.durability.torrent_completed(storage),
+                false => Ok(synced)
+            });
+        match synced {
+            Ok(true) => self.journal_synced(),
+            Ok(false) => {},
+            Err(err) => self.storage_error(err)
+        }
+        self.finish_files();
+        if completes {
+            self.move_to_destination();
+        }
+    }
+
+    // Gives files their final names once they're complete.
+    fn finish_files(&self) {
+        match self.torrent.storage().finish_complete_files(self.torrent.have()) {
+            Ok(files) => files
+                .into_iter()
+                .for_each(|file| debug!(path = %self.torrent.storage().layout().files()[file].path.display(), "file complete")),
+            Err(err) => self.storage_error(err)
+        }
+    }
+
+    // Everything verified so far is on the disk now: under `OnPiece` that's
+    // only the piece just verified, otherwise it was all synced at once.
+    fn journal_synced(&mut self) {
+        let Some(journal) = &mut self.journal else {
+            return;
+        };
+        let appended = self.unsynced
+            .drain(..)
+            .try_for_each(|piece| journal.append(JournalEntry::Verified(piece)));
+        if let Err(err) = appended {
+            self.storage_error(err);
+        }
+    }
+
+    fn move_to_destination(&mut self) {
+        let Some(dest) = self.destination.take() else {
+            return;
+        };
+        match self.torrent.storage_mut().move_to(&dest) {
+            Ok(()) => info!(dest = %dest.display(), "moved to destination"),
+            Err(err) => self.storage_error(err)
+        }
+    }
+
+    // Storage without a root, or a platform that can't say, goes unchecked.
+    fn check_space(&mut self, needed: u64) {
+        let Some(root) = self.torrent.storage().root() else {
+            return;
+        };
+        match self.space.poll(root, needed, Instant::now()) {
+            Ok(Some(event)) => {
+                let _ = self.events.send(Event::Space(event));
+            },
+            Ok(None) => {},
+            Err(err) => debug!(error = %err, "cannot check the free space")
+        }
+    }
+
+    fn save(&mut self, uploaded: u64, downloaded: u64) {
+        let flushed = self.cache
+            .flush(self.torrent.storage_mut())
+            .and_then(|_| self.torrent.storage_mut().flush());
+        if let Err(err) = flushed {
+            self.storage_error(err);
+        }
+        if let Err(err) = self.save_resume(uploaded, downloaded) {
+            return self.storage_error(err);
+        }
+        // It's all in the resume data now.
+        self.unsynced.clear();
+        if let Some(Err(err)) = self.journal.as_mut().map(Journal::reset) {
+            self.storage_error(err);
+        }
+    }
+
+    fn save_resume(&self, uploaded: u64, downloaded: u64) -> io::Result<()> {
+        let Some(path) = &self.resume_path else {
+            return Ok(());
+        };
+        let metainfo = self.torrent.metainfo();
+        let mut resume = ResumeData::capture(metainfo.info_hash, self.torrent.storage(), self.torrent.have());
+        resume.uploaded = uploaded;
+        resume.downloaded = downloaded;
+        resume.renamed = sanitize::renamed(&metainfo.files, self.torrent.storage().layout().files());
+        self.durability.save_resume(&resume, path)
+    }
+
+    fn storage_error(&self, err: io::Error) {
+        error!(error = %err, "storage error");
+        let info_hash = self.torrent.metainfo().info_hash;
+        let _ = self.alerts.send(Alert::StorageError { info_hash, message: err.to_string() });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::bitfield::Bitfield;
+    use crate::block::BlockRequest;
+    use crate::engine::backlog::Backlog;
+    use crate::engine::disk::{DiskJob, DiskThread};
+    use crate::engine::test::metainfo;
+    use crate::engine::torrent::{Event, Shared, TorrentOptions};
+    use crate::storage::durability::SyncPolicy;
+    use crate::storage::journal::{self, Journal};
+    use crate::storage::memory::MemoryStorage;
+    use crate::storage::resume::ResumeData;
+    use crate::storage::space::SpaceEvent;
+    use crate::torrent::Torrent;
+    use std::fs;
+    use tokio::sync::{mpsc, oneshot};
+    use tracing::Span;
+
+    #[tokio::test]
+    async fn test_disk_thread() {
+        let metainfo = metainfo(b"0123456789", 4);
+        let storage = MemoryStorage::new(metainfo.layout().unwrap());
+        let (events, mut answers) = mpsc::unbounded_channel();
+        let backlog = Backlog::default();
+        let torrent = Torrent::with_storage(metainfo, storage).unwrap();
+        let disk = DiskThread::new(torrent, &TorrentOptions::new(), &Shared::default(), events, None, backlog.clone())
+            .spawn(Span::none());
+        let addr = "127.0.0.1:6881".parse().unwrap();
+
+        backlog.push(4);
+        disk.send(DiskJob::Write { addr, request: BlockRequest::new(1, 0, 4), data: "4567".into() });
+        disk.send(DiskJob::Hash(1));
+        disk.send(DiskJob::Hash(2));
+        assert!(matches!(answers.recv().await, Some(Event::Written { written: true, .. })));
+        assert_eq!(backlog.blocks(), 0);
+        let mut hashed = [answers.recv().await, answers.recv().await];
+        hashed.sort_by_key(|event| matches!(event, Some(Event::Hashed { piece: 2, .. })));
+        assert!(matches!(hashed[0], Some(Event::Hashed { piece: 1, valid: true })));
+        assert!(matches!(hashed[1], Some(Event::Hashed { piece: 2, valid: false })));
+
+        // Only verified pieces can be read by offset.
+        let (reply, read) = oneshot::channel();
+        disk.send(DiskJob::Read { offset: 4, len: 4, reply });
+        assert!(read.await.unwrap().is_err());
+        disk.send(DiskJob::Verified(1));
+        let (reply, read) = oneshot::channel();
+        disk.send(DiskJob::Read { offset: 4, len: 4, reply });
+        assert_eq!(read.await.unwrap().unwrap(), b"4567");
+
+        disk.send(DiskJob::Serve { addr, request: BlockRequest::new(1, 1, 2) });
+        assert!(matches!(answers.recv().await, Some(Event::Served { data, .. }) if data == "56"));
+    }
+
+    #[tokio::test]
+    async fn test_journal() {
+        let metainfo = metainfo(b"0123456789", 4);
+        let storage = MemoryStorage::new(metainfo.layout().unwrap());
+        let (events, _answers) = mpsc::unbounded_channel();
+        let resume_path = std::env::temp_dir().join(format!("disk-{}.resume", std::process::id()));
+        let options = TorrentOptions::new()
+            .with_resume_path(&resume_path)
+            .with_sync_policy(SyncPolicy::OnPiece);
+        let torrent = Torrent::with_storage(metainfo, storage).unwrap();
+        let disk = DiskThread::new(torrent, &options, &Shared::default(), events, None, Backlog::default())
+            .spawn(Span::none());
+
+        // Read back once the jobs before are done.
+        let done = || async {
+            let (reply, read) = oneshot::channel();
+            disk.send(DiskJob::Read { offset: 0, len: 0, reply });
+            read.await.unwrap().unwrap();
+        };
+        disk.send(DiskJob::Verified(2));
+        done().await;
+        let mut pieces = Bitfield::new(3);
+        assert_eq!(Journal::replay(journal::path_for(&resume_path), &mut pieces).unwrap(), 1);
+        assert_eq!(pieces.ones().collect::<Vec<_>>(), vec![2]);
+
+        // Saving resume data empties it.
+        let (done, saved) = oneshot::channel();
+        disk.send(DiskJob::Save { uploaded: 0, downloaded: 0, done });
+        saved.await.unwrap();
+        assert_eq!(Journal::replay(journal::path_for(&resume_path), &mut pieces).unwrap(), 0);
+        assert_eq!(ResumeData::load(&resume_path).unwrap().pieces.ones().collect::<Vec<_>>(), vec![2]);
+
+        fs::remove_file(journal::path_for(&resume_path)).unwrap();
+        fs::remove_file(&resume_path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_complete_files() {
+        let data = b"0123456789";
+        let metainfo = metainfo(data, 4);
+        let root = std::env::temp_dir().join(format!("disk-files-{}", std::process::id()));
+        let (staging, dest) = (root.join("staging"), root.join("done"));
+        let (events, mut answers) = mpsc::unbounded_channel();
+        let torrent = Torrent::new(metainfo, &staging).unwrap();
+        let options = TorrentOptions::new().with_destination(&dest);
+        let disk = DiskThread::new(torrent, &options, &Shared::default(), events, None, Backlog::default())
+            .spawn(Span::none());
+        let addr = "127.0.0.1:6881".parse().unwrap();
+        let done = || async {
+            let (reply, read) = oneshot::channel();
+            disk.send(DiskJob::Read { offset: 0, len: 0, reply });
+            read.await.unwrap().unwrap();
+        };
+
+        for (piece, chunk) in data.chunks(4).enumerate() {
+            let request = BlockRequest::new(piece as u32, 0, chunk.len() as u32);
+            disk.send(DiskJob::Write { addr, request, data: chunk.to_vec().into() });
+            disk.send(DiskJob::Hash(piece as u32));
+        }
+        disk.send(DiskJob::Verified(0));
+        disk.send(DiskJob::Verified(1));
+        done().await;
+        assert!(staging.join("file.part").exists());
+        assert!(!staging.join("file").exists());
+
+        // Given its final name, then moved.
+        disk.send(DiskJob::Verified(2));
+        done().await;
+        assert_eq!(fs::read(dest.join("file")).unwrap(), data);
+        assert!(!staging.join("file").exists());
+        assert!(!staging.join("file.part").exists());
+        // And seeded from there.
+        disk.send(DiskJob::Serve { addr, request: BlockRequest::new(2, 0, 2) });
+        let served = loop {
+            if let Some(Event::Served { data, .. }) = answers.recv().await {
+                break data;
+            }
+        };
+        assert_eq!(served, "89");
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_check_space() {
+        let metainfo = metainfo(b"0123456789", 4);
+        let (events, mut answers) = mpsc::unbounded_channel();
+        // Nothing is written, so nothing is created.
+        let torrent = Torrent::new(metainfo, std::env::temp_dir().join("disk-space")).unwrap();
+        let disk = DiskThread::new(torrent, &TorrentOptions::new(), &Shared::default(), events, None, Backlog::default())
+            .spawn(Span::none());
+
+        disk.send(DiskJob::CheckSpace { needed: u64::MAX / 2 });
+        assert!(matches!(answers.recv().await, Some(Event::Space(SpaceEvent::Low { needed, .. })) if needed == u64::MAX / 2));
+    }
+}
@@ -0,0 +1,218 @@
+use crate::ban::BanList;
+use crate::engine::peer::{read_handshake, write_handshake};
+use crate::engine::torrent::{Event, TorrentHandle};
+use crate::handshake::{Handshake, HANDSHAKE_TIMEOUT};
+use crate::ipfilter::IpFilter;
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::ops::RangeInclusive;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::task::JoinHandle;
+use tokio::time;
+use tracing::debug;
+
+// Which port to accept peers on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ListenPort {
+    Fixed(u16),
+    // The first free port, tried in order from the start to the end.
+    Range(u16, u16),
+    // Whatever free port the OS hands out.
+    Random
+}
+
+impl ListenPort {
+    // `6881`, `6881-6889`, or `0` for a random port.
+    pub fn parse(text: &str) -> Option<Self> {
+        match text.split_once('-') {
+            Some((start, end)) => {
+                let (start, end) = (start.parse().ok()?, end.parse().ok()?);
+                (0 < start && start <= end).then_some(Self::Range(start, end))
+            },
+            None => match text.parse().ok()? {
+                0 => Some(Self::Random),
+                port => Some(Self::Fixed(port))
+            }
+        }
+    }
+
+    fn candidates(&self) -> RangeInclusive<u16> {
+        match *self {
+            Self::Fixed(port) => port..=port,
+            Self::Range(start, end) => start..=end,
+            Self::Random => 0..=0
+        }
+    }
+}
+
+// As `parse` takes it.
+impl fmt::Display for ListenPort {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Fixed(port) => write!(f, "{}", port),
+            Self::Range(start, end) => write!(f, "{}-{}", start, end),
+            Self::Random => write!(f, "0")
+        }
+    }
+}
+
+struct Registration {
+    peer_id: [u8; 20],
+    events: UnboundedSender<Event>
+}
+
+// Accepts inbound peers for the engine's torrents and hands each one that
+// handshakes for a registered info hash to that torrent.
+#[derive(Clone)]
+pub struct PeerListener {
+    listener: Arc<TcpListener>,
+    torrents: Arc<Mutex<HashMap<[u8; 20], Registration>>>,
+    // Zero while we run no DHT node.
+    dht_port: Arc<AtomicU16>,
+    filter: Arc<Mutex<IpFilter>>,
+    bans: Arc<Mutex<BanList>>
+}
+
+impl PeerListener {
+    pub async fn bind(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        Ok(Self {
+            listener: Arc::new(TcpListener::bind(addr).await?),
+            torrents: Arc::new(Mutex::new(HashMap::new())),
+            dht_port: Arc::default(),
+            filter: Arc::default(),
+            bans: Arc::default()
+        })
+    }
+
+    pub async fn bind_port(ip: IpAddr, port: ListenPort) -> io::Result<Self> {
+        let mut last = None;
+        for port in port.candidates() {
+            match Self::bind((ip, port)).await {
+                Ok(listener) => return Ok(listener),
+                Err(err) => {
+                    debug!(port, error = %err, "cannot listen");
+                    last = Some(err);
+                }
+            }
+        }
+        Err(last.unwrap_or_else(|| io::Error::new(io::ErrorKind::AddrInUse, "no port to listen on")))
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    pub fn dht_port(&self) -> Option<u16> {
+        match self.dht_port.load(Ordering::Relaxed) {
+            0 => None,
+            port => Some(port)
+        }
+    }
+
+    // Inbound peers are told about the DHT node in our handshake.
+    pub fn set_dht_port(&self, port: Option<u16>) {
+        self.dht_port.store(port.unwrap_or(0), Ordering::Relaxed);
+    }
+
+    // Peers from blocked addresses are dropped before the handshake. The
+    // filter can be shared with whatever else needs to check peers.
+    pub fn ip_filter(&self) -> Arc<Mutex<IpFilter>> {
+        self.filter.clone()
+    }
+
+    // Peers banned for sending corrupt data are dropped the same way.
+    pub fn ban_list(&self) -> Arc<Mutex<BanList>> {
+        self.bans.clone()
+    }
+
+    pub fn register(&self, torrent: &TorrentHandle) {
+        let registration = Registration { peer_id: torrent.peer_id(), events: torrent.events() };
+        self.torrents.lock().unwrap().insert(torrent.info_hash(), registration);
+    }
+
+    pub fn unregister(&self, info_hash: &[u8; 20]) {
+        self.torrents.lock().unwrap().remove(info_hash);
+    }
+
+    pub fn spawn(&self) -> JoinHandle<()> {
+        let listener = self.clone();
+        tokio::spawn(async move { listener.run().await })
+    }
+
+    pub async fn run(&self) {
+        loop {
+            let Ok((stream, addr)) = self.listener.accept().await else {
+                continue;
+            };
+            if let Some(reason) = self.filter.lock().unwrap().blocked_by(addr.ip()) {
+                debug!(%addr, list = %reason.list, "refused blocked peer");
+                continue;
+            }
+            if self.bans.lock().unwrap().is_banned(addr.ip()) {
+                debug!(%addr, "refused banned peer");
+                continue;
+            }
+            let listener = self.clone();
+            tokio::spawn(async move {
+                match time::timeout(HANDSHAKE_TIMEOUT, listener.handle(stream, addr)).await {
+                    Ok(Err(err)) => debug!(%addr, error = %err, "inbound handshake failed"),
+                    Err(_) => debug!(%addr, "inbound handshake timed out"),
+                    Ok(Ok(())) => {}
+                }
+            });
+        }
+    }
+
+    async fn handle(&self, mut stream: TcpStream, addr: SocketAddr) -> io::Result<()> {
+        let handshake = read_handshake(&mut stream).await?;
+        let (peer_id, events) = match self.torrents.lock().unwrap().get(&handshake.info_hash) {
+            Some(registration) => (registration.peer_id, registration.events.clone()),
+            None => return Err(io::Error::new(io::ErrorKind::NotFound, "unknown info hash"))
+        };
+
+        let ours = match self.dht_port() {
+            Some(_) => Handshake::new(handshake.info_hash, peer_id).with_dht(),
+            None => Handshake::new(handshake.info_hash, peer_id)
+        };
+        write_handshake(&mut stream, &ours).await?;
+        if events.send(Event::Connected { addr, stream, handshake }).is_err() {
+            self.unregister(&handshake.info_hash);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::engine::listener::{ListenPort, PeerListener};
+    use std::net::{IpAddr, Ipv4Addr};
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn test_parse_port() {
+        assert_eq!(ListenPort::parse("6881"), Some(ListenPort::Fixed(6881)));
+        assert_eq!(ListenPort::parse("6881-6889"), Some(ListenPort::Range(6881, 6889)));
+        assert_eq!(ListenPort::parse("0"), Some(ListenPort::Random));
+        assert_eq!(ListenPort::parse("6889-6881"), None);
+        assert_eq!(ListenPort::parse("70000"), None);
+        assert_eq!(ListenPort::Range(6881, 6889).to_string(), "6881-6889");
+    }
+
+    #[tokio::test]
+    async fn test_bind_range() {
+        let localhost = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let taken = TcpListener::bind((localhost, 0)).await.unwrap();
+        let port = taken.local_addr().unwrap().port();
+
+        assert!(PeerListener::bind_port(localhost, ListenPort::Fixed(port)).await.is_err());
+        let listener = PeerListener::bind_port(localhost, ListenPort::Range(port, port.saturating_add(20))).await.unwrap();
+        assert_ne!(listener.local_addr().unwrap().port(), port);
+        let listener = PeerListener::bind_port(localhost, ListenPort::Random).await.unwrap();
+        assert_ne!(listener.local_addr().unwrap().port(), 0);
+    }
+}
@@ -0,0 +1,163 @@
+use crate::handshake::HANDSHAKE_TIMEOUT;
+use std::fmt::Write as _;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time;
+use tracing::debug;
+
+// Requests bigger than this are not scrapes.
+const MAX_REQUEST_LEN: usize = 8 * 1024;
+
+// Session-wide counters and gauges, kept up to date by the torrents and
+// exported in the Prometheus text format.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    uploaded: AtomicU64,
+    downloaded: AtomicU64,
+    peers: AtomicU64,
+    pieces_verified: AtomicU64,
+    hash_failures: AtomicU64,
+    tracker_errors: AtomicU64,
+    dht_nodes: AtomicU64
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_uploaded(&self, bytes: u64) {
+        self.uploaded.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn add_downloaded(&self, bytes: u64) {
+        self.downloaded.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn peer_connected(&self) {
+        self.peers.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn peer_disconnected(&self) {
+        self.peers.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn piece_verified(&self) {
+        self.pieces_verified.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn hash_failed(&self) {
+        self.hash_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn tracker_error(&self) {
+        self.tracker_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // The DHT runs outside the session, so whoever runs it reports its
+    // routing table size here.
+    pub fn set_dht_nodes(&self, nodes: usize) {
+        self.dht_nodes.store(nodes as u64, Ordering::Relaxed);
+    }
+
+    pub fn render(&self) -> String {
+        let metrics = [
+            ("bittorrent_uploaded_bytes_total", "counter", "Payload bytes sent to peers.", &self.uploaded),
+            ("bittorrent_downloaded_bytes_total", "counter", "Payload bytes received from peers.", &self.downloaded),
+            ("bittorrent_peers_connected", "gauge", "Connected peers.", &self.peers),
+            ("bittorrent_pieces_verified_total", "counter", "Pieces that passed their hash check.", &self.pieces_verified),
+            ("bittorrent_hash_failures_total", "counter", "Pieces that failed their hash check.", &self.hash_failures),
+            ("bittorrent_tracker_errors_total", "counter", "Failed tracker announces.", &self.tracker_errors),
+            ("bittorrent_dht_nodes", "gauge", "Nodes in the DHT routing table.", &self.dht_nodes)
+        ];
+        let mut text = String::new();
+        for (name, kind, help, value) in metrics {
+            let _ = writeln!(text, "# HELP {} {}", name, help);
+            let _ = writeln!(text, "# TYPE {} {}", name, kind);
+            let _ = writeln!(text, "{} {}", name, value.load(Ordering::Relaxed));
+        }
+        text
+    }
+}
+
+// Answers `GET /metrics` on `listener` for as long as the task runs.
+pub async fn serve(listener: TcpListener, metrics: Arc<Metrics>) {
+    loop {
+        let Ok((stream, addr)) = listener.accept().await else {
+            continue;
+        };
+        let metrics = metrics.clone();
+        tokio::spawn(async move {
+            if let Ok(Err(err)) = time::timeout(HANDSHAKE_TIMEOUT, respond(stream, &metrics)).await {
+                debug!(%addr, error = %err, "metrics request failed");
+            }
+        });
+    }
+}
+
+async fn respond(mut stream: TcpStream, metrics: &Metrics) -> io::Result<()> {
+    let mut request = Vec::new();
+    let mut buf = [0; 1024];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 || request.len() + n > MAX_REQUEST_LEN {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "bad request"));
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+
+    let response = match request.starts_with(b"GET /metrics ") {
+        true => {
+            let body = metrics.render();
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            )
+        },
+        false => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string()
+    };
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+#[cfg(test)]
+mod test {
+    use crate::engine::metrics::{serve, Metrics};
+    use std::sync::Arc;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    async fn get(addr: std::net::SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(format!("GET {} HTTP/1.1\r\nHost: x\r\n\r\n", path).as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn test_serve() {
+        let metrics = Arc::new(Metrics::new());
+        metrics.add_downloaded(1234);
+        metrics.peer_connected();
+        metrics.peer_connected();
+        metrics.peer_disconnected();
+        metrics.set_dht_nodes(80);
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, metrics));
+
+        let response = get(addr, "/metrics").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.contains("\nbittorrent_downloaded_bytes_total 1234\n"));
+        assert!(response.contains("\nbittorrent_peers_connected 1\n"));
+        assert!(response.contains("# TYPE bittorrent_dht_nodes gauge\nbittorrent_dht_nodes 80\n"));
+
+        assert!(get(addr, "/").await.starts_with("HTTP/1.1 404"));
+    }
+}
@@ -0,0 +1,313 @@
+pub mod alert;
+pub mod backlog;
+pub mod buffers;
+pub mod connections;
+#[cfg(feature = "rpc")]
+pub mod control;
+pub(crate) mod disk;
+pub mod listener;
+pub mod metrics;
+pub mod peer;
+pub mod queue;
+pub mod rate;
+#[cfg(feature = "rpc")]
+pub mod rpc;
+pub mod schedule;
+pub mod seeding;
+pub mod session;
+pub mod stats;
+pub mod stream;
+pub mod torrent;
+#[cfg(any(feature = "tracker-http", feature = "tracker-udp"))]
+pub(crate) mod trackers;
+#[cfg(feature = "rpc")]
+pub mod transmission;
+pub mod unchoke;
+#[cfg(feature = "rpc")]
+pub mod watch;
+
+pub use alert::Alert;
+pub use backlog::Backlog;
+pub use buffers::BufferPool;
+pub use connections::ConnectionLimits;
+#[cfg(feature = "rpc")]
+pub use control::ControlServer;
+pub use listener::{ListenPort, PeerListener};
+pub use metrics::Metrics;
+pub use queue::QueueLimits;
+pub use rate::{RateLimiter, RateLimits};
+pub use schedule::BandwidthSchedule;
+pub use seeding::SeedGoal;
+pub use session::Session;
+pub use stats::{PeerInfo, TorrentStats};
+pub use stream::ContentReader;
+pub use torrent::TorrentHandle;
+#[cfg(feature = "rpc")]
+pub use watch::WatchFolder;
+
+#[cfg(test)]
+mod test {
+    use crate::bencode::Value;
+    use crate::bitfield::Bitfield;
+    use crate::block::BlockRequest;
+    use crate::engine::peer::{extended_handshake, read_handshake, read_message, write_handshake, write_message};
+    use crate::engine::torrent::{Shared, TorrentOptions};
+    use crate::engine::{Alert, PeerListener, TorrentHandle};
+    use crate::extension::{ExtendedHandshake, MetadataMessage, UT_METADATA};
+    use crate::handshake::Handshake;
+    use crate::hash::sha1;
+    use crate::message::Message;
+    use crate::metainfo::Metainfo;
+    use crate::storage::memory::MemoryStorage;
+    use crate::storage::selection::{FileSelection, Priority};
+    use crate::storage::layout::Layout;
+    use crate::storage::Storage;
+    use crate::torrent::Torrent;
+    use std::io;
+    use std::net::SocketAddr;
+    use std::time::Duration;
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::time;
+
+    pub(super) fn metainfo(data: &[u8], piece_length: usize) -> Metainfo {
+        let pieces: Vec<u8> = data.chunks(piece_length).flat_map(sha1).collect();
+        let info = Value::dict([
+            ("name", "file".into()),
+            ("length", (data.len() as i64).into()),
+            ("piece length", (piece_length as i64).into()),
+            ("pieces", pieces.into())
+        ]);
+        Metainfo::from_bytes(&Value::dict([("info", info)]).encode()).unwrap()
+    }
+
+    pub(super) fn seed(metainfo: &Metainfo, data: &[u8]) -> Torrent<MemoryStorage> {
+        let piece_length = metainfo.piece_length as usize;
+        let mut storage = MemoryStorage::new(metainfo.layout().unwrap());
+        for (piece, chunk) in data.chunks(piece_length).enumerate() {
+            storage.write(piece as u32, 0, chunk).unwrap();
+        }
+        let mut seed = Torrent::with_storage(metainfo.clone(), storage).unwrap();
+        assert!(seed.recheck().is_complete());
+        seed
+    }
+
+    // A peer that hands the torrent's metadata to the first to connect, and
+    // has nothing else.
+    pub(super) async fn metadata_peer(metainfo: &Metainfo) -> SocketAddr {
+        let info = metainfo.raw.get("info").unwrap().encode();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let theirs = read_handshake(&mut stream).await.unwrap();
+            write_handshake(&mut stream, &Handshake::new(theirs.info_hash, [9; 20]).with_extensions()).await.unwrap();
+            let ours = ExtendedHandshake::new().with_extension(UT_METADATA, 3).with_metadata_size(info.len() as u64);
+            let id = extended_handshake(&mut stream, &ours).await.unwrap().extension_id(UT_METADATA).unwrap();
+            while let Ok(Message::Extended { id: 3, payload }) = read_message(&mut stream).await {
+                let Some(MetadataMessage::Request(piece)) = MetadataMessage::decode(&payload) else {
+                    return;
+                };
+                let reply = MetadataMessage::Data { piece, total_size: info.len() as u64, data: info.clone() };
+                write_message(&mut stream, &Message::Extended { id, payload: reply.encode() }).await.unwrap();
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_download_from_seeder() {
+        let data: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+        let metainfo = metainfo(&data, 32 * 1024);
+
+        let seed = seed(&metainfo, &data);
+
+        let listener = PeerListener::bind("127.0.0.1:0").await.unwrap();
+        let seeder = TorrentHandle::spawn(seed, [1; 20]);
+        listener.register(&seeder);
+        listener.spawn();
+
+        let leech = Torrent::with_storage(metainfo.clone(), MemoryStorage::new(metainfo.layout().unwrap())).unwrap();
+        let leecher = TorrentHandle::spawn(leech, [2; 20]);
+        leecher.add_peer(listener.local_addr().unwrap());
+
+        time::timeout(Duration::from_secs(10), leecher.wait_complete()).await.unwrap();
+        assert!(leecher.have().is_complete());
+        leecher.shutdown().await;
+        seeder.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_piece_deadline() {
+        let data: Vec<u8> = (0..100_000u32).map(|i| (i % 241) as u8).collect();
+        let metainfo = metainfo(&data, 16 * 1024);
+
+        let listener = PeerListener::bind("127.0.0.1:0").await.unwrap();
+        let seeder = TorrentHandle::spawn(seed(&metainfo, &data), [1; 20]);
+        listener.register(&seeder);
+        listener.spawn();
+
+        // The last piece jumps the queue.
+        let leech = Torrent::with_storage(metainfo.clone(), MemoryStorage::new(metainfo.layout().unwrap())).unwrap();
+        let leecher = TorrentHandle::spawn(leech, [2; 20]);
+        let mut alerts = leecher.subscribe();
+        leecher.set_piece_deadline(6, Duration::from_secs(5));
+        leecher.add_peer(listener.local_addr().unwrap());
+        let first = time::timeout(Duration::from_secs(10), async {
+            loop {
+                if let Alert::PieceVerified { piece, .. } = alerts.recv().await.unwrap() {
+                    return piece;
+                }
+            }
+        });
+        assert_eq!(first.await.unwrap(), 6);
+
+        time::timeout(Duration::from_secs(10), leecher.wait_complete()).await.unwrap();
+        leecher.shutdown().await;
+        seeder.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_file_priorities() {
+        let data: Vec<u8> = (0..50_000u32).map(|i| (i % 239) as u8).collect();
+        let metainfo = metainfo(&data, 16 * 1024);
+
+        let listener = PeerListener::bind("127.0.0.1:0").await.unwrap();
+        let seeder = TorrentHandle::spawn(seed(&metainfo, &data), [1; 20]);
+        listener.register(&seeder);
+        listener.spawn();
+
+        let leech = Torrent::with_storage(metainfo.clone(), MemoryStorage::new(metainfo.layout().unwrap())).unwrap();
+        let leecher = TorrentHandle::spawn(leech, [2; 20]);
+        let mut selection = FileSelection::all(leecher.layout().files().len());
+        selection.set_priority(0, Priority::Skip);
+        leecher.set_file_priorities(&selection);
+        leecher.add_peer(listener.local_addr().unwrap());
+        time::sleep(Duration::from_millis(500)).await;
+        assert_eq!(leecher.have().count_ones(), 0);
+        // Nothing wanted is nothing to wait for.
+        time::timeout(Duration::from_secs(1), leecher.wait_selected(&selection)).await.unwrap();
+
+        selection.set_priority(0, Priority::High);
+        leecher.set_file_priorities(&selection);
+        time::timeout(Duration::from_secs(10), leecher.wait_complete()).await.unwrap();
+        leecher.shutdown().await;
+        seeder.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_super_seeding() {
+        let data: Vec<u8> = (0..50_000u32).map(|i| (i % 233) as u8).collect();
+        let metainfo = metainfo(&data, 16 * 1024);
+
+        let listener = PeerListener::bind("127.0.0.1:0").await.unwrap();
+        let options = TorrentOptions::new().with_super_seeding(true);
+        let seeder = TorrentHandle::spawn_with(seed(&metainfo, &data), [1; 20], options, Shared::default());
+        listener.register(&seeder);
+        listener.spawn();
+
+        // A lone peer is shown one piece, and nothing more until some other
+        // peer turns up with it.
+        let leech = Torrent::with_storage(metainfo.clone(), MemoryStorage::new(metainfo.layout().unwrap())).unwrap();
+        let leecher = TorrentHandle::spawn(leech, [2; 20]);
+        leecher.add_peer(listener.local_addr().unwrap());
+        time::sleep(Duration::from_millis(500)).await;
+        assert_eq!(leecher.have().count_ones(), 1);
+
+        seeder.set_super_seeding(false);
+        time::timeout(Duration::from_secs(10), leecher.wait_complete()).await.unwrap();
+        leecher.shutdown().await;
+        seeder.shutdown().await;
+    }
+
+    // Takes its time over every read, as a busy disk would.
+    struct SlowStorage(MemoryStorage);
+
+    impl Storage for SlowStorage {
+        fn layout(&self) -> &Layout {
+            self.0.layout()
+        }
+
+        fn read(&self, piece: u32, begin: u32, len: usize) -> io::Result<Vec<u8>> {
+            std::thread::sleep(Duration::from_millis(100));
+            self.0.read(piece, begin, len)
+        }
+
+        fn write(&mut self, piece: u32, begin: u32, data: &[u8]) -> io::Result<()> {
+            self.0.write(piece, begin, data)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_request() {
+        let data: Vec<u8> = (0..32_768u32).map(|i| (i % 229) as u8).collect();
+        let metainfo = metainfo(&data, 16 * 1024);
+        let mut storage = MemoryStorage::new(metainfo.layout().unwrap());
+        storage.write(0, 0, &data[..16 * 1024]).unwrap();
+        storage.write(1, 0, &data[16 * 1024..]).unwrap();
+        let mut slow = Torrent::with_storage(metainfo.clone(), SlowStorage(storage)).unwrap();
+        assert!(slow.recheck().is_complete());
+
+        let listener = PeerListener::bind("127.0.0.1:0").await.unwrap();
+        let seeder = TorrentHandle::spawn(slow, [1; 20]);
+        listener.register(&seeder);
+        listener.spawn();
+
+        let mut stream = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        write_handshake(&mut stream, &Handshake::new(metainfo.info_hash, [2; 20])).await.unwrap();
+        read_handshake(&mut stream).await.unwrap();
+        write_message(&mut stream, &Message::Interested).await.unwrap();
+        while !matches!(read_message(&mut stream).await.unwrap(), Message::Unchoke) {}
+
+        // Cancelled while the seeder reads it, the first block is never
+        // sent; the second is.
+        let (cancelled, wanted) = (BlockRequest::new(0, 0, 16 * 1024), BlockRequest::new(1, 0, 16 * 1024));
+        write_message(&mut stream, &Message::Request(cancelled)).await.unwrap();
+        write_message(&mut stream, &Message::Cancel(cancelled)).await.unwrap();
+        write_message(&mut stream, &Message::Request(wanted)).await.unwrap();
+        let piece = time::timeout(Duration::from_secs(5), async {
+            loop {
+                if let Message::Piece { index, begin, .. } = read_message(&mut stream).await.unwrap() {
+                    return (index, begin);
+                }
+            }
+        });
+        assert_eq!(piece.await.unwrap(), (1, 0));
+        seeder.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_unwanted_requests_cancelled() {
+        let data: Vec<u8> = (0..32_768u32).map(|i| (i % 227) as u8).collect();
+        let metainfo = metainfo(&data, 16 * 1024);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+        let leech = Torrent::with_storage(metainfo.clone(), MemoryStorage::new(metainfo.layout().unwrap())).unwrap();
+        let leecher = TorrentHandle::spawn(leech, [2; 20]);
+        leecher.add_peer(listener.local_addr().unwrap());
+        let (mut stream, _) = listener.accept().await.unwrap();
+        read_handshake(&mut stream).await.unwrap();
+        write_handshake(&mut stream, &Handshake::new(metainfo.info_hash, [1; 20])).await.unwrap();
+        write_message(&mut stream, &Message::Bitfield(Bitfield::full(2).as_bytes().to_vec())).await.unwrap();
+        write_message(&mut stream, &Message::Unchoke).await.unwrap();
+
+        // Left unanswered, the requests are taken back once the file is no
+        // longer wanted.
+        let request = loop {
+            if let Message::Request(request) = read_message(&mut stream).await.unwrap() {
+                break request;
+            }
+        };
+        let mut selection = FileSelection::all(1);
+        selection.set_priority(0, Priority::Skip);
+        leecher.set_file_priorities(&selection);
+        let cancelled = time::timeout(Duration::from_secs(5), async {
+            loop {
+                if let Message::Cancel(cancelled) = read_message(&mut stream).await.unwrap() {
+                    return cancelled;
+                }
+            }
+        });
+        assert_eq!(cancelled.await.unwrap(), request);
+        leecher.shutdown().await;
+    }
+}
@@ -0,0 +1,413 @@
+use crate::engine::backlog::Backlog;
+use crate::engine::buffers::BufferPool;
+use crate::engine::rate::RateLimits;
+use crate::engine::torrent::Event;
+use crate::extension::{self, ExtendedHandshake, MetadataAssembler, MetadataMessage, UT_METADATA};
+use crate::handshake::{Handshake, HANDSHAKE_LEN};
+use crate::message::{Message, MAX_MESSAGE_LEN};
+use bytes::{Buf, BytesMut};
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc::{self, UnboundedSender};
+use tokio::task::JoinHandle;
+use tokio::time;
+use tracing::{debug, trace, Instrument};
+
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+pub async fn read_handshake(reader: &mut (impl AsyncRead + Unpin)) -> io::Result<Handshake> {
+    let mut bytes = [0; HANDSHAKE_LEN];
+    reader.read_exact(&mut bytes).await?;
+    Handshake::from_bytes(&bytes).ok_or_else(|| invalid("invalid handshake"))
+}
+
+pub async fn write_handshake(writer: &mut (impl AsyncWrite + Unpin), handshake: &Handshake) -> io::Result<()> {
+    writer.write_all(&handshake.to_bytes()).await
+}
+
+pub async fn read_message(reader: &mut (impl AsyncRead + Unpin)) -> io::Result<Message> {
+    read_message_with(reader, BytesMut::zeroed).await
+}
+
+// `read_message`, with a block's data read straight into a buffer from
+// `buffers` rather than copied out of the message.
+pub async fn read_message_pooled(reader: &mut (impl AsyncRead + Unpin), buffers: &BufferPool) -> io::Result<Message> {
+    read_message_with(reader, |len| buffers.take(len)).await
+}
+
+async fn read_message_with(reader: &mut (impl AsyncRead + Unpin), buffer: impl FnOnce(usize) -> BytesMut) -> io::Result<Message> {
+    let len = reader.read_u32().await? as usize;
+    if len > MAX_MESSAGE_LEN {
+        return Err(invalid("message too long"));
+    }
+    // A piece's id, index and offset.
+    let mut header = [0; 9];
+    let head = len.min(header.len());
+    reader.read_exact(&mut header[..head]).await?;
+    if let (9, [7, i0, i1, i2, i3, b0, b1, b2, b3]) = (head, header) {
+        let mut data = buffer(len - head);
+        reader.read_exact(&mut data).await?;
+        let (index, begin) = (u32::from_be_bytes([i0, i1, i2, i3]), u32::from_be_bytes([b0, b1, b2, b3]));
+        return Ok(Message::Piece { index, begin, data: data.freeze() });
+    }
+    let mut payload = header[..head].to_vec();
+    payload.resize(len, 0);
+    reader.read_exact(&mut payload[head..]).await?;
+    Message::decode(&payload).ok_or_else(|| invalid("malformed message"))
+}
+
+// A block goes out behind its header in one vectored write, rather than
+// copied in after it.
+pub async fn write_message(writer: &mut (impl AsyncWrite + Unpin), message: &Message) -> io::Result<()> {
+    let Message::Piece { index, begin, data } = message else {
+        return writer.write_all(&message.encode()).await;
+    };
+    let mut header = [0; 13];
+    header[..4].copy_from_slice(&(9 + data.len() as u32).to_be_bytes());
+    header[4] = 7;
+    header[5..9].copy_from_slice(&index.to_be_bytes());
+    header[9..].copy_from_slice(&begin.to_be_bytes());
+    writer.write_all_buf(&mut Buf::chain(&header[..], &data[..])).await
+}
+
+// Connects to a peer and exchanges handshakes, making sure it serves the
+// torrent we asked for.
+pub async fn connect(addr: SocketAddr, ours: Handshake) -> io::Result<(TcpStream, Handshake)> {
+    let exchange = async {
+        let mut stream = TcpStream::connect(addr).await?;
+        write_handshake(&mut stream, &ours).await?;
+        let theirs = read_handshake(&mut stream).await?;
+        if theirs.info_hash != ours.info_hash {
+            return Err(invalid("wrong info hash"));
+        }
+        Ok((stream, theirs))
+    };
+    time::timeout(CONNECT_TIMEOUT, exchange)
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "handshake timed out"))?
+}
+
+// Sends our extended handshake and waits for the peer's, passing over
+// whatever else it sends first, such as its bitfield.
+pub async fn extended_handshake(stream: &mut TcpStream, ours: &ExtendedHandshake) -> io::Result<ExtendedHandshake> {
+    let exchange = async {
+        let message = Message::Extended { id: extension::HANDSHAKE_ID, payload: ours.encode() };
+        write_message(stream, &message).await?;
+        loop {
+            if let Message::Extended { id: extension::HANDSHAKE_ID, payload } = read_message(stream).await? {
+                return ExtendedHandshake::decode(&payload).ok_or_else(|| invalid("invalid extended handshake"));
+            }
+        }
+    };
+    time::timeout(CONNECT_TIMEOUT, exchange)
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "extended handshake timed out"))?
+}
+
+// `connect` and `extended_handshake` in one, for peers that must speak
+// the extension protocol.
+pub async fn connect_extended(addr: SocketAddr, ours: Handshake, extensions: &ExtendedHandshake) -> io::Result<(TcpStream, Handshake, ExtendedHandshake)> {
+    let (mut stream, theirs) = connect(addr, ours).await?;
+    if !theirs.supports_extensions() {
+        return Err(io::Error::new(io::ErrorKind::Unsupported, "no extension protocol"));
+    }
+    let extended = extended_handshake(&mut stream, extensions).await?;
+    Ok((stream, theirs, extended))
+}
+
+// Fetches the info dictionary from a peer that offers it (BEP 9), one
+// piece at a time, and checks it fits the size the peer gave and hashes to
+// `info_hash`. `ours` is the id our extended handshake gave `ut_metadata`,
+// which the peer answers with.
+pub async fn fetch_metadata(
+    stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    theirs: &ExtendedHandshake,
+    ours: u8,
+    info_hash: [u8; 20]
+) -> io::Result<Vec<u8>> {
+    let id = theirs
+        .extension_id(UT_METADATA)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::Unsupported, "no ut_metadata"))?;
+    let mut metadata = MetadataAssembler::new(theirs.metadata_size).map_err(|err| invalid(&err.to_string()))?;
+    for piece in 0..metadata.num_pieces() {
+        let request = Message::Extended { id, payload: MetadataMessage::Request(piece).encode() };
+        write_message(stream, &request).await?;
+        let answer = async {
+            loop {
+                let Message::Extended { id, payload } = read_message(stream).await? else {
+                    continue;
+                };
+                match MetadataMessage::decode(&payload) {
+                    Some(MetadataMessage::Data { piece: got, total_size, data }) if id == ours && got == piece => return Ok((total_size, data)),
+                    Some(MetadataMessage::Reject(got)) if id == ours && got == piece => return Err(io::Error::other("metadata request rejected")),
+                    _ => {}
+                }
+            }
+        };
+        let (total_size, data) = time::timeout(CONNECT_TIMEOUT, answer)
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "metadata request timed out"))??;
+        metadata.add(piece, total_size, data).map_err(|err| invalid(&err.to_string()))?;
+    }
+    metadata.finish(&info_hash).map_err(|err| invalid(&err.to_string()))
+}
+
+// What a peer tells about itself in the first moments of a connection.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerProbe {
+    pub peer_id: [u8; 20],
+    pub dht: bool,
+    // Only from peers that speak the extension protocol.
+    pub extended: Option<ExtendedHandshake>,
+    // Pieces it has, if it said in time: peers with none may send nothing.
+    pub pieces: Option<usize>
+}
+
+// Connects and handshakes, then listens for up to `wait` for the peer's
+// bitfield and extended handshake. Nothing is requested, so it is cheap
+// enough to do to every peer in a swarm.
+pub async fn probe(addr: SocketAddr, ours: Handshake, extensions: &ExtendedHandshake, wait: Duration) -> io::Result<PeerProbe> {
+    let (mut stream, theirs) = time::timeout(wait, connect(addr, ours))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "handshake timed out"))??;
+    let mut probe = PeerProbe { peer_id: theirs.peer_id, dht: theirs.supports_dht(), extended: None, pieces: None };
+    let extended = ours.supports_extensions() && theirs.supports_extensions();
+    if extended {
+        let message = Message::Extended { id: extension::HANDSHAKE_ID, payload: extensions.encode() };
+        write_message(&mut stream, &message).await?;
+    }
+    let listen = async {
+        loop {
+            match read_message(&mut stream).await? {
+                Message::Bitfield(bits) => probe.pieces = Some(bits.iter().map(|byte| byte.count_ones() as usize).sum()),
+                Message::Have(_) => probe.pieces = Some(probe.pieces.unwrap_or(0) + 1),
+                Message::Extended { id: extension::HANDSHAKE_ID, payload } => probe.extended = ExtendedHandshake::decode(&payload),
+                _ => {}
+            }
+            if probe.pieces.is_some() && (probe.extended.is_some() || !extended) {
+                return Ok::<_, io::Error>(());
+            }
+        }
+    };
+    // Whatever arrived before the peer went quiet or hung up is what we know.
+    let _ = time::timeout(wait, listen).await;
+    Ok(probe)
+}
+
+// A handshaken connection, driven by two tasks: one reads messages and
+// forwards them to the torrent as events, the other writes whatever the
+// torrent sends it. Dropping the connection closes both. Both sides pass
+// every message through each of `limits` before moving on, and blocks are
+// read into buffers from `buffers`. No more is read while `backlog` is full.
+pub struct Connection {
+    sender: UnboundedSender<Message>,
+    reader: JoinHandle<()>
+}
+
+impl Connection {
+    pub fn spawn(
+        stream: TcpStream,
+        addr: SocketAddr,
+        events: UnboundedSender<Event>,
+        limits: Vec<RateLimits>,
+        buffers: BufferPool,
+        backlog: Backlog
+    ) -> Self {
+        let (mut read_half, mut write_half) = stream.into_split();
+        let (sender, mut outgoing) = mpsc::unbounded_channel::<Message>();
+        let write_limits = limits.clone();
+        let span = tracing::debug_span!("peer", %addr);
+
+        let reader = tokio::spawn(async move {
+            loop {
+                backlog.wait().await;
+                let event = match read_message_pooled(&mut read_half, &buffers).await {
+                    Ok(message) => {
+                        trace!(%message, "received");
+                        for limit in &limits {
+                            limit.download.acquire(message.wire_len()).await;
+                        }
+                        // Counted only once nothing can abort the task
+                        // before the torrent gets it, or it never would be
+                        // taken off again.
+                        if let Message::Piece { data, .. } = &message {
+                            backlog.push(data.len());
+                        }
+                        Event::Message(addr, message)
+                    },
+                    Err(err) => {
+                        debug!(error = %err, "connection closed");
+                        Event::Closed(addr)
+                    }
+                };
+                let closed = matches!(event, Event::Closed(_));
+                if events.send(event).is_err() || closed {
+                    return;
+                }
+            }
+        }.instrument(span.clone()));
+        tokio::spawn(async move {
+            while let Some(message) = outgoing.recv().await {
+                for limit in &write_limits {
+                    limit.upload.acquire(message.wire_len()).await;
+                }
+                if let Err(err) = write_message(&mut write_half, &message).await {
+                    debug!(error = %err, "write failed");
+                    return;
+                }
+                trace!(%message, "sent");
+            }
+        }.instrument(span));
+        Self { sender, reader }
+    }
+
+    // Queues a message; a closed connection shows up as a `Closed` event.
+    pub fn send(&self, message: Message) {
+        let _ = self.sender.send(message);
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::engine::backlog::Backlog;
+    use crate::engine::buffers::BufferPool;
+    use crate::engine::peer::{extended_handshake, fetch_metadata, probe, read_handshake, read_message, read_message_pooled, write_handshake, write_message, Connection};
+    use crate::engine::rate::RateLimits;
+    use crate::handshake::Handshake;
+    use crate::extension::{ExtendedHandshake, MetadataMessage, METADATA_PIECE_LEN, UT_METADATA};
+    use crate::hash::sha1;
+    use crate::message::{Message, MAX_MESSAGE_LEN};
+    use std::time::Duration;
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::sync::mpsc;
+    use tokio::time;
+
+    #[tokio::test]
+    async fn test_message_io() {
+        let mut bytes = Vec::new();
+        write_message(&mut bytes, &Message::Have(3)).await.unwrap();
+        write_message(&mut bytes, &Message::KeepAlive).await.unwrap();
+        bytes.extend_from_slice(&(MAX_MESSAGE_LEN as u32 + 1).to_be_bytes());
+
+        let mut reader = &bytes[..];
+        assert_eq!(read_message(&mut reader).await.unwrap(), Message::Have(3));
+        assert_eq!(read_message(&mut reader).await.unwrap(), Message::KeepAlive);
+        assert!(read_message(&mut reader).await.is_err());
+
+        // A block lands in a pooled buffer; a piece too short to have one is
+        // malformed as ever.
+        let pool = BufferPool::new(4);
+        let piece = Message::Piece { index: 1, begin: 16, data: vec![9; 100].into() };
+        pool.give(pool.take(100).freeze());
+        let mut bytes = Vec::new();
+        write_message(&mut bytes, &piece).await.unwrap();
+        assert_eq!(bytes, piece.encode());
+        bytes.extend_from_slice(&[0, 0, 0, 5, 7, 0, 0, 0, 1]);
+        let mut reader = &bytes[..];
+        assert_eq!(read_message_pooled(&mut reader, &pool).await.unwrap(), piece);
+        assert_eq!(pool.available(), 0);
+        assert!(read_message_pooled(&mut reader, &pool).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_extended_handshake() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let peer = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let theirs = read_message(&mut stream).await.unwrap();
+            write_message(&mut stream, &Message::Bitfield(vec![0xff])).await.unwrap();
+            let ours = ExtendedHandshake::new().with_extension(UT_METADATA, 2).with_metadata_size(1234);
+            write_message(&mut stream, &Message::Extended { id: 0, payload: ours.encode() }).await.unwrap();
+            theirs
+        });
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let ours = ExtendedHandshake::new().with_extension(UT_METADATA, 1);
+        let theirs = extended_handshake(&mut stream, &ours).await.unwrap();
+        assert_eq!((theirs.extension_id(UT_METADATA), theirs.metadata_size), (Some(2), Some(1234)));
+        assert_eq!(peer.await.unwrap(), Message::Extended { id: 0, payload: ours.encode() });
+    }
+
+    #[tokio::test]
+    async fn test_fetch_metadata() {
+        let info: Vec<u8> = (0..METADATA_PIECE_LEN + 100).map(|at| at as u8).collect();
+        let (mut ours, mut theirs) = tokio::io::duplex(1 << 16);
+        let served = info.clone();
+        tokio::spawn(async move {
+            while let Ok(Message::Extended { id: 3, payload }) = read_message(&mut theirs).await {
+                let Some(MetadataMessage::Request(piece)) = MetadataMessage::decode(&payload) else {
+                    return;
+                };
+                let start = piece as usize * METADATA_PIECE_LEN;
+                let data = served[start..served.len().min(start + METADATA_PIECE_LEN)].to_vec();
+                write_message(&mut theirs, &Message::Have(0)).await.unwrap();
+                let reply = MetadataMessage::Data { piece, total_size: served.len() as u64, data };
+                write_message(&mut theirs, &Message::Extended { id: 1, payload: reply.encode() }).await.unwrap();
+            }
+        });
+
+        let extended = ExtendedHandshake::new().with_extension(UT_METADATA, 3).with_metadata_size(info.len() as u64);
+        assert_eq!(fetch_metadata(&mut ours, &extended, 1, sha1(&info)).await.unwrap(), info);
+        assert!(fetch_metadata(&mut ours, &extended, 1, [0; 20]).await.is_err());
+        let unoffered = ExtendedHandshake::new().with_metadata_size(info.len() as u64);
+        assert!(fetch_metadata(&mut ours, &unoffered, 1, sha1(&info)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_probe() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let theirs = read_handshake(&mut stream).await.unwrap();
+            write_handshake(&mut stream, &Handshake::new(theirs.info_hash, *b"-qB4650-abcdefghijkl").with_extensions()).await.unwrap();
+            write_message(&mut stream, &Message::Bitfield(vec![0xf0, 0x80])).await.unwrap();
+            write_message(&mut stream, &Message::Have(9)).await.unwrap();
+            read_message(&mut stream).await.unwrap();
+            let ours = ExtendedHandshake::new().with_extension(UT_METADATA, 2).with_client("qBittorrent/4.6.5");
+            write_message(&mut stream, &Message::Extended { id: 0, payload: ours.encode() }).await.unwrap();
+            // Stays connected until the prober hangs up.
+            let _ = read_message(&mut stream).await;
+        });
+
+        let ours = Handshake::new([7; 20], [1; 20]).with_extensions();
+        let found = probe(addr, ours, &ExtendedHandshake::new(), std::time::Duration::from_secs(5)).await.unwrap();
+        assert_eq!(&found.peer_id[..8], b"-qB4650-");
+        assert_eq!(found.pieces, Some(6));
+        let extended = found.extended.unwrap();
+        assert_eq!((extended.extension_id(UT_METADATA), extended.client.as_deref()), (Some(2), Some("qBittorrent/4.6.5")));
+    }
+
+    #[tokio::test]
+    async fn test_dropped_while_limited() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut theirs = TcpStream::connect(addr).await.unwrap();
+        let (ours, _) = listener.accept().await.unwrap();
+        let (events, mut received) = mpsc::unbounded_channel();
+        let backlog = Backlog::default();
+        let limits = vec![RateLimits::new(0, 10)];
+        let connection = Connection::spawn(ours, addr, events, limits, BufferPool::new(4), backlog.clone());
+
+        // The block is read, then held up by the limit for some seconds.
+        write_message(&mut theirs, &Message::Piece { index: 0, begin: 0, data: vec![1; 100].into() }).await.unwrap();
+        time::sleep(Duration::from_millis(100)).await;
+        drop(connection);
+        assert!(received.recv().await.is_none());
+        assert_eq!((backlog.blocks(), backlog.bytes()), (0, 0));
+    }
+}
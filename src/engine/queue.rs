@@ -0,0 +1,175 @@
+use std::sync::{Arc, Mutex};
+
+// How many torrents may run at once, by kind; `None` for no limit.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QueueLimits {
+    pub max_downloads: Option<usize>,
+    pub max_seeds: Option<usize>
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Entry {
+    info_hash: [u8; 20],
+    complete: bool,
+    paused: bool,
+    active: bool
+}
+
+#[derive(Debug, Default)]
+struct State {
+    limits: QueueLimits,
+    entries: Vec<Entry>
+}
+
+impl State {
+    // Walks the queue in order, letting torrents run until each kind's
+    // limit is reached. Paused torrents take no slot.
+    fn plan(&mut self) {
+        let (mut downloads, mut seeds) = (0, 0);
+        for entry in &mut self.entries {
+            let (running, limit) = match entry.complete {
+                true => (&mut seeds, self.limits.max_seeds),
+                false => (&mut downloads, self.limits.max_downloads)
+            };
+            entry.active = !entry.paused && limit.is_none_or(|limit| *running < limit);
+            if entry.active {
+                *running += 1;
+            }
+        }
+    }
+
+    fn position(&self, info_hash: &[u8; 20]) -> Option<usize> {
+        self.entries.iter().position(|entry| entry.info_hash == *info_hash)
+    }
+}
+
+// The order in which a session's torrents get to run. Torrents that don't
+// fit within the limits stay queued and start as slots free up; the
+// torrents themselves check in once a tick.
+#[derive(Debug, Clone, Default)]
+pub struct TorrentQueue {
+    state: Arc<Mutex<State>>
+}
+
+impl TorrentQueue {
+    pub fn new(limits: QueueLimits) -> Self {
+        Self { state: Arc::new(Mutex::new(State { limits, entries: Vec::new() })) }
+    }
+
+    pub fn limits(&self) -> QueueLimits {
+        self.state.lock().unwrap().limits
+    }
+
+    pub fn set_limits(&self, limits: QueueLimits) {
+        let mut state = self.state.lock().unwrap();
+        state.limits = limits;
+        state.plan();
+    }
+
+    // Adds a torrent at the back of the queue.
+    pub fn push(&self, info_hash: [u8; 20], complete: bool) {
+        let mut state = self.state.lock().unwrap();
+        if state.position(&info_hash).is_none() {
+            state.entries.push(Entry { info_hash, complete, paused: false, active: false });
+            state.plan();
+        }
+    }
+
+    pub fn remove(&self, info_hash: &[u8; 20]) {
+        let mut state = self.state.lock().unwrap();
+        state.entries.retain(|entry| entry.info_hash != *info_hash);
+        state.plan();
+    }
+
+    pub fn position(&self, info_hash: &[u8; 20]) -> Option<usize> {
+        self.state.lock().unwrap().position(info_hash)
+    }
+
+    // Moves a torrent to `position`, or to the back if that is past it.
+    pub fn set_position(&self, info_hash: &[u8; 20], position: usize) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let Some(current) = state.position(info_hash) else {
+            return false;
+        };
+        let entry = state.entries.remove(current);
+        let position = position.min(state.entries.len());
+        state.entries.insert(position, entry);
+        state.plan();
+        true
+    }
+
+    // The torrents in queue order.
+    pub fn order(&self) -> Vec<[u8; 20]> {
+        self.state
+            .lock()
+            .unwrap()
+            .entries
+            .iter()
+            .map(|entry| entry.info_hash)
+            .collect()
+    }
+
+    // Records a torrent's state and returns whether it may run.
+    pub fn update(&self, info_hash: &[u8; 20], complete: bool, paused: bool) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let Some(position) = state.position(info_hash) else {
+            return !paused;
+        };
+        let entry = &mut state.entries[position];
+        if (entry.complete, entry.paused) != (complete, paused) {
+            (entry.complete, entry.paused) = (complete, paused);
+            state.plan();
+        }
+        state.entries[position].active
+    }
+
+    pub fn is_active(&self, info_hash: &[u8; 20]) -> bool {
+        let state = self.state.lock().unwrap();
+        state.position(info_hash).is_some_and(|position| state.entries[position].active)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::engine::queue::{QueueLimits, TorrentQueue};
+
+    #[test]
+    fn test_limits() {
+        let queue = TorrentQueue::new(QueueLimits { max_downloads: Some(1), max_seeds: Some(1) });
+        queue.push([1; 20], false);
+        queue.push([2; 20], false);
+        queue.push([3; 20], true);
+        queue.push([4; 20], true);
+        assert!(queue.is_active(&[1; 20]));
+        assert!(!queue.is_active(&[2; 20]));
+        assert!(queue.is_active(&[3; 20]));
+        assert!(!queue.is_active(&[4; 20]));
+
+        // A finished download hands its slot to the next one and, being
+        // ahead in the queue, takes the seeding slot. Pausing it gives that
+        // back.
+        assert!(queue.update(&[1; 20], true, false));
+        assert!(queue.is_active(&[2; 20]));
+        assert!(!queue.is_active(&[3; 20]));
+        assert!(!queue.update(&[1; 20], true, true));
+        assert!(queue.is_active(&[3; 20]));
+
+        queue.set_limits(QueueLimits::default());
+        assert!(queue.is_active(&[4; 20]));
+    }
+
+    #[test]
+    fn test_reorder() {
+        let queue = TorrentQueue::new(QueueLimits { max_downloads: Some(1), max_seeds: None });
+        queue.push([1; 20], false);
+        queue.push([2; 20], false);
+        assert!(queue.set_position(&[2; 20], 0));
+        assert_eq!(queue.order(), [[2; 20], [1; 20]]);
+        assert!(queue.is_active(&[2; 20]));
+        assert!(!queue.is_active(&[1; 20]));
+
+        queue.remove(&[2; 20]);
+        assert!(queue.is_active(&[1; 20]));
+        assert!(!queue.set_position(&[2; 20], 0));
+    }
+}
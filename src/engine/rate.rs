@@ -0,0 +1,254 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::time;
+
+// Parses a rate in bytes per second such as `500`, `100K` or `1.5M`, with
+// decimal K, M and G suffixes. `0` means unlimited.
+pub fn parse_rate(text: &str) -> Option<u64> {
+    let text = text.trim();
+    let (number, scale) = match text.char_indices().last()? {
+        (at, 'k' | 'K') => (&text[..at], 1e3),
+        (at, 'm' | 'M') => (&text[..at], 1e6),
+        (at, 'g' | 'G') => (&text[..at], 1e9),
+        _ => (text, 1.0)
+    };
+    let rate = number.parse::<f64>().ok()? * scale;
+    (rate.is_finite() && rate >= 0.0).then_some(rate.round() as u64)
+}
+
+// A token bucket holding up to one second's worth of bytes. Taking more
+// than it holds leaves it in debt, and the taker waits the debt off, so a
+// large message is never starved by smaller ones.
+#[derive(Debug)]
+struct Bucket {
+    // Bytes per second; zero means unlimited.
+    rate: u64,
+    tokens: f64,
+    last: Instant
+}
+
+impl Bucket {
+    fn new(rate: u64, now: Instant) -> Self {
+        Self { rate, tokens: rate as f64, last: now }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate as f64).min(self.rate as f64);
+        self.last = now;
+    }
+
+    fn set_rate(&mut self, rate: u64, now: Instant) {
+        self.refill(now);
+        self.rate = rate;
+        self.tokens = self.tokens.min(rate as f64);
+    }
+
+    // Takes `amount` bytes and returns how long to wait before using them.
+    fn take(&mut self, amount: usize, now: Instant) -> Duration {
+        if self.rate == 0 {
+            return Duration::ZERO;
+        }
+        self.refill(now);
+        self.tokens -= amount as f64;
+        if self.tokens >= 0.0 {
+            return Duration::ZERO;
+        }
+        Duration::from_secs_f64(-self.tokens / self.rate as f64)
+    }
+}
+
+// A shareable rate limit. Clones share one bucket, so a limit can be
+// changed while connections are using it.
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    bucket: Arc<Mutex<Bucket>>
+}
+
+impl RateLimiter {
+    pub fn new(rate: u64) -> Self {
+        Self { bucket: Arc::new(Mutex::new(Bucket::new(rate, Instant::now()))) }
+    }
+
+    pub fn unlimited() -> Self {
+        Self::new(0)
+    }
+
+    pub fn rate(&self) -> u64 {
+        self.bucket.lock().unwrap().rate
+    }
+
+    pub fn set_rate(&self, rate: u64) {
+        self.bucket.lock().unwrap().set_rate(rate, Instant::now());
+    }
+
+    pub fn is_unlimited(&self) -> bool {
+        self.rate() == 0
+    }
+
+    // Waits until `amount` bytes may pass.
+    pub async fn acquire(&self, amount: usize) {
+        let wait = self.bucket.lock().unwrap().take(amount, Instant::now());
+        if !wait.is_zero() {
+            time::sleep(wait).await;
+        }
+    }
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        Self::unlimited()
+    }
+}
+
+// Upload and download limits for one scope, either the whole session or a
+// single torrent. A connection passes through every scope it belongs to.
+#[derive(Debug, Clone, Default)]
+pub struct RateLimits {
+    pub upload: RateLimiter,
+    pub download: RateLimiter
+}
+
+impl RateLimits {
+    pub fn new(upload: u64, download: u64) -> Self {
+        Self { upload: RateLimiter::new(upload), download: RateLimiter::new(download) }
+    }
+}
+
+// Default alternate ("turtle") limits, in bytes per second.
+pub const DEFAULT_ALTERNATE_UPLOAD: u64 = 50_000;
+pub const DEFAULT_ALTERNATE_DOWNLOAD: u64 = 100_000;
+
+#[derive(Debug)]
+struct Modes {
+    normal: (u64, u64),
+    alternate: (u64, u64),
+    use_alternate: bool
+}
+
+// A session's global limits: a normal pair and an alternate pair that can
+// be switched to and back at once, without losing either setting.
+#[derive(Debug, Clone)]
+pub struct GlobalLimits {
+    active: RateLimits,
+    modes: Arc<Mutex<Modes>>
+}
+
+impl GlobalLimits {
+    pub fn new(active: RateLimits) -> Self {
+        let normal = (active.upload.rate(), active.download.rate());
+        let alternate = (DEFAULT_ALTERNATE_UPLOAD, DEFAULT_ALTERNATE_DOWNLOAD);
+        Self { active, modes: Arc::new(Mutex::new(Modes { normal, alternate, use_alternate: false })) }
+    }
+
+    // The limiters connections use, set to whichever pair is in force.
+    pub fn active(&self) -> &RateLimits {
+        &self.active
+    }
+
+    pub fn normal(&self) -> (u64, u64) {
+        self.modes.lock().unwrap().normal
+    }
+
+    pub fn alternate(&self) -> (u64, u64) {
+        self.modes.lock().unwrap().alternate
+    }
+
+    pub fn uses_alternate(&self) -> bool {
+        self.modes.lock().unwrap().use_alternate
+    }
+
+    pub fn set_normal(&self, upload: u64, download: u64) {
+        self.modes.lock().unwrap().normal = (upload, download);
+        self.apply();
+    }
+
+    pub fn set_alternate(&self, upload: u64, download: u64) {
+        self.modes.lock().unwrap().alternate = (upload, download);
+        self.apply();
+    }
+
+    pub fn set_use_alternate(&self, use_alternate: bool) {
+        self.modes.lock().unwrap().use_alternate = use_alternate;
+        self.apply();
+    }
+
+    fn apply(&self) {
+        let modes = self.modes.lock().unwrap();
+        let (upload, download) = match modes.use_alternate {
+            true => modes.alternate,
+            false => modes.normal
+        };
+        self.active.upload.set_rate(upload);
+        self.active.download.set_rate(download);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::engine::rate::{parse_rate, Bucket, GlobalLimits, RateLimiter, RateLimits};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_bucket() {
+        let now = Instant::now();
+        let mut bucket = Bucket::new(1000, now);
+        assert_eq!(bucket.take(1000, now), Duration::ZERO);
+        assert_eq!(bucket.take(500, now), Duration::from_millis(500));
+        // The debt is paid off after half a second, then it refills again.
+        assert_eq!(bucket.take(100, now + Duration::from_millis(700)), Duration::ZERO);
+        assert_eq!(bucket.take(1000, now + Duration::from_secs(10)), Duration::ZERO);
+
+        bucket.set_rate(0, now + Duration::from_secs(10));
+        assert_eq!(bucket.take(1 << 30, now + Duration::from_secs(10)), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_set_rate_caps_tokens() {
+        let now = Instant::now();
+        let mut bucket = Bucket::new(10_000, now);
+        bucket.set_rate(100, now);
+        assert_eq!(bucket.take(200, now), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_parse_rate() {
+        assert_eq!(parse_rate("0"), Some(0));
+        assert_eq!(parse_rate("512"), Some(512));
+        assert_eq!(parse_rate("100K"), Some(100_000));
+        assert_eq!(parse_rate("1.5m"), Some(1_500_000));
+        assert_eq!(parse_rate("2G"), Some(2_000_000_000));
+        assert_eq!(parse_rate("-1"), None);
+        assert_eq!(parse_rate("fast"), None);
+        assert_eq!(parse_rate(""), None);
+    }
+
+    #[test]
+    fn test_alternate_limits() {
+        let limits = GlobalLimits::new(RateLimits::default());
+        limits.set_normal(1000, 2000);
+        limits.set_alternate(10, 20);
+        assert_eq!(limits.active().upload.rate(), 1000);
+
+        limits.set_use_alternate(true);
+        assert_eq!(limits.active().download.rate(), 20);
+        // Changing the normal pair while the alternate is on keeps it for later.
+        limits.set_normal(3000, 4000);
+        assert_eq!(limits.active().download.rate(), 20);
+        limits.set_use_alternate(false);
+        assert_eq!((limits.active().upload.rate(), limits.active().download.rate()), (3000, 4000));
+    }
+
+    #[tokio::test]
+    async fn test_acquire() {
+        let limiter = RateLimiter::new(100_000);
+        let start = Instant::now();
+        limiter.acquire(100_000).await;
+        limiter.acquire(20_000).await;
+        assert!(start.elapsed() >= Duration::from_millis(150));
+
+        let clone = limiter.clone();
+        clone.set_rate(0);
+        assert!(limiter.is_unlimited());
+    }
+}
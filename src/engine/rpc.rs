@@ -0,0 +1,369 @@
+use crate::engine::control::{self, Command, Request};
+use crate::engine::Alert;
+use crate::handshake::HANDSHAKE_TIMEOUT;
+use crate::hash::hex;
+use serde_json::{json, Value};
+use std::collections::VecDeque;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, Notify};
+use tokio::time;
+use tracing::debug;
+
+const MAX_HEADER_LEN: usize = 8 * 1024;
+const MAX_BODY_LEN: usize = 1 << 20;
+// Events kept for long-polling clients to catch up on.
+const EVENT_CAPACITY: usize = 1024;
+const MAX_POLL_TIMEOUT: Duration = Duration::from_secs(60);
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+// Whatever the session refused, such as an unknown info hash.
+const SERVER_ERROR: i64 = -32000;
+
+// JSON-RPC methods that map onto a control request of the same shape.
+const METHODS: [(&str, &str); 11] = [
+    ("torrent.add", "add"),
+    ("torrent.add_magnet", "add_magnet"),
+    ("torrent.list", "stats"),
+    ("torrent.pause", "pause"),
+    ("torrent.resume", "resume"),
+    ("torrent.remove", "remove"),
+    ("session.set_limits", "set_limits"),
+    ("session.set_schedule", "set_schedule"),
+    ("session.set_alternate_limits", "set_alternate_limits"),
+    ("session.set_alternate_speed", "set_alternate_speed"),
+    ("session.set_seed_goal", "set_seed_goal")
+];
+
+// The session's recent alerts, numbered from 1 so clients can ask for
+// whatever came after the last one they saw.
+#[derive(Debug, Default)]
+pub(super) struct EventLog {
+    events: Mutex<VecDeque<(u64, Alert)>>,
+    added: Notify
+}
+
+impl EventLog {
+    pub(super) fn push(&self, alert: Alert) {
+        let mut events = self.events.lock().unwrap();
+        let id = events.back().map_or(1, |(id, _)| id + 1);
+        events.push_back((id, alert));
+        if events.len() > EVENT_CAPACITY {
+            events.pop_front();
+        }
+        self.added.notify_waiters();
+    }
+
+    fn after(&self, since: u64) -> Vec<(u64, Alert)> {
+        let events = self.events.lock().unwrap();
+        events.iter().filter(|(id, _)| *id > since).cloned().collect()
+    }
+
+    // Events after `since`, waiting up to `timeout` for one if there are
+    // none yet.
+    async fn poll(&self, since: u64, timeout: Duration) -> Vec<(u64, Alert)> {
+        let added = self.added.notified();
+        let events = self.after(since);
+        if !events.is_empty() {
+            return events;
+        }
+        let _ = time::timeout(timeout, added).await;
+        self.after(since)
+    }
+}
+
+fn event_json(id: u64, alert: &Alert) -> Value {
+    let (kind, mut event) = match alert {
+        Alert::PeerConnected { addr, .. } => ("peer_connected", json!({ "addr": addr })),
+        Alert::PeerDisconnected { addr, .. } => ("peer_disconnected", json!({ "addr": addr })),
+        Alert::PieceVerified { piece, .. } => ("piece_verified", json!({ "piece": piece })),
+        Alert::HashFailed { piece, .. } => ("hash_failed", json!({ "piece": piece })),
+        Alert::TorrentCompleted { .. } => ("torrent_completed", json!({})),
+        Alert::TorrentPaused { .. } => ("torrent_paused", json!({})),
+        Alert::TorrentResumed { .. } => ("torrent_resumed", json!({})),
+        Alert::SeedingGoalReached { .. } => ("seeding_goal_reached", json!({})),
+        Alert::StorageError { message, .. } => ("storage_error", json!({ "message": message })),
+        Alert::LowDiskSpace { available, needed, .. } => {
+            ("low_disk_space", json!({ "available": available, "needed": needed }))
+        },
+        Alert::TrackerError { url, message, .. } => ("tracker_error", json!({ "url": url, "message": message })),
+        Alert::MetadataReceived { addr, .. } => ("metadata_received", json!({ "addr": addr }))
+    };
+    event["id"] = id.into();
+    event["type"] = kind.into();
+    event["info_hash"] = hex(&alert.info_hash()).into();
+    event
+}
+
+type RpcResult = Result<Value, (i64, String)>;
+
+fn invalid_params() -> (i64, String) {
+    (INVALID_PARAMS, "invalid params".to_string())
+}
+
+async fn method(name: &str, params: &Value, commands: &mpsc::UnboundedSender<Command>, log: &EventLog) -> RpcResult {
+    if name == "events.poll" {
+        let since = params.get("since").map_or(Some(0), Value::as_u64).ok_or_else(invalid_params)?;
+        let timeout = params.get("timeout").map_or(Some(0), Value::as_u64).ok_or_else(invalid_params)?;
+        let events = log.poll(since, Duration::from_secs(timeout).min(MAX_POLL_TIMEOUT)).await;
+        let next = events.last().map_or(since, |(id, _)| *id);
+        let events: Vec<_> = events.iter().map(|(id, alert)| event_json(*id, alert)).collect();
+        return Ok(json!({ "events": events, "next": next }));
+    }
+    let Some(&(_, command)) = METHODS.iter().find(|(method, _)| *method == name) else {
+        return Err((METHOD_NOT_FOUND, format!("no method {}", name)));
+    };
+    let mut request = match params {
+        Value::Null => json!({}),
+        Value::Object(_) => params.clone(),
+        _ => return Err(invalid_params())
+    };
+    request["command"] = command.into();
+    let request = Request::from_json(&request).ok_or_else(invalid_params)?;
+    let mut response = control::execute(commands, request).await;
+    if let Some(message) = response.get("error").and_then(Value::as_str) {
+        return Err((SERVER_ERROR, message.to_string()));
+    }
+    Ok(match name {
+        "torrent.list" => response["torrents"].take(),
+        "torrent.add" | "torrent.add_magnet" => json!({ "info_hash": response["info_hash"], "name": response["name"] }),
+        _ => Value::Bool(true)
+    })
+}
+
+// Answers one JSON-RPC 2.0 call; `None` for notifications, which get no
+// answer.
+async fn call(request: &Value, commands: &mpsc::UnboundedSender<Command>, log: &EventLog) -> Option<Value> {
+    let id = request.get("id").cloned();
+    let name = match (request.get("jsonrpc").and_then(Value::as_str), request.get("method").and_then(Value::as_str)) {
+        (Some("2.0"), Some(name)) => name,
+        _ => return Some(json!({ "jsonrpc": "2.0", "id": Value::Null, "error": { "code": INVALID_REQUEST, "message": "invalid request" } }))
+    };
+    let result = method(name, request.get("params").unwrap_or(&Value::Null), commands, log).await;
+    let id = id?;
+    Some(match result {
+        Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+        Err((code, message)) => json!({ "jsonrpc": "2.0", "id": id, "error": { "code": code, "message": message } })
+    })
+}
+
+// A single call or a batch of them, as sent in one request body.
+async fn handle(body: &[u8], commands: &mpsc::UnboundedSender<Command>, log: &EventLog) -> Option<Value> {
+    let request: Value = match serde_json::from_slice(body) {
+        Ok(request) => request,
+        Err(_) => return Some(json!({ "jsonrpc": "2.0", "id": Value::Null, "error": { "code": PARSE_ERROR, "message": "parse error" } }))
+    };
+    let Value::Array(batch) = request else {
+        return call(&request, commands, log).await;
+    };
+    if batch.is_empty() {
+        return call(&Value::Null, commands, log).await;
+    }
+    let mut responses = Vec::new();
+    for request in &batch {
+        responses.extend(call(request, commands, log).await);
+    }
+    (!responses.is_empty()).then_some(Value::Array(responses))
+}
+
+// The value of header `name` in a request head.
+pub(super) fn header<'a>(head: &'a str, name: &str) -> Option<&'a str> {
+    head.lines()
+        .filter_map(|line| line.split_once(':'))
+        .find(|(key, _)| key.trim().eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.trim())
+}
+
+// The head and body of an HTTP request, as far as a POST of JSON needs.
+pub(super) async fn read_request(stream: &mut TcpStream) -> io::Result<(String, Vec<u8>)> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "bad request");
+    let mut request = Vec::new();
+    let mut buf = [0; 4096];
+    let end = loop {
+        if let Some(at) = request.windows(4).position(|window| window == b"\r\n\r\n") {
+            break at + 4;
+        }
+        let n = stream.read(&mut buf).await?;
+        if n == 0 || request.len() + n > MAX_HEADER_LEN + MAX_BODY_LEN {
+            return Err(invalid());
+        }
+        request.extend_from_slice(&buf[..n]);
+    };
+    let head = String::from_utf8_lossy(&request[..end]).into_owned();
+    let length = header(&head, "content-length")
+        .map_or(Some(0), |value| value.parse::<usize>().ok())
+        .filter(|&length| length <= MAX_BODY_LEN)
+        .ok_or_else(invalid)?;
+    let mut body = request.split_off(end);
+    if body.len() < length {
+        let start = body.len();
+        body.resize(length, 0);
+        stream.read_exact(&mut body[start..]).await?;
+    }
+    body.truncate(length);
+    Ok((head, body))
+}
+
+async fn respond(mut stream: TcpStream, commands: &mpsc::UnboundedSender<Command>, log: &EventLog) -> io::Result<()> {
+    let (head, body) = time::timeout(HANDSHAKE_TIMEOUT, read_request(&mut stream))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "request timed out"))??;
+    let json = header(&head, "content-type")
+        .and_then(|value| value.split(';').next())
+        .is_some_and(|media| media.trim().eq_ignore_ascii_case("application/json"));
+    // Pages elsewhere can only post forms and plain text to us without the
+    // browser asking first, which we never agree to; and as we serve no
+    // pages, a request that says where its page came from is always some
+    // other site's, even one that rebound its name to our address.
+    let response = match (head.starts_with("POST "), json, header(&head, "origin")) {
+        (false, _, _) => "HTTP/1.1 405 Method Not Allowed\r\nAllow: POST\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string(),
+        (true, _, Some(_)) => "HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string(),
+        (true, false, None) => "HTTP/1.1 415 Unsupported Media Type\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string(),
+        (true, true, None) => match handle(&body, commands, log).await {
+            Some(response) => {
+                let body = response.to_string();
+                format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                )
+            },
+            None => "HTTP/1.1 204 No Content\r\nConnection: close\r\n\r\n".to_string()
+        }
+    };
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+// Answers JSON-RPC POSTs on `listener` for as long as the task runs.
+pub(super) async fn serve(listener: TcpListener, commands: mpsc::UnboundedSender<Command>, log: Arc<EventLog>) {
+    loop {
+        let Ok((stream, addr)) = listener.accept().await else {
+            continue;
+        };
+        let (commands, log) = (commands.clone(), log.clone());
+        tokio::spawn(async move {
+            if let Err(err) = respond(stream, &commands, &log).await {
+                debug!(%addr, error = %err, "rpc request failed");
+            }
+        });
+    }
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use crate::engine::control::ControlServer;
+    use crate::engine::rpc::EventLog;
+    use crate::engine::test::{metadata_peer, metainfo};
+    use crate::engine::{Alert, Session};
+    use crate::hash::hex;
+    use serde_json::{json, Value};
+    use std::net::SocketAddr;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    async fn post(addr: SocketAddr, body: &str) -> (String, Option<Value>) {
+        post_with(addr, "Content-Type: application/json; charset=utf-8\r\n", body).await
+    }
+
+    async fn post_with(addr: SocketAddr, headers: &str, body: &str) -> (String, Option<Value>) {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let request = format!("POST /rpc HTTP/1.1\r\nHost: x\r\n{}Content-Length: {}\r\n\r\n{}", headers, body.len(), body);
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        (head.lines().next().unwrap().to_string(), serde_json::from_str(body).ok())
+    }
+
+    #[tokio::test]
+    async fn test_event_log() {
+        let log = EventLog::default();
+        assert!(log.poll(0, Duration::from_millis(10)).await.is_empty());
+        for piece in 0..3 {
+            log.push(Alert::PieceVerified { info_hash: [1; 20], piece });
+        }
+        let events = log.poll(1, Duration::ZERO).await;
+        assert_eq!(events.iter().map(|(id, _)| *id).collect::<Vec<_>>(), vec![2, 3]);
+    }
+
+    #[tokio::test]
+    async fn test_rpc() {
+        let dir = std::env::temp_dir().join(format!("rpc-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let session = Session::bind("127.0.0.1:0").await.unwrap();
+        let socket = dir.join("control.sock");
+        let server = tokio::spawn({
+            let socket = socket.clone();
+            async move { ControlServer::new(session).with_rpc(listener).serve(&socket).await }
+        });
+
+        let list = r#"{"jsonrpc": "2.0", "id": 1, "method": "torrent.list"}"#;
+        let (status, response) = post(addr, list).await;
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        assert_eq!(response.unwrap(), json!({ "jsonrpc": "2.0", "id": 1, "result": [] }));
+        // What another site's page could send.
+        assert_eq!(post_with(addr, "Content-Type: text/plain\r\n", list).await.0, "HTTP/1.1 415 Unsupported Media Type");
+        let origin = "Content-Type: application/json\r\nOrigin: http://example.com\r\n";
+        assert_eq!(post_with(addr, origin, list).await.0, "HTTP/1.1 403 Forbidden");
+
+        let batch = r#"[
+            {"jsonrpc": "2.0", "id": "a", "method": "session.set_limits", "params": {"upload": 1000, "download": 0}},
+            {"jsonrpc": "2.0", "id": "b", "method": "torrent.pause", "params": {"info_hash": "0000000000000000000000000000000000000000"}},
+            {"jsonrpc": "2.0", "id": "c", "method": "torrent.pause"},
+            {"jsonrpc": "2.0", "id": "d", "method": "nope"},
+            {"jsonrpc": "2.0", "method": "torrent.list"}
+        ]"#;
+        let responses = post(addr, batch).await.1.unwrap();
+        let codes: Vec<_> = responses.as_array().unwrap().iter().map(|response| response["error"]["code"].clone()).collect();
+        assert_eq!(codes, [Value::Null, json!(-32000), json!(-32602), json!(-32601)]);
+        assert_eq!(responses[0]["result"], true);
+
+        let (status, _) = post(addr, r#"{"jsonrpc": "2.0", "method": "torrent.list"}"#).await;
+        assert_eq!(status, "HTTP/1.1 204 No Content");
+        assert_eq!(post(addr, "{").await.1.unwrap()["error"]["code"], -32700);
+        let magnet = r#"{"jsonrpc": "2.0", "id": 2, "method": "torrent.add_magnet", "params": {"magnet": "magnet:?dn=x", "dir": "/"}}"#;
+        assert_eq!(post(addr, magnet).await.1.unwrap()["error"]["code"], -32602);
+
+        // A link whose one peer never answers is still being fetched while
+        // other calls are answered.
+        let silent = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stuck = json!({ "jsonrpc": "2.0", "id": 4, "method": "torrent.add_magnet", "params": {
+            "magnet": format!("magnet:?xt=urn:btih:{}", "ab".repeat(20)),
+            "dir": dir,
+            "peers": [silent.local_addr().unwrap()]
+        } });
+        tokio::spawn(async move { post(addr, &stuck.to_string()).await });
+        let list = r#"{"jsonrpc": "2.0", "id": 5, "method": "torrent.list"}"#;
+        assert_eq!(post(addr, list).await.1.unwrap()["result"], json!([]));
+
+        let metainfo = metainfo(&[7; 1000], 16 * 1024);
+        let peer = metadata_peer(&metainfo).await;
+        let magnet = json!({ "jsonrpc": "2.0", "id": 6, "method": "torrent.add_magnet", "params": {
+            "magnet": format!("magnet:?xt=urn:btih:{}&x.pe={}", hex(&metainfo.info_hash), peer),
+            "dir": dir
+        } });
+        let added = post(addr, &magnet.to_string()).await.1.unwrap();
+        assert_eq!(added["result"], json!({ "info_hash": hex(&metainfo.info_hash), "name": "file" }));
+        assert_eq!(post(addr, list).await.1.unwrap()["result"][0]["name"], "file");
+
+        let poll = r#"{"jsonrpc": "2.0", "id": 3, "method": "events.poll", "params": {"since": 0, "timeout": 0}}"#;
+        let polled = post(addr, poll).await.1.unwrap()["result"].clone();
+        assert_eq!((&polled["events"][0]["type"], &polled["events"][0]["addr"]), (&json!("metadata_received"), &json!(peer)));
+        let poll = r#"{"jsonrpc": "2.0", "id": 3, "method": "events.poll", "params": {"since": 1, "timeout": 0}}"#;
+        assert_eq!(post(addr, poll).await.1.unwrap()["result"], json!({ "events": [], "next": 1 }));
+
+        crate::engine::control::send(&socket, &crate::engine::control::Request::Shutdown).await.unwrap();
+        assert!(server.await.unwrap().unwrap());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,186 @@
+use crate::engine::rate::{parse_rate, GlobalLimits};
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tokio::time;
+use tracing::info;
+
+// How often the schedule is checked against the clock.
+pub const SCHEDULE_INTERVAL: Duration = Duration::from_secs(30);
+
+const DAYS: [&str; 7] = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+const MINUTES_PER_DAY: u16 = 24 * 60;
+
+// A local weekday (0 is Sunday) and minute of the day.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LocalTime {
+    pub weekday: u8,
+    pub minute: u16
+}
+
+impl LocalTime {
+    #[cfg(unix)]
+    pub fn now() -> Self {
+        let now = unsafe { libc::time(std::ptr::null_mut()) };
+        let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+        unsafe { libc::localtime_r(&now, &mut tm) };
+        Self { weekday: tm.tm_wday as u8, minute: (tm.tm_hour * 60 + tm.tm_min) as u16 }
+    }
+
+    // Without a timezone database, schedules follow UTC.
+    #[cfg(not(unix))]
+    pub fn now() -> Self {
+        let secs = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs());
+        let days = secs / 86400;
+        // The epoch was a Thursday.
+        Self { weekday: ((days + 4) % 7) as u8, minute: (secs % 86400 / 60) as u16 }
+    }
+}
+
+// Limits (bytes per second, zero for none) that apply on some days between
+// two times. A window that ends before it starts runs past midnight, and
+// then belongs to the day it started on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScheduleRule {
+    // Bit n set for weekday n.
+    pub days: u8,
+    pub start: u16,
+    pub end: u16,
+    pub upload: u64,
+    pub download: u64
+}
+
+impl ScheduleRule {
+    fn applies(&self, time: LocalTime) -> bool {
+        let on = |weekday: u8| self.days & (1 << weekday) != 0;
+        match self.start <= self.end {
+            true => on(time.weekday) && (self.start..self.end).contains(&time.minute),
+            false => {
+                let yesterday = (time.weekday + 6) % 7;
+                (on(time.weekday) && time.minute >= self.start) || (on(yesterday) && time.minute < self.end)
+            }
+        }
+    }
+}
+
+fn parse_days(text: &str) -> Option<u8> {
+    if text == "*" {
+        return Some(0x7f);
+    }
+    let day = |name: &str| DAYS.iter().position(|day| *day == name);
+    text.split(',').try_fold(0u8, |days, part| {
+        let bits = match part.split_once('-') {
+            Some((from, to)) => {
+                let (from, to) = (day(from)?, day(to)?);
+                (0..7).filter(|d| (d + 7 - from) % 7 <= (to + 7 - from) % 7).fold(0, |bits, d| bits | 1 << d)
+            },
+            None => 1 << day(part)?
+        };
+        Some(days | bits)
+    })
+}
+
+fn parse_time(text: &str) -> Option<u16> {
+    let (hours, minutes) = text.split_once(':')?;
+    let (hours, minutes): (u16, u16) = (hours.parse().ok()?, minutes.parse().ok()?);
+    if hours > 24 || minutes > 59 || hours * 60 + minutes > MINUTES_PER_DAY {
+        return None;
+    }
+    Some((hours * 60 + minutes) % MINUTES_PER_DAY)
+}
+
+// Global limits by time of day. The first rule that applies wins; outside
+// every rule the default limits hold.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BandwidthSchedule {
+    pub default_upload: u64,
+    pub default_download: u64,
+    pub rules: Vec<ScheduleRule>
+}
+
+impl BandwidthSchedule {
+    // One rule per line: days, a time window and the upload and download
+    // limits, e.g. `mon-fri 09:00-17:00 1M 2M`. Days are `*` or day names
+    // and ranges separated by commas; `0` means unlimited. Blank lines and
+    // lines starting with `#` are skipped.
+    pub fn parse(text: &str) -> Option<Self> {
+        let mut schedule = Self::default();
+        for line in text.lines().map(str::trim).filter(|line| !line.is_empty() && !line.starts_with('#')) {
+            let [days, window, upload, download] = line.split_whitespace().collect::<Vec<_>>()[..] else {
+                return None;
+            };
+            let (start, end) = window.split_once('-')?;
+            schedule.rules.push(ScheduleRule {
+                days: parse_days(&days.to_ascii_lowercase())?,
+                start: parse_time(start)?,
+                end: parse_time(end)?,
+                upload: parse_rate(upload)?,
+                download: parse_rate(download)?
+            });
+        }
+        Some(schedule)
+    }
+
+    // Upload and download limits at `time`.
+    pub fn limits_at(&self, time: LocalTime) -> (u64, u64) {
+        self.rules
+            .iter()
+            .find(|rule| rule.applies(time))
+            .map_or((self.default_upload, self.default_download), |rule| (rule.upload, rule.download))
+    }
+
+    // Keeps the normal pair of `limits` set to what the schedule says for
+    // as long as the task runs. The alternate pair still wins when on.
+    pub fn spawn(self, limits: GlobalLimits) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut tick = time::interval(SCHEDULE_INTERVAL);
+            loop {
+                tick.tick().await;
+                let (upload, download) = self.limits_at(LocalTime::now());
+                if (upload, download) != limits.normal() {
+                    info!(upload, download, "bandwidth schedule changed the global limits");
+                    limits.set_normal(upload, download);
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::engine::schedule::{parse_days, BandwidthSchedule, LocalTime};
+
+    fn at(weekday: u8, hour: u16, minute: u16) -> LocalTime {
+        LocalTime { weekday, minute: hour * 60 + minute }
+    }
+
+    #[test]
+    fn test_parse_days() {
+        assert_eq!(parse_days("*"), Some(0x7f));
+        assert_eq!(parse_days("mon-fri"), Some(0b0111110));
+        assert_eq!(parse_days("sat,sun"), Some(0b1000001));
+        assert_eq!(parse_days("fri-mon"), Some(0b1100011));
+        assert_eq!(parse_days("someday"), None);
+    }
+
+    #[test]
+    fn test_schedule() {
+        let schedule = BandwidthSchedule::parse(
+            "# work hours
+            mon-fri 09:00-17:00 100K 1M
+            * 23:00-07:00 0 0"
+        ).unwrap();
+        let schedule = BandwidthSchedule { default_upload: 50_000, default_download: 500_000, ..schedule };
+
+        assert_eq!(schedule.limits_at(at(1, 9, 0)), (100_000, 1_000_000));
+        assert_eq!(schedule.limits_at(at(5, 16, 59)), (100_000, 1_000_000));
+        assert_eq!(schedule.limits_at(at(5, 17, 0)), (50_000, 500_000));
+        assert_eq!(schedule.limits_at(at(6, 12, 0)), (50_000, 500_000));
+        assert_eq!(schedule.limits_at(at(0, 23, 30)), (0, 0));
+        assert_eq!(schedule.limits_at(at(1, 6, 59)), (0, 0));
+
+        assert_eq!(BandwidthSchedule::parse("mon 9:00 1M 1M"), None);
+        assert_eq!(BandwidthSchedule::parse("mon 09:00-25:00 1M 1M"), None);
+    }
+}
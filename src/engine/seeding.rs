@@ -0,0 +1,57 @@
+use std::time::Duration;
+
+// When a finished torrent has seeded enough and should pause: after
+// uploading `ratio` times its size, or after seeding for `time`, whichever
+// comes first. Neither set means seeding forever, which is the default.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct SeedGoal {
+    pub ratio: Option<f64>,
+    pub time: Option<Duration>
+}
+
+impl SeedGoal {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Pauses torrents as soon as they finish, without seeding.
+    pub fn no_seeding() -> Self {
+        Self::new().with_time(Duration::ZERO)
+    }
+
+    pub fn with_ratio(mut self, ratio: f64) -> Self {
+        self.ratio = Some(ratio);
+        self
+    }
+
+    pub fn with_time(mut self, time: Duration) -> Self {
+        self.time = Some(time);
+        self
+    }
+
+    pub fn is_reached(&self, uploaded: u64, size: u64, seeding_time: Duration) -> bool {
+        let ratio = match size {
+            0 => f64::INFINITY,
+            size => uploaded as f64 / size as f64
+        };
+        self.ratio.is_some_and(|goal| ratio >= goal) || self.time.is_some_and(|goal| seeding_time >= goal)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::engine::seeding::SeedGoal;
+    use std::time::Duration;
+
+    #[test]
+    fn test_seed_goal() {
+        let hour = Duration::from_secs(3600);
+        assert!(!SeedGoal::new().is_reached(u64::MAX, 1, hour * 1000));
+
+        let goal = SeedGoal::new().with_ratio(2.0).with_time(hour);
+        assert!(!goal.is_reached(1999, 1000, hour / 2));
+        assert!(goal.is_reached(2000, 1000, hour / 2));
+        assert!(goal.is_reached(0, 1000, hour));
+        assert!(SeedGoal::no_seeding().is_reached(0, 1000, Duration::ZERO));
+    }
+}
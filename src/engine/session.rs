@@ -0,0 +1,727 @@
+use crate::choker::UploadSlots;
+use crate::engine::connections::ConnectionLimits;
+use crate::engine::metrics::{self, Metrics};
+use crate::engine::queue::QueueLimits;
+use crate::engine::rate::GlobalLimits;
+use crate::engine::schedule::BandwidthSchedule;
+use crate::engine::seeding::SeedGoal;
+use crate::engine::torrent::{Shared, TorrentOptions, SHUTDOWN_TIMEOUT};
+use crate::engine::listener::ListenPort;
+use crate::engine::peer;
+use crate::engine::{Alert, PeerListener, RateLimits, TorrentHandle};
+use crate::extension::{ExtendedHandshake, UT_METADATA};
+use crate::geoip::GeoIp;
+use crate::handshake::Handshake;
+use crate::ipfilter::{BlockReason, IpFilter};
+use crate::magnet::Magnet;
+use crate::metainfo::Metainfo;
+use crate::nat::{Gateway, PortMapper};
+use crate::peer_id;
+use crate::storage::Storage;
+use crate::torrent::Torrent;
+#[cfg(any(feature = "tracker-http", feature = "tracker-udp"))]
+use crate::tracker::{self, Announce, Tracker};
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::{TcpListener, ToSocketAddrs};
+use tokio::sync::broadcast;
+use tokio::task::JoinHandle;
+#[cfg(any(feature = "tracker-http", feature = "tracker-udp"))]
+use tokio::task::JoinSet;
+use tokio::time::Instant;
+use tracing::debug;
+
+// The id our extended handshake gives `ut_metadata`.
+const OUR_UT_METADATA: u8 = 1;
+
+// Runs any number of torrents behind one listening port and one peer id.
+// This is the entry point for embedding the engine.
+pub struct Session {
+    peer_id: [u8; 20],
+    listener: PeerListener,
+    listener_task: JoinHandle<()>,
+    metrics_task: Option<JoinHandle<()>>,
+    schedule_task: Option<JoinHandle<()>>,
+    port_mapper: Option<PortMapper>,
+    shared: Shared,
+    global_limits: GlobalLimits,
+    shutdown_timeout: Duration,
+    torrents: HashMap<[u8; 20], TorrentHandle>
+}
+
+impl Session {
+    // Starts listening for peers on `addr`; port 0 picks a free one.
+    pub async fn bind(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        Ok(Self::start(PeerListener::bind(addr).await?))
+    }
+
+    // Starts listening on `ip` at a fixed port, the first free one of a
+    // range, or a random one.
+    pub async fn bind_port(ip: IpAddr, port: ListenPort) -> io::Result<Self> {
+        Ok(Self::start(PeerListener::bind_port(ip, port).await?))
+    }
+
+    fn start(listener: PeerListener) -> Self {
+        let listener_task = listener.spawn();
+        let shared = Shared {
+            port: listener.local_addr().map_or(0, |addr| addr.port()),
+            ip_filter: listener.ip_filter(),
+            bans: listener.ban_list(),
+            ..Shared::default()
+        };
+        Self {
+            peer_id: peer_id::generate(),
+            listener,
+            listener_task,
+            metrics_task: None,
+            schedule_task: None,
+            port_mapper: None,
+            global_limits: GlobalLimits::new(shared.limits.clone()),
+            shared,
+            shutdown_timeout: SHUTDOWN_TIMEOUT,
+            torrents: HashMap::new()
+        }
+    }
+
+    pub fn peer_id(&self) -> [u8; 20] {
+        self.peer_id
+    }
+
+    pub fn listen_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    // The port we ended up on, which is what to announce to trackers, the
+    // DHT and local peers.
+    pub fn listen_port(&self) -> u16 {
+        self.listen_addr().map_or(0, |addr| addr.port())
+    }
+
+    // Keeps the listen port forwarded on the local gateway, for TCP and for
+    // UDP so a DHT node on the same port is reachable too. The mappings
+    // go away on shutdown.
+    pub fn forward_port(&mut self) {
+        if self.port_mapper.is_none() {
+            self.port_mapper = Some(PortMapper::spawn(self.listen_port()));
+        }
+    }
+
+    // Forwards the port through `gateway` rather than one found on the
+    // network.
+    pub fn forward_port_via(&mut self, gateway: Gateway) {
+        if self.port_mapper.is_none() {
+            self.port_mapper = Some(PortMapper::spawn_with(self.listen_port(), Some(gateway)));
+        }
+    }
+
+    // Tells peers that support the DHT where our node is, with a port
+    // message after the handshake.
+    pub fn set_dht_port(&self, port: Option<u16>) {
+        self.listener.set_dht_port(port);
+        self.shared.dht_port.store(port.unwrap_or(0), Ordering::Relaxed);
+    }
+
+    // Replaces the blocklist. Connected peers that it blocks are dropped
+    // within a second.
+    pub fn set_ip_filter(&self, filter: IpFilter) {
+        *self.shared.ip_filter.lock().unwrap() = filter;
+    }
+
+    // Adds a blocklist file to the filter, returning how many ranges it had.
+    pub fn load_blocklist(&self, path: impl AsRef<Path>) -> io::Result<usize> {
+        // Torrents check the filter every tick, so don't hold it while reading.
+        let mut filter = self.shared.ip_filter.lock().unwrap().clone();
+        let added = filter.load(path)?;
+        self.set_ip_filter(filter);
+        Ok(added)
+    }
+
+    // Opens a MaxMind country database, so peers that connect from now on
+    // carry a country code. Needs the `geoip` feature.
+    pub fn load_geoip(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let geoip = GeoIp::open(path)?;
+        *self.shared.geoip.lock().unwrap() = Some(geoip);
+        Ok(())
+    }
+
+    pub fn blocked_by(&self, ip: IpAddr) -> Option<BlockReason> {
+        self.shared.ip_filter.lock().unwrap().blocked_by(ip)
+    }
+
+    // Bans a peer from every torrent of the session, as happens by itself
+    // to one that keeps sending pieces that fail their hash check. If it's
+    // connected, it's dropped within a second.
+    pub fn ban(&self, ip: IpAddr) -> bool {
+        self.shared.bans.lock().unwrap().ban(ip)
+    }
+
+    // Lets a banned peer back in, and forgets the corrupt data it sent.
+    pub fn unban(&self, ip: IpAddr) -> bool {
+        self.shared.bans.lock().unwrap().unban(ip)
+    }
+
+    pub fn banned(&self) -> Vec<IpAddr> {
+        self.shared.bans.lock().unwrap().banned().collect()
+    }
+
+    // The limits shared by every torrent in the session as they are right
+    // now. Each torrent can be held tighter through its handle's own.
+    pub fn limits(&self) -> &RateLimits {
+        &self.shared.limits
+    }
+
+    // Sets the global limits in bytes per second, zero for unlimited. They
+    // are unlimited until set.
+    pub fn set_limits(&self, upload: u64, download: u64) {
+        self.global_limits.set_normal(upload, download);
+    }
+
+    // A second pair of global limits to switch to on demand, such as while
+    // someone else needs the connection.
+    pub fn set_alternate_limits(&self, upload: u64, download: u64) {
+        self.global_limits.set_alternate(upload, download);
+    }
+
+    pub fn set_alternate_speed(&self, enabled: bool) {
+        self.global_limits.set_use_alternate(enabled);
+    }
+
+    pub fn is_alternate_speed(&self) -> bool {
+        self.global_limits.uses_alternate()
+    }
+
+    // Lets `schedule` drive the session's normal limits from now on,
+    // replacing any schedule set before. `None` stops scheduling and leaves
+    // the limits where they are.
+    pub fn set_bandwidth_schedule(&mut self, schedule: Option<BandwidthSchedule>) {
+        let task = schedule.map(|schedule| schedule.spawn(self.global_limits.clone()));
+        if let Some(old) = std::mem::replace(&mut self.schedule_task, task) {
+            old.abort();
+        }
+    }
+
+    pub fn upload_slots(&self) -> UploadSlots {
+        self.shared.unchoke.slots()
+    }
+
+    // How many peers to upload to at once, per torrent and across the
+    // session; torrents can set their own through `TorrentOptions`. Takes
+    // effect as each torrent next rechokes.
+    pub fn set_upload_slots(&self, slots: UploadSlots) {
+        self.shared.unchoke.set_slots(slots);
+    }
+
+    pub fn connection_limits(&self) -> ConnectionLimits {
+        self.shared.slots.limits()
+    }
+
+    // Takes effect for new connections; lowering a limit closes nothing.
+    pub fn set_connection_limits(&self, limits: ConnectionLimits) {
+        self.shared.slots.set_limits(limits);
+    }
+
+    // Alerts from every torrent in the session, from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<Alert> {
+        self.shared.alerts.subscribe()
+    }
+
+    pub fn metrics(&self) -> &Arc<Metrics> {
+        &self.shared.metrics
+    }
+
+    // Serves the session's metrics for Prometheus at `/metrics` on `addr`,
+    // replacing any endpoint started before.
+    pub async fn serve_metrics(&mut self, addr: impl ToSocketAddrs) -> io::Result<SocketAddr> {
+        let listener = TcpListener::bind(addr).await?;
+        let local_addr = listener.local_addr()?;
+        let task = tokio::spawn(metrics::serve(listener, self.shared.metrics.clone()));
+        if let Some(old) = self.metrics_task.replace(task) {
+            old.abort();
+        }
+        Ok(local_addr)
+    }
+
+    pub fn connections(&self) -> usize {
+        self.shared.slots.connections()
+    }
+
+    // How long `shutdown` waits for torrents to save their state before
+    // killing them.
+    pub fn set_shutdown_timeout(&mut self, timeout: Duration) {
+        self.shutdown_timeout = timeout;
+    }
+
+    // Starts a torrent. `None` if one with the same info hash is running.
+    pub fn add_torrent<S: Storage + Send + 'static>(&mut self, torrent: Torrent<S>) -> Option<&mut TorrentHandle> {
+        self.add_torrent_with(torrent, TorrentOptions::default())
+    }
+
+    pub fn add_torrent_with<S: Storage + Send + 'static>(
+        &mut self,
+        torrent: Torrent<S>,
+        options: TorrentOptions
+    ) -> Option<&mut TorrentHandle> {
+        let info_hash = torrent.metainfo().info_hash;
+        if self.torrents.contains_key(&info_hash) {
+            return None;
+        }
+        let handle = TorrentHandle::spawn_with(torrent, self.peer_id, options, self.shared.clone());
+        self.listener.register(&handle);
+        Some(self.torrents.entry(info_hash).or_insert(handle))
+    }
+
+    // Fetches a magnet link's torrent from the first peer to hand over its
+    // metadata: `peers`, such as the DHT found, then the link's own, then
+    // those its trackers give. What comes back is added like any other
+    // torrent. The fetch borrows nothing of the session, so it can run on a
+    // task of its own.
+    pub fn fetch_metadata(&self, magnet: &Magnet, peers: &[SocketAddr]) -> impl Future<Output = io::Result<Metainfo>> + Send + 'static {
+        let (magnet, mut peers) = (magnet.clone(), peers.to_vec());
+        let (peer_id, port, alerts) = (self.peer_id, self.listen_port(), self.shared.alerts.clone());
+        async move {
+            let info_hash = magnet.info_hash;
+            let ours = Handshake::new(info_hash, peer_id).with_extensions();
+            let extensions = ExtendedHandshake::new().with_extension(UT_METADATA, OUR_UT_METADATA);
+            let mut failed = io::Error::new(io::ErrorKind::NotFound, "no peers to fetch the metadata from");
+            peers.extend(&magnet.peers);
+            let (mut next, mut asked_trackers) = (0, false);
+            loop {
+                let Some(&addr) = peers.get(next) else {
+                    if asked_trackers {
+                        return Err(failed);
+                    }
+                    asked_trackers = true;
+                    for peer in tracker_peers(&magnet.trackers, info_hash, peer_id, port).await {
+                        if !peers.contains(&peer) {
+                            peers.push(peer);
+                        }
+                    }
+                    continue;
+                };
+                next += 1;
+                let fetched = async {
+                    let (mut stream, _, theirs) = peer::connect_extended(addr, ours, &extensions).await?;
+                    peer::fetch_metadata(&mut stream, &theirs, OUR_UT_METADATA, info_hash).await
+                };
+                match fetched.await {
+                    Ok(info) => {
+                        // It hashes to the info hash, so no other peer has better.
+                        let metainfo = Metainfo::from_info(&info, &magnet.trackers)
+                            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "the metadata isn't a valid torrent"))?;
+                        let _ = alerts.send(Alert::MetadataReceived { info_hash, addr });
+                        return Ok(metainfo);
+                    },
+                    Err(err) => {
+                        debug!(%addr, error = %err, "cannot fetch metadata");
+                        failed = err;
+                    }
+                }
+            }
+        }
+    }
+
+    // Stops a torrent and forgets it. Its data stays where it is.
+    pub async fn remove_torrent(&mut self, info_hash: &[u8; 20]) -> bool {
+        let Some(handle) = self.detach_torrent(info_hash) else {
+            return false;
+        };
+        handle.shutdown().await;
+        true
+    }
+
+    // Takes a torrent out of the session still running, for the caller to
+    // shut down without holding on to the session meanwhile.
+    pub fn detach_torrent(&mut self, info_hash: &[u8; 20]) -> Option<TorrentHandle> {
+        let handle = self.torrents.remove(info_hash)?;
+        self.listener.unregister(info_hash);
+        self.shared.queue.remove(info_hash);
+        Some(handle)
+    }
+
+    pub fn torrent(&self, info_hash: &[u8; 20]) -> Option<&TorrentHandle> {
+        self.torrents.get(info_hash)
+    }
+
+    pub fn torrent_mut(&mut self, info_hash: &[u8; 20]) -> Option<&mut TorrentHandle> {
+        self.torrents.get_mut(info_hash)
+    }
+
+    pub fn torrents(&self) -> impl Iterator<Item = &TorrentHandle> {
+        self.torrents.values()
+    }
+
+    pub fn queue_limits(&self) -> QueueLimits {
+        self.shared.queue.limits()
+    }
+
+    // Torrents past the limits wait, paused, until others finish or are
+    // removed, then start on their own.
+    pub fn set_queue_limits(&self, limits: QueueLimits) {
+        self.shared.queue.set_limits(limits);
+    }
+
+    // Info hashes in queue order; earlier torrents start first.
+    pub fn queue(&self) -> Vec<[u8; 20]> {
+        self.shared.queue.order()
+    }
+
+    pub fn queue_position(&self, info_hash: &[u8; 20]) -> Option<usize> {
+        self.shared.queue.position(info_hash)
+    }
+
+    pub fn set_queue_position(&self, info_hash: &[u8; 20], position: usize) -> bool {
+        self.shared.queue.set_position(info_hash, position)
+    }
+
+    pub fn seed_goal(&self) -> SeedGoal {
+        *self.shared.seed_goal.lock().unwrap()
+    }
+
+    // When finished torrents stop seeding, unless they were added with a
+    // goal of their own. Applies to torrents already running too.
+    pub fn set_seed_goal(&self, goal: SeedGoal) {
+        *self.shared.seed_goal.lock().unwrap() = goal;
+    }
+
+    pub fn pause(&mut self, info_hash: &[u8; 20]) -> bool {
+        self.torrents
+            .get_mut(info_hash)
+            .map(TorrentHandle::pause)
+            .is_some()
+    }
+
+    pub fn resume(&mut self, info_hash: &[u8; 20]) -> bool {
+        self.torrents
+            .get_mut(info_hash)
+            .map(TorrentHandle::resume)
+            .is_some()
+    }
+
+    // Stops accepting peers, then stops every torrent, which closes its
+    // connections, flushes its storage and saves its resume data. Torrents
+    // still at it when the timeout runs out are killed. Returns false if
+    // any were.
+    pub async fn shutdown(mut self) -> bool {
+        self.listener_task.abort();
+        for task in [self.metrics_task.take(), self.schedule_task.take()].into_iter().flatten() {
+            task.abort();
+        }
+        let deadline = Instant::now() + self.shutdown_timeout;
+        if let Some(mapper) = self.port_mapper.take() {
+            mapper.stop().await;
+        }
+        let mut clean = true;
+        for (_, handle) in self.torrents.drain() {
+            let left = deadline.saturating_duration_since(Instant::now());
+            clean &= handle.shutdown_timeout(left).await;
+        }
+        clean
+    }
+}
+
+// The peers `trackers` give for a torrent we don't have the metadata of,
+// all asked at once.
+#[cfg(any(feature = "tracker-http", feature = "tracker-udp"))]
+async fn tracker_peers(trackers: &[String], info_hash: [u8; 20], peer_id: [u8; 20], port: u16) -> Vec<SocketAddr> {
+    // Its size isn't known yet, but with nothing left to download the
+    // trackers would take us for a seed and keep the seeds from us.
+    let request = Announce { left: 1, ..Announce::new(info_hash, peer_id, port) };
+    let mut announces = JoinSet::new();
+    for url in trackers {
+        let Some(tracker) = Tracker::parse(url) else {
+            continue;
+        };
+        let (url, request) = (url.clone(), request.clone());
+        announces.spawn(async move {
+            match tracker::announce(&tracker, &request).await {
+                Ok(announced) => announced.response.map(|response| response.peers).unwrap_or_default(),
+                Err(err) => {
+                    debug!(tracker = %url, error = %err, "cannot ask the tracker for metadata peers");
+                    Vec::new()
+                }
+            }
+        });
+    }
+    let mut peers = Vec::new();
+    while let Some(found) = announces.join_next().await {
+        peers.extend(found.unwrap_or_default());
+    }
+    peers
+}
+
+#[cfg(not(any(feature = "tracker-http", feature = "tracker-udp")))]
+async fn tracker_peers(_: &[String], _: [u8; 20], _: [u8; 20], _: u16) -> Vec<SocketAddr> {
+    Vec::new()
+}
+
+#[cfg(test)]
+mod test {
+    use crate::engine::session::Session;
+    use crate::engine::torrent::TorrentOptions;
+    use crate::engine::{Alert, QueueLimits, SeedGoal};
+    use crate::ipfilter::IpFilter;
+    use crate::engine::test::{metadata_peer, metainfo, seed};
+    use crate::magnet::Magnet;
+    use crate::storage::memory::MemoryStorage;
+    use crate::storage::resume::ResumeData;
+    use crate::storage::Storage;
+    use crate::torrent::Torrent;
+    use std::time::Duration;
+    use tokio::net::TcpListener;
+    use tokio::time;
+
+    #[tokio::test]
+    async fn test_session() {
+        let data: Vec<u8> = (0..50_000u32).map(|i| (i % 13) as u8).collect();
+        let metainfo = metainfo(&data, 16 * 1024);
+        let info_hash = metainfo.info_hash;
+
+        let mut seeder = Session::bind("127.0.0.1:0").await.unwrap();
+        assert!(seeder.add_torrent(seed(&metainfo, &data)).is_some());
+        assert!(seeder.add_torrent(seed(&metainfo, &data)).is_none());
+
+        let mut leecher = Session::bind("127.0.0.1:0").await.unwrap();
+        let mut alerts = leecher.subscribe();
+        let leech = Torrent::with_storage(metainfo.clone(), MemoryStorage::new(metainfo.layout().unwrap())).unwrap();
+        let resume_path = std::env::temp_dir().join(format!("session-{}.resume", std::process::id()));
+        let handle = leecher.add_torrent_with(leech, TorrentOptions::new().with_resume_path(&resume_path)).unwrap();
+        handle.pause();
+        assert!(leecher.torrent(&info_hash).unwrap().is_paused());
+        // Pausing saves resume data straight away.
+        assert_eq!(alerts.recv().await.unwrap(), Alert::TorrentPaused { info_hash });
+        assert_eq!(ResumeData::load(&resume_path).unwrap().pieces.count_ones(), 0);
+
+        let handle = leecher.torrent_mut(&info_hash).unwrap();
+        handle.add_peer(seeder.listen_addr().unwrap());
+        handle.resume();
+        assert_eq!(alerts.recv().await.unwrap(), Alert::TorrentResumed { info_hash });
+        time::timeout(Duration::from_secs(10), handle.wait_complete()).await.unwrap();
+
+        assert_eq!(leecher.connections(), 1);
+        let mut verified = 0;
+        loop {
+            match alerts.recv().await.unwrap() {
+                Alert::PieceVerified { .. } => verified += 1,
+                Alert::TorrentCompleted { info_hash: hash } => {
+                    assert_eq!(hash, info_hash);
+                    break;
+                },
+                alert => assert_eq!(alert, Alert::PeerConnected { info_hash, addr: seeder.listen_addr().unwrap() })
+            }
+        }
+        assert_eq!(verified, 4);
+        let exported = leecher.metrics().render();
+        assert!(exported.contains("\nbittorrent_downloaded_bytes_total 50000\n"));
+        assert!(exported.contains("\nbittorrent_pieces_verified_total 4\n"));
+
+        let stats = seeder.torrent(&info_hash).unwrap().stats();
+        assert_eq!(stats.bytes_total, 50_000);
+        assert!(stats.is_complete());
+        assert!(leecher.remove_torrent(&info_hash).await);
+        assert!(!leecher.remove_torrent(&info_hash).await);
+        let resume = ResumeData::load(&resume_path).unwrap();
+        assert!(resume.pieces.is_complete());
+        assert_eq!(resume.downloaded, 50_000);
+        std::fs::remove_file(resume_path).unwrap();
+
+        assert_eq!(seeder.torrents().count(), 1);
+        assert!(seeder.shutdown().await);
+        assert!(leecher.shutdown().await);
+    }
+
+    #[tokio::test]
+    async fn test_queue() {
+        let first: Vec<u8> = (0..40_000u32).map(|i| (i % 7) as u8).collect();
+        let second: Vec<u8> = (0..40_000u32).map(|i| (i % 11) as u8).collect();
+        let (first, second) = ((metainfo(&first, 16 * 1024), first), (metainfo(&second, 16 * 1024), second));
+
+        let mut seeder = Session::bind("127.0.0.1:0").await.unwrap();
+        let mut leecher = Session::bind("127.0.0.1:0").await.unwrap();
+        leecher.set_queue_limits(QueueLimits { max_downloads: Some(1), max_seeds: None });
+        for (metainfo, data) in [&first, &second] {
+            seeder.add_torrent(seed(metainfo, data));
+            let leech = Torrent::with_storage(metainfo.clone(), MemoryStorage::new(metainfo.layout().unwrap())).unwrap();
+            leecher.add_torrent(leech).unwrap().add_peer(seeder.listen_addr().unwrap());
+        }
+        let (first, second) = (first.0.info_hash, second.0.info_hash);
+        assert_eq!(leecher.queue(), [first, second]);
+        assert!(leecher.set_queue_position(&second, 0));
+        assert_eq!(leecher.queue_position(&first), Some(1));
+
+        // The second torrent goes first; the other starts once it is done.
+        for info_hash in [second, first] {
+            let handle = leecher.torrent(&info_hash).unwrap();
+            time::timeout(Duration::from_secs(10), handle.wait_complete()).await.unwrap();
+        }
+        seeder.shutdown().await;
+        leecher.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_seed_goal() {
+        let data: Vec<u8> = (0..40_000u32).map(|i| (i % 5) as u8).collect();
+        let metainfo = metainfo(&data, 16 * 1024);
+        let info_hash = metainfo.info_hash;
+
+        let mut seeder = Session::bind("127.0.0.1:0").await.unwrap();
+        seeder.set_seed_goal(SeedGoal::new().with_ratio(1.0));
+        let mut alerts = seeder.subscribe();
+        seeder.add_torrent(seed(&metainfo, &data));
+
+        // The leecher doesn't stay to seed.
+        let mut leecher = Session::bind("127.0.0.1:0").await.unwrap();
+        leecher.set_seed_goal(SeedGoal::no_seeding());
+        let leech = Torrent::with_storage(metainfo.clone(), MemoryStorage::new(metainfo.layout().unwrap())).unwrap();
+        let handle = leecher.add_torrent(leech).unwrap();
+        handle.add_peer(seeder.listen_addr().unwrap());
+        time::timeout(Duration::from_secs(10), handle.wait_complete()).await.unwrap();
+        time::timeout(Duration::from_secs(3), async {
+            while !handle.is_paused() {
+                time::sleep(Duration::from_millis(50)).await;
+            }
+        }).await.unwrap();
+
+        // Having uploaded the whole torrent once, the seeder stops.
+        let reached = time::timeout(Duration::from_secs(5), async {
+            loop {
+                if let Alert::SeedingGoalReached { info_hash: hash } = alerts.recv().await.unwrap() {
+                    return hash;
+                }
+            }
+        });
+        assert_eq!(reached.await.unwrap(), info_hash);
+        loop {
+            match alerts.recv().await.unwrap() {
+                Alert::PeerDisconnected { .. } => {},
+                alert => break assert_eq!(alert, Alert::TorrentPaused { info_hash })
+            }
+        }
+        assert!(seeder.torrent(&info_hash).unwrap().is_paused());
+
+        seeder.shutdown().await;
+        leecher.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_ip_filter() {
+        let data: Vec<u8> = (0..20_000u32).map(|i| (i % 3) as u8).collect();
+        let metainfo = metainfo(&data, 16 * 1024);
+        let mut seeder = Session::bind("127.0.0.1:0").await.unwrap();
+        seeder.add_torrent(seed(&metainfo, &data));
+
+        let mut leecher = Session::bind("127.0.0.1:0").await.unwrap();
+        let mut filter = IpFilter::new();
+        filter.add_list("Loopback:127.0.0.0-127.255.255.255", "test");
+        leecher.set_ip_filter(filter);
+        let reason = leecher.blocked_by(seeder.listen_addr().unwrap().ip()).unwrap();
+        assert_eq!(reason.description.as_deref(), Some("Loopback"));
+
+        let leech = Torrent::with_storage(metainfo.clone(), MemoryStorage::new(metainfo.layout().unwrap())).unwrap();
+        let handle = leecher.add_torrent(leech).unwrap();
+        handle.add_peer(seeder.listen_addr().unwrap());
+        time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(leecher.connections(), 0);
+
+        leecher.set_ip_filter(IpFilter::new());
+        let handle = leecher.torrent(&metainfo.info_hash).unwrap();
+        handle.add_peer(seeder.listen_addr().unwrap());
+        time::timeout(Duration::from_secs(10), handle.wait_complete()).await.unwrap();
+
+        // The peer listing catches up on the next tick.
+        time::timeout(Duration::from_secs(3), async {
+            while handle.peers().is_empty() {
+                time::sleep(Duration::from_millis(50)).await;
+            }
+        }).await.unwrap();
+        let peers = handle.peers();
+        assert_eq!(peers[0].addr, seeder.listen_addr().unwrap());
+        assert_eq!((peers[0].pieces, peers[0].country.as_deref()), (2, None));
+        seeder.shutdown().await;
+        leecher.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_ban_corrupt_peer() {
+        let data: Vec<u8> = (0..20_000u32).map(|i| (i % 7) as u8).collect();
+        let metainfo = metainfo(&data, 16 * 1024);
+        let mut seeder = Session::bind("127.0.0.1:0").await.unwrap();
+        let mut seed = seed(&metainfo, &data);
+        seed.storage_mut().write(0, 0, b"corrupt").unwrap();
+        seeder.add_torrent(seed);
+
+        let mut leecher = Session::bind("127.0.0.1:0").await.unwrap();
+        let leech = Torrent::with_storage(metainfo.clone(), MemoryStorage::new(metainfo.layout().unwrap())).unwrap();
+        leecher.add_torrent(leech).unwrap().add_peer(seeder.listen_addr().unwrap());
+
+        // Piece 0 fails from the only peer until it has sent more corrupt
+        // data than the threshold allows.
+        let ip = seeder.listen_addr().unwrap().ip();
+        time::timeout(Duration::from_secs(10), async {
+            while leecher.banned() != vec![ip] || leecher.connections() > 0 {
+                time::sleep(Duration::from_millis(50)).await;
+            }
+        }).await.unwrap();
+        leecher.torrent(&metainfo.info_hash).unwrap().add_peer(seeder.listen_addr().unwrap());
+        time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(leecher.connections(), 0);
+
+        assert!(leecher.unban(ip));
+        assert!(leecher.banned().is_empty());
+        seeder.shutdown().await;
+        leecher.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_fetch_metadata() {
+        let metainfo = metainfo(&[7; 1000], 16 * 1024);
+        let addr = metadata_peer(&metainfo).await;
+        // Nothing listens on the first peer.
+        let closed = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+
+        let session = Session::bind("127.0.0.1:0").await.unwrap();
+        let mut alerts = session.subscribe();
+        let magnet = Magnet { info_hash: metainfo.info_hash, name: None, trackers: Vec::new(), peers: vec![addr] };
+        let fetched = session.fetch_metadata(&magnet, &[closed]).await.unwrap();
+        assert_eq!((fetched.info_hash, fetched.files), (metainfo.info_hash, metainfo.files));
+        assert_eq!(alerts.recv().await.unwrap(), Alert::MetadataReceived { info_hash: metainfo.info_hash, addr });
+        let unreachable = Magnet { peers: Vec::new(), ..magnet };
+        assert!(session.fetch_metadata(&unreachable, &[closed]).await.is_err());
+        session.shutdown().await;
+    }
+
+    #[cfg(feature = "tracker-http")]
+    #[tokio::test]
+    async fn test_fetch_metadata_from_tracker() {
+        use crate::bencode::Value;
+        use crate::compact::encode_compact;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let metainfo = metainfo(&[5; 1000], 16 * 1024);
+        let peer = metadata_peer(&metainfo).await;
+        let tracker = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/announce", tracker.local_addr().unwrap());
+        let announced = tokio::spawn(async move {
+            let (mut stream, _) = tracker.accept().await.unwrap();
+            let mut request = vec![0; 4096];
+            let len = stream.read(&mut request).await.unwrap();
+            let body = Value::dict([("interval", 1800.into()), ("peers", Value::from(&encode_compact(peer)[..]))]).encode();
+            stream.write_all(b"HTTP/1.0 200 OK\r\n\r\n").await.unwrap();
+            stream.write_all(&body).await.unwrap();
+            String::from_utf8_lossy(&request[..len]).into_owned()
+        });
+
+        // The link names no peers, so its tracker is asked for some.
+        let session = Session::bind("127.0.0.1:0").await.unwrap();
+        let magnet = Magnet { info_hash: metainfo.info_hash, name: None, trackers: vec![url], peers: Vec::new() };
+        let fetched = session.fetch_metadata(&magnet, &[]).await.unwrap();
+        assert_eq!(fetched.info_hash, metainfo.info_hash);
+        assert!(announced.await.unwrap().contains("left=1&"));
+        session.shutdown().await;
+    }
+}
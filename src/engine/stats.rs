@@ -0,0 +1,142 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+
+// Weight of the newest sample in the moving average. Low enough that the
+// rate and ETA don't jump around with every burst of blocks.
+const SMOOTHING: f64 = 0.2;
+
+// Bytes per second as an exponential moving average over ticks.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RateMeter {
+    pending: u64,
+    total: u64,
+    rate: f64
+}
+
+impl RateMeter {
+    pub fn record(&mut self, bytes: u64) {
+        self.pending += bytes;
+        self.total += bytes;
+    }
+
+    // Folds what was recorded over the last `elapsed` into the rate.
+    pub fn tick(&mut self, elapsed: Duration) {
+        if elapsed.is_zero() {
+            return;
+        }
+        let sample = self.pending as f64 / elapsed.as_secs_f64();
+        self.rate = SMOOTHING * sample + (1.0 - SMOOTHING) * self.rate;
+        self.pending = 0;
+    }
+
+    pub fn rate(&self) -> u64 {
+        self.rate.round() as u64
+    }
+
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    pub fn reset_rate(&mut self) {
+        self.pending = 0;
+        self.rate = 0.0;
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TorrentStats {
+    // Bytes of verified pieces.
+    pub bytes_done: u64,
+    pub bytes_total: u64,
+    // Payload bytes sent and received since the torrent started.
+    pub uploaded: u64,
+    pub downloaded: u64,
+    pub upload_rate: u64,
+    pub download_rate: u64,
+    pub connected_peers: usize,
+    // Connected peers plus those waiting to be dialed.
+    pub known_peers: usize,
+    // Waiting for its turn in the session's queue.
+    pub queued: bool,
+    // Blocks received and waiting to be written, and their bytes. While
+    // there are too many, no more are requested.
+    pub backlog_blocks: usize,
+    pub backlog_bytes: u64
+}
+
+impl TorrentStats {
+    pub fn percent(&self) -> f64 {
+        match self.bytes_total {
+            0 => 100.0,
+            total => self.bytes_done as f64 * 100.0 / total as f64
+        }
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.bytes_done == self.bytes_total
+    }
+
+    // Time left at the current download rate; `None` while nothing is
+    // coming in.
+    pub fn eta(&self) -> Option<Duration> {
+        if self.is_complete() {
+            return Some(Duration::ZERO);
+        }
+        if self.download_rate == 0 {
+            return None;
+        }
+        Some(Duration::from_secs((self.bytes_total - self.bytes_done).div_ceil(self.download_rate)))
+    }
+}
+
+// A connected peer as of the last tick.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerInfo {
+    pub addr: SocketAddr,
+    // Name and version from the peer id, when it is a known client.
+    pub client: Option<String>,
+    pub pieces: usize,
+    pub am_choking: bool,
+    pub am_interested: bool,
+    pub peer_choking: bool,
+    pub peer_interested: bool,
+    // Payload bytes per second to and from this peer.
+    pub upload_rate: u64,
+    pub download_rate: u64,
+    // ISO 3166 country code, when the session has a GeoIP database.
+    pub country: Option<String>
+}
+
+#[cfg(test)]
+mod test {
+    use crate::engine::stats::{RateMeter, TorrentStats};
+    use std::time::Duration;
+
+    #[test]
+    fn test_rate_meter() {
+        let mut meter = RateMeter::default();
+        for _ in 0..50 {
+            meter.record(1000);
+            meter.tick(Duration::from_secs(1));
+        }
+        assert_eq!(meter.rate(), 1000);
+        assert_eq!(meter.total(), 50_000);
+
+        // One idle second only pulls the rate down part of the way.
+        meter.tick(Duration::from_secs(1));
+        assert_eq!(meter.rate(), 800);
+        meter.reset_rate();
+        assert_eq!(meter.rate(), 0);
+    }
+
+    #[test]
+    fn test_eta() {
+        let mut stats = TorrentStats { bytes_done: 250, bytes_total: 1000, ..Default::default() };
+        assert_eq!(stats.percent(), 25.0);
+        assert_eq!(stats.eta(), None);
+        stats.download_rate = 100;
+        assert_eq!(stats.eta(), Some(Duration::from_secs(8)));
+        stats.bytes_done = 1000;
+        assert_eq!(stats.eta(), Some(Duration::ZERO));
+    }
+}
@@ -0,0 +1,329 @@
+use crate::bitfield::Bitfield;
+use crate::engine::torrent::Event;
+use crate::handshake::HANDSHAKE_TIMEOUT;
+use crate::storage::layout::Layout;
+use std::fmt::Write as _;
+use std::io;
+use std::ops::Range;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::{oneshot, watch};
+use tokio::time;
+use tracing::debug;
+
+const MAX_REQUEST_LEN: usize = 8 * 1024;
+// Pieces asked for ahead of the one being read, so playback doesn't stall
+// at every piece boundary.
+const READ_AHEAD: u32 = 8;
+// The deadline for the piece a player is waiting on; each piece after it
+// gets this much longer.
+const STREAM_DEADLINE: Duration = Duration::from_secs(2);
+
+// Reads a torrent's verified content by offset while it downloads. Stops
+// working, with errors, once the torrent does.
+#[derive(Debug, Clone)]
+pub struct ContentReader {
+    events: UnboundedSender<Event>,
+    have: watch::Receiver<Bitfield>,
+    layout: Arc<Layout>
+}
+
+impl ContentReader {
+    pub(crate) fn new(events: UnboundedSender<Event>, have: watch::Receiver<Bitfield>, layout: Arc<Layout>) -> Self {
+        Self { events, have, layout }
+    }
+
+    pub fn layout(&self) -> &Layout {
+        &self.layout
+    }
+
+    pub fn has_piece(&self, piece: u32) -> bool {
+        self.have.borrow().get(piece as usize)
+    }
+
+    // Fails with `WouldBlock` while any of the range is missing.
+    pub async fn read_range(&self, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+        let (reply, result) = oneshot::channel();
+        let _ = self.events.send(Event::Read { offset, len, reply });
+        result.await.unwrap_or_else(|_| Err(stopped()))
+    }
+
+    // Waits until `piece` is verified. False if the torrent stopped first.
+    pub async fn wait_piece(&self, piece: u32) -> bool {
+        let mut have = self.have.clone();
+        let verified = have.wait_for(|have| have.get(piece as usize)).await.is_ok();
+        verified
+    }
+
+    pub fn set_piece_deadline(&self, piece: u32, within: Duration) {
+        let _ = self.events.send(Event::SetDeadline(piece, Instant::now() + within));
+    }
+
+    // Reads `len` bytes at `offset`, waiting for pieces that are missing and
+    // putting deadlines on them and the few after, up to `until`.
+    pub async fn read_streaming(&self, offset: u64, len: usize, until: u64) -> io::Result<Vec<u8>> {
+        let piece_length = self.layout.geometry().piece_length() as u64;
+        let piece = (offset / piece_length) as u32;
+        let last = (until.saturating_sub(1) / piece_length) as u32;
+        for ahead in 0..READ_AHEAD.min(last.saturating_sub(piece) + 1) {
+            if !self.has_piece(piece + ahead) {
+                self.set_piece_deadline(piece + ahead, STREAM_DEADLINE * (ahead + 1));
+            }
+        }
+        let end_piece = ((offset + len as u64).saturating_sub(1) / piece_length) as u32;
+        for piece in piece..=end_piece {
+            if !self.wait_piece(piece).await {
+                return Err(stopped());
+            }
+        }
+        self.read_range(offset, len).await
+    }
+}
+
+fn stopped() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "torrent stopped")
+}
+
+// A `Range` header within a file of `length`: `bytes=0-499`, `bytes=500-`
+// or the last 500 bytes as `bytes=-500`. Only single ranges are served;
+// `None` for anything else or a range outside the file.
+fn parse_range(value: &str, length: u64) -> Option<Range<u64>> {
+    let (start, end) = value.trim().strip_prefix("bytes=")?.split_once('-')?;
+    let (start, end) = (start.trim(), end.trim());
+    let range = match (start.is_empty(), end.is_empty()) {
+        (true, false) => length.saturating_sub(end.parse().ok()?)..length,
+        (false, true) => start.parse().ok()?..length,
+        (false, false) => start.parse().ok()?..end.parse::<u64>().ok()?.saturating_add(1).min(length),
+        (true, true) => return None
+    };
+    (range.start < range.end).then_some(range)
+}
+
+fn header<'a>(request: &'a str, name: &str) -> Option<&'a str> {
+    request.lines().skip(1).find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        key.trim().eq_ignore_ascii_case(name).then(|| value.trim())
+    })
+}
+
+fn content_type(path: &str) -> &'static str {
+    let extension = path.rsplit_once('.').map_or("", |(_, extension)| extension).to_ascii_lowercase();
+    match extension.as_str() {
+        "mp4" | "m4v" => "video/mp4",
+        "mkv" => "video/x-matroska",
+        "webm" => "video/webm",
+        "avi" => "video/x-msvideo",
+        "mov" => "video/quicktime",
+        "ts" => "video/mp2t",
+        "mp3" => "audio/mpeg",
+        "flac" => "audio/flac",
+        "ogg" => "audio/ogg",
+        "m4a" => "audio/mp4",
+        "srt" => "application/x-subrip",
+        "txt" | "nfo" => "text/plain",
+        _ => "application/octet-stream"
+    }
+}
+
+fn encode_path(path: &str) -> String {
+    let mut encoded = String::new();
+    for byte in path.bytes() {
+        match byte {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => encoded.push(byte as char),
+            _ => {
+                let _ = write!(encoded, "%{:02X}", byte);
+            }
+        }
+    }
+    encoded
+}
+
+// Serves the torrent's files over HTTP: `/` lists them and `/<index>/<name>`
+// serves one, with range requests so a player can seek. Reads wait for the
+// pieces they need, which jump the download queue through deadlines.
+pub async fn serve(listener: TcpListener, reader: ContentReader) {
+    loop {
+        let Ok((stream, addr)) = listener.accept().await else {
+            continue;
+        };
+        let reader = reader.clone();
+        tokio::spawn(async move {
+            if let Err(err) = respond(stream, &reader).await {
+                debug!(%addr, error = %err, "stream request failed");
+            }
+        });
+    }
+}
+
+async fn read_request(stream: &mut TcpStream) -> io::Result<String> {
+    let mut request = Vec::new();
+    let mut buf = [0; 1024];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 || request.len() + n > MAX_REQUEST_LEN {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "bad request"));
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+    Ok(String::from_utf8_lossy(&request).into_owned())
+}
+
+async fn respond(mut stream: TcpStream, reader: &ContentReader) -> io::Result<()> {
+    let request = time::timeout(HANDSHAKE_TIMEOUT, read_request(&mut stream))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "request timed out"))??;
+    let mut words = request.split_whitespace();
+    let (method, target) = (words.next().unwrap_or(""), words.next().unwrap_or(""));
+    let head = match method {
+        "GET" => false,
+        "HEAD" => true,
+        _ => return reply(&mut stream, "405 Method Not Allowed", "").await
+    };
+
+    let layout = reader.layout();
+    let path = target.split('?').next().unwrap_or("");
+    if path == "/" {
+        let mut body = String::new();
+        for (index, file) in layout.files().iter().enumerate() {
+            let _ = writeln!(body, "/{}/{}", index, encode_path(&file.path.to_string_lossy()));
+        }
+        return match head {
+            true => reply(&mut stream, "200 OK", "").await,
+            false => reply(&mut stream, "200 OK", &body).await
+        };
+    }
+    let file = path.trim_start_matches('/').split('/').next().and_then(|index| index.parse::<usize>().ok());
+    let Some(file) = file.filter(|&file| file < layout.files().len()) else {
+        return reply(&mut stream, "404 Not Found", "").await;
+    };
+
+    let length = layout.files()[file].length;
+    let (status, range) = match header(&request, "range") {
+        Some(value) => match parse_range(value, length) {
+            Some(range) => ("206 Partial Content", range),
+            None => {
+                let response = format!(
+                    "HTTP/1.1 416 Range Not Satisfiable\r\nContent-Range: bytes */{}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                    length
+                );
+                stream.write_all(response.as_bytes()).await?;
+                return stream.shutdown().await;
+            }
+        },
+        None => ("200 OK", 0..length)
+    };
+    let mut response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nAccept-Ranges: bytes\r\n",
+        status,
+        content_type(&layout.files()[file].path.to_string_lossy()),
+        range.end - range.start
+    );
+    if status.starts_with("206") {
+        let _ = write!(response, "Content-Range: bytes {}-{}/{}\r\n", range.start, range.end - 1, length);
+    }
+    response.push_str("Connection: close\r\n\r\n");
+    stream.write_all(response.as_bytes()).await?;
+    if head {
+        return stream.shutdown().await;
+    }
+
+    // One piece at a time, so the player gets data as soon as it verifies.
+    let piece_length = layout.geometry().piece_length() as u64;
+    let start = layout.file_offset(file);
+    let (mut at, end) = (start + range.start, start + range.end);
+    while at < end {
+        let len = (end - at).min(piece_length - at % piece_length) as usize;
+        let data = reader.read_streaming(at, len, end).await?;
+        stream.write_all(&data).await?;
+        at += len as u64;
+    }
+    stream.shutdown().await
+}
+
+async fn reply(stream: &mut TcpStream, status: &str, body: &str) -> io::Result<()> {
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+#[cfg(test)]
+mod test {
+    use crate::engine::stream::{parse_range, serve};
+    use crate::engine::test::{metainfo, seed};
+    use crate::engine::{PeerListener, TorrentHandle};
+    use crate::storage::memory::MemoryStorage;
+    use crate::torrent::Torrent;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::time;
+
+    async fn get(addr: std::net::SocketAddr, path: &str, range: &str) -> Vec<u8> {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let request = format!("GET {} HTTP/1.1\r\nHost: x\r\n{}\r\n", path, range);
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await.unwrap();
+        response
+    }
+
+    fn split(response: &[u8]) -> (String, &[u8]) {
+        let at = response.windows(4).position(|window| window == b"\r\n\r\n").unwrap();
+        (String::from_utf8_lossy(&response[..at]).into_owned(), &response[at + 4..])
+    }
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(parse_range("bytes=0-499", 1000), Some(0..500));
+        assert_eq!(parse_range("bytes=500-", 1000), Some(500..1000));
+        assert_eq!(parse_range("bytes=-300", 1000), Some(700..1000));
+        assert_eq!(parse_range("bytes=900-5000", 1000), Some(900..1000));
+        assert_eq!(parse_range("bytes=-5000", 1000), Some(0..1000));
+        assert_eq!(parse_range("bytes=1000-", 1000), None);
+        assert_eq!(parse_range("bytes=5-2", 1000), None);
+        assert_eq!(parse_range("bytes=0-1,5-6", 1000), None);
+        assert_eq!(parse_range("items=0-1", 1000), None);
+    }
+
+    #[tokio::test]
+    async fn test_stream() {
+        let data: Vec<u8> = (0..100_000u32).map(|i| (i % 239) as u8).collect();
+        let metainfo = metainfo(&data, 16 * 1024);
+
+        let leech = Torrent::with_storage(metainfo.clone(), MemoryStorage::new(metainfo.layout().unwrap())).unwrap();
+        let leecher = TorrentHandle::spawn(leech, [2; 20]);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, leecher.reader()));
+
+        let listing = get(addr, "/", "").await;
+        assert!(split(&listing).1.starts_with(b"/0/file\n"));
+        assert!(String::from_utf8_lossy(&get(addr, "/7", "").await).starts_with("HTTP/1.1 404"));
+        assert!(String::from_utf8_lossy(&get(addr, "/0", "Range: bytes=200000-\r\n").await).starts_with("HTTP/1.1 416"));
+
+        // Nothing is downloaded yet, so this waits for a seeder to show up.
+        let request = tokio::spawn(async move { get(addr, "/0/file", "Range: bytes=50000-50099\r\n").await });
+        let seeder_listener = PeerListener::bind("127.0.0.1:0").await.unwrap();
+        let seeder = TorrentHandle::spawn(seed(&metainfo, &data), [1; 20]);
+        seeder_listener.register(&seeder);
+        seeder_listener.spawn();
+        leecher.add_peer(seeder_listener.local_addr().unwrap());
+
+        let response = time::timeout(Duration::from_secs(10), request).await.unwrap().unwrap();
+        let (head, body) = split(&response);
+        assert!(head.starts_with("HTTP/1.1 206"));
+        assert!(head.contains("Content-Range: bytes 50000-50099/100000"));
+        assert_eq!(body, &data[50000..50100]);
+
+        leecher.shutdown().await;
+        seeder.shutdown().await;
+    }
+}
@@ -0,0 +1,1431 @@
+use crate::ban::BanList;
+use crate::bitfield::Bitfield;
+use crate::block::{BlockRequest, LatePiecePolicy, PendingRequests, Received};
+use crate::choker::{ChokeCandidate, Choker};
+use crate::dial::{DialConfig, DialQueue, PeerSource};
+use crate::engine::alert::{self, Alert};
+use crate::engine::backlog::Backlog;
+use crate::engine::buffers::BufferPool;
+use crate::engine::connections::{least_useful, ConnectionSlots, Slot};
+use crate::engine::disk::{Disk, DiskJob, DiskThread};
+use crate::engine::metrics::Metrics;
+use crate::engine::peer::{self, Connection};
+use crate::engine::queue::TorrentQueue;
+use crate::engine::rate::RateLimits;
+use crate::engine::seeding::SeedGoal;
+use crate::engine::stream::ContentReader;
+use crate::engine::stats::{PeerInfo, RateMeter, TorrentStats};
+#[cfg(any(feature = "tracker-http", feature = "tracker-udp"))]
+use crate::engine::trackers::{self, Trackers};
+use crate::engine::unchoke::UnchokeSlots;
+use crate::geoip::GeoIp;
+use crate::handshake::Handshake;
+use crate::hash::hex;
+use crate::ipfilter::IpFilter;
+use crate::merkle::{BlockVerifier, Hash, HashRequest};
+use crate::message::Message;
+use crate::picker::{BlockScheduler, PiecePicker};
+use crate::storage::cache::DEFAULT_CACHE_SIZE;
+use crate::storage::durability::SyncPolicy;
+use crate::storage::layout::Layout;
+use crate::storage::selection::{FileSelection, Priority};
+use crate::storage::space::{missing_bytes, SpaceEvent};
+use crate::storage::Storage;
+use crate::superseed::{SuperSeeder, DEFAULT_PIECES_PER_PEER};
+use crate::swarm::Swarm;
+use crate::torrent::Torrent;
+#[cfg(any(feature = "tracker-http", feature = "tracker-udp"))]
+use crate::tracker::Announce;
+use bytes::Bytes;
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU16, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::sync::{broadcast, oneshot, watch};
+use tokio::task::JoinHandle;
+use tokio::time;
+use tracing::{debug, info, warn, Instrument};
+
+// Requests kept in flight per peer.
+pub const PIPELINE_LEN: usize = 16;
+pub const TICK_INTERVAL: Duration = Duration::from_secs(1);
+pub const RECHOKE_INTERVAL: Duration = Duration::from_secs(10);
+pub const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(120);
+// How long a torrent gets to flush and save its state before it is killed.
+pub const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Everything a torrent's coordinator reacts to, from its handle, its dial
+// tasks, the listener and its peers' connections.
+#[derive(Debug)]
+pub enum Event {
+    AddPeer(SocketAddr),
+    TrackerPeers(Vec<SocketAddr>),
+    Connected { addr: SocketAddr, stream: TcpStream, handshake: Handshake },
+    DialFailed(SocketAddr),
+    Message(SocketAddr, Message),
+    Closed(SocketAddr),
+    Pause,
+    Resume,
+    SetDeadline(u32, Instant),
+    ClearDeadlines,
+    Read { offset: u64, len: usize, reply: oneshot::Sender<io::Result<Vec<u8>>> },
+    SetPicker(Box<dyn PiecePicker>),
+    SetPriorities(Vec<Priority>),
+    SetSuperSeeding(bool),
+    // Answers from the disk thread.
+    Written { addr: SocketAddr, request: BlockRequest, written: bool },
+    Hashed { piece: u32, valid: bool },
+    Served { addr: SocketAddr, request: BlockRequest, data: Bytes },
+    Space(SpaceEvent),
+    Shutdown
+}
+
+struct PeerConnection {
+    connection: Connection,
+    pending: PendingRequests,
+    // Blocks being read for the peer; one it cancels meanwhile isn't sent.
+    serving: HashSet<BlockRequest>,
+    country: Option<String>,
+    uploaded: RateMeter,
+    downloaded: RateMeter,
+    // Whether it takes v2 hash requests.
+    v2: bool,
+    _slot: Slot
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct TorrentOptions {
+    resume_path: Option<PathBuf>,
+    seed_goal: Option<SeedGoal>,
+    cache_size: Option<usize>,
+    sync_policy: SyncPolicy,
+    destination: Option<PathBuf>,
+    super_seeding: bool,
+    upload_slots: Option<usize>,
+    limits: Option<(u64, u64)>,
+    late_piece_policy: LatePiecePolicy
+}
+
+impl TorrentOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Where to write resume data when the torrent stops.
+    pub fn with_resume_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.resume_path = Some(path.into());
+        self
+    }
+
+    pub fn resume_path(&self) -> Option<&PathBuf> {
+        self.resume_path.as_ref()
+    }
+
+    // When to stop seeding, instead of the session's goal.
+    pub fn with_seed_goal(mut self, goal: SeedGoal) -> Self {
+        self.seed_goal = Some(goal);
+        self
+    }
+
+    pub fn seed_goal(&self) -> Option<SeedGoal> {
+        self.seed_goal
+    }
+
+    // How many bytes of blocks are held in memory to be written together.
+    pub fn with_cache_size(mut self, bytes: usize) -> Self {
+        self.cache_size = Some(bytes);
+        self
+    }
+
+    pub fn cache_size(&self) -> usize {
+        self.cache_size.unwrap_or(DEFAULT_CACHE_SIZE)
+    }
+
+    // When written data and resume data are forced out to the disk.
+    pub fn with_sync_policy(mut self, policy: SyncPolicy) -> Self {
+        self.sync_policy = policy;
+        self
+    }
+
+    pub fn sync_policy(&self) -> SyncPolicy {
+        self.sync_policy
+    }
+
+    // Where the files go once the torrent completes, to be seeded from
+    // there, when it's downloaded somewhere else first.
+    pub fn with_destination(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.destination = Some(dir.into());
+        self
+    }
+
+    pub fn destination(&self) -> Option<&PathBuf> {
+        self.destination.as_ref()
+    }
+
+    // Once complete, shows each peer a piece at a time instead of the whole
+    // bitfield, as an initial seed with few peers to spread it (BEP 16).
+    pub fn with_super_seeding(mut self, super_seeding: bool) -> Self {
+        self.super_seeding = super_seeding;
+        self
+    }
+
+    pub fn super_seeding(&self) -> bool {
+        self.super_seeding
+    }
+
+    // How many peers to upload to at once, instead of the session's
+    // per-torrent cap. The session's global cap still applies.
+    pub fn with_upload_slots(mut self, slots: usize) -> Self {
+        self.upload_slots = Some(slots);
+        self
+    }
+
+    pub fn upload_slots(&self) -> Option<usize> {
+        self.upload_slots
+    }
+
+    // The torrent's own upload and download limits in bytes per second,
+    // zero for unlimited, to start with; its handle can change them later.
+    // The session's limits still apply.
+    pub fn with_limits(mut self, upload: u64, download: u64) -> Self {
+        self.limits = Some((upload, download));
+        self
+    }
+
+    pub fn limits(&self) -> Option<(u64, u64)> {
+        self.limits
+    }
+
+    // What to do with a block that turns up after we cancelled its request,
+    // when another peer beat it to us or we got choked.
+    pub fn with_late_piece_policy(mut self, policy: LatePiecePolicy) -> Self {
+        self.late_piece_policy = policy;
+        self
+    }
+
+    pub fn late_piece_policy(&self) -> LatePiecePolicy {
+        self.late_piece_policy
+    }
+}
+
+// What a torrent shares with the other torrents of its session.
+#[derive(Debug, Clone)]
+pub struct Shared {
+    pub limits: RateLimits,
+    pub slots: ConnectionSlots,
+    pub unchoke: UnchokeSlots,
+    pub alerts: broadcast::Sender<Alert>,
+    pub metrics: Arc<Metrics>,
+    pub queue: TorrentQueue,
+    // For torrents without a goal of their own.
+    pub seed_goal: Arc<Mutex<SeedGoal>>,
+    // The port peers reach us on, zero for none, to tell trackers.
+    pub port: u16,
+    // Our DHT node's port, zero for none, to tell peers about.
+    pub dht_port: Arc<AtomicU16>,
+    pub ip_filter: Arc<Mutex<IpFilter>>,
+    // Peers whose blocks failed too many hash checks.
+    pub bans: Arc<Mutex<BanList>>,
+    pub geoip: Arc<Mutex<Option<GeoIp>>>,
+    pub buffers: BufferPool
+}
+
+impl Default for Shared {
+    fn default() -> Self {
+        Self {
+            limits: RateLimits::default(),
+            slots: ConnectionSlots::default(),
+            unchoke: UnchokeSlots::default(),
+            alerts: alert::channel(),
+            metrics: Arc::new(Metrics::new()),
+            queue: TorrentQueue::default(),
+            seed_goal: Arc::default(),
+            port: 0,
+            dht_port: Arc::default(),
+            ip_filter: Arc::default(),
+            bans: Arc::default(),
+            geoip: Arc::default(),
+            buffers: BufferPool::default()
+        }
+    }
+}
+
+// Owns one torrent's state and runs on its own task. Peers run on tasks of
+// their own and talk to it only through events, so no state is shared. The
+// content is left to the torrent's disk thread, which answers the same way;
+// what we have is the scheduler's bitfield.
+struct Coordinator {
+    disk: Disk,
+    handshake: Handshake,
+    scheduler: BlockScheduler,
+    swarm: Swarm,
+    connections: HashMap<SocketAddr, PeerConnection>,
+    dial: DialQueue,
+    choker: Choker,
+    events: UnboundedSender<Event>,
+    have: watch::Sender<Bitfield>,
+    stats: watch::Sender<TorrentStats>,
+    peers: watch::Sender<Vec<PeerInfo>>,
+    uploaded: RateMeter,
+    downloaded: RateMeter,
+    // The session's limits first, then the torrent's own.
+    limits: Vec<RateLimits>,
+    options: TorrentOptions,
+    slots: ConnectionSlots,
+    unchoke: UnchokeSlots,
+    alerts: broadcast::Sender<Alert>,
+    metrics: Arc<Metrics>,
+    queue: TorrentQueue,
+    // A paused torrent, or one waiting for its turn in the queue, drops its
+    // peers and neither dials nor accepts any.
+    paused: bool,
+    queued: bool,
+    // Shared with the handle, which may also be paused from in here.
+    paused_flag: Arc<AtomicBool>,
+    seed_goal: Arc<Mutex<SeedGoal>>,
+    dht_port: Arc<AtomicU16>,
+    ip_filter: Arc<Mutex<IpFilter>>,
+    bans: Arc<Mutex<BanList>>,
+    geoip: Arc<Mutex<Option<GeoIp>>>,
+    // Where blocks are read into, and go back to once written.
+    buffers: BufferPool,
+    // A hybrid torrent's hash trees, which the disk thread checks each block
+    // against as it comes in, and the hashes asked of peers for them, by who
+    // was asked.
+    merkle: Option<Arc<Mutex<BlockVerifier>>>,
+    hash_requests: HashMap<HashRequest, SocketAddr>,
+    // Blocks on their way in from the peers, and whether any peer went
+    // without requests because there were too many.
+    backlog: Backlog,
+    backlogged: bool,
+    // Time spent seeding while running, and whether that or the upload
+    // ratio has met the goal. A torrent resumed after that seeds on.
+    seeding_time: Duration,
+    goal_reached: bool,
+    // Paused for want of disk space, to resume once there is some.
+    low_space: bool,
+    // Whether to super seed, and the seeder doing so while we are complete.
+    super_seeding: bool,
+    super_seeder: Option<SuperSeeder>,
+    // Announces us while we run, if the torrent has trackers.
+    #[cfg(any(feature = "tracker-http", feature = "tracker-udp"))]
+    trackers: Option<Trackers>
+}
+
+impl Coordinator {
+    async fn run(mut self, mut events: UnboundedReceiver<Event>) {
+        let mut tick = time::interval(TICK_INTERVAL);
+        let mut last_tick = Instant::now();
+        let mut last_rechoke = Instant::now();
+        let mut last_keepalive = Instant::now();
+        // A torrent that starts out complete super seeds from the first peer.
+        self.update_super_seeding();
+        // The trackers are told what's left from the start.
+        self.update_stats(Duration::ZERO);
+        self.update_trackers();
+
+        loop {
+            tokio::select! {
+                event = events.recv() => match event {
+                    Some(Event::Shutdown) | None => return self.stop().await,
+                    Some(Event::Pause) => {
+                        // Paused by hand, so it stays that way once there's room.
+                        self.low_space = false;
+                        self.pause().await;
+                    },
+                    Some(Event::Space(event)) => self.on_space(event).await,
+                    Some(event) => self.handle(event)
+                },
+                _ = tick.tick() => {
+                    let now = Instant::now();
+                    self.drop_blocked_peers();
+                    self.scheduler.expire_deadlines(now);
+                    self.cancel_unwanted();
+                    self.check_seed_goal(now - last_tick).await;
+                    self.check_space();
+                    self.update_queue();
+                    self.update_stats(now - last_tick);
+                    last_tick = now;
+                    self.dial_next(now);
+                    if now >= last_rechoke + RECHOKE_INTERVAL {
+                        last_rechoke = now;
+                        self.rechoke();
+                    }
+                    if now >= last_keepalive + KEEPALIVE_INTERVAL {
+                        last_keepalive = now;
+                        self.connections.values().for_each(|peer| peer.connection.send(Message::KeepAlive));
+                    }
+                    // Blocks given back by peers that left go to whoever can take them.
+                    let addrs: Vec<_> = self.connections.keys().copied().collect();
+                    addrs.into_iter().for_each(|addr| self.fill_requests(addr));
+                }
+            }
+        }
+    }
+
+    fn handle(&mut self, event: Event) {
+        match event {
+            Event::AddPeer(addr) => {
+                self.dial.add(addr, PeerSource::Manual);
+                self.dial_next(Instant::now());
+            },
+            Event::TrackerPeers(addrs) => {
+                addrs.into_iter().for_each(|addr| self.dial.add(addr, PeerSource::Tracker));
+                self.dial_next(Instant::now());
+            },
+            Event::Connected { addr, .. } if !self.is_running() => self.dial.disconnected(addr),
+            Event::Connected { addr, stream, handshake } => {
+                self.dial.connected(addr);
+                self.add_connection(addr, stream, handshake);
+            },
+            Event::DialFailed(addr) => self.dial.failed(addr),
+            Event::Message(addr, message) => {
+                if let Message::Piece { data, .. } = &message {
+                    self.backlog.pop(data.len());
+                }
+                self.on_message(addr, message);
+                self.catch_up();
+            },
+            Event::Closed(addr) => self.remove_peer(addr),
+            Event::Resume => self.resume(),
+            Event::SetDeadline(piece, deadline) => {
+                self.scheduler.set_deadline(piece, deadline);
+                self.update_all_interest();
+            },
+            Event::ClearDeadlines => {
+                self.scheduler.clear_deadlines();
+                self.cancel_unwanted();
+                self.update_all_interest();
+            },
+            Event::Read { offset, len, reply } => self.disk.send(DiskJob::Read { offset, len, reply }),
+            Event::SetPicker(picker) => self.scheduler.set_picker(picker),
+            Event::SetPriorities(priorities) => {
+                self.scheduler.set_priorities(priorities);
+                self.cancel_unwanted();
+                self.update_all_interest();
+            },
+            Event::SetSuperSeeding(super_seeding) => {
+                self.super_seeding = super_seeding;
+                self.update_super_seeding();
+            },
+            Event::Written { addr, request, written } => {
+                self.on_written(addr, request, written);
+                self.catch_up();
+            },
+            Event::Hashed { piece, valid } => self.on_hashed(piece, valid),
+            Event::Served { addr, request, data } => self.on_served(addr, request, data),
+            // These wait for the disk thread, so `run` sees to them.
+            Event::Pause | Event::Space(_) | Event::Shutdown => {}
+        }
+    }
+
+    // Asks the peers for blocks again once the disk has caught up.
+    fn catch_up(&mut self) {
+        if self.backlogged && !self.backlog.is_full() {
+            self.backlogged = false;
+            self.update_all_interest();
+        }
+    }
+
+    // Everything but the connections stays in memory, so resuming needs no
+    // recheck. State is saved in case we never resume.
+    async fn pause(&mut self) {
+        if self.paused {
+            return;
+        }
+        self.paused = true;
+        self.paused_flag.store(true, Ordering::Relaxed);
+        self.disconnect_all();
+        let _ = self.save_state().await;
+        self.update_queue();
+        self.update_trackers();
+        info!("paused");
+        self.alert(Alert::TorrentPaused { info_hash: self.handshake.info_hash });
+    }
+
+    fn resume(&mut self) {
+        if !self.paused {
+            return;
+        }
+        self.low_space = false;
+        self.paused = false;
+        self.paused_flag.store(false, Ordering::Relaxed);
+        self.update_queue();
+        self.update_trackers();
+        self.dial_next(Instant::now());
+        info!("resumed");
+        self.alert(Alert::TorrentResumed { info_hash: self.handshake.info_hash });
+    }
+
+    // Pauses a finished torrent once it has seeded enough.
+    async fn check_seed_goal(&mut self, elapsed: Duration) {
+        if !self.is_running() || !self.scheduler.bitfield().is_complete() {
+            return;
+        }
+        self.seeding_time += elapsed;
+        let goal = self.options
+            .seed_goal()
+            .unwrap_or_else(|| *self.seed_goal.lock().unwrap());
+        let size = self.scheduler.geometry().total_length();
+        if self.goal_reached || !goal.is_reached(self.uploaded.total(), size, self.seeding_time) {
+            return;
+        }
+        self.goal_reached = true;
+        info!(uploaded = self.uploaded.total(), seeding_time = ?self.seeding_time, "seeding goal reached");
+        self.alert(Alert::SeedingGoalReached { info_hash: self.handshake.info_hash });
+        self.pause().await;
+    }
+
+    // Only while there is something left to download, or the torrent waits
+    // for room to download it in.
+    fn check_space(&self) {
+        if !self.low_space && (!self.is_running() || self.scheduler.is_done()) {
+            return;
+        }
+        let mut wanted = Bitfield::new(self.scheduler.geometry().num_pieces() as usize);
+        for piece in (0..wanted.len()).filter(|&piece| self.scheduler.is_wanted(piece as u32)) {
+            wanted.set(piece);
+        }
+        let needed = missing_bytes(self.scheduler.geometry(), self.scheduler.bitfield(), &wanted);
+        self.disk.send(DiskJob::CheckSpace { needed });
+    }
+
+    // Pauses before a write fails for a full disk.
+    async fn on_space(&mut self, event: SpaceEvent) {
+        match event {
+            SpaceEvent::Low { available, needed } => {
+                if self.paused {
+                    return;
+                }
+                self.alert(Alert::LowDiskSpace { info_hash: self.handshake.info_hash, available, needed });
+                self.low_space = true;
+                self.pause().await;
+            },
+            SpaceEvent::Recovered => {
+                if self.low_space {
+                    self.low_space = false;
+                    self.resume();
+                }
+            }
+        }
+    }
+
+    fn is_running(&self) -> bool {
+        !self.paused && !self.queued
+    }
+
+    // Catches peers that were connected before their range was blocked, or
+    // before they were banned.
+    fn drop_blocked_peers(&mut self) {
+        let blocked: Vec<_> = {
+            let (filter, bans) = (self.ip_filter.lock().unwrap(), self.bans.lock().unwrap());
+            self.connections
+                .keys()
+                .filter(|addr| filter.is_blocked(addr.ip()) || bans.is_banned(addr.ip()))
+                .copied()
+                .collect()
+        };
+        for addr in blocked {
+            debug!(%addr, "dropping blocked peer");
+            self.dial.remove(addr);
+            self.remove_peer(addr);
+        }
+    }
+
+    fn disconnect_all(&mut self) {
+        let addrs: Vec<_> = self.connections.keys().copied().collect();
+        addrs.into_iter().for_each(|addr| self.remove_peer(addr));
+        self.uploaded.reset_rate();
+        self.downloaded.reset_rate();
+    }
+
+    // Stops or starts the torrent when its turn in the queue changes.
+    fn update_queue(&mut self) {
+        let complete = self.scheduler.bitfield().is_complete();
+        let active = self.queue.update(&self.handshake.info_hash, complete, self.paused);
+        let queued = !self.paused && !active;
+        if queued == self.queued {
+            return;
+        }
+        self.queued = queued;
+        self.update_trackers();
+        match queued {
+            true => {
+                debug!("queued");
+                self.disconnect_all();
+            },
+            false => {
+                debug!("started from the queue");
+                self.dial_next(Instant::now());
+            }
+        }
+    }
+
+    // Closes every connection, then makes sure what was downloaded is on
+    // disk and can be picked up again without rehashing.
+    async fn stop(&mut self) {
+        self.queue.remove(&self.handshake.info_hash);
+        self.unchoke.remove(&self.handshake.info_hash);
+        self.disconnect_all();
+        let saved = self.save_state();
+        #[cfg(any(feature = "tracker-http", feature = "tracker-udp"))]
+        if let Some(trackers) = self.trackers.take() {
+            trackers.stop().await;
+        }
+        let _ = saved.await;
+    }
+
+    // The trackers hear of it when we start or stop running, and once we
+    // complete.
+    fn update_trackers(&self) {
+        #[cfg(any(feature = "tracker-http", feature = "tracker-udp"))]
+        if let Some(trackers) = &self.trackers {
+            let complete = self.scheduler.bitfield().is_complete();
+            trackers.update(trackers::Status { running: self.is_running(), complete });
+        }
+    }
+
+    // Resolves once the disk thread is done.
+    fn save_state(&self) -> oneshot::Receiver<()> {
+        let (uploaded, downloaded) = (self.uploaded.total(), self.downloaded.total());
+        let (done, saved) = oneshot::channel();
+        self.disk.send(DiskJob::Save { uploaded, downloaded, done });
+        saved
+    }
+
+    fn update_stats(&mut self, elapsed: Duration) {
+        self.uploaded.tick(elapsed);
+        self.downloaded.tick(elapsed);
+        for connection in self.connections.values_mut() {
+            connection.uploaded.tick(elapsed);
+            connection.downloaded.tick(elapsed);
+        }
+        // Over the pieces wanted, so skipped files don't count.
+        let geometry = self.scheduler.geometry();
+        let (mut bytes_done, mut bytes_total) = (0, 0);
+        for piece in (0..geometry.num_pieces()).filter(|&piece| self.scheduler.is_wanted(piece)) {
+            let size = u64::from(geometry.piece_size(piece).unwrap_or(0));
+            bytes_total += size;
+            if self.scheduler.bitfield().get(piece as usize) {
+                bytes_done += size;
+            }
+        }
+        let stats = TorrentStats {
+            bytes_done,
+            bytes_total,
+            uploaded: self.uploaded.total(),
+            downloaded: self.downloaded.total(),
+            upload_rate: self.uploaded.rate(),
+            download_rate: self.downloaded.rate(),
+            connected_peers: self.connections.len(),
+            queued: self.queued,
+            known_peers: self.connections.len() + self.dial.queued() + self.dial.half_open(),
+            backlog_blocks: self.backlog.blocks(),
+            backlog_bytes: self.backlog.bytes()
+        };
+        self.stats.send_replace(stats);
+
+        let peers = self.connections
+            .iter()
+            .filter_map(|(addr, connection)| {
+                let peer = self.swarm.peer(*addr)?;
+                Some(PeerInfo {
+                    addr: *addr,
+                    client: peer.client.as_ref().map(ToString::to_string),
+                    pieces: peer.has.count_ones(),
+                    am_choking: peer.am_choking,
+                    am_interested: peer.am_interested,
+                    peer_choking: peer.peer_choking,
+                    peer_interested: peer.peer_interested,
+                    upload_rate: connection.uploaded.rate(),
+                    download_rate: connection.downloaded.rate(),
+                    country: connection.country.clone()
+                })
+            })
+            .collect();
+        self.peers.send_replace(peers);
+    }
+
+    fn dial_next(&mut self, now: Instant) {
+        if !self.is_running() {
+            return;
+        }
+        let limits = self.slots.limits();
+        while self.connections.len() + self.dial.half_open() < limits.per_torrent
+            && self.slots.connections() < limits.max_connections
+        {
+            let Some(slot) = self.slots.try_dial() else {
+                break;
+            };
+            let Some(addr) = self.dial.next_dial(now) else {
+                break;
+            };
+            if self.connections.contains_key(&addr) {
+                self.dial.connected(addr);
+                continue;
+            }
+            if self.ip_filter.lock().unwrap().is_blocked(addr.ip()) || self.bans.lock().unwrap().is_banned(addr.ip()) {
+                debug!(%addr, "not dialing blocked peer");
+                self.dial.remove(addr);
+                continue;
+            }
+            let (events, handshake) = (self.events.clone(), self.our_handshake());
+            tokio::spawn(async move {
+                let event = match peer::connect(addr, handshake).await {
+                    Ok((stream, handshake)) => Event::Connected { addr, stream, handshake },
+                    Err(err) => {
+                        debug!(%addr, error = %err, "dial failed");
+                        Event::DialFailed(addr)
+                    }
+                };
+                drop(slot);
+                let _ = events.send(event);
+            }.in_current_span());
+        }
+    }
+
+    fn dht_port(&self) -> Option<u16> {
+        match self.dht_port.load(Ordering::Relaxed) {
+            0 => None,
+            port => Some(port)
+        }
+    }
+
+    fn our_handshake(&self) -> Handshake {
+        let handshake = match self.dht_port() {
+            Some(_) => self.handshake.with_dht(),
+            None => self.handshake
+        };
+        match self.merkle {
+            Some(_) => handshake.with_v2(),
+            None => handshake
+        }
+    }
+
+    fn add_connection(&mut self, addr: SocketAddr, stream: TcpStream, handshake: Handshake) {
+        if self.connections.contains_key(&addr) || handshake.peer_id == self.handshake.peer_id {
+            return;
+        }
+        if self.bans.lock().unwrap().is_banned(addr.ip()) {
+            debug!(%addr, "refused banned peer");
+            self.dial.remove(addr);
+            return;
+        }
+        let Some(slot) = self.connection_slot() else {
+            self.dial.disconnected(addr);
+            return;
+        };
+        let connection = Connection::spawn(stream, addr, self.events.clone(), self.limits.clone(), self.buffers.clone(), self.backlog.clone());
+        if let Some(seeder) = &mut self.super_seeder {
+            let nothing = Bitfield::new(self.swarm.num_pieces());
+            seeder.peer_connected(addr, &nothing).into_iter().for_each(|piece| connection.send(Message::Have(piece)));
+        } else if self.swarm.bitfield().count_ones() > 0 {
+            connection.send(Message::Bitfield(self.swarm.bitfield().as_bytes().to_vec()));
+        }
+        if let Some(port) = self.dht_port().filter(|_| handshake.supports_dht()) {
+            connection.send(Message::Port(port));
+        }
+        self.swarm.add_peer(addr).set_peer_id(handshake.peer_id);
+        let country = self.geoip.lock().unwrap().as_ref().and_then(|geoip| geoip.country(addr.ip()));
+        self.connections.insert(addr, PeerConnection {
+            connection,
+            pending: PendingRequests::new(self.options.late_piece_policy()),
+            serving: HashSet::new(),
+            country,
+            uploaded: RateMeter::default(),
+            downloaded: RateMeter::default(),
+            v2: handshake.supports_v2(),
+            _slot: slot
+        });
+        self.metrics.peer_connected();
+        debug!(%addr, client = ?self.swarm.peer(addr).and_then(|peer| peer.client.clone()), "peer connected");
+        self.alert(Alert::PeerConnected { info_hash: self.handshake.info_hash, addr });
+    }
+
+    // Nobody listening is fine.
+    fn alert(&self, alert: Alert) {
+        let _ = self.alerts.send(alert);
+    }
+
+    // Takes a slot for a new connection, closing an idle peer to make room
+    // if this torrent or the session is full.
+    fn connection_slot(&mut self) -> Option<Slot> {
+        if self.connections.len() < self.slots.limits().per_torrent {
+            if let Some(slot) = self.slots.try_connect() {
+                return Some(slot);
+            }
+        }
+        let addr = least_useful(self.swarm.peers())?;
+        self.remove_peer(addr);
+        self.slots.try_connect()
+    }
+
+    fn remove_peer(&mut self, addr: SocketAddr) {
+        if self.connections.remove(&addr).is_none() {
+            return;
+        }
+        let peer = self.swarm.remove_peer(addr);
+        if let Some(peer) = &peer {
+            self.scheduler.remove_availability(&peer.has);
+        }
+        let was_unchoked = peer.is_some_and(|peer| !peer.am_choking);
+        if let Some(seeder) = &mut self.super_seeder {
+            seeder.peer_disconnected(addr);
+        }
+        self.scheduler.peer_lost(addr);
+        self.hash_requests.retain(|_, asked| *asked != addr);
+        self.dial.disconnected(addr);
+        self.metrics.peer_disconnected();
+        debug!(%addr, "peer disconnected");
+        self.alert(Alert::PeerDisconnected { info_hash: self.handshake.info_hash, addr });
+        if was_unchoked {
+            self.rechoke();
+        }
+    }
+
+    fn send(&self, addr: SocketAddr, message: Message) {
+        if let Some(peer) = self.connections.get(&addr) {
+            peer.connection.send(message);
+        }
+    }
+
+    fn on_message(&mut self, addr: SocketAddr, message: Message) {
+        if let Err(violation) = message.validate(self.scheduler.geometry()) {
+            warn!(%addr, %violation, "dropping peer for breaking the protocol");
+            self.dial.remove(addr);
+            self.remove_peer(addr);
+            return;
+        }
+        let num_pieces = self.swarm.num_pieces();
+        let Some(peer) = self.swarm.peer_mut(addr) else {
+            return;
+        };
+        match message {
+            Message::KeepAlive | Message::Port(_) | Message::Extended { .. } => {},
+            Message::Cancel(request) => {
+                if let Some(connection) = self.connections.get_mut(&addr) {
+                    connection.serving.remove(&request);
+                }
+            },
+            Message::HashRequest(request) => self.serve_hashes(addr, request),
+            Message::Hashes { request, hashes } => self.on_hashes(addr, request, hashes),
+            Message::HashReject(request) => {
+                self.hash_requests.remove(&request);
+            },
+            Message::Choke => {
+                peer.peer_choking = true;
+                if let Some(connection) = self.connections.get_mut(&addr) {
+                    for request in connection.pending.choked() {
+                        self.scheduler.cancel(addr, &request);
+                    }
+                }
+            },
+            Message::Unchoke => {
+                if let Some(connection) = self.connections.get_mut(&addr).filter(|_| peer.peer_choking) {
+                    connection.pending.unchoked();
+                }
+                peer.peer_choking = false;
+                self.fill_requests(addr);
+            },
+            Message::Interested => {
+                peer.peer_interested = true;
+                self.rechoke();
+            },
+            Message::NotInterested => {
+                peer.peer_interested = false;
+                self.rechoke();
+            },
+            Message::Have(piece) => {
+                if self.swarm.peer_have(addr, piece) {
+                    self.scheduler.piece_available(piece);
+                }
+                // A piece we showed one peer turning up at another shows
+                // it was passed on, so the first can be shown another.
+                if let Some(seeder) = &mut self.super_seeder {
+                    for (other, piece) in seeder.peer_have(addr, piece) {
+                        self.send(other, Message::Have(piece));
+                    }
+                }
+                self.update_interest(addr);
+            },
+            Message::Bitfield(bytes) => {
+                if let Some(has) = Bitfield::from_bytes(&bytes, num_pieces) {
+                    self.scheduler.remove_availability(&peer.has);
+                    self.scheduler.add_availability(&has);
+                    if let (Some(seeder), Some(connection)) = (&mut self.super_seeder, self.connections.get(&addr)) {
+                        for piece in seeder.peer_connected(addr, &has) {
+                            connection.connection.send(Message::Have(piece));
+                        }
+                    }
+                    peer.has = has;
+                }
+                self.update_interest(addr);
+            },
+            Message::Request(request) => self.serve(addr, request),
+            Message::Piece { index, begin, data } => self.on_block(addr, index, begin, data)
+        }
+    }
+
+    // Whether the peer has any piece we still want.
+    fn update_interest(&mut self, addr: SocketAddr) {
+        let Some(peer) = self.swarm.peer_mut(addr) else {
+            return;
+        };
+        let interested = peer.has
+            .ones()
+            .any(|piece| !self.scheduler.bitfield().get(piece) && self.scheduler.is_wanted(piece as u32));
+        if interested != peer.am_interested {
+            peer.am_interested = interested;
+            self.send(addr, if interested { Message::Interested } else { Message::NotInterested });
+        }
+        self.fill_requests(addr);
+    }
+
+    fn update_all_interest(&mut self) {
+        let addrs: Vec<_> = self.connections.keys().copied().collect();
+        addrs.into_iter().for_each(|addr| self.update_interest(addr));
+    }
+
+    fn fill_requests(&mut self, addr: SocketAddr) {
+        let (Some(peer), Some(connection)) = (self.swarm.peer(addr), self.connections.get_mut(&addr)) else {
+            return;
+        };
+        if peer.peer_choking || !peer.am_interested {
+            return;
+        }
+        // The disk is behind; the peer is asked again once it catches up.
+        if self.backlog.is_full() {
+            self.backlogged = true;
+            return;
+        }
+        while connection.pending.len() < PIPELINE_LEN {
+            let request = self.scheduler
+                .next_request(addr, &peer.has)
+                .or_else(|| self.scheduler.endgame_request(addr, &peer.has, |request| connection.pending.contains(request)));
+            let Some(request) = request else {
+                break;
+            };
+            // Handed back to a peer that was still fetching it anyway.
+            if !connection.pending.request(request) {
+                continue;
+            }
+            connection.connection.send(Message::Request(request));
+            // And, once for each piece, the hashes that will check its blocks.
+            let hash_request = self.merkle
+                .as_ref()
+                .filter(|_| connection.v2)
+                .and_then(|merkle| merkle.lock().unwrap().request(request.index));
+            if let Some(hash_request) = hash_request.filter(|hash_request| !self.hash_requests.contains_key(hash_request)) {
+                self.hash_requests.insert(hash_request, addr);
+                connection.connection.send(Message::HashRequest(hash_request));
+            }
+        }
+    }
+
+    fn serve_hashes(&mut self, addr: SocketAddr, request: HashRequest) {
+        let message = match self.merkle.as_ref().and_then(|merkle| merkle.lock().unwrap().serve(&request)) {
+            Some(hashes) => Message::Hashes { request, hashes },
+            None => Message::HashReject(request)
+        };
+        self.send(addr, message);
+    }
+
+    // Hashes nobody asked this peer for are ignored.
+    fn on_hashes(&mut self, addr: SocketAddr, request: HashRequest, hashes: Vec<Hash>) {
+        if self.hash_requests.get(&request) != Some(&addr) {
+            return;
+        }
+        self.hash_requests.remove(&request);
+        if let Some(merkle) = &self.merkle {
+            if !merkle.lock().unwrap().add_hashes(&request, &hashes) {
+                warn!(%addr, "hashes failed their proof");
+            }
+        }
+    }
+
+    // Gives up on a request, telling the peer not to send the block.
+    fn cancel_request(&mut self, addr: SocketAddr, request: &BlockRequest) {
+        if let Some(connection) = self.connections.get_mut(&addr) {
+            if connection.pending.cancel(request) {
+                connection.connection.send(Message::Cancel(*request));
+            }
+        }
+    }
+
+    // Requests for pieces no longer wanted are given up on, their blocks
+    // going back to the scheduler for when they're wanted again.
+    fn cancel_unwanted(&mut self) {
+        let unwanted: Vec<_> = self.connections
+            .iter()
+            .flat_map(|(&addr, connection)| connection.pending.iter().map(move |&request| (addr, request)))
+            .filter(|(_, request)| !self.scheduler.is_wanted(request.index))
+            .collect();
+        for (addr, request) in unwanted {
+            self.cancel_request(addr, &request);
+            self.scheduler.cancel(addr, &request);
+        }
+    }
+
+    // Read on the disk thread, then sent on from `on_served`.
+    fn serve(&mut self, addr: SocketAddr, request: BlockRequest) {
+        let choking = self.swarm.peer(addr).is_none_or(|peer| peer.am_choking);
+        let hidden = self.super_seeder.as_ref().is_some_and(|seeder| !seeder.is_offered(addr, request.index));
+        if choking
+            || hidden
+            || !self.scheduler.bitfield().get(request.index as usize)
+            || self.scheduler.geometry().validate_request(&request).is_err()
+        {
+            return;
+        }
+        if let Some(connection) = self.connections.get_mut(&addr) {
+            connection.serving.insert(request);
+        }
+        self.disk.send(DiskJob::Serve { addr, request });
+    }
+
+    // A peer choked since asking has had its requests dropped, and one that
+    // cancelled the request doesn't want it any more.
+    fn on_served(&mut self, addr: SocketAddr, request: BlockRequest, data: Bytes) {
+        let (Some(peer), Some(connection)) = (self.swarm.peer(addr), self.connections.get_mut(&addr)) else {
+            return;
+        };
+        if !connection.serving.remove(&request) || peer.am_choking {
+            return;
+        }
+        connection.uploaded.record(data.len() as u64);
+        self.uploaded.record(data.len() as u64);
+        self.metrics.add_uploaded(data.len() as u64);
+        connection.connection.send(Message::Piece { index: request.index, begin: request.begin, data });
+    }
+
+    fn on_block(&mut self, addr: SocketAddr, index: u32, begin: u32, data: Bytes) {
+        let Some(connection) = self.connections.get_mut(&addr) else {
+            return;
+        };
+        match connection.pending.piece_received(index, begin, data.len() as u32) {
+            Received::Requested | Received::LateAccepted => {},
+            Received::LateDiscarded | Received::Unrequested => return
+        }
+        connection.downloaded.record(data.len() as u64);
+        self.downloaded.record(data.len() as u64);
+        self.metrics.add_downloaded(data.len() as u64);
+
+        // Peers racing this one for it in the endgame needn't send it too.
+        let request = BlockRequest::new(index, begin, data.len() as u32);
+        let others: Vec<_> = self.connections.keys().filter(|&&other| other != addr).copied().collect();
+        others.into_iter().for_each(|other| self.cancel_request(other, &request));
+
+        // Back in the backlog until the disk thread has written it.
+        self.backlog.push(data.len());
+        self.disk.send(DiskJob::Write { addr, request, data });
+        self.fill_requests(addr);
+    }
+
+    // The block only counts once it's on disk. One that didn't make it, or
+    // failed its hash check, goes again.
+    fn on_written(&mut self, addr: SocketAddr, request: BlockRequest, written: bool) {
+        if !written {
+            self.scheduler.cancel(addr, &request);
+            self.fill_requests(addr);
+            return;
+        }
+        // Whoever sent it shares the blame should the piece fail.
+        self.bans
+            .lock()
+            .unwrap()
+            .block_received(self.handshake.info_hash, request.index, addr.ip(), request.length);
+        if let Some(piece) = self.scheduler.block_received(&request) {
+            self.disk.send(DiskJob::Hash(piece));
+        }
+    }
+
+    // A piece fetched twice, after its first peer left with a block still
+    // being written, can be hashed twice.
+    fn on_hashed(&mut self, piece: u32, valid: bool) {
+        if self.scheduler.bitfield().get(piece as usize) {
+            return;
+        }
+        let info_hash = self.handshake.info_hash;
+        match valid {
+            true => {
+                self.bans.lock().unwrap().piece_passed(info_hash, piece);
+                self.piece_verified(piece);
+            },
+            false => {
+                self.scheduler.piece_failed(piece);
+                warn!(piece, "piece failed its hash check");
+                self.metrics.hash_failed();
+                self.alert(Alert::HashFailed { info_hash, piece });
+                let banned = self.bans.lock().unwrap().piece_failed(info_hash, piece);
+                self.drop_banned(&banned);
+                self.update_all_interest();
+            }
+        }
+    }
+
+    // Other torrents drop them on their next tick.
+    fn drop_banned(&mut self, banned: &[IpAddr]) {
+        let addrs: Vec<_> = self.connections.keys().filter(|addr| banned.contains(&addr.ip())).copied().collect();
+        for addr in addrs {
+            warn!(%addr, "banned for sending corrupt data");
+            self.dial.remove(addr);
+            self.remove_peer(addr);
+        }
+    }
+
+    fn piece_verified(&mut self, piece: u32) {
+        debug!(piece, "piece verified");
+        self.metrics.piece_verified();
+        self.scheduler.piece_verified(piece);
+        self.disk.send(DiskJob::Verified(piece));
+        for addr in self.swarm.piece_verified(piece) {
+            self.send(addr, Message::Have(piece));
+        }
+        self.have.send_replace(self.scheduler.bitfield().clone());
+        let info_hash = self.handshake.info_hash;
+        self.alert(Alert::PieceVerified { info_hash, piece });
+        self.update_all_interest();
+
+        // Carry on as a seed: the choker now favours the peers we upload to
+        // fastest, and the seeding goal decides when to stop.
+        if self.scheduler.bitfield().is_complete() {
+            info!("download complete, seeding");
+            self.alert(Alert::TorrentCompleted { info_hash });
+            self.update_queue();
+            self.update_trackers();
+            self.update_super_seeding();
+            self.rechoke();
+        }
+    }
+
+    // Super seeding only starts once there's every piece to show. Peers
+    // already connected are shown pieces from then on; when it stops, they
+    // are told of everything held back.
+    fn update_super_seeding(&mut self) {
+        let active = self.super_seeding && self.scheduler.bitfield().is_complete();
+        match (active, self.super_seeder.take()) {
+            (true, None) => {
+                let mut seeder = SuperSeeder::new(self.swarm.num_pieces(), DEFAULT_PIECES_PER_PEER);
+                let peers: Vec<_> = self.swarm.peers().map(|peer| (peer.addr, peer.has.clone())).collect();
+                for (addr, has) in peers {
+                    for piece in seeder.peer_connected(addr, &has) {
+                        self.send(addr, Message::Have(piece));
+                    }
+                }
+                debug!("super seeding");
+                self.super_seeder = Some(seeder);
+            },
+            (false, Some(_)) => {
+                for peer in self.swarm.peers() {
+                    for piece in (0..peer.has.len()).filter(|&piece| !peer.has.get(piece)) {
+                        self.send(peer.addr, Message::Have(piece as u32));
+                    }
+                }
+                debug!("stopped super seeding");
+            },
+            (_, seeder) => self.super_seeder = seeder
+        }
+    }
+
+    fn rechoke(&mut self) {
+        // Tit-for-tat while downloading; while seeding nobody has anything
+        // to give back, so keep the fastest takers busy instead.
+        let seeding = self.scheduler.bitfield().is_complete();
+        let candidates: Vec<_> = self.swarm
+            .peers()
+            .map(|peer| {
+                let rate = self.connections.get(&peer.addr).map_or(0, |connection| match seeding {
+                    true => connection.uploaded.rate(),
+                    false => connection.downloaded.rate()
+                });
+                ChokeCandidate { addr: peer.addr, interested: peer.peer_interested, rate }
+            })
+            .collect();
+        let interested = candidates.iter().filter(|peer| peer.interested).count();
+        let slots = self.unchoke.allocate(self.handshake.info_hash, interested, self.options.upload_slots());
+        let unchoked = self.choker.rechoke(&candidates, slots);
+
+        let addrs: Vec<_> = self.connections.keys().copied().collect();
+        for addr in addrs {
+            let Some(peer) = self.swarm.peer_mut(addr) else {
+                continue;
+            };
+            let choke = !unchoked.contains(&addr);
+            if choke != peer.am_choking {
+                peer.am_choking = choke;
+                self.send(addr, if choke { Message::Choke } else { Message::Unchoke });
+            }
+        }
+    }
+}
+
+// Controls a torrent running on the engine. Dropping the handle stops it.
+pub struct TorrentHandle {
+    info_hash: [u8; 20],
+    name: Arc<str>,
+    peer_id: [u8; 20],
+    events: UnboundedSender<Event>,
+    have: watch::Receiver<Bitfield>,
+    stats: watch::Receiver<TorrentStats>,
+    peers: watch::Receiver<Vec<PeerInfo>>,
+    layout: Arc<Layout>,
+    limits: RateLimits,
+    alerts: broadcast::Sender<Alert>,
+    paused: Arc<AtomicBool>,
+    task: Option<JoinHandle<()>>
+}
+
+impl TorrentHandle {
+    // Starts the torrent on the current tokio runtime.
+    pub fn spawn<S: Storage + Send + 'static>(torrent: Torrent<S>, peer_id: [u8; 20]) -> Self {
+        Self::spawn_with(torrent, peer_id, TorrentOptions::default(), Shared::default())
+    }
+
+    // Like `spawn`, with peers held to the session's rate limits on top of
+    // the torrent's own and counted against its connection limits.
+    pub fn spawn_with<S: Storage + Send + 'static>(
+        torrent: Torrent<S>,
+        peer_id: [u8; 20],
+        options: TorrentOptions,
+        shared: Shared
+    ) -> Self {
+        let own = options.limits().map_or_else(RateLimits::default, |(upload, download)| RateLimits::new(upload, download));
+        let limits = vec![shared.limits.clone(), own.clone()];
+        let info_hash = torrent.metainfo().info_hash;
+        let name = torrent.metainfo().name.as_str().into();
+        let layout = Arc::new(torrent.storage().layout().clone());
+        let geometry = *layout.geometry();
+        let (events, receiver) = mpsc::unbounded_channel();
+        let (have, have_receiver) = watch::channel(torrent.have().clone());
+        let (stats, stats_receiver) = watch::channel(TorrentStats::default());
+        let (peers, peers_receiver) = watch::channel(Vec::new());
+        shared.queue.push(info_hash, torrent.have().is_complete());
+        let active = shared.queue.is_active(&info_hash);
+        let paused = Arc::new(AtomicBool::new(false));
+        let super_seeding = options.super_seeding();
+        let merkle = torrent.metainfo()
+            .v2
+            .as_ref()
+            .map(|info| Arc::new(Mutex::new(BlockVerifier::new(info, torrent.metainfo().piece_length))));
+        let backlog = Backlog::default();
+        let span = tracing::info_span!("torrent", info_hash = %hex(&info_hash));
+        #[cfg(any(feature = "tracker-http", feature = "tracker-udp"))]
+        let trackers = Trackers::spawn(
+            &torrent.metainfo().trackers(),
+            Announce::new(info_hash, peer_id, shared.port),
+            torrent.have().is_complete(),
+            stats.subscribe(),
+            events.clone(),
+            &shared,
+            span.clone()
+        );
+        let scheduler = BlockScheduler::new(geometry, torrent.have().clone());
+        let swarm = Swarm::with_bitfield(torrent.have().clone());
+        let disk = DiskThread::new(torrent, &options, &shared, events.clone(), merkle.clone(), backlog.clone());
+
+        let coordinator = Coordinator {
+            disk: disk.spawn(span.clone()),
+            handshake: Handshake::new(info_hash, peer_id),
+            scheduler,
+            swarm,
+            connections: HashMap::new(),
+            dial: DialQueue::new(DialConfig::default()),
+            choker: Choker::new(),
+            events: events.clone(),
+            have,
+            stats,
+            peers,
+            uploaded: RateMeter::default(),
+            downloaded: RateMeter::default(),
+            limits,
+            options,
+            slots: shared.slots,
+            unchoke: shared.unchoke,
+            alerts: shared.alerts.clone(),
+            metrics: shared.metrics,
+            queue: shared.queue,
+            paused: false,
+            queued: !active,
+            paused_flag: paused.clone(),
+            seed_goal: shared.seed_goal,
+            dht_port: shared.dht_port,
+            ip_filter: shared.ip_filter,
+            bans: shared.bans,
+            geoip: shared.geoip,
+            buffers: shared.buffers,
+            merkle,
+            hash_requests: HashMap::new(),
+            backlog,
+            backlogged: false,
+            seeding_time: Duration::ZERO,
+            goal_reached: false,
+            low_space: false,
+            super_seeding,
+            super_seeder: None,
+            #[cfg(any(feature = "tracker-http", feature = "tracker-udp"))]
+            trackers
+        };
+        let task = tokio::spawn(coordinator.run(receiver).instrument(span));
+        Self {
+            info_hash,
+            name,
+            peer_id,
+            events,
+            have: have_receiver,
+            stats: stats_receiver,
+            peers: peers_receiver,
+            layout,
+            limits: own,
+            alerts: shared.alerts,
+            paused,
+            task: Some(task)
+        }
+    }
+
+    pub fn info_hash(&self) -> [u8; 20] {
+        self.info_hash
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn peer_id(&self) -> [u8; 20] {
+        self.peer_id
+    }
+
+    // Reads the torrent's content as it downloads, for streaming it.
+    pub fn reader(&self) -> ContentReader {
+        ContentReader::new(self.events.clone(), self.have.clone(), self.layout.clone())
+    }
+
+    pub(crate) fn events(&self) -> UnboundedSender<Event> {
+        self.events.clone()
+    }
+
+    // Fetches `piece` ahead of everything else, skipped or not, so that it
+    // arrives within `within`, as a player needs the pieces around its
+    // position. The deadline lapses once that time is up.
+    pub fn set_piece_deadline(&self, piece: u32, within: Duration) {
+        let _ = self.events.send(Event::SetDeadline(piece, Instant::now() + within));
+    }
+
+    // Drops every deadline, as after seeking elsewhere.
+    pub fn clear_piece_deadlines(&self) {
+        let _ = self.events.send(Event::ClearDeadlines);
+    }
+
+    // Replaces how the torrent chooses the pieces to fetch. Pieces already
+    // started are kept.
+    pub fn set_picker(&self, picker: Box<dyn PiecePicker>) {
+        let _ = self.events.send(Event::SetPicker(picker));
+    }
+
+    // Starts or stops super seeding, as `TorrentOptions::with_super_seeding`
+    // does. It waits for the torrent to complete.
+    pub fn set_super_seeding(&self, super_seeding: bool) {
+        let _ = self.events.send(Event::SetSuperSeeding(super_seeding));
+    }
+
+    // Which of the torrent's files to fetch, and which first. Pieces already
+    // verified are kept whatever their files' priority.
+    pub fn set_file_priorities(&self, selection: &FileSelection) {
+        let _ = self.events.send(Event::SetPriorities(selection.piece_priorities(&self.layout)));
+    }
+
+    // The torrent's files and how its pieces map onto them.
+    pub fn layout(&self) -> &Layout {
+        &self.layout
+    }
+
+    pub fn add_peer(&self, addr: SocketAddr) {
+        let _ = self.events.send(Event::AddPeer(addr));
+    }
+
+    // This torrent's own limits, unlimited until set. Changes apply to
+    // connected peers straight away.
+    pub fn limits(&self) -> &RateLimits {
+        &self.limits
+    }
+
+    // Alerts from this torrent, or from every torrent in its session if it
+    // was spawned into one.
+    pub fn subscribe(&self) -> broadcast::Receiver<Alert> {
+        self.alerts.subscribe()
+    }
+
+    pub fn pause(&mut self) {
+        self.paused.store(true, Ordering::Relaxed);
+        let _ = self.events.send(Event::Pause);
+    }
+
+    pub fn resume(&mut self) {
+        self.paused.store(false, Ordering::Relaxed);
+        let _ = self.events.send(Event::Resume);
+    }
+
+    // Also true once the torrent has paused itself after reaching its
+    // seeding goal.
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+
+    pub fn have(&self) -> Bitfield {
+        self.have.borrow().clone()
+    }
+
+    // Progress and transfer rates, refreshed once a second.
+    pub fn stats(&self) -> TorrentStats {
+        *self.stats.borrow()
+    }
+
+    // Connected peers, refreshed once a second.
+    pub fn peers(&self) -> Vec<PeerInfo> {
+        self.peers.borrow().clone()
+    }
+
+    // Waits until every piece is verified.
+    pub async fn wait_complete(&self) {
+        let mut have = self.have.clone();
+        let _ = have.wait_for(Bitfield::is_complete).await;
+    }
+
+    // Waits until every piece of the files `selection` doesn't skip is
+    // verified.
+    pub async fn wait_selected(&self, selection: &FileSelection) {
+        let priorities = selection.piece_priorities(&self.layout);
+        let mut have = self.have.clone();
+        let _ = have
+            .wait_for(|have| priorities.iter().enumerate().all(|(piece, &priority)| priority == Priority::Skip || have.get(piece)))
+            .await;
+    }
+
+    pub async fn shutdown(self) {
+        self.shutdown_timeout(SHUTDOWN_TIMEOUT).await;
+    }
+
+    // Stops the torrent, giving it `timeout` to close its peers, flush and
+    // save resume data. Returns false if it had to be killed instead.
+    pub async fn shutdown_timeout(mut self, timeout: Duration) -> bool {
+        let _ = self.events.send(Event::Shutdown);
+        let Some(mut task) = self.task.take() else {
+            return true;
+        };
+        if time::timeout(timeout, &mut task).await.is_ok() {
+            return true;
+        }
+        task.abort();
+        false
+    }
+}
+
+impl Drop for TorrentHandle {
+    fn drop(&mut self) {
+        let _ = self.events.send(Event::Shutdown);
+    }
+}
@@ -0,0 +1,243 @@
+use crate::engine::alert::Alert;
+use crate::engine::metrics::Metrics;
+use crate::engine::stats::TorrentStats;
+use crate::engine::torrent::{Event, Shared};
+use crate::tracker::{self, Announce, AnnounceEvent, Tracker};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::{broadcast, watch};
+use tokio::task::JoinSet;
+use tokio::time::{self, Instant};
+use tracing::{debug, warn, Instrument, Span};
+
+// How long to wait between announces when the tracker doesn't say, and the
+// least we wait whatever it says.
+pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(30 * 60);
+pub const MIN_INTERVAL: Duration = Duration::from_secs(60);
+// How long to wait before trying a tracker again after it failed.
+pub const RETRY_INTERVAL: Duration = Duration::from_secs(5 * 60);
+// How long the trackers get to hear that we stopped before shutting down
+// goes on without them.
+pub const STOPPED_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Where the torrent is at, as the trackers hear of it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Status {
+    pub running: bool,
+    pub complete: bool
+}
+
+// Announces a torrent to each of its trackers on their own interval: started
+// when it runs, stopped when it's paused, queued or shut down, completed once
+// it finishes. Every tracker is announced to rather than one per tier, so a
+// dead tracker costs nothing but its own retries. The peers they give go
+// back to the coordinator; their failures are alerts, and counted in the
+// session's metrics.
+pub struct Trackers {
+    status: watch::Sender<Status>,
+    tasks: JoinSet<()>
+}
+
+impl Trackers {
+    // `None` if none of the URLs is a tracker this build can announce to.
+    pub fn spawn(
+        urls: &[String],
+        request: Announce,
+        complete: bool,
+        stats: watch::Receiver<TorrentStats>,
+        events: UnboundedSender<Event>,
+        shared: &Shared,
+        span: Span
+    ) -> Option<Self> {
+        let (status, receiver) = watch::channel(Status { running: false, complete });
+        let mut tasks = JoinSet::new();
+        for url in urls {
+            let Some(tracker) = Tracker::parse(url) else {
+                debug!(tracker = %url, "not a tracker we can announce to");
+                continue;
+            };
+            let announcer = Announcer {
+                url: url.clone(),
+                tracker,
+                request: request.clone(),
+                stats: stats.clone(),
+                events: events.clone(),
+                alerts: shared.alerts.clone(),
+                metrics: shared.metrics.clone()
+            };
+            tasks.spawn(announcer.run(receiver.clone()).instrument(span.clone()));
+        }
+        (!tasks.is_empty()).then_some(Self { status, tasks })
+    }
+
+    pub fn update(&self, status: Status) {
+        self.status.send_if_modified(|old| std::mem::replace(old, status) != status);
+    }
+
+    // Tells the trackers we stopped, waiting at most `STOPPED_TIMEOUT` for
+    // them to hear it.
+    pub async fn stop(self) {
+        let Self { status, mut tasks } = self;
+        drop(status);
+        let stopped = time::timeout(STOPPED_TIMEOUT, async { while tasks.join_next().await.is_some() {} });
+        if stopped.await.is_err() {
+            debug!("trackers didn't answer the stopped announce in time");
+        }
+    }
+}
+
+struct Announcer {
+    url: String,
+    tracker: Tracker,
+    request: Announce,
+    stats: watch::Receiver<TorrentStats>,
+    events: UnboundedSender<Event>,
+    alerts: broadcast::Sender<Alert>,
+    metrics: Arc<Metrics>
+}
+
+impl Announcer {
+    // Runs until the status sender is dropped. A failed started or completed
+    // announce is tried again in place of the next regular one.
+    async fn run(self, mut status: watch::Receiver<Status>) {
+        let mut started = false;
+        // A torrent that starts out complete has nothing to report.
+        let mut completed = status.borrow().complete;
+        let mut next = Instant::now();
+        loop {
+            let Status { running, complete } = *status.borrow_and_update();
+            if !running && started {
+                self.announce(Some(AnnounceEvent::Stopped)).await;
+                started = false;
+                next = Instant::now();
+            }
+            let finished = started && complete && !completed;
+            if running && (finished || Instant::now() >= next) {
+                let event = match (started, finished) {
+                    (false, _) => Some(AnnounceEvent::Started),
+                    (true, true) => Some(AnnounceEvent::Completed),
+                    (true, false) => None
+                };
+                next = Instant::now() + match self.announce(event).await {
+                    Some(interval) => {
+                        started = true;
+                        completed |= complete;
+                        interval
+                    },
+                    None => RETRY_INTERVAL
+                };
+            }
+            tokio::select! {
+                changed = status.changed() => if changed.is_err() {
+                    break;
+                },
+                _ = time::sleep_until(next), if running => {}
+            }
+        }
+        if started {
+            self.announce(Some(AnnounceEvent::Stopped)).await;
+        }
+    }
+
+    // How long to wait for the next announce, or `None` if this one failed.
+    async fn announce(&self, event: Option<AnnounceEvent>) -> Option<Duration> {
+        match self.try_announce(event).await {
+            Ok(interval) => Some(interval),
+            Err(message) => {
+                warn!(tracker = %self.url, error = %message, "announce failed");
+                self.metrics.tracker_error();
+                let (info_hash, url) = (self.request.info_hash, self.url.clone());
+                let _ = self.alerts.send(Alert::TrackerError { info_hash, url, message });
+                None
+            }
+        }
+    }
+
+    async fn try_announce(&self, event: Option<AnnounceEvent>) -> Result<Duration, String> {
+        let stats = *self.stats.borrow();
+        let request = Announce {
+            uploaded: stats.uploaded,
+            downloaded: stats.downloaded,
+            left: stats.bytes_total.saturating_sub(stats.bytes_done),
+            event,
+            ..self.request.clone()
+        };
+        let announced = tracker::announce(&self.tracker, &request).await.map_err(|err| err.to_string())?;
+        let response = announced.response.ok_or("the tracker's response can't be read")?;
+        if let Some(failure) = response.failure {
+            return Err(format!("refused: {}", failure));
+        }
+        if let Some(warning) = response.warning {
+            warn!(tracker = %self.url, warning = %warning, "tracker warning");
+        }
+        debug!(tracker = %self.url, event = event.map_or("none", AnnounceEvent::name), peers = response.peers.len(), "announced");
+        if !response.peers.is_empty() {
+            let _ = self.events.send(Event::TrackerPeers(response.peers));
+        }
+        let interval = response.interval.map_or(DEFAULT_INTERVAL, |secs| Duration::from_secs(secs.into()));
+        Ok(interval.max(MIN_INTERVAL))
+    }
+}
+
+#[cfg(all(test, feature = "tracker-http"))]
+mod test {
+    use crate::bencode::Value;
+    use crate::compact::encode_compact;
+    use crate::engine::alert::Alert;
+    use crate::engine::stats::TorrentStats;
+    use crate::engine::torrent::{Event, Shared};
+    use crate::engine::trackers::{Status, Trackers};
+    use crate::tracker::Announce;
+    use std::net::SocketAddr;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tokio::sync::{mpsc, watch};
+    use tokio::time;
+    use tracing::Span;
+
+    // Answers one announce with `body`, returning the request line.
+    async fn answer(listener: &TcpListener, body: &Value) -> String {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut request = vec![0; 4096];
+        let len = stream.read(&mut request).await.unwrap();
+        let body = body.encode();
+        stream.write_all(b"HTTP/1.0 200 OK\r\n\r\n").await.unwrap();
+        stream.write_all(&body).await.unwrap();
+        String::from_utf8_lossy(&request[..len]).lines().next().unwrap().to_string()
+    }
+
+    #[tokio::test]
+    async fn test_announce_lifecycle() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/announce", listener.local_addr().unwrap());
+        let peer: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let peers = Value::dict([("interval", 1800.into()), ("peers", Value::from(&encode_compact(peer)[..]))]);
+        let (_stats, stats_receiver) = watch::channel(TorrentStats { bytes_total: 100, ..TorrentStats::default() });
+        let (events, mut found) = mpsc::unbounded_channel();
+        let request = Announce::new([1; 20], [2; 20], 6881);
+        let shared = Shared::default();
+        let mut errors = shared.alerts.subscribe();
+        let urls = [url, "wss://unsupported".into()];
+        let trackers = Trackers::spawn(&urls, request, false, stats_receiver, events, &shared, Span::none()).unwrap();
+
+        // Nothing is announced until the torrent runs.
+        assert!(time::timeout(Duration::from_millis(100), listener.accept()).await.is_err());
+        trackers.update(Status { running: true, complete: false });
+        let started = answer(&listener, &peers).await;
+        assert!(started.contains("event=started") && started.contains("left=100"), "{}", started);
+        assert!(matches!(found.recv().await, Some(Event::TrackerPeers(peers)) if peers == [peer]));
+
+        // A refusal is an alert, and stopping is still announced.
+        trackers.update(Status { running: true, complete: true });
+        let refusal = Value::dict([("failure reason", "unregistered".into())]);
+        assert!(answer(&listener, &refusal).await.contains("event=completed"));
+        let error = Alert::TrackerError { info_hash: [1; 20], url: urls[0].clone(), message: "refused: unregistered".into() };
+        assert_eq!(errors.recv().await.unwrap(), error);
+        assert!(shared.metrics.render().contains("\nbittorrent_tracker_errors_total 1\n"));
+
+        let (_, stopped) = tokio::join!(trackers.stop(), answer(&listener, &peers));
+        assert!(stopped.contains("event=stopped"));
+    }
+}
@@ -0,0 +1,423 @@
+use crate::engine::control::{self, Command, Request};
+use crate::engine::rpc::{header, read_request};
+use crate::handshake::HANDSHAKE_TIMEOUT;
+use crate::hash::{hex, unhex};
+use crate::magnet::Magnet;
+use crate::metainfo::Metainfo;
+use crate::peer_id;
+use serde_json::{json, Map, Value};
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio::time;
+use tracing::debug;
+
+const RPC_PATH: &str = "/transmission/rpc";
+const SESSION_HEADER: &str = "X-Transmission-Session-Id";
+// The Transmission release and RPC version this answers like. Clients
+// check them before talking to us.
+const VERSION: &str = "4.0.6";
+const RPC_VERSION: u32 = 17;
+const RPC_VERSION_MINIMUM: u32 = 14;
+
+// Torrent statuses.
+const STOPPED: u32 = 0;
+const DOWNLOAD_WAIT: u32 = 3;
+const DOWNLOADING: u32 = 4;
+const SEED_WAIT: u32 = 5;
+const SEEDING: u32 = 6;
+
+// Transmission's own failure for a method it doesn't know.
+const UNKNOWN_METHOD: &str = "method name not recognized";
+
+// The base64 of torrent-add's `metainfo`, which may be wrapped in lines.
+fn base64(text: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(text.len() * 3 / 4);
+    let (mut buffer, mut bits) = (0u32, 0);
+    for ch in text.bytes().filter(|ch| !ch.is_ascii_whitespace()) {
+        let value = match ch {
+            b'A'..=b'Z' => ch - b'A',
+            b'a'..=b'z' => ch - b'a' + 26,
+            b'0'..=b'9' => ch - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            b'=' => break,
+            _ => return None
+        };
+        buffer = buffer << 6 | u32::from(value);
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((buffer >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+// Transmission numbers torrents; ours are the order they were first seen.
+#[derive(Debug, Default)]
+struct Ids(Mutex<Vec<String>>);
+
+impl Ids {
+    fn id(&self, info_hash: &str) -> usize {
+        let mut hashes = self.0.lock().unwrap();
+        match hashes.iter().position(|hash| hash == info_hash) {
+            Some(index) => index + 1,
+            None => {
+                hashes.push(info_hash.to_string());
+                hashes.len()
+            }
+        }
+    }
+
+    // Whether a torrent is one of `ids`: absent for every torrent, or a
+    // number, a hash, or a list of them.
+    fn matches(&self, ids: Option<&Value>, info_hash: &str) -> bool {
+        let one = |id: &Value| match id {
+            Value::Number(id) => id.as_u64() == Some(self.id(info_hash) as u64),
+            Value::String(hash) => hash.eq_ignore_ascii_case(info_hash),
+            _ => false
+        };
+        match ids {
+            None => true,
+            // "recently-active" and the like narrow nothing down here.
+            Some(Value::String(name)) if unhex::<20>(name).is_none() => true,
+            Some(Value::Array(ids)) => ids.iter().any(one),
+            Some(id) => one(id)
+        }
+    }
+}
+
+struct State {
+    commands: mpsc::UnboundedSender<Command>,
+    download_dir: PathBuf,
+    ids: Ids,
+    session_id: String
+}
+
+impl State {
+    async fn execute(&self, request: Request) -> Result<Value, String> {
+        let response = control::execute(&self.commands, request).await;
+        match response.get("error").and_then(Value::as_str) {
+            Some(message) => Err(message.to_string()),
+            None => Ok(response)
+        }
+    }
+
+    async fn torrents(&self) -> Result<Vec<Value>, String> {
+        let mut response = self.execute(Request::Stats).await?;
+        Ok(response["torrents"].as_array_mut().map(std::mem::take).unwrap_or_default())
+    }
+
+    async fn selected(&self, arguments: &Value) -> Result<Vec<[u8; 20]>, String> {
+        let ids = arguments.get("ids");
+        let torrents = self.torrents().await?;
+        Ok(torrents
+            .iter()
+            .filter_map(|torrent| torrent["info_hash"].as_str())
+            .filter(|hash| self.ids.matches(ids, hash))
+            .filter_map(unhex)
+            .collect())
+    }
+
+    fn field(&self, torrent: &Value, name: &str) -> Option<Value> {
+        let number = |key: &str| torrent[key].as_u64().unwrap_or(0);
+        let (done, total) = (number("bytes_done"), number("bytes_total"));
+        let hash = torrent["info_hash"].as_str()?;
+        let paused = torrent["paused"].as_bool() == Some(true);
+        let value = match name {
+            "id" => self.ids.id(hash).into(),
+            "hashString" => hash.into(),
+            "name" => torrent["name"].clone(),
+            "downloadDir" => torrent["dir"].clone(),
+            "status" => match (paused, torrent["queued"].as_bool() == Some(true), done == total) {
+                (true, _, _) => STOPPED,
+                (_, true, false) => DOWNLOAD_WAIT,
+                (_, true, true) => SEED_WAIT,
+                (_, false, false) => DOWNLOADING,
+                (_, false, true) => SEEDING
+            }
+            .into(),
+            "percentDone" => (torrent["percent"].as_f64().unwrap_or(0.0) / 100.0).into(),
+            "totalSize" | "sizeWhenDone" => total.into(),
+            "leftUntilDone" => (total - done).into(),
+            "haveValid" => done.into(),
+            "rateDownload" => torrent["download_rate"].clone(),
+            "rateUpload" => torrent["upload_rate"].clone(),
+            "uploadedEver" => torrent["uploaded"].clone(),
+            "downloadedEver" => torrent["downloaded"].clone(),
+            "uploadRatio" => match done {
+                0 => (-1).into(),
+                done => (number("uploaded") as f64 / done as f64).into()
+            },
+            // -1 is Transmission's "not available".
+            "eta" => match number("download_rate") {
+                0 => (-1).into(),
+                rate => (total - done).div_ceil(rate).into()
+            },
+            "peersConnected" => torrent["peers"].clone(),
+            "isFinished" => (paused && done == total).into(),
+            "error" => 0.into(),
+            "errorString" => "".into(),
+            "labels" => json!([]),
+            _ => return None
+        };
+        Some(value)
+    }
+
+    async fn torrent_add(&self, arguments: &Value) -> Result<Value, String> {
+        let dir = arguments.get("download-dir").and_then(Value::as_str).map_or(self.download_dir.clone(), PathBuf::from);
+        let metainfo = match (arguments.get("metainfo").and_then(Value::as_str), arguments.get("filename").and_then(Value::as_str)) {
+            (Some(metainfo), _) => base64(metainfo).ok_or("invalid or corrupt torrent file")?,
+            // The *arr apps add magnets this way; the answer waits for the
+            // metadata to be fetched.
+            (None, Some(filename)) if filename.starts_with("magnet:") => {
+                let info_hash = Magnet::parse(filename).ok_or("invalid or corrupt magnet link")?.info_hash;
+                let request = Request::AddMagnet { magnet: filename.to_string(), dir, peers: Vec::new() };
+                return self.added(request, info_hash, arguments).await;
+            },
+            (None, Some(filename)) if filename.contains("://") => return Err("fetching torrents by URL is not supported".to_string()),
+            (None, Some(filename)) => std::fs::read(filename).map_err(|err| format!("cannot read {}: {}", filename, err))?,
+            (None, None) => return Err("no filename or metainfo specified".to_string())
+        };
+        let info_hash = Metainfo::from_bytes(&metainfo).ok_or("invalid or corrupt torrent file")?.info_hash;
+        self.added(Request::AddMetainfo { metainfo, dir }, info_hash, arguments).await
+    }
+
+    // Adds the torrent `request` carries, answering as torrent-add does.
+    async fn added(&self, request: Request, info_hash: [u8; 20], arguments: &Value) -> Result<Value, String> {
+        let added = match self.execute(request).await {
+            Ok(_) => "torrent-added",
+            Err(_) if self.torrents().await?.iter().any(|torrent| torrent["info_hash"] == hex(&info_hash)) => "torrent-duplicate",
+            Err(err) => return Err(err)
+        };
+        if added == "torrent-added" && arguments.get("paused").and_then(Value::as_bool) == Some(true) {
+            self.execute(Request::Pause { info_hash }).await?;
+        }
+        let hash = hex(&info_hash);
+        let torrents = self.torrents().await?;
+        let torrent = torrents.iter().find(|torrent| torrent["info_hash"] == hash.as_str()).ok_or("the torrent went away")?;
+        Ok(json!({ added: { "id": self.ids.id(&hash), "name": torrent["name"], "hashString": hash } }))
+    }
+
+    async fn call(&self, method: &str, arguments: &Value) -> Result<Value, String> {
+        match method {
+            "torrent-get" => {
+                let fields: Vec<&str> = arguments["fields"].as_array().into_iter().flatten().filter_map(Value::as_str).collect();
+                let ids = arguments.get("ids");
+                let torrents: Vec<Value> = self
+                    .torrents()
+                    .await?
+                    .iter()
+                    .filter(|torrent| torrent["info_hash"].as_str().is_some_and(|hash| self.ids.matches(ids, hash)))
+                    .map(|torrent| {
+                        let fields = fields.iter().filter_map(|&name| Some((name.to_string(), self.field(torrent, name)?)));
+                        Value::Object(fields.collect::<Map<_, _>>())
+                    })
+                    .collect();
+                Ok(json!({ "torrents": torrents }))
+            },
+            "torrent-add" => self.torrent_add(arguments).await,
+            "torrent-remove" | "torrent-start" | "torrent-start-now" | "torrent-stop" => {
+                for info_hash in self.selected(arguments).await? {
+                    let request = match method {
+                        "torrent-remove" => Request::Remove { info_hash },
+                        "torrent-stop" => Request::Pause { info_hash },
+                        _ => Request::Resume { info_hash }
+                    };
+                    self.execute(request).await?;
+                }
+                Ok(json!({}))
+            },
+            "session-get" => Ok(json!({
+                "version": format!("{} (bittorrent-rs {})", VERSION, env!("CARGO_PKG_VERSION")),
+                "rpc-version": RPC_VERSION,
+                "rpc-version-minimum": RPC_VERSION_MINIMUM,
+                "download-dir": self.download_dir,
+                "session-id": self.session_id
+            })),
+            "session-stats" => {
+                let torrents = self.torrents().await?;
+                let sum = |key: &str| torrents.iter().filter_map(|torrent| torrent[key].as_u64()).sum::<u64>();
+                let paused = torrents.iter().filter(|torrent| torrent["paused"] == true).count();
+                // Nothing is kept across restarts, so both totals are this run's.
+                let totals = json!({ "uploadedBytes": sum("uploaded"), "downloadedBytes": sum("downloaded"), "filesAdded": torrents.len() });
+                Ok(json!({
+                    "torrentCount": torrents.len(),
+                    "activeTorrentCount": torrents.len() - paused,
+                    "pausedTorrentCount": paused,
+                    "downloadSpeed": sum("download_rate"),
+                    "uploadSpeed": sum("upload_rate"),
+                    "cumulative-stats": totals,
+                    "current-stats": totals
+                }))
+            },
+            _ => Err(UNKNOWN_METHOD.to_string())
+        }
+    }
+}
+
+fn response(status: &str, session_id: &str, body: &str) -> String {
+    format!(
+        "HTTP/1.1 {}\r\n{}: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        SESSION_HEADER,
+        session_id,
+        body.len(),
+        body
+    )
+}
+
+async fn respond(mut stream: TcpStream, state: &State) -> io::Result<()> {
+    let (head, body) = time::timeout(HANDSHAKE_TIMEOUT, read_request(&mut stream))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "request timed out"))??;
+    let request_line = head.lines().next().unwrap_or_default();
+    let response = match (request_line.split(' ').nth(1), header(&head, SESSION_HEADER)) {
+        (Some(path), _) if path.split('?').next() != Some(RPC_PATH) => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string(),
+        // Clients learn the id from this and retry, which keeps other
+        // sites' pages from posting to us.
+        (_, id) if id != Some(state.session_id.as_str()) => response("409 Conflict", &state.session_id, ""),
+        _ => {
+            let request: Value = serde_json::from_slice(&body).unwrap_or_default();
+            let (method, arguments) = (request["method"].as_str().unwrap_or_default(), &request["arguments"]);
+            let mut reply = match state.call(method, arguments).await {
+                Ok(arguments) => json!({ "result": "success", "arguments": arguments }),
+                Err(err) => json!({ "result": err, "arguments": {} })
+            };
+            if let Some(tag) = request.get("tag") {
+                reply["tag"] = tag.clone();
+            }
+            response("200 OK", &state.session_id, &reply.to_string())
+        }
+    };
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+// Answers Transmission RPC requests on `listener` for as long as the task
+// runs.
+pub(super) async fn serve(listener: TcpListener, commands: mpsc::UnboundedSender<Command>, download_dir: PathBuf) {
+    let state = Arc::new(State { commands, download_dir, ids: Ids::default(), session_id: hex(&peer_id::generate()[8..]) });
+    loop {
+        let Ok((stream, addr)) = listener.accept().await else {
+            continue;
+        };
+        let state = state.clone();
+        tokio::spawn(async move {
+            if let Err(err) = respond(stream, &state).await {
+                debug!(%addr, error = %err, "transmission request failed");
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::engine::transmission::{base64, Ids};
+    use serde_json::{json, Value};
+
+    #[cfg(unix)]
+    async fn post(addr: std::net::SocketAddr, session_id: &str, body: &Value) -> (String, String, Value) {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let body = body.to_string();
+        let request = format!(
+            "POST /transmission/rpc HTTP/1.1\r\nX-Transmission-Session-Id: {}\r\nContent-Length: {}\r\n\r\n{}",
+            session_id,
+            body.len(),
+            body
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        let session_id = crate::engine::rpc::header(head, "x-transmission-session-id").unwrap().to_string();
+        (head.lines().next().unwrap().to_string(), session_id, serde_json::from_str(body).unwrap_or_default())
+    }
+
+    #[test]
+    fn test_base64() {
+        assert_eq!(base64("ZDQ6aW5mb2Rl").unwrap(), b"d4:infode");
+        assert_eq!(base64("aGk=\n").unwrap(), b"hi");
+        assert_eq!(base64("a*b"), None);
+    }
+
+    #[test]
+    fn test_ids() {
+        let ids = Ids::default();
+        let (a, b) = ("aa".repeat(20), "bb".repeat(20));
+        assert_eq!((ids.id(&a), ids.id(&b), ids.id(&a)), (1, 2, 1));
+        assert!(ids.matches(None, &a));
+        assert!(ids.matches(Some(&json!(2)), &b));
+        assert!(ids.matches(Some(&json!([1, b.to_uppercase()])), &b));
+        assert!(!ids.matches(Some(&json!([1])), &b));
+        assert!(ids.matches(Some(&json!("recently-active")), &b));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_transmission() {
+        use crate::engine::control::{send, ControlServer, Request};
+        use crate::engine::test::{metadata_peer, metainfo};
+        use crate::engine::Session;
+        use crate::hash::hex;
+
+        let data: Vec<u8> = (0..40_000u32).map(|i| (i % 11) as u8).collect();
+        let metainfo = metainfo(&data, 16 * 1024);
+        let dir = std::env::temp_dir().join(format!("transmission-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("file"), &data).unwrap();
+        let torrent = dir.join("file.torrent");
+        std::fs::write(&torrent, metainfo.raw.encode()).unwrap();
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let session = Session::bind("127.0.0.1:0").await.unwrap();
+        let socket = dir.join("control.sock");
+        let server = tokio::spawn({
+            let (socket, dir) = (socket.clone(), dir.clone());
+            async move { ControlServer::new(session).with_transmission(listener, dir).serve(&socket).await }
+        });
+
+        let (status, session_id, _) = post(addr, "", &json!({ "method": "session-get" })).await;
+        assert_eq!(status, "HTTP/1.1 409 Conflict");
+        let (status, _, response) = post(addr, &session_id, &json!({ "method": "session-get", "tag": 7 })).await;
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        assert_eq!((&response["result"], &response["tag"], &response["arguments"]["rpc-version"]), (&json!("success"), &json!(7), &json!(17)));
+
+        let add = json!({ "method": "torrent-add", "arguments": { "filename": torrent, "paused": true } });
+        let added = post(addr, &session_id, &add).await.2;
+        assert_eq!(added["arguments"]["torrent-added"], json!({ "id": 1, "name": "file", "hashString": hex(&metainfo.info_hash) }));
+        assert_eq!(post(addr, &session_id, &add).await.2["arguments"]["torrent-duplicate"]["id"], 1);
+
+        let get = json!({ "method": "torrent-get", "arguments": { "ids": [1], "fields": ["id", "status", "downloadDir", "leftUntilDone", "bogus"] } });
+        let torrents = post(addr, &session_id, &get).await.2["arguments"]["torrents"].clone();
+        assert_eq!(torrents, json!([{ "id": 1, "status": 0, "downloadDir": dir, "leftUntilDone": 0 }]));
+
+        let stats = post(addr, &session_id, &json!({ "method": "session-stats" })).await.2;
+        assert_eq!((&stats["arguments"]["torrentCount"], &stats["arguments"]["pausedTorrentCount"]), (&json!(1), &json!(1)));
+        assert_eq!(post(addr, &session_id, &json!({ "method": "blocklist-update" })).await.2["result"], "method name not recognized");
+
+        let remove = json!({ "method": "torrent-remove", "arguments": { "ids": [hex(&metainfo.info_hash)] } });
+        assert_eq!(post(addr, &session_id, &remove).await.2["result"], "success");
+        assert_eq!(post(addr, &session_id, &get).await.2["arguments"]["torrents"], json!([]));
+
+        // A magnet's torrent is fetched from its peer before it's added.
+        let fetched = crate::engine::test::metainfo(&[3; 1000], 16 * 1024);
+        let peer = metadata_peer(&fetched).await;
+        let link = format!("magnet:?xt=urn:btih:{}&x.pe={}", hex(&fetched.info_hash), peer);
+        let add = json!({ "method": "torrent-add", "arguments": { "filename": link, "download-dir": dir.join("magnet") } });
+        let added = post(addr, &session_id, &add).await.2;
+        assert_eq!(added["arguments"]["torrent-added"], json!({ "id": 2, "name": "file", "hashString": hex(&fetched.info_hash) }));
+        let bad = json!({ "method": "torrent-add", "arguments": { "filename": "magnet:?dn=x" } });
+        assert_eq!(post(addr, &session_id, &bad).await.2["result"], "invalid or corrupt magnet link");
+
+        send(&socket, &Request::Shutdown).await.unwrap();
+        assert!(server.await.unwrap().unwrap());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
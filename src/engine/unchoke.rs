@@ -0,0 +1,71 @@
+use crate::choker::UploadSlots;
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug, Default)]
+struct State {
+    slots: UploadSlots,
+    // Peers each torrent would unchoke, already held to its own cap.
+    demand: Vec<([u8; 20], usize)>
+}
+
+// Splits a session's upload slots between its torrents. Each torrent tells
+// it how many peers it would unchoke whenever it rechokes, and is given its
+// share of the global cap as the other torrents last asked for theirs.
+#[derive(Debug, Clone, Default)]
+pub struct UnchokeSlots {
+    state: Arc<Mutex<State>>
+}
+
+impl UnchokeSlots {
+    pub fn new(slots: UploadSlots) -> Self {
+        Self { state: Arc::new(Mutex::new(State { slots, demand: Vec::new() })) }
+    }
+
+    pub fn slots(&self) -> UploadSlots {
+        self.state.lock().unwrap().slots
+    }
+
+    // Takes effect as each torrent next rechokes.
+    pub fn set_slots(&self, slots: UploadSlots) {
+        self.state.lock().unwrap().slots = slots;
+    }
+
+    // Records that a torrent would unchoke `wanted` peers and returns how
+    // many it may. `own` takes the place of the session's per-torrent cap.
+    pub fn allocate(&self, info_hash: [u8; 20], wanted: usize, own: Option<usize>) -> usize {
+        let mut state = self.state.lock().unwrap();
+        let wanted = wanted.min(own.unwrap_or(state.slots.per_torrent));
+        match state.demand.iter_mut().find(|(hash, _)| *hash == info_hash) {
+            Some(entry) => entry.1 = wanted,
+            None => state.demand.push((info_hash, wanted))
+        }
+        let slots = UploadSlots { global: state.slots.global, per_torrent: usize::MAX };
+        slots.allocate(&state.demand)[&info_hash]
+    }
+
+    pub fn remove(&self, info_hash: &[u8; 20]) {
+        self.state.lock().unwrap().demand.retain(|(hash, _)| hash != info_hash);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::choker::UploadSlots;
+    use crate::engine::unchoke::UnchokeSlots;
+
+    #[test]
+    fn test_split_global_cap() {
+        let slots = UnchokeSlots::new(UploadSlots { global: Some(6), per_torrent: 4 });
+        assert_eq!(slots.allocate([1; 20], 10, None), 4);
+        assert_eq!(slots.allocate([2; 20], 10, None), 3);
+        assert_eq!(slots.allocate([1; 20], 10, None), 3);
+        assert_eq!(slots.allocate([3; 20], 1, None), 1);
+        assert_eq!(slots.allocate([2; 20], 10, None), 2);
+
+        slots.remove(&[1; 20]);
+        assert_eq!(slots.allocate([2; 20], 10, None), 4);
+        // A torrent's own cap goes past the session's.
+        slots.set_slots(UploadSlots { global: None, per_torrent: 4 });
+        assert_eq!(slots.allocate([2; 20], 10, Some(8)), 8);
+    }
+}
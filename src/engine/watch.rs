@@ -0,0 +1,197 @@
+use crate::engine::control::{self, Command, Request};
+use crate::magnet::Magnet;
+use crate::metainfo::Metainfo;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time;
+use tracing::{info, warn};
+
+pub const POLL_INTERVAL: Duration = Duration::from_secs(2);
+const ADDED_DIR: &str = "added";
+const ADDED_SUFFIX: &str = "added";
+
+// What becomes of a file in the watch folder once its torrent is added.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AfterAdd {
+    // Left where it is, and added again on the next start.
+    #[default]
+    Keep,
+    // Renamed to end in `.added`.
+    Rename,
+    // Moved into an `added` subdirectory.
+    Move
+}
+
+// A directory that .torrent and .magnet files are dropped into to add them.
+#[derive(Debug, Clone)]
+pub struct WatchFolder {
+    dir: PathBuf,
+    download_dir: PathBuf,
+    after_add: AfterAdd,
+    // Files dealt with, with the size of those that didn't parse: a file
+    // still being written is tried again once it changes.
+    seen: HashMap<PathBuf, Option<u64>>
+}
+
+impl WatchFolder {
+    pub fn new(dir: impl Into<PathBuf>, download_dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into(), download_dir: download_dir.into(), after_add: AfterAdd::Keep, seen: HashMap::new() }
+    }
+
+    pub fn with_after_add(mut self, after_add: AfterAdd) -> Self {
+        self.after_add = after_add;
+        self
+    }
+
+    // Torrent and magnet files not dealt with yet, oldest name first.
+    fn pending(&self) -> Vec<(PathBuf, u64)> {
+        let Ok(entries) = fs::read_dir(&self.dir) else {
+            return Vec::new();
+        };
+        let mut pending: Vec<_> = entries
+            .filter_map(Result::ok)
+            .filter_map(|entry| {
+                let path = entry.path();
+                let metadata = entry.metadata().ok().filter(|metadata| metadata.is_file())?;
+                if !matches!(path.extension()?.to_str()?, "torrent" | "magnet") {
+                    return None;
+                }
+                match self.seen.get(&path) {
+                    None => Some((path, metadata.len())),
+                    Some(Some(len)) if *len != metadata.len() => Some((path, metadata.len())),
+                    Some(_) => None
+                }
+            })
+            .collect();
+        pending.sort();
+        pending
+    }
+
+    // What to ask of the server for the file, or `None` if it doesn't parse.
+    // A magnet's torrent is fetched by the server before it's added.
+    fn request(&self, path: &Path, bytes: Vec<u8>) -> Option<Request> {
+        let dir = self.download_dir.clone();
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("magnet") => {
+                let magnet = std::str::from_utf8(&bytes).ok()?.trim();
+                Magnet::parse(magnet)?;
+                Some(Request::AddMagnet { magnet: magnet.to_string(), dir, peers: Vec::new() })
+            },
+            _ => {
+                Metainfo::from_bytes(&bytes)?;
+                Some(Request::AddMetainfo { metainfo: bytes, dir })
+            }
+        }
+    }
+
+    fn set_aside(&mut self, path: &Path) {
+        let Some(name) = path.file_name() else {
+            return;
+        };
+        let target = match self.after_add {
+            AfterAdd::Keep => return,
+            AfterAdd::Rename => path.with_file_name(format!("{}.{}", name.to_string_lossy(), ADDED_SUFFIX)),
+            AfterAdd::Move => {
+                let dir = self.dir.join(ADDED_DIR);
+                if let Err(err) = fs::create_dir_all(&dir) {
+                    warn!(dir = %dir.display(), error = %err, "cannot create the added directory");
+                    return;
+                }
+                dir.join(name)
+            }
+        };
+        match fs::rename(path, &target) {
+            Ok(()) => {
+                self.seen.remove(path);
+            },
+            Err(err) => warn!(file = %path.display(), error = %err, "cannot set aside an added torrent")
+        }
+    }
+
+    async fn poll(&mut self, commands: &mpsc::UnboundedSender<Command>) {
+        for (path, len) in self.pending() {
+            let Ok(bytes) = fs::read(&path) else {
+                continue;
+            };
+            let Some(request) = self.request(&path, bytes) else {
+                self.seen.insert(path, Some(len));
+                continue;
+            };
+            let response = control::execute(commands, request).await;
+            self.seen.insert(path.clone(), None);
+            match response.get("error").and_then(Value::as_str) {
+                Some(err) => warn!(file = %path.display(), error = %err, "cannot add from the watch folder"),
+                None => {
+                    info!(file = %path.display(), name = response["name"].as_str().unwrap_or("?"), "added from the watch folder");
+                    self.set_aside(&path);
+                }
+            }
+        }
+    }
+}
+
+pub(super) async fn serve(mut watch: WatchFolder, commands: mpsc::UnboundedSender<Command>) {
+    let mut tick = time::interval(POLL_INTERVAL);
+    loop {
+        tick.tick().await;
+        watch.poll(&commands).await;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::engine::control::{Command, Request};
+    use crate::engine::test::metainfo;
+    use crate::engine::watch::{AfterAdd, WatchFolder};
+    use serde_json::json;
+    use std::fs;
+    use tokio::sync::mpsc;
+
+    #[tokio::test]
+    async fn test_watch() {
+        let dir = std::env::temp_dir().join(format!("watch-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let torrent = metainfo(&[7; 1000], 16 * 1024).raw.encode();
+        fs::write(dir.join("a.torrent"), &torrent).unwrap();
+        fs::write(dir.join("b.torrent"), &torrent[..10]).unwrap();
+        fs::write(dir.join("c.magnet"), "magnet:?xt=urn:btih:c12fe1c06bba254a9dc9f519b335aa7c1367a88a\n").unwrap();
+        fs::write(dir.join("notes.txt"), "not a torrent").unwrap();
+
+        let (commands, mut receiver) = mpsc::unbounded_channel();
+        let server = tokio::spawn(async move {
+            let mut added = Vec::new();
+            while let Some(Command::Request(request, reply)) = receiver.recv().await {
+                added.push(request);
+                let _ = reply.send(json!({ "ok": true, "name": "file" }));
+            }
+            added
+        });
+
+        let mut watch = WatchFolder::new(&dir, "/downloads").with_after_add(AfterAdd::Move);
+        watch.poll(&commands).await;
+        assert!(dir.join("added/a.torrent").exists());
+        assert!(!dir.join("a.torrent").exists());
+        assert!(dir.join("b.torrent").exists());
+        assert!(dir.join("added/c.magnet").exists());
+        assert!(watch.pending().is_empty());
+
+        // The half-written torrent is added once it is whole.
+        fs::write(dir.join("b.torrent"), &torrent).unwrap();
+        let mut watch = watch.with_after_add(AfterAdd::Rename);
+        watch.poll(&commands).await;
+        assert!(dir.join("b.torrent.added").exists());
+        watch.poll(&commands).await;
+
+        drop(commands);
+        let added = server.await.unwrap();
+        assert_eq!(added.len(), 3);
+        let magnet = "magnet:?xt=urn:btih:c12fe1c06bba254a9dc9f519b335aa7c1367a88a".to_string();
+        assert_eq!(added[1], Request::AddMagnet { magnet, dir: "/downloads".into(), peers: Vec::new() });
+        assert!([&added[0], &added[2]].iter().all(|request| matches!(request, Request::AddMetainfo { dir, .. } if dir.to_str() == Some("/downloads"))));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
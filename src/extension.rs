@@ -0,0 +1,274 @@
+use crate::bencode::{self, Value};
+use crate::hash::sha1;
+use std::collections::BTreeMap;
+use std::fmt;
+
+// The extended message id of the extended handshake itself.
+pub const HANDSHAKE_ID: u8 = 0;
+// Metadata exchange (BEP 9).
+pub const UT_METADATA: &str = "ut_metadata";
+// The info dictionary travels in pieces of this size, the last shorter.
+pub const METADATA_PIECE_LEN: usize = 16384;
+// Larger info dictionaries are refused rather than buffered. Real ones run
+// to a few megabytes at most.
+pub const MAX_METADATA_SIZE: u64 = 8 << 20;
+
+// The payload of an extended handshake (BEP 10): which extensions a peer
+// speaks, under the message ids it wants to receive them as, plus a few
+// optional facts about itself.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ExtendedHandshake {
+    pub extensions: BTreeMap<String, u8>,
+    // The size of the info dictionary, for peers offering `ut_metadata`.
+    pub metadata_size: Option<u64>,
+    pub client: Option<String>,
+    pub listen_port: Option<u16>
+}
+
+impl ExtendedHandshake {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_extension(mut self, name: &str, id: u8) -> Self {
+        self.extensions.insert(name.to_string(), id);
+        self
+    }
+
+    pub fn with_metadata_size(mut self, size: u64) -> Self {
+        self.metadata_size = Some(size);
+        self
+    }
+
+    pub fn with_client(mut self, client: &str) -> Self {
+        self.client = Some(client.to_string());
+        self
+    }
+
+    // The id to send `name` messages to this peer with, unless it doesn't
+    // speak it. Id 0 means the extension was turned off.
+    pub fn extension_id(&self, name: &str) -> Option<u8> {
+        self.extensions.get(name).copied().filter(|&id| id != 0)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let extensions = self
+            .extensions
+            .iter()
+            .map(|(name, &id)| (name.as_bytes().to_vec(), Value::Integer(id.into())))
+            .collect();
+        let mut entries = vec![("m", Value::Dict(extensions))];
+        if let Some(size) = self.metadata_size {
+            entries.push(("metadata_size", Value::Integer(size as i64)));
+        }
+        if let Some(client) = &self.client {
+            entries.push(("v", Value::from(client.as_str())));
+        }
+        if let Some(port) = self.listen_port {
+            entries.push(("p", Value::Integer(port.into())));
+        }
+        Value::dict(entries).encode()
+    }
+
+    // Entries that don't fit, such as an id above 255 or a negative size,
+    // are dropped rather than failing the whole handshake.
+    pub fn decode(payload: &[u8]) -> Option<Self> {
+        let value = bencode::decode(payload)?;
+        value.as_dict()?;
+        let extensions = value
+            .get("m")
+            .and_then(Value::as_dict)
+            .into_iter()
+            .flatten()
+            .filter_map(|(name, id)| Some((String::from_utf8(name.clone()).ok()?, u8::try_from(id.as_int()?).ok()?)))
+            .collect();
+        Some(Self {
+            extensions,
+            metadata_size: value.get("metadata_size").and_then(Value::as_int).and_then(|size| u64::try_from(size).ok()),
+            client: value.get("v").and_then(Value::as_str).map(str::to_string),
+            listen_port: value.get("p").and_then(Value::as_int).and_then(|port| u16::try_from(port).ok())
+        })
+    }
+}
+
+// A `ut_metadata` message: a bencoded header, followed by the piece
+// itself for `Data`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MetadataMessage {
+    Request(u32),
+    Data { piece: u32, total_size: u64, data: Vec<u8> },
+    Reject(u32)
+}
+
+impl MetadataMessage {
+    pub fn encode(&self) -> Vec<u8> {
+        let (msg_type, piece) = match self {
+            Self::Request(piece) => (0, piece),
+            Self::Data { piece, .. } => (1, piece),
+            Self::Reject(piece) => (2, piece)
+        };
+        let mut entries = vec![("msg_type", Value::Integer(msg_type)), ("piece", Value::Integer((*piece).into()))];
+        if let Self::Data { total_size, .. } = self {
+            entries.push(("total_size", Value::Integer(*total_size as i64)));
+        }
+        let mut bytes = Value::dict(entries).encode();
+        if let Self::Data { data, .. } = self {
+            bytes.extend_from_slice(data);
+        }
+        bytes
+    }
+
+    pub fn decode(payload: &[u8]) -> Option<Self> {
+        let (header, rest) = bencode::decode_prefix(payload)?;
+        let piece = u32::try_from(header.get("piece")?.as_int()?).ok()?;
+        match header.get("msg_type")?.as_int()? {
+            0 => Some(Self::Request(piece)),
+            1 => Some(Self::Data {
+                piece,
+                total_size: u64::try_from(header.get("total_size")?.as_int()?).ok()?,
+                data: rest.to_vec()
+            }),
+            2 => Some(Self::Reject(piece)),
+            _ => None
+        }
+    }
+}
+
+// Why an info dictionary from a peer can't be used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetadataError {
+    // None at all, or more than `MAX_METADATA_SIZE`.
+    InvalidSize(u64),
+    InvalidPiece(u32),
+    // A piece whose `total_size` isn't the size the peer announced.
+    SizeMismatch(u64),
+    WrongLength(u32),
+    Incomplete,
+    HashMismatch
+}
+
+impl fmt::Display for MetadataError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MetadataError::InvalidSize(size) => write!(f, "unusable metadata size {}", size),
+            MetadataError::InvalidPiece(piece) => write!(f, "no metadata piece {}", piece),
+            MetadataError::SizeMismatch(size) => write!(f, "metadata piece for {} bytes of metadata", size),
+            MetadataError::WrongLength(piece) => write!(f, "metadata piece {} of the wrong size", piece),
+            MetadataError::Incomplete => write!(f, "metadata pieces missing"),
+            MetadataError::HashMismatch => write!(f, "metadata doesn't match the info hash")
+        }
+    }
+}
+
+impl std::error::Error for MetadataError {}
+
+// An info dictionary put together from `ut_metadata` pieces. Only pieces
+// that fit the size the peer announced are taken, and the whole is handed
+// over only once it hashes to the info hash it was fetched for.
+#[derive(Debug, Clone)]
+pub struct MetadataAssembler {
+    size: usize,
+    pieces: Vec<Option<Vec<u8>>>
+}
+
+impl MetadataAssembler {
+    // `size` is the peer's `metadata_size`.
+    pub fn new(size: Option<u64>) -> Result<Self, MetadataError> {
+        let size = size.unwrap_or(0);
+        if size == 0 || size > MAX_METADATA_SIZE {
+            return Err(MetadataError::InvalidSize(size));
+        }
+        let size = size as usize;
+        Ok(Self { size, pieces: vec![None; size.div_ceil(METADATA_PIECE_LEN)] })
+    }
+
+    pub fn num_pieces(&self) -> u32 {
+        self.pieces.len() as u32
+    }
+
+    pub fn missing(&self) -> impl Iterator<Item = u32> + '_ {
+        (0..self.num_pieces()).filter(|&piece| self.pieces[piece as usize].is_none())
+    }
+
+    pub fn add(&mut self, piece: u32, total_size: u64, data: Vec<u8>) -> Result<(), MetadataError> {
+        if total_size != self.size as u64 {
+            return Err(MetadataError::SizeMismatch(total_size));
+        }
+        let slot = self.pieces.get_mut(piece as usize).ok_or(MetadataError::InvalidPiece(piece))?;
+        let start = piece as usize * METADATA_PIECE_LEN;
+        if data.len() != METADATA_PIECE_LEN.min(self.size - start) {
+            return Err(MetadataError::WrongLength(piece));
+        }
+        *slot = Some(data);
+        Ok(())
+    }
+
+    pub fn finish(self, info_hash: &[u8; 20]) -> Result<Vec<u8>, MetadataError> {
+        let mut metadata = Vec::with_capacity(self.size);
+        for piece in self.pieces {
+            metadata.extend(piece.ok_or(MetadataError::Incomplete)?);
+        }
+        match sha1(&metadata) == *info_hash {
+            true => Ok(metadata),
+            false => Err(MetadataError::HashMismatch)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::extension::{ExtendedHandshake, MetadataAssembler, MetadataError, MetadataMessage, MAX_METADATA_SIZE, METADATA_PIECE_LEN, UT_METADATA};
+    use crate::hash::sha1;
+
+    #[test]
+    fn test_roundtrip() {
+        let handshake = ExtendedHandshake::new().with_extension(UT_METADATA, 3).with_metadata_size(31235).with_client("BR 0.1");
+        let bytes = handshake.encode();
+        assert_eq!(bytes, b"d1:md11:ut_metadatai3ee13:metadata_sizei31235e1:v6:BR 0.1e");
+        assert_eq!(ExtendedHandshake::decode(&bytes), Some(handshake));
+    }
+
+    #[test]
+    fn test_decode() {
+        let handshake = ExtendedHandshake::decode(b"d1:md5:ut_hxi2e11:ut_metadatai0e6:ut_pexi300ee1:pi6881ee").unwrap();
+        assert_eq!(handshake.extension_id(UT_METADATA), None);
+        assert_eq!(handshake.extension_id("ut_pex"), None);
+        assert_eq!(handshake.extension_id("ut_hx"), Some(2));
+        assert_eq!((handshake.metadata_size, handshake.listen_port), (None, Some(6881)));
+        assert_eq!(ExtendedHandshake::decode(b"de"), Some(ExtendedHandshake::new()));
+        assert_eq!(ExtendedHandshake::decode(b"le"), None);
+        assert_eq!(ExtendedHandshake::decode(b"d1:m"), None);
+    }
+
+    #[test]
+    fn test_metadata_message() {
+        let data = MetadataMessage::Data { piece: 1, total_size: 16390, data: b"tail!!".to_vec() };
+        let bytes = data.encode();
+        assert_eq!(bytes, b"d8:msg_typei1e5:piecei1e10:total_sizei16390eetail!!");
+        assert_eq!(MetadataMessage::decode(&bytes), Some(data));
+        assert_eq!(MetadataMessage::decode(&MetadataMessage::Request(2).encode()), Some(MetadataMessage::Request(2)));
+        assert_eq!(MetadataMessage::decode(b"d8:msg_typei2e5:piecei0ee"), Some(MetadataMessage::Reject(0)));
+        assert_eq!(MetadataMessage::decode(b"d8:msg_typei1e5:piecei0ee"), None);
+        assert_eq!(MetadataMessage::decode(b"d8:msg_typei7e5:piecei0ee"), None);
+    }
+
+    #[test]
+    fn test_assembler() {
+        assert_eq!(MetadataAssembler::new(None).unwrap_err(), MetadataError::InvalidSize(0));
+        assert_eq!(MetadataAssembler::new(Some(MAX_METADATA_SIZE + 1)).unwrap_err(), MetadataError::InvalidSize(MAX_METADATA_SIZE + 1));
+
+        let info: Vec<u8> = (0..METADATA_PIECE_LEN + 10).map(|at| at as u8).collect();
+        let mut assembler = MetadataAssembler::new(Some(info.len() as u64)).unwrap();
+        let size = info.len() as u64;
+        assert_eq!(assembler.num_pieces(), 2);
+        assert_eq!(assembler.add(2, size, vec![0; 10]), Err(MetadataError::InvalidPiece(2)));
+        assert_eq!(assembler.add(1, size + 1, info[METADATA_PIECE_LEN..].to_vec()), Err(MetadataError::SizeMismatch(size + 1)));
+        assert_eq!(assembler.add(1, size, vec![0; 11]), Err(MetadataError::WrongLength(1)));
+        assert_eq!(assembler.add(1, size, info[METADATA_PIECE_LEN..].to_vec()), Ok(()));
+        assert_eq!(assembler.missing().collect::<Vec<_>>(), [0]);
+        assert_eq!(assembler.clone().finish(&sha1(&info)).unwrap_err(), MetadataError::Incomplete);
+        assert_eq!(assembler.add(0, size, info[..METADATA_PIECE_LEN].to_vec()), Ok(()));
+        assert_eq!(assembler.clone().finish(&[0; 20]).unwrap_err(), MetadataError::HashMismatch);
+        assert_eq!(assembler.finish(&sha1(&info)).unwrap(), info);
+    }
+}
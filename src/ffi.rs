@@ -0,0 +1,312 @@
+//! A C API over the library, built with the `ffi` feature, which also
+//! writes its header to `include/bittorrent_rs.h`. Build the library for C
+//! with `cargo rustc --release --lib --features ffi --crate-type cdylib`
+//! (or `staticlib`).
+//!
+//! Functions that can fail return 0 on success and -1 on failure, or a
+//! null pointer. Strings handed out must be freed with `bt_string_free`,
+//! torrents with `bt_torrent_free`; a session is freed by stopping it.
+
+use crate::bencode;
+use crate::engine::Session;
+use crate::hash::sha1;
+use crate::metainfo::Metainfo;
+use crate::torrent::Torrent;
+use std::ffi::{c_char, c_int, CStr, CString};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::ptr;
+use std::slice;
+use tokio::runtime::Runtime;
+
+/// A parsed .torrent file.
+pub struct BtTorrent {
+    metainfo: Metainfo,
+    name: CString
+}
+
+/// A running session and the runtime it runs on.
+pub struct BtSession {
+    runtime: Runtime,
+    session: Session
+}
+
+/// A torrent's progress, as `bt_session_stats` fills it in.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BtStats {
+    pub bytes_done: u64,
+    pub bytes_total: u64,
+    pub uploaded: u64,
+    pub downloaded: u64,
+    /// Bytes per second.
+    pub upload_rate: u64,
+    pub download_rate: u64,
+    pub connected_peers: u32,
+    pub paused: bool,
+    pub queued: bool
+}
+
+unsafe fn bytes<'a>(data: *const u8, len: usize) -> Option<&'a [u8]> {
+    match data.is_null() {
+        true => None,
+        false => Some(slice::from_raw_parts(data, len))
+    }
+}
+
+unsafe fn text<'a>(text: *const c_char) -> Option<&'a str> {
+    match text.is_null() {
+        true => None,
+        false => CStr::from_ptr(text).to_str().ok()
+    }
+}
+
+unsafe fn info_hash(info_hash: *const u8) -> Option<[u8; 20]> {
+    bytes(info_hash, 20)?.try_into().ok()
+}
+
+fn status(ok: bool) -> c_int {
+    if ok { 0 } else { -1 }
+}
+
+/// Frees a string this library returned.
+///
+/// # Safety
+/// `text` must be null or a string from this library, not freed before.
+#[no_mangle]
+pub unsafe extern "C" fn bt_string_free(text: *mut c_char) {
+    if !text.is_null() {
+        drop(CString::from_raw(text));
+    }
+}
+
+/// Decodes `len` bytes of bencode into JSON, with binary strings as hex.
+/// Null if they aren't a single bencoded value.
+///
+/// # Safety
+/// `data` must point to `len` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn bt_bencode_to_json(data: *const u8, len: usize) -> *mut c_char {
+    let Some(value) = bytes(data, len).and_then(bencode::decode) else {
+        return ptr::null_mut();
+    };
+    CString::new(value.to_json().to_string()).map_or(ptr::null_mut(), CString::into_raw)
+}
+
+/// Writes the 20 byte info hash of a .torrent file's `len` bytes to `out`.
+///
+/// # Safety
+/// `data` must point to `len` readable bytes and `out` to 20 writable ones.
+#[no_mangle]
+pub unsafe extern "C" fn bt_info_hash(data: *const u8, len: usize, out: *mut u8) -> c_int {
+    let Some(info) = bytes(data, len).and_then(|data| bencode::raw_value(data, "info")) else {
+        return -1;
+    };
+    if out.is_null() {
+        return -1;
+    }
+    ptr::copy_nonoverlapping(sha1(info).as_ptr(), out, 20);
+    0
+}
+
+/// Parses `len` bytes of a .torrent file. Null if they aren't one.
+///
+/// # Safety
+/// `data` must point to `len` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn bt_torrent_parse(data: *const u8, len: usize) -> *mut BtTorrent {
+    let Some(metainfo) = bytes(data, len).and_then(Metainfo::from_bytes) else {
+        return ptr::null_mut();
+    };
+    let name = CString::new(metainfo.name.replace('\0', "")).unwrap_or_default();
+    Box::into_raw(Box::new(BtTorrent { metainfo, name }))
+}
+
+/// # Safety
+/// `torrent` must be null or from `bt_torrent_parse`, not freed before.
+#[no_mangle]
+pub unsafe extern "C" fn bt_torrent_free(torrent: *mut BtTorrent) {
+    if !torrent.is_null() {
+        drop(Box::from_raw(torrent));
+    }
+}
+
+/// The torrent's name, valid until the torrent is freed.
+///
+/// # Safety
+/// `torrent` must be from `bt_torrent_parse`.
+#[no_mangle]
+pub unsafe extern "C" fn bt_torrent_name(torrent: *const BtTorrent) -> *const c_char {
+    (*torrent).name.as_ptr()
+}
+
+/// Writes the torrent's 20 byte info hash to `out`.
+///
+/// # Safety
+/// `torrent` must be from `bt_torrent_parse` and `out` point to 20
+/// writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn bt_torrent_info_hash(torrent: *const BtTorrent, out: *mut u8) {
+    ptr::copy_nonoverlapping((*torrent).metainfo.info_hash.as_ptr(), out, 20);
+}
+
+/// # Safety
+/// `torrent` must be from `bt_torrent_parse`.
+#[no_mangle]
+pub unsafe extern "C" fn bt_torrent_total_length(torrent: *const BtTorrent) -> u64 {
+    (*torrent).metainfo.total_length()
+}
+
+/// # Safety
+/// `torrent` must be from `bt_torrent_parse`.
+#[no_mangle]
+pub unsafe extern "C" fn bt_torrent_num_pieces(torrent: *const BtTorrent) -> usize {
+    (*torrent).metainfo.num_pieces()
+}
+
+/// # Safety
+/// `torrent` must be from `bt_torrent_parse`.
+#[no_mangle]
+pub unsafe extern "C" fn bt_torrent_num_files(torrent: *const BtTorrent) -> usize {
+    (*torrent).metainfo.files.len()
+}
+
+/// Starts a session accepting peers on `port`, 0 for any. Null if it
+/// can't bind.
+#[no_mangle]
+pub extern "C" fn bt_session_start(port: u16) -> *mut BtSession {
+    let Ok(runtime) = Runtime::new() else {
+        return ptr::null_mut();
+    };
+    match runtime.block_on(Session::bind(("0.0.0.0", port))) {
+        Ok(session) => Box::into_raw(Box::new(BtSession { runtime, session })),
+        Err(_) => ptr::null_mut()
+    }
+}
+
+/// Starts downloading and seeding `torrent` into `download_dir`, carrying
+/// on from whatever is already there, which is hashed first. The torrent
+/// can be freed afterwards.
+///
+/// # Safety
+/// `session` must be from `bt_session_start`, `torrent` from
+/// `bt_torrent_parse`, and `download_dir` a C string.
+#[no_mangle]
+pub unsafe extern "C" fn bt_session_add(session: *mut BtSession, torrent: *const BtTorrent, download_dir: *const c_char) -> c_int {
+    let session = &mut *session;
+    let Some(dir) = text(download_dir) else {
+        return -1;
+    };
+    let Some(mut torrent) = Torrent::new((*torrent).metainfo.clone(), dir) else {
+        return -1;
+    };
+    torrent.recheck();
+    let _guard = session.runtime.enter();
+    status(session.session.add_torrent(torrent).is_some())
+}
+
+/// Gives a running torrent a peer to connect to, as `host:port`.
+///
+/// # Safety
+/// `session` must be from `bt_session_start`, `info_hash` point to 20
+/// readable bytes and `addr` be a C string.
+#[no_mangle]
+pub unsafe extern "C" fn bt_session_add_peer(session: *mut BtSession, info_hash: *const u8, addr: *const c_char) -> c_int {
+    let session = &*session;
+    let addr = text(addr).and_then(|addr| addr.to_socket_addrs().ok()?.find(SocketAddr::is_ipv4));
+    let (Some(info_hash), Some(addr)) = (self::info_hash(info_hash), addr) else {
+        return -1;
+    };
+    let Some(handle) = session.session.torrent(&info_hash) else {
+        return -1;
+    };
+    handle.add_peer(addr);
+    0
+}
+
+/// Fills in `out` with a running torrent's progress.
+///
+/// # Safety
+/// `session` must be from `bt_session_start`, `info_hash` point to 20
+/// readable bytes and `out` to a `BtStats`.
+#[no_mangle]
+pub unsafe extern "C" fn bt_session_stats(session: *const BtSession, info_hash: *const u8, out: *mut BtStats) -> c_int {
+    let session = &*session;
+    let Some(handle) = self::info_hash(info_hash).and_then(|info_hash| session.session.torrent(&info_hash)) else {
+        return -1;
+    };
+    let stats = handle.stats();
+    *out = BtStats {
+        bytes_done: stats.bytes_done,
+        bytes_total: stats.bytes_total,
+        uploaded: stats.uploaded,
+        downloaded: stats.downloaded,
+        upload_rate: stats.upload_rate,
+        download_rate: stats.download_rate,
+        connected_peers: stats.connected_peers as u32,
+        paused: handle.is_paused(),
+        queued: stats.queued
+    };
+    0
+}
+
+/// Stops every torrent, saving what they need to resume, and frees the
+/// session. 0 if they all stopped cleanly.
+///
+/// # Safety
+/// `session` must be from `bt_session_start`, not stopped before.
+#[no_mangle]
+pub unsafe extern "C" fn bt_session_stop(session: *mut BtSession) -> c_int {
+    let BtSession { runtime, session } = *Box::from_raw(session);
+    status(runtime.block_on(session.shutdown()))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::bencode::Value;
+    use crate::ffi::{
+        bt_bencode_to_json, bt_info_hash, bt_session_add, bt_session_start, bt_session_stats, bt_session_stop, bt_string_free,
+        bt_torrent_free, bt_torrent_info_hash, bt_torrent_name, bt_torrent_num_pieces, bt_torrent_parse, BtStats
+    };
+    use crate::hash::sha1;
+    use std::ffi::{CStr, CString};
+
+    #[test]
+    fn test_parsing() {
+        unsafe {
+            let json = bt_bencode_to_json(b"d3:cowi1ee".as_ptr(), 10);
+            assert_eq!(CStr::from_ptr(json).to_str(), Ok(r#"{"cow":1}"#));
+            bt_string_free(json);
+            assert!(bt_bencode_to_json(b"d3:cow".as_ptr(), 6).is_null());
+
+            let info = Value::dict([("name", "a.iso".into()), ("length", 4.into()), ("piece length", 4.into()), ("pieces", Value::from(&[0; 20][..]))]);
+            let file = Value::dict([("info", info.clone())]).encode();
+            let mut hash = [0; 20];
+            assert_eq!(bt_info_hash(file.as_ptr(), file.len(), hash.as_mut_ptr()), 0);
+            assert_eq!(hash, sha1(&info.encode()));
+
+            let torrent = bt_torrent_parse(file.as_ptr(), file.len());
+            assert_eq!(CStr::from_ptr(bt_torrent_name(torrent)).to_str(), Ok("a.iso"));
+            assert_eq!(bt_torrent_num_pieces(torrent), 1);
+            let mut from_torrent = [0; 20];
+            bt_torrent_info_hash(torrent, from_torrent.as_mut_ptr());
+            assert_eq!(from_torrent, hash);
+
+            let session = bt_session_start(0);
+            let dir = CString::new(std::env::temp_dir().join("bt-ffi-test").to_str().unwrap()).unwrap();
+            assert_eq!(bt_session_add(session, torrent, dir.as_ptr()), 0);
+            bt_torrent_free(torrent);
+            // The stats arrive once the torrent's task is up.
+            let mut stats = BtStats::default();
+            for _ in 0..100 {
+                assert_eq!(bt_session_stats(session, hash.as_ptr(), &mut stats), 0);
+                if stats.bytes_total > 0 {
+                    break;
+                }
+                std::thread::sleep(std::time::Duration::from_millis(10));
+            }
+            assert_eq!((stats.bytes_total, stats.bytes_done, stats.paused), (4, 0, false));
+            assert_eq!(bt_session_stats(session, [0; 20].as_ptr(), &mut stats), -1);
+            assert_eq!(bt_session_stop(session), 0);
+        }
+    }
+}
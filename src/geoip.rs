@@ -0,0 +1,81 @@
+use std::fmt;
+use std::io;
+use std::net::IpAddr;
+use std::path::Path;
+
+// Country lookups in a MaxMind database (GeoLite2 or GeoIP2 Country or
+// City). Reading the database needs the `geoip` feature; without it
+// opening one fails and nothing gets a country.
+pub struct GeoIp {
+    #[cfg(feature = "geoip")]
+    reader: maxminddb::Reader<Vec<u8>>
+}
+
+// The reader's own Debug would print the whole database.
+impl fmt::Debug for GeoIp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("GeoIp").finish_non_exhaustive()
+    }
+}
+
+impl GeoIp {
+    #[cfg(feature = "geoip")]
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let reader = maxminddb::Reader::open_readfile(path)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.to_string()))?;
+        Ok(Self { reader })
+    }
+
+    #[cfg(not(feature = "geoip"))]
+    pub fn open(_path: impl AsRef<Path>) -> io::Result<Self> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "built without the geoip feature"))
+    }
+
+    // The ISO 3166 code of the country `ip` is in.
+    #[cfg(feature = "geoip")]
+    pub fn country(&self, ip: IpAddr) -> Option<String> {
+        let country: maxminddb::geoip2::Country = self.reader.lookup(ip).ok()?;
+        Some(country.country?.iso_code?.to_string())
+    }
+
+    #[cfg(not(feature = "geoip"))]
+    pub fn country(&self, _ip: IpAddr) -> Option<String> {
+        None
+    }
+}
+
+#[cfg(all(test, feature = "geoip"))]
+mod test {
+    use crate::geoip::GeoIp;
+
+    // A one-node IPv4 tree: the lower half of the address space is in the
+    // Netherlands, the upper half nowhere.
+    fn database() -> Vec<u8> {
+        let mut db = vec![0, 0, 17, 0, 0, 1];
+        db.extend_from_slice(&[0; 16]);
+        db.extend_from_slice(b"\xe1\x47country\xe1\x48iso_code\x42NL");
+        db.extend_from_slice(b"\xab\xcd\xefMaxMind.com\xe9");
+        db.extend_from_slice(b"\x5bbinary_format_major_version\xa1\x02");
+        db.extend_from_slice(b"\x5bbinary_format_minor_version\xa0");
+        db.extend_from_slice(b"\x4bbuild_epoch\x00\x02");
+        db.extend_from_slice(b"\x4ddatabase_type\x44Test");
+        db.extend_from_slice(b"\x4bdescription\xe0");
+        db.extend_from_slice(b"\x4aip_version\xa1\x04");
+        db.extend_from_slice(b"\x49languages\x00\x04");
+        db.extend_from_slice(b"\x4anode_count\xc1\x01");
+        db.extend_from_slice(b"\x4brecord_size\xa1\x18");
+        db
+    }
+
+    #[test]
+    fn test_country() {
+        let path = std::env::temp_dir().join(format!("geoip-{}.mmdb", std::process::id()));
+        std::fs::write(&path, database()).unwrap();
+        let geoip = GeoIp::open(&path).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        assert_eq!(geoip.country("10.1.2.3".parse().unwrap()).as_deref(), Some("NL"));
+        assert_eq!(geoip.country("200.1.2.3".parse().unwrap()), None);
+        assert!(GeoIp::open("/nonexistent.mmdb").is_err());
+    }
+}
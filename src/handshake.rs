@@ -0,0 +1,127 @@
+use std::io::{self, Read, Write};
+use std::time::Duration;
+
+// How long a new connection gets to say who it is, by its handshake or,
+// for the HTTP servers, its request.
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+pub const PROTOCOL: &[u8; 19] = b"BitTorrent protocol";
+pub const HANDSHAKE_LEN: usize = 68;
+// Reserved bit for peers running a DHT node (BEP 5).
+const DHT_BIT: (usize, u8) = (7, 0x01);
+// Reserved bit for peers speaking the extension protocol (BEP 10).
+const EXTENSION_BIT: (usize, u8) = (5, 0x10);
+// Reserved bit for peers that know BitTorrent v2 (BEP 52).
+const V2_BIT: (usize, u8) = (7, 0x10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Handshake {
+    pub reserved: [u8; 8],
+    pub info_hash: [u8; 20],
+    pub peer_id: [u8; 20]
+}
+
+impl Handshake {
+    pub fn new(info_hash: [u8; 20], peer_id: [u8; 20]) -> Self {
+        Self { reserved: [0; 8], info_hash, peer_id }
+    }
+
+    pub fn with_dht(mut self) -> Self {
+        self.reserved[DHT_BIT.0] |= DHT_BIT.1;
+        self
+    }
+
+    pub fn supports_dht(&self) -> bool {
+        self.reserved[DHT_BIT.0] & DHT_BIT.1 != 0
+    }
+
+    pub fn with_extensions(mut self) -> Self {
+        self.reserved[EXTENSION_BIT.0] |= EXTENSION_BIT.1;
+        self
+    }
+
+    pub fn supports_extensions(&self) -> bool {
+        self.reserved[EXTENSION_BIT.0] & EXTENSION_BIT.1 != 0
+    }
+
+    pub fn with_v2(mut self) -> Self {
+        self.reserved[V2_BIT.0] |= V2_BIT.1;
+        self
+    }
+
+    pub fn supports_v2(&self) -> bool {
+        self.reserved[V2_BIT.0] & V2_BIT.1 != 0
+    }
+
+    pub fn to_bytes(&self) -> [u8; HANDSHAKE_LEN] {
+        let mut bytes = [0; HANDSHAKE_LEN];
+        bytes[0] = PROTOCOL.len() as u8;
+        bytes[1..20].copy_from_slice(PROTOCOL);
+        bytes[20..28].copy_from_slice(&self.reserved);
+        bytes[28..48].copy_from_slice(&self.info_hash);
+        bytes[48..68].copy_from_slice(&self.peer_id);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != HANDSHAKE_LEN || bytes[0] as usize != PROTOCOL.len() || &bytes[1..20] != PROTOCOL {
+            return None;
+        }
+
+        Some(Self {
+            reserved: bytes[20..28].try_into().ok()?,
+            info_hash: bytes[28..48].try_into().ok()?,
+            peer_id: bytes[48..68].try_into().ok()?
+        })
+    }
+
+    pub fn read_from(reader: &mut impl Read) -> io::Result<Self> {
+        let mut bytes = [0; HANDSHAKE_LEN];
+        reader.read_exact(&mut bytes)?;
+        Self::from_bytes(&bytes)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid handshake"))
+    }
+
+    pub fn write_to(&self, writer: &mut impl Write) -> io::Result<()> {
+        writer.write_all(&self.to_bytes())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::handshake::Handshake;
+
+    #[test]
+    fn test_roundtrip() {
+        let handshake = Handshake::new([1; 20], *b"-BR0100-abcdefghijkl");
+        let bytes = handshake.to_bytes();
+
+        assert_eq!(&bytes[..20], b"\x13BitTorrent protocol");
+        assert_eq!(Handshake::from_bytes(&bytes), Some(handshake));
+        assert_eq!(Handshake::from_bytes(&bytes[..67]), None);
+
+        let mut bad = bytes;
+        bad[1] = b'b';
+        assert_eq!(Handshake::from_bytes(&bad), None);
+    }
+
+    #[test]
+    fn test_dht_bit() {
+        let handshake = Handshake::new([1; 20], [2; 20]);
+        assert!(!handshake.supports_dht());
+        let bytes = handshake.with_dht().to_bytes();
+        assert_eq!(bytes[27], 0x01);
+        assert!(Handshake::from_bytes(&bytes).unwrap().supports_dht());
+    }
+
+    #[test]
+    fn test_extension_bit() {
+        let handshake = Handshake::new([1; 20], [2; 20]);
+        assert!(!handshake.supports_extensions());
+        let bytes = handshake.with_extensions().with_dht().to_bytes();
+        assert_eq!(bytes[25], 0x10);
+        let parsed = Handshake::from_bytes(&bytes).unwrap();
+        assert!(parsed.supports_extensions() && parsed.supports_dht());
+        assert!(!parsed.supports_v2());
+        assert_eq!(handshake.with_v2().with_dht().to_bytes()[27], 0x11);
+    }
+}
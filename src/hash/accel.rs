@@ -0,0 +1,124 @@
+// Piece hashing is what the CPU spends a fast download on, so SHA-1 runs
+// on the hashing instructions where the CPU has them, as found at run time.
+// On x86 the `sha1` crate already takes SHA-NI when it's there; on ARMv8
+// it only has portable code, so the crypto extension is driven here.
+
+type Compress = fn(&mut [u32; 5], &[[u8; 64]]);
+
+const INITIAL: [u32; 5] = [0x6745_2301, 0xefcd_ab89, 0x98ba_dcfe, 0x1032_5476, 0xc3d2_e1f0];
+
+// What hashing runs on: "sha-ni", "armv8" or "portable".
+pub fn backend() -> &'static str {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    if is_x86_feature_detected!("sha") && is_x86_feature_detected!("ssse3") && is_x86_feature_detected!("sse4.1") {
+        return "sha-ni";
+    }
+    #[cfg(target_arch = "aarch64")]
+    if std::arch::is_aarch64_feature_detected!("sha2") {
+        return "armv8";
+    }
+    "portable"
+}
+
+pub fn sha1(data: &[u8]) -> [u8; 20] {
+    #[cfg(target_arch = "aarch64")]
+    if std::arch::is_aarch64_feature_detected!("sha2") {
+        return digest(armv8::compress, data);
+    }
+    use sha1::{Digest, Sha1};
+    Sha1::digest(data).into()
+}
+
+// SHA-1 around a compression function: the data's whole blocks, then the
+// rest padded out with its length in bits.
+#[cfg_attr(not(any(test, target_arch = "aarch64")), allow(dead_code))]
+fn digest(compress: Compress, data: &[u8]) -> [u8; 20] {
+    let mut state = INITIAL;
+    let (blocks, rest) = data.as_chunks::<64>();
+    compress(&mut state, blocks);
+
+    let mut tail = [0; 128];
+    tail[..rest.len()].copy_from_slice(rest);
+    tail[rest.len()] = 0x80;
+    let len = if rest.len() < 56 { 64 } else { 128 };
+    tail[len - 8..len].copy_from_slice(&(data.len() as u64 * 8).to_be_bytes());
+    compress(&mut state, tail[..len].as_chunks::<64>().0);
+
+    let mut hash = [0; 20];
+    for (bytes, word) in hash.chunks_exact_mut(4).zip(state) {
+        bytes.copy_from_slice(&word.to_be_bytes());
+    }
+    hash
+}
+
+#[cfg(target_arch = "aarch64")]
+mod armv8 {
+    use std::arch::aarch64::*;
+
+    const K: [u32; 4] = [0x5a82_7999, 0x6ed9_eba1, 0x8f1b_bcdc, 0xca62_c1d6];
+
+    pub(super) fn compress(state: &mut [u32; 5], blocks: &[[u8; 64]]) {
+        // SAFETY: only called once `sha2`, which brings the SHA-1
+        // instructions with it, has been detected.
+        unsafe { rounds(state, blocks) }
+    }
+
+    // Four rounds at a time, with the message schedule four words ahead.
+    #[target_feature(enable = "sha2")]
+    unsafe fn rounds(state: &mut [u32; 5], blocks: &[[u8; 64]]) {
+        let mut abcd = vld1q_u32(state.as_ptr());
+        let mut e = state[4];
+        for block in blocks {
+            let (start_abcd, start_e) = (abcd, e);
+            let mut w = [0, 1, 2, 3].map(|i| vreinterpretq_u32_u8(vrev32q_u8(vld1q_u8(block.as_ptr().add(16 * i)))));
+            for i in 0..20 {
+                let wk = vaddq_u32(w[i % 4], vdupq_n_u32(K[i / 5]));
+                let next_e = vsha1h_u32(vgetq_lane_u32(abcd, 0));
+                abcd = match i / 5 {
+                    0 => vsha1cq_u32(abcd, e, wk),
+                    2 => vsha1mq_u32(abcd, e, wk),
+                    _ => vsha1pq_u32(abcd, e, wk)
+                };
+                e = next_e;
+                if i < 16 {
+                    let scheduled = vsha1su0q_u32(w[i % 4], w[(i + 1) % 4], w[(i + 2) % 4]);
+                    w[i % 4] = vsha1su1q_u32(scheduled, w[(i + 3) % 4]);
+                }
+            }
+            abcd = vaddq_u32(abcd, start_abcd);
+            e = e.wrapping_add(start_e);
+        }
+        vst1q_u32(state.as_mut_ptr(), abcd);
+        state[4] = e;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::hash::accel::{backend, digest, sha1, Compress};
+    use sha1::{Digest, Sha1};
+
+    fn portable(state: &mut [u32; 5], blocks: &[[u8; 64]]) {
+        let blocks: Vec<_> = blocks.iter().map(|block| (*block).into()).collect();
+        sha1::compress(state, &blocks);
+    }
+
+    fn check(compress: Compress) {
+        let data: Vec<u8> = (0..300u32).map(|i| (i * 7) as u8).collect();
+        // Every way the padding can fall, around one block and two.
+        for len in 0..data.len() {
+            assert_eq!(digest(compress, &data[..len]), <[u8; 20]>::from(Sha1::digest(&data[..len])), "{} bytes", len);
+        }
+    }
+
+    #[test]
+    fn test_digest() {
+        check(portable);
+        assert_eq!(crate::hash::hex(&sha1(b"abc")), "a9993e364706816aba3e25717850c26c9cd0d89d");
+        assert!(["sha-ni", "armv8", "portable"].contains(&backend()));
+        #[cfg(target_arch = "aarch64")]
+        if std::arch::is_aarch64_feature_detected!("sha2") {
+            check(crate::hash::accel::armv8::compress);
+        }
+    }
+}
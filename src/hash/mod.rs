@@ -0,0 +1,195 @@
+pub mod accel;
+
+pub use accel::sha1;
+
+#[cfg(feature = "metainfo")]
+use crate::merkle::PieceRoot;
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// The inverse of `hex`.
+pub fn unhex_bytes(text: &str) -> Option<Vec<u8>> {
+    // from_str_radix would take a sign too.
+    if !text.len().is_multiple_of(2) || !text.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return None;
+    }
+    (0..text.len()).step_by(2).map(|at| u8::from_str_radix(&text[at..at + 2], 16).ok()).collect()
+}
+
+// `unhex_bytes` for exactly `N` bytes.
+pub fn unhex<const N: usize>(text: &str) -> Option<[u8; N]> {
+    unhex_bytes(text)?.try_into().ok()
+}
+
+// Not cryptographically strong, but unpredictable enough for node ids,
+// transaction ids and tokens without pulling in a random number crate.
+#[cfg_attr(not(any(feature = "engine", feature = "dht", feature = "tracker-udp")), allow(dead_code))]
+pub(crate) fn random_bytes<const N: usize>() -> [u8; N] {
+    let mut bytes = [0; N];
+    for (i, chunk) in bytes.chunks_mut(8).enumerate() {
+        let random = RandomState::new().hash_one(i).to_be_bytes();
+        chunk.copy_from_slice(&random[..chunk.len()]);
+    }
+    bytes
+}
+
+pub fn crc32c(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = match crc & 1 {
+                1 => (crc >> 1) ^ 0x82f6_3b78,
+                _ => crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+// What a piece must hash to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PieceHash {
+    Sha1([u8; 20]),
+    // A v2-only torrent's (BEP 52).
+    #[cfg(feature = "metainfo")]
+    Merkle(PieceRoot)
+}
+
+impl PieceHash {
+    pub fn matches(&self, data: &[u8]) -> bool {
+        match self {
+            Self::Sha1(hash) => sha1(data) == *hash,
+            #[cfg(feature = "metainfo")]
+            Self::Merkle(root) => root.matches(data)
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HashJob {
+    pub piece: u32,
+    pub data: Vec<u8>,
+    pub expected: PieceHash
+}
+
+// The data comes back so a verified piece can be written without a copy.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HashResult {
+    pub piece: u32,
+    pub data: Vec<u8>,
+    pub valid: bool
+}
+
+// Verifies completed pieces on worker threads so hashing a large piece never
+// holds up socket reads. At most `queue_len` pieces wait for a worker; past
+// that `try_submit` hands the job back and the caller should stop requesting
+// blocks for a while.
+pub struct HashPool {
+    jobs: Option<SyncSender<HashJob>>,
+    results: Receiver<HashResult>,
+    workers: Vec<JoinHandle<()>>
+}
+
+impl HashPool {
+    pub fn new(workers: usize, queue_len: usize) -> Self {
+        let (jobs, queue) = mpsc::sync_channel::<HashJob>(queue_len);
+        let (done, results) = mpsc::channel();
+        let queue = Arc::new(Mutex::new(queue));
+
+        let workers = (0..workers.max(1))
+            .map(|_| {
+                let queue = Arc::clone(&queue);
+                let done = done.clone();
+                thread::spawn(move || loop {
+                    // The lock is only held while waiting for the next job.
+                    let job = match queue.lock().unwrap().recv() {
+                        Ok(job) => job,
+                        Err(_) => return
+                    };
+                    let valid = job.expected.matches(&job.data);
+                    if done.send(HashResult { piece: job.piece, data: job.data, valid }).is_err() {
+                        return;
+                    }
+                })
+            })
+            .collect();
+        Self { jobs: Some(jobs), results, workers }
+    }
+
+    // One worker per core.
+    pub fn with_available_parallelism(queue_len: usize) -> Self {
+        Self::new(thread::available_parallelism().map_or(1, usize::from), queue_len)
+    }
+
+    pub fn try_submit(&self, job: HashJob) -> Result<(), HashJob> {
+        let jobs = self.jobs.as_ref().expect("only taken on drop");
+        match jobs.try_send(job) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(job) | TrySendError::Disconnected(job)) => Err(job)
+        }
+    }
+
+    // Waits for room in the queue.
+    pub fn submit(&self, job: HashJob) {
+        let jobs = self.jobs.as_ref().expect("only taken on drop");
+        let _ = jobs.send(job);
+    }
+
+    // Results that are ready, without waiting.
+    pub fn results(&self) -> impl Iterator<Item = HashResult> + '_ {
+        self.results.try_iter()
+    }
+
+    pub fn wait_result(&self) -> Option<HashResult> {
+        self.results.recv().ok()
+    }
+}
+
+impl Drop for HashPool {
+    fn drop(&mut self) {
+        drop(self.jobs.take());
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::hash::{hex, sha1, unhex, unhex_bytes, HashJob, HashPool, PieceHash};
+
+    #[test]
+    fn test_hex() {
+        assert_eq!(hex(&[0x00, 0xab, 0x7f]), "00ab7f");
+        assert_eq!(unhex::<3>("00AB7f"), Some([0x00, 0xab, 0x7f]));
+        assert_eq!(unhex::<3>("00ab7"), None);
+        assert_eq!(unhex::<2>("0g11"), None);
+        assert_eq!(unhex::<2>("+1+1"), None);
+        assert_eq!(unhex_bytes(""), Some(Vec::new()));
+        assert_eq!(unhex_bytes("abc"), None);
+    }
+
+    #[test]
+    fn test_pool() {
+        let pool = HashPool::new(2, 4);
+        for piece in 0..4u32 {
+            let data = vec![piece as u8; 1000];
+            let expected = PieceHash::Sha1(if piece == 2 { [0; 20] } else { sha1(&data) });
+            pool.submit(HashJob { piece, data, expected });
+        }
+
+        let mut results: Vec<_> = (0..4).map(|_| pool.wait_result().unwrap()).collect();
+        results.sort_by_key(|result| result.piece);
+        assert_eq!(results.iter().map(|result| result.valid).collect::<Vec<_>>(), vec![true, true, false, true]);
+        assert_eq!(results[3].data, vec![3; 1000]);
+        assert_eq!(pool.results().count(), 0);
+    }
+}
@@ -0,0 +1,254 @@
+use flate2::read::GzDecoder;
+use std::io::{self, Read};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::Path;
+use std::sync::Arc;
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+// eMule levels below this block; the rest explicitly allow.
+const DAT_ALLOW_LEVEL: u32 = 128;
+
+// Why an address is blocked: the range it fell into, the description the
+// list gave it and the list it came from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockReason {
+    pub start: IpAddr,
+    pub end: IpAddr,
+    pub description: Option<String>,
+    pub list: Arc<str>
+}
+
+#[derive(Debug, Clone)]
+struct Rule {
+    start: u128,
+    end: u128,
+    description: Option<String>,
+    list: Arc<str>
+}
+
+// Address ranges to refuse peers from, loaded from blocklists. IPv4
+// addresses live in the IPv4-mapped part of the IPv6 space so one sorted
+// table covers both.
+#[derive(Debug, Clone, Default)]
+pub struct IpFilter {
+    // Sorted by start, with the largest end among each rule and those
+    // before it, so lookups can stop early despite overlapping ranges.
+    rules: Vec<(Rule, u128)>
+}
+
+fn key(ip: IpAddr) -> u128 {
+    match ip {
+        IpAddr::V4(ip) => u128::from(ip.to_ipv6_mapped()),
+        IpAddr::V6(ip) => u128::from(ip)
+    }
+}
+
+fn ip(key: u128) -> IpAddr {
+    let ip = Ipv6Addr::from(key);
+    ip.to_ipv4_mapped().map_or(IpAddr::V6(ip), IpAddr::V4)
+}
+
+// eMule lists pad IPv4 octets with zeros, which the std parser refuses.
+fn parse_ip(text: &str) -> Option<IpAddr> {
+    let text = text.trim();
+    if let Ok(ip) = text.parse() {
+        return Some(ip);
+    }
+    let octets: Vec<u8> = text.split('.').map(|octet| octet.parse().ok()).collect::<Option<_>>()?;
+    let octets: [u8; 4] = octets.try_into().ok()?;
+    Some(IpAddr::V4(Ipv4Addr::from(octets)))
+}
+
+fn parse_range(text: &str) -> Option<(IpAddr, IpAddr)> {
+    if let Some((start, end)) = text.split_once('-') {
+        let (start, end) = (parse_ip(start)?, parse_ip(end)?);
+        return (start.is_ipv4() == end.is_ipv4() && key(start) <= key(end)).then_some((start, end));
+    }
+    let (addr, prefix) = match text.split_once('/') {
+        Some((addr, prefix)) => (parse_ip(addr)?, prefix.trim().parse::<u32>().ok()?),
+        None => {
+            let addr = parse_ip(text)?;
+            (addr, if addr.is_ipv4() { 32 } else { 128 })
+        }
+    };
+    let bits = if addr.is_ipv4() { 32 } else { 128 };
+    if prefix > bits {
+        return None;
+    }
+    let host_bits = bits - prefix;
+    let mask = u128::MAX.checked_shl(host_bits).unwrap_or(0);
+    let start = key(addr) & mask;
+    Some((ip(start), ip(start | !mask)))
+}
+
+// One line in any of the supported formats: a CIDR block, an address or
+// a range; eMule's `start - end , level , description`; or PeerGuardian's
+// `description:start-end`.
+fn parse_line(line: &str) -> Option<(IpAddr, IpAddr, Option<&str>)> {
+    fn description(text: &str) -> Option<&str> {
+        Some(text.trim()).filter(|text| !text.is_empty())
+    }
+    let mut fields = line.splitn(3, ',');
+    if let (Some(range), Some(level)) = (fields.next(), fields.next()) {
+        if let Ok(level) = level.trim().parse::<u32>() {
+            let (start, end) = parse_range(range.trim())?;
+            return (level < DAT_ALLOW_LEVEL).then(|| (start, end, fields.next().and_then(description)));
+        }
+    }
+    if let Some((start, end)) = parse_range(line) {
+        return Some((start, end, None));
+    }
+    // IPv6 addresses have colons too, so try each one as the separator.
+    line.match_indices(':').find_map(|(at, _)| {
+        let (start, end) = parse_range(&line[at + 1..])?;
+        Some((start, end, description(&line[..at])))
+    })
+}
+
+impl IpFilter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Adds the ranges in `text`, naming them after `list`. Blank lines and
+    // comments (`#`, `//`) are skipped, as are lines that don't parse, since
+    // published lists are rarely clean. Returns the number of ranges added.
+    pub fn add_list(&mut self, text: &str, list: &str) -> usize {
+        let list: Arc<str> = list.into();
+        let mut rules: Vec<Rule> = self.rules.drain(..).map(|(rule, _)| rule).collect();
+        let before = rules.len();
+        for line in text.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') || line.starts_with("//") {
+                continue;
+            }
+            if let Some((start, end, description)) = parse_line(line) {
+                rules.push(Rule { start: key(start), end: key(end), description: description.map(str::to_string), list: list.clone() });
+            }
+        }
+        let added = rules.len() - before;
+        self.index(rules);
+        added
+    }
+
+    // Reads a blocklist file, gzipped or not.
+    pub fn load(&mut self, path: impl AsRef<Path>) -> io::Result<usize> {
+        let path = path.as_ref();
+        let bytes = std::fs::read(path)?;
+        let text = match bytes.starts_with(&GZIP_MAGIC) {
+            true => {
+                let mut text = String::new();
+                GzDecoder::new(&bytes[..]).read_to_string(&mut text)?;
+                text
+            },
+            false => String::from_utf8_lossy(&bytes).into_owned()
+        };
+        Ok(self.add_list(&text, &path.display().to_string()))
+    }
+
+    fn index(&mut self, mut rules: Vec<Rule>) {
+        rules.sort_by_key(|rule| (rule.start, rule.end));
+        let mut max_end = 0;
+        self.rules = rules
+            .into_iter()
+            .map(|rule| {
+                max_end = max_end.max(rule.end);
+                (rule, max_end)
+            })
+            .collect();
+    }
+
+    pub fn len(&self) -> usize {
+        self.rules.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.rules.clear();
+    }
+
+    pub fn is_blocked(&self, ip: IpAddr) -> bool {
+        self.blocked_by(ip).is_some()
+    }
+
+    // The first range, by start, that covers `ip`.
+    pub fn blocked_by(&self, ip: IpAddr) -> Option<BlockReason> {
+        let key = key(ip);
+        let candidates = self.rules.partition_point(|(rule, _)| rule.start <= key);
+        self.rules[..candidates]
+            .iter()
+            .rev()
+            .take_while(|(_, max_end)| *max_end >= key)
+            .filter(|(rule, _)| rule.end >= key)
+            .last()
+            .map(|(rule, _)| BlockReason {
+                start: self::ip(rule.start),
+                end: self::ip(rule.end),
+                description: rule.description.clone(),
+                list: rule.list.clone()
+            })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::ipfilter::{parse_line, parse_range, IpFilter};
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+    use std::net::IpAddr;
+
+    fn ip(text: &str) -> IpAddr {
+        text.parse().unwrap()
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse_range("10.0.0.0/8"), Some((ip("10.0.0.0"), ip("10.255.255.255"))));
+        assert_eq!(parse_range("1.2.3.4"), Some((ip("1.2.3.4"), ip("1.2.3.4"))));
+        assert_eq!(parse_range("0.0.0.0/0"), Some((ip("0.0.0.0"), ip("255.255.255.255"))));
+        assert_eq!(parse_range("2001:db8::/32"), Some((ip("2001:db8::"), ip("2001:db8:ffff:ffff:ffff:ffff:ffff:ffff"))));
+        assert_eq!(parse_range("10.0.0.0/33"), None);
+        assert_eq!(parse_range("1.2.3.9-1.2.3.1"), None);
+
+        assert_eq!(
+            parse_line("001.002.003.000 - 001.002.003.255 , 000 , Some Org, Inc"),
+            Some((ip("1.2.3.0"), ip("1.2.3.255"), Some("Some Org, Inc")))
+        );
+        assert_eq!(parse_line("001.002.003.000 - 001.002.003.255 , 200 , Friends"), None);
+        assert_eq!(parse_line("Bad Corp:5.6.7.0-5.6.7.255"), Some((ip("5.6.7.0"), ip("5.6.7.255"), Some("Bad Corp"))));
+        assert_eq!(parse_line("Bad v6:2001:db8::1-2001:db8::ff"), Some((ip("2001:db8::1"), ip("2001:db8::ff"), Some("Bad v6"))));
+        assert_eq!(parse_line("not an address"), None);
+    }
+
+    #[test]
+    fn test_filter() {
+        let mut filter = IpFilter::new();
+        let added = filter.add_list("# comment\n10.0.0.0/8\nWide:1.0.0.0-1.255.255.255\nNarrow:1.2.0.0-1.2.0.255\ngarbage\n", "local");
+        assert_eq!(added, 3);
+        assert!(filter.is_blocked(ip("10.1.2.3")));
+        assert!(!filter.is_blocked(ip("11.0.0.0")));
+        assert!(!filter.is_blocked(ip("::ffff:b00:0")));
+
+        // Both cover it; the wide one starts first.
+        let reason = filter.blocked_by(ip("1.2.0.7")).unwrap();
+        assert_eq!((reason.start, reason.description.as_deref(), &*reason.list), (ip("1.0.0.0"), Some("Wide"), "local"));
+        // Only the wide one covers this, though the narrow one starts later.
+        assert_eq!(filter.blocked_by(ip("1.3.0.0")).unwrap().description.as_deref(), Some("Wide"));
+    }
+
+    #[test]
+    fn test_load_gzip() {
+        let path = std::env::temp_dir().join(format!("blocklist-{}.p2p.gz", std::process::id()));
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(b"Someone:9.9.9.0-9.9.9.255\n").unwrap();
+        std::fs::write(&path, encoder.finish().unwrap()).unwrap();
+
+        let mut filter = IpFilter::new();
+        assert_eq!(filter.load(&path).unwrap(), 1);
+        assert_eq!(&*filter.blocked_by(ip("9.9.9.9")).unwrap().list, path.display().to_string());
+        std::fs::remove_file(path).unwrap();
+    }
+}
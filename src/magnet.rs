@@ -0,0 +1,124 @@
+use crate::hash::unhex;
+use std::net::{SocketAddr, ToSocketAddrs};
+
+const SCHEME: &str = "magnet:?";
+const BTIH: &str = "urn:btih:";
+
+// A magnet link (BEP 9): enough to find a torrent's swarm and fetch its
+// metadata from it, but not the metadata itself.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Magnet {
+    pub info_hash: [u8; 20],
+    // The display name, until the real one arrives with the metadata.
+    pub name: Option<String>,
+    pub trackers: Vec<String>,
+    // Peers given directly with `x.pe`.
+    pub peers: Vec<SocketAddr>
+}
+
+fn percent_decode(text: &str) -> Option<String> {
+    let bytes = text.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut at = 0;
+    while at < bytes.len() {
+        match bytes[at] {
+            b'%' => {
+                let [byte] = unhex::<1>(std::str::from_utf8(bytes.get(at + 1..at + 3)?).ok()?)?;
+                out.push(byte);
+                at += 3;
+            },
+            b'+' => {
+                out.push(b' ');
+                at += 1;
+            },
+            byte => {
+                out.push(byte);
+                at += 1;
+            }
+        }
+    }
+    String::from_utf8(out).ok()
+}
+
+// The older 32 character form of an info hash (RFC 4648 base32).
+fn base32_digits(text: &str) -> Option<[u8; 20]> {
+    if text.len() != 32 {
+        return None;
+    }
+    let mut out = Vec::with_capacity(20);
+    let (mut buffer, mut bits) = (0u64, 0);
+    for ch in text.bytes() {
+        let value = match ch.to_ascii_uppercase() {
+            ch @ b'A'..=b'Z' => ch - b'A',
+            ch @ b'2'..=b'7' => ch - b'2' + 26,
+            _ => return None
+        };
+        buffer = buffer << 5 | u64::from(value);
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((buffer >> bits) as u8);
+        }
+    }
+    out.try_into().ok()
+}
+
+impl Magnet {
+    pub fn parse(uri: &str) -> Option<Self> {
+        let query = uri.strip_prefix(SCHEME)?;
+        let mut info_hash = None;
+        let mut magnet = Self { info_hash: [0; 20], name: None, trackers: Vec::new(), peers: Vec::new() };
+        for (key, value) in query.split('&').filter_map(|pair| pair.split_once('=')) {
+            let value = percent_decode(value)?;
+            match key {
+                // Links can carry other hashes too, such as v2's btmh.
+                "xt" => {
+                    if let Some(hash) = value.strip_prefix(BTIH) {
+                        info_hash = Some(unhex(hash).or_else(|| base32_digits(hash))?);
+                    }
+                },
+                "dn" => magnet.name = Some(value),
+                "tr" => magnet.trackers.push(value),
+                // Peers that don't resolve are as good as absent.
+                "x.pe" => magnet.peers.extend(value.to_socket_addrs().ok().and_then(|mut addrs| addrs.next())),
+                _ => {}
+            }
+        }
+        magnet.info_hash = info_hash?;
+        Some(magnet)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::magnet::Magnet;
+
+    #[test]
+    fn test_parse() {
+        let magnet = Magnet::parse(
+            "magnet:?xt=urn:btih:d69f91e6b2ae4c542468d1073a71d4ea13879a7f&dn=sample.torrent\
+             &tr=http%3A%2F%2Fbittorrent-test-tracker.codecrafters.io%2Fannounce&x.pe=127.0.0.1:6881"
+        )
+        .unwrap();
+        assert_eq!(&magnet.info_hash[..4], [0xd6, 0x9f, 0x91, 0xe6]);
+        assert_eq!(magnet.name.as_deref(), Some("sample.torrent"));
+        assert_eq!(magnet.trackers, ["http://bittorrent-test-tracker.codecrafters.io/announce"]);
+        assert_eq!(magnet.peers, ["127.0.0.1:6881".parse().unwrap()]);
+    }
+
+    #[test]
+    fn test_base32() {
+        let hex = Magnet::parse("magnet:?xt=urn:btih:c12fe1c06bba254a9dc9f519b335aa7c1367a88a").unwrap();
+        let base32 = Magnet::parse("magnet:?dn=Some+Name&xt=urn:btih:YEX6DQDLXISUVHOJ6UM3GNNKPQJWPKEK").unwrap();
+        assert_eq!(hex.info_hash, base32.info_hash);
+        assert_eq!(base32.name.as_deref(), Some("Some Name"));
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert_eq!(Magnet::parse("http://example.com/?xt=urn:btih:c12fe1c06bba254a9dc9f519b335aa7c1367a88a"), None);
+        assert_eq!(Magnet::parse("magnet:?dn=nothing"), None);
+        assert_eq!(Magnet::parse("magnet:?xt=urn:btih:c12fe1c06bba"), None);
+        assert_eq!(Magnet::parse("magnet:?xt=urn:btih:c12fe1c06bba254a9dc9f519b335aa7c1367a88a&dn=%zz"), None);
+    }
+}
@@ -0,0 +1,1746 @@
+mod progress;
+#[cfg(feature = "tui")]
+mod tui;
+
+use crate::progress::{bytes, Progress};
+#[cfg(feature = "tui")]
+use crate::tui::App;
+use bittorrent_rs::bencode::{self, Value};
+use bittorrent_rs::bitfield::Bitfield;
+use bittorrent_rs::dht::bootstrap::DEFAULT_ROUTERS;
+use bittorrent_rs::dht::item::{mutable_target, Item, MutableItem};
+use bittorrent_rs::dht::{Dht, NodeId};
+use bittorrent_rs::engine::control::{self, Request};
+use bittorrent_rs::engine::peer::{self as peer_wire, connect, connect_extended, PeerProbe};
+use bittorrent_rs::engine::rate::parse_rate;
+use bittorrent_rs::engine::torrent::{Shared, TorrentOptions};
+use bittorrent_rs::engine::watch::AfterAdd;
+use bittorrent_rs::engine::{Alert, ControlServer, ListenPort, SeedGoal, Session, TorrentHandle, WatchFolder};
+use bittorrent_rs::extension::{ExtendedHandshake, UT_METADATA};
+use bittorrent_rs::geoip::GeoIp;
+use bittorrent_rs::handshake::Handshake;
+use bittorrent_rs::hash::{hex, unhex};
+use bittorrent_rs::magnet::Magnet;
+use bittorrent_rs::metainfo::{Limits, Metainfo};
+use bittorrent_rs::peer_id;
+use bittorrent_rs::storage::fastresume::FastResume;
+use bittorrent_rs::storage::journal;
+use bittorrent_rs::storage::memory::MemoryStorage;
+use bittorrent_rs::storage::migrate::{Client, Found, Migration};
+use bittorrent_rs::storage::resume::ResumeData;
+use bittorrent_rs::storage::selection::FileSelection;
+use bittorrent_rs::storage::FileStorage;
+use bittorrent_rs::torrent::Torrent;
+use bittorrent_rs::tracker::{self, Announce, AnnounceEvent, Tracker};
+use clap::{Parser, Subcommand, ValueEnum};
+use serde_json::json;
+use std::collections::{HashSet, VecDeque};
+use std::env;
+use std::fs;
+use std::io::{self, Read, Write};
+#[cfg(feature = "tui")]
+use std::io::IsTerminal;
+use std::net::{Ipv4Addr, SocketAddr, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use tokio::net::TcpListener;
+use tokio::runtime::Runtime;
+use tokio::sync::Semaphore;
+#[cfg(feature = "tui")]
+use tokio::sync::mpsc;
+use tokio::time;
+use tracing_subscriber::EnvFilter;
+
+const AFTER_HELP: &str = "Logs go to stderr, or to --log-file. -v logs more, up to -vvv for every
+message sent to or received from each peer, and -q only errors. Without
+either, RUST_LOG chooses what is logged, for example
+RUST_LOG=bittorrent_rs::dht=debug (the default is warn).
+
+Errors are one line, error[kind]: message, on stderr (or a JSON object
+on stdout with --output json). The exit status says which kind:
+  1 error        anything else
+  2 usage        bad arguments
+  3 parse        a torrent, magnet link, bencode or JSON that doesn't parse,
+                 or a torrent with absurd pieces or over --max-size
+  4 network      a peer, the DHT or the daemon is unreachable
+  5 no-peers     nobody to download from
+  6 hash         data that doesn't match its piece hashes
+  7 disk         a file that can't be read or written
+  130 interrupted
+
+Torrents with fewer than 16 KiB or more than 256 MiB to a piece, or
+millions of pieces, are refused before anything is written, as are those
+over --max-size; --force takes them anyway.";
+
+// Long enough to never lapse while the piece is still on its way.
+const PIECE_DEADLINE: Duration = Duration::from_secs(24 * 3600);
+
+// The id we ask peers to send `ut_metadata` messages with.
+const OUR_UT_METADATA: u8 = 1;
+
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
+// Set once from `--output`, and read wherever output is printed.
+static JSON: AtomicBool = AtomicBool::new(false);
+
+// Set once from `--max-size` unless `--force` is given; torrents outside
+// these are refused wherever they're read.
+static LIMITS: OnceLock<Limits> = OnceLock::new();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Format {
+    Text,
+    // One JSON document on stdout per command, errors included.
+    Json
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Added {
+    // Leave it, to be added again on the next start.
+    Keep,
+    // Append `.added` to its name.
+    Rename,
+    // Move it into an `added` subdirectory.
+    Move
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Event {
+    Started,
+    Stopped,
+    Completed
+}
+
+#[derive(Parser)]
+#[command(version, about = "A BitTorrent client", after_help = AFTER_HELP)]
+struct Cli {
+    #[arg(long = "output", global = true, value_enum, default_value_t = Format::Text, help = "How to print results")]
+    format: Format,
+    #[arg(short, long, global = true, action = clap::ArgAction::Count, help = "Log more; repeat for more still")]
+    verbose: u8,
+    #[arg(short, long, global = true, conflicts_with = "verbose", help = "Only log errors")]
+    quiet: bool,
+    #[arg(long, global = true, value_name = "PATH", help = "Append logs to this file instead of stderr")]
+    log_file: Option<PathBuf>,
+    #[arg(long, global = true, value_name = "BYTES", help = "Refuse torrents larger than this")]
+    max_size: Option<u64>,
+    #[arg(long, global = true, conflicts_with = "max_size", help = "Take torrents however many or large their pieces, or large their files")]
+    force: bool,
+    #[command(subcommand)]
+    command: Command
+}
+
+#[derive(Subcommand)]
+enum Command {
+    #[command(about = "Print a bencoded value as JSON, read from stdin unless given")]
+    Decode {
+        #[arg(conflicts_with = "file")]
+        value: Option<String>,
+        #[arg(long, help = "Read the value from a file, such as a .torrent")]
+        file: Option<PathBuf>
+    },
+    #[command(about = "Bencode JSON read from stdin, or given")]
+    Encode {
+        value: Option<String>,
+        #[arg(short = 'o', long = "out", help = "Where to write the bencoded bytes; stdout if not given")]
+        output: Option<PathBuf>
+    },
+    #[command(about = "Print what a .torrent file describes")]
+    Info {
+        torrent: PathBuf
+    },
+    #[command(about = "Find peers for a torrent on the DHT")]
+    Peers {
+        torrent: PathBuf,
+        #[arg(long, help = "Connect to each peer to show its client, extensions, progress and country")]
+        probe: bool,
+        #[arg(long, default_value_t = 32, value_parser = clap::value_parser!(u32).range(1..), requires = "probe", help = "How many peers to probe at once")]
+        concurrency: u32,
+        #[arg(long, value_name = "SECS", default_value_t = 5, requires = "probe", help = "How long each peer gets to answer")]
+        timeout: u64,
+        #[arg(long, value_name = "PATH", requires = "probe", help = "A MaxMind country database, for the country column")]
+        geoip: Option<PathBuf>
+    },
+    #[command(about = "Announce once to a tracker and print its raw and decoded answer")]
+    Announce {
+        #[arg(help = "The announce URL, http:// or udp://")]
+        tracker: String,
+        #[arg(value_parser = parse_hex::<20>)]
+        info_hash: [u8; 20],
+        #[arg(long, value_enum, help = "The event to announce; a regular update if not given")]
+        event: Option<Event>,
+        #[arg(long, help = "How many peers to ask for; the tracker's default if not given")]
+        numwant: Option<u32>,
+        #[arg(long, default_value_t = 6881, help = "The port to tell the tracker we accept peers on")]
+        port: u16,
+        #[arg(long, default_value_t = 0, help = "The bytes we still need")]
+        left: u64
+    },
+    #[command(about = "Handshake with a peer and print its peer id")]
+    Handshake {
+        torrent: PathBuf,
+        #[arg(help = "The peer's <host:port>", value_parser = resolve)]
+        peer: SocketAddr
+    },
+    #[command(name = "magnet_handshake", about = "Handshake with a peer of a magnet link and print what metadata it offers")]
+    MagnetHandshake {
+        magnet: String,
+        #[arg(long = "peer", value_name = "HOST:PORT", value_parser = resolve, help = "Peers to try; the link's own, then the DHT's, if not given")]
+        peers: Vec<SocketAddr>
+    },
+    #[command(name = "download_piece", about = "Download one piece into a file")]
+    DownloadPiece {
+        #[arg(short = 'o', long = "out", help = "Where to write the piece")]
+        output: PathBuf,
+        torrent: PathBuf,
+        piece: u32,
+        #[arg(long = "peer", value_name = "HOST:PORT", value_parser = resolve, help = "Peers to download from, on top of the torrent's trackers; found on the DHT if not given, unless the torrent is private")]
+        peers: Vec<SocketAddr>
+    },
+    #[command(name = "magnet_download_piece", about = "Download one piece of a magnet link's torrent into a file")]
+    MagnetDownloadPiece {
+        #[arg(short = 'o', long = "out", help = "Where to write the piece")]
+        output: PathBuf,
+        magnet: String,
+        piece: u32,
+        #[arg(long = "peer", value_name = "HOST:PORT", value_parser = resolve, help = "Peers to try; the link's own, then the DHT's, if not given")]
+        peers: Vec<SocketAddr>
+    },
+    #[command(about = "Download a torrent, carrying on from whatever is already there")]
+    Download {
+        #[arg(short = 'o', long = "out", help = "Where to save the torrent: the file itself, or the directory a multi-file torrent fills")]
+        output: Option<PathBuf>,
+        #[arg(long, env = "BITTORRENT_DOWNLOAD_DIR", default_value = ".", help = "The directory torrents are saved in under their own name, unless --out says otherwise")]
+        download_dir: PathBuf,
+        torrent: PathBuf,
+        #[arg(long = "peer", value_name = "HOST:PORT", value_parser = resolve, help = "Peers to download from; found on the DHT if not given, unless the torrent is private")]
+        peers: Vec<SocketAddr>,
+        #[arg(long, value_name = "DIR", help = "Where to move the download once it's complete, having been saved as usual until then")]
+        move_to: Option<PathBuf>,
+        #[arg(long, value_name = "RATE", default_value = "0", value_parser = rate, help = "The upload limit in bytes per second, such as 500K or 1.5M; 0 for unlimited")]
+        limit_up: u64,
+        #[arg(long, value_name = "RATE", default_value = "0", value_parser = rate, help = "The download limit in bytes per second, such as 500K or 1.5M; 0 for unlimited")]
+        limit_down: u64,
+        #[arg(long, value_name = "LIST", help = "Only download these files, such as 1,3-5, counting from 0 in the order `status` lists them")]
+        files: Option<String>,
+        #[arg(long, help = "Keep seeding once the download is complete, until Ctrl+C or the --seed-ratio or --seed-time goal")]
+        seed: bool,
+        #[arg(long, value_name = "RATIO", requires = "seed", help = "Stop seeding once this many times the torrent's size is uploaded")]
+        seed_ratio: Option<f64>,
+        #[arg(long, value_name = "SECONDS", requires = "seed", help = "Stop seeding after this many seconds")]
+        seed_time: Option<u64>
+    },
+    #[command(about = "Check downloaded data against the torrent's piece hashes")]
+    Verify {
+        torrent: PathBuf,
+        #[arg(help = "The directory the torrent was downloaded into")]
+        data: PathBuf
+    },
+    #[command(about = "Show how much of a torrent is downloaded, from its resume data or file sizes, without hashing")]
+    Status {
+        torrent: PathBuf,
+        #[arg(default_value = ".", help = "The directory the torrent was downloaded into")]
+        data: PathBuf
+    },
+    #[command(subcommand, about = "Move resume data to and from libtorrent clients such as qBittorrent and Deluge")]
+    Fastresume(FastresumeCommand),
+    #[cfg(feature = "tui")]
+    #[command(about = "Download and seed torrents from an interactive dashboard")]
+    Tui {
+        #[arg(required = true)]
+        torrents: Vec<PathBuf>,
+        #[arg(long, env = "BITTORRENT_DOWNLOAD_DIR", default_value = ".", help = "The directory to download into")]
+        download_dir: PathBuf,
+        #[arg(long, default_value = "6881", value_parser = listen_port, help = "The port to accept peers on, or the first free one of a range such as 6881-6889")]
+        port: ListenPort,
+        #[arg(long = "peer", value_name = "HOST:PORT", value_parser = resolve, help = "Peers to download from, on top of the torrent's trackers; found on the DHT if not given, unless the torrent is private")]
+        peers: Vec<SocketAddr>
+    },
+    #[command(subcommand, about = "Talk to the mainline DHT")]
+    Dht(DhtCommand),
+    #[command(about = "Run a session that `ctl` commands control, until told to shut down or stopped with Ctrl+C or SIGTERM")]
+    Daemon {
+        #[arg(long, default_value = "6881", value_parser = listen_port, help = "The port to accept peers on, or the first free one of a range such as 6881-6889")]
+        port: ListenPort,
+        #[arg(long, env = "BITTORRENT_SOCKET", help = "The control socket (a named pipe on Windows)")]
+        socket: Option<PathBuf>,
+        #[arg(long, help = "Don't look for peers on the DHT")]
+        no_dht: bool,
+        #[arg(long, help = "Don't forward the port on the router with UPnP or NAT-PMP")]
+        no_upnp: bool,
+        #[arg(long, value_name = "HOST:PORT", value_parser = resolve, help = "Also take JSON-RPC calls over HTTP here, such as 127.0.0.1:9091")]
+        rpc: Option<SocketAddr>,
+        #[arg(long, value_name = "HOST:PORT", value_parser = resolve, help = "Also answer Transmission RPC here, for its remotes and the *arr apps, such as 127.0.0.1:9091")]
+        transmission: Option<SocketAddr>,
+        #[arg(long, env = "BITTORRENT_DOWNLOAD_DIR", default_value = ".", help = "Where watched and Transmission clients' torrents go unless they say otherwise")]
+        download_dir: PathBuf,
+        #[arg(long, value_name = "DIR", env = "BITTORRENT_WATCH_DIR", help = "Add the .torrent and .magnet files dropped in here")]
+        watch: Option<PathBuf>,
+        #[arg(long, value_enum, default_value_t = Added::Keep, requires = "watch", help = "What to do with a watched file once it is added")]
+        watch_added: Added
+    },
+    #[command(about = "Add the torrents of a qBittorrent BT_backup or Transmission config directory to a running daemon, with their progress")]
+    Import {
+        #[arg(help = "qBittorrent's BT_backup, or Transmission's directory of torrents/ and resume/")]
+        dir: PathBuf,
+        #[arg(long, env = "BITTORRENT_SOCKET", help = "The daemon's control socket")]
+        socket: Option<PathBuf>,
+        #[arg(long, env = "BITTORRENT_DOWNLOAD_DIR", default_value = ".", help = "Where torrents go whose resume data doesn't say")]
+        download_dir: PathBuf
+    },
+    #[command(about = "Control a running daemon")]
+    Ctl {
+        #[arg(long, env = "BITTORRENT_SOCKET", help = "The daemon's control socket")]
+        socket: Option<PathBuf>,
+        #[command(subcommand)]
+        command: CtlCommand
+    }
+}
+
+#[derive(Subcommand)]
+enum CtlCommand {
+    #[command(about = "Start a torrent, carrying on from whatever is already downloaded")]
+    Add {
+        #[arg(help = "A .torrent file, or a magnet link for the daemon to fetch the torrent of")]
+        torrent: PathBuf,
+        #[arg(long, env = "BITTORRENT_DOWNLOAD_DIR", default_value = ".", help = "The directory to download into")]
+        download_dir: PathBuf,
+        #[arg(long = "peer", value_name = "HOST:PORT", value_parser = resolve, help = "Peers to download from, on top of the torrent's trackers; found on the DHT if not given, unless the torrent is private")]
+        peers: Vec<SocketAddr>
+    },
+    #[command(about = "Stop a torrent and forget it, leaving its data")]
+    Remove {
+        #[arg(value_parser = parse_hex::<20>)]
+        info_hash: [u8; 20]
+    },
+    #[command(about = "Pause a torrent")]
+    Pause {
+        #[arg(value_parser = parse_hex::<20>)]
+        info_hash: [u8; 20]
+    },
+    #[command(about = "Resume a paused torrent")]
+    Resume {
+        #[arg(value_parser = parse_hex::<20>)]
+        info_hash: [u8; 20]
+    },
+    #[command(about = "Print the progress of every torrent")]
+    Stats,
+    #[command(about = "Set the global rate limits in bytes per second, 0 for unlimited")]
+    Limits {
+        upload: u64,
+        download: u64
+    },
+    #[command(about = "Set the global rate limits by time of day from a schedule file, one rule per line such as `mon-fri 09:00-17:00 100K 1M`")]
+    Schedule {
+        #[arg(required_unless_present = "clear")]
+        file: Option<PathBuf>,
+        #[arg(long, default_value_t = 0, help = "The upload limit outside every rule in bytes per second, 0 for unlimited")]
+        upload: u64,
+        #[arg(long, default_value_t = 0, help = "The download limit outside every rule in bytes per second, 0 for unlimited")]
+        download: u64,
+        #[arg(long, conflicts_with = "file", help = "Stop scheduling, leaving the limits as they are")]
+        clear: bool
+    },
+    #[command(about = "Set the alternate rate limits in bytes per second, 0 for unlimited")]
+    AltLimits {
+        upload: u64,
+        download: u64
+    },
+    #[command(about = "Switch to the alternate rate limits, or back")]
+    AltSpeed {
+        #[arg(action = clap::ArgAction::Set, value_parser = on_off, value_name = "on|off")]
+        enabled: bool
+    },
+    #[command(about = "Stop seeding finished torrents at a ratio or after a time, whichever comes first; neither seeds forever")]
+    SeedGoal {
+        #[arg(long, help = "Stop once this many times a torrent's size is uploaded")]
+        ratio: Option<f64>,
+        #[arg(long, value_name = "SECONDS", help = "Stop after seeding this long")]
+        time: Option<u64>
+    },
+    #[command(about = "Stop the daemon")]
+    Shutdown
+}
+
+#[derive(Subcommand)]
+enum FastresumeCommand {
+    #[command(about = "Carry on a download from a .fastresume file without rehashing it")]
+    Import {
+        torrent: PathBuf,
+        #[arg(help = "qBittorrent's <info hash>.fastresume, or Deluge's torrents.fastresume")]
+        fastresume: PathBuf,
+        #[arg(long, help = "The directory the torrent was downloaded into; the file's save path if not given")]
+        data: Option<PathBuf>
+    },
+    #[command(about = "Write a .fastresume file for a libtorrent client to carry on a download from")]
+    Export {
+        torrent: PathBuf,
+        #[arg(default_value = ".", help = "The directory the torrent was downloaded into")]
+        data: PathBuf,
+        #[arg(short = 'o', long = "out", help = "Where to write it; <info hash>.fastresume if not given")]
+        output: Option<PathBuf>
+    }
+}
+
+#[derive(Subcommand)]
+enum DhtCommand {
+    #[command(about = "Ping a node and print its id and round trip")]
+    Ping {
+        #[arg(help = "The node's <host:port>", value_parser = resolve)]
+        node: SocketAddr
+    },
+    #[command(name = "get-peers", about = "Look up the peers of an info hash")]
+    GetPeers {
+        #[arg(value_parser = parse_hex::<20>)]
+        info_hash: [u8; 20]
+    },
+    #[command(about = "Print the info hashes nodes store, as BEP 51 samples")]
+    Sample {
+        #[arg(long, default_value_t = 100, help = "How many nodes to ask")]
+        nodes: usize
+    },
+    #[command(about = "Store an item; mutable if signed with --secret")]
+    Put {
+        #[arg(long, value_parser = parse_hex::<32>, help = "An ed25519 seed, as 64 hex digits")]
+        secret: Option<[u8; 32]>,
+        #[arg(long, default_value = "", requires = "secret")]
+        salt: String,
+        #[arg(long, default_value_t = 0, requires = "secret")]
+        seq: i64,
+        value: String
+    },
+    #[command(about = "Fetch an item by target, or a mutable one by --key")]
+    Get {
+        #[arg(value_parser = parse_hex::<20>, required_unless_present = "key", conflicts_with = "key")]
+        target: Option<[u8; 20]>,
+        #[arg(long, value_parser = parse_hex::<32>, help = "The public key, as 64 hex digits")]
+        key: Option<[u8; 32]>,
+        #[arg(long, default_value = "")]
+        salt: String
+    }
+}
+
+fn is_json() -> bool {
+    JSON.load(Ordering::Relaxed)
+}
+
+// What went wrong, as far as a script wrapping us cares. Each kind exits
+// with its own status.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Failure {
+    Other,
+    // Arguments that parse but make no sense, such as a piece past the end.
+    Usage,
+    // A torrent, magnet link, bencode or JSON that doesn't parse, or a
+    // torrent outside the limits.
+    Parse,
+    // A peer, the DHT or the daemon could not be reached, or a port bound.
+    Network,
+    NoPeers,
+    // Data that doesn't match its piece hashes.
+    Hash,
+    Disk,
+    Interrupted
+}
+
+impl Failure {
+    // 2 is also what clap exits with for arguments it can't parse.
+    fn code(self) -> i32 {
+        match self {
+            Failure::Other => 1,
+            Failure::Usage => 2,
+            Failure::Parse => 3,
+            Failure::Network => 4,
+            Failure::NoPeers => 5,
+            Failure::Hash => 6,
+            Failure::Disk => 7,
+            Failure::Interrupted => 130
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Failure::Other => "error",
+            Failure::Usage => "usage",
+            Failure::Parse => "parse",
+            Failure::Network => "network",
+            Failure::NoPeers => "no-peers",
+            Failure::Hash => "hash",
+            Failure::Disk => "disk",
+            Failure::Interrupted => "interrupted"
+        }
+    }
+}
+
+// One line either way: `error[kind]: message` on stderr, or a JSON object
+// with the kind and exit status on stdout.
+fn fail(failure: Failure, message: &str) -> ! {
+    match is_json() {
+        true => println!("{}", json!({ "error": message, "kind": failure.name(), "code": failure.code() })),
+        false => eprintln!("error[{}]: {}", failure.name(), message.replace('\n', "; "))
+    }
+    process::exit(failure.code())
+}
+
+fn rate(text: &str) -> Result<u64, String> {
+    parse_rate(text).ok_or_else(|| format!("{} is not a rate, such as 500K or 1.5M", text))
+}
+
+fn on_off(text: &str) -> Result<bool, String> {
+    match text {
+        "on" => Ok(true),
+        "off" => Ok(false),
+        _ => Err("expected on or off".into())
+    }
+}
+
+fn parse_hex<const N: usize>(text: &str) -> Result<[u8; N], String> {
+    unhex(text).ok_or_else(|| format!("expected {} hex digits", N * 2))
+}
+
+fn listen_port(text: &str) -> Result<ListenPort, String> {
+    ListenPort::parse(text).ok_or_else(|| "expected a port, a range such as 6881-6889, or 0 for any".to_string())
+}
+
+fn resolve(addr: &str) -> Result<SocketAddr, String> {
+    addr.to_socket_addrs()
+        .map_err(|err| err.to_string())?
+        .find(SocketAddr::is_ipv4)
+        .ok_or_else(|| format!("cannot resolve {}", addr))
+}
+
+// Checked against the limits before anything is allocated for it.
+fn within_limits(metainfo: &Metainfo) -> Result<(), String> {
+    match LIMITS.get() {
+        Some(limits) => metainfo.check_limits(limits).map_err(|err| format!("{}; pass --force to take it anyway", err)),
+        None => Ok(())
+    }
+}
+
+fn read_torrent(path: &Path) -> Metainfo {
+    let bytes = fs::read(path).unwrap_or_else(|err| fail(Failure::Disk, &format!("cannot read {}: {}", path.display(), err)));
+    let metainfo = Metainfo::from_bytes(&bytes).unwrap_or_else(|| fail(Failure::Parse, &format!("{} is not a valid torrent", path.display())));
+    within_limits(&metainfo).unwrap_or_else(|err| fail(Failure::Parse, &format!("{}: {}", path.display(), err)));
+    metainfo
+}
+
+fn runtime() -> Runtime {
+    Runtime::new().unwrap_or_else(|err| fail(Failure::Other, &format!("cannot start: {}", err)))
+}
+
+fn bootstrap() -> io::Result<Dht> {
+    let mut dht = Dht::bind("0.0.0.0:0").map_err(|err| io::Error::new(err.kind(), format!("cannot bind: {}", err)))?;
+    let routers: Vec<_> = DEFAULT_ROUTERS.iter().map(|router| router.to_string()).collect();
+    match dht.bootstrap(&routers, &[]) {
+        0 => Err(io::Error::other("could not reach the DHT")),
+        _ => Ok(dht)
+    }
+}
+
+fn bootstrapped() -> Dht {
+    bootstrap().unwrap_or_else(|err| fail(Failure::Network, &err.to_string()))
+}
+
+// The peers given, or else whatever the DHT knows of. A torrent with
+// trackers gets more from them once it starts, so it can start with none.
+// Private torrents keep their peers to their trackers, so never ask the DHT
+// for them.
+fn find_peers(info_hash: [u8; 20], private: bool, trackers: bool, peers: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    if !peers.is_empty() || (private && trackers) {
+        return peers;
+    }
+    if private {
+        fail(Failure::NoPeers, "the torrent is private and has no trackers, so give its peers with --peer");
+    }
+    let peers = match bootstrap() {
+        Ok(mut dht) => dht.lookup_peers(info_hash).peers,
+        Err(_) if trackers => Vec::new(),
+        Err(err) => fail(Failure::Network, &err.to_string())
+    };
+    if peers.is_empty() && !trackers {
+        fail(Failure::NoPeers, "no peers found");
+    }
+    peers
+}
+
+fn read_stdin() -> Vec<u8> {
+    let mut input = Vec::new();
+    io::stdin().read_to_end(&mut input).unwrap_or_else(|err| fail(Failure::Other, &format!("cannot read stdin: {}", err)));
+    input
+}
+
+fn decode(value: Option<String>, file: Option<&Path>) {
+    let input = match (value, file) {
+        (Some(value), _) => value.into_bytes(),
+        (None, Some(path)) => fs::read(path).unwrap_or_else(|err| fail(Failure::Disk, &format!("cannot read {}: {}", path.display(), err))),
+        (None, None) => read_stdin()
+    };
+    // Tolerate the newline `echo` leaves at the end.
+    let input = input.strip_suffix(b"\n").unwrap_or(&input);
+    let value = bencode::decode(input).unwrap_or_else(|| fail(Failure::Parse, "invalid bencode"));
+    println!("{}", value.to_json());
+}
+
+fn encode(value: Option<String>, output: Option<&Path>) {
+    let input = value.map_or_else(read_stdin, String::into_bytes);
+    let value: serde_json::Value = serde_json::from_slice(&input).unwrap_or_else(|err| fail(Failure::Parse, &format!("invalid JSON: {}", err)));
+    let encoded = Value::from_json(&value)
+        .unwrap_or_else(|| fail(Failure::Parse, "only integers, strings, lists and objects can be bencoded"))
+        .encode();
+    match output {
+        Some(path) => {
+            fs::write(path, &encoded).unwrap_or_else(|err| fail(Failure::Disk, &format!("cannot write {}: {}", path.display(), err)));
+            if is_json() {
+                println!("{}", json!({ "output": path, "length": encoded.len() }));
+            }
+        },
+        None if is_json() => println!("{}", json!({ "bencode": String::from_utf8_lossy(&encoded), "length": encoded.len() })),
+        None => io::stdout()
+            .write_all(&encoded)
+            .unwrap_or_else(|err| fail(Failure::Other, &format!("cannot write: {}", err)))
+    }
+}
+
+fn info(path: &Path) {
+    let metainfo = read_torrent(path);
+    if is_json() {
+        let files: Vec<_> = metainfo.files
+            .iter()
+            .map(|file| json!({ "path": file.path.to_string_lossy(), "length": file.length }))
+            .collect();
+        let pieces: Vec<_> = metainfo.pieces.iter().map(|hash| hex(hash)).collect();
+        println!("{}", json!({
+            "tracker": metainfo.announce,
+            "name": metainfo.name,
+            "length": metainfo.total_length(),
+            "info_hash": hex(&metainfo.info_hash),
+            "piece_length": metainfo.piece_length,
+            "private": metainfo.private,
+            "files": files,
+            "pieces": pieces
+        }));
+        return;
+    }
+    if let Some(announce) = &metainfo.announce {
+        println!("Tracker URL: {}", announce);
+    }
+    println!("Name: {}", metainfo.name);
+    println!("Length: {}", metainfo.total_length());
+    println!("Info Hash: {}", hex(&metainfo.info_hash));
+    println!("Piece Length: {}", metainfo.piece_length);
+    if metainfo.files.len() > 1 {
+        println!("Files:");
+        for file in &metainfo.files {
+            println!("{} {}", file.length, file.path.display());
+        }
+    }
+    println!("Piece Hashes:");
+    for hash in &metainfo.pieces {
+        println!("{}", hex(hash));
+    }
+}
+
+// How `peers --probe` goes about it.
+struct Probing {
+    concurrency: u32,
+    timeout: Duration,
+    geoip: Option<GeoIp>
+}
+
+fn peers(path: &Path, probing: Option<Probing>) {
+    let metainfo = read_torrent(path);
+    let peers = bootstrapped().lookup_peers(metainfo.info_hash).peers;
+    let Some(probing) = probing else {
+        match is_json() {
+            true => println!("{}", json!({ "peers": peers })),
+            false => peers.iter().for_each(|peer| println!("{}", peer))
+        }
+        return;
+    };
+
+    let ours = Handshake::new(metainfo.info_hash, peer_id::generate()).with_dht().with_extensions();
+    let probes = runtime().block_on(async {
+        let limit = Arc::new(Semaphore::new(probing.concurrency as usize));
+        let tasks: Vec<_> = peers
+            .iter()
+            .map(|&peer| {
+                let limit = limit.clone();
+                tokio::spawn(async move {
+                    let _permit = limit.acquire_owned().await;
+                    peer_wire::probe(peer, ours, &ExtendedHandshake::new(), probing.timeout).await
+                })
+            })
+            .collect();
+        let mut probes = Vec::with_capacity(tasks.len());
+        for task in tasks {
+            probes.push(task.await.unwrap_or_else(|err| Err(io::Error::other(err))));
+        }
+        probes
+    });
+
+    let pieces = metainfo.num_pieces();
+    let rows: Vec<_> = peers
+        .iter()
+        .zip(&probes)
+        .map(|(peer, probe)| probe_row(*peer, probe, pieces, probing.geoip.as_ref()))
+        .collect();
+    if is_json() {
+        println!("{}", json!({ "peers": rows }));
+        return;
+    }
+    println!("{:<22} {:<22} {:>5}  {:<3}  {:<2}  EXTENSIONS", "ADDRESS", "CLIENT", "DONE", "DHT", "CC");
+    for row in rows {
+        let text = |key: &str| row[key].as_str().unwrap_or("-").to_string();
+        if let Some(error) = row["error"].as_str() {
+            println!("{:<22} {}", text("addr"), error);
+            continue;
+        }
+        let done = row["percent"].as_u64().map_or_else(|| "?".to_string(), |percent| format!("{}%", percent));
+        let extensions: Vec<_> = row["extensions"].as_array().into_iter().flatten().filter_map(|name| name.as_str()).collect();
+        let dht = if row["dht"].as_bool() == Some(true) { "yes" } else { "no" };
+        println!("{:<22} {:<22} {:>5}  {:<3}  {:<2}  {}", text("addr"), text("client"), done, dht, text("country"), extensions.join(", "));
+    }
+}
+
+// The client is what the peer calls itself in its extended handshake,
+// else whatever its peer id gives away.
+fn probe_row(peer: SocketAddr, probe: &io::Result<PeerProbe>, pieces: usize, geoip: Option<&GeoIp>) -> serde_json::Value {
+    let country = geoip.and_then(|geoip| geoip.country(peer.ip()));
+    let probe = match probe {
+        Ok(probe) => probe,
+        Err(err) => return json!({ "addr": peer, "country": country, "error": err.to_string() })
+    };
+    let extended = probe.extended.as_ref();
+    let client = extended
+        .and_then(|extended| extended.client.clone())
+        .or_else(|| peer_id::identify(&probe.peer_id).map(|client| client.to_string()));
+    let extensions: Vec<_> = extended.into_iter().flat_map(|extended| extended.extensions.iter()).filter(|(_, &id)| id != 0).map(|(name, _)| name).collect();
+    let percent = probe.pieces.map(|have| match pieces {
+        0 => 100,
+        _ => have.min(pieces) * 100 / pieces
+    });
+    json!({
+        "addr": peer,
+        "peer_id": hex(&probe.peer_id),
+        "client": client,
+        "pieces": probe.pieces,
+        "percent": percent,
+        "dht": probe.dht,
+        "extensions": extensions,
+        "country": country
+    })
+}
+
+// For debugging a tracker on its own: one announce, and everything it said.
+fn announce(url: &str, request: &Announce) {
+    let tracker = Tracker::parse(url).unwrap_or_else(|| fail(Failure::Parse, &format!("{} is not an http:// or udp:// tracker", url)));
+    let announced = runtime()
+        .block_on(tracker::announce(&tracker, request))
+        .unwrap_or_else(|err| fail(Failure::Network, &format!("{}: {}", url, err)));
+    let raw = announced.raw.escape_ascii().to_string();
+    let Some(response) = announced.response else {
+        fail(Failure::Parse, &format!("{} answered something that isn't a tracker response: {}", announced.addr, raw))
+    };
+    if is_json() {
+        let decoded = json!({
+            "failure": response.failure,
+            "warning": response.warning,
+            "interval": response.interval,
+            "min_interval": response.min_interval,
+            "tracker_id": response.tracker_id,
+            "complete": response.complete,
+            "incomplete": response.incomplete,
+            "peers": response.peers
+        });
+        println!("{}", json!({ "tracker": announced.addr, "raw": raw, "decoded": decoded }));
+        return;
+    }
+    println!("Tracker: {}", announced.addr);
+    println!("Raw: {}", raw);
+    let fields = [
+        ("Failure", response.failure),
+        ("Warning", response.warning),
+        ("Interval", response.interval.map(|secs| format!("{} s", secs))),
+        ("Min interval", response.min_interval.map(|secs| format!("{} s", secs))),
+        ("Tracker id", response.tracker_id),
+        ("Seeders", response.complete.map(|count| count.to_string())),
+        ("Leechers", response.incomplete.map(|count| count.to_string()))
+    ];
+    for (name, value) in fields {
+        if let Some(value) = value {
+            println!("{}: {}", name, value);
+        }
+    }
+    println!("Peers: {}", response.peers.len());
+    response.peers.iter().for_each(|peer| println!("    {}", peer));
+}
+
+fn handshake(path: &Path, peer: SocketAddr) {
+    let metainfo = read_torrent(path);
+    let ours = Handshake::new(metainfo.info_hash, peer_id::generate());
+    match runtime().block_on(connect(peer, ours)) {
+        Ok((_, theirs)) if is_json() => println!("{}", json!({ "peer": peer, "peer_id": hex(&theirs.peer_id) })),
+        Ok((_, theirs)) => println!("Peer ID: {}", hex(&theirs.peer_id)),
+        Err(err) => fail(Failure::Network, &format!("{}: {}", peer, err))
+    }
+}
+
+// Tries the peers in turn until one does both handshakes.
+fn magnet_handshake(link: &str, peers: Vec<SocketAddr>) {
+    let magnet = Magnet::parse(link).unwrap_or_else(|| fail(Failure::Parse, "not a valid magnet link"));
+    let peers = match peers.is_empty() {
+        true => find_peers(magnet.info_hash, false, false, magnet.peers),
+        false => peers
+    };
+    let ours = Handshake::new(magnet.info_hash, peer_id::generate()).with_extensions();
+    let extensions = ExtendedHandshake::new().with_extension(UT_METADATA, OUR_UT_METADATA);
+    let runtime = runtime();
+    let mut errors = Vec::new();
+    for peer in peers {
+        let (_, theirs, extended) = match runtime.block_on(connect_extended(peer, ours, &extensions)) {
+            Ok(result) => result,
+            Err(err) => {
+                errors.push(format!("{}: {}", peer, err));
+                continue;
+            }
+        };
+        match is_json() {
+            true => println!(
+                "{}",
+                json!({
+                    "peer": peer,
+                    "peer_id": hex(&theirs.peer_id),
+                    "ut_metadata": extended.extension_id(UT_METADATA),
+                    "metadata_size": extended.metadata_size
+                })
+            ),
+            false => {
+                println!("Peer ID: {}", hex(&theirs.peer_id));
+                match (extended.extension_id(UT_METADATA), extended.metadata_size) {
+                    (Some(id), Some(size)) => println!("Metadata: {} bytes, extension id {}", size, id),
+                    (Some(id), None) => println!("Metadata: size unknown, extension id {}", id),
+                    (None, _) => println!("Metadata: not offered")
+                }
+            }
+        }
+        return;
+    }
+    fail(Failure::Network, &errors.join("; "));
+}
+
+fn download_piece(output: &Path, metainfo: Metainfo, piece: u32, peers: Vec<SocketAddr>) {
+    let layout = metainfo.layout().unwrap_or_else(|| fail(Failure::Parse, "the torrent's pieces don't match its files"));
+    let Some(size) = layout.geometry().piece_size(piece) else {
+        fail(Failure::Usage, &format!("the torrent has {} pieces", layout.geometry().num_pieces()));
+    };
+    let offset = layout.geometry().piece_offset(piece);
+    let peers = find_peers(metainfo.info_hash, metainfo.private, !metainfo.trackers().is_empty(), peers);
+
+    // Only the one piece is waited for; its deadline puts it first.
+    let torrent = Torrent::with_storage(metainfo, MemoryStorage::new(layout)).unwrap_or_else(|| unreachable!());
+    let data = runtime().block_on(async {
+        let handle = TorrentHandle::spawn(torrent, peer_id::generate());
+        handle.set_piece_deadline(piece, PIECE_DEADLINE);
+        peers.into_iter().for_each(|peer| handle.add_peer(peer));
+        let reader = handle.reader();
+        reader.wait_piece(piece).await;
+        let data = reader.read_range(offset, size as usize).await;
+        handle.shutdown().await;
+        data
+    });
+    let data = data.unwrap_or_else(|err| fail(Failure::Other, &format!("cannot read piece {}: {}", piece, err)));
+    fs::write(output, data).unwrap_or_else(|err| fail(Failure::Disk, &format!("cannot write {}: {}", output.display(), err)));
+    match is_json() {
+        true => println!("{}", json!({ "piece": piece, "length": size, "output": output })),
+        false => println!("Piece {} downloaded to {}.", piece, output.display())
+    }
+}
+
+async fn fetch_metadata(peer: SocketAddr, ours: Handshake, extensions: &ExtendedHandshake) -> io::Result<Vec<u8>> {
+    let (mut stream, _, extended) = connect_extended(peer, ours, extensions).await?;
+    peer_wire::fetch_metadata(&mut stream, &extended, OUR_UT_METADATA, ours.info_hash).await
+}
+
+// `download_piece` for a magnet link: the torrent comes from the first of
+// the peers to hand over its metadata, and the piece from all of them.
+fn magnet_download_piece(output: &Path, link: &str, piece: u32, peers: Vec<SocketAddr>) {
+    let magnet = Magnet::parse(link).unwrap_or_else(|| fail(Failure::Parse, "not a valid magnet link"));
+    let peers = match peers.is_empty() {
+        true => find_peers(magnet.info_hash, false, false, magnet.peers),
+        false => peers
+    };
+    let ours = Handshake::new(magnet.info_hash, peer_id::generate()).with_extensions();
+    let extensions = ExtendedHandshake::new().with_extension(UT_METADATA, OUR_UT_METADATA);
+    let runtime = runtime();
+    let mut errors = Vec::new();
+    for &peer in &peers {
+        let info = match runtime.block_on(fetch_metadata(peer, ours, &extensions)) {
+            Ok(info) => info,
+            Err(err) => {
+                errors.push(format!("{}: {}", peer, err));
+                continue;
+            }
+        };
+        let metainfo = Metainfo::from_info(&info, &magnet.trackers)
+            .unwrap_or_else(|| fail(Failure::Parse, &format!("{} sent metadata that isn't a valid torrent", peer)));
+        within_limits(&metainfo).unwrap_or_else(|err| fail(Failure::Parse, &format!("{}: {}", metainfo.name, err)));
+        return download_piece(output, metainfo, piece, peers);
+    }
+    fail(Failure::Network, &errors.join("; "));
+}
+
+// Where the torrent's files are rooted, renaming it to fit `output` if
+// given. A single file can also be dropped into an existing directory.
+fn destination(metainfo: &mut Metainfo, output: Option<&Path>, download_dir: &Path) -> PathBuf {
+    let Some(output) = output else {
+        return download_dir.to_path_buf();
+    };
+    let single = metainfo.files.len() == 1 && metainfo.files[0].path == Path::new(&metainfo.name);
+    if single && output.is_dir() {
+        return output.to_path_buf();
+    }
+    let name = output.file_name().unwrap_or_else(|| fail(Failure::Usage, &format!("{} names no file", output.display())));
+    metainfo.rename(&name.to_string_lossy());
+    match output.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+        _ => PathBuf::from(".")
+    }
+}
+
+// How `download` goes about it, besides where things go.
+struct Transfer<'a> {
+    limits: (u64, u64),
+    files: Option<&'a str>,
+    // How long to seed once the download is complete; not at all if `None`.
+    seed: Option<SeedGoal>
+}
+
+fn download(output: Option<&Path>, download_dir: &Path, path: &Path, peers: Vec<SocketAddr>, move_to: Option<&Path>, transfer: Transfer) {
+    let Transfer { limits: (upload, download), files, seed } = transfer;
+    let mut metainfo = read_torrent(path);
+    let name = metainfo.name.clone();
+    let root = destination(&mut metainfo, output, download_dir);
+    let output = move_to.unwrap_or(&root).join(&metainfo.name);
+    let length = metainfo.total_length();
+    // Saved next to the data when a download is interrupted, so the next
+    // one only hashes what changed since.
+    let resume_path = root.join(format!(".{}.resume", hex(&metainfo.info_hash)));
+    let peers = find_peers(metainfo.info_hash, metainfo.private, !metainfo.trackers().is_empty(), peers);
+    let mut torrent = Torrent::new(metainfo, &root).unwrap_or_else(|| fail(Failure::Parse, "the torrent's pieces don't match its files"));
+    let have = torrent.resume_from(&resume_path);
+    if have.count_ones() > 0 && !have.is_complete() && !is_json() {
+        println!("Carrying on with {} of {} pieces.", have.count_ones(), have.len());
+    }
+    let num_files = torrent.storage().layout().files().len();
+    let selection = match files {
+        Some(files) => FileSelection::parse(files, num_files)
+            .unwrap_or_else(|| fail(Failure::Usage, &format!("{} doesn't name files of the {} in the torrent", files, num_files))),
+        None => FileSelection::all(num_files)
+    };
+
+    let progress = Progress::new(!is_json());
+    let outcome = runtime().block_on(async {
+        let mut options = TorrentOptions::new()
+            .with_resume_path(&resume_path)
+            .with_limits(upload, download)
+            .with_seed_goal(seed.unwrap_or_else(SeedGoal::no_seeding));
+        if let Some(dir) = move_to {
+            options = options.with_destination(dir);
+        }
+        let handle = TorrentHandle::spawn_with(torrent, peer_id::generate(), options, Shared::default());
+        handle.set_file_priorities(&selection);
+        peers.into_iter().for_each(|peer| handle.add_peer(peer));
+        let mut alerts = handle.subscribe();
+        let mut tick = time::interval(PROGRESS_INTERVAL);
+        let mut seeding = false;
+        let mut outcome = loop {
+            tokio::select! {
+                _ = handle.wait_selected(&selection), if !seeding => {
+                    let complete = handle.have().is_complete();
+                    if !complete || seed.is_none() {
+                        break Ok(complete);
+                    }
+                    seeding = true;
+                    if !is_json() {
+                        progress.finish();
+                        println!("Seeding {}; Ctrl+C stops.", name);
+                    }
+                },
+                _ = tokio::signal::ctrl_c() => match seeding {
+                    true => break Ok(true),
+                    false => break Err((Failure::Interrupted, "interrupted; run the same download again to carry on".to_string()))
+                },
+                Ok(alert) = alerts.recv() => match alert {
+                    Alert::StorageError { message, .. } => break Err((Failure::Disk, message)),
+                    Alert::SeedingGoalReached { .. } => break Ok(true),
+                    _ => {}
+                },
+                _ = tick.tick() => progress.update(&handle.stats())
+            }
+        };
+        progress.finish();
+        handle.shutdown().await;
+        // Moving the files only fails once the download is complete.
+        while let (Ok(_), Ok(alert)) = (&outcome, alerts.try_recv()) {
+            if let Alert::StorageError { message, .. } = alert {
+                outcome = Err((Failure::Disk, message));
+            }
+        }
+        outcome
+    });
+    let complete = outcome.unwrap_or_else(|(failure, message)| fail(failure, &message));
+    // Kept for the rest of the files, should they be wanted later.
+    if complete {
+        let _ = fs::remove_file(&resume_path);
+        let _ = fs::remove_file(journal::path_for(&resume_path));
+    }
+    match is_json() {
+        true => println!("{}", json!({ "name": name, "length": length, "output": output })),
+        false => println!("Downloaded {} to {}.", name, output.display())
+    }
+}
+
+// `0-4, 7, 9-10`
+fn ranges(pieces: &[u32]) -> String {
+    let mut ranges: Vec<(u32, u32)> = Vec::new();
+    for &piece in pieces {
+        match ranges.last_mut() {
+            Some((_, end)) if *end + 1 == piece => *end = piece,
+            _ => ranges.push((piece, piece))
+        }
+    }
+    let ranges: Vec<_> = ranges
+        .into_iter()
+        .map(|(start, end)| match start == end {
+            true => start.to_string(),
+            false => format!("{}-{}", start, end)
+        })
+        .collect();
+    ranges.join(", ")
+}
+
+fn percent(count: usize, of: usize) -> f64 {
+    match of {
+        0 => 100.0,
+        of => count as f64 * 100.0 / of as f64
+    }
+}
+
+// What `verify` would likely find, without hashing anything: the pieces
+// the resume data vouches for where their files haven't changed since, or
+// without it, the pieces whose files are long enough to hold them.
+fn status(path: &Path, data: &Path) {
+    let metainfo = read_torrent(path);
+    let layout = metainfo.layout().unwrap_or_else(|| fail(Failure::Parse, "the torrent's pieces don't match its files"));
+    let storage = FileStorage::new(data, layout.clone()).with_part_files(true);
+    let resume_path = data.join(format!(".{}.resume", hex(&metainfo.info_hash)));
+    let (have, resumed) = match ResumeData::load(&resume_path) {
+        Ok(resume) if resume.info_hash == metainfo.info_hash => (resume.verified_pieces(&storage), true),
+        _ => {
+            let mut have = Bitfield::new(metainfo.num_pieces());
+            (0..have.len() as u32).filter(|&piece| storage.is_on_disk(piece)).for_each(|piece| {
+                have.set(piece as usize);
+            });
+            (have, false)
+        }
+    };
+    let missing: Vec<u32> = have.zeros().map(|piece| piece as u32).collect();
+    let percent = percent(have.count_ones(), have.len());
+    let files = layout.file_percents(&have);
+    if is_json() {
+        let files: Vec<_> = layout.files()
+            .iter()
+            .zip(&files)
+            .map(|(file, percent)| json!({ "path": file.path, "length": file.length, "percent": percent }))
+            .collect();
+        println!("{}", json!({
+            "source": if resumed { "resume" } else { "files" },
+            "pieces": have.len(),
+            "have": have.count_ones(),
+            "percent": percent,
+            "missing": missing,
+            "files": files
+        }));
+        return;
+    }
+    match resumed {
+        true => println!("From: resume data, trusted where the files haven't changed"),
+        false => println!("From: file sizes only; run verify to hash them")
+    }
+    println!("Have: {}/{} pieces ({:.1}%)", have.count_ones(), have.len(), percent);
+    if !missing.is_empty() {
+        println!("Missing: {}", ranges(&missing));
+    }
+    println!("Files:");
+    for (file, percent) in layout.files().iter().zip(&files) {
+        println!("    {:5.1}%  {}  {}", percent, bytes(file.length), file.path.display());
+    }
+}
+
+fn verify(path: &Path, data: &Path) {
+    let metainfo = read_torrent(path);
+    let layout = metainfo.layout().unwrap_or_else(|| fail(Failure::Parse, "the torrent's pieces don't match its files"));
+    let storage = FileStorage::new(data, layout).with_part_files(true);
+    let mut torrent = Torrent::with_storage(metainfo, storage).unwrap_or_else(|| unreachable!());
+    let have = torrent.recheck().clone();
+
+    let (missing, bad): (Vec<u32>, Vec<u32>) = (0..have.len() as u32)
+        .filter(|&piece| !have.get(piece as usize))
+        .partition(|&piece| !torrent.storage().is_on_disk(piece));
+    let percent = percent(have.count_ones(), have.len());
+    if is_json() {
+        println!("{}", json!({
+            "pieces": have.len(),
+            "verified": have.count_ones(),
+            "percent": percent,
+            "missing": missing,
+            "bad": bad
+        }));
+    } else {
+        println!("Verified: {}/{} pieces ({:.1}%)", have.count_ones(), have.len(), percent);
+        if !missing.is_empty() {
+            println!("Missing: {}", ranges(&missing));
+        }
+        if !bad.is_empty() {
+            println!("Bad: {}", ranges(&bad));
+        }
+    }
+    // The report is the output; the status only says whether it all checked out.
+    if !have.is_complete() {
+        process::exit(Failure::Hash.code());
+    }
+}
+
+fn fastresume_import(path: &Path, fastresume: &Path, data: Option<&Path>) {
+    let metainfo = read_torrent(path);
+    let layout = metainfo.layout().unwrap_or_else(|| fail(Failure::Parse, "the torrent's pieces don't match its files"));
+    let bytes = fs::read(fastresume).unwrap_or_else(|err| fail(Failure::Disk, &format!("cannot read {}: {}", fastresume.display(), err)));
+    let fast = FastResume::decode(&bytes)
+        .or_else(|| FastResume::from_deluge(&bytes, &metainfo.info_hash))
+        .unwrap_or_else(|| fail(Failure::Parse, &format!("{} is not libtorrent resume data for this torrent", fastresume.display())));
+    if fast.info_hash != metainfo.info_hash {
+        fail(Failure::Parse, &format!("{} is for another torrent, {}", fastresume.display(), hex(&fast.info_hash)));
+    }
+    let data = data
+        .map(Path::to_path_buf)
+        .or(fast.save_path.clone())
+        .unwrap_or_else(|| fail(Failure::Usage, "the file has no save path; give the data directory with --data"));
+    let storage = FileStorage::new(&data, layout).with_part_files(true);
+    let resume = fast.to_resume(&storage);
+    let resume_path = data.join(format!(".{}.resume", hex(&metainfo.info_hash)));
+    resume.save(&resume_path).unwrap_or_else(|err| fail(Failure::Disk, &format!("cannot write {}: {}", resume_path.display(), err)));
+    if is_json() {
+        println!("{}", json!({ "data": data, "resume": resume_path, "pieces": resume.pieces.len(), "have": resume.pieces.count_ones() }));
+    } else {
+        println!("Imported: {}/{} pieces, into {}", resume.pieces.count_ones(), resume.pieces.len(), resume_path.display());
+    }
+}
+
+fn fastresume_export(path: &Path, data: &Path, output: Option<&Path>) {
+    let metainfo = read_torrent(path);
+    let name = metainfo.name.clone();
+    let info_hash = metainfo.info_hash;
+    let resume_path = data.join(format!(".{}.resume", hex(&info_hash)));
+    // Without resume data, what's there is hashed, as verify would.
+    let resume = match ResumeData::load(&resume_path) {
+        Ok(resume) if resume.info_hash == info_hash => resume,
+        _ => {
+            let layout = metainfo.layout().unwrap_or_else(|| fail(Failure::Parse, "the torrent's pieces don't match its files"));
+            let storage = FileStorage::new(data, layout).with_part_files(true);
+            let mut torrent = Torrent::with_storage(metainfo, storage).unwrap_or_else(|| unreachable!());
+            let have = torrent.recheck().clone();
+            ResumeData::capture(info_hash, torrent.storage(), &have)
+        }
+    };
+    // libtorrent wants the directory as an absolute path.
+    let save_path = fs::canonicalize(data).unwrap_or_else(|_| data.to_path_buf());
+    let output = output.map(Path::to_path_buf).unwrap_or_else(|| PathBuf::from(format!("{}.fastresume", hex(&info_hash))));
+    let fast = FastResume::from_resume(&resume, save_path, &name);
+    fs::write(&output, fast.encode()).unwrap_or_else(|err| fail(Failure::Disk, &format!("cannot write {}: {}", output.display(), err)));
+    if is_json() {
+        println!("{}", json!({ "fastresume": output, "pieces": resume.pieces.len(), "have": resume.pieces.count_ones() }));
+    } else {
+        println!("Exported: {}/{} pieces, into {}", resume.pieces.count_ones(), resume.pieces.len(), output.display());
+    }
+}
+
+#[cfg(feature = "tui")]
+fn tui(paths: &[PathBuf], download_dir: &Path, port: ListenPort, peers: Vec<SocketAddr>) {
+    if !io::stdout().is_terminal() {
+        fail(Failure::Usage, "the dashboard needs a terminal");
+    }
+    let torrents: Vec<_> = paths
+        .iter()
+        .map(|path| {
+            let metainfo = read_torrent(path);
+            let trackers = metainfo.trackers();
+            let mut torrent = Torrent::new(metainfo, download_dir).unwrap_or_else(|| fail(Failure::Parse, &format!("{} doesn't match its files", path.display())));
+            torrent.recheck();
+            (torrent, trackers)
+        })
+        .collect();
+    let result = runtime().block_on(async {
+        let mut session = Session::bind_port(Ipv4Addr::UNSPECIFIED.into(), port)
+            .await
+            .unwrap_or_else(|err| fail(Failure::Network, &format!("cannot listen on port {}: {}", port, err)));
+        let mut app = App::new();
+        let mut lookups = Vec::new();
+        for (torrent, trackers) in torrents {
+            let private = torrent.metainfo().private;
+            // The same torrent given twice is only added once.
+            let Some(handle) = session.add_torrent(torrent) else {
+                continue;
+            };
+            app.add(handle, trackers);
+            match (peers.is_empty(), private) {
+                (true, false) => lookups.push(handle.info_hash()),
+                _ => peers.iter().for_each(|&peer| handle.add_peer(peer))
+            }
+        }
+        let (sender, found) = mpsc::unbounded_channel();
+        if !lookups.is_empty() {
+            let port = session.listen_port();
+            tokio::task::spawn_blocking(move || {
+                // Without the DHT the torrents wait for peers to find them.
+                let Ok(mut dht) = Dht::bind("0.0.0.0:0") else {
+                    return;
+                };
+                let routers: Vec<_> = DEFAULT_ROUTERS.iter().map(|router| router.to_string()).collect();
+                if dht.bootstrap(&routers, &[]) == 0 {
+                    return;
+                }
+                for info_hash in lookups {
+                    let _ = sender.send((info_hash, dht.announce(info_hash, Some(port)).peers));
+                }
+            });
+        }
+        let result = tui::run(&mut session, app, found);
+        session.shutdown().await;
+        result
+    });
+    result.unwrap_or_else(|err| fail(Failure::Other, &format!("cannot draw the dashboard: {}", err)));
+}
+
+fn dht_ping(addr: SocketAddr) {
+    let mut dht = Dht::bind("0.0.0.0:0").unwrap_or_else(|err| fail(Failure::Network, &format!("cannot bind: {}", err)));
+    let start = Instant::now();
+    match dht.ping(addr) {
+        Ok(id) if is_json() => println!("{}", json!({ "id": hex(&id.0), "rtt_ms": start.elapsed().as_millis() as u64 })),
+        Ok(id) => println!("{:?} {} ms", id, start.elapsed().as_millis()),
+        Err(err) => fail(Failure::Network, &format!("{}: {}", addr, err))
+    }
+}
+
+fn dht_get_peers(info_hash: [u8; 20]) {
+    let lookup = bootstrapped().lookup_peers(info_hash);
+    if is_json() {
+        let nodes: Vec<_> = lookup.nodes
+            .iter()
+            .map(|(node, _)| json!({ "id": hex(&node.id.0), "addr": node.addr }))
+            .collect();
+        println!("{}", json!({ "peers": lookup.peers, "nodes": nodes }));
+        return;
+    }
+    println!("peers:");
+    lookup.peers.iter().for_each(|peer| println!("    {}", peer));
+    println!("closest nodes:");
+    lookup.nodes.iter().for_each(|(node, _)| println!("    {:?} {}", node.id, node.addr));
+}
+
+// Walks the DHT asking each node for a sample of the info hashes it
+// stores, and prints every new one.
+fn dht_sample(limit: usize) {
+    let mut dht = bootstrapped();
+    let mut queue: VecDeque<_> = dht.nodes().into();
+    let mut asked = HashSet::new();
+    let mut seen = HashSet::new();
+    let mut found = Vec::new();
+    while let Some(node) = queue.pop_front() {
+        if asked.len() >= limit {
+            break;
+        }
+        if !asked.insert(node.addr) {
+            continue;
+        }
+        let Ok(samples) = dht.sample_infohashes(node.addr, NodeId::random()) else {
+            continue;
+        };
+        for info_hash in samples.samples.iter().filter(|&&info_hash| seen.insert(info_hash)) {
+            match is_json() {
+                true => found.push(hex(info_hash)),
+                false => println!("{}", hex(info_hash))
+            }
+        }
+        queue.extend(samples.nodes);
+    }
+    match is_json() {
+        true => println!("{}", json!({ "info_hashes": found, "nodes": asked.len() })),
+        false => eprintln!("{} info hashes from {} nodes", seen.len(), asked.len())
+    }
+}
+
+fn dht_put(secret: Option<[u8; 32]>, salt: &str, seq: i64, value: &str) {
+    let value = Value::from(value);
+    let item = match secret {
+        Some(secret) => Item::Mutable(MutableItem::sign(&secret, salt.as_bytes(), seq, value)),
+        None => Item::Immutable(value)
+    };
+    if let Err(err) = item.validate() {
+        fail(Failure::Usage, &err.to_string());
+    }
+
+    let stored = bootstrapped().put(&item, None);
+    let key = match &item {
+        Item::Mutable(item) => Some(hex(&item.key)),
+        Item::Immutable(_) => None
+    };
+    if is_json() {
+        println!("{}", json!({ "key": key, "target": hex(&item.target().0), "stored": stored }));
+        return;
+    }
+    if let Some(key) = key {
+        println!("key: {}", key);
+    }
+    println!("target: {:?}", item.target());
+    println!("stored on {} nodes", stored);
+}
+
+fn dht_get(target: Option<[u8; 20]>, key: Option<[u8; 32]>, salt: &str) {
+    let target = match (key, target) {
+        (Some(key), _) => mutable_target(&key, salt.as_bytes()),
+        (None, Some(target)) => NodeId(target),
+        (None, None) => unreachable!()
+    };
+
+    match bootstrapped().get(target, salt.as_bytes()).item {
+        Some(item) if is_json() => println!("{}", json!({ "seq": item.seq(), "value": item.value().to_json() })),
+        Some(item) => {
+            if let Some(seq) = item.seq() {
+                println!("seq: {}", seq);
+            }
+            match item.value().as_str() {
+                Some(text) => println!("{}", text),
+                None => println!("{}", String::from_utf8_lossy(&item.value().encode()))
+            }
+        },
+        None => fail(Failure::Other, "not found")
+    }
+}
+
+// Where the daemon's torrents come from, besides `ctl add`.
+struct Sources {
+    rpc: Option<SocketAddr>,
+    transmission: Option<SocketAddr>,
+    download_dir: PathBuf,
+    watch: Option<PathBuf>,
+    watch_added: Added
+}
+
+fn daemon(port: ListenPort, socket: &Path, no_dht: bool, no_upnp: bool, sources: Sources) {
+    let Sources { rpc, transmission, download_dir, watch, watch_added } = sources;
+    let runtime = runtime();
+    let mut session = runtime
+        .block_on(Session::bind_port(Ipv4Addr::UNSPECIFIED.into(), port))
+        .unwrap_or_else(|err| fail(Failure::Network, &format!("cannot listen on port {}: {}", port, err)));
+    let port = session.listen_port();
+    // Lookups go through the DHT node; without one only added peers and
+    // those that connect to us are used. It takes the same port over UDP
+    // where it can, so that the one forwarded port does for both.
+    let dht = match no_dht {
+        true => None,
+        false => {
+            let mut dht = Dht::bind(("0.0.0.0", port))
+                .or_else(|_| Dht::bind("0.0.0.0:0"))
+                .unwrap_or_else(|err| fail(Failure::Network, &format!("cannot bind: {}", err)));
+            let routers: Vec<_> = DEFAULT_ROUTERS.iter().map(|router| router.to_string()).collect();
+            match dht.bootstrap(&routers, &[]) {
+                0 => {
+                    eprintln!("could not reach the DHT, going on without it");
+                    None
+                },
+                _ => Some(dht)
+            }
+        }
+    };
+    // Peers are told where the node is, to add it to their routing tables.
+    session.set_dht_port(dht.as_ref().and_then(|dht| dht.local_addr().ok()).map(|addr| addr.port()));
+    let clean = runtime.block_on(async {
+        // Kept up for as long as the daemon runs, so that peers outside the
+        // local network can reach us through the router.
+        if !no_upnp {
+            session.forward_port();
+        }
+        let mut server = ControlServer::new(session).with_limits(LIMITS.get().copied());
+        if let Some(dht) = dht {
+            server = server.with_dht(dht);
+        }
+        if let Some(addr) = rpc {
+            let listener = TcpListener::bind(addr).await.unwrap_or_else(|err| fail(Failure::Network, &format!("cannot listen on {}: {}", addr, err)));
+            server = server.with_rpc(listener);
+        }
+        if let Some(addr) = transmission {
+            let listener = TcpListener::bind(addr).await.unwrap_or_else(|err| fail(Failure::Network, &format!("cannot listen on {}: {}", addr, err)));
+            server = server.with_transmission(listener, absolute(&download_dir));
+        }
+        if let Some(dir) = &watch {
+            let after_add = match watch_added {
+                Added::Keep => AfterAdd::Keep,
+                Added::Rename => AfterAdd::Rename,
+                Added::Move => AfterAdd::Move
+            };
+            server = server.with_watch(WatchFolder::new(absolute(dir), absolute(&download_dir)).with_after_add(after_add));
+        }
+        match is_json() {
+            true => println!("{}", json!({ "port": port, "socket": socket, "rpc": rpc, "transmission": transmission, "watch": watch })),
+            false => println!("Listening on port {}, controlled through {}.", port, socket.display())
+        }
+        server.serve(socket).await.unwrap_or_else(|err| fail(Failure::Other, &format!("cannot serve on {}: {}", socket.display(), err)))
+    });
+    if !clean {
+        fail(Failure::Other, "some torrents did not stop in time");
+    }
+}
+
+// Paths are sent absolute, since the daemon runs elsewhere.
+fn absolute(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|err| fail(Failure::Disk, &format!("cannot find {}: {}", path.display(), err)))
+}
+
+// Each torrent's progress is left next to its data as our resume data, for
+// the daemon to hash only what it can't vouch for as it adds it.
+fn import(dir: &Path, socket: &Path, download_dir: &Path) {
+    let migration = Migration::scan(dir).unwrap_or_else(|err| fail(Failure::Disk, &format!("cannot import {}: {}", dir.display(), err)));
+    let runtime = runtime();
+    // The daemon turning one torrent down, as one it already has, only
+    // skips that one.
+    let send = |request: &Request| runtime.block_on(control::send(socket, request)).map_err(|err| match err.kind() {
+        io::ErrorKind::Other => err.to_string(),
+        _ => fail(Failure::Network, &format!("cannot reach the daemon at {}: {}", socket.display(), err))
+    });
+    let mut imported = Vec::new();
+    for found in &migration.torrents {
+        let added = import_one(migration.client, found, download_dir).and_then(|(request, paused)| {
+            let response = send(&request)?;
+            if let (true, Some(info_hash)) = (paused, response["info_hash"].as_str().and_then(unhex::<20>)) {
+                send(&Request::Pause { info_hash })?;
+            }
+            Ok(response)
+        });
+        match (added, is_json()) {
+            (Ok(response), true) => imported.push(json!({ "torrent": found.torrent, "info_hash": response["info_hash"], "name": response["name"] })),
+            (Err(err), true) => imported.push(json!({ "torrent": found.torrent, "error": err })),
+            (Ok(response), false) => println!("Added {} ({}).", response["name"].as_str().unwrap_or("?"), response["info_hash"].as_str().unwrap_or("?")),
+            (Err(err), false) => eprintln!("Skipped {}: {}", found.torrent.display(), err)
+        }
+    }
+    if is_json() {
+        println!("{}", json!({ "client": migration.client.name(), "torrents": imported }));
+    }
+}
+
+// The request adding `found`, and whether it was paused.
+fn import_one(client: Client, found: &Found, download_dir: &Path) -> Result<(Request, bool), String> {
+    let bytes = fs::read(&found.torrent).map_err(|err| format!("cannot read it: {}", err))?;
+    let metainfo = Metainfo::from_bytes(&bytes).ok_or("not a valid torrent")?;
+    within_limits(&metainfo)?;
+    let layout = metainfo.layout().ok_or("the torrent's pieces don't match its files")?;
+    let resume = found
+        .resume
+        .as_ref()
+        .and_then(|path| fs::read(path).ok())
+        .and_then(|bytes| client.read_resume(&bytes, &metainfo));
+    let dir = resume.as_ref().and_then(|resume| resume.save_path.clone()).unwrap_or_else(|| download_dir.to_path_buf());
+    let dir = fs::canonicalize(&dir).map_err(|err| format!("cannot find {}: {}", dir.display(), err))?;
+    if let Some(resume) = &resume {
+        let storage = FileStorage::new(&dir, layout).with_part_files(true);
+        let resume_path = dir.join(format!(".{}.resume", hex(&metainfo.info_hash)));
+        resume.to_resume(&storage).save(&resume_path).map_err(|err| format!("cannot write {}: {}", resume_path.display(), err))?;
+    }
+    let torrent = fs::canonicalize(&found.torrent).map_err(|err| err.to_string())?;
+    let paused = resume.is_some_and(|resume| resume.paused);
+    Ok((Request::Add { torrent, dir, peers: Vec::new() }, paused))
+}
+
+fn ctl(socket: &Path, command: CtlCommand) {
+    let request = match command {
+        CtlCommand::Add { torrent, download_dir, peers } => match torrent.to_str().filter(|torrent| torrent.starts_with("magnet:")) {
+            Some(magnet) => Request::AddMagnet { magnet: magnet.to_string(), dir: absolute(&download_dir), peers },
+            None => Request::Add { torrent: absolute(&torrent), dir: absolute(&download_dir), peers }
+        },
+        CtlCommand::Remove { info_hash } => Request::Remove { info_hash },
+        CtlCommand::Pause { info_hash } => Request::Pause { info_hash },
+        CtlCommand::Resume { info_hash } => Request::Resume { info_hash },
+        CtlCommand::Stats => Request::Stats,
+        CtlCommand::Limits { upload, download } => Request::SetLimits { upload, download },
+        CtlCommand::Schedule { file, upload, download, .. } => {
+            let rules = file.map(|file| {
+                fs::read_to_string(&file).unwrap_or_else(|err| fail(Failure::Disk, &format!("cannot read {}: {}", file.display(), err)))
+            });
+            Request::SetSchedule { rules, upload, download }
+        },
+        CtlCommand::AltLimits { upload, download } => Request::SetAlternateLimits { upload, download },
+        CtlCommand::AltSpeed { enabled } => Request::SetAlternateSpeed { enabled },
+        CtlCommand::SeedGoal { ratio, time } => Request::SetSeedGoal { ratio, time: time.map(Duration::from_secs) },
+        CtlCommand::Shutdown => Request::Shutdown
+    };
+    let response = runtime().block_on(control::send(socket, &request)).unwrap_or_else(|err| match err.kind() {
+        io::ErrorKind::Other => fail(Failure::Other, &err.to_string()),
+        _ => fail(Failure::Network, &format!("cannot reach the daemon at {}: {}", socket.display(), err))
+    });
+    if is_json() {
+        println!("{}", response);
+        return;
+    }
+    match request {
+        Request::Add { .. } | Request::AddMagnet { .. } => println!("Added {} ({}).", response["name"].as_str().unwrap_or("?"), response["info_hash"].as_str().unwrap_or("?")),
+        Request::Stats => {
+            for torrent in response["torrents"].as_array().into_iter().flatten() {
+                let state = match (torrent["paused"].as_bool(), torrent["queued"].as_bool()) {
+                    (Some(true), _) => "paused",
+                    (_, Some(true)) => "queued",
+                    _ => "active"
+                };
+                println!(
+                    "{}  {:5.1}%  down {}/s  up {}/s  {} peers  {}  {}",
+                    torrent["info_hash"].as_str().unwrap_or("?"),
+                    torrent["percent"].as_f64().unwrap_or(0.0),
+                    bytes(torrent["download_rate"].as_u64().unwrap_or(0)),
+                    bytes(torrent["upload_rate"].as_u64().unwrap_or(0)),
+                    torrent["peers"].as_u64().unwrap_or(0),
+                    state,
+                    torrent["name"].as_str().unwrap_or("?")
+                );
+            }
+        },
+        _ => println!("Done.")
+    }
+}
+
+// The level `-v` and `-q` ask for, or `None` to leave it to RUST_LOG.
+fn log_level(verbose: u8, quiet: bool) -> Option<&'static str> {
+    match (verbose, quiet) {
+        (_, true) => Some("error"),
+        (0, false) => None,
+        (1, false) => Some("info"),
+        (2, false) => Some("debug"),
+        _ => Some("trace")
+    }
+}
+
+fn logging(verbose: u8, quiet: bool, log_file: Option<&Path>) {
+    let filter = match log_level(verbose, quiet) {
+        Some(level) => EnvFilter::new(level),
+        None => EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("warn"))
+    };
+    let logs = tracing_subscriber::fmt().with_env_filter(filter);
+    match log_file {
+        Some(path) => {
+            let file = fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .unwrap_or_else(|err| fail(Failure::Disk, &format!("cannot open {}: {}", path.display(), err)));
+            logs.with_writer(Mutex::new(file)).with_ansi(false).init()
+        },
+        None => logs.with_writer(io::stderr).init()
+    }
+}
+
+fn main() {
+    let cli = Cli::try_parse().unwrap_or_else(|err| {
+        // `--output` hasn't been parsed when the rest fails to, so look for
+        // it by hand to report usage errors as JSON too.
+        let args: Vec<String> = env::args().collect();
+        let json = args.windows(2).any(|pair| pair[0] == "--output" && pair[1] == "json")
+            || args.iter().any(|arg| arg == "--output=json");
+        if json && err.use_stderr() {
+            let rendered = err.render().to_string();
+            let message: Vec<_> = rendered.lines().take_while(|line| !line.is_empty()).map(str::trim).collect();
+            let message = message.join(" ");
+            println!("{}", json!({ "error": message.trim_start_matches("error: "), "kind": Failure::Usage.name(), "code": err.exit_code() }));
+            process::exit(err.exit_code());
+        }
+        err.exit()
+    });
+    JSON.store(cli.format == Format::Json, Ordering::Relaxed);
+    if !cli.force {
+        let _ = LIMITS.set(Limits { max_total_length: cli.max_size, ..Limits::default() });
+    }
+    logging(cli.verbose, cli.quiet, cli.log_file.as_deref());
+    match cli.command {
+        Command::Decode { value, file } => decode(value, file.as_deref()),
+        Command::Encode { value, output } => encode(value, output.as_deref()),
+        Command::Info { torrent } => info(&torrent),
+        Command::Peers { torrent, probe, concurrency, timeout, geoip } => {
+            let geoip = geoip.map(|path| GeoIp::open(&path).unwrap_or_else(|err| fail(Failure::Disk, &format!("cannot open {}: {}", path.display(), err))));
+            let probing = probe.then_some(Probing { concurrency, timeout: Duration::from_secs(timeout), geoip });
+            peers(&torrent, probing)
+        },
+        Command::Announce { tracker, info_hash, event, numwant, port, left } => {
+            let mut request = Announce::new(info_hash, peer_id::generate(), port);
+            request.event = event.map(|event| match event {
+                Event::Started => AnnounceEvent::Started,
+                Event::Stopped => AnnounceEvent::Stopped,
+                Event::Completed => AnnounceEvent::Completed
+            });
+            request.numwant = numwant;
+            request.left = left;
+            announce(&tracker, &request)
+        },
+        Command::Handshake { torrent, peer } => handshake(&torrent, peer),
+        Command::MagnetHandshake { magnet, peers } => magnet_handshake(&magnet, peers),
+        Command::DownloadPiece { output, torrent, piece, peers } => download_piece(&output, read_torrent(&torrent), piece, peers),
+        Command::MagnetDownloadPiece { output, magnet, piece, peers } => magnet_download_piece(&output, &magnet, piece, peers),
+        Command::Download { output, download_dir, torrent, peers, move_to, limit_up, limit_down, files, seed, seed_ratio, seed_time } => {
+            let seed = seed.then(|| {
+                let goal = seed_ratio.map_or(SeedGoal::new(), |ratio| SeedGoal::new().with_ratio(ratio));
+                seed_time.map_or(goal, |secs| goal.with_time(Duration::from_secs(secs)))
+            });
+            let transfer = Transfer { limits: (limit_up, limit_down), files: files.as_deref(), seed };
+            download(output.as_deref(), &download_dir, &torrent, peers, move_to.as_deref(), transfer)
+        },
+        Command::Verify { torrent, data } => verify(&torrent, &data),
+        Command::Status { torrent, data } => status(&torrent, &data),
+        Command::Fastresume(FastresumeCommand::Import { torrent, fastresume, data }) => fastresume_import(&torrent, &fastresume, data.as_deref()),
+        Command::Fastresume(FastresumeCommand::Export { torrent, data, output }) => fastresume_export(&torrent, &data, output.as_deref()),
+        #[cfg(feature = "tui")]
+        Command::Tui { torrents, download_dir, port, peers } => tui(&torrents, &download_dir, port, peers),
+        Command::Dht(DhtCommand::Ping { node }) => dht_ping(node),
+        Command::Dht(DhtCommand::GetPeers { info_hash }) => dht_get_peers(info_hash),
+        Command::Dht(DhtCommand::Sample { nodes }) => dht_sample(nodes),
+        Command::Dht(DhtCommand::Put { secret, salt, seq, value }) => dht_put(secret, &salt, seq, &value),
+        Command::Dht(DhtCommand::Get { target, key, salt }) => dht_get(target, key, &salt),
+        Command::Daemon { port, socket, no_dht, no_upnp, rpc, transmission, download_dir, watch, watch_added } => {
+            let sources = Sources { rpc, transmission, download_dir, watch, watch_added };
+            daemon(port, &socket.unwrap_or_else(control::default_socket), no_dht, no_upnp, sources)
+        },
+        Command::Import { dir, socket, download_dir } => import(&dir, &socket.unwrap_or_else(control::default_socket), &download_dir),
+        Command::Ctl { socket, command } => ctl(&socket.unwrap_or_else(control::default_socket), command)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{destination, log_level, probe_row, ranges, Cli, Command, Failure};
+    use bittorrent_rs::engine::peer::PeerProbe;
+    use bittorrent_rs::engine::ListenPort;
+    use bittorrent_rs::extension::{ExtendedHandshake, UT_METADATA};
+    use bittorrent_rs::bencode::Value;
+    use bittorrent_rs::metainfo::Metainfo;
+    use clap::{CommandFactory, Parser};
+    use serde_json::json;
+    use std::collections::HashSet;
+    use std::path::{Path, PathBuf};
+
+    fn metainfo(files: Option<Value>) -> Metainfo {
+        let mut info = vec![("name", "album".into()), ("piece length", 16384.into()), ("pieces", Value::from(&[0; 20][..]))];
+        match files {
+            Some(files) => info.push(("files", files)),
+            None => info.push(("length", 10.into()))
+        }
+        Metainfo::from_bytes(&Value::dict([("info", Value::dict(info))]).encode()).unwrap()
+    }
+
+    #[test]
+    fn test_cli() {
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn test_failure_codes() {
+        let failures = [
+            Failure::Other,
+            Failure::Usage,
+            Failure::Parse,
+            Failure::Network,
+            Failure::NoPeers,
+            Failure::Hash,
+            Failure::Disk,
+            Failure::Interrupted
+        ];
+        let codes: HashSet<_> = failures.iter().map(|failure| failure.code()).collect();
+        let names: HashSet<_> = failures.iter().map(|failure| failure.name()).collect();
+        assert_eq!((codes.len(), names.len()), (failures.len(), failures.len()));
+        assert!(!codes.contains(&0));
+    }
+
+    #[test]
+    fn test_probe_row() {
+        let peer = "10.0.0.1:6881".parse().unwrap();
+        let extended = ExtendedHandshake::new().with_extension(UT_METADATA, 3).with_extension("ut_pex", 0).with_client("Tixati 3.25");
+        let probe = PeerProbe { peer_id: *b"-XX0001-abcdefghijkl", dht: true, extended: Some(extended), pieces: Some(30) };
+        let row = probe_row(peer, &Ok(probe), 40, None);
+        assert_eq!(row["client"], "Tixati 3.25");
+        assert_eq!((row["percent"].as_u64(), row["dht"].as_bool()), (Some(75), Some(true)));
+        assert_eq!(row["extensions"], json!(["ut_metadata"]));
+
+        let probe = PeerProbe { peer_id: *b"-qB4650-abcdefghijkl", dht: false, extended: None, pieces: None };
+        let row = probe_row(peer, &Ok(probe), 40, None);
+        assert_eq!((row["client"].as_str(), row["percent"].as_u64()), (Some("qBittorrent 4.6.5"), None));
+        let row = probe_row(peer, &Err(std::io::Error::other("refused")), 40, None);
+        assert_eq!(row["error"], "refused");
+    }
+
+    #[test]
+    fn test_log_level() {
+        assert_eq!(log_level(0, false), None);
+        assert_eq!(log_level(2, false), Some("debug"));
+        assert_eq!(log_level(5, false), Some("trace"));
+        assert_eq!(log_level(0, true), Some("error"));
+        let cli = Cli::try_parse_from(["bittorrent-rs", "info", "a.torrent", "-vv", "--log-file", "log.txt"]).unwrap();
+        assert_eq!((cli.verbose, cli.log_file), (2, Some(PathBuf::from("log.txt"))));
+        assert!(Cli::try_parse_from(["bittorrent-rs", "-v", "-q", "info", "a.torrent"]).is_err());
+    }
+
+    #[test]
+    fn test_listen_port() {
+        let cli = Cli::try_parse_from(["bittorrent-rs", "daemon", "--port", "6881-6889"]).unwrap();
+        assert!(matches!(cli.command, Command::Daemon { port: ListenPort::Range(6881, 6889), .. }));
+        assert!(Cli::try_parse_from(["bittorrent-rs", "daemon", "--port", "6889-6881"]).is_err());
+    }
+
+    #[test]
+    fn test_download_limits() {
+        let cli = Cli::try_parse_from(["bittorrent-rs", "download", "a.torrent", "--limit-up", "1.5M", "--limit-down", "500K"]).unwrap();
+        assert!(matches!(cli.command, Command::Download { limit_up: 1_500_000, limit_down: 500_000, .. }));
+        let cli = Cli::try_parse_from(["bittorrent-rs", "download", "a.torrent"]).unwrap();
+        assert!(matches!(cli.command, Command::Download { limit_up: 0, limit_down: 0, .. }));
+        assert!(Cli::try_parse_from(["bittorrent-rs", "download", "a.torrent", "--limit-up", "fast"]).is_err());
+    }
+
+    #[test]
+    fn test_download_seed() {
+        let cli = Cli::try_parse_from(["bittorrent-rs", "download", "a.torrent", "--seed", "--seed-ratio", "2", "--seed-time", "3600"]).unwrap();
+        assert!(matches!(cli.command, Command::Download { seed: true, seed_ratio: Some(2.0), seed_time: Some(3600), .. }));
+        let cli = Cli::try_parse_from(["bittorrent-rs", "download", "a.torrent"]).unwrap();
+        assert!(matches!(cli.command, Command::Download { seed: false, seed_ratio: None, seed_time: None, .. }));
+        assert!(Cli::try_parse_from(["bittorrent-rs", "download", "a.torrent", "--seed-ratio", "2"]).is_err());
+    }
+
+    #[test]
+    fn test_ranges() {
+        assert_eq!(ranges(&[0, 1, 2, 3, 4, 7, 9, 10]), "0-4, 7, 9-10");
+        assert_eq!(ranges(&[]), "");
+    }
+
+    #[test]
+    fn test_destination() {
+        let single = metainfo(None);
+        let mut renamed = single.clone();
+        assert_eq!(destination(&mut renamed, None, Path::new("downloads")), PathBuf::from("downloads"));
+        assert_eq!(renamed, single);
+        assert_eq!(destination(&mut renamed, Some(Path::new("/tmp/song.mp3")), Path::new(".")), PathBuf::from("/tmp"));
+        assert_eq!(renamed.files[0].path, PathBuf::from("song.mp3"));
+        let mut into_dir = single.clone();
+        assert_eq!(destination(&mut into_dir, Some(&std::env::temp_dir()), Path::new(".")), std::env::temp_dir());
+        assert_eq!(into_dir, single);
+
+        let files = Value::List(vec![Value::dict([("length", 10.into()), ("path", Value::List(vec!["a".into()]))])]);
+        let mut multi = metainfo(Some(files));
+        assert_eq!(destination(&mut multi, Some(Path::new("tunes")), Path::new("downloads")), PathBuf::from("."));
+        assert_eq!(multi.files[0].path, PathBuf::from("tunes/a"));
+    }
+}
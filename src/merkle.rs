@@ -0,0 +1,527 @@
+use crate::bencode::Value;
+use crate::storage::layout::FileEntry;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+// BitTorrent v2 (BEP 52) hashes each file on its own, as a merkle tree over
+// its 16 KiB blocks padded out to a power of two with zero hashes. The
+// metainfo carries each tree's root and its layer at the piece size; peers
+// hand out the rest on request, with the hashes that prove them. With a
+// block's own hash known, a bad block is caught as it arrives rather than
+// when the whole piece fails.
+
+pub type Hash = [u8; 32];
+
+pub const BLOCK_LEN: u32 = 16 * 1024;
+// The most hashes asked for at once, as many as peers will send.
+pub const MAX_HASHES: u32 = 512;
+
+pub fn sha256(data: &[u8]) -> Hash {
+    Sha256::digest(data).into()
+}
+
+fn parent(left: &Hash, right: &Hash) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+// The root of a subtree `layer` levels high over nothing but padding.
+pub fn pad(layer: u32) -> Hash {
+    (0..layer).fold([0; 32], |hash, _| parent(&hash, &hash))
+}
+
+// The root over `hashes` from `layer`, padded out to `width` of them.
+pub fn root(hashes: &[Hash], layer: u32, width: usize) -> Hash {
+    let mut nodes = hashes.to_vec();
+    nodes.resize(width.next_power_of_two().max(hashes.len()), pad(layer));
+    while nodes.len() > 1 {
+        nodes = nodes.chunks(2).map(|pair| parent(&pair[0], &pair[1])).collect();
+    }
+    nodes[0]
+}
+
+// A request for hashes from one file's tree: `length` of them from
+// `base_layer`, 0 being the blocks, starting at `index`, with what proves
+// them up to `proof_layers` above it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct HashRequest {
+    pub pieces_root: Hash,
+    pub base_layer: u32,
+    pub index: u32,
+    pub length: u32,
+    pub proof_layers: u32
+}
+
+pub const HASH_REQUEST_LEN: usize = 48;
+
+impl HashRequest {
+    // How many uncle hashes follow the requested ones. The requested hashes
+    // make the first layers of the proof themselves.
+    pub fn uncles(&self) -> u32 {
+        self.proof_layers.saturating_sub(self.length.trailing_zeros())
+    }
+
+    fn is_aligned(&self) -> bool {
+        self.length.is_power_of_two() && self.index.is_multiple_of(self.length)
+    }
+
+    pub fn encode_into(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.pieces_root);
+        for field in [self.base_layer, self.index, self.length, self.proof_layers] {
+            out.extend_from_slice(&field.to_be_bytes());
+        }
+    }
+
+    pub fn decode(bytes: &[u8]) -> Option<Self> {
+        let bytes: &[u8; HASH_REQUEST_LEN] = bytes.try_into().ok()?;
+        let field = |at: usize| u32::from_be_bytes(bytes[at..at + 4].try_into().unwrap());
+        Some(Self { pieces_root: bytes[..32].try_into().unwrap(), base_layer: field(32), index: field(36), length: field(40), proof_layers: field(44) })
+    }
+}
+
+// A file of the torrent as v2 knows it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MerkleFile {
+    pub path: PathBuf,
+    pub length: u64,
+    pub pieces_root: Hash,
+    // Where it starts in the v1 layout, which is always on a piece boundary.
+    pub offset: u64
+}
+
+// What a piece of a v2-only torrent hashes to, having no SHA-1 of its own:
+// the root of its file's tree over the piece's blocks, `width` of them
+// under it. Only the first `len` bytes of the piece are the file's; the
+// rest is padding up to the next file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PieceRoot {
+    pub root: Hash,
+    pub len: usize,
+    pub width: usize
+}
+
+impl PieceRoot {
+    pub fn matches(&self, data: &[u8]) -> bool {
+        let Some(data) = data.get(..self.len) else {
+            return false;
+        };
+        let blocks: Vec<Hash> = data.chunks(BLOCK_LEN as usize).map(sha256).collect();
+        root(&blocks, 0, self.width) == self.root
+    }
+}
+
+// The v2 half of a hybrid torrent, or all of a v2-only one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MerkleInfo {
+    // SHA-256 of the info dictionary.
+    pub info_hash: Hash,
+    // Files with content; empty ones have no tree.
+    pub files: Vec<MerkleFile>,
+    // The piece layer of each file longer than a piece, by its root. Only
+    // layers that hash up to their root are kept, as the info hash doesn't
+    // cover them.
+    pub piece_layers: HashMap<Hash, Vec<Hash>>
+}
+
+// Every file in a `file tree`, with its path from the torrent's directory.
+pub(crate) fn walk(tree: &Value, path: PathBuf, files: &mut Vec<(PathBuf, u64, Option<Hash>)>) -> Option<()> {
+    for (key, value) in tree.as_dict()? {
+        if key.is_empty() {
+            let length = u64::try_from(value.get("length")?.as_int()?).ok()?;
+            let root = match value.get("pieces root") {
+                Some(root) => Some(root.as_bytes()?.try_into().ok()?),
+                None => None
+            };
+            files.push((path.clone(), length, root));
+        } else {
+            walk(value, path.join(std::str::from_utf8(key).ok()?), files)?;
+        }
+    }
+    Some(())
+}
+
+impl MerkleInfo {
+    // The v2 parts of a torrent whose v1 `files` are laid out for them, as
+    // a hybrid torrent's are, padded so each file starts a piece. `None`
+    // for a v1-only torrent, or v2 parts that don't fit.
+    pub fn parse(raw: &Value, info: &[u8], name: &str, files: &[FileEntry], piece_length: u32) -> Option<Self> {
+        let info_dict = raw.get("info")?;
+        if info_dict.get("meta version")?.as_int()? != 2 || !piece_length.is_power_of_two() || piece_length < BLOCK_LEN {
+            return None;
+        }
+        let mut tree = Vec::new();
+        walk(info_dict.get("file tree")?, PathBuf::new(), &mut tree)?;
+        // A single file sits at the top of the tree under the torrent's name,
+        // where v1 has it too; files in a directory sit in the name.
+        let single = matches!(&tree[..], [(path, _, _)] if path == std::path::Path::new(name));
+
+        let mut offsets = HashMap::new();
+        let mut offset = 0;
+        for file in files {
+            offsets.insert(file.path.clone(), (offset, file.length));
+            offset += file.length;
+        }
+        let mut merkle_files = Vec::new();
+        for (path, length, root) in tree {
+            let Some(pieces_root) = root else {
+                continue;
+            };
+            let v1_path = if single { path.clone() } else { PathBuf::from(name).join(&path) };
+            let &(offset, v1_length) = offsets.get(&v1_path)?;
+            if v1_length != length || !offset.is_multiple_of(piece_length as u64) {
+                return None;
+            }
+            merkle_files.push(MerkleFile { path, length, pieces_root, offset });
+        }
+        merkle_files.sort_by_key(|file| file.offset);
+
+        let piece_layers = raw
+            .get("piece layers")
+            .and_then(Value::as_dict)
+            .into_iter()
+            .flatten()
+            .filter_map(|(root, layer)| {
+                let root: Hash = root[..].try_into().ok()?;
+                let layer: Vec<Hash> = layer
+                    .as_bytes()
+                    .filter(|layer| layer.len().is_multiple_of(32))?
+                    .chunks_exact(32)
+                    .map(|hash| hash.try_into().unwrap())
+                    .collect();
+                let file = merkle_files.iter().find(|file| file.pieces_root == root)?;
+                FileHashes::new(root, file.length, piece_length).set_piece_layer(&layer).then_some((root, layer))
+            })
+            .collect();
+        Some(Self { info_hash: sha256(info), files: merkle_files, piece_layers })
+    }
+
+    // What `piece` hashes to, where the files start on piece boundaries.
+    // `None` for a piece of padding, or of a file whose piece layer the
+    // metainfo lacks.
+    pub fn piece_root(&self, piece: u32, piece_length: u32) -> Option<PieceRoot> {
+        let offset = piece as u64 * piece_length as u64;
+        let file = self.files.partition_point(|file| file.offset <= offset).checked_sub(1).map(|file| &self.files[file])?;
+        if offset >= file.offset + file.length {
+            return None;
+        }
+        let index = ((offset - file.offset) / piece_length as u64) as usize;
+        let len = (file.offset + file.length - offset).min(piece_length as u64) as usize;
+        // A file no longer than a piece has its root for its one piece hash.
+        match file.length <= piece_length as u64 {
+            true => Some(PieceRoot { root: file.pieces_root, len, width: len.div_ceil(BLOCK_LEN as usize) }),
+            false => {
+                let root = *self.piece_layers.get(&file.pieces_root)?.get(index)?;
+                Some(PieceRoot { root, len, width: (piece_length / BLOCK_LEN) as usize })
+            }
+        }
+    }
+}
+
+// What's known of one file's tree: its root, from the metainfo, and any of
+// its piece and block layers that have been proved against it.
+#[derive(Debug, Clone)]
+pub struct FileHashes {
+    pieces_root: Hash,
+    length: u64,
+    // Layers above the blocks of the pieces, and of the root.
+    piece_height: u32,
+    height: u32,
+    pieces: Vec<Option<Hash>>,
+    blocks: Vec<Option<Hash>>
+}
+
+impl FileHashes {
+    pub fn new(pieces_root: Hash, length: u64, piece_length: u32) -> Self {
+        let blocks = length.div_ceil(BLOCK_LEN as u64).max(1) as usize;
+        let height = blocks.next_power_of_two().trailing_zeros();
+        // A file no longer than a piece has its root for its one piece hash.
+        let piece_height = (piece_length / BLOCK_LEN).trailing_zeros().min(height);
+        let mut pieces = vec![None; blocks.div_ceil(1 << piece_height)];
+        if piece_height == height {
+            pieces[0] = Some(pieces_root);
+        }
+        Self { pieces_root, length, piece_height, height, pieces, blocks: vec![None; blocks] }
+    }
+
+    pub fn pieces_root(&self) -> &Hash {
+        &self.pieces_root
+    }
+
+    // Takes a piece layer, such as the metainfo's, if it hashes to the root.
+    pub fn set_piece_layer(&mut self, layer: &[Hash]) -> bool {
+        if layer.len() != self.pieces.len() || root(layer, self.piece_height, 1 << (self.height - self.piece_height)) != self.pieces_root {
+            return false;
+        }
+        self.store(self.piece_height, 0, layer);
+        true
+    }
+
+    pub fn piece_layer(&self) -> Option<Vec<Hash>> {
+        self.pieces.iter().copied().collect()
+    }
+
+    fn store(&mut self, layer: u32, start: usize, hashes: &[Hash]) {
+        let fill = |known: &mut Vec<Option<Hash>>| {
+            for (slot, hash) in known.iter_mut().skip(start).zip(hashes) {
+                *slot = Some(*hash);
+            }
+        };
+        if layer == self.piece_height {
+            fill(&mut self.pieces);
+        }
+        if layer == 0 {
+            fill(&mut self.blocks);
+        }
+    }
+
+    // A node of the tree, if it's known or follows from what is.
+    fn node(&self, layer: u32, index: usize) -> Option<Hash> {
+        if layer > self.height || index >= 1 << (self.height - layer) {
+            return None;
+        }
+        if layer == self.height {
+            return Some(self.pieces_root);
+        }
+        // Past the end of the file, it's all padding.
+        if index << layer >= self.blocks.len() {
+            return Some(pad(layer));
+        }
+        match layer {
+            layer if layer == self.piece_height => self.pieces[index],
+            0 => self.blocks[index],
+            _ => Some(parent(&self.node(layer - 1, 2 * index)?, &self.node(layer - 1, 2 * index + 1)?))
+        }
+    }
+
+    // What to ask a peer for to check `piece`'s blocks: their hashes, or,
+    // without the piece's own hash to prove them by, the piece layer around
+    // it. `None` once they're known.
+    pub fn request(&self, piece: usize) -> Option<HashRequest> {
+        if self.pieces.get(piece)?.is_none() {
+            let length = (1 << (self.height - self.piece_height)).min(MAX_HASHES);
+            let index = piece as u32 / length * length;
+            let proof_layers = self.height - self.piece_height;
+            return Some(HashRequest { pieces_root: self.pieces_root, base_layer: self.piece_height, index, length, proof_layers });
+        }
+        let piece_blocks = 1 << self.piece_height;
+        let first = piece * piece_blocks;
+        if self.blocks[first..(first + piece_blocks).min(self.blocks.len())].iter().all(Option::is_some) {
+            return None;
+        }
+        let (index, length) = (first as u32, piece_blocks as u32);
+        Some(HashRequest { pieces_root: self.pieces_root, base_layer: 0, index, length, proof_layers: self.piece_height })
+    }
+
+    // Keeps the hashes a peer sent for `request` if they and their uncles
+    // hash up to a node that's already known.
+    pub fn add_hashes(&mut self, request: &HashRequest, hashes: &[Hash]) -> bool {
+        let length = request.length as usize;
+        if !request.is_aligned() || hashes.len() != length + request.uncles() as usize {
+            return false;
+        }
+        let (base, uncles) = hashes.split_at(length);
+        let mut layer = request.base_layer + request.length.trailing_zeros();
+        let mut index = request.index as usize / length;
+        let mut node = root(base, request.base_layer, length);
+        for uncle in uncles {
+            node = match index % 2 {
+                0 => parent(&node, uncle),
+                _ => parent(uncle, &node)
+            };
+            (layer, index) = (layer + 1, index / 2);
+        }
+        if self.node(layer, index) != Some(node) {
+            return false;
+        }
+        self.store(request.base_layer, request.index as usize, base);
+        true
+    }
+
+    // `Some(false)` for a block that isn't what the tree says, `None` while
+    // its hash isn't known.
+    pub fn verify_block(&self, block: usize, data: &[u8]) -> Option<bool> {
+        let expected = (*self.blocks.get(block)?)?;
+        Some(sha256(data) == expected)
+    }
+
+    // The hashes for a peer's request, from what's known. `None` rejects it.
+    pub fn serve(&self, request: &HashRequest) -> Option<Vec<Hash>> {
+        if !request.is_aligned() || request.length > MAX_HASHES {
+            return None;
+        }
+        let mut hashes = (0..request.length as usize)
+            .map(|at| self.node(request.base_layer, request.index as usize + at))
+            .collect::<Option<Vec<_>>>()?;
+        let mut layer = request.base_layer + request.length.trailing_zeros();
+        let mut index = (request.index / request.length) as usize;
+        for _ in 0..request.uncles() {
+            if layer >= self.height {
+                return None;
+            }
+            hashes.push(self.node(layer, index ^ 1)?);
+            (layer, index) = (layer + 1, index / 2);
+        }
+        Some(hashes)
+    }
+}
+
+// A v2 torrent's file trees, for checking its blocks as they arrive.
+// Pieces are found in them by where the files start in the v1 layout, or
+// the one a v2-only torrent is given.
+#[derive(Debug, Clone)]
+pub struct BlockVerifier {
+    piece_length: u32,
+    files: Vec<(u64, FileHashes)>
+}
+
+impl BlockVerifier {
+    pub fn new(info: &MerkleInfo, piece_length: u32) -> Self {
+        let mut files: Vec<_> = info
+            .files
+            .iter()
+            .map(|file| {
+                let mut hashes = FileHashes::new(file.pieces_root, file.length, piece_length);
+                if let Some(layer) = info.piece_layers.get(&file.pieces_root) {
+                    hashes.set_piece_layer(layer);
+                }
+                (file.offset, hashes)
+            })
+            .collect();
+        files.sort_by_key(|(offset, _)| *offset);
+        Self { piece_length, files }
+    }
+
+    // The file `piece` starts in, and its index there. Pieces of padding
+    // are in none.
+    fn locate(&self, piece: u32) -> Option<(&FileHashes, usize)> {
+        let offset = piece as u64 * self.piece_length as u64;
+        let file = self.files.partition_point(|(start, _)| *start <= offset).checked_sub(1)?;
+        let (start, hashes) = &self.files[file];
+        (offset < start + hashes.length).then(|| (hashes, ((offset - start) / self.piece_length as u64) as usize))
+    }
+
+    fn file_mut(&mut self, pieces_root: &Hash) -> Option<&mut FileHashes> {
+        self.files.iter_mut().map(|(_, hashes)| hashes).find(|hashes| hashes.pieces_root == *pieces_root)
+    }
+
+    // Checks a block of `piece` at `begin`. Where the block runs past its
+    // file into padding, only the file's part counts.
+    pub fn verify_block(&self, piece: u32, begin: u32, data: &[u8]) -> Option<bool> {
+        let (hashes, index) = self.locate(piece)?;
+        let at = index as u64 * self.piece_length as u64 + begin as u64;
+        if !begin.is_multiple_of(BLOCK_LEN) || at >= hashes.length {
+            return None;
+        }
+        let len = (hashes.length - at).min(BLOCK_LEN as u64) as usize;
+        if data.len() != len && data.len() != BLOCK_LEN as usize {
+            return None;
+        }
+        hashes.verify_block((at / BLOCK_LEN as u64) as usize, &data[..len])
+    }
+
+    pub fn request(&self, piece: u32) -> Option<HashRequest> {
+        let (hashes, index) = self.locate(piece)?;
+        hashes.request(index)
+    }
+
+    pub fn add_hashes(&mut self, request: &HashRequest, hashes: &[Hash]) -> bool {
+        self.file_mut(&request.pieces_root).is_some_and(|file| file.add_hashes(request, hashes))
+    }
+
+    pub fn serve(&self, request: &HashRequest) -> Option<Vec<Hash>> {
+        let (_, file) = self.files.iter().find(|(_, hashes)| hashes.pieces_root == request.pieces_root)?;
+        file.serve(request)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::merkle::{pad, root, sha256, BlockVerifier, FileHashes, Hash, HashRequest, MerkleFile, MerkleInfo, BLOCK_LEN};
+    use std::collections::HashMap;
+
+    const PIECE_LENGTH: u32 = 4 * BLOCK_LEN;
+
+    // A file of `blocks` blocks and a bit, and its tree's layers.
+    fn file(blocks: usize) -> (Vec<u8>, Vec<Hash>, Vec<Hash>, Hash) {
+        let data: Vec<u8> = (0..blocks * BLOCK_LEN as usize + 100).map(|at| (at / 7) as u8).collect();
+        let leaves: Vec<Hash> = data.chunks(BLOCK_LEN as usize).map(sha256).collect();
+        let width = leaves.len().next_power_of_two();
+        let pieces: Vec<Hash> = leaves.chunks(4).map(|piece| root(piece, 0, 4)).collect();
+        (data, leaves.clone(), pieces, root(&leaves, 0, width))
+    }
+
+    #[test]
+    fn test_tree() {
+        assert_eq!(pad(1), sha256(&[0; 64]));
+        let (_, leaves, pieces, pieces_root) = file(9);
+        assert_eq!(root(&pieces, 2, 4), pieces_root);
+        assert_eq!(root(&leaves[..1], 0, 1), leaves[0]);
+
+        let mut hashes = FileHashes::new(pieces_root, 9 * BLOCK_LEN as u64 + 100, PIECE_LENGTH);
+        assert!(!hashes.set_piece_layer(&pieces[..2]));
+        assert!(hashes.set_piece_layer(&pieces));
+        assert_eq!(hashes.piece_layer(), Some(pieces.clone()));
+
+        // Block hashes come proved by the piece's hash; the last piece is
+        // mostly padding.
+        let request = hashes.request(2).unwrap();
+        assert_eq!((request.base_layer, request.index, request.length, request.uncles()), (0, 8, 4, 0));
+        let mut sent = leaves[8..].to_vec();
+        sent.resize(4, [0; 32]);
+        assert!(!hashes.add_hashes(&request, &[[1; 32]; 4]));
+        assert_eq!(hashes.verify_block(8, b"whatever"), None);
+        assert!(hashes.add_hashes(&request, &sent));
+        assert_eq!(hashes.request(2), None);
+        assert_eq!(hashes.verify_block(9, b"whatever"), Some(false));
+
+        // What a peer can be given, with its uncles, proves out the same way.
+        let asked = HashRequest { pieces_root, base_layer: 2, index: 2, length: 1, proof_layers: 2 };
+        let served = hashes.serve(&asked).unwrap();
+        assert_eq!(served, [pieces[2], pad(2), root(&pieces[..2], 2, 2)]);
+        let mut fresh = FileHashes::new(pieces_root, 9 * BLOCK_LEN as u64 + 100, PIECE_LENGTH);
+        assert_eq!(fresh.request(2).map(|request| (request.base_layer, request.length, request.uncles())), Some((2, 4, 0)));
+        assert!(fresh.add_hashes(&asked, &served));
+        assert!(!fresh.add_hashes(&asked, &[pieces[1], served[1], served[2]]));
+        assert_eq!(fresh.serve(&HashRequest { base_layer: 0, ..asked }), None);
+    }
+
+    #[test]
+    fn test_verifier() {
+        let (data, leaves, pieces, pieces_root) = file(5);
+        let (small, small_leaves, _, small_root) = file(0);
+        let info = MerkleInfo {
+            info_hash: [0; 32],
+            files: vec![
+                MerkleFile { path: "b".into(), length: small.len() as u64, pieces_root: small_root, offset: 2 * PIECE_LENGTH as u64 },
+                MerkleFile { path: "a".into(), length: data.len() as u64, pieces_root, offset: 0 }
+            ],
+            piece_layers: HashMap::from([(pieces_root, pieces)])
+        };
+        let mut verifier = BlockVerifier::new(&info, PIECE_LENGTH);
+        let block = |at: usize| &data[at * BLOCK_LEN as usize..(at + 1) * BLOCK_LEN as usize];
+
+        let request = verifier.request(1).unwrap();
+        assert_eq!((request.pieces_root, request.index), (pieces_root, 4));
+        let mut sent = leaves[4..].to_vec();
+        sent.resize(4, [0; 32]);
+        assert!(verifier.add_hashes(&request, &sent));
+        assert_eq!(verifier.verify_block(1, 0, block(4)), Some(true));
+        assert_eq!(verifier.verify_block(1, 0, block(3)), Some(false));
+        // The rest of the block is padding up to the next file.
+        let mut tail = data[5 * BLOCK_LEN as usize..].to_vec();
+        tail.resize(BLOCK_LEN as usize, 0);
+        assert_eq!(verifier.verify_block(1, BLOCK_LEN, &tail), Some(true));
+        assert_eq!(verifier.verify_block(1, 2 * BLOCK_LEN, &[0; BLOCK_LEN as usize]), None);
+        assert_eq!(verifier.verify_block(0, 0, block(0)), None);
+
+        // A file no longer than a piece is proved by its root alone.
+        let request = verifier.request(2).unwrap();
+        assert_eq!((request.pieces_root, request.index, request.length), (small_root, 0, 1));
+        assert!(verifier.add_hashes(&request, &small_leaves));
+        assert_eq!(verifier.verify_block(2, 0, &small), Some(true));
+        assert_eq!(verifier.request(3), None);
+    }
+}
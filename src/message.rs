@@ -0,0 +1,292 @@
+use crate::bitfield::Bitfield;
+use crate::block::BlockRequest;
+use crate::merkle::{Hash, HashRequest, HASH_REQUEST_LEN};
+use crate::piece::{PieceGeometry, RequestError};
+use bytes::Bytes;
+use std::fmt;
+
+// Longer messages are refused; the largest legitimate ones are a bitfield
+// for a torrent with millions of pieces or a maximum-size block.
+pub const MAX_MESSAGE_LEN: usize = 1 << 20;
+
+// A peer wire message (BEP 3), after the handshake.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    KeepAlive,
+    Choke,
+    Unchoke,
+    Interested,
+    NotInterested,
+    Have(u32),
+    Bitfield(Vec<u8>),
+    Request(BlockRequest),
+    // A block's data is shared, not owned, so it goes from the socket to the
+    // disk, or the disk to the socket, without being copied on the way.
+    Piece { index: u32, begin: u32, data: Bytes },
+    Cancel(BlockRequest),
+    Port(u16),
+    // An extension protocol message (BEP 10): 0 is the extended handshake,
+    // the rest are whatever ids the receiving side handed out in it.
+    Extended { id: u8, payload: Vec<u8> },
+    // A v2 file's tree hashes (BEP 52): asked for, sent with their proof,
+    // or refused.
+    HashRequest(HashRequest),
+    Hashes { request: HashRequest, hashes: Vec<Hash> },
+    HashReject(HashRequest)
+}
+
+// How a message breaks the protocol for the torrent it came on. A peer that
+// sends one is dropped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Violation {
+    InvalidPiece(u32),
+    // The wrong length, or bits set past the last piece.
+    InvalidBitfield,
+    InvalidBlock(RequestError)
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Violation::InvalidPiece(piece) => write!(f, "no piece {}", piece),
+            Violation::InvalidBitfield => write!(f, "bitfield doesn't match the pieces"),
+            Violation::InvalidBlock(err) => write!(f, "invalid block: {}", err)
+        }
+    }
+}
+
+impl std::error::Error for Violation {}
+
+fn u32_at(payload: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(payload.get(at..at + 4)?.try_into().ok()?))
+}
+
+fn request(payload: &[u8]) -> Option<BlockRequest> {
+    if payload.len() != 12 {
+        return None;
+    }
+    Some(BlockRequest::new(u32_at(payload, 0)?, u32_at(payload, 4)?, u32_at(payload, 8)?))
+}
+
+impl Message {
+    // The message with its four-byte length prefix.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut payload = Vec::new();
+        let mut push_request = |id: u8, request: &BlockRequest| {
+            payload.push(id);
+            payload.extend_from_slice(&request.index.to_be_bytes());
+            payload.extend_from_slice(&request.begin.to_be_bytes());
+            payload.extend_from_slice(&request.length.to_be_bytes());
+        };
+        match self {
+            Self::KeepAlive => {},
+            Self::Choke => payload.push(0),
+            Self::Unchoke => payload.push(1),
+            Self::Interested => payload.push(2),
+            Self::NotInterested => payload.push(3),
+            Self::Have(index) => {
+                payload.push(4);
+                payload.extend_from_slice(&index.to_be_bytes());
+            },
+            Self::Bitfield(bits) => {
+                payload.push(5);
+                payload.extend_from_slice(bits);
+            },
+            Self::Request(request) => push_request(6, request),
+            Self::Piece { index, begin, data } => {
+                payload.push(7);
+                payload.extend_from_slice(&index.to_be_bytes());
+                payload.extend_from_slice(&begin.to_be_bytes());
+                payload.extend_from_slice(data);
+            },
+            Self::Cancel(request) => push_request(8, request),
+            Self::Port(port) => {
+                payload.push(9);
+                payload.extend_from_slice(&port.to_be_bytes());
+            },
+            Self::Extended { id, payload: body } => {
+                payload.push(20);
+                payload.push(*id);
+                payload.extend_from_slice(body);
+            },
+            Self::HashRequest(request) => {
+                payload.push(21);
+                request.encode_into(&mut payload);
+            },
+            Self::Hashes { request, hashes } => {
+                payload.push(22);
+                request.encode_into(&mut payload);
+                hashes.iter().for_each(|hash| payload.extend_from_slice(hash));
+            },
+            Self::HashReject(request) => {
+                payload.push(23);
+                request.encode_into(&mut payload);
+            }
+        }
+
+        let mut bytes = (payload.len() as u32).to_be_bytes().to_vec();
+        bytes.extend_from_slice(&payload);
+        bytes
+    }
+
+    // The length of `encode()`'s output, without building it.
+    pub fn wire_len(&self) -> usize {
+        4 + match self {
+            Self::KeepAlive => 0,
+            Self::Choke | Self::Unchoke | Self::Interested | Self::NotInterested => 1,
+            Self::Have(_) => 5,
+            Self::Bitfield(bits) => 1 + bits.len(),
+            Self::Request(_) | Self::Cancel(_) => 13,
+            Self::Piece { data, .. } => 9 + data.len(),
+            Self::Port(_) => 3,
+            Self::Extended { payload, .. } => 2 + payload.len(),
+            Self::HashRequest(_) | Self::HashReject(_) => 1 + HASH_REQUEST_LEN,
+            Self::Hashes { hashes, .. } => 1 + HASH_REQUEST_LEN + 32 * hashes.len()
+        }
+    }
+
+    // Checks every piece index, offset and length in the message against
+    // the torrent's geometry.
+    pub fn validate(&self, geometry: &PieceGeometry) -> Result<(), Violation> {
+        match self {
+            Self::Have(piece) if *piece >= geometry.num_pieces() => Err(Violation::InvalidPiece(*piece)),
+            Self::Bitfield(bits) if Bitfield::from_bytes(bits, geometry.num_pieces() as usize).is_none() => Err(Violation::InvalidBitfield),
+            Self::Request(request) | Self::Cancel(request) => geometry.validate_request(request).map_err(Violation::InvalidBlock),
+            Self::Piece { index, begin, data } => {
+                let block = BlockRequest::new(*index, *begin, u32::try_from(data.len()).unwrap_or(u32::MAX));
+                geometry.validate_request(&block).map_err(Violation::InvalidBlock)
+            },
+            _ => Ok(())
+        }
+    }
+
+    // Parses a message without its length prefix. `None` for unknown or
+    // malformed messages.
+    pub fn decode(payload: &[u8]) -> Option<Self> {
+        let Some((&id, rest)) = payload.split_first() else {
+            return Some(Self::KeepAlive);
+        };
+        let message = match (id, rest.len()) {
+            (0, 0) => Self::Choke,
+            (1, 0) => Self::Unchoke,
+            (2, 0) => Self::Interested,
+            (3, 0) => Self::NotInterested,
+            (4, 4) => Self::Have(u32_at(rest, 0)?),
+            (5, _) => Self::Bitfield(rest.to_vec()),
+            (6, _) => Self::Request(request(rest)?),
+            (7, len) if len >= 8 => Self::Piece {
+                index: u32_at(rest, 0)?,
+                begin: u32_at(rest, 4)?,
+                data: Bytes::copy_from_slice(&rest[8..])
+            },
+            (8, _) => Self::Cancel(request(rest)?),
+            (9, 2) => Self::Port(u16::from_be_bytes([rest[0], rest[1]])),
+            (20, len) if len >= 1 => Self::Extended { id: rest[0], payload: rest[1..].to_vec() },
+            (21, _) => Self::HashRequest(HashRequest::decode(rest)?),
+            (22, len) if len >= HASH_REQUEST_LEN && (len - HASH_REQUEST_LEN).is_multiple_of(32) => Self::Hashes {
+                request: HashRequest::decode(&rest[..HASH_REQUEST_LEN])?,
+                hashes: rest[HASH_REQUEST_LEN..].chunks(32).map(|hash| hash.try_into().unwrap()).collect()
+            },
+            (23, _) => Self::HashReject(HashRequest::decode(rest)?),
+            _ => return None
+        };
+        Some(message)
+    }
+}
+
+// One line per message for the logs, with payloads as their lengths.
+impl fmt::Display for Message {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::KeepAlive => write!(f, "keep-alive"),
+            Self::Choke => write!(f, "choke"),
+            Self::Unchoke => write!(f, "unchoke"),
+            Self::Interested => write!(f, "interested"),
+            Self::NotInterested => write!(f, "not interested"),
+            Self::Have(index) => write!(f, "have {index}"),
+            Self::Bitfield(bits) => write!(f, "bitfield of {} bytes", bits.len()),
+            Self::Request(request) => write!(f, "request {}:{}+{}", request.index, request.begin, request.length),
+            Self::Piece { index, begin, data } => write!(f, "piece {index}:{begin}+{}", data.len()),
+            Self::Cancel(request) => write!(f, "cancel {}:{}+{}", request.index, request.begin, request.length),
+            Self::Port(port) => write!(f, "port {port}"),
+            Self::Extended { id, payload } => write!(f, "extended {id} of {} bytes", payload.len()),
+            Self::HashRequest(request) => write!(f, "hash request {}:{}+{}", request.base_layer, request.index, request.length),
+            Self::Hashes { request, hashes } => write!(f, "hashes {}:{}+{} with {}", request.base_layer, request.index, request.length, hashes.len()),
+            Self::HashReject(request) => write!(f, "hash reject {}:{}+{}", request.base_layer, request.index, request.length)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::block::BlockRequest;
+    use crate::merkle::HashRequest;
+    use crate::message::{Message, Violation};
+    use crate::piece::{PieceGeometry, RequestError};
+    use bytes::Bytes;
+
+    #[test]
+    fn test_roundtrip() {
+        let hash_request = HashRequest { pieces_root: [7; 32], base_layer: 0, index: 8, length: 4, proof_layers: 2 };
+        let messages = [
+            Message::KeepAlive,
+            Message::Unchoke,
+            Message::Have(7),
+            Message::Bitfield(vec![0xf0]),
+            Message::Request(BlockRequest::new(1, 16384, 16384)),
+            Message::Piece { index: 1, begin: 0, data: Bytes::from_static(b"abc") },
+            Message::Cancel(BlockRequest::new(1, 0, 16384)),
+            Message::Port(6881),
+            Message::Extended { id: 0, payload: b"de".to_vec() },
+            Message::HashRequest(hash_request),
+            Message::Hashes { request: hash_request, hashes: vec![[3; 32], [4; 32]] },
+            Message::HashReject(hash_request)
+        ];
+        for message in messages {
+            let bytes = message.encode();
+            let len = u32::from_be_bytes(bytes[..4].try_into().unwrap()) as usize;
+            assert_eq!(len, bytes.len() - 4);
+            assert_eq!(message.wire_len(), bytes.len());
+            assert_eq!(Message::decode(&bytes[4..]), Some(message));
+        }
+        assert_eq!(Message::Have(1).encode(), [0, 0, 0, 5, 4, 0, 0, 0, 1]);
+    }
+
+    #[test]
+    fn test_malformed() {
+        assert_eq!(Message::decode(&[0, 1]), None);
+        assert_eq!(Message::decode(&[4, 0, 0]), None);
+        assert_eq!(Message::decode(&[7, 0, 0, 0, 1]), None);
+        assert_eq!(Message::decode(&[42]), None);
+        assert_eq!(Message::decode(&[20]), None);
+        assert_eq!(Message::decode(&[21; 48]), None);
+        assert_eq!(Message::decode(&[22; 50]), None);
+    }
+
+    #[test]
+    fn test_display() {
+        assert_eq!(Message::NotInterested.to_string(), "not interested");
+        assert_eq!(Message::Request(BlockRequest::new(1, 16384, 16384)).to_string(), "request 1:16384+16384");
+        assert_eq!(Message::Piece { index: 1, begin: 0, data: vec![0; 5].into() }.to_string(), "piece 1:0+5");
+    }
+
+    #[test]
+    fn test_validate() {
+        // Three pieces, the last of 1000 bytes.
+        let geometry = PieceGeometry::new(32768, 66536).unwrap();
+        let block = |index, begin, len| Message::Piece { index, begin, data: vec![0; len].into() };
+        assert_eq!(Message::Have(2).validate(&geometry), Ok(()));
+        assert_eq!(Message::Have(3).validate(&geometry), Err(Violation::InvalidPiece(3)));
+        assert_eq!(Message::Bitfield(vec![0xe0]).validate(&geometry), Ok(()));
+        assert_eq!(Message::Bitfield(vec![0xf0]).validate(&geometry), Err(Violation::InvalidBitfield));
+        assert_eq!(Message::Bitfield(vec![0xe0, 0]).validate(&geometry), Err(Violation::InvalidBitfield));
+        assert_eq!(Message::Request(BlockRequest::new(2, 0, 1000)).validate(&geometry), Ok(()));
+        assert_eq!(Message::Cancel(BlockRequest::new(2, 16384, 16384)).validate(&geometry), Err(Violation::InvalidBlock(RequestError::OutOfRange)));
+        assert_eq!(Message::Request(BlockRequest::new(0, 0, 0)).validate(&geometry), Err(Violation::InvalidBlock(RequestError::ZeroLength)));
+        assert_eq!(block(1, 16384, 16384).validate(&geometry), Ok(()));
+        assert_eq!(block(5, 0, 16384).validate(&geometry), Err(Violation::InvalidBlock(RequestError::InvalidPiece)));
+        assert_eq!(block(2, 999, 2).validate(&geometry), Err(Violation::InvalidBlock(RequestError::OutOfRange)));
+        assert_eq!(block(0, u32::MAX, 16384).validate(&geometry), Err(Violation::InvalidBlock(RequestError::OutOfRange)));
+        assert_eq!(Message::Port(1).validate(&geometry), Ok(()));
+    }
+}
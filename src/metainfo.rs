@@ -0,0 +1,493 @@
+use crate::bencode::{self, Value};
+use crate::hash::{sha1, PieceHash};
+use crate::merkle::{self, MerkleInfo};
+use crate::storage::layout::{FileEntry, Layout};
+use crate::storage::sanitize::sanitize;
+use std::fmt;
+use std::path::{Component, Path, PathBuf};
+
+// What a torrent may ask of us before we take it on. Well made torrents
+// stay far inside these; one outside them is broken or hostile, and would
+// have us allocate a bitfield for billions of pieces or claim the disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    pub max_pieces: u64,
+    pub min_piece_length: u32,
+    pub max_piece_length: u32,
+    // No cap if `None`.
+    pub max_total_length: Option<u64>
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self { max_pieces: 1 << 22, min_piece_length: 16 * 1024, max_piece_length: 256 * 1024 * 1024, max_total_length: None }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitError {
+    TooManyPieces(u64),
+    PieceLengthTooSmall(u32),
+    PieceLengthTooLarge(u32),
+    // `None` if the file lengths don't even add up in 64 bits.
+    TooLarge(Option<u64>)
+}
+
+impl fmt::Display for LimitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LimitError::TooManyPieces(pieces) => write!(f, "too many pieces ({})", pieces),
+            LimitError::PieceLengthTooSmall(length) => write!(f, "piece length {} is too small", length),
+            LimitError::PieceLengthTooLarge(length) => write!(f, "piece length {} is too large", length),
+            LimitError::TooLarge(Some(length)) => write!(f, "too large ({} bytes)", length),
+            LimitError::TooLarge(None) => write!(f, "too large to add up")
+        }
+    }
+}
+
+impl std::error::Error for LimitError {}
+
+// A parsed .torrent file (BEP 3, with the announce-list of BEP 12).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Metainfo {
+    pub info_hash: [u8; 20],
+    pub announce: Option<String>,
+    pub announce_list: Vec<Vec<String>>,
+    pub name: String,
+    pub piece_length: u32,
+    // Empty for a v2-only torrent, whose pieces are checked by its trees.
+    pub pieces: Vec<[u8; 20]>,
+    // A single-file torrent has one entry named after the torrent;
+    // multi-file torrents nest their files in a directory of that name.
+    pub files: Vec<FileEntry>,
+    pub private: bool,
+    // The per-file hash trees of BEP 52. A hybrid torrent's v1 pieces are
+    // what's downloaded, and the trees check the blocks in them; a v2-only
+    // torrent's files are laid out as a hybrid's would be.
+    pub v2: Option<MerkleInfo>,
+    // The whole file, for keys not parsed here such as DHT `nodes`.
+    pub raw: Value
+}
+
+fn string(value: &Value) -> Option<String> {
+    value.as_str().map(str::to_string)
+}
+
+// Whether `name` is a single file or directory name, which joined onto the
+// download directory stays inside it. `..`, an absolute path or a Windows
+// drive would have a torrent write wherever it liked.
+pub(crate) fn is_plain_name(name: &str) -> bool {
+    let mut components = Path::new(name).components();
+    let plain = matches!((components.next(), components.next()), (Some(Component::Normal(part)), None) if part == name);
+    plain && !name.contains(['/', '\\'])
+}
+
+fn files(info: &Value, name: &str) -> Option<Vec<FileEntry>> {
+    let length = |value: &Value| u64::try_from(value.get("length")?.as_int()?).ok();
+    if !is_plain_name(name) {
+        return None;
+    }
+    let Some(files) = info.get("files") else {
+        return Some(vec![FileEntry::new(name, length(info)?)]);
+    };
+
+    files
+        .as_list()?
+        .iter()
+        .map(|file| {
+            let mut path = PathBuf::from(name);
+            for component in file.get("path")?.as_list()? {
+                let component = component.as_str()?;
+                if !is_plain_name(component) {
+                    return None;
+                }
+                path.push(component);
+            }
+            Some(FileEntry::new(path, length(file)?))
+        })
+        .collect()
+}
+
+// A v2-only torrent's files in the order of its file tree, with padding
+// before each file that doesn't start a piece (BEP 47), so they're laid out
+// as v2 pieces them.
+fn v2_files(info: &Value, name: &str, piece_length: u32) -> Option<Vec<FileEntry>> {
+    if !is_plain_name(name) || piece_length == 0 {
+        return None;
+    }
+    let mut tree = Vec::new();
+    merkle::walk(info.get("file tree")?, PathBuf::new(), &mut tree)?;
+    let single = matches!(&tree[..], [(path, _, _)] if path == Path::new(name));
+    let mut files = Vec::new();
+    let mut offset = 0;
+    for (path, length, _) in tree {
+        let plain = |component: Component| matches!(component, Component::Normal(part) if part.to_str().is_some_and(is_plain_name));
+        if !path.components().all(plain) {
+            return None;
+        }
+        let padding = (piece_length as u64 - offset % piece_length as u64) % piece_length as u64;
+        if padding > 0 && length > 0 {
+            files.push(FileEntry::new(Path::new(name).join(".pad").join(padding.to_string()), padding));
+            offset = offset.checked_add(padding)?;
+        }
+        files.push(FileEntry::new(if single { path } else { Path::new(name).join(path) }, length));
+        offset = offset.checked_add(length)?;
+    }
+    Some(files)
+}
+
+impl Metainfo {
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let raw = bencode::decode(bytes)?;
+        let info = raw.get("info")?;
+        let name = info.get("name")?.as_str()?.to_string();
+
+        let announce_list = raw
+            .get("announce-list")
+            .and_then(Value::as_list)
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|tier| tier.as_list()?.iter().map(string).collect())
+            .collect();
+
+        let info_bytes = bencode::raw_value(bytes, "info")?;
+        let piece_length = u32::try_from(info.get("piece length")?.as_int()?).ok()?;
+        // Without v1 pieces, it must be v2 alone, known to peers by its
+        // SHA-256 info hash cut to 20 bytes.
+        let (info_hash, pieces, files, v2) = match info.get("pieces") {
+            Some(pieces) => {
+                let pieces = pieces.as_bytes().filter(|pieces| pieces.len().is_multiple_of(20))?;
+                let files = files(info, &name)?;
+                let v2 = MerkleInfo::parse(&raw, info_bytes, &name, &files, piece_length);
+                (sha1(info_bytes), pieces.chunks(20).map(|hash| hash.try_into().unwrap()).collect(), files, v2)
+            },
+            None => {
+                let files = v2_files(info, &name, piece_length)?;
+                let v2 = MerkleInfo::parse(&raw, info_bytes, &name, &files, piece_length)?;
+                (v2.info_hash[..20].try_into().unwrap(), Vec::new(), files, Some(v2))
+            }
+        };
+        Some(Self {
+            info_hash,
+            announce: raw.get("announce").and_then(string),
+            announce_list,
+            piece_length,
+            pieces,
+            v2,
+            files,
+            private: info.get("private").and_then(Value::as_int) == Some(1),
+            name,
+            raw
+        })
+    }
+
+    // A torrent from an info dictionary fetched from peers (BEP 9), with a
+    // magnet link's trackers, each its own tier.
+    pub fn from_info(info: &[u8], trackers: &[String]) -> Option<Self> {
+        let tiers = trackers.iter().map(|tracker| Value::List(vec![tracker.as_str().into()])).collect();
+        let mut bytes = Value::dict([("announce-list", Value::List(tiers))]).encode();
+        // Spliced in as sent, so it hashes to the info hash peers know it by.
+        bytes.pop();
+        bytes.extend_from_slice(b"4:info");
+        bytes.extend_from_slice(info);
+        bytes.push(b'e');
+        let mut metainfo = Self::from_bytes(&bytes)?;
+        metainfo.announce = trackers.first().cloned();
+        Some(metainfo)
+    }
+
+    // Saves the torrent under another name: the single file, or the
+    // directory the files are nested in.
+    pub fn rename(&mut self, name: &str) {
+        for file in &mut self.files {
+            let mut path = PathBuf::from(name);
+            path.extend(file.path.components().skip(1));
+            file.path = path;
+        }
+        self.name = name.to_string();
+    }
+
+    pub fn total_length(&self) -> u64 {
+        self.files.iter().map(|file| file.length).sum()
+    }
+
+    pub fn is_v2_only(&self) -> bool {
+        self.pieces.is_empty() && self.v2.is_some()
+    }
+
+    // Counted from the files for a v2-only torrent, which has no v1 piece
+    // hashes to count.
+    pub fn num_pieces(&self) -> usize {
+        match self.is_v2_only() {
+            true => self.total_length().div_ceil(self.piece_length as u64) as usize,
+            false => self.pieces.len()
+        }
+    }
+
+    // What `piece` must hash to: its v1 hash, or for a v2-only torrent the
+    // root its file's tree has for it.
+    pub fn piece_hash(&self, piece: u32) -> Option<PieceHash> {
+        match (self.pieces.get(piece as usize), &self.v2) {
+            (Some(hash), _) => Some(PieceHash::Sha1(*hash)),
+            (None, Some(v2)) if self.pieces.is_empty() => v2.piece_root(piece, self.piece_length).map(PieceHash::Merkle),
+            (None, _) => None
+        }
+    }
+
+    // The announce-list if there is one, else the lone announce URL.
+    pub fn trackers(&self) -> Vec<String> {
+        match self.announce_list.is_empty() {
+            true => self.announce.iter().cloned().collect(),
+            false => self.announce_list.iter().flatten().cloned().collect()
+        }
+    }
+
+    // Whether the torrent stays within `limits`. Cheap enough to run on
+    // anything parsed, before its pieces are allocated or its files opened.
+    pub fn check_limits(&self, limits: &Limits) -> Result<(), LimitError> {
+        if self.piece_length < limits.min_piece_length {
+            return Err(LimitError::PieceLengthTooSmall(self.piece_length));
+        }
+        if self.piece_length > limits.max_piece_length {
+            return Err(LimitError::PieceLengthTooLarge(self.piece_length));
+        }
+        let total = self.files.iter().try_fold(0u64, |total, file| total.checked_add(file.length)).ok_or(LimitError::TooLarge(None))?;
+        if limits.max_total_length.is_some_and(|max| total > max) {
+            return Err(LimitError::TooLarge(Some(total)));
+        }
+        // The hashes are bounded by the file they came in; the content's
+        // pieces are what gets allocated for.
+        let pieces = total.div_ceil(self.piece_length as u64).max(self.pieces.len() as u64);
+        if pieces > limits.max_pieces {
+            return Err(LimitError::TooManyPieces(pieces));
+        }
+        Ok(())
+    }
+
+    // `None` if the piece hashes do not cover the files, or a file would
+    // land outside the download directory, as one renamed to `..` would.
+    // On Windows, the files are where `sanitize` puts them.
+    pub fn layout(&self) -> Option<Layout> {
+        let inside = |file: &FileEntry| file.path.components().all(|component| matches!(component, Component::Normal(_)));
+        if !self.files.iter().all(inside) {
+            return None;
+        }
+        let files = match cfg!(windows) {
+            true => sanitize(&self.files),
+            false => self.files.clone()
+        };
+        let layout = Layout::new(files, self.piece_length)?;
+        (layout.geometry().num_pieces() as usize == self.num_pieces()).then_some(layout)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::bencode::Value;
+    use crate::hash::sha1;
+    use crate::hash::PieceHash;
+    use crate::merkle::{root, sha256, Hash, MerkleFile, PieceRoot};
+    use crate::metainfo::{LimitError, Limits, Metainfo};
+    use crate::storage::layout::FileEntry;
+
+    #[test]
+    fn test_multi_file() {
+        let info = Value::dict([
+            ("name", "album".into()),
+            ("piece length", 4.into()),
+            ("pieces", Value::from(&[0; 40][..])),
+            ("private", 1.into()),
+            (
+                "files",
+                Value::List(vec![
+                    Value::dict([("length", 5.into()), ("path", Value::List(vec!["cd1".into(), "a".into()]))]),
+                    Value::dict([("length", 3.into()), ("path", Value::List(vec!["b".into()]))])
+                ])
+            )
+        ]);
+        let torrent = Value::dict([
+            ("announce", "http://tracker/announce".into()),
+            ("announce-list", Value::List(vec![Value::List(vec!["http://a".into(), "http://b".into()])])),
+            ("info", info.clone())
+        ]);
+
+        let metainfo = Metainfo::from_bytes(&torrent.encode()).unwrap();
+        assert_eq!(metainfo.info_hash, sha1(&info.encode()));
+        assert_eq!(metainfo.announce.as_deref(), Some("http://tracker/announce"));
+        assert_eq!(metainfo.announce_list, vec![vec!["http://a".to_string(), "http://b".to_string()]]);
+        assert_eq!(metainfo.trackers(), ["http://a", "http://b"]);
+        assert_eq!(metainfo.files, vec![FileEntry::new("album/cd1/a", 5), FileEntry::new("album/b", 3)]);
+        assert!(metainfo.private);
+        assert_eq!(metainfo.layout().unwrap().geometry().num_pieces(), 2);
+
+        let mut renamed = metainfo.clone();
+        renamed.rename("music");
+        assert_eq!(renamed.files, vec![FileEntry::new("music/cd1/a", 5), FileEntry::new("music/b", 3)]);
+        assert_eq!((renamed.name.as_str(), renamed.info_hash), ("music", metainfo.info_hash));
+
+        renamed.rename("..");
+        assert!(renamed.layout().is_none());
+        renamed.rename("/tmp/music");
+        assert!(renamed.layout().is_none());
+    }
+
+    #[test]
+    fn test_unsafe_paths() {
+        let torrent = |name: &str, path: Option<&[&str]>| {
+            let mut info = vec![("name", name.into()), ("piece length", 4.into()), ("pieces", Value::from(&[0; 20][..]))];
+            match path {
+                Some(path) => {
+                    let path = Value::List(path.iter().map(|&part| part.into()).collect());
+                    info.push(("files", Value::List(vec![Value::dict([("length", 3.into()), ("path", path)])])));
+                },
+                None => info.push(("length", 3.into()))
+            }
+            Metainfo::from_bytes(&Value::dict([("info", Value::dict(info))]).encode())
+        };
+        assert!(torrent("album", Some(&["cd1", "a.mp3"])).is_some());
+        assert!(torrent("..album", Some(&["a..b"])).is_some());
+        for bad in ["", ".", "..", "/etc", "a/b", "a\\b"] {
+            assert!(torrent(bad, None).is_none(), "{:?}", bad);
+            assert!(torrent("album", Some(&["cd1", bad])).is_none(), "{:?}", bad);
+        }
+        assert!(torrent("album", Some(&["..", "..", ".bashrc"])).is_none());
+        assert!(torrent("album", Some(&["/", "etc", "passwd"])).is_none());
+        #[cfg(windows)]
+        assert!(torrent("album", Some(&["C:", "evil"])).is_none());
+    }
+
+    #[test]
+    fn test_single_file() {
+        let info = Value::dict([
+            ("name", "file.iso".into()),
+            ("length", 10.into()),
+            ("piece length", 4.into()),
+            ("pieces", Value::from(&[0; 40][..]))
+        ]);
+        let metainfo = Metainfo::from_bytes(&Value::dict([("info", info)]).encode()).unwrap();
+        assert_eq!(metainfo.files, vec![FileEntry::new("file.iso", 10)]);
+        assert!(!metainfo.private);
+        let mut renamed = metainfo.clone();
+        renamed.rename("copy.iso");
+        assert_eq!(renamed.files, vec![FileEntry::new("copy.iso", 10)]);
+        // Ten bytes need three pieces.
+        assert!(metainfo.layout().is_none());
+    }
+
+    #[test]
+    fn test_from_info() {
+        let info = Value::dict([
+            ("name", "file.iso".into()),
+            ("length", 8.into()),
+            ("piece length", 4.into()),
+            ("pieces", Value::from(&[0; 40][..]))
+        ]);
+        let trackers = ["udp://a:1".to_string(), "http://b/announce".to_string()];
+        let metainfo = Metainfo::from_info(&info.encode(), &trackers).unwrap();
+        assert_eq!(metainfo.info_hash, sha1(&info.encode()));
+        assert_eq!(metainfo.announce.as_deref(), Some("udp://a:1"));
+        assert_eq!(metainfo.announce_list, vec![vec!["udp://a:1".to_string()], vec!["http://b/announce".to_string()]]);
+        assert_eq!(metainfo.files, vec![FileEntry::new("file.iso", 8)]);
+        assert!(Metainfo::from_info(b"i1e", &[]).is_none());
+    }
+
+    #[test]
+    fn test_hybrid() {
+        let file = |length: i64, root: &[u8]| Value::dict([("", Value::dict([("length", length.into()), ("pieces root", root.into())]))]);
+        let layer = [[1; 32], [2; 32]];
+        let a_root = root(&layer, 0, 2);
+        // The v1 files are padded (BEP 47) so the second starts a piece.
+        let info = Value::dict([
+            ("name", "album".into()),
+            ("piece length", 16384.into()),
+            ("pieces", Value::from(&[0; 60][..])),
+            ("meta version", 2.into()),
+            ("file tree", Value::dict([("a", file(20000, &a_root)), ("b", file(100, &[4; 32]))])),
+            (
+                "files",
+                Value::List(vec![
+                    Value::dict([("length", 20000.into()), ("path", Value::List(vec!["a".into()]))]),
+                    Value::dict([("attr", "p".into()), ("length", 12768.into()), ("path", Value::List(vec![".pad".into(), "12768".into()]))]),
+                    Value::dict([("length", 100.into()), ("path", Value::List(vec!["b".into()]))])
+                ])
+            )
+        ]);
+        let layers = |layer: &[Hash]| Value::Dict([(a_root.to_vec(), Value::from(&layer.concat()[..]))].into());
+        let torrent = Value::dict([("info", info.clone()), ("piece layers", layers(&layer))]);
+
+        let v2 = Metainfo::from_bytes(&torrent.encode()).unwrap().v2.unwrap();
+        assert_eq!(v2.info_hash, sha256(&info.encode()));
+        assert_eq!(v2.files, [
+            MerkleFile { path: "a".into(), length: 20000, pieces_root: a_root, offset: 0 },
+            MerkleFile { path: "b".into(), length: 100, pieces_root: [4; 32], offset: 32768 }
+        ]);
+        assert_eq!(v2.piece_layers[&a_root], layer);
+        // The info hash doesn't cover the layers, so they must prove out.
+        let forged = Value::dict([("info", info.clone()), ("piece layers", layers(&[[1; 32], [3; 32]]))]);
+        assert!(Metainfo::from_bytes(&forged.encode()).unwrap().v2.unwrap().piece_layers.is_empty());
+
+        // Without padding, the second file isn't where v2 needs it.
+        let Value::Dict(mut unpadded) = info else { unreachable!() };
+        unpadded.insert(b"files".to_vec(), Value::List(vec![
+            Value::dict([("length", 20000.into()), ("path", Value::List(vec!["a".into()]))]),
+            Value::dict([("length", 100.into()), ("path", Value::List(vec!["b".into()]))])
+        ]));
+        unpadded.insert(b"pieces".to_vec(), Value::from(&[0; 40][..]));
+        let metainfo = Metainfo::from_bytes(&Value::dict([("info", Value::Dict(unpadded))]).encode()).unwrap();
+        assert_eq!(metainfo.v2, None);
+    }
+
+    #[test]
+    fn test_v2_only() {
+        let file = |length: i64, root: &[u8]| Value::dict([("", Value::dict([("length", length.into()), ("pieces root", root.into())]))]);
+        let info = Value::dict([
+            ("name", "album".into()),
+            ("piece length", 16384.into()),
+            ("meta version", 2.into()),
+            ("file tree", Value::dict([("a", file(100, &[3; 32])), ("empty", Value::dict([("", Value::dict([("length", 0.into())]))])), ("b", file(200, &[4; 32]))]))
+        ]);
+
+        let metainfo = Metainfo::from_bytes(&Value::dict([("info", info.clone())]).encode()).unwrap();
+        assert!(metainfo.is_v2_only());
+        assert_eq!(metainfo.info_hash, sha256(&info.encode())[..20]);
+        // Padded as a hybrid would be, so `b` starts a piece.
+        assert_eq!(metainfo.files, [
+            FileEntry::new("album/a", 100),
+            FileEntry::new("album/.pad/16284", 16284),
+            FileEntry::new("album/b", 200),
+            FileEntry::new("album/empty", 0)
+        ]);
+        assert_eq!((metainfo.num_pieces(), metainfo.layout().unwrap().geometry().num_pieces()), (2, 2));
+        assert_eq!(metainfo.piece_hash(1), Some(PieceHash::Merkle(PieceRoot { root: [4; 32], len: 200, width: 1 })));
+        assert_eq!(metainfo.piece_hash(2), None);
+
+        // Neither v1 pieces nor a v2 tree.
+        let Value::Dict(mut v1) = info else { unreachable!() };
+        v1.remove(&b"meta version"[..]);
+        assert!(Metainfo::from_bytes(&Value::dict([("info", Value::Dict(v1))]).encode()).is_none());
+    }
+
+    #[test]
+    fn test_limits() {
+        let torrent = |piece_length: i64, lengths: &[i64]| {
+            let files = lengths.iter().enumerate().map(|(i, &length)| Value::dict([("length", length.into()), ("path", Value::List(vec![i.to_string().as_str().into()]))]));
+            let info = Value::dict([
+                ("name", "big".into()),
+                ("files", Value::List(files.collect())),
+                ("piece length", piece_length.into()),
+                ("pieces", Value::from(&[0; 20][..]))
+            ]);
+            Metainfo::from_bytes(&Value::dict([("info", info)]).encode()).unwrap()
+        };
+        let limits = Limits::default();
+        assert_eq!(torrent(1 << 20, &[1 << 40]).check_limits(&limits), Ok(()));
+        assert_eq!(torrent(16 * 1024, &[1 << 40]).check_limits(&limits), Err(LimitError::TooManyPieces(1 << 26)));
+        assert_eq!(torrent(4, &[10]).check_limits(&limits), Err(LimitError::PieceLengthTooSmall(4)));
+        assert_eq!(torrent(1 << 30, &[10]).check_limits(&limits), Err(LimitError::PieceLengthTooLarge(1 << 30)));
+        assert_eq!(torrent(1 << 20, &[i64::MAX, i64::MAX, 2]).check_limits(&limits), Err(LimitError::TooLarge(None)));
+
+        let capped = Limits { max_total_length: Some(1 << 30), ..limits };
+        assert_eq!(torrent(1 << 20, &[1 << 29, 1 << 29]).check_limits(&capped), Ok(()));
+        assert_eq!(torrent(1 << 20, &[1 << 29, 1 << 29, 1]).check_limits(&capped), Err(LimitError::TooLarge(Some((1 << 30) + 1))));
+    }
+}
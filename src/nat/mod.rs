@@ -0,0 +1,214 @@
+pub mod natpmp;
+pub mod upnp;
+
+use std::fmt;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::Duration;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use tokio::time;
+use tracing::{debug, info, warn};
+
+// Lifetime asked for; mappings are renewed halfway through.
+pub const MAPPING_LIFETIME: Duration = Duration::from_secs(3600);
+// How long to wait before trying again after mapping failed.
+const RETRY_INTERVAL: Duration = Duration::from_secs(300);
+const UNMAP_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    Tcp,
+    Udp
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Protocol::Tcp => write!(f, "TCP"),
+            Protocol::Udp => write!(f, "UDP")
+        }
+    }
+}
+
+// The IPv4 default route's gateway, read from the kernel's routing table.
+#[cfg(target_os = "linux")]
+pub fn default_gateway() -> Option<Ipv4Addr> {
+    let routes = std::fs::read_to_string("/proc/net/route").ok()?;
+    routes.lines().skip(1).find_map(|line| {
+        let fields: Vec<_> = line.split_whitespace().collect();
+        if fields.get(1) != Some(&"00000000") {
+            return None;
+        }
+        // Stored in host byte order.
+        let gateway = u32::from_str_radix(fields.get(2)?, 16).ok()?;
+        Some(Ipv4Addr::from(gateway.to_le_bytes()))
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn default_gateway() -> Option<Ipv4Addr> {
+    None
+}
+
+// A gateway we know how to ask for mappings.
+#[derive(Debug, Clone)]
+pub enum Gateway {
+    NatPmp(SocketAddr),
+    Upnp(upnp::Gateway)
+}
+
+impl Gateway {
+    // NAT-PMP answers quickly or not at all, so it goes first.
+    pub async fn discover() -> io::Result<Self> {
+        if let Some(gateway) = default_gateway() {
+            let gateway = SocketAddr::from((gateway, natpmp::NAT_PMP_PORT));
+            match natpmp::external_address(gateway).await {
+                Ok(external) => {
+                    debug!(%gateway, %external, "found a NAT-PMP gateway");
+                    return Ok(Self::NatPmp(gateway));
+                },
+                Err(err) => debug!(%gateway, error = %err, "no NAT-PMP")
+            }
+        }
+        upnp::discover().await.map(Self::Upnp)
+    }
+
+    // Returns the lifetime granted, zero for forever.
+    pub async fn map(&self, protocol: Protocol, port: u16, lifetime: Duration) -> io::Result<Duration> {
+        match self {
+            Self::NatPmp(gateway) => {
+                let (external, lifetime) = natpmp::map(*gateway, protocol, port, port, lifetime).await?;
+                if external != port {
+                    warn!(port, external, %protocol, "gateway mapped a different external port");
+                }
+                Ok(lifetime)
+            },
+            Self::Upnp(gateway) => gateway.map(protocol, port, lifetime).await
+        }
+    }
+
+    pub async fn unmap(&self, protocol: Protocol, port: u16) -> io::Result<()> {
+        match self {
+            Self::NatPmp(gateway) => natpmp::unmap(*gateway, protocol, port).await,
+            Self::Upnp(gateway) => gateway.unmap(protocol, port).await
+        }
+    }
+}
+
+// Keeps a port forwarded for TCP and UDP on the local gateway: maps it,
+// renews the mappings before they expire and removes them when stopped.
+pub struct PortMapper {
+    stop: oneshot::Sender<()>,
+    task: JoinHandle<()>
+}
+
+impl PortMapper {
+    // Finds the gateway on its own; gives up quietly on networks without
+    // one, retrying now and then.
+    pub fn spawn(port: u16) -> Self {
+        Self::spawn_with(port, None)
+    }
+
+    pub fn spawn_with(port: u16, gateway: Option<Gateway>) -> Self {
+        let (stop, stopped) = oneshot::channel();
+        let task = tokio::spawn(run(port, gateway, stopped));
+        Self { stop, task }
+    }
+
+    // Removes the mappings, waiting a few seconds at most for the gateway.
+    pub async fn stop(self) {
+        let _ = self.stop.send(());
+        let mut task = self.task;
+        if time::timeout(UNMAP_TIMEOUT, &mut task).await.is_err() {
+            task.abort();
+        }
+    }
+}
+
+async fn run(port: u16, mut gateway: Option<Gateway>, mut stopped: oneshot::Receiver<()>) {
+    let (mut mapped, mut interrupted) = (None, false);
+    loop {
+        let result = tokio::select! {
+            result = map_all(&mut gateway, port) => result,
+            _ = &mut stopped => {
+                interrupted = true;
+                break;
+            }
+        };
+        let wait = match result {
+            Ok(lifetime) => {
+                if mapped.is_none() {
+                    info!(port, ?lifetime, "port forwarded");
+                }
+                mapped = gateway.clone();
+                match lifetime.is_zero() {
+                    true => MAPPING_LIFETIME / 2,
+                    false => lifetime / 2
+                }
+            },
+            Err(err) => {
+                debug!(port, error = %err, "port forwarding failed");
+                // It may be another gateway by the time we try again.
+                gateway = None;
+                RETRY_INTERVAL
+            }
+        };
+        tokio::select! {
+            _ = time::sleep(wait) => {},
+            _ = &mut stopped => break
+        }
+    }
+
+    // Stopped halfway through mapping, the gateway may have made some of
+    // the mappings already.
+    if interrupted {
+        mapped = gateway.or(mapped);
+    }
+    if let Some(gateway) = mapped {
+        for protocol in [Protocol::Tcp, Protocol::Udp] {
+            if let Err(err) = gateway.unmap(protocol, port).await {
+                debug!(port, %protocol, error = %err, "cannot remove port mapping");
+            }
+        }
+    }
+}
+
+// Maps both protocols, returning the shorter lifetime.
+async fn map_all(gateway: &mut Option<Gateway>, port: u16) -> io::Result<Duration> {
+    let gateway = match gateway {
+        Some(gateway) => gateway,
+        None => gateway.insert(Gateway::discover().await?)
+    };
+    let tcp = gateway.map(Protocol::Tcp, port, MAPPING_LIFETIME).await?;
+    let udp = gateway.map(Protocol::Udp, port, MAPPING_LIFETIME).await?;
+    Ok(tcp.min(udp))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::nat::natpmp::test::gateway;
+    use crate::nat::{Gateway, PortMapper};
+    use std::time::Duration;
+    use tokio::time;
+
+    #[tokio::test]
+    async fn test_port_mapper() {
+        let (addr, requests) = gateway().await;
+        let mapper = PortMapper::spawn_with(6881, Some(Gateway::NatPmp(addr)));
+        while requests.lock().unwrap().len() < 2 {
+            time::sleep(Duration::from_millis(10)).await;
+        }
+        mapper.stop().await;
+
+        // TCP and UDP for an hour each, then both removed.
+        let requests = requests.lock().unwrap();
+        let summary: Vec<_> = requests.iter().map(|request| (request[1], &request[4..6], &request[8..12])).collect();
+        assert_eq!(summary, [
+            (2, &[0x1a, 0xe1][..], &3600u32.to_be_bytes()[..]),
+            (1, &[0x1a, 0xe1][..], &3600u32.to_be_bytes()[..]),
+            (2, &[0x1a, 0xe1][..], &[0; 4][..]),
+            (1, &[0x1a, 0xe1][..], &[0; 4][..])
+        ]);
+    }
+}
@@ -0,0 +1,144 @@
+use crate::nat::Protocol;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::time;
+
+// Gateways listen for NAT-PMP (RFC 6886) here.
+pub const NAT_PMP_PORT: u16 = 5351;
+
+const VERSION: u8 = 0;
+const EXTERNAL_ADDRESS: u8 = 0;
+const RESPONSE: u8 = 128;
+// The RFC retries up to nine times; a gateway that hasn't answered within
+// four has probably never heard of NAT-PMP.
+const ATTEMPTS: u32 = 4;
+const FIRST_TIMEOUT: Duration = Duration::from_millis(250);
+
+fn opcode(protocol: Protocol) -> u8 {
+    match protocol {
+        Protocol::Udp => 1,
+        Protocol::Tcp => 2
+    }
+}
+
+fn map_request(protocol: Protocol, internal: u16, external: u16, lifetime: u32) -> [u8; 12] {
+    let mut request = [0; 12];
+    request[0] = VERSION;
+    request[1] = opcode(protocol);
+    request[4..6].copy_from_slice(&internal.to_be_bytes());
+    request[6..8].copy_from_slice(&external.to_be_bytes());
+    request[8..12].copy_from_slice(&lifetime.to_be_bytes());
+    request
+}
+
+// Checks the header of a response to `opcode` and returns its body.
+fn response_body(response: &[u8], opcode: u8) -> io::Result<&[u8]> {
+    if response.len() < 8 || response[0] != VERSION || response[1] != RESPONSE + opcode {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "unexpected NAT-PMP response"));
+    }
+    match u16::from_be_bytes([response[2], response[3]]) {
+        0 => Ok(&response[8..]),
+        code => Err(io::Error::other(format!("NAT-PMP result code {}", code)))
+    }
+}
+
+// Sends `request` with the RFC's doubling timeouts until an answer comes.
+async fn call(gateway: SocketAddr, request: &[u8]) -> io::Result<Vec<u8>> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    socket.connect(gateway).await?;
+    let mut buf = [0; 16];
+    let mut timeout = FIRST_TIMEOUT;
+    for _ in 0..ATTEMPTS {
+        socket.send(request).await?;
+        if let Ok(received) = time::timeout(timeout, socket.recv(&mut buf)).await {
+            return Ok(buf[..received?].to_vec());
+        }
+        timeout *= 2;
+    }
+    Err(io::Error::new(io::ErrorKind::TimedOut, "no NAT-PMP response"))
+}
+
+// Asks the gateway for its public address, which also tells whether it
+// speaks NAT-PMP at all.
+pub async fn external_address(gateway: SocketAddr) -> io::Result<Ipv4Addr> {
+    let response = call(gateway, &[VERSION, EXTERNAL_ADDRESS]).await?;
+    let body = response_body(&response, EXTERNAL_ADDRESS)?;
+    let octets: [u8; 4] = body
+        .get(..4)
+        .and_then(|octets| octets.try_into().ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "short NAT-PMP response"))?;
+    Ok(Ipv4Addr::from(octets))
+}
+
+// Maps `internal` to `external` (or whatever the gateway picks) for
+// `lifetime`. Returns the external port and lifetime granted.
+pub async fn map(gateway: SocketAddr, protocol: Protocol, internal: u16, external: u16, lifetime: Duration) -> io::Result<(u16, Duration)> {
+    let lifetime = lifetime.as_secs().min(u32::MAX as u64) as u32;
+    let response = call(gateway, &map_request(protocol, internal, external, lifetime)).await?;
+    let body = response_body(&response, opcode(protocol))?;
+    if body.len() < 8 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "short NAT-PMP response"));
+    }
+    let external = u16::from_be_bytes([body[2], body[3]]);
+    let lifetime = u32::from_be_bytes([body[4], body[5], body[6], body[7]]);
+    Ok((external, Duration::from_secs(lifetime as u64)))
+}
+
+pub async fn unmap(gateway: SocketAddr, protocol: Protocol, internal: u16) -> io::Result<()> {
+    map(gateway, protocol, internal, 0, Duration::ZERO).await.map(|_| ())
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use crate::nat::natpmp::{external_address, map, unmap};
+    use crate::nat::Protocol;
+    use std::net::{Ipv4Addr, SocketAddr};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use tokio::net::UdpSocket;
+
+    // A gateway that grants every mapping as asked and keeps the requests.
+    pub async fn gateway() -> (SocketAddr, Arc<Mutex<Vec<Vec<u8>>>>) {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let log = requests.clone();
+        tokio::spawn(async move {
+            let mut buf = [0; 16];
+            loop {
+                let (n, from) = socket.recv_from(&mut buf).await.unwrap();
+                log.lock().unwrap().push(buf[..n].to_vec());
+                let mut response = vec![0, buf[1] + 128, 0, 0, 0, 0, 0, 1];
+                match (n, buf[1]) {
+                    (2, 0) => response.extend_from_slice(&[203, 0, 113, 7]),
+                    (12, _) => {
+                        response.extend_from_slice(&buf[4..6]);
+                        response.extend_from_slice(&buf[6..8]);
+                        response.extend_from_slice(&buf[8..12]);
+                    },
+                    _ => response[3] = 5
+                }
+                socket.send_to(&response, from).await.unwrap();
+            }
+        });
+        (addr, requests)
+    }
+
+    #[tokio::test]
+    async fn test_map() {
+        let (gateway, _) = gateway().await;
+        assert_eq!(external_address(gateway).await.unwrap(), Ipv4Addr::new(203, 0, 113, 7));
+        let granted = map(gateway, Protocol::Tcp, 6881, 6881, Duration::from_secs(7200)).await.unwrap();
+        assert_eq!(granted, (6881, Duration::from_secs(7200)));
+        unmap(gateway, Protocol::Udp, 6881).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_no_gateway() {
+        let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let err = external_address(silent.local_addr().unwrap()).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+    }
+}
@@ -0,0 +1,262 @@
+use crate::nat::Protocol;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::time;
+
+const SSDP_ADDR: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(239, 255, 255, 250), 1900);
+const SEARCH_TARGET: &str = "urn:schemas-upnp-org:device:InternetGatewayDevice:1";
+const SEARCH_TIMEOUT: Duration = Duration::from_secs(3);
+const HTTP_TIMEOUT: Duration = Duration::from_secs(5);
+// Descriptions and SOAP answers are small; anything bigger is not one.
+const MAX_RESPONSE_LEN: usize = 256 * 1024;
+// Services that can forward ports, best first.
+const SERVICES: [&str; 3] = [
+    "urn:schemas-upnp-org:service:WANIPConnection:2",
+    "urn:schemas-upnp-org:service:WANIPConnection:1",
+    "urn:schemas-upnp-org:service:WANPPPConnection:1"
+];
+// The error a gateway answers when it only does mappings without a lease.
+const ONLY_PERMANENT_LEASES: &str = "<errorCode>725</errorCode>";
+const DESCRIPTION: &str = "bittorrent-rs";
+
+// A UPnP internet gateway's port-forwarding service.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Gateway {
+    pub addr: SocketAddr,
+    pub control_path: String,
+    pub service: String,
+    // Our address as the gateway sees it, which mappings point to.
+    pub local_ip: Ipv4Addr
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+fn header<'a>(response: &'a str, name: &str) -> Option<&'a str> {
+    response.lines().find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        key.trim().eq_ignore_ascii_case(name).then(|| value.trim())
+    })
+}
+
+// Splits `http://host:port/path` into the address and path.
+fn split_url(url: &str) -> Option<(SocketAddr, &str)> {
+    let rest = url.strip_prefix("http://")?;
+    let (host, path) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+    let addr = match host.parse() {
+        Ok(addr) => addr,
+        Err(_) => SocketAddr::new(host.parse().ok()?, 80)
+    };
+    Some((addr, if path.is_empty() { "/" } else { path }))
+}
+
+fn tag<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+    let start = xml.find(&format!("<{}>", name))? + name.len() + 2;
+    let end = start + xml[start..].find(&format!("</{}>", name))?;
+    Some(xml[start..end].trim())
+}
+
+// Finds the best forwarding service in a device description and returns
+// its type and control URL.
+fn control_url(description: &str) -> Option<(&str, &str)> {
+    let services: Vec<_> = description.split("<service>").skip(1).collect();
+    SERVICES.iter().find_map(|wanted| {
+        services
+            .iter()
+            .find(|service| tag(service, "serviceType") == Some(wanted))
+            .and_then(|service| Some((*wanted, tag(service, "controlURL")?)))
+    })
+}
+
+async fn http(addr: SocketAddr, request: String) -> io::Result<String> {
+    let exchange = async {
+        let mut stream = TcpStream::connect(addr).await?;
+        stream.write_all(request.as_bytes()).await?;
+        let mut response = Vec::new();
+        (&mut stream).take(MAX_RESPONSE_LEN as u64).read_to_end(&mut response).await?;
+        Ok::<_, io::Error>(String::from_utf8_lossy(&response).into_owned())
+    };
+    let response = time::timeout(HTTP_TIMEOUT, exchange)
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "gateway timed out"))??;
+    let (head, body) = response.split_once("\r\n\r\n").ok_or_else(|| invalid("bad HTTP response"))?;
+    let status = head.split_whitespace().nth(1).ok_or_else(|| invalid("bad HTTP response"))?;
+    match status {
+        "200" => Ok(body.to_string()),
+        _ if body.contains(ONLY_PERMANENT_LEASES) => Err(io::Error::new(io::ErrorKind::Unsupported, ONLY_PERMANENT_LEASES)),
+        status => Err(io::Error::other(format!("gateway answered {}", status)))
+    }
+}
+
+// The address we reach `addr` from. Connecting a UDP socket sends nothing.
+async fn local_ip(addr: SocketAddr) -> io::Result<Ipv4Addr> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    socket.connect(addr).await?;
+    match socket.local_addr()?.ip() {
+        IpAddr::V4(ip) => Ok(ip),
+        IpAddr::V6(_) => Err(invalid("gateway is not on IPv4"))
+    }
+}
+
+// Looks for a gateway with SSDP, then reads its description for the
+// service to talk to.
+pub async fn discover() -> io::Result<Gateway> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    let search = format!(
+        "M-SEARCH * HTTP/1.1\r\nHOST: {}\r\nMAN: \"ssdp:discover\"\r\nMX: 2\r\nST: {}\r\n\r\n",
+        SSDP_ADDR,
+        SEARCH_TARGET
+    );
+    socket.send_to(search.as_bytes(), SSDP_ADDR).await?;
+    let mut buf = [0; 2048];
+    let (n, _) = time::timeout(SEARCH_TIMEOUT, socket.recv_from(&mut buf))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "no UPnP gateway"))??;
+    let response = String::from_utf8_lossy(&buf[..n]);
+    let location = header(&response, "location").ok_or_else(|| invalid("SSDP response without a location"))?;
+    gateway(location).await
+}
+
+// Reads the description at `location`.
+pub async fn gateway(location: &str) -> io::Result<Gateway> {
+    let (addr, path) = split_url(location).ok_or_else(|| invalid("unsupported description URL"))?;
+    let request = format!("GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n", path, addr);
+    let description = http(addr, request).await?;
+    let (service, control) = control_url(&description).ok_or_else(|| invalid("gateway cannot forward ports"))?;
+    let (addr, control_path) = match control.starts_with("http://") {
+        true => split_url(control).ok_or_else(|| invalid("unsupported control URL"))?,
+        false => (addr, control)
+    };
+    let local_ip = local_ip(addr).await?;
+    Ok(Gateway { addr, control_path: control_path.to_string(), service: service.to_string(), local_ip })
+}
+
+impl Gateway {
+    async fn soap(&self, action: &str, arguments: &[(&str, String)]) -> io::Result<String> {
+        let arguments: String = arguments
+            .iter()
+            .map(|(name, value)| format!("<{0}>{1}</{0}>", name, value))
+            .collect();
+        let body = format!(
+            "<?xml version=\"1.0\"?>\r\n<s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\"><s:Body><u:{0} xmlns:u=\"{1}\">{2}</u:{0}></s:Body></s:Envelope>",
+            action,
+            self.service,
+            arguments
+        );
+        let request = format!(
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: text/xml; charset=\"utf-8\"\r\nSOAPAction: \"{}#{}\"\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            self.control_path,
+            self.addr,
+            self.service,
+            action,
+            body.len(),
+            body
+        );
+        http(self.addr, request).await
+    }
+
+    pub async fn external_address(&self) -> io::Result<Ipv4Addr> {
+        let response = self.soap("GetExternalIPAddress", &[]).await?;
+        tag(&response, "NewExternalIPAddress")
+            .and_then(|ip| ip.parse().ok())
+            .ok_or_else(|| invalid("no external address"))
+    }
+
+    // Forwards `port` to us for `lifetime`, falling back to a mapping that
+    // never expires on gateways that only do those. Returns the lifetime
+    // granted, zero for forever.
+    pub async fn map(&self, protocol: Protocol, port: u16, lifetime: Duration) -> io::Result<Duration> {
+        let arguments = |lifetime: Duration| [
+            ("NewRemoteHost", String::new()),
+            ("NewExternalPort", port.to_string()),
+            ("NewProtocol", protocol.to_string()),
+            ("NewInternalPort", port.to_string()),
+            ("NewInternalClient", self.local_ip.to_string()),
+            ("NewEnabled", "1".to_string()),
+            ("NewPortMappingDescription", DESCRIPTION.to_string()),
+            ("NewLeaseDuration", lifetime.as_secs().to_string())
+        ];
+        match self.soap("AddPortMapping", &arguments(lifetime)).await {
+            Err(err) if err.kind() == io::ErrorKind::Unsupported => {
+                self.soap("AddPortMapping", &arguments(Duration::ZERO)).await?;
+                Ok(Duration::ZERO)
+            },
+            result => result.map(|_| lifetime)
+        }
+    }
+
+    pub async fn unmap(&self, protocol: Protocol, port: u16) -> io::Result<()> {
+        let arguments = [
+            ("NewRemoteHost", String::new()),
+            ("NewExternalPort", port.to_string()),
+            ("NewProtocol", protocol.to_string())
+        ];
+        self.soap("DeletePortMapping", &arguments).await.map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::nat::upnp::{control_url, gateway, header, split_url};
+    use crate::nat::Protocol;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    const DESCRIPTION: &str = "<root><device><serviceList>
+        <service><serviceType>urn:schemas-upnp-org:service:Layer3Forwarding:1</serviceType><controlURL>/l3f</controlURL></service>
+        <service><serviceType>urn:schemas-upnp-org:service:WANPPPConnection:1</serviceType><controlURL>/ppp</controlURL></service>
+        <service><serviceType>urn:schemas-upnp-org:service:WANIPConnection:1</serviceType><controlURL>/ctl/IPConn</controlURL></service>
+        </serviceList></device></root>";
+
+    #[test]
+    fn test_parse() {
+        let response = "HTTP/1.1 200 OK\r\nCACHE-CONTROL: max-age=120\r\nLocation: http://192.168.1.1:5000/rootDesc.xml\r\n\r\n";
+        assert_eq!(header(response, "LOCATION"), Some("http://192.168.1.1:5000/rootDesc.xml"));
+        assert_eq!(split_url("http://192.168.1.1:5000/rootDesc.xml"), Some(("192.168.1.1:5000".parse().unwrap(), "/rootDesc.xml")));
+        assert_eq!(split_url("http://192.168.1.1"), Some(("192.168.1.1:80".parse().unwrap(), "/")));
+        assert_eq!(split_url("https://192.168.1.1/"), None);
+        assert_eq!(control_url(DESCRIPTION), Some(("urn:schemas-upnp-org:service:WANIPConnection:1", "/ctl/IPConn")));
+        assert_eq!(control_url("<root></root>"), None);
+    }
+
+    #[tokio::test]
+    async fn test_map() {
+        // Answers the description, then refuses leases, then takes the
+        // permanent mapping.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let answers = [
+                format!("HTTP/1.1 200 OK\r\n\r\n{}", DESCRIPTION),
+                "HTTP/1.1 500 Internal Server Error\r\n\r\n<UPnPError><errorCode>725</errorCode></UPnPError>".to_string(),
+                "HTTP/1.1 200 OK\r\n\r\n<u:AddPortMappingResponse/>".to_string()
+            ];
+            let mut requests = Vec::new();
+            for answer in answers {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = vec![0; 4096];
+                let n = stream.read(&mut request).await.unwrap();
+                requests.push(String::from_utf8_lossy(&request[..n]).into_owned());
+                stream.write_all(answer.as_bytes()).await.unwrap();
+            }
+            requests
+        });
+
+        let gateway = gateway(&format!("http://{}/rootDesc.xml", addr)).await.unwrap();
+        assert_eq!(gateway.control_path, "/ctl/IPConn");
+        assert_eq!(gateway.local_ip.to_string(), "127.0.0.1");
+        assert_eq!(gateway.map(Protocol::Tcp, 6881, Duration::from_secs(3600)).await.unwrap(), Duration::ZERO);
+
+        let requests = server.await.unwrap();
+        assert!(requests[0].starts_with("GET /rootDesc.xml "));
+        assert!(requests[1].contains("<NewLeaseDuration>3600</NewLeaseDuration>"));
+        assert!(requests[2].contains("SOAPAction: \"urn:schemas-upnp-org:service:WANIPConnection:1#AddPortMapping\""));
+        assert!(requests[2].contains("<NewProtocol>TCP</NewProtocol>"));
+        assert!(requests[2].contains("<NewLeaseDuration>0</NewLeaseDuration>"));
+    }
+}
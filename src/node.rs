@@ -0,0 +1,278 @@
+//! Node.js bindings to the session, built with the `node` feature by the
+//! napi-rs CLI (`napi build --release --features node`) or by hand:
+//!
+//! ```text
+//! cargo rustc --release --lib --features node --crate-type cdylib
+//! ```
+//!
+//! then copying `libbittorrent_rs.so` to `bittorrent_rs.node`.
+//!
+//! ```text
+//! const { Session } = require('./bittorrent_rs.node')
+//! const session = await Session.start(6881)
+//! const infoHash = await session.addTorrent(fs.readFileSync('a.torrent'), 'downloads')
+//! for await (const event of session.events(infoHash)) {
+//!     if (event.type === 'torrent_completed') break
+//! }
+//! await session.shutdown()
+//! ```
+//!
+//! Torrents are named by their info hash in hex; events are the alerts the
+//! JSON-RPC server's `events.poll` returns, under the same names.
+
+use crate::engine::{self, Alert};
+use crate::hash::{hex, unhex};
+use crate::metainfo::Metainfo;
+use crate::torrent::Torrent;
+use napi::bindgen_prelude::{within_runtime_if_available, Buffer};
+use napi::{Env, Error, JsFunction, JsObject, Result};
+use napi_derive::{module_exports, napi};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast::{self, error::RecvError};
+
+fn invalid(message: impl Into<String>) -> Error {
+    Error::from_reason(message.into())
+}
+
+fn info_hash(text: &str) -> Result<[u8; 20]> {
+    unhex(text).ok_or_else(|| invalid(format!("{} is not an info hash", text)))
+}
+
+/// A torrent's progress. Byte counts are numbers rather than BigInts:
+/// exact up to 8 PiB.
+#[napi(object)]
+pub struct Stats {
+    pub bytes_done: f64,
+    pub bytes_total: f64,
+    pub uploaded: f64,
+    pub downloaded: f64,
+    /// Bytes per second.
+    pub upload_rate: f64,
+    pub download_rate: f64,
+    pub connected_peers: u32,
+    pub known_peers: u32,
+    pub paused: bool,
+    pub queued: bool,
+    /// Bytes received and waiting to be written.
+    pub backlog_bytes: f64
+}
+
+#[napi(object)]
+pub struct Event {
+    #[napi(js_name = "type")]
+    pub kind: String,
+    pub info_hash: String,
+    /// `peer_connected`, `peer_disconnected` and `metadata_received`.
+    pub addr: Option<String>,
+    /// `piece_verified` and `hash_failed`.
+    pub piece: Option<u32>,
+    /// `storage_error`, and `tracker_error` after the tracker's URL.
+    pub message: Option<String>
+}
+
+impl From<Alert> for Event {
+    fn from(alert: Alert) -> Self {
+        let info_hash = hex(&alert.info_hash());
+        let (kind, addr, piece, message) = match alert {
+            Alert::PeerConnected { addr, .. } => ("peer_connected", Some(addr.to_string()), None, None),
+            Alert::PeerDisconnected { addr, .. } => ("peer_disconnected", Some(addr.to_string()), None, None),
+            Alert::PieceVerified { piece, .. } => ("piece_verified", None, Some(piece), None),
+            Alert::HashFailed { piece, .. } => ("hash_failed", None, Some(piece), None),
+            Alert::TorrentCompleted { .. } => ("torrent_completed", None, None, None),
+            Alert::TorrentPaused { .. } => ("torrent_paused", None, None, None),
+            Alert::TorrentResumed { .. } => ("torrent_resumed", None, None, None),
+            Alert::SeedingGoalReached { .. } => ("seeding_goal_reached", None, None, None),
+            Alert::StorageError { message, .. } => ("storage_error", None, None, Some(message)),
+            Alert::LowDiskSpace { .. } => ("low_disk_space", None, None, None),
+            Alert::TrackerError { url, message, .. } => ("tracker_error", None, None, Some(format!("{}: {}", url, message))),
+            Alert::MetadataReceived { addr, .. } => ("metadata_received", Some(addr.to_string()), None, None)
+        };
+        Self { kind: kind.to_string(), info_hash, addr, piece, message }
+    }
+}
+
+/// What an async iterator's `next()` resolves to.
+#[napi(object)]
+pub struct Next {
+    pub value: Option<Event>,
+    pub done: bool
+}
+
+/// A session's events, as an async iterator. It ends when the session
+/// shuts down.
+#[napi]
+pub struct EventStream {
+    alerts: Arc<tokio::sync::Mutex<broadcast::Receiver<Alert>>>,
+    info_hash: Option<[u8; 20]>
+}
+
+#[napi]
+impl EventStream {
+    #[napi]
+    pub async fn next(&self) -> Result<Next> {
+        let mut alerts = self.alerts.lock().await;
+        loop {
+            match alerts.recv().await {
+                Ok(alert) if self.info_hash.is_none_or(|info_hash| alert.info_hash() == info_hash) => {
+                    return Ok(Next { value: Some(alert.into()), done: false });
+                },
+                // Events a slow reader missed are gone; carry on from the
+                // oldest still kept.
+                Ok(_) | Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => return Ok(Next { value: None, done: true })
+            }
+        }
+    }
+}
+
+/// Any number of torrents behind one listening port.
+#[napi]
+pub struct Session {
+    // Taken by `shutdown`, after which every method fails.
+    session: Arc<Mutex<Option<engine::Session>>>
+}
+
+impl Session {
+    fn with<T>(&self, f: impl FnOnce(&mut engine::Session) -> Result<T>) -> Result<T> {
+        let mut session = self.session.lock().unwrap();
+        let session = session.as_mut().ok_or_else(|| invalid("the session has shut down"))?;
+        within_runtime_if_available(|| f(session))
+    }
+}
+
+#[napi]
+impl Session {
+    /// Starts listening for peers on `port`, or any free one.
+    #[napi]
+    pub async fn start(port: Option<u16>) -> Result<Session> {
+        let session = engine::Session::bind(("0.0.0.0", port.unwrap_or(0))).await?;
+        Ok(Self { session: Arc::new(Mutex::new(Some(session))) })
+    }
+
+    #[napi(getter)]
+    pub fn listen_port(&self) -> Result<u16> {
+        self.with(|session| Ok(session.listen_port()))
+    }
+
+    /// Starts downloading and seeding a .torrent file's bytes into
+    /// `downloadDir`, carrying on from whatever is already there, which is
+    /// hashed first, off the event loop. Resolves to its info hash.
+    #[napi]
+    pub async fn add_torrent(&self, torrent: Buffer, download_dir: String) -> Result<String> {
+        let metainfo = Metainfo::from_bytes(&torrent).ok_or_else(|| invalid("not a valid torrent"))?;
+        let info_hash = hex(&metainfo.info_hash);
+        let torrent = tokio::task::spawn_blocking(move || {
+            let mut torrent = Torrent::new(metainfo, download_dir).ok_or_else(|| invalid("the torrent's pieces don't match its files"))?;
+            torrent.recheck();
+            Ok::<_, Error>(torrent)
+        })
+        .await
+        .map_err(|err| invalid(err.to_string()))??;
+        self.with(|session| match session.add_torrent(torrent) {
+            Some(_) => Ok(info_hash),
+            None => Err(invalid("the torrent is already in the session"))
+        })
+    }
+
+    /// Stops a torrent, saving what it needs to resume. False if it isn't
+    /// in the session.
+    #[napi]
+    pub async fn remove_torrent(&self, info_hash: String) -> Result<bool> {
+        let info_hash = self::info_hash(&info_hash)?;
+        match self.with(|session| Ok(session.detach_torrent(&info_hash)))? {
+            Some(handle) => {
+                handle.shutdown().await;
+                Ok(true)
+            },
+            None => Ok(false)
+        }
+    }
+
+    /// Gives a torrent a peer to connect to, as `host:port`.
+    #[napi]
+    pub fn add_peer(&self, info_hash: String, addr: String) -> Result<()> {
+        let info_hash = self::info_hash(&info_hash)?;
+        let addr = addr
+            .to_socket_addrs()
+            .ok()
+            .and_then(|mut addrs| addrs.find(SocketAddr::is_ipv4))
+            .ok_or_else(|| invalid(format!("{} is not a peer address", addr)))?;
+        self.with(|session| {
+            let handle = session.torrent(&info_hash).ok_or_else(|| invalid("no such torrent"))?;
+            handle.add_peer(addr);
+            Ok(())
+        })
+    }
+
+    #[napi]
+    pub fn pause(&self, info_hash: String) -> Result<bool> {
+        let info_hash = self::info_hash(&info_hash)?;
+        self.with(|session| Ok(session.pause(&info_hash)))
+    }
+
+    #[napi]
+    pub fn resume(&self, info_hash: String) -> Result<bool> {
+        let info_hash = self::info_hash(&info_hash)?;
+        self.with(|session| Ok(session.resume(&info_hash)))
+    }
+
+    /// The info hashes of the session's torrents.
+    #[napi]
+    pub fn torrents(&self) -> Result<Vec<String>> {
+        self.with(|session| Ok(session.torrents().map(|handle| hex(&handle.info_hash())).collect()))
+    }
+
+    /// A torrent's progress, or null if it isn't in the session.
+    #[napi]
+    pub fn stats(&self, info_hash: String) -> Result<Option<Stats>> {
+        let info_hash = self::info_hash(&info_hash)?;
+        self.with(|session| {
+            let Some(handle) = session.torrent(&info_hash) else {
+                return Ok(None);
+            };
+            let stats = handle.stats();
+            Ok(Some(Stats {
+                bytes_done: stats.bytes_done as f64,
+                bytes_total: stats.bytes_total as f64,
+                uploaded: stats.uploaded as f64,
+                downloaded: stats.downloaded as f64,
+                upload_rate: stats.upload_rate as f64,
+                download_rate: stats.download_rate as f64,
+                connected_peers: stats.connected_peers as u32,
+                known_peers: stats.known_peers as u32,
+                paused: handle.is_paused(),
+                queued: stats.queued,
+                backlog_bytes: stats.backlog_bytes as f64
+            }))
+        })
+    }
+
+    /// Events from then on, of one torrent or of all of them.
+    #[napi]
+    pub fn events(&self, info_hash: Option<String>) -> Result<EventStream> {
+        let info_hash = info_hash.as_deref().map(self::info_hash).transpose()?;
+        self.with(|session| Ok(EventStream { alerts: Arc::new(tokio::sync::Mutex::new(session.subscribe())), info_hash }))
+    }
+
+    /// Stops every torrent, saving what they need to resume. True if they
+    /// all stopped cleanly.
+    #[napi]
+    pub async fn shutdown(&self) -> Result<bool> {
+        let session = self.session.lock().unwrap().take();
+        match session {
+            Some(session) => Ok(session.shutdown().await),
+            None => Ok(true)
+        }
+    }
+}
+
+// napi-rs has no way to declare `[Symbol.asyncIterator]`, so it goes on
+// the prototype once the classes are exported.
+#[module_exports]
+fn init(exports: JsObject, env: Env) -> Result<()> {
+    let stream: JsFunction = exports.get_named_property("EventStream")?;
+    let install: JsFunction = env.run_script("(stream) => { stream.prototype[Symbol.asyncIterator] = function () { return this } }")?;
+    install.call(None, &[stream])?;
+    Ok(())
+}
@@ -0,0 +1,196 @@
+use crate::hash::random_bytes;
+use std::fmt;
+
+// Azureus-style prefix identifying this client and its version.
+pub const CLIENT_PREFIX: &[u8; 8] = b"-BR0100-";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientInfo {
+    pub name: String,
+    pub version: String
+}
+
+impl fmt::Display for ClientInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.version.is_empty() {
+            write!(f, "{}", self.name)
+        } else {
+            write!(f, "{} {}", self.name, self.version)
+        }
+    }
+}
+
+const AZUREUS_CLIENTS: &[(&str, &str)] = &[
+    ("7T", "aTorrent"),
+    ("AG", "Ares"),
+    ("AZ", "Vuze"),
+    ("BC", "BitComet"),
+    ("BI", "BiglyBT"),
+    ("BR", "bittorrent-rs"),
+    ("BT", "BitTorrent"),
+    ("BW", "BitWombi"),
+    ("DE", "Deluge"),
+    ("FD", "Free Download Manager"),
+    ("FW", "FrostWire"),
+    ("KT", "KTorrent"),
+    ("LT", "libtorrent"),
+    ("lt", "libTorrent"),
+    ("PI", "PicoTorrent"),
+    ("qB", "qBittorrent"),
+    ("RT", "rTorrent"),
+    ("SD", "Thunder"),
+    ("TL", "Tribler"),
+    ("TR", "Transmission"),
+    ("UM", "\u{b5}Torrent Mac"),
+    ("UT", "\u{b5}Torrent"),
+    ("UW", "\u{b5}Torrent Web"),
+    ("WD", "WebTorrent Desktop"),
+    ("WW", "WebTorrent"),
+    ("XL", "Xunlei")
+];
+
+const SHADOW_CLIENTS: &[(u8, &str)] = &[
+    (b'A', "ABC"),
+    (b'O', "Osprey Permaseed"),
+    (b'Q', "BTQueue"),
+    (b'R', "Tribler"),
+    (b'S', "Shadow"),
+    (b'T', "BitTornado"),
+    (b'U', "UPnP NAT Bit Torrent")
+];
+
+fn version_digit(ch: u8) -> Option<u32> {
+    match ch {
+        b'0'..=b'9' => Some((ch - b'0') as u32),
+        b'A'..=b'Z' => Some((ch - b'A') as u32 + 10),
+        b'a'..=b'z' => Some((ch - b'a') as u32 + 36),
+        b'.' => Some(62),
+        _ => None
+    }
+}
+
+fn format_version(mut digits: Vec<u32>) -> String {
+    while digits.len() > 2 && digits.last() == Some(&0) {
+        digits.pop();
+    }
+    digits
+        .iter()
+        .map(|digit| digit.to_string())
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+// Azureus style: `-qB4650-` followed by random bytes.
+fn azureus(peer_id: &[u8]) -> Option<ClientInfo> {
+    if peer_id.len() < 8 || peer_id[0] != b'-' || peer_id[7] != b'-' {
+        return None;
+    }
+
+    let code = std::str::from_utf8(&peer_id[1..3]).ok()?;
+    if !code.bytes().all(|ch| ch.is_ascii_alphanumeric()) {
+        return None;
+    }
+    let digits = peer_id[3..7]
+        .iter()
+        .map(|&ch| version_digit(ch))
+        .collect::<Option<Vec<_>>>()?;
+
+    let name = AZUREUS_CLIENTS
+        .iter()
+        .find(|(known, _)| *known == code)
+        .map(|(_, name)| name.to_string())
+        .unwrap_or_else(|| format!("Unknown [{}]", code));
+
+    Some(ClientInfo { name, version: format_version(digits) })
+}
+
+// Shadow style: a client letter, up to five version characters padded with
+// `-`, e.g. `T03I--...`.
+fn shadow(peer_id: &[u8]) -> Option<ClientInfo> {
+    if peer_id.len() < 6 {
+        return None;
+    }
+
+    let name = SHADOW_CLIENTS
+        .iter()
+        .find(|(known, _)| *known == peer_id[0])?
+        .1;
+
+    let version = &peer_id[1..6];
+    let len = version
+        .iter()
+        .position(|&ch| ch == b'-')
+        .unwrap_or(version.len());
+    if len == 0 || version[len..].iter().any(|&ch| ch != b'-') {
+        return None;
+    }
+    let digits = version[..len]
+        .iter()
+        .map(|&ch| version_digit(ch))
+        .collect::<Option<Vec<_>>>()?;
+
+    Some(ClientInfo { name: name.into(), version: format_version(digits) })
+}
+
+pub fn identify(peer_id: &[u8]) -> Option<ClientInfo> {
+    azureus(peer_id).or_else(|| shadow(peer_id))
+}
+
+// A fresh peer id: our prefix followed by random alphanumerics.
+pub fn generate() -> [u8; 20] {
+    const ALPHABET: &[u8] = b"0123456789abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ";
+    let mut peer_id = [0; 20];
+    peer_id[..8].copy_from_slice(CLIENT_PREFIX);
+    for (slot, random) in peer_id[8..].iter_mut().zip(random_bytes::<12>()) {
+        *slot = ALPHABET[random as usize % ALPHABET.len()];
+    }
+    peer_id
+}
+
+#[cfg(test)]
+mod test {
+    use crate::peer_id::{generate, identify, CLIENT_PREFIX};
+
+    #[test]
+    fn test_generate() {
+        let peer_id = generate();
+        assert_eq!(&peer_id[..8], CLIENT_PREFIX);
+        assert!(peer_id[8..].iter().all(u8::is_ascii_alphanumeric));
+        assert_eq!(identify(&peer_id).unwrap().to_string(), "bittorrent-rs 0.1");
+    }
+
+    #[test]
+    fn test_azureus() {
+        assert_eq!(
+            identify(b"-qB4600-abcdefghijkl").unwrap().to_string(),
+            "qBittorrent 4.6"
+        );
+        assert_eq!(
+            identify(b"-TR2940-abcdefghijkl").unwrap().to_string(),
+            "Transmission 2.9.4"
+        );
+        assert_eq!(
+            identify(b"-ZZ1200-abcdefghijkl").unwrap().to_string(),
+            "Unknown [ZZ] 1.2"
+        );
+    }
+
+    #[test]
+    fn test_shadow() {
+        assert_eq!(
+            identify(b"T03I--abcdefghijklmn").unwrap().to_string(),
+            "BitTornado 0.3.18"
+        );
+        assert_eq!(
+            identify(b"S58B-----abcdefghijk").unwrap().to_string(),
+            "Shadow 5.8.11"
+        );
+    }
+
+    #[test]
+    fn test_unknown() {
+        assert_eq!(identify(b"00000000000000000000"), None);
+        assert_eq!(identify(b"T-3I--abcdefghijklmn"), None);
+        assert_eq!(identify(b"-qB"), None);
+    }
+}
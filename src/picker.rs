@@ -0,0 +1,583 @@
+use crate::bitfield::Bitfield;
+use crate::block::BlockRequest;
+use crate::piece::PieceGeometry;
+use crate::storage::selection::Priority;
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::time::Instant;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BlockState {
+    Missing,
+    Requested(SocketAddr),
+    Received
+}
+
+// What a picker gets to see of the download when choosing a piece.
+pub struct PickContext<'a> {
+    priorities: &'a [Priority],
+    deadlines: &'a BTreeMap<u32, Instant>,
+    availability: &'a [u32],
+    partial: &'a BTreeMap<u32, Vec<BlockState>>
+}
+
+impl PickContext<'_> {
+    pub fn priority(&self, index: u32) -> Priority {
+        self.priorities.get(index as usize).copied().unwrap_or(Priority::Skip)
+    }
+
+    pub fn deadline(&self, index: u32) -> Option<Instant> {
+        self.deadlines.get(&index).copied()
+    }
+
+    // How many connected peers have the piece.
+    pub fn availability(&self, index: u32) -> u32 {
+        self.availability.get(index as usize).copied().unwrap_or(0)
+    }
+
+    // Whether some of the piece has been requested already.
+    pub fn is_started(&self, index: u32) -> bool {
+        self.partial.contains_key(&index)
+    }
+}
+
+// Chooses which piece a peer should fetch from next. The scheduler does the
+// bookkeeping of blocks, peers and partial pieces; a picker only orders the
+// pieces, so embedders can swap in their own strategy.
+pub trait PiecePicker: fmt::Debug + Send {
+    // `candidates` are the pieces, in index order, that the peer has and we
+    // still need blocks of: wanted pieces we haven't started, started ones
+    // with blocks nobody is fetching, and any piece with a deadline. Picking
+    // something else, or nothing, leaves the peer idle.
+    fn pick(&mut self, candidates: &[u32], context: &PickContext<'_>) -> Option<u32>;
+}
+
+// Finishes started pieces first, then goes through the rest by priority
+// and index. Good for previewing media from the start.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Sequential;
+
+impl PiecePicker for Sequential {
+    fn pick(&mut self, candidates: &[u32], context: &PickContext<'_>) -> Option<u32> {
+        candidates
+            .iter()
+            .copied()
+            .filter(|&index| context.priority(index) != Priority::Skip)
+            .min_by_key(|&index| (!context.is_started(index), Reverse(context.priority(index)), index))
+    }
+}
+
+// Finishes started pieces first, then starts the pieces fewest peers have,
+// so they survive peers leaving and we have something others want.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RarestFirst;
+
+impl PiecePicker for RarestFirst {
+    fn pick(&mut self, candidates: &[u32], context: &PickContext<'_>) -> Option<u32> {
+        candidates
+            .iter()
+            .copied()
+            .filter(|&index| context.priority(index) != Priority::Skip)
+            .min_by_key(|&index| {
+                (!context.is_started(index), Reverse(context.priority(index)), context.availability(index), index)
+            })
+    }
+}
+
+// Pieces with a deadline first, soonest first, skipped or not; everything
+// else as `inner` would pick it.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DeadlineFirst<P> {
+    inner: P
+}
+
+impl<P: PiecePicker> DeadlineFirst<P> {
+    pub fn new(inner: P) -> Self {
+        Self { inner }
+    }
+}
+
+impl<P: PiecePicker> PiecePicker for DeadlineFirst<P> {
+    fn pick(&mut self, candidates: &[u32], context: &PickContext<'_>) -> Option<u32> {
+        candidates
+            .iter()
+            .filter_map(|&index| Some((context.deadline(index)?, index)))
+            .min()
+            .map(|(_, index)| index)
+            .or_else(|| self.inner.pick(candidates, context))
+    }
+}
+
+// Hands out blocks to peers and remembers who is fetching what, so that
+// when a peer stalls or goes away its blocks can go straight back into the
+// pool. Blocks already received for a partial piece are kept; other peers
+// only need to fill in the gaps. Which piece comes next is up to its
+// `PiecePicker`, deadlines then rarest first unless told otherwise.
+pub struct BlockScheduler {
+    geometry: PieceGeometry,
+    have: Bitfield,
+    // Per piece; see `FileSelection::piece_priorities`. Skipped pieces are
+    // never started and higher ones are started first.
+    priorities: Vec<Priority>,
+    // Pieces needed by a certain time, as for streaming. They go before
+    // everything else, skipped or not, until they arrive or the time is up.
+    deadlines: BTreeMap<u32, Instant>,
+    availability: Vec<u32>,
+    partial: BTreeMap<u32, Vec<BlockState>>,
+    picker: Box<dyn PiecePicker>
+}
+
+impl BlockScheduler {
+    pub fn new(geometry: PieceGeometry, have: Bitfield) -> Self {
+        let priorities = vec![Priority::Normal; have.len()];
+        let availability = vec![0; have.len()];
+        Self {
+            geometry,
+            have,
+            priorities,
+            deadlines: BTreeMap::new(),
+            availability,
+            partial: BTreeMap::new(),
+            picker: Box::new(DeadlineFirst::new(RarestFirst))
+        }
+    }
+
+    pub fn with_picker(mut self, picker: Box<dyn PiecePicker>) -> Self {
+        self.picker = picker;
+        self
+    }
+
+    pub fn set_picker(&mut self, picker: Box<dyn PiecePicker>) {
+        self.picker = picker;
+    }
+
+    // A peer turned up with these pieces, or sent its bitfield.
+    pub fn add_availability(&mut self, has: &Bitfield) {
+        has.ones().for_each(|index| {
+            if let Some(count) = self.availability.get_mut(index) {
+                *count += 1;
+            }
+        });
+    }
+
+    // A peer with these pieces left.
+    pub fn remove_availability(&mut self, has: &Bitfield) {
+        has.ones().for_each(|index| {
+            if let Some(count) = self.availability.get_mut(index) {
+                *count = count.saturating_sub(1);
+            }
+        });
+    }
+
+    // A peer announced a piece it didn't have before.
+    pub fn piece_available(&mut self, index: u32) {
+        if let Some(count) = self.availability.get_mut(index as usize) {
+            *count += 1;
+        }
+    }
+
+    pub fn availability(&self, index: u32) -> u32 {
+        self.availability.get(index as usize).copied().unwrap_or(0)
+    }
+
+    pub fn set_wanted(&mut self, wanted: &Bitfield) {
+        if wanted.len() == self.have.len() {
+            self.priorities = (0..wanted.len())
+                .map(|index| if wanted.get(index) { Priority::Normal } else { Priority::Skip })
+                .collect();
+        }
+    }
+
+    pub fn set_priorities(&mut self, priorities: Vec<Priority>) {
+        if priorities.len() == self.have.len() {
+            self.priorities = priorities;
+        }
+    }
+
+    pub fn priority(&self, index: u32) -> Priority {
+        self.priorities.get(index as usize).copied().unwrap_or(Priority::Skip)
+    }
+
+    pub fn set_deadline(&mut self, index: u32, deadline: Instant) {
+        if (index as usize) < self.have.len() && !self.have.get(index as usize) {
+            self.deadlines.insert(index, deadline);
+        }
+    }
+
+    pub fn clear_deadlines(&mut self) {
+        self.deadlines.clear();
+    }
+
+    // Drops the deadlines that have passed; whoever needed those pieces has
+    // moved on.
+    pub fn expire_deadlines(&mut self, now: Instant) {
+        self.deadlines.retain(|_, deadline| *deadline > now);
+    }
+
+    pub fn deadline(&self, index: u32) -> Option<Instant> {
+        self.deadlines.get(&index).copied()
+    }
+
+    // Whether the piece should be downloaded at all.
+    pub fn is_wanted(&self, index: u32) -> bool {
+        self.priority(index) != Priority::Skip || self.deadlines.contains_key(&index)
+    }
+
+    // Whether every wanted piece has been verified.
+    pub fn is_done(&self) -> bool {
+        (0..self.have.len()).all(|index| self.have.get(index) || self.priorities[index] == Priority::Skip)
+    }
+
+    pub fn geometry(&self) -> &PieceGeometry {
+        &self.geometry
+    }
+
+    pub fn bitfield(&self) -> &Bitfield {
+        &self.have
+    }
+
+    fn request_for(&self, index: u32, block: usize) -> BlockRequest {
+        let block_size = self.geometry.block_size();
+        let size = self.geometry.piece_size(index).unwrap_or(0);
+        let begin = block as u32 * block_size;
+        BlockRequest::new(index, begin, block_size.min(size - begin))
+    }
+
+    fn block_index(&self, request: &BlockRequest) -> Option<usize> {
+        let block_size = self.geometry.block_size();
+        if !request.begin.is_multiple_of(block_size) {
+            return None;
+        }
+        Some((request.begin / block_size) as usize)
+    }
+
+    // The first block of the piece nobody is fetching yet.
+    fn missing_block(&self, index: u32) -> Option<usize> {
+        match self.partial.get(&index) {
+            Some(blocks) => blocks.iter().position(|&state| state == BlockState::Missing),
+            None => Some(0)
+        }
+    }
+
+    pub fn next_request(&mut self, peer: SocketAddr, peer_has: &Bitfield) -> Option<BlockRequest> {
+        let candidates: Vec<u32> = peer_has
+            .ones()
+            .map(|index| index as u32)
+            .filter(|&index| {
+                !self.have.get(index as usize) && self.is_wanted(index) && self.missing_block(index).is_some()
+            })
+            .collect();
+        let context = PickContext {
+            priorities: &self.priorities,
+            deadlines: &self.deadlines,
+            availability: &self.availability,
+            partial: &self.partial
+        };
+        let index = self.picker.pick(&candidates, &context)?;
+        if candidates.binary_search(&index).is_err() {
+            return None;
+        }
+
+        let block = self.missing_block(index)?;
+        if !self.partial.contains_key(&index) {
+            let num_blocks = self.geometry.num_blocks(index)? as usize;
+            self.partial.insert(index, vec![BlockState::Missing; num_blocks]);
+        }
+        self.partial.get_mut(&index)?[block] = BlockState::Requested(peer);
+        Some(self.request_for(index, block))
+    }
+
+    // Whether every block still wanted is on its way, so that the last ones
+    // are worth asking a second peer for.
+    pub fn is_endgame(&self) -> bool {
+        (0..self.have.len() as u32)
+            .filter(|&index| !self.have.get(index as usize) && self.is_wanted(index))
+            .all(|index| self.partial.get(&index).is_some_and(|blocks| !blocks.contains(&BlockState::Missing)))
+    }
+
+    // In the endgame, a block some other peer is fetching, for this one to
+    // race it for. `asked` says which it's fetching already. Whichever peer
+    // is beaten to the block is sent a `cancel`.
+    pub fn endgame_request(&self, peer: SocketAddr, peer_has: &Bitfield, asked: impl Fn(&BlockRequest) -> bool) -> Option<BlockRequest> {
+        if !self.is_endgame() {
+            return None;
+        }
+        self.partial
+            .iter()
+            .filter(|(&index, _)| peer_has.get(index as usize) && self.is_wanted(index))
+            .flat_map(|(&index, blocks)| {
+                blocks.iter().enumerate().filter_map(move |(block, &state)| match state {
+                    BlockState::Requested(other) if other != peer => Some((index, block)),
+                    _ => None
+                })
+            })
+            .map(|(index, block)| self.request_for(index, block))
+            .find(|request| !asked(request))
+    }
+
+    // Returns the piece index once every block of it has arrived.
+    pub fn block_received(&mut self, request: &BlockRequest) -> Option<u32> {
+        let block = self.block_index(request)?;
+        let blocks = self.partial.get_mut(&request.index)?;
+        *blocks.get_mut(block)? = BlockState::Received;
+
+        blocks
+            .iter()
+            .all(|&state| state == BlockState::Received)
+            .then_some(request.index)
+    }
+
+    pub fn cancel(&mut self, peer: SocketAddr, request: &BlockRequest) {
+        let block = match self.block_index(request) {
+            Some(block) => block,
+            None => return
+        };
+        if let Some(state) = self.partial
+            .get_mut(&request.index)
+            .and_then(|blocks| blocks.get_mut(block))
+        {
+            if *state == BlockState::Requested(peer) {
+                *state = BlockState::Missing;
+            }
+        }
+    }
+
+    // Returns every block the peer was fetching to the pool.
+    pub fn peer_lost(&mut self, peer: SocketAddr) -> Vec<BlockRequest> {
+        let mut requeued = Vec::new();
+        for (&index, blocks) in self.partial.iter_mut() {
+            for (block, state) in blocks.iter_mut().enumerate() {
+                if *state == BlockState::Requested(peer) {
+                    *state = BlockState::Missing;
+                    requeued.push((index, block));
+                }
+            }
+        }
+
+        requeued
+            .into_iter()
+            .map(|(index, block)| self.request_for(index, block))
+            .collect()
+    }
+
+    pub fn piece_verified(&mut self, index: u32) {
+        self.partial.remove(&index);
+        self.deadlines.remove(&index);
+        self.have.set(index as usize);
+    }
+
+    // A piece that failed its hash check is downloaded again from scratch.
+    pub fn piece_failed(&mut self, index: u32) {
+        if let Some(blocks) = self.partial.get_mut(&index) {
+            blocks.fill(BlockState::Missing);
+        }
+    }
+
+    pub fn missing_blocks(&self, index: u32) -> Vec<BlockRequest> {
+        self.partial
+            .get(&index)
+            .map(|blocks| {
+                blocks
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, &state)| state != BlockState::Received)
+                    .map(|(block, _)| self.request_for(index, block))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::bitfield::Bitfield;
+    use crate::block::BlockRequest;
+    use crate::picker::{BlockScheduler, PickContext, PiecePicker, Sequential};
+    use crate::piece::PieceGeometry;
+    use crate::storage::selection::Priority;
+    use std::net::SocketAddr;
+    use std::time::{Duration, Instant};
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    fn scheduler() -> BlockScheduler {
+        let geometry = PieceGeometry::new(4, 10)
+            .unwrap()
+            .with_block_size(2);
+        BlockScheduler::new(geometry, Bitfield::new(3))
+    }
+
+    #[test]
+    fn test_requests() {
+        let mut scheduler = scheduler();
+        let all = Bitfield::full(3);
+
+        assert_eq!(scheduler.next_request(addr(1), &all), Some(BlockRequest::new(0, 0, 2)));
+        assert_eq!(scheduler.next_request(addr(1), &all), Some(BlockRequest::new(0, 2, 2)));
+        assert_eq!(scheduler.next_request(addr(2), &all), Some(BlockRequest::new(1, 0, 2)));
+
+        assert_eq!(scheduler.block_received(&BlockRequest::new(0, 0, 2)), None);
+        assert_eq!(scheduler.block_received(&BlockRequest::new(0, 2, 2)), Some(0));
+        scheduler.piece_verified(0);
+        assert!(scheduler.bitfield().get(0));
+    }
+
+    #[test]
+    fn test_peer_lost_requeues() {
+        let mut scheduler = scheduler();
+        let all = Bitfield::full(3);
+
+        scheduler.next_request(addr(1), &all);
+        scheduler.next_request(addr(1), &all);
+        scheduler.block_received(&BlockRequest::new(0, 0, 2));
+
+        assert_eq!(scheduler.peer_lost(addr(1)), vec![BlockRequest::new(0, 2, 2)]);
+        assert_eq!(scheduler.missing_blocks(0), vec![BlockRequest::new(0, 2, 2)]);
+
+        // Another peer only has to fetch the missing block.
+        assert_eq!(scheduler.next_request(addr(2), &all), Some(BlockRequest::new(0, 2, 2)));
+        assert_eq!(scheduler.block_received(&BlockRequest::new(0, 2, 2)), Some(0));
+    }
+
+    #[test]
+    fn test_endgame() {
+        let mut scheduler = scheduler();
+        let all = Bitfield::full(3);
+        for _ in 0..4 {
+            scheduler.next_request(addr(1), &all).unwrap();
+        }
+        assert!(!scheduler.is_endgame());
+        assert_eq!(scheduler.endgame_request(addr(2), &all, |_| false), None);
+
+        // Once nothing is left unasked for, a second peer races the first
+        // for the blocks it hasn't asked for yet.
+        assert_eq!(scheduler.next_request(addr(1), &all), Some(BlockRequest::new(2, 0, 2)));
+        assert!(scheduler.is_endgame());
+        assert_eq!(scheduler.endgame_request(addr(1), &all, |_| false), None);
+        assert_eq!(scheduler.endgame_request(addr(2), &all, |_| false), Some(BlockRequest::new(0, 0, 2)));
+        let asked = |request: &BlockRequest| request.index < 2;
+        assert_eq!(scheduler.endgame_request(addr(2), &all, asked), Some(BlockRequest::new(2, 0, 2)));
+        scheduler.block_received(&BlockRequest::new(2, 0, 2));
+        assert_eq!(scheduler.endgame_request(addr(2), &all, asked), None);
+    }
+
+    #[test]
+    fn test_last_piece_and_failure() {
+        let mut scheduler = scheduler();
+        let mut has = Bitfield::new(3);
+        has.set(2);
+
+        assert_eq!(scheduler.next_request(addr(1), &has), Some(BlockRequest::new(2, 0, 2)));
+        assert_eq!(scheduler.next_request(addr(1), &has), None);
+        scheduler.block_received(&BlockRequest::new(2, 0, 2));
+        scheduler.piece_failed(2);
+        assert_eq!(scheduler.missing_blocks(2), vec![BlockRequest::new(2, 0, 2)]);
+    }
+
+    #[test]
+    fn test_skips_unwanted_pieces() {
+        let mut scheduler = scheduler();
+        let mut wanted = Bitfield::new(3);
+        wanted.set(1);
+        scheduler.set_wanted(&wanted);
+
+        assert_eq!(scheduler.next_request(addr(1), &Bitfield::full(3)), Some(BlockRequest::new(1, 0, 2)));
+        assert_eq!(scheduler.next_request(addr(1), &Bitfield::full(3)), Some(BlockRequest::new(1, 2, 2)));
+        assert_eq!(scheduler.next_request(addr(1), &Bitfield::full(3)), None);
+        assert!(!scheduler.is_done());
+        scheduler.piece_verified(1);
+        assert!(scheduler.is_done());
+    }
+
+    #[test]
+    fn test_priorities() {
+        let mut scheduler = scheduler();
+        scheduler.set_priorities(vec![Priority::Low, Priority::Normal, Priority::High]);
+        let all = Bitfield::full(3);
+
+        assert_eq!(scheduler.next_request(addr(1), &all), Some(BlockRequest::new(2, 0, 2)));
+        assert_eq!(scheduler.next_request(addr(1), &all), Some(BlockRequest::new(1, 0, 2)));
+        assert_eq!(scheduler.next_request(addr(1), &all), Some(BlockRequest::new(1, 2, 2)));
+        assert_eq!(scheduler.next_request(addr(1), &all), Some(BlockRequest::new(0, 0, 2)));
+    }
+
+    #[test]
+    fn test_deadlines() {
+        let mut scheduler = scheduler();
+        let mut wanted = Bitfield::new(3);
+        wanted.set(0);
+        scheduler.set_wanted(&wanted);
+        let all = Bitfield::full(3);
+        let now = Instant::now();
+
+        // The soonest deadline goes first, even for a skipped piece, and
+        // then the rest of a partly requested piece.
+        assert_eq!(scheduler.next_request(addr(1), &all), Some(BlockRequest::new(0, 0, 2)));
+        scheduler.set_deadline(2, now + Duration::from_secs(2));
+        scheduler.set_deadline(1, now + Duration::from_secs(1));
+        assert!(scheduler.is_wanted(1));
+        assert_eq!(scheduler.next_request(addr(1), &all), Some(BlockRequest::new(1, 0, 2)));
+        assert_eq!(scheduler.next_request(addr(1), &all), Some(BlockRequest::new(1, 2, 2)));
+        assert_eq!(scheduler.next_request(addr(1), &all), Some(BlockRequest::new(2, 0, 2)));
+        assert_eq!(scheduler.next_request(addr(1), &all), Some(BlockRequest::new(0, 2, 2)));
+
+        scheduler.block_received(&BlockRequest::new(1, 0, 2));
+        scheduler.block_received(&BlockRequest::new(1, 2, 2));
+        scheduler.piece_verified(1);
+        assert_eq!(scheduler.deadline(1), None);
+        scheduler.expire_deadlines(now + Duration::from_secs(3));
+        assert_eq!(scheduler.deadline(2), None);
+        assert!(!scheduler.is_wanted(2));
+    }
+
+    #[test]
+    fn test_rarest_first() {
+        let mut scheduler = scheduler();
+        scheduler.add_availability(&Bitfield::full(3));
+        scheduler.add_availability(&Bitfield::full(3));
+        let mut has = Bitfield::new(3);
+        has.set(0);
+        has.set(2);
+        scheduler.add_availability(&has);
+        scheduler.remove_availability(&Bitfield::full(3));
+        assert_eq!((scheduler.availability(0), scheduler.availability(1)), (2, 1));
+
+        // Piece 1 is the rarest; then piece 0's other block before piece 2.
+        let all = Bitfield::full(3);
+        assert_eq!(scheduler.next_request(addr(1), &all), Some(BlockRequest::new(1, 0, 2)));
+        scheduler.piece_available(1);
+        scheduler.piece_available(1);
+        scheduler.block_received(&BlockRequest::new(1, 0, 2));
+        assert_eq!(scheduler.next_request(addr(1), &all), Some(BlockRequest::new(1, 2, 2)));
+        assert_eq!(scheduler.next_request(addr(1), &all), Some(BlockRequest::new(0, 0, 2)));
+    }
+
+    // Fetches the highest index first.
+    #[derive(Debug)]
+    struct Backwards;
+
+    impl PiecePicker for Backwards {
+        fn pick(&mut self, candidates: &[u32], _: &PickContext<'_>) -> Option<u32> {
+            candidates.last().copied()
+        }
+    }
+
+    #[test]
+    fn test_custom_pickers() {
+        let mut scheduler = scheduler().with_picker(Box::new(Backwards));
+        let all = Bitfield::full(3);
+        assert_eq!(scheduler.next_request(addr(1), &all), Some(BlockRequest::new(2, 0, 2)));
+        assert_eq!(scheduler.next_request(addr(1), &all), Some(BlockRequest::new(1, 0, 2)));
+
+        // Sequential ignores rarity and finishes piece 1 first.
+        scheduler.set_picker(Box::new(Sequential));
+        scheduler.add_availability(&Bitfield::full(3));
+        scheduler.piece_available(0);
+        assert_eq!(scheduler.next_request(addr(1), &all), Some(BlockRequest::new(1, 2, 2)));
+        assert_eq!(scheduler.next_request(addr(1), &all), Some(BlockRequest::new(0, 0, 2)));
+    }
+}
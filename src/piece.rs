@@ -0,0 +1,169 @@
+use crate::block::BlockRequest;
+use std::fmt;
+
+pub const DEFAULT_BLOCK_SIZE: u32 = 16 * 1024;
+pub const MAX_REQUEST_LENGTH: u32 = 128 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RequestError {
+    InvalidPiece,
+    ZeroLength,
+    TooLong,
+    OutOfRange
+}
+
+impl fmt::Display for RequestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RequestError::InvalidPiece => write!(f, "piece index out of range"),
+            RequestError::ZeroLength => write!(f, "zero length request"),
+            RequestError::TooLong => write!(f, "request longer than {} bytes", MAX_REQUEST_LENGTH),
+            RequestError::OutOfRange => write!(f, "request extends past the end of the piece")
+        }
+    }
+}
+
+impl std::error::Error for RequestError {}
+
+// How a torrent's content is cut into pieces and pieces into blocks. Only
+// the last piece and the last block of each piece may be short.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PieceGeometry {
+    piece_length: u32,
+    total_length: u64,
+    block_size: u32
+}
+
+impl PieceGeometry {
+    pub fn new(piece_length: u32, total_length: u64) -> Option<Self> {
+        if piece_length == 0 {
+            return None;
+        }
+        Some(Self { piece_length, total_length, block_size: DEFAULT_BLOCK_SIZE })
+    }
+
+    // Block sizes are kept within what peers are required to serve.
+    pub fn with_block_size(mut self, block_size: u32) -> Self {
+        self.block_size = block_size.clamp(1, MAX_REQUEST_LENGTH);
+        self
+    }
+
+    pub fn piece_length(&self) -> u32 {
+        self.piece_length
+    }
+
+    pub fn total_length(&self) -> u64 {
+        self.total_length
+    }
+
+    pub fn block_size(&self) -> u32 {
+        self.block_size
+    }
+
+    pub fn num_pieces(&self) -> u32 {
+        self.total_length.div_ceil(self.piece_length as u64) as u32
+    }
+
+    pub fn piece_offset(&self, index: u32) -> u64 {
+        index as u64 * self.piece_length as u64
+    }
+
+    pub fn piece_size(&self, index: u32) -> Option<u32> {
+        if index >= self.num_pieces() {
+            return None;
+        }
+        let remaining = self.total_length - self.piece_offset(index);
+        Some(remaining.min(self.piece_length as u64) as u32)
+    }
+
+    pub fn num_blocks(&self, index: u32) -> Option<u32> {
+        self.piece_size(index)
+            .map(|size| size.div_ceil(self.block_size))
+    }
+
+    pub fn blocks(&self, index: u32) -> impl Iterator<Item = BlockRequest> {
+        let size = self.piece_size(index).unwrap_or(0);
+        let block_size = self.block_size;
+
+        (0..size)
+            .step_by(block_size as usize)
+            .map(move |begin| BlockRequest::new(index, begin, block_size.min(size - begin)))
+    }
+
+    // Checks an incoming `request` before we serve it.
+    pub fn validate_request(&self, request: &BlockRequest) -> Result<(), RequestError> {
+        let size = self
+            .piece_size(request.index)
+            .ok_or(RequestError::InvalidPiece)?;
+
+        if request.length == 0 {
+            return Err(RequestError::ZeroLength);
+        }
+        if request.length > MAX_REQUEST_LENGTH {
+            return Err(RequestError::TooLong);
+        }
+        if request.begin as u64 + request.length as u64 > size as u64 {
+            return Err(RequestError::OutOfRange);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::block::BlockRequest;
+    use crate::piece::{PieceGeometry, RequestError};
+
+    #[test]
+    fn test_pieces() {
+        let geometry = PieceGeometry::new(32768, 80000).unwrap();
+
+        assert_eq!(geometry.num_pieces(), 3);
+        assert_eq!(geometry.piece_size(1), Some(32768));
+        assert_eq!(geometry.piece_size(2), Some(14464));
+        assert_eq!(geometry.piece_size(3), None);
+        assert_eq!(PieceGeometry::new(0, 10), None);
+    }
+
+    #[test]
+    fn test_blocks() {
+        let geometry = PieceGeometry::new(32768, 80000).unwrap();
+
+        assert_eq!(
+            geometry.blocks(2).collect::<Vec<_>>(),
+            vec![BlockRequest::new(2, 0, 14464)]
+        );
+
+        let geometry = geometry.with_block_size(10000);
+        assert_eq!(geometry.num_blocks(0), Some(4));
+        assert_eq!(
+            geometry.blocks(0).last(),
+            Some(BlockRequest::new(0, 30000, 2768))
+        );
+        assert_eq!(geometry.blocks(7).count(), 0);
+    }
+
+    #[test]
+    fn test_validate_request() {
+        let geometry = PieceGeometry::new(262144, 300000).unwrap();
+
+        assert_eq!(geometry.validate_request(&BlockRequest::new(0, 0, 16384)), Ok(()));
+        assert_eq!(geometry.validate_request(&BlockRequest::new(0, 0, 131072)), Ok(()));
+        assert_eq!(
+            geometry.validate_request(&BlockRequest::new(0, 0, 131073)),
+            Err(RequestError::TooLong)
+        );
+        assert_eq!(
+            geometry.validate_request(&BlockRequest::new(1, 32768, 16384)),
+            Err(RequestError::OutOfRange)
+        );
+        assert_eq!(
+            geometry.validate_request(&BlockRequest::new(2, 0, 16384)),
+            Err(RequestError::InvalidPiece)
+        );
+        assert_eq!(
+            geometry.validate_request(&BlockRequest::new(0, 0, 0)),
+            Err(RequestError::ZeroLength)
+        );
+    }
+}
@@ -0,0 +1,110 @@
+use bittorrent_rs::engine::TorrentStats;
+use std::io::{self, IsTerminal, Write};
+use std::time::Duration;
+
+const BAR_WIDTH: usize = 30;
+
+pub fn bytes(n: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = n as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    match unit {
+        0 => format!("{} B", n),
+        _ => format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+fn duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    match secs {
+        0..=59 => format!("{}s", secs),
+        60..=3599 => format!("{}m{:02}s", secs / 60, secs % 60),
+        _ => format!("{}h{:02}m", secs / 3600, secs / 60 % 60)
+    }
+}
+
+fn render(stats: &TorrentStats) -> String {
+    let filled = (stats.percent() / 100.0 * BAR_WIDTH as f64) as usize;
+    let eta = match stats.eta() {
+        Some(eta) => duration(eta),
+        None => "-".to_string()
+    };
+    format!(
+        "[{}{}] {:5.1}%  down {}/s  up {}/s  {} peers  ETA {}",
+        "#".repeat(filled),
+        ".".repeat(BAR_WIDTH - filled),
+        stats.percent(),
+        bytes(stats.download_rate),
+        bytes(stats.upload_rate),
+        stats.connected_peers,
+        eta
+    )
+}
+
+// A progress line for a download, redrawn in place. It stays quiet unless
+// stdout is a terminal, so pipes and scripts only see the results.
+pub struct Progress {
+    enabled: bool
+}
+
+impl Progress {
+    pub fn new(enabled: bool) -> Self {
+        Self { enabled: enabled && io::stdout().is_terminal() }
+    }
+
+    pub fn update(&self, stats: &TorrentStats) {
+        if self.enabled {
+            let mut stdout = io::stdout().lock();
+            let _ = write!(stdout, "\r{}\x1b[K", render(stats));
+            let _ = stdout.flush();
+        }
+    }
+
+    // Clears the line for whatever gets printed next.
+    pub fn finish(&self) {
+        if self.enabled {
+            let mut stdout = io::stdout().lock();
+            let _ = write!(stdout, "\r\x1b[K");
+            let _ = stdout.flush();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::progress::{bytes, duration, render};
+    use bittorrent_rs::engine::TorrentStats;
+    use std::time::Duration;
+
+    #[test]
+    fn test_format() {
+        assert_eq!(bytes(512), "512 B");
+        assert_eq!(bytes(1536), "1.5 KiB");
+        assert_eq!(bytes(3 * 1024 * 1024 * 1024), "3.0 GiB");
+        assert_eq!(duration(Duration::from_secs(42)), "42s");
+        assert_eq!(duration(Duration::from_secs(192)), "3m12s");
+        assert_eq!(duration(Duration::from_secs(7500)), "2h05m");
+    }
+
+    #[test]
+    fn test_render() {
+        let stats = TorrentStats {
+            bytes_done: 500,
+            bytes_total: 1000,
+            download_rate: 100,
+            upload_rate: 2048,
+            connected_peers: 3,
+            ..TorrentStats::default()
+        };
+        assert_eq!(
+            render(&stats),
+            format!("[{}{}]  50.0%  down 100 B/s  up 2.0 KiB/s  3 peers  ETA 5s", "#".repeat(15), ".".repeat(15))
+        );
+        let stalled = TorrentStats { download_rate: 0, ..stats };
+        assert!(render(&stalled).ends_with("ETA -"));
+    }
+}
@@ -0,0 +1,321 @@
+//! Python bindings, built with the `python` feature, by maturin
+//! (`maturin develop --features python`) or by hand:
+//!
+//! ```text
+//! PYO3_BUILD_EXTENSION_MODULE=1 cargo rustc --release --lib --features python --crate-type cdylib
+//! ```
+//!
+//! then copying `libbittorrent_rs.so` to `bittorrent_rs.so` on the Python
+//! path. Bencode decodes to ints, bytes, lists and dicts with bytes keys,
+//! as it is on the wire; `download` blocks, without holding the GIL, until
+//! the data is all there.
+
+use crate::bencode::{self, Value};
+use crate::dht::bootstrap::DEFAULT_ROUTERS;
+use crate::dht::Dht;
+use crate::engine::torrent::{Shared, TorrentOptions};
+use crate::engine::{Alert, TorrentHandle};
+use crate::hash::hex;
+use crate::magnet::Magnet;
+use crate::metainfo::Metainfo;
+use crate::peer_id;
+use crate::torrent::Torrent as Data;
+use pyo3::exceptions::{PyConnectionError, PyOSError, PyTypeError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::{PyBool, PyBytes, PyDict, PyList, PyString, PyTuple};
+use std::collections::BTreeMap;
+use std::fs;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::runtime::Runtime;
+use tokio::time;
+
+// How often a download lets Python look for Ctrl-C.
+const SIGNAL_INTERVAL: Duration = Duration::from_millis(100);
+
+fn to_python<'py>(py: Python<'py>, value: &Value) -> PyResult<Bound<'py, PyAny>> {
+    Ok(match value {
+        Value::Integer(int) => int.into_pyobject(py)?.into_any(),
+        Value::Bytes(bytes) => PyBytes::new(py, bytes).into_any(),
+        Value::List(list) => PyList::new(py, list.iter().map(|item| to_python(py, item)).collect::<PyResult<Vec<_>>>()?)?.into_any(),
+        Value::Dict(dict) => {
+            let out = PyDict::new(py);
+            for (key, item) in dict {
+                out.set_item(PyBytes::new(py, key), to_python(py, item)?)?;
+            }
+            out.into_any()
+        }
+    })
+}
+
+fn from_python(object: &Bound<'_, PyAny>) -> PyResult<Value> {
+    // `bool` is an `int` to Python, but bencode has no booleans.
+    if object.is_instance_of::<PyBool>() {
+        return Err(PyTypeError::new_err("bencode has no booleans"));
+    }
+    if let Ok(int) = object.extract::<i64>() {
+        return Ok(Value::Integer(int));
+    }
+    if let Ok(bytes) = object.cast::<PyBytes>() {
+        return Ok(Value::from(bytes.as_bytes()));
+    }
+    if let Ok(text) = object.cast::<PyString>() {
+        return Ok(Value::from(text.to_str()?));
+    }
+    if let Ok(dict) = object.cast::<PyDict>() {
+        let mut out = BTreeMap::new();
+        for (key, item) in dict {
+            let key = match from_python(&key)? {
+                Value::Bytes(key) => key,
+                _ => return Err(PyTypeError::new_err("dict keys must be str or bytes"))
+            };
+            out.insert(key, from_python(&item)?);
+        }
+        return Ok(Value::Dict(out));
+    }
+    if object.is_instance_of::<PyList>() || object.is_instance_of::<PyTuple>() {
+        return Ok(Value::List(object.try_iter()?.map(|item| from_python(&item?)).collect::<PyResult<_>>()?));
+    }
+    Err(PyTypeError::new_err(format!("cannot bencode a {}", object.get_type().name()?)))
+}
+
+/// Decodes one bencoded value.
+#[pyfunction]
+fn decode<'py>(py: Python<'py>, data: &[u8]) -> PyResult<Bound<'py, PyAny>> {
+    let value = bencode::decode(data).ok_or_else(|| PyValueError::new_err("not a bencoded value"))?;
+    to_python(py, &value)
+}
+
+/// Bencodes an int, str, bytes, list, tuple or dict of those.
+#[pyfunction]
+fn encode<'py>(py: Python<'py>, value: &Bound<'py, PyAny>) -> PyResult<Bound<'py, PyBytes>> {
+    Ok(PyBytes::new(py, &from_python(value)?.encode()))
+}
+
+/// A parsed .torrent file.
+#[pyclass(frozen, module = "bittorrent_rs")]
+struct Torrent {
+    metainfo: Metainfo
+}
+
+#[pymethods]
+impl Torrent {
+    #[new]
+    fn new(data: &[u8]) -> PyResult<Self> {
+        let metainfo = Metainfo::from_bytes(data).ok_or_else(|| PyValueError::new_err("not a valid torrent"))?;
+        Ok(Self { metainfo })
+    }
+
+    #[staticmethod]
+    fn from_file(path: PathBuf) -> PyResult<Self> {
+        Self::new(&fs::read(path)?)
+    }
+
+    #[getter]
+    fn info_hash(&self) -> String {
+        hex(&self.metainfo.info_hash)
+    }
+
+    #[getter]
+    fn name(&self) -> &str {
+        &self.metainfo.name
+    }
+
+    #[getter]
+    fn piece_length(&self) -> u32 {
+        self.metainfo.piece_length
+    }
+
+    #[getter]
+    fn piece_count(&self) -> usize {
+        self.metainfo.num_pieces()
+    }
+
+    #[getter]
+    fn total_length(&self) -> u64 {
+        self.metainfo.total_length()
+    }
+
+    #[getter]
+    fn private(&self) -> bool {
+        self.metainfo.private
+    }
+
+    // `announce`, then the announce-list's, without repeats.
+    #[getter]
+    fn trackers(&self) -> Vec<String> {
+        let mut trackers: Vec<String> = self.metainfo.announce.iter().cloned().collect();
+        for tracker in self.metainfo.announce_list.iter().flatten() {
+            if !trackers.contains(tracker) {
+                trackers.push(tracker.clone());
+            }
+        }
+        trackers
+    }
+
+    /// `(path, length)` pairs.
+    #[getter]
+    fn files(&self) -> Vec<(PathBuf, u64)> {
+        self.metainfo.files.iter().map(|file| (file.path.clone(), file.length)).collect()
+    }
+
+    fn __repr__(&self) -> String {
+        format!("Torrent({:?}, info_hash={:?})", self.metainfo.name, hex(&self.metainfo.info_hash))
+    }
+}
+
+/// A parsed magnet link.
+#[pyclass(frozen, module = "bittorrent_rs")]
+struct MagnetLink {
+    magnet: Magnet
+}
+
+#[pymethods]
+impl MagnetLink {
+    #[new]
+    fn new(link: &str) -> PyResult<Self> {
+        let magnet = Magnet::parse(link).ok_or_else(|| PyValueError::new_err("not a valid magnet link"))?;
+        Ok(Self { magnet })
+    }
+
+    #[getter]
+    fn info_hash(&self) -> String {
+        hex(&self.magnet.info_hash)
+    }
+
+    #[getter]
+    fn name(&self) -> Option<&str> {
+        self.magnet.name.as_deref()
+    }
+
+    #[getter]
+    fn trackers(&self) -> Vec<String> {
+        self.magnet.trackers.clone()
+    }
+
+    // `x.pe` peers, as `host:port`.
+    #[getter]
+    fn peers(&self) -> Vec<String> {
+        self.magnet.peers.iter().map(ToString::to_string).collect()
+    }
+
+    fn __repr__(&self) -> String {
+        format!("MagnetLink(info_hash={:?})", hex(&self.magnet.info_hash))
+    }
+}
+
+// The peers given, or else whatever the DHT knows of.
+fn find_peers(info_hash: [u8; 20], peers: Option<Vec<String>>) -> PyResult<Vec<SocketAddr>> {
+    if let Some(peers) = peers {
+        return peers
+            .iter()
+            .map(|peer| {
+                let mut addrs = peer.to_socket_addrs()?;
+                addrs.next().ok_or_else(|| PyValueError::new_err(format!("{} resolves to nothing", peer)))
+            })
+            .collect();
+    }
+    let mut dht = Dht::bind("0.0.0.0:0")?;
+    let routers: Vec<_> = DEFAULT_ROUTERS.iter().map(|router| router.to_string()).collect();
+    if dht.bootstrap(&routers, &[]) == 0 {
+        return Err(PyConnectionError::new_err("could not reach the DHT"));
+    }
+    match dht.lookup_peers(info_hash).peers {
+        peers if peers.is_empty() => Err(PyConnectionError::new_err("no peers found")),
+        peers => Ok(peers)
+    }
+}
+
+/// Downloads `torrent` into `dir`, from `peers` (`host:port` strings) or
+/// else from those the DHT finds, and returns the path of what it wrote.
+/// Interrupted, the next call for the same torrent and directory carries on
+/// where it stopped.
+#[pyfunction]
+#[pyo3(signature = (torrent, dir = PathBuf::from("."), peers = None))]
+fn download(py: Python<'_>, torrent: &Torrent, dir: PathBuf, peers: Option<Vec<String>>) -> PyResult<PathBuf> {
+    let metainfo = torrent.metainfo.clone();
+    py.detach(move || {
+        let output = dir.join(&metainfo.name);
+        // Saved next to the data when a download is interrupted, as the
+        // `download` command does.
+        let resume_path = dir.join(format!(".{}.resume", hex(&metainfo.info_hash)));
+        let peers = find_peers(metainfo.info_hash, peers)?;
+        let mut data = Data::new(metainfo, &dir).ok_or_else(|| PyValueError::new_err("the torrent's pieces don't match its files"))?;
+        data.resume_from(&resume_path);
+
+        let outcome = Runtime::new()?.block_on(async {
+            let options = TorrentOptions::new().with_resume_path(&resume_path);
+            let handle = TorrentHandle::spawn_with(data, peer_id::generate(), options, Shared::default());
+            peers.into_iter().for_each(|peer| handle.add_peer(peer));
+            let mut alerts = handle.subscribe();
+            let mut tick = time::interval(SIGNAL_INTERVAL);
+            let outcome = loop {
+                tokio::select! {
+                    _ = handle.wait_complete() => break Ok(()),
+                    Ok(Alert::StorageError { message, .. }) = alerts.recv() => break Err(PyOSError::new_err(message)),
+                    _ = tick.tick() => {
+                        if let Err(err) = Python::attach(|py| py.check_signals()) {
+                            break Err(err);
+                        }
+                    }
+                }
+            };
+            handle.shutdown().await;
+            outcome
+        });
+        outcome?;
+        let _ = fs::remove_file(&resume_path);
+        Ok(output)
+    })
+}
+
+#[pymodule]
+fn bittorrent_rs(module: &Bound<'_, PyModule>) -> PyResult<()> {
+    module.add_function(wrap_pyfunction!(decode, module)?)?;
+    module.add_function(wrap_pyfunction!(encode, module)?)?;
+    module.add_function(wrap_pyfunction!(download, module)?)?;
+    module.add_class::<Torrent>()?;
+    module.add_class::<MagnetLink>()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::bencode::Value;
+    use crate::python::{decode, encode, MagnetLink, Torrent};
+    use pyo3::prelude::*;
+    use pyo3::IntoPyObjectExt;
+    use pyo3::types::{PyBytes, PyDict};
+
+    #[test]
+    fn test_bindings() {
+        Python::initialize();
+        Python::attach(|py| {
+            let value = decode(py, b"d3:cowl3:mooi1eee").unwrap();
+            let dict = value.cast::<PyDict>().unwrap();
+            let cow = dict.get_item(PyBytes::new(py, b"cow")).unwrap().unwrap();
+            assert_eq!(cow.repr().unwrap().to_str().unwrap(), "[b'moo', 1]");
+            assert_eq!(encode(py, &value).unwrap().as_bytes(), b"d3:cowl3:mooi1eee");
+
+            // str keys and values encode as bytes; tuples as lists.
+            let dict = PyDict::new(py);
+            dict.set_item("cow", ("moo", 1)).unwrap();
+            assert_eq!(encode(py, &dict).unwrap().as_bytes(), b"d3:cowl3:mooi1eee");
+            assert!(encode(py, &true.into_bound_py_any(py).unwrap()).is_err());
+            assert!(encode(py, &1.5f64.into_bound_py_any(py).unwrap()).is_err());
+            assert!(decode(py, b"d3:cow").is_err());
+
+            let info = Value::dict([("name", "a.iso".into()), ("length", 4.into()), ("piece length", 4.into()), ("pieces", Value::from(&[0; 20][..]))]);
+            let file = Value::dict([("announce", "http://a".into()), ("info", info)]).encode();
+            let torrent = Torrent::new(&file).unwrap();
+            assert_eq!((torrent.name(), torrent.piece_count(), torrent.total_length()), ("a.iso", 1, 4));
+            assert_eq!(torrent.trackers(), ["http://a"]);
+            assert_eq!(torrent.files(), [("a.iso".into(), 4)]);
+            assert!(Torrent::new(b"de").is_err());
+
+            let magnet = MagnetLink::new("magnet:?xt=urn:btih:c12fe1c06bba254a9dc9f519b335aa7c1367a88a&dn=a").unwrap();
+            assert_eq!((magnet.info_hash(), magnet.name()), ("c12fe1c06bba254a9dc9f519b335aa7c1367a88a".to_string(), Some("a")));
+        });
+    }
+}
@@ -0,0 +1,80 @@
+use std::fs::File;
+use std::io;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Allocation {
+    // Holes that take no disk space until pieces are written into them.
+    #[default]
+    Sparse,
+    // Every block reserved up front, so the file does not fragment as
+    // pieces arrive out of order and a full disk shows up straight away.
+    Full
+}
+
+// Grows the file to `len` bytes. Files are never shrunk.
+pub fn allocate(file: &File, len: u64, mode: Allocation) -> io::Result<()> {
+    match mode {
+        Allocation::Sparse => {
+            if file.metadata()?.len() < len {
+                file.set_len(len)?;
+            }
+            Ok(())
+        },
+        Allocation::Full => reserve(file, len)
+    }
+}
+
+#[cfg(unix)]
+fn reserve(file: &File, len: u64) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    if len == 0 {
+        return Ok(());
+    }
+    let len = libc::off_t::try_from(len).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "file too large"))?;
+    match unsafe { libc::posix_fallocate(file.as_raw_fd(), 0, len) } {
+        0 => Ok(()),
+        error => Err(io::Error::from_raw_os_error(error))
+    }
+}
+
+// Extending a file on Windows allocates its clusters without zeroing them.
+#[cfg(not(unix))]
+fn reserve(file: &File, len: u64) -> io::Result<()> {
+    if file.metadata()?.len() < len {
+        file.set_len(len)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::storage::allocate::{allocate, Allocation};
+    use std::fs::{self, File};
+
+    #[test]
+    fn test_allocate() {
+        let dir = std::env::temp_dir();
+        let len = 1 << 20;
+
+        for (name, mode) in [("sparse", Allocation::Sparse), ("full", Allocation::Full)] {
+            let path = dir.join(format!("allocate-{}-{}", name, std::process::id()));
+            let file = File::create(&path).unwrap();
+            allocate(&file, len, mode).unwrap();
+            allocate(&file, 10, mode).unwrap();
+            let metadata = file.metadata().unwrap();
+            assert_eq!(metadata.len(), len);
+
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::MetadataExt;
+                let reserved = metadata.blocks() * 512;
+                match mode {
+                    Allocation::Sparse => assert!(reserved < len),
+                    Allocation::Full => assert!(reserved >= len)
+                }
+            }
+            fs::remove_file(&path).unwrap();
+        }
+    }
+}
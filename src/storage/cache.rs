@@ -0,0 +1,198 @@
+use crate::storage::Storage;
+use std::collections::BTreeMap;
+use std::io;
+
+pub const DEFAULT_CACHE_SIZE: usize = 16 * 1024 * 1024;
+
+// Holds received blocks in memory until their piece completes, then writes
+// each run of adjacent blocks with a single call instead of one per block.
+// When the buffered bytes go over the capacity, the pieces holding the most
+// data are written out early.
+pub struct WriteCache {
+    capacity: usize,
+    size: usize,
+    // Blocks of each piece keyed by their offset in it.
+    pieces: BTreeMap<u32, BTreeMap<u32, Vec<u8>>>
+}
+
+impl Default for WriteCache {
+    fn default() -> Self {
+        Self::new(DEFAULT_CACHE_SIZE)
+    }
+}
+
+impl WriteCache {
+    pub fn new(capacity: usize) -> Self {
+        Self { capacity, size: 0, pieces: BTreeMap::new() }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    // Bytes currently waiting to be written.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn write<S: Storage + ?Sized>(&mut self, storage: &mut S, piece: u32, begin: u32, data: Vec<u8>) -> io::Result<()> {
+        self.size += data.len();
+        if let Some(old) = self.pieces.entry(piece).or_default().insert(begin, data) {
+            self.size -= old.len();
+        }
+
+        while self.size > self.capacity {
+            let Some(fullest) = self.pieces
+                .iter()
+                .max_by_key(|(_, blocks)| blocks.values().map(Vec::len).sum::<usize>())
+                .map(|(&piece, _)| piece)
+            else {
+                break;
+            };
+            self.flush_piece(storage, fullest)?;
+        }
+        Ok(())
+    }
+
+    // Writes out everything buffered for `piece`, such as when it completes
+    // and is about to be hashed.
+    pub fn flush_piece<S: Storage + ?Sized>(&mut self, storage: &mut S, piece: u32) -> io::Result<()> {
+        let Some(blocks) = self.pieces.remove(&piece) else {
+            return Ok(());
+        };
+        self.size -= blocks.values().map(Vec::len).sum::<usize>();
+        write_runs(storage, &[(piece, blocks)])
+    }
+
+    // Writes out everything, with runs that carry on from one piece into the
+    // next written together.
+    pub fn flush<S: Storage + ?Sized>(&mut self, storage: &mut S) -> io::Result<()> {
+        let pieces: Vec<_> = std::mem::take(&mut self.pieces).into_iter().collect();
+        self.size = 0;
+        write_runs(storage, &pieces)
+    }
+
+    // Drops a piece's blocks without writing them, such as when it failed
+    // its hash check.
+    pub fn discard(&mut self, piece: u32) {
+        if let Some(blocks) = self.pieces.remove(&piece) {
+            self.size -= blocks.values().map(Vec::len).sum::<usize>();
+        }
+    }
+}
+
+// Writes `pieces`' blocks, in order, with one vectored write for each run
+// of them that follows on without a gap. Nothing is copied to join them.
+fn write_runs<S: Storage + ?Sized>(storage: &mut S, pieces: &[(u32, BTreeMap<u32, Vec<u8>>)]) -> io::Result<()> {
+    let mut start = None;
+    let mut end = (0, 0);
+    let mut run: Vec<&[u8]> = Vec::new();
+    for (piece, blocks) in pieces {
+        for (&begin, data) in blocks {
+            let piece_size = storage.layout().geometry().piece_size(end.0).unwrap_or(0);
+            let follows = start.is_some() && ((*piece, begin) == end || (*piece == end.0 + 1 && begin == 0 && end.1 == piece_size));
+            if !follows {
+                if let Some((piece, begin)) = start.replace((*piece, begin)) {
+                    storage.write_vectored(piece, begin, &run)?;
+                }
+                run.clear();
+            }
+            run.push(data);
+            end = (*piece, begin + data.len() as u32);
+        }
+    }
+    match start {
+        Some((piece, begin)) => storage.write_vectored(piece, begin, &run),
+        None => Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::storage::cache::WriteCache;
+    use crate::storage::layout::{FileEntry, Layout};
+    use crate::storage::memory::MemoryStorage;
+    use crate::storage::{FileStorage, Storage};
+    use std::fs;
+    use std::io;
+
+    // Counts the writes `WriteCache` makes.
+    struct Counting<S>(S, Vec<(u32, u32, usize)>);
+
+    impl<S: Storage> Storage for Counting<S> {
+        fn layout(&self) -> &Layout {
+            self.0.layout()
+        }
+
+        fn read(&self, piece: u32, begin: u32, len: usize) -> io::Result<Vec<u8>> {
+            self.0.read(piece, begin, len)
+        }
+
+        fn write(&mut self, piece: u32, begin: u32, data: &[u8]) -> io::Result<()> {
+            self.write_vectored(piece, begin, &[data])
+        }
+
+        fn write_vectored(&mut self, piece: u32, begin: u32, buffers: &[&[u8]]) -> io::Result<()> {
+            self.1.push((piece, begin, buffers.len()));
+            self.0.write_vectored(piece, begin, buffers)
+        }
+    }
+
+    #[test]
+    fn test_write_cache() {
+        let root = std::env::temp_dir().join(format!("cache-{}", std::process::id()));
+        let mut storage = FileStorage::new(&root, Layout::new(vec![FileEntry::new("file", 16)], 8).unwrap());
+        storage.create_files().unwrap();
+        let mut cache = WriteCache::new(6);
+
+        cache.write(&mut storage, 0, 2, b"cd".to_vec()).unwrap();
+        cache.write(&mut storage, 0, 0, b"ab".to_vec()).unwrap();
+        cache.write(&mut storage, 1, 0, b"ij".to_vec()).unwrap();
+        assert_eq!(cache.size(), 6);
+        assert_eq!(fs::read(root.join("file")).unwrap(), [0; 16]);
+
+        // Going over capacity writes out piece 0, which holds the most.
+        cache.write(&mut storage, 0, 4, b"ef".to_vec()).unwrap();
+        assert_eq!(cache.size(), 2);
+        assert_eq!(storage.read(0, 0, 8).unwrap(), b"abcdef\0\0");
+
+        cache.write(&mut storage, 1, 6, b"op".to_vec()).unwrap();
+        cache.discard(1);
+        cache.write(&mut storage, 1, 0, b"IJ".to_vec()).unwrap();
+        cache.flush(&mut storage).unwrap();
+        assert_eq!(cache.size(), 0);
+        assert_eq!(storage.read_piece(1).unwrap(), b"IJ\0\0\0\0\0\0");
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_flush_runs() {
+        let root = std::env::temp_dir().join(format!("cache-runs-{}", std::process::id()));
+        let layout = Layout::new(vec![FileEntry::new("a", 5), FileEntry::new("b", 11)], 8).unwrap();
+        let storage = FileStorage::new(&root, layout.clone());
+        storage.create_files().unwrap();
+        let mut storage = Counting(storage, Vec::new());
+        let mut memory = Counting(MemoryStorage::new(layout), Vec::new());
+
+        for storage in [&mut storage as &mut dyn Storage, &mut memory] {
+            let mut cache = WriteCache::default();
+            let blocks: [(u32, u32, &[u8]); 4] = [(1, 0, b"ijkl"), (0, 4, b"efgh"), (0, 0, b"abcd"), (1, 6, b"op")];
+            for (piece, begin, data) in blocks {
+                cache.write(storage, piece, begin, data.to_vec()).unwrap();
+            }
+            cache.flush(storage).unwrap();
+            assert_eq!(cache.size(), 0);
+        }
+        // Across the pieces and the files, up to the gap, goes in one write.
+        assert_eq!(storage.1, [(0, 0, 3), (1, 6, 1)]);
+        assert_eq!(memory.1, storage.1);
+        assert_eq!(fs::read(root.join("a")).unwrap(), b"abcde");
+        assert_eq!(fs::read(root.join("b")).unwrap(), b"fghijkl\0\0op");
+        for piece in 0..2 {
+            assert_eq!(memory.read_piece(piece).unwrap(), storage.read_piece(piece).unwrap());
+        }
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,102 @@
+use crate::storage::resume::ResumeData;
+use crate::storage::Storage;
+use std::io;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+// When written data is forced out to the disk. Syncing more often loses
+// less on a crash and costs throughput.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SyncPolicy {
+    // Leave it to the operating system.
+    #[default]
+    Never,
+    // The files of each piece once it verifies.
+    OnPiece,
+    // All files at most this often while pieces keep verifying.
+    Periodic(Duration),
+    // All files once the whole torrent is done.
+    OnCompletion
+}
+
+// Applies a `SyncPolicy` as pieces verify and the torrent completes.
+#[derive(Debug, Clone)]
+pub struct Durability {
+    policy: SyncPolicy,
+    last_sync: Option<Instant>
+}
+
+impl Durability {
+    pub fn new(policy: SyncPolicy) -> Self {
+        Self { policy, last_sync: None }
+    }
+
+    pub fn policy(&self) -> SyncPolicy {
+        self.policy
+    }
+
+    // Returns whether anything was synced.
+    pub fn piece_verified<S: Storage + ?Sized>(&mut self, storage: &S, piece: u32, now: Instant) -> io::Result<bool> {
+        match self.policy {
+            SyncPolicy::OnPiece => storage.sync_piece(piece).map(|_| true),
+            SyncPolicy::Periodic(interval) => {
+                if self.last_sync.is_some_and(|last| now < last + interval) {
+                    return Ok(false);
+                }
+                self.last_sync = Some(now);
+                storage.sync_all().map(|_| true)
+            },
+            SyncPolicy::Never | SyncPolicy::OnCompletion => Ok(false)
+        }
+    }
+
+    pub fn torrent_completed<S: Storage + ?Sized>(&mut self, storage: &S) -> io::Result<bool> {
+        match self.policy {
+            SyncPolicy::Never => Ok(false),
+            _ => storage.sync_all().map(|_| true)
+        }
+    }
+
+    // Saves resume data, synced unless the policy never syncs.
+    pub fn save_resume(&self, resume: &ResumeData, path: impl AsRef<Path>) -> io::Result<()> {
+        match self.policy {
+            SyncPolicy::Never => resume.save(path),
+            _ => resume.save_synced(path)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::storage::durability::{Durability, SyncPolicy};
+    use crate::storage::layout::{FileEntry, Layout};
+    use crate::storage::FileStorage;
+    use std::fs;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_policies() {
+        let root = std::env::temp_dir().join(format!("durability-{}", std::process::id()));
+        let storage = FileStorage::new(&root, Layout::new(vec![FileEntry::new("file", 8)], 4).unwrap());
+        storage.create_files().unwrap();
+        let now = Instant::now();
+
+        let mut never = Durability::new(SyncPolicy::Never);
+        assert!(!never.piece_verified(&storage, 0, now).unwrap());
+        assert!(!never.torrent_completed(&storage).unwrap());
+
+        let mut on_piece = Durability::new(SyncPolicy::OnPiece);
+        assert!(on_piece.piece_verified(&storage, 1, now).unwrap());
+
+        let mut periodic = Durability::new(SyncPolicy::Periodic(Duration::from_secs(5)));
+        assert!(periodic.piece_verified(&storage, 0, now).unwrap());
+        assert!(!periodic.piece_verified(&storage, 1, now + Duration::from_secs(1)).unwrap());
+        assert!(periodic.piece_verified(&storage, 1, now + Duration::from_secs(5)).unwrap());
+
+        let mut on_completion = Durability::new(SyncPolicy::OnCompletion);
+        assert!(!on_completion.piece_verified(&storage, 0, now).unwrap());
+        assert!(on_completion.torrent_completed(&storage).unwrap());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
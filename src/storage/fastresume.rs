@@ -0,0 +1,238 @@
+use crate::bencode::{self, Value};
+use crate::bitfield::Bitfield;
+use crate::hash::hex;
+use crate::storage::resume::{FileStamp, ResumeData, TrackerState};
+use crate::storage::Storage;
+use std::path::PathBuf;
+
+const FILE_FORMAT: &str = "libtorrent resume file";
+
+// libtorrent's resume data, as qBittorrent keeps it in one `.fastresume`
+// file per torrent and Deluge in one `torrents.fastresume` for them all.
+// Only what maps onto `ResumeData`, and where the data is, is kept.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FastResume {
+    pub info_hash: [u8; 20],
+    // One per byte of `pieces`, which may not match the torrent.
+    pub pieces: Bitfield,
+    // Everything is there, unchecked; `pieces` may be left out.
+    pub seed_mode: bool,
+    // `file_sizes`, which libtorrent stopped writing in 1.2.
+    pub files: Option<Vec<FileStamp>>,
+    pub uploaded: u64,
+    pub downloaded: u64,
+    // Tiers of announce URLs, replacing the torrent's.
+    pub trackers: Vec<Vec<String>>,
+    pub save_path: Option<PathBuf>,
+    pub name: Option<String>,
+    pub paused: bool
+}
+
+fn int(value: u64) -> Value {
+    Value::Integer(value as i64)
+}
+
+fn uint(value: &Value, key: &str) -> Option<u64> {
+    u64::try_from(value.get(key)?.as_int()?).ok()
+}
+
+fn flag(value: &Value, key: &str) -> bool {
+    uint(value, key).is_some_and(|flag| flag != 0)
+}
+
+impl FastResume {
+    pub fn decode(bytes: &[u8]) -> Option<Self> {
+        let value = bencode::decode(bytes)?;
+        if value.get("file-format")?.as_str()? != FILE_FORMAT {
+            return None;
+        }
+        let pieces = value.get("pieces").and_then(Value::as_bytes).unwrap_or_default();
+        let mut have = Bitfield::new(pieces.len());
+        for (piece, _) in pieces.iter().enumerate().filter(|(_, flags)| *flags & 1 == 1) {
+            have.set(piece);
+        }
+        // `[size, mtime]`, with 0 for a time it didn't know.
+        let files = value.get("file_sizes").and_then(Value::as_list).map(|files| {
+            files
+                .iter()
+                .filter_map(Value::as_list)
+                .map(|file| FileStamp {
+                    length: file.first().and_then(Value::as_int).and_then(|length| u64::try_from(length).ok()).unwrap_or(0),
+                    modified: file.get(1).and_then(Value::as_int).and_then(|modified| u64::try_from(modified).ok()).filter(|&modified| modified > 0)
+                })
+                .collect()
+        });
+        let trackers = value
+            .get("trackers")
+            .and_then(Value::as_list)
+            .unwrap_or_default()
+            .iter()
+            .filter_map(Value::as_list)
+            .map(|tier| tier.iter().filter_map(Value::as_str).map(str::to_string).collect::<Vec<_>>())
+            .filter(|tier| !tier.is_empty())
+            .collect();
+        // qBittorrent keeps its own copy of where the data is.
+        let save_path = ["save_path", "qBt-savePath"]
+            .iter()
+            .find_map(|key| value.get(key)?.as_str().filter(|path| !path.is_empty()))
+            .map(PathBuf::from);
+
+        Some(Self {
+            info_hash: value.get("info-hash")?.as_bytes()?.try_into().ok()?,
+            pieces: have,
+            seed_mode: flag(&value, "seed_mode"),
+            files,
+            uploaded: uint(&value, "total_uploaded").unwrap_or(0),
+            downloaded: uint(&value, "total_downloaded").unwrap_or(0),
+            trackers,
+            save_path,
+            name: value.get("name").and_then(Value::as_str).map(str::to_string),
+            paused: flag(&value, "paused")
+        })
+    }
+
+    // The torrent with `info_hash` out of Deluge's `torrents.fastresume`,
+    // which keys each torrent's resume data, bencoded, by its hex info hash.
+    pub fn from_deluge(bytes: &[u8], info_hash: &[u8; 20]) -> Option<Self> {
+        let torrents = bencode::decode(bytes)?;
+        Self::decode(torrents.get(&hex(info_hash))?.as_bytes()?)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let pieces: Vec<u8> = (0..self.pieces.len()).map(|piece| self.pieces.get(piece) as u8).collect();
+        let mut fields = vec![
+            ("file-format", Value::from(FILE_FORMAT)),
+            ("file-version", int(1)),
+            ("info-hash", Value::from(&self.info_hash[..])),
+            ("pieces", pieces.into()),
+            ("seed_mode", int(self.seed_mode as u64)),
+            ("total_uploaded", int(self.uploaded)),
+            ("total_downloaded", int(self.downloaded)),
+            ("paused", int(self.paused as u64))
+        ];
+        if let Some(files) = &self.files {
+            let files = files.iter().map(|file| Value::List(vec![int(file.length), int(file.modified.unwrap_or(0))])).collect();
+            fields.push(("file_sizes", Value::List(files)));
+        }
+        // An empty list would leave the torrent with no trackers at all.
+        if !self.trackers.is_empty() {
+            let tiers = self.trackers
+                .iter()
+                .map(|tier| Value::List(tier.iter().map(|url| Value::from(url.as_str())).collect()))
+                .collect();
+            fields.push(("trackers", Value::List(tiers)));
+        }
+        if let Some(path) = &self.save_path {
+            fields.push(("save_path", Value::from(path.to_string_lossy().as_ref())));
+        }
+        if let Some(name) = &self.name {
+            fields.push(("name", Value::from(name.as_str())));
+        }
+        Value::dict(fields).encode()
+    }
+
+    // Our resume data for the torrent kept by `storage`. Files libtorrent
+    // didn't stamp are stamped as they are now, taking its word that the
+    // pieces in them are good; a piece count that doesn't match the
+    // torrent's leaves nothing to trust.
+    pub fn to_resume<S: Storage + ?Sized>(&self, storage: &S) -> ResumeData {
+        let num_pieces = storage.layout().geometry().num_pieces() as usize;
+        let pieces = match (self.seed_mode, self.pieces.len() == num_pieces) {
+            (true, _) => Bitfield::full(num_pieces),
+            (false, true) => self.pieces.clone(),
+            (false, false) => Bitfield::new(num_pieces)
+        };
+        let mut resume = ResumeData::capture(self.info_hash, storage, &pieces);
+        if let Some(files) = self.files.as_ref().filter(|files| files.len() == resume.files.len()) {
+            resume.files = files.clone();
+        }
+        resume.uploaded = self.uploaded;
+        resume.downloaded = self.downloaded;
+        resume.trackers = self.trackers
+            .iter()
+            .flatten()
+            .map(|url| TrackerState { url: url.clone(), tracker_id: None, completed_sent: false })
+            .collect();
+        resume
+    }
+
+    // `resume` for libtorrent, with the data under `save_path`.
+    pub fn from_resume(resume: &ResumeData, save_path: impl Into<PathBuf>, name: &str) -> Self {
+        Self {
+            info_hash: resume.info_hash,
+            pieces: resume.pieces.clone(),
+            seed_mode: false,
+            files: Some(resume.files.clone()),
+            uploaded: resume.uploaded,
+            downloaded: resume.downloaded,
+            trackers: resume.trackers.iter().map(|tracker| vec![tracker.url.clone()]).collect(),
+            save_path: Some(save_path.into()),
+            name: Some(name.to_string()),
+            paused: false
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::bencode::Value;
+    use crate::bitfield::Bitfield;
+    use crate::hash::hex;
+    use crate::storage::fastresume::FastResume;
+    use crate::storage::layout::{FileEntry, Layout};
+    use crate::storage::resume::{FileStamp, ResumeData};
+    use crate::storage::FileStorage;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_fastresume() {
+        // As qBittorrent writes it, less the keys only it reads.
+        let bytes = Value::dict([
+            ("file-format", "libtorrent resume file".into()),
+            ("file-version", 1.into()),
+            ("info-hash", Value::from(&[7; 20][..])),
+            ("pieces", Value::from(&[1, 0, 1][..])),
+            ("total_uploaded", 40.into()),
+            ("total_downloaded", 30.into()),
+            ("trackers", Value::List(vec![Value::List(vec!["udp://a:1".into()]), Value::List(Vec::new())])),
+            ("qBt-savePath", "/downloads".into()),
+            ("paused", 1.into())
+        ])
+        .encode();
+        let fast = FastResume::decode(&bytes).unwrap();
+        assert_eq!(fast.pieces.ones().collect::<Vec<_>>(), [0, 2]);
+        assert_eq!((fast.uploaded, fast.downloaded, fast.paused, fast.seed_mode), (40, 30, true, false));
+        assert_eq!(fast.trackers, [["udp://a:1"]]);
+        assert_eq!((fast.save_path, fast.files), (Some(PathBuf::from("/downloads")), None));
+        assert_eq!(FastResume::decode(&Value::dict([("info-hash", Value::from(&[7; 20][..]))]).encode()), None);
+
+        let deluge = Value::dict([(hex(&[7; 20]).as_str(), Value::from(bytes.clone()))]).encode();
+        assert_eq!(FastResume::from_deluge(&deluge, &[7; 20]), FastResume::decode(&bytes));
+        assert_eq!(FastResume::from_deluge(&deluge, &[8; 20]), None);
+    }
+
+    #[test]
+    fn test_conversion() {
+        let root = std::env::temp_dir().join(format!("fastresume-{}", std::process::id()));
+        let storage = FileStorage::new(&root, Layout::new(vec![FileEntry::new("a", 6), FileEntry::new("b", 6)], 4).unwrap());
+        storage.create_files().unwrap();
+
+        let mut resume = ResumeData::capture([1; 20], &storage, &Bitfield::from_bytes(&[0b1010_0000], 3).unwrap());
+        resume.uploaded = 5;
+        let fast = FastResume::from_resume(&resume, &root, "t");
+        let decoded = FastResume::decode(&fast.encode()).unwrap();
+        assert_eq!(decoded, fast);
+        assert_eq!(decoded.to_resume(&storage), resume);
+        assert!(decoded.to_resume(&storage).verified_pieces(&storage).get(2));
+
+        // Newer libtorrent leaves the files unstamped, and seeds can leave
+        // out the pieces.
+        let fast = FastResume { files: None, pieces: Bitfield::new(0), seed_mode: true, ..decoded };
+        let resume = fast.to_resume(&storage);
+        assert!(resume.pieces.is_complete());
+        assert_eq!(resume.files, [FileStamp::of(root.join("a")), FileStamp::of(root.join("b"))]);
+        assert!(resume.verified_pieces(&storage).is_complete());
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
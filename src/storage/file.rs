@@ -0,0 +1,416 @@
+use crate::bitfield::Bitfield;
+use crate::storage::allocate::{allocate, Allocation};
+use crate::storage::layout::{Layout, Span};
+use crate::storage::resume::FileStamp;
+use crate::storage::{out_of_range, relocate, Storage};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, IoSlice, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+pub const PART_SUFFIX: &str = ".part";
+
+// The most buffers one vectored write takes, `IOV_MAX` on Linux.
+const MAX_SLICES: usize = 1024;
+
+// Reads and writes piece data in the torrent's files under `root`. Files
+// and their directories are created the first time they are written.
+pub struct FileStorage {
+    root: PathBuf,
+    layout: Layout,
+    allocation: Allocation,
+    // Whether unfinished files carry `PART_SUFFIX` until they complete.
+    part_files: bool
+}
+
+impl FileStorage {
+    pub fn new(root: impl Into<PathBuf>, layout: Layout) -> Self {
+        Self { root: root.into(), layout, allocation: Allocation::default(), part_files: false }
+    }
+
+    pub fn with_allocation(mut self, allocation: Allocation) -> Self {
+        self.allocation = allocation;
+        self
+    }
+
+    pub fn allocation(&self) -> Allocation {
+        self.allocation
+    }
+
+    pub fn with_part_files(mut self, part_files: bool) -> Self {
+        self.part_files = part_files;
+        self
+    }
+
+    pub fn part_files(&self) -> bool {
+        self.part_files
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn layout(&self) -> &Layout {
+        &self.layout
+    }
+
+    // Where the file ends up once it is complete.
+    pub fn final_path(&self, file: usize) -> PathBuf {
+        self.root.join(&self.layout.files()[file].path)
+    }
+
+    fn part_path(&self, file: usize) -> PathBuf {
+        let mut path = self.final_path(file).into_os_string();
+        path.push(PART_SUFFIX);
+        path.into()
+    }
+
+    // Where the file is right now. With part files, a file that has not been
+    // renamed to its final name yet lives under the `.part` name.
+    pub fn path(&self, file: usize) -> PathBuf {
+        let path = self.final_path(file);
+        match self.part_files && !path.exists() {
+            true => self.part_path(file),
+            false => path
+        }
+    }
+
+    // Renames the file to its final name, if it still has the `.part` one.
+    pub fn finish_file(&self, file: usize) -> io::Result<()> {
+        let part = self.part_path(file);
+        match self.part_files && part.exists() {
+            true => fs::rename(part, self.final_path(file)),
+            false => Ok(())
+        }
+    }
+
+    // Finishes every file whose pieces are all in `have`, returning the
+    // files that were renamed just now.
+    pub fn finish_complete_files(&self, have: &Bitfield) -> io::Result<Vec<usize>> {
+        let mut finished = Vec::new();
+        for file in 0..self.layout.files().len() {
+            let complete = self.layout.file_pieces(file).all(|piece| have.get(piece as usize));
+            if complete && self.part_files && self.part_path(file).exists() {
+                self.finish_file(file)?;
+                finished.push(file);
+            }
+        }
+        Ok(finished)
+    }
+
+    fn open_for_write(&self, file: usize) -> io::Result<File> {
+        let path = self.path(file);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        OpenOptions::new().create(true).truncate(false).write(true).open(path)
+    }
+
+    // Moves the torrent's files from a staging directory to `dest`, keeping
+    // their paths relative to the root, and carries on from there. Files
+    // that were never created are skipped.
+    pub fn move_to(&mut self, dest: impl Into<PathBuf>) -> io::Result<()> {
+        let dest = dest.into();
+        for file in 0..self.layout.files().len() {
+            let from = self.path(file);
+            if !from.exists() {
+                continue;
+            }
+            let name = from.strip_prefix(&self.root).expect("files live under the root");
+            relocate::move_file(&from, &dest.join(name))?;
+            if let Some(parent) = from.parent() {
+                relocate::remove_empty_dirs(parent, &self.root);
+            }
+        }
+        self.root = dest;
+        Ok(())
+    }
+
+    // Creates every file at its full size, sparse or preallocated.
+    pub fn create_files(&self) -> io::Result<()> {
+        self.layout
+            .files()
+            .iter()
+            .enumerate()
+            .try_for_each(|(i, file)| allocate(&self.open_for_write(i)?, file.length, self.allocation))
+    }
+
+    pub fn write(&self, piece: u32, begin: u32, data: &[u8]) -> io::Result<()> {
+        let spans = self.layout
+            .piece_spans(piece, begin, data.len())
+            .ok_or_else(out_of_range)?;
+        let mut data = data;
+        for Span { file, offset, len } in spans {
+            let mut handle = self.open_for_write(file)?;
+            handle.seek(SeekFrom::Start(offset))?;
+            handle.write_all(&data[..len])?;
+            data = &data[len..];
+        }
+        Ok(())
+    }
+
+    // `write` for data in several buffers, such as cached blocks, which may
+    // run on past `piece` into the pieces after it. Each file's share goes
+    // to the disk in one vectored write rather than one write per buffer.
+    pub fn write_vectored(&self, piece: u32, begin: u32, buffers: &[&[u8]]) -> io::Result<()> {
+        let size = self.layout.geometry().piece_size(piece).ok_or_else(out_of_range)?;
+        if begin > size {
+            return Err(out_of_range());
+        }
+        let offset = self.layout.geometry().piece_offset(piece) + begin as u64;
+        let len = buffers.iter().map(|buffer| buffer.len()).sum();
+        let spans = self.layout.spans(offset, len).ok_or_else(out_of_range)?;
+        let mut buffers = buffers.iter().copied().filter(|buffer| !buffer.is_empty());
+        let mut current: &[u8] = &[];
+        for Span { file, offset, len } in spans {
+            let mut slices = Vec::new();
+            let mut left = len;
+            while left > 0 {
+                if current.is_empty() {
+                    current = buffers.next().expect("the spans cover the buffers");
+                }
+                let (share, rest) = current.split_at(left.min(current.len()));
+                slices.push(IoSlice::new(share));
+                current = rest;
+                left -= share.len();
+            }
+            write_all_at(&self.open_for_write(file)?, offset, &mut slices)?;
+        }
+        Ok(())
+    }
+
+    pub fn read(&self, piece: u32, begin: u32, len: usize) -> io::Result<Vec<u8>> {
+        let spans = self.layout
+            .piece_spans(piece, begin, len)
+            .ok_or_else(out_of_range)?;
+        self.read_spans(spans, len)
+    }
+
+    // Reads `len` bytes at `offset` into the torrent's content, but only if
+    // every piece they touch is in `verified`. A range that is not fully
+    // verified yet fails with `WouldBlock`, so a streaming reader can wait
+    // and try again.
+    pub fn read_range(&self, offset: u64, len: usize, verified: &Bitfield) -> io::Result<Vec<u8>> {
+        let spans = self.layout.spans(offset, len).ok_or_else(out_of_range)?;
+        if len > 0 {
+            let piece_length = self.layout.geometry().piece_length() as u64;
+            let first = offset / piece_length;
+            let last = (offset + len as u64 - 1) / piece_length;
+            if !(first..=last).all(|piece| verified.get(piece as usize)) {
+                return Err(io::Error::new(io::ErrorKind::WouldBlock, "range not verified yet"));
+            }
+        }
+        self.read_spans(spans, len)
+    }
+
+    fn read_spans(&self, spans: Vec<Span>, len: usize) -> io::Result<Vec<u8>> {
+        let mut data = vec![0; len];
+        let mut at = 0;
+        for Span { file, offset, len } in spans {
+            let mut handle = File::open(self.path(file))?;
+            handle.seek(SeekFrom::Start(offset))?;
+            handle.read_exact(&mut data[at..at + len])?;
+            at += len;
+        }
+        Ok(data)
+    }
+
+    pub fn read_piece(&self, piece: u32) -> io::Result<Vec<u8>> {
+        let size = self.layout
+            .geometry()
+            .piece_size(piece)
+            .ok_or_else(out_of_range)?;
+        self.read(piece, 0, size as usize)
+    }
+
+    // Whether the files the piece lives in exist and are long enough to
+    // hold it, which tells a missing piece from a corrupt one.
+    pub fn is_on_disk(&self, piece: u32) -> bool {
+        let Some(size) = self.layout.geometry().piece_size(piece) else {
+            return false;
+        };
+        let spans = self.layout.piece_spans(piece, 0, size as usize).unwrap_or_default();
+        spans.iter().all(|span| {
+            fs::metadata(self.path(span.file)).is_ok_and(|metadata| metadata.len() >= span.offset + span.len as u64)
+        })
+    }
+
+    // Flushes a file's data to the disk. Files not created yet are skipped.
+    fn sync_file(&self, file: usize) -> io::Result<()> {
+        match OpenOptions::new().write(true).open(self.path(file)) {
+            Ok(handle) => handle.sync_data(),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(err) => Err(err)
+        }
+    }
+
+    pub fn sync_piece(&self, piece: u32) -> io::Result<()> {
+        let size = self.layout
+            .geometry()
+            .piece_size(piece)
+            .ok_or_else(out_of_range)?;
+        let spans = self.layout
+            .piece_spans(piece, 0, size as usize)
+            .ok_or_else(out_of_range)?;
+        spans.iter().try_for_each(|span| self.sync_file(span.file))
+    }
+
+    pub fn sync_all(&self) -> io::Result<()> {
+        (0..self.layout.files().len()).try_for_each(|file| self.sync_file(file))
+    }
+}
+
+impl Storage for FileStorage {
+    fn layout(&self) -> &Layout {
+        &self.layout
+    }
+
+    fn read(&self, piece: u32, begin: u32, len: usize) -> io::Result<Vec<u8>> {
+        FileStorage::read(self, piece, begin, len)
+    }
+
+    fn write(&mut self, piece: u32, begin: u32, data: &[u8]) -> io::Result<()> {
+        FileStorage::write(self, piece, begin, data)
+    }
+
+    fn write_vectored(&mut self, piece: u32, begin: u32, buffers: &[&[u8]]) -> io::Result<()> {
+        FileStorage::write_vectored(self, piece, begin, buffers)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.sync_all()
+    }
+
+    fn sync_piece(&self, piece: u32) -> io::Result<()> {
+        FileStorage::sync_piece(self, piece)
+    }
+
+    fn sync_all(&self) -> io::Result<()> {
+        FileStorage::sync_all(self)
+    }
+
+    fn finish_complete_files(&self, have: &Bitfield) -> io::Result<Vec<usize>> {
+        FileStorage::finish_complete_files(self, have)
+    }
+
+    fn move_to(&mut self, dest: &Path) -> io::Result<()> {
+        FileStorage::move_to(self, dest)
+    }
+
+    fn root(&self) -> Option<&Path> {
+        Some(FileStorage::root(self))
+    }
+
+    fn file_stamps(&self) -> Vec<FileStamp> {
+        (0..self.layout.files().len())
+            .map(|file| FileStamp::of(self.path(file)))
+            .collect()
+    }
+}
+
+fn write_all_at(file: &File, mut offset: u64, mut slices: &mut [IoSlice]) -> io::Result<()> {
+    while !slices.is_empty() {
+        let batch = slices.len().min(MAX_SLICES);
+        match write_at(file, offset, &slices[..batch]) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(written) => {
+                offset += written as u64;
+                IoSlice::advance_slices(&mut slices, written);
+            },
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {},
+            Err(err) => return Err(err)
+        }
+    }
+    Ok(())
+}
+
+// One system call, with no seek before it.
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+fn write_at(file: &File, offset: u64, slices: &[IoSlice]) -> io::Result<usize> {
+    use std::os::unix::io::AsRawFd;
+
+    let offset = libc::off_t::try_from(offset).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "file too large"))?;
+    // SAFETY: `IoSlice` is guaranteed to be laid out as an `iovec` on Unix.
+    let written = unsafe { libc::pwritev(file.as_raw_fd(), slices.as_ptr().cast(), slices.len() as libc::c_int, offset) };
+    match written {
+        -1 => Err(io::Error::last_os_error()),
+        written => Ok(written as usize)
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd")))]
+fn write_at(mut file: &File, offset: u64, slices: &[IoSlice]) -> io::Result<usize> {
+    file.seek(SeekFrom::Start(offset))?;
+    file.write_vectored(slices)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::bitfield::Bitfield;
+    use crate::storage::layout::{FileEntry, Layout};
+    use crate::storage::file::FileStorage;
+    use std::fs;
+
+    #[test]
+    fn test_write_across_files() {
+        let root = std::env::temp_dir().join(format!("storage-{}", std::process::id()));
+        let files = vec![
+            FileEntry::new("dir/a", 5),
+            FileEntry::new("dir/empty", 0),
+            FileEntry::new("b", 10)
+        ];
+        let storage = FileStorage::new(&root, Layout::new(files, 4).unwrap());
+
+        storage.create_files().unwrap();
+        assert!(root.join("dir/empty").exists());
+        assert_eq!(fs::metadata(root.join("b")).unwrap().len(), 10);
+
+        storage.write(1, 0, b"efgh").unwrap();
+        storage.write(0, 0, b"abcd").unwrap();
+        storage.write(3, 0, b"mno").unwrap();
+        storage.write(2, 0, b"ijkl").unwrap();
+        assert!(storage.write(3, 2, b"xx").is_err());
+
+        assert_eq!(fs::read(root.join("dir/a")).unwrap(), b"abcde");
+        assert_eq!(fs::read(root.join("b")).unwrap(), b"fghijklmno");
+        assert_eq!(storage.read(1, 1, 3).unwrap(), b"fgh");
+        assert_eq!(storage.read_piece(3).unwrap(), b"mno");
+        assert!(storage.is_on_disk(3));
+        assert!(!FileStorage::new(root.join("elsewhere"), storage.layout().clone()).is_on_disk(0));
+
+        let mut verified = Bitfield::new(4);
+        verified.set(1);
+        verified.set(2);
+        assert_eq!(storage.read_range(5, 7, &verified).unwrap(), b"fghijkl");
+        let err = storage.read_range(3, 4, &verified).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::WouldBlock);
+        assert!(storage.read_range(14, 2, &Bitfield::full(4)).is_err());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_part_files() {
+        let root = std::env::temp_dir().join(format!("part-{}", std::process::id()));
+        let files = vec![FileEntry::new("a", 6), FileEntry::new("b", 2)];
+        let storage = FileStorage::new(&root, Layout::new(files, 4).unwrap()).with_part_files(true);
+
+        storage.write(0, 0, b"abcd").unwrap();
+        assert!(!storage.is_on_disk(1));
+        storage.write(1, 0, b"efgh").unwrap();
+        assert!(storage.is_on_disk(1));
+        assert!(root.join("a.part").exists());
+        assert!(!root.join("a").exists());
+        assert_eq!(storage.read(1, 0, 4).unwrap(), b"efgh");
+
+        let mut have = Bitfield::new(2);
+        have.set(0);
+        assert!(storage.finish_complete_files(&have).unwrap().is_empty());
+        have.set(1);
+        assert_eq!(storage.finish_complete_files(&have).unwrap(), vec![0, 1]);
+        assert_eq!(fs::read(root.join("a")).unwrap(), b"abcdef");
+        assert!(!root.join("b.part").exists());
+        assert_eq!(storage.read_piece(1).unwrap(), b"efgh");
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,127 @@
+use crate::bitfield::Bitfield;
+use crate::hash::crc32c;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+const RECORD_LEN: usize = 9;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JournalEntry {
+    Verified(u32),
+    // The piece must be downloaded again, such as after its file changed.
+    Cleared(u32)
+}
+
+impl JournalEntry {
+    // A tag, the piece index and a CRC32-C of both, so a record torn by a
+    // crash is recognised instead of misread.
+    fn encode(self) -> [u8; RECORD_LEN] {
+        let (tag, piece) = match self {
+            Self::Verified(piece) => (b'v', piece),
+            Self::Cleared(piece) => (b'c', piece)
+        };
+        let mut record = [0; RECORD_LEN];
+        record[0] = tag;
+        record[1..5].copy_from_slice(&piece.to_be_bytes());
+        let check = crc32c(&record[..5]);
+        record[5..].copy_from_slice(&check.to_be_bytes());
+        record
+    }
+
+    fn decode(record: &[u8]) -> Option<Self> {
+        if record.len() != RECORD_LEN || crc32c(&record[..5]).to_be_bytes() != record[5..] {
+            return None;
+        }
+        let piece = u32::from_be_bytes(record[1..5].try_into().unwrap());
+        match record[0] {
+            b'v' => Some(Self::Verified(piece)),
+            b'c' => Some(Self::Cleared(piece)),
+            _ => None
+        }
+    }
+}
+
+// Where the journal for the resume data at `resume_path` is kept.
+pub fn path_for(resume_path: &Path) -> PathBuf {
+    resume_path.with_extension("journal")
+}
+
+// An append-only log of piece state kept next to the resume file, covering
+// whatever happened since the resume data was last saved. A piece's data
+// must be synced before its `Verified` entry is appended, so a crash can
+// only lose the entry, never record a piece whose data did not make it.
+pub struct Journal {
+    file: File
+}
+
+impl Journal {
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self { file })
+    }
+
+    // Appends an entry and waits for it to reach the disk.
+    pub fn append(&mut self, entry: JournalEntry) -> io::Result<()> {
+        self.file.write_all(&entry.encode())?;
+        self.file.sync_data()
+    }
+
+    // Empties the journal once fresh resume data has been saved.
+    pub fn reset(&mut self) -> io::Result<()> {
+        self.file.set_len(0)?;
+        self.file.sync_all()
+    }
+
+    // Applies the journal at `path` to pieces loaded from the resume data,
+    // returning how many entries were applied. Reading stops at the first
+    // damaged record, which can only be the last one written before a crash.
+    pub fn replay(path: impl AsRef<Path>, pieces: &mut Bitfield) -> io::Result<usize> {
+        let bytes = match fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(0),
+            Err(err) => return Err(err)
+        };
+
+        let mut applied = 0;
+        for entry in bytes.chunks(RECORD_LEN).map_while(JournalEntry::decode) {
+            match entry {
+                JournalEntry::Verified(piece) => {
+                    pieces.set(piece as usize);
+                },
+                JournalEntry::Cleared(piece) => pieces.clear(piece as usize)
+            }
+            applied += 1;
+        }
+        Ok(applied)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::bitfield::Bitfield;
+    use crate::storage::journal::{Journal, JournalEntry};
+    use std::fs::{self, OpenOptions};
+    use std::io::Write;
+
+    #[test]
+    fn test_replay() {
+        let path = std::env::temp_dir().join(format!("journal-{}", std::process::id()));
+        let mut journal = Journal::open(&path).unwrap();
+        journal.append(JournalEntry::Verified(0)).unwrap();
+        journal.append(JournalEntry::Verified(2)).unwrap();
+        journal.append(JournalEntry::Cleared(0)).unwrap();
+        // Half a record, as a crash in the middle of a write would leave.
+        OpenOptions::new().append(true).open(&path).unwrap().write_all(&[b'v', 0, 0]).unwrap();
+
+        let mut pieces = Bitfield::new(4);
+        pieces.set(0);
+        assert_eq!(Journal::replay(&path, &mut pieces).unwrap(), 3);
+        assert_eq!(pieces.ones().collect::<Vec<_>>(), vec![2]);
+
+        journal.reset().unwrap();
+        assert_eq!(Journal::replay(&path, &mut pieces).unwrap(), 0);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(Journal::replay(&path, &mut pieces).unwrap(), 0);
+    }
+}
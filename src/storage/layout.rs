@@ -0,0 +1,156 @@
+use crate::bitfield::Bitfield;
+use crate::piece::PieceGeometry;
+use std::ops::Range;
+use std::path::PathBuf;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileEntry {
+    // Relative to the download directory.
+    pub path: PathBuf,
+    pub length: u64
+}
+
+impl FileEntry {
+    pub fn new(path: impl Into<PathBuf>, length: u64) -> Self {
+        Self { path: path.into(), length }
+    }
+}
+
+// Part of a range of torrent data that falls inside one file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub file: usize,
+    pub offset: u64,
+    pub len: usize
+}
+
+// The files of a torrent laid end to end, as the pieces see them.
+#[derive(Debug, Clone)]
+pub struct Layout {
+    files: Vec<FileEntry>,
+    starts: Vec<u64>,
+    geometry: PieceGeometry
+}
+
+impl Layout {
+    pub fn new(files: Vec<FileEntry>, piece_length: u32) -> Option<Self> {
+        let starts: Vec<_> = files
+            .iter()
+            .scan(0, |offset, file| {
+                let start = *offset;
+                *offset += file.length;
+                Some(start)
+            })
+            .collect();
+        let total = files.iter().map(|file| file.length).sum();
+        Some(Self { files, starts, geometry: PieceGeometry::new(piece_length, total)? })
+    }
+
+    pub fn files(&self) -> &[FileEntry] {
+        &self.files
+    }
+
+    pub fn geometry(&self) -> &PieceGeometry {
+        &self.geometry
+    }
+
+    pub fn file_offset(&self, file: usize) -> u64 {
+        self.starts[file]
+    }
+
+    // Splits `len` bytes at `offset` into the files they belong to. Empty
+    // files never show up. `None` if the range runs past the end.
+    pub fn spans(&self, offset: u64, len: usize) -> Option<Vec<Span>> {
+        let end = offset.checked_add(len as u64)?;
+        if end > self.geometry.total_length() {
+            return None;
+        }
+
+        let first = self.starts.partition_point(|&start| start <= offset).saturating_sub(1);
+        let spans = (first..self.files.len())
+            .take_while(|&file| self.starts[file] < end)
+            .filter_map(|file| {
+                let start = self.starts[file].max(offset);
+                let stop = (self.starts[file] + self.files[file].length).min(end);
+                (stop > start).then(|| Span { file, offset: start - self.starts[file], len: (stop - start) as usize })
+            })
+            .collect();
+        Some(spans)
+    }
+
+    pub fn piece_spans(&self, piece: u32, begin: u32, len: usize) -> Option<Vec<Span>> {
+        let size = self.geometry.piece_size(piece)?;
+        if begin as u64 + len as u64 > size as u64 {
+            return None;
+        }
+        self.spans(self.geometry.piece_offset(piece) + begin as u64, len)
+    }
+
+    // The pieces that hold some of the file's data.
+    pub fn file_pieces(&self, file: usize) -> Range<u32> {
+        let length = self.files[file].length;
+        if length == 0 {
+            return 0..0;
+        }
+        let piece_length = self.geometry.piece_length() as u64;
+        let start = self.starts[file];
+        (start / piece_length) as u32..((start + length - 1) / piece_length + 1) as u32
+    }
+
+    // How much of each file the pieces in `have` cover, as a percentage.
+    // Empty files count as complete.
+    pub fn file_percents(&self, have: &Bitfield) -> Vec<f64> {
+        (0..self.files.len())
+            .map(|file| {
+                let pieces = self.file_pieces(file);
+                match pieces.len() {
+                    0 => 100.0,
+                    len => pieces.filter(|&piece| have.get(piece as usize)).count() as f64 * 100.0 / len as f64
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::bitfield::Bitfield;
+    use crate::storage::layout::{FileEntry, Layout, Span};
+
+    fn layout() -> Layout {
+        let files = vec![FileEntry::new("a", 5), FileEntry::new("empty", 0), FileEntry::new("b", 10)];
+        Layout::new(files, 4).unwrap()
+    }
+
+    #[test]
+    fn test_spans() {
+        let layout = layout();
+        assert_eq!(layout.geometry().num_pieces(), 4);
+        assert_eq!(layout.file_offset(2), 5);
+
+        assert_eq!(layout.piece_spans(0, 0, 4), Some(vec![Span { file: 0, offset: 0, len: 4 }]));
+        assert_eq!(
+            layout.piece_spans(1, 0, 4),
+            Some(vec![Span { file: 0, offset: 4, len: 1 }, Span { file: 2, offset: 0, len: 3 }])
+        );
+        assert_eq!(layout.piece_spans(3, 0, 3), Some(vec![Span { file: 2, offset: 7, len: 3 }]));
+        assert_eq!(layout.piece_spans(3, 0, 4), None);
+        assert_eq!(layout.spans(14, 2), None);
+    }
+
+    #[test]
+    fn test_file_pieces() {
+        let layout = layout();
+        assert_eq!(layout.file_pieces(0), 0..2);
+        assert_eq!(layout.file_pieces(1), 0..0);
+        assert_eq!(layout.file_pieces(2), 1..4);
+    }
+
+    #[test]
+    fn test_file_percents() {
+        let mut have = Bitfield::new(4);
+        have.set(0);
+        have.set(2);
+        assert_eq!(layout().file_percents(&have), vec![50.0, 100.0, 100.0 / 3.0]);
+    }
+}
@@ -0,0 +1,106 @@
+use crate::storage::layout::Layout;
+use crate::storage::{out_of_range, Storage};
+use std::collections::BTreeMap;
+use std::io;
+
+// Keeps pieces in memory, for tests and benchmarks or for downloading
+// straight into a pipe. Each piece is allocated when first written, and
+// pieces already consumed can be taken out to free their memory. Unwritten
+// ranges read as zeros, like holes in a sparse file.
+pub struct MemoryStorage {
+    layout: Layout,
+    pieces: BTreeMap<u32, Vec<u8>>,
+    used: usize,
+    limit: Option<usize>
+}
+
+impl MemoryStorage {
+    pub fn new(layout: Layout) -> Self {
+        Self { layout, pieces: BTreeMap::new(), used: 0, limit: None }
+    }
+
+    // Refuses writes that would need more than `limit` bytes of pieces.
+    pub fn with_limit(mut self, limit: usize) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    // Bytes held right now.
+    pub fn used(&self) -> usize {
+        self.used
+    }
+
+    // Removes a piece, such as once it has been passed on.
+    pub fn take_piece(&mut self, piece: u32) -> Option<Vec<u8>> {
+        let data = self.pieces.remove(&piece)?;
+        self.used -= data.len();
+        Some(data)
+    }
+
+    fn piece_size(&self, piece: u32) -> io::Result<usize> {
+        self.layout
+            .geometry()
+            .piece_size(piece)
+            .map(|size| size as usize)
+            .ok_or_else(out_of_range)
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn layout(&self) -> &Layout {
+        &self.layout
+    }
+
+    fn read(&self, piece: u32, begin: u32, len: usize) -> io::Result<Vec<u8>> {
+        let begin = begin as usize;
+        if begin + len > self.piece_size(piece)? {
+            return Err(out_of_range());
+        }
+        Ok(match self.pieces.get(&piece) {
+            Some(data) => data[begin..begin + len].to_vec(),
+            None => vec![0; len]
+        })
+    }
+
+    fn write(&mut self, piece: u32, begin: u32, data: &[u8]) -> io::Result<()> {
+        let size = self.piece_size(piece)?;
+        let begin = begin as usize;
+        if begin + data.len() > size {
+            return Err(out_of_range());
+        }
+        if !self.pieces.contains_key(&piece) {
+            if self.limit.is_some_and(|limit| self.used + size > limit) {
+                return Err(io::Error::new(io::ErrorKind::StorageFull, "memory storage is full"));
+            }
+            self.used += size;
+        }
+        self.pieces.entry(piece).or_insert_with(|| vec![0; size])[begin..begin + data.len()].copy_from_slice(data);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::storage::layout::{FileEntry, Layout};
+    use crate::storage::memory::MemoryStorage;
+    use crate::storage::Storage;
+
+    #[test]
+    fn test_memory_storage() {
+        let layout = Layout::new(vec![FileEntry::new("a", 5), FileEntry::new("b", 5)], 4).unwrap();
+        let mut storage = MemoryStorage::new(layout).with_limit(8);
+
+        storage.write(0, 0, b"abcd").unwrap();
+        storage.write(2, 1, b"j").unwrap();
+        assert_eq!(storage.used(), 6);
+        assert_eq!(storage.read_piece(2).unwrap(), b"\0j");
+        assert_eq!(storage.read(1, 0, 2).unwrap(), b"\0\0");
+        assert!(storage.write(2, 1, b"jk").is_err());
+
+        let err = storage.write(1, 0, b"efgh").unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::StorageFull);
+        assert_eq!(storage.take_piece(0).unwrap(), b"abcd");
+        storage.write(1, 0, b"efgh").unwrap();
+        assert_eq!(storage.used(), 6);
+    }
+}
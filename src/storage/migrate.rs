@@ -0,0 +1,213 @@
+use crate::bencode::{self, Value};
+use crate::bitfield::Bitfield;
+use crate::metainfo::Metainfo;
+use crate::storage::fastresume::FastResume;
+use std::ffi::OsStr;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+// Transmission tracks progress in blocks of its own, always this size.
+const TRANSMISSION_BLOCK: u64 = 16 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Client {
+    // `BT_backup`: `<info hash>.torrent` beside `<info hash>.fastresume`.
+    QBittorrent,
+    // Its config directory, with `torrents/<name>.torrent` and
+    // `resume/<name>.resume`.
+    Transmission
+}
+
+impl Client {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::QBittorrent => "qBittorrent",
+            Self::Transmission => "Transmission"
+        }
+    }
+
+    // Its resume data for `metainfo`, as libtorrent's.
+    pub fn read_resume(self, bytes: &[u8], metainfo: &Metainfo) -> Option<FastResume> {
+        match self {
+            Self::QBittorrent => FastResume::decode(bytes).filter(|fast| fast.info_hash == metainfo.info_hash),
+            Self::Transmission => transmission(bytes, metainfo)
+        }
+    }
+}
+
+// A torrent another client has, and its resume data if it kept any.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Found {
+    pub torrent: PathBuf,
+    pub resume: Option<PathBuf>
+}
+
+// The torrents in another client's state directory, which is told apart
+// by its layout.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Migration {
+    pub client: Client,
+    pub torrents: Vec<Found>
+}
+
+fn with_extension(dir: &Path, extension: &str) -> io::Result<Vec<PathBuf>> {
+    let mut paths: Vec<_> = fs::read_dir(dir)?
+        .filter_map(|entry| Some(entry.ok()?.path()))
+        .filter(|path| path.extension() == Some(OsStr::new(extension)))
+        .collect();
+    paths.sort();
+    Ok(paths)
+}
+
+fn found(torrents: Vec<PathBuf>, resume_dir: &Path, extension: &str) -> Vec<Found> {
+    torrents
+        .into_iter()
+        .map(|torrent| {
+            let mut name = torrent.file_stem().unwrap_or_default().to_os_string();
+            name.push(".");
+            name.push(extension);
+            let resume = resume_dir.join(name);
+            Found { resume: resume.is_file().then_some(resume), torrent }
+        })
+        .collect()
+}
+
+impl Migration {
+    // Transmission's config directory may be given, or the `torrents`
+    // directory in it.
+    pub fn scan(dir: impl AsRef<Path>) -> io::Result<Self> {
+        let dir = dir.as_ref();
+        let config = match dir.file_name() == Some(OsStr::new("torrents")) {
+            true => dir.parent().unwrap_or(dir),
+            false => dir
+        };
+        if config.join("torrents").is_dir() && config.join("resume").is_dir() {
+            let torrents = with_extension(&config.join("torrents"), "torrent")?;
+            return Ok(Self { client: Client::Transmission, torrents: found(torrents, &config.join("resume"), "resume") });
+        }
+        let torrents = with_extension(dir, "torrent")?;
+        if torrents.is_empty() || with_extension(dir, "fastresume")?.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "neither a qBittorrent BT_backup nor a Transmission directory"));
+        }
+        Ok(Self { client: Client::QBittorrent, torrents: found(torrents, dir, "fastresume") })
+    }
+}
+
+// The pieces all of whose blocks are set in `blocks`.
+fn pieces_of_blocks(blocks: &[u8], metainfo: &Metainfo) -> Bitfield {
+    let block = |index: u64| blocks.get((index / 8) as usize).is_some_and(|byte| byte & (0x80 >> (index % 8)) != 0);
+    let (piece_length, total) = (metainfo.piece_length as u64, metainfo.total_length());
+    let mut have = Bitfield::new(metainfo.num_pieces());
+    for piece in 0..have.len() as u64 {
+        let end = ((piece + 1) * piece_length).min(total);
+        if (piece * piece_length / TRANSMISSION_BLOCK..end.div_ceil(TRANSMISSION_BLOCK)).all(block) {
+            have.set(piece as usize);
+        }
+    }
+    have
+}
+
+// Transmission's `.resume`: where the data is and what of it was checked,
+// as a bitfield of pieces (3.0 and later) or of blocks, or "all"/"none".
+fn transmission(bytes: &[u8], metainfo: &Metainfo) -> Option<FastResume> {
+    let value = bencode::decode(bytes)?;
+    let progress = value.get("progress")?;
+    let num_pieces = metainfo.num_pieces();
+    let everything = |key: &str| progress.get(key).and_then(Value::as_str) == Some("all");
+    let pieces = match (progress.get("pieces").and_then(Value::as_bytes), progress.get("blocks").or(progress.get("bitfield"))) {
+        _ if everything("pieces") || everything("blocks") || everything("have") => Bitfield::full(num_pieces),
+        (Some(pieces), _) if pieces != b"none" => Bitfield::from_bytes(pieces, num_pieces).unwrap_or(Bitfield::new(num_pieces)),
+        (_, Some(blocks)) if blocks.as_str() != Some("none") => pieces_of_blocks(blocks.as_bytes()?, metainfo),
+        _ => Bitfield::new(num_pieces)
+    };
+    let uint = |key: &str| value.get(key).and_then(Value::as_int).and_then(|value| u64::try_from(value).ok());
+    Some(FastResume {
+        info_hash: metainfo.info_hash,
+        pieces,
+        seed_mode: false,
+        files: None,
+        uploaded: uint("uploaded").unwrap_or(0),
+        downloaded: uint("downloaded").unwrap_or(0),
+        trackers: Vec::new(),
+        save_path: value.get("destination").and_then(Value::as_str).map(PathBuf::from),
+        name: value.get("name").and_then(Value::as_str).map(str::to_string),
+        paused: uint("paused").is_some_and(|paused| paused != 0)
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use crate::bencode::Value;
+    use crate::hash::sha1;
+    use crate::metainfo::Metainfo;
+    use crate::storage::migrate::{Client, Found, Migration};
+    use std::fs;
+    use std::path::PathBuf;
+
+    fn metainfo() -> Metainfo {
+        // Three pieces of two blocks, the last of one.
+        let pieces: Vec<u8> = (0..3).flat_map(|piece| sha1(&[piece])).collect();
+        let info = Value::dict([
+            ("name", "a".into()),
+            ("length", (80 * 1024).into()),
+            ("piece length", (32 * 1024).into()),
+            ("pieces", pieces.into())
+        ]);
+        Metainfo::from_bytes(&Value::dict([("info", info)]).encode()).unwrap()
+    }
+
+    #[test]
+    fn test_transmission() {
+        let metainfo = metainfo();
+        let resume = |progress: Vec<(&'static str, Value)>| {
+            let bytes = Value::dict([
+                ("destination", "/downloads".into()),
+                ("uploaded", 9.into()),
+                ("paused", 1.into()),
+                ("progress", Value::dict(progress))
+            ])
+            .encode();
+            Client::Transmission.read_resume(&bytes, &metainfo).unwrap()
+        };
+        let fast = resume(vec![("pieces", Value::from(&[0b1010_0000][..]))]);
+        assert_eq!(fast.pieces.ones().collect::<Vec<_>>(), [0, 2]);
+        assert_eq!((fast.save_path, fast.uploaded, fast.paused), (Some(PathBuf::from("/downloads")), 9, true));
+        assert_eq!(fast.info_hash, metainfo.info_hash);
+        // Blocks 0, 1 and 4 of five.
+        assert_eq!(resume(vec![("blocks", Value::from(&[0b1100_1000][..]))]).pieces.ones().collect::<Vec<_>>(), [0, 2]);
+        assert!(resume(vec![("have", "all".into())]).pieces.is_complete());
+        assert_eq!(resume(vec![("blocks", "none".into())]).pieces.count_ones(), 0);
+    }
+
+    #[test]
+    fn test_scan() {
+        let root = std::env::temp_dir().join(format!("migrate-{}", std::process::id()));
+        let (torrents, resume) = (root.join("torrents"), root.join("resume"));
+        fs::create_dir_all(&torrents).unwrap();
+        fs::create_dir_all(&resume).unwrap();
+        fs::write(torrents.join("a.1234.torrent"), b"").unwrap();
+        fs::write(torrents.join("b.torrent"), b"").unwrap();
+        fs::write(resume.join("a.1234.resume"), b"").unwrap();
+        let expected = Migration {
+            client: Client::Transmission,
+            torrents: vec![
+                Found { torrent: torrents.join("a.1234.torrent"), resume: Some(resume.join("a.1234.resume")) },
+                Found { torrent: torrents.join("b.torrent"), resume: None }
+            ]
+        };
+        assert_eq!(Migration::scan(&root).unwrap(), expected);
+        assert_eq!(Migration::scan(&torrents).unwrap(), expected);
+
+        fs::write(resume.join("c.torrent"), b"").unwrap();
+        fs::write(resume.join("c.fastresume"), b"").unwrap();
+        let expected = Migration {
+            client: Client::QBittorrent,
+            torrents: vec![Found { torrent: resume.join("c.torrent"), resume: Some(resume.join("c.fastresume")) }]
+        };
+        assert_eq!(Migration::scan(&resume).unwrap(), expected);
+        assert!(Migration::scan(root.join("missing")).is_err());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,141 @@
+use crate::storage::allocate::{allocate, Allocation};
+use crate::storage::layout::{Layout, Span};
+use crate::storage::{out_of_range, Storage};
+use memmap2::MmapMut;
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+
+// Storage that maps every file into memory up front, so writing a block is
+// a copy into the mapping instead of a seek and a write call. The kernel
+// writes dirty pages back on its own schedule, or on `flush`.
+pub struct MmapStorage {
+    root: PathBuf,
+    layout: Layout,
+    // `None` for empty files, which cannot be mapped.
+    maps: Vec<Option<MmapMut>>
+}
+
+impl MmapStorage {
+    pub fn open(root: impl Into<PathBuf>, layout: Layout, allocation: Allocation) -> io::Result<Self> {
+        let root = root.into();
+        let mut maps = Vec::new();
+        for entry in layout.files() {
+            let path = root.join(&entry.path);
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            let file = OpenOptions::new().create(true).truncate(false).read(true).write(true).open(path)?;
+            allocate(&file, entry.length, allocation)?;
+            // Safe as long as nothing else truncates the file while it is
+            // mapped; the files belong to this torrent.
+            maps.push(match entry.length {
+                0 => None,
+                _ => Some(unsafe { MmapMut::map_mut(&file)? })
+            });
+        }
+        Ok(Self { root, layout, maps })
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn layout(&self) -> &Layout {
+        &self.layout
+    }
+
+    fn map(&mut self, file: usize) -> &mut MmapMut {
+        self.maps[file].as_mut().expect("spans never cover empty files")
+    }
+
+    pub fn write(&mut self, piece: u32, begin: u32, data: &[u8]) -> io::Result<()> {
+        let spans = self.layout
+            .piece_spans(piece, begin, data.len())
+            .ok_or_else(out_of_range)?;
+        let mut data = data;
+        for Span { file, offset, len } in spans {
+            let offset = offset as usize;
+            self.map(file)[offset..offset + len].copy_from_slice(&data[..len]);
+            data = &data[len..];
+        }
+        Ok(())
+    }
+
+    pub fn read(&self, piece: u32, begin: u32, len: usize) -> io::Result<Vec<u8>> {
+        let spans = self.layout
+            .piece_spans(piece, begin, len)
+            .ok_or_else(out_of_range)?;
+        let mut data = Vec::with_capacity(len);
+        for Span { file, offset, len } in spans {
+            let offset = offset as usize;
+            let map = self.maps[file].as_ref().expect("spans never cover empty files");
+            data.extend_from_slice(&map[offset..offset + len]);
+        }
+        Ok(data)
+    }
+
+    pub fn read_piece(&self, piece: u32) -> io::Result<Vec<u8>> {
+        let size = self.layout
+            .geometry()
+            .piece_size(piece)
+            .ok_or_else(out_of_range)?;
+        self.read(piece, 0, size as usize)
+    }
+
+    pub fn flush(&self) -> io::Result<()> {
+        self.maps
+            .iter()
+            .flatten()
+            .try_for_each(MmapMut::flush)
+    }
+}
+
+impl Storage for MmapStorage {
+    fn layout(&self) -> &Layout {
+        &self.layout
+    }
+
+    fn read(&self, piece: u32, begin: u32, len: usize) -> io::Result<Vec<u8>> {
+        MmapStorage::read(self, piece, begin, len)
+    }
+
+    fn write(&mut self, piece: u32, begin: u32, data: &[u8]) -> io::Result<()> {
+        MmapStorage::write(self, piece, begin, data)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        MmapStorage::flush(self)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::storage::allocate::Allocation;
+    use crate::storage::layout::{FileEntry, Layout};
+    use crate::storage::mmap::MmapStorage;
+    use std::fs;
+
+    #[test]
+    fn test_write_across_files() {
+        let root = std::env::temp_dir().join(format!("mmap-{}", std::process::id()));
+        let files = vec![FileEntry::new("dir/a", 5), FileEntry::new("empty", 0), FileEntry::new("b", 10)];
+        let mut storage = MmapStorage::open(&root, Layout::new(files, 4).unwrap(), Allocation::Sparse).unwrap();
+
+        storage.write(3, 0, b"mno").unwrap();
+        storage.write(1, 0, b"efgh").unwrap();
+        storage.write(0, 0, b"abcd").unwrap();
+        storage.write(2, 0, b"ijkl").unwrap();
+        assert!(storage.write(3, 2, b"xx").is_err());
+        storage.flush().unwrap();
+
+        assert_eq!(fs::read(root.join("dir/a")).unwrap(), b"abcde");
+        assert_eq!(fs::read(root.join("b")).unwrap(), b"fghijklmno");
+        assert!(root.join("empty").exists());
+        assert_eq!(storage.read(1, 1, 3).unwrap(), b"fgh");
+        assert_eq!(storage.read_piece(3).unwrap(), b"mno");
+
+        drop(storage);
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,123 @@
+pub mod allocate;
+pub mod cache;
+pub mod durability;
+pub mod fastresume;
+pub mod file;
+pub mod journal;
+pub mod layout;
+pub mod memory;
+pub mod migrate;
+pub mod mmap;
+pub mod relocate;
+pub mod resume;
+pub mod sanitize;
+pub mod selection;
+pub mod space;
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+pub mod uring;
+
+pub use file::{FileStorage, PART_SUFFIX};
+
+use crate::bitfield::Bitfield;
+use crate::hash::PieceHash;
+use crate::storage::layout::Layout;
+use crate::storage::resume::FileStamp;
+use std::io;
+use std::path::Path;
+
+pub(crate) fn out_of_range() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, "range outside the torrent")
+}
+
+// Where a torrent's pieces live. `FileStorage` keeps them in the torrent's
+// files; other backends (in memory, encrypted, remote) only need to store
+// and return bytes by piece and offset.
+pub trait Storage {
+    fn layout(&self) -> &Layout;
+
+    fn read(&self, piece: u32, begin: u32, len: usize) -> io::Result<Vec<u8>>;
+
+    fn write(&mut self, piece: u32, begin: u32, data: &[u8]) -> io::Result<()>;
+
+    // Writes `buffers` one after another from `begin`, running on into the
+    // pieces after `piece` if they're long enough to. Backends that can
+    // write them all at once, as `FileStorage` does, override this.
+    fn write_vectored(&mut self, mut piece: u32, mut begin: u32, buffers: &[&[u8]]) -> io::Result<()> {
+        for mut buffer in buffers.iter().copied() {
+            while !buffer.is_empty() {
+                let size = self.layout().geometry().piece_size(piece).ok_or_else(out_of_range)?;
+                if begin == size {
+                    (piece, begin) = (piece + 1, 0);
+                    continue;
+                }
+                let (share, rest) = buffer.split_at(buffer.len().min((size - begin) as usize));
+                self.write(piece, begin, share)?;
+                begin += share.len() as u32;
+                buffer = rest;
+            }
+        }
+        Ok(())
+    }
+
+    // Makes written data durable, for backends that buffer it.
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    // Total bytes of content.
+    fn size(&self) -> u64 {
+        self.layout().geometry().total_length()
+    }
+
+    fn read_piece(&self, piece: u32) -> io::Result<Vec<u8>> {
+        let size = self.layout()
+            .geometry()
+            .piece_size(piece)
+            .ok_or_else(out_of_range)?;
+        self.read(piece, 0, size as usize)
+    }
+
+    // Backends that can check a piece without reading it back, such as one
+    // that hashed it on the way in, can override this.
+    fn verify_piece(&self, piece: u32, expected: &PieceHash) -> io::Result<bool> {
+        Ok(expected.matches(&self.read_piece(piece)?))
+    }
+
+    // Called once a piece has passed its hash check.
+    fn piece_verified(&mut self, _piece: u32) -> io::Result<()> {
+        Ok(())
+    }
+
+    // Force written data out to the disk, as a `SyncPolicy` asks. Backends
+    // with no files have nothing to sync.
+    fn sync_piece(&self, _piece: u32) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn sync_all(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    // Called with every piece verified so far, for backends that see to
+    // files as they complete. Returns the files seen to just now.
+    fn finish_complete_files(&self, _have: &Bitfield) -> io::Result<Vec<usize>> {
+        Ok(Vec::new())
+    }
+
+    // Moves the content under `dest`, for backends that keep it in files.
+    fn move_to(&mut self, _dest: &Path) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "the storage has no files to move"))
+    }
+
+    // Where the backend keeps its files, so the space left there can be
+    // watched. Backends without files have no root.
+    fn root(&self) -> Option<&Path> {
+        None
+    }
+
+    // How the backend's files look on disk, so resume data can tell when
+    // they were changed behind our back. Backends without files have none.
+    fn file_stamps(&self) -> Vec<FileStamp> {
+        Vec::new()
+    }
+}
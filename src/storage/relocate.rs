@@ -0,0 +1,114 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read};
+use std::path::Path;
+use tracing::debug;
+
+const CHUNK: usize = 1024 * 1024;
+
+fn same_contents(a: &Path, b: &Path) -> io::Result<bool> {
+    let (mut a, mut b) = (File::open(a)?, File::open(b)?);
+    if a.metadata()?.len() != b.metadata()?.len() {
+        return Ok(false);
+    }
+    let (mut left, mut right) = (vec![0; CHUNK], vec![0; CHUNK]);
+    loop {
+        let read = a.read(&mut left)?;
+        if read == 0 {
+            return Ok(true);
+        }
+        b.read_exact(&mut right[..read])?;
+        if left[..read] != right[..read] {
+            return Ok(false);
+        }
+    }
+}
+
+// Copies `from` next to `to`, syncs and compares the copy, and only then
+// gives it its final name and removes the original. For moves between
+// filesystems, where a rename is impossible.
+pub fn copy_verified(from: &Path, to: &Path) -> io::Result<()> {
+    let mut tmp = to.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = Path::new(&tmp);
+
+    fs::copy(from, tmp)?;
+    OpenOptions::new().write(true).open(tmp)?.sync_all()?;
+    if !same_contents(from, tmp)? {
+        let _ = fs::remove_file(tmp);
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "copy does not match the original"));
+    }
+    fs::rename(tmp, to)?;
+    fs::remove_file(from)
+}
+
+// Moves a file, creating the destination's directories. A rename is atomic,
+// so other programs see either nothing or the whole file; across
+// filesystems it falls back to `copy_verified`.
+pub fn move_file(from: &Path, to: &Path) -> io::Result<()> {
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    match fs::rename(from, to) {
+        Err(err) if err.kind() == io::ErrorKind::CrossesDevices => {
+            debug!(from = %from.display(), to = %to.display(), "copying across filesystems");
+            copy_verified(from, to)
+        },
+        result => result
+    }
+}
+
+// Removes `dir` and its parents up to, but not including, `root` for as
+// long as they are empty.
+pub fn remove_empty_dirs(dir: &Path, root: &Path) {
+    let mut dir = Some(dir);
+    while let Some(current) = dir.filter(|current| current.starts_with(root) && *current != root) {
+        if fs::remove_dir(current).is_err() {
+            break;
+        }
+        dir = current.parent();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::storage::layout::{FileEntry, Layout};
+    use crate::storage::relocate::{copy_verified, move_file, remove_empty_dirs};
+    use crate::storage::FileStorage;
+    use std::fs;
+
+    #[test]
+    fn test_move() {
+        let root = std::env::temp_dir().join(format!("relocate-{}", std::process::id()));
+        fs::create_dir_all(root.join("staging/album")).unwrap();
+        fs::write(root.join("staging/album/a"), b"abc").unwrap();
+        fs::write(root.join("staging/album/b"), b"def").unwrap();
+
+        move_file(&root.join("staging/album/a"), &root.join("done/album/a")).unwrap();
+        copy_verified(&root.join("staging/album/b"), &root.join("done/album/b")).unwrap();
+        remove_empty_dirs(&root.join("staging/album"), &root.join("staging"));
+
+        assert_eq!(fs::read(root.join("done/album/a")).unwrap(), b"abc");
+        assert_eq!(fs::read(root.join("done/album/b")).unwrap(), b"def");
+        assert!(!root.join("done/album/b.tmp").exists());
+        assert!(!root.join("staging/album").exists());
+        assert!(root.join("staging").exists());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_storage_move_to() {
+        let root = std::env::temp_dir().join(format!("move-to-{}", std::process::id()));
+        let files = vec![FileEntry::new("album/a", 4), FileEntry::new("album/b", 4)];
+        let mut storage = FileStorage::new(root.join("staging"), Layout::new(files, 4).unwrap()).with_part_files(true);
+        storage.write(0, 0, b"abcd").unwrap();
+
+        storage.move_to(root.join("done")).unwrap();
+        assert_eq!(storage.root(), root.join("done"));
+        assert_eq!(fs::read(root.join("done/album/a.part")).unwrap(), b"abcd");
+        assert!(!root.join("staging/album").exists());
+        assert_eq!(storage.read_piece(0).unwrap(), b"abcd");
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,251 @@
+use crate::bencode::{self, Value};
+use crate::bitfield::Bitfield;
+use crate::storage::{FileStorage, Storage};
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+// A file's size and modification time (seconds since the epoch) as they
+// were when the resume data was saved. A missing file has neither.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileStamp {
+    pub length: u64,
+    pub modified: Option<u64>
+}
+
+impl FileStamp {
+    pub fn of(path: impl AsRef<Path>) -> Self {
+        match fs::metadata(path) {
+            Ok(metadata) => Self {
+                length: metadata.len(),
+                modified: metadata
+                    .modified()
+                    .ok()
+                    .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+                    .map(|modified| modified.as_secs())
+            },
+            Err(_) => Self { length: 0, modified: None }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrackerState {
+    pub url: String,
+    // The `tracker id` the tracker asked us to send back.
+    pub tracker_id: Option<Vec<u8>>,
+    // Whether the `completed` event has been sent.
+    pub completed_sent: bool
+}
+
+// What a torrent needs to pick up where it left off without rehashing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResumeData {
+    pub info_hash: [u8; 20],
+    pub pieces: Bitfield,
+    pub files: Vec<FileStamp>,
+    pub uploaded: u64,
+    pub downloaded: u64,
+    pub trackers: Vec<TrackerState>,
+    // The files saved other than where the torrent says, by index, as
+    // `sanitize` renames them for Windows.
+    pub renamed: Vec<(usize, PathBuf)>
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+fn int(value: u64) -> Value {
+    Value::Integer(value as i64)
+}
+
+fn uint(value: &Value, key: &str) -> Option<u64> {
+    u64::try_from(value.get(key)?.as_int()?).ok()
+}
+
+impl ResumeData {
+    // Stamps the files as they are on disk right now.
+    pub fn capture<S: Storage + ?Sized>(info_hash: [u8; 20], storage: &S, pieces: &Bitfield) -> Self {
+        let files = storage.file_stamps();
+        Self { info_hash, pieces: pieces.clone(), files, uploaded: 0, downloaded: 0, trackers: Vec::new(), renamed: Vec::new() }
+    }
+
+    // The saved pieces that can still be trusted: any piece touching a file
+    // whose size or modification time changed since, or that was saved
+    // under another name than it would be now, is dropped.
+    pub fn verified_pieces(&self, storage: &FileStorage) -> Bitfield {
+        let layout = storage.layout();
+        let mut pieces = self.pieces.clone();
+        if pieces.len() != layout.geometry().num_pieces() as usize || self.files.len() != layout.files().len() {
+            return Bitfield::new(layout.geometry().num_pieces() as usize);
+        }
+
+        let moved = |file: usize| self.renamed.iter().any(|(index, path)| *index == file && layout.files()[file].path != *path);
+        for (file, stamp) in self.files.iter().enumerate() {
+            if FileStamp::of(storage.path(file)) != *stamp || moved(file) {
+                layout.file_pieces(file).for_each(|piece| pieces.clear(piece as usize));
+            }
+        }
+        pieces
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let files = self.files
+            .iter()
+            .map(|stamp| {
+                let mut fields = vec![("length", int(stamp.length))];
+                if let Some(modified) = stamp.modified {
+                    fields.push(("mtime", int(modified)));
+                }
+                Value::dict(fields)
+            })
+            .collect::<Vec<_>>();
+        let trackers = self.trackers
+            .iter()
+            .map(|tracker| {
+                let mut fields = vec![
+                    ("url", Value::from(tracker.url.as_str())),
+                    ("completed", int(tracker.completed_sent as u64))
+                ];
+                if let Some(id) = &tracker.tracker_id {
+                    fields.push(("tracker id", Value::from(&id[..])));
+                }
+                Value::dict(fields)
+            })
+            .collect::<Vec<_>>();
+        let renamed = self.renamed
+            .iter()
+            .map(|(file, path)| Value::dict([("file", int(*file as u64)), ("path", Value::from(path.to_string_lossy().as_ref()))]))
+            .collect::<Vec<_>>();
+
+        let mut fields = vec![
+            ("info-hash", Value::from(&self.info_hash[..])),
+            ("pieces", Value::from(self.pieces.as_bytes())),
+            ("num pieces", int(self.pieces.len() as u64)),
+            ("files", files.into()),
+            ("uploaded", int(self.uploaded)),
+            ("downloaded", int(self.downloaded)),
+            ("trackers", trackers.into())
+        ];
+        // Left out where nothing was, as it is everywhere but Windows.
+        if !renamed.is_empty() {
+            fields.push(("renamed", renamed.into()));
+        }
+        Value::dict(fields).encode()
+    }
+
+    pub fn decode(bytes: &[u8]) -> Option<Self> {
+        let value = bencode::decode(bytes)?;
+        let num_pieces = uint(&value, "num pieces")? as usize;
+        let files = value
+            .get("files")?
+            .as_list()?
+            .iter()
+            .map(|file| Some(FileStamp { length: uint(file, "length")?, modified: uint(file, "mtime") }))
+            .collect::<Option<_>>()?;
+        let trackers = value
+            .get("trackers")?
+            .as_list()?
+            .iter()
+            .map(|tracker| {
+                Some(TrackerState {
+                    url: tracker.get("url")?.as_str()?.to_string(),
+                    tracker_id: tracker.get("tracker id").and_then(Value::as_bytes).map(<[u8]>::to_vec),
+                    completed_sent: uint(tracker, "completed")? == 1
+                })
+            })
+            .collect::<Option<_>>()?;
+        let renamed = value
+            .get("renamed")
+            .and_then(Value::as_list)
+            .unwrap_or_default()
+            .iter()
+            .map(|renamed| Some((uint(renamed, "file")? as usize, PathBuf::from(renamed.get("path")?.as_str()?))))
+            .collect::<Option<_>>()?;
+
+        Some(Self {
+            info_hash: value.get("info-hash")?.as_bytes()?.try_into().ok()?,
+            pieces: Bitfield::from_bytes(value.get("pieces")?.as_bytes()?, num_pieces)?,
+            files,
+            uploaded: uint(&value, "uploaded")?,
+            downloaded: uint(&value, "downloaded")?,
+            trackers,
+            renamed
+        })
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let bytes = fs::read(path)?;
+        Self::decode(&bytes).ok_or_else(|| invalid("corrupt resume file"))
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let path = path.as_ref();
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, self.encode())?;
+        fs::rename(tmp, path)
+    }
+
+    // Like `save`, but the new file reaches the disk before it replaces the
+    // old one, and the rename itself is synced where directories can be.
+    pub fn save_synced(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let path = path.as_ref();
+        let tmp = path.with_extension("tmp");
+        let mut file = fs::File::create(&tmp)?;
+        file.write_all(&self.encode())?;
+        file.sync_all()?;
+        fs::rename(tmp, path)?;
+        #[cfg(unix)]
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::File::open(dir)?.sync_all()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::bitfield::Bitfield;
+    use crate::storage::layout::{FileEntry, Layout};
+    use crate::storage::resume::{ResumeData, TrackerState};
+    use crate::storage::FileStorage;
+    use std::fs;
+
+    #[test]
+    fn test_resume() {
+        let root = std::env::temp_dir().join(format!("resume-{}", std::process::id()));
+        let files = vec![FileEntry::new("a", 6), FileEntry::new("b", 6)];
+        let storage = FileStorage::new(&root, Layout::new(files, 4).unwrap());
+        storage.create_files().unwrap();
+
+        let mut resume = ResumeData::capture([1; 20], &storage, &Bitfield::full(3));
+        resume.uploaded = 10;
+        resume.trackers.push(TrackerState {
+            url: "http://tracker/announce".into(),
+            tracker_id: Some(b"abc".to_vec()),
+            completed_sent: true
+        });
+
+        let path = root.join("resume.dat");
+        resume.save(&path).unwrap();
+        assert_eq!(ResumeData::load(&path).unwrap(), resume);
+        resume.save_synced(&path).unwrap();
+        let loaded = ResumeData::load(&path).unwrap();
+        assert_eq!(loaded, resume);
+        assert!(loaded.verified_pieces(&storage).is_complete());
+
+        // Saved under another name than `a` goes by now, by an older
+        // sanitizing, its pieces are not to be trusted.
+        let moved = ResumeData { renamed: vec![(0, "a%3A".into())], ..loaded.clone() };
+        assert_eq!(ResumeData::decode(&moved.encode()).unwrap(), moved);
+        assert_eq!(moved.verified_pieces(&storage).ones().collect::<Vec<_>>(), [2]);
+
+        // Piece 1 straddles both files, so it goes along with piece 2.
+        fs::OpenOptions::new().write(true).open(root.join("b")).unwrap().set_len(7).unwrap();
+        assert_eq!(loaded.verified_pieces(&storage).ones().collect::<Vec<_>>(), vec![0]);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,141 @@
+use crate::hash::{hex, sha1};
+use crate::storage::layout::FileEntry;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+// Characters Windows won't have in a file name, besides those below ' '.
+const INVALID: &[char] = &['<', '>', ':', '"', '/', '\\', '|', '?', '*'];
+// Device names, reserved with any extension and in any case.
+const RESERVED: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9", "COM¹", "COM²", "COM³", "LPT1",
+    "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9", "LPT¹", "LPT²", "LPT³"
+];
+// The longest name NTFS allows, in UTF-16 units.
+const MAX_NAME: usize = 255;
+
+fn escape(c: char) -> String {
+    let mut utf8 = [0; 4];
+    c.encode_utf8(&mut utf8).bytes().map(|byte| format!("%{:02X}", byte)).collect()
+}
+
+// `name` as Windows will take it. Bad characters, and the dots and spaces
+// it would drop from the end, are escaped as `%XX`, along with any `%`
+// once something needed escaping; a device name gets a `_` after it; a name
+// too long is cut short, keeping its extension, with part of its hash to
+// keep it apart from others cut the same way.
+pub fn component(name: &str) -> String {
+    let keep = name.trim_end_matches(['.', ' ']).len();
+    let bad = |(index, c): (usize, char)| INVALID.contains(&c) || c < ' ' || index >= keep;
+    let mut safe: String = match name.char_indices().any(bad) {
+        true => name.char_indices().map(|(index, c)| match bad((index, c)) || c == '%' {
+            true => escape(c),
+            false => c.to_string()
+        }).collect(),
+        false => name.to_string()
+    };
+    if safe.is_empty() {
+        safe.push('_');
+    }
+    let stem_len = safe.find('.').unwrap_or(safe.len());
+    if RESERVED.iter().any(|reserved| safe[..stem_len].trim_end().eq_ignore_ascii_case(reserved)) {
+        safe.insert(stem_len, '_');
+    }
+    if safe.encode_utf16().count() > MAX_NAME {
+        let extension = Path::new(&safe).extension().map(|extension| format!(".{}", extension.to_string_lossy())).filter(|extension| extension.len() <= 16);
+        let extension = extension.unwrap_or_default();
+        let tag = format!("~{}", &hex(&sha1(name.as_bytes()))[..8]);
+        let budget = MAX_NAME - tag.len() - extension.encode_utf16().count();
+        let mut stem = String::new();
+        for c in safe.chars() {
+            if stem.encode_utf16().count() + c.len_utf16() > budget {
+                break;
+            }
+            stem.push(c);
+        }
+        safe = stem + &tag + &extension;
+    }
+    safe
+}
+
+// The torrent's files as they can be saved on Windows: each name made safe
+// by `component`, and names that then clash, which case alone is enough to
+// do, told apart by a `~N` after the file's stem. The result depends only
+// on the files and their order, so the same torrent always maps the same.
+pub fn sanitize(files: &[FileEntry]) -> Vec<FileEntry> {
+    let mut taken = HashSet::new();
+    files
+        .iter()
+        .map(|file| {
+            let path: PathBuf = file.path.iter().map(|name| component(&name.to_string_lossy())).collect();
+            let mut unique = path.clone();
+            let mut n = 1;
+            while !taken.insert(unique.to_string_lossy().to_lowercase()) {
+                let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+                let name = match path.extension() {
+                    Some(extension) => format!("{}~{}.{}", stem, n, extension.to_string_lossy()),
+                    None => format!("{}~{}", stem, n)
+                };
+                unique = path.with_file_name(name);
+                n += 1;
+            }
+            FileEntry::new(unique, file.length)
+        })
+        .collect()
+}
+
+// Where each file saved somewhere other than its path in the torrent went,
+// by its index.
+pub fn renamed(original: &[FileEntry], on_disk: &[FileEntry]) -> Vec<(usize, PathBuf)> {
+    original
+        .iter()
+        .zip(on_disk)
+        .enumerate()
+        .filter(|(_, (original, on_disk))| original.path != on_disk.path)
+        .map(|(index, (_, on_disk))| (index, on_disk.path.clone()))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use crate::storage::layout::FileEntry;
+    use crate::storage::sanitize::{component, renamed, sanitize};
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_component() {
+        assert_eq!(component("song.mp3"), "song.mp3");
+        assert_eq!(component("100%.txt"), "100%.txt");
+        assert_eq!(component("a:b?.txt"), "a%3Ab%3F.txt");
+        assert_eq!(component("100%: done"), "100%25%3A done");
+        assert_eq!(component("notes. "), "notes%2E%20");
+        assert_eq!(component(".."), "%2E%2E");
+        assert_eq!(component("tab\there"), "tab%09here");
+        assert_eq!(component("con"), "con_");
+        assert_eq!(component("CON.txt"), "CON_.txt");
+        assert_eq!(component("lpt1.tar.gz"), "lpt1_.tar.gz");
+        assert_eq!(component("console.txt"), "console.txt");
+        assert_eq!(component(""), "_");
+
+        let long = component(&format!("{}.mkv", "é".repeat(300)));
+        assert_eq!(long.encode_utf16().count(), 255);
+        assert!(long.ends_with(".mkv"));
+        assert_ne!(long, component(&format!("{}.mkv", "é".repeat(301))));
+    }
+
+    #[test]
+    fn test_sanitize() {
+        let files = vec![
+            FileEntry::new("t/a:b.txt", 1),
+            FileEntry::new("t/a%3Ab.txt", 2),
+            FileEntry::new("t/A%3AB.txt", 3),
+            FileEntry::new("t/aux", 4),
+            FileEntry::new("t/plain", 5)
+        ];
+        let safe = sanitize(&files);
+        let paths: Vec<_> = safe.iter().map(|file| file.path.clone()).collect();
+        assert_eq!(paths, ["t/a%3Ab.txt", "t/a%3Ab~1.txt", "t/A%3AB~2.txt", "t/aux_", "t/plain"].map(PathBuf::from));
+        assert_eq!(safe[2].length, 3);
+        assert_eq!(sanitize(&files), safe);
+        assert_eq!(renamed(&files, &safe), [(0, paths[0].clone()), (1, paths[1].clone()), (2, paths[2].clone()), (3, paths[3].clone())]);
+    }
+}
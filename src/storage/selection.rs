@@ -0,0 +1,181 @@
+use crate::bitfield::Bitfield;
+use crate::storage::layout::Layout;
+
+// How eagerly to fetch a file. A piece gets the highest priority of the
+// files it overlaps, and the picker starts higher pieces first.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    Skip,
+    Low,
+    #[default]
+    Normal,
+    High
+}
+
+// Which files of a torrent to download, and in what order. Pieces that
+// straddle a skipped and a wanted file are still fetched in full, so skipped
+// files may end up with a few bytes written at their edges.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileSelection {
+    priorities: Vec<Priority>
+}
+
+impl FileSelection {
+    pub fn all(num_files: usize) -> Self {
+        Self { priorities: vec![Priority::Normal; num_files] }
+    }
+
+    // Parses a list of file indices and inclusive ranges such as `1,3-5`,
+    // counting from zero. `None` if it names a file that does not exist.
+    pub fn parse(spec: &str, num_files: usize) -> Option<Self> {
+        let mut priorities = vec![Priority::Skip; num_files];
+        for part in spec.split(',').map(str::trim) {
+            let (first, last) = match part.split_once('-') {
+                Some((first, last)) => (first.trim().parse().ok()?, last.trim().parse().ok()?),
+                None => {
+                    let file = part.parse().ok()?;
+                    (file, file)
+                }
+            };
+            if first > last || last >= num_files {
+                return None;
+            }
+            priorities[first..=last].fill(Priority::Normal);
+        }
+        Some(Self { priorities })
+    }
+
+    pub fn len(&self) -> usize {
+        self.priorities.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.priorities.is_empty()
+    }
+
+    pub fn priority(&self, file: usize) -> Priority {
+        self.priorities.get(file).copied().unwrap_or(Priority::Skip)
+    }
+
+    pub fn set_priority(&mut self, file: usize, priority: Priority) {
+        if let Some(slot) = self.priorities.get_mut(file) {
+            *slot = priority;
+        }
+    }
+
+    pub fn is_wanted(&self, file: usize) -> bool {
+        self.priority(file) != Priority::Skip
+    }
+
+    pub fn set_wanted(&mut self, file: usize, wanted: bool) {
+        self.set_priority(file, if wanted { Priority::Normal } else { Priority::Skip });
+    }
+
+    // The priority of every piece: the highest of the files it overlaps.
+    pub fn piece_priorities(&self, layout: &Layout) -> Vec<Priority> {
+        let mut pieces = vec![Priority::Skip; layout.geometry().num_pieces() as usize];
+        for file in 0..layout.files().len() {
+            let priority = self.priority(file);
+            for piece in layout.file_pieces(file) {
+                let slot = &mut pieces[piece as usize];
+                *slot = (*slot).max(priority);
+            }
+        }
+        pieces
+    }
+
+    // Every piece overlapping at least one wanted file.
+    pub fn wanted_pieces(&self, layout: &Layout) -> Bitfield {
+        let mut pieces = Bitfield::new(layout.geometry().num_pieces() as usize);
+        for file in (0..layout.files().len()).filter(|&file| self.is_wanted(file)) {
+            layout.file_pieces(file).for_each(|piece| {
+                pieces.set(piece as usize);
+            });
+        }
+        pieces
+    }
+
+    pub fn wanted_length(&self, layout: &Layout) -> u64 {
+        (0..layout.files().len())
+            .filter(|&file| self.is_wanted(file))
+            .map(|file| layout.files()[file].length)
+            .sum()
+    }
+
+    // How much of the wanted files we have, from 0 to 1. Nothing wanted
+    // counts as done.
+    pub fn completion(&self, layout: &Layout, have: &Bitfield) -> f64 {
+        let total = self.wanted_length(layout);
+        if total == 0 {
+            return 1.0;
+        }
+        let done: u64 = (0..layout.files().len())
+            .filter(|&file| self.is_wanted(file))
+            .map(|file| file_bytes_done(layout, file, have))
+            .sum();
+        done as f64 / total as f64
+    }
+}
+
+// Bytes of `file` that lie in pieces we have.
+pub fn file_bytes_done(layout: &Layout, file: usize, have: &Bitfield) -> u64 {
+    let start = layout.file_offset(file);
+    let end = start + layout.files()[file].length;
+    let geometry = layout.geometry();
+    layout
+        .file_pieces(file)
+        .filter(|&piece| have.get(piece as usize))
+        .map(|piece| {
+            let piece_start = geometry.piece_offset(piece);
+            let piece_end = piece_start + geometry.piece_size(piece).unwrap_or(0) as u64;
+            piece_end.min(end) - piece_start.max(start)
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod test {
+    use crate::bitfield::Bitfield;
+    use crate::storage::layout::{FileEntry, Layout};
+    use crate::storage::selection::{FileSelection, Priority};
+
+    #[test]
+    fn test_parse() {
+        let selection = FileSelection::parse("1, 3-5", 6).unwrap();
+        assert_eq!((0..6).filter(|&file| selection.is_wanted(file)).collect::<Vec<_>>(), vec![1, 3, 4, 5]);
+        assert!(FileSelection::parse("6", 6).is_none());
+        assert!(FileSelection::parse("4-3", 6).is_none());
+        assert!(FileSelection::parse("a", 6).is_none());
+    }
+
+    #[test]
+    fn test_wanted_pieces_and_completion() {
+        // Pieces of 4 bytes: a is 0..6, b is 6..8, c is 8..12.
+        let files = vec![FileEntry::new("a", 6), FileEntry::new("b", 2), FileEntry::new("c", 4)];
+        let layout = Layout::new(files, 4).unwrap();
+        let selection = FileSelection::parse("1", 3).unwrap();
+
+        assert_eq!(selection.wanted_pieces(&layout).ones().collect::<Vec<_>>(), vec![1]);
+        assert_eq!(selection.wanted_length(&layout), 2);
+
+        let mut have = Bitfield::new(3);
+        have.set(2);
+        assert_eq!(selection.completion(&layout, &have), 0.0);
+        have.set(1);
+        assert_eq!(selection.completion(&layout, &have), 1.0);
+        assert_eq!(FileSelection::all(3).completion(&layout, &have), 8.0 / 12.0);
+    }
+
+    #[test]
+    fn test_piece_priorities() {
+        let files = vec![FileEntry::new("a", 6), FileEntry::new("b", 2), FileEntry::new("c", 4)];
+        let layout = Layout::new(files, 4).unwrap();
+        let mut selection = FileSelection::all(3);
+        selection.set_priority(0, Priority::Low);
+        selection.set_priority(1, Priority::High);
+        selection.set_wanted(2, false);
+
+        assert_eq!(selection.piece_priorities(&layout), vec![Priority::Low, Priority::High, Priority::Skip]);
+        assert!(!selection.wanted_pieces(&layout).get(2));
+    }
+}
@@ -0,0 +1,148 @@
+use crate::bitfield::Bitfield;
+use crate::piece::PieceGeometry;
+use std::io;
+use std::path::Path;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+pub const SPACE_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+// Bytes free for an unprivileged user on the filesystem holding `path`.
+// The path need not exist yet; its closest existing ancestor is asked.
+#[cfg(unix)]
+pub fn available_space(path: &Path) -> io::Result<u64> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = path
+        .ancestors()
+        .find(|dir| dir.exists())
+        .unwrap_or(Path::new("."));
+    let path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "path contains a nul byte"))?;
+    let mut stats = unsafe { std::mem::zeroed::<libc::statvfs>() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stats) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(stats.f_bavail as u64 * stats.f_frsize as u64)
+}
+
+#[cfg(not(unix))]
+pub fn available_space(_path: &Path) -> io::Result<u64> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "free space is only known on unix"))
+}
+
+// Bytes of the wanted pieces we do not have yet.
+pub fn missing_bytes(geometry: &PieceGeometry, have: &Bitfield, wanted: &Bitfield) -> u64 {
+    wanted
+        .ones()
+        .filter(|&piece| !have.get(piece))
+        .filter_map(|piece| geometry.piece_size(piece as u32))
+        .map(u64::from)
+        .sum()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpaceEvent {
+    // The torrent should pause before a write fails with a full disk.
+    Low { available: u64, needed: u64 },
+    // Enough space again; the torrent can resume.
+    Recovered
+}
+
+// Checks free space before a torrent starts and every so often while it
+// downloads, reporting only when the answer changes.
+#[derive(Debug, Clone)]
+pub struct SpaceMonitor {
+    interval: Duration,
+    // Space to leave free on top of what the torrent still needs.
+    reserve: u64,
+    low: bool,
+    next: Option<Instant>
+}
+
+impl Default for SpaceMonitor {
+    fn default() -> Self {
+        Self::new(SPACE_CHECK_INTERVAL)
+    }
+}
+
+impl SpaceMonitor {
+    pub fn new(interval: Duration) -> Self {
+        Self { interval, reserve: 0, low: false, next: None }
+    }
+
+    pub fn with_reserve(mut self, reserve: u64) -> Self {
+        self.reserve = reserve;
+        self
+    }
+
+    pub fn is_low(&self) -> bool {
+        self.low
+    }
+
+    pub fn is_due(&self, now: Instant) -> bool {
+        self.next.is_none_or(|next| now >= next)
+    }
+
+    // Compares `available` with `needed`, as measured by the caller.
+    pub fn update(&mut self, available: u64, needed: u64) -> Option<SpaceEvent> {
+        let low = available < needed.saturating_add(self.reserve);
+        if low == self.low {
+            return None;
+        }
+        self.low = low;
+        Some(match low {
+            true => SpaceEvent::Low { available, needed },
+            false => SpaceEvent::Recovered
+        })
+    }
+
+    // Checks the filesystem under `root` if a check is due.
+    pub fn poll(&mut self, root: &Path, needed: u64, now: Instant) -> io::Result<Option<SpaceEvent>> {
+        if !self.is_due(now) {
+            return Ok(None);
+        }
+        self.next = Some(now + self.interval);
+        let event = self.update(available_space(root)?, needed);
+        match event {
+            Some(SpaceEvent::Low { available, needed }) => warn!(root = %root.display(), available, needed, "low on disk space"),
+            Some(SpaceEvent::Recovered) => info!(root = %root.display(), "disk space recovered"),
+            None => {}
+        }
+        Ok(event)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::bitfield::Bitfield;
+    use crate::piece::PieceGeometry;
+    use crate::storage::space::{available_space, missing_bytes, SpaceEvent, SpaceMonitor};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_missing_bytes() {
+        let geometry = PieceGeometry::new(4, 10).unwrap();
+        let mut have = Bitfield::new(3);
+        have.set(0);
+        assert_eq!(missing_bytes(&geometry, &have, &Bitfield::full(3)), 6);
+    }
+
+    #[test]
+    fn test_monitor() {
+        let mut monitor = SpaceMonitor::new(Duration::from_secs(10)).with_reserve(5);
+        assert_eq!(monitor.update(100, 90), None);
+        assert_eq!(monitor.update(100, 96), Some(SpaceEvent::Low { available: 100, needed: 96 }));
+        assert_eq!(monitor.update(90, 96), None);
+        assert_eq!(monitor.update(200, 96), Some(SpaceEvent::Recovered));
+
+        let now = Instant::now();
+        let root = std::env::temp_dir().join("does/not/exist");
+        assert!(available_space(&root).unwrap() > 0);
+        assert_eq!(monitor.poll(&root, 0, now).unwrap(), None);
+        assert!(!monitor.is_due(now));
+        assert_eq!(monitor.poll(&root, u64::MAX, now).unwrap(), None);
+        assert!(matches!(monitor.poll(&root, u64::MAX, now + Duration::from_secs(10)), Ok(Some(SpaceEvent::Low { .. }))));
+    }
+}
@@ -0,0 +1,177 @@
+use crate::storage::allocate::{allocate, Allocation};
+use crate::storage::layout::{Layout, Span};
+use crate::storage::{out_of_range, Storage};
+use io_uring::{opcode, squeue, types, IoUring};
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+pub const RING_ENTRIES: u32 = 64;
+
+// Storage that hands reads and writes to io_uring. All the blocks written
+// for a piece, or all the reads needed to hash one, go to the kernel in one
+// submission instead of one system call each.
+pub struct UringStorage {
+    root: PathBuf,
+    layout: Layout,
+    files: Vec<File>,
+    // Behind a lock so reads can share the storage.
+    ring: Mutex<IoUring>
+}
+
+impl UringStorage {
+    pub fn open(root: impl Into<PathBuf>, layout: Layout, allocation: Allocation) -> io::Result<Self> {
+        let root = root.into();
+        let mut files = Vec::new();
+        for entry in layout.files() {
+            let path = root.join(&entry.path);
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            let file = OpenOptions::new().create(true).truncate(false).read(true).write(true).open(path)?;
+            allocate(&file, entry.length, allocation)?;
+            files.push(file);
+        }
+        Ok(Self { root, layout, files, ring: Mutex::new(IoUring::new(RING_ENTRIES)?) })
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn layout(&self) -> &Layout {
+        &self.layout
+    }
+
+    // Submits the operations a ring's worth at a time and waits for all of
+    // them. Each must transfer exactly the length it asked for. The buffers
+    // the entries point into must outlive the call.
+    fn run(&self, ops: &[(squeue::Entry, usize)]) -> io::Result<()> {
+        let mut ring = self.ring.lock().unwrap();
+        for batch in ops.chunks(RING_ENTRIES as usize) {
+            for (i, (entry, _)) in batch.iter().enumerate() {
+                let entry = entry.clone().user_data(i as u64);
+                unsafe { ring.submission().push(&entry) }
+                    .map_err(|_| io::Error::other("submission queue full"))?;
+            }
+            ring.submit_and_wait(batch.len())?;
+
+            let results: Vec<_> = ring
+                .completion()
+                .map(|done| (done.user_data() as usize, done.result()))
+                .collect();
+            for (i, result) in results {
+                if result < 0 {
+                    return Err(io::Error::from_raw_os_error(-result));
+                }
+                if result as usize != batch[i].1 {
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "short read or write"));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Writes several blocks of one piece, given as (begin, data), at once.
+    pub fn write_blocks(&self, piece: u32, blocks: &[(u32, &[u8])]) -> io::Result<()> {
+        let mut ops = Vec::new();
+        for &(begin, data) in blocks {
+            let spans = self.layout
+                .piece_spans(piece, begin, data.len())
+                .ok_or_else(out_of_range)?;
+            let mut data = data;
+            for Span { file, offset, len } in spans {
+                let fd = types::Fd(self.files[file].as_raw_fd());
+                let write = opcode::Write::new(fd, data.as_ptr(), len as u32).offset(offset).build();
+                ops.push((write, len));
+                data = &data[len..];
+            }
+        }
+        self.run(&ops)
+    }
+
+    pub fn write(&self, piece: u32, begin: u32, data: &[u8]) -> io::Result<()> {
+        self.write_blocks(piece, &[(begin, data)])
+    }
+
+    pub fn read(&self, piece: u32, begin: u32, len: usize) -> io::Result<Vec<u8>> {
+        let spans = self.layout
+            .piece_spans(piece, begin, len)
+            .ok_or_else(out_of_range)?;
+        let mut data = vec![0; len];
+        let mut at = 0;
+        let mut ops = Vec::new();
+        for Span { file, offset, len } in spans {
+            let fd = types::Fd(self.files[file].as_raw_fd());
+            let read = opcode::Read::new(fd, data[at..].as_mut_ptr(), len as u32).offset(offset).build();
+            ops.push((read, len));
+            at += len;
+        }
+        self.run(&ops)?;
+        Ok(data)
+    }
+
+    pub fn read_piece(&self, piece: u32) -> io::Result<Vec<u8>> {
+        let size = self.layout
+            .geometry()
+            .piece_size(piece)
+            .ok_or_else(out_of_range)?;
+        self.read(piece, 0, size as usize)
+    }
+
+    pub fn flush(&self) -> io::Result<()> {
+        self.files.iter().try_for_each(File::sync_data)
+    }
+}
+
+impl Storage for UringStorage {
+    fn layout(&self) -> &Layout {
+        &self.layout
+    }
+
+    fn read(&self, piece: u32, begin: u32, len: usize) -> io::Result<Vec<u8>> {
+        UringStorage::read(self, piece, begin, len)
+    }
+
+    fn write(&mut self, piece: u32, begin: u32, data: &[u8]) -> io::Result<()> {
+        UringStorage::write(self, piece, begin, data)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        UringStorage::flush(self)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::storage::allocate::Allocation;
+    use crate::storage::layout::{FileEntry, Layout};
+    use crate::storage::uring::UringStorage;
+    use std::fs;
+
+    #[test]
+    fn test_write_across_files() {
+        let root = std::env::temp_dir().join(format!("uring-{}", std::process::id()));
+        let files = vec![FileEntry::new("dir/a", 5), FileEntry::new("empty", 0), FileEntry::new("b", 10)];
+        // Kernels without io_uring, or sandboxes that block it, skip this.
+        let Ok(storage) = UringStorage::open(&root, Layout::new(files, 4).unwrap(), Allocation::Sparse) else {
+            return;
+        };
+
+        storage.write_blocks(1, &[(0, b"ef"), (2, b"gh")]).unwrap();
+        storage.write(0, 0, b"abcd").unwrap();
+        storage.write(3, 0, b"mno").unwrap();
+        storage.write(2, 0, b"ijkl").unwrap();
+        assert!(storage.write(3, 2, b"xx").is_err());
+
+        assert_eq!(fs::read(root.join("dir/a")).unwrap(), b"abcde");
+        assert_eq!(fs::read(root.join("b")).unwrap(), b"fghijklmno");
+        assert_eq!(storage.read(1, 1, 3).unwrap(), b"fgh");
+        assert_eq!(storage.read_piece(3).unwrap(), b"mno");
+
+        drop(storage);
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,181 @@
+use crate::bitfield::Bitfield;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+pub const DEFAULT_PIECES_PER_PEER: usize = 1;
+
+struct OfferedPeer {
+    has: Bitfield,
+    offered: Vec<u32>
+}
+
+// Initial seeding (BEP 16). Instead of a full bitfield each peer is told
+// about only a few pieces via `have`, preferring the rarest ones. A peer is
+// shown a new piece only once some *other* peer announces a piece we revealed
+// to it, i.e. once it has passed that piece on to the swarm.
+pub struct SuperSeeder {
+    availability: Vec<u32>,
+    peers: HashMap<SocketAddr, OfferedPeer>,
+    pieces_per_peer: usize
+}
+
+impl SuperSeeder {
+    pub fn new(num_pieces: usize, pieces_per_peer: usize) -> Self {
+        Self {
+            availability: vec![0; num_pieces],
+            peers: HashMap::new(),
+            pieces_per_peer: pieces_per_peer.max(1)
+        }
+    }
+
+    // Returns the pieces to announce to the newly connected peer, given the
+    // bitfield it sent (empty if it sent none). One of the wrong length is
+    // taken as empty.
+    pub fn peer_connected(&mut self, addr: SocketAddr, has: &Bitfield) -> Vec<u32> {
+        self.peer_disconnected(addr);
+        let has = match has.len() == self.availability.len() {
+            true => has.clone(),
+            false => Bitfield::new(self.availability.len())
+        };
+        has.ones().for_each(|index| self.availability[index] += 1);
+
+        let peer = OfferedPeer {
+            has,
+            offered: Vec::new()
+        };
+        self.peers.insert(addr, peer);
+        self.fill_offers(addr)
+    }
+
+    pub fn peer_disconnected(&mut self, addr: SocketAddr) {
+        if let Some(peer) = self.peers.remove(&addr) {
+            peer.has
+                .ones()
+                .for_each(|index| self.availability[index] -= 1);
+        }
+    }
+
+    // Records a `have` and returns the new pieces to reveal, per peer.
+    pub fn peer_have(&mut self, addr: SocketAddr, piece: u32) -> Vec<(SocketAddr, u32)> {
+        let newly_had = match self.peers.get_mut(&addr) {
+            Some(peer) => peer.has.set(piece as usize),
+            None => false
+        };
+        if !newly_had {
+            return Vec::new();
+        }
+        self.availability[piece as usize] += 1;
+
+        let mut refill = Vec::new();
+        for (&other, peer) in self.peers.iter_mut() {
+            if other == addr {
+                continue;
+            }
+            if let Some(pos) = peer.offered.iter().position(|&p| p == piece) {
+                peer.offered.remove(pos);
+                refill.push(other);
+            }
+        }
+
+        refill
+            .into_iter()
+            .flat_map(|peer| {
+                self.fill_offers(peer)
+                    .into_iter()
+                    .map(move |piece| (peer, piece))
+            })
+            .collect()
+    }
+
+    // Only requests for pieces revealed to the peer should be served.
+    pub fn is_offered(&self, addr: SocketAddr, piece: u32) -> bool {
+        self.peers
+            .get(&addr)
+            .map(|peer| peer.offered.contains(&piece))
+            .unwrap_or(false)
+    }
+
+    fn fill_offers(&mut self, addr: SocketAddr) -> Vec<u32> {
+        let mut revealed = Vec::new();
+        while let Some(piece) = self.rarest_for(addr) {
+            let peer = match self.peers.get_mut(&addr) {
+                Some(peer) => peer,
+                None => break
+            };
+            peer.offered.push(piece);
+            revealed.push(piece);
+        }
+        revealed
+    }
+
+    fn rarest_for(&self, addr: SocketAddr) -> Option<u32> {
+        let peer = self.peers.get(&addr)?;
+        if peer.offered.len() >= self.pieces_per_peer {
+            return None;
+        }
+        let offered_elsewhere = |piece: u32| {
+            self.peers
+                .iter()
+                .any(|(&other, p)| other != addr && p.offered.contains(&piece))
+        };
+
+        (0..self.availability.len() as u32)
+            .filter(|&piece| !peer.has.get(piece as usize) && !peer.offered.contains(&piece))
+            .min_by_key(|&piece| (offered_elsewhere(piece), self.availability[piece as usize], piece))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::bitfield::Bitfield;
+    use crate::superseed::SuperSeeder;
+    use std::net::SocketAddr;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn test_reveal_after_propagation() {
+        let mut seeder = SuperSeeder::new(4, 1);
+        let none = Bitfield::new(4);
+
+        assert_eq!(seeder.peer_connected(addr(1), &none), vec![0]);
+        assert_eq!(seeder.peer_connected(addr(2), &none), vec![1]);
+        assert!(seeder.is_offered(addr(1), 0));
+        assert!(!seeder.is_offered(addr(1), 1));
+
+        // Peer 1 downloading the piece we showed it reveals nothing new yet.
+        assert!(seeder.peer_have(addr(1), 0).is_empty());
+
+        // Peer 2 getting piece 0 from peer 1 proves it propagated.
+        assert_eq!(seeder.peer_have(addr(2), 0), vec![(addr(1), 2)]);
+        assert!(seeder.is_offered(addr(1), 2));
+        assert!(!seeder.is_offered(addr(1), 0));
+    }
+
+    #[test]
+    fn test_prefers_rarest() {
+        let mut seeder = SuperSeeder::new(3, 2);
+        let none = Bitfield::new(3);
+        let mut has = Bitfield::new(3);
+        has.set(1);
+
+        assert_eq!(seeder.peer_connected(addr(1), &has), vec![0, 2]);
+        assert_eq!(seeder.peer_connected(addr(2), &none), vec![1, 0]);
+
+        seeder.peer_disconnected(addr(1));
+        assert_eq!(seeder.peer_connected(addr(3), &none), vec![2, 0]);
+        assert!(seeder.peer_connected(addr(4), &Bitfield::full(3)).is_empty());
+    }
+
+    #[test]
+    fn test_mismatched_bitfield() {
+        let mut seeder = SuperSeeder::new(3, 1);
+
+        assert_eq!(seeder.peer_connected(addr(1), &Bitfield::full(16)), vec![0]);
+        assert!(seeder.peer_have(addr(1), 7).is_empty());
+        seeder.peer_disconnected(addr(1));
+        assert_eq!(seeder.peer_connected(addr(2), &Bitfield::new(3)), vec![0]);
+    }
+}
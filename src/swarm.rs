@@ -0,0 +1,165 @@
+use crate::bitfield::Bitfield;
+use crate::peer_id::{self, ClientInfo};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+#[derive(Debug, Clone)]
+pub struct Peer {
+    pub addr: SocketAddr,
+    pub peer_id: Option<[u8; 20]>,
+    pub client: Option<ClientInfo>,
+    pub has: Bitfield,
+    pub am_choking: bool,
+    pub am_interested: bool,
+    pub peer_choking: bool,
+    pub peer_interested: bool
+}
+
+impl Peer {
+    pub fn new(addr: SocketAddr, num_pieces: usize) -> Self {
+        Self {
+            addr,
+            peer_id: None,
+            client: None,
+            has: Bitfield::new(num_pieces),
+            am_choking: true,
+            am_interested: false,
+            peer_choking: true,
+            peer_interested: false
+        }
+    }
+
+    pub fn set_peer_id(&mut self, peer_id: [u8; 20]) {
+        self.peer_id = Some(peer_id);
+        self.client = peer_id::identify(&peer_id);
+    }
+}
+
+// The connected peers of one torrent together with our own piece bitfield,
+// which is what we send in the `bitfield` message after new handshakes.
+pub struct Swarm {
+    have: Bitfield,
+    peers: HashMap<SocketAddr, Peer>,
+    lazy_have: bool
+}
+
+impl Swarm {
+    pub fn new(num_pieces: usize) -> Self {
+        Self::with_bitfield(Bitfield::new(num_pieces))
+    }
+
+    pub fn with_bitfield(have: Bitfield) -> Self {
+        Self { have, peers: HashMap::new(), lazy_have: false }
+    }
+
+    // With lazy `have`s enabled, peers that already have a piece are not told
+    // that we have it too.
+    pub fn set_lazy_have(&mut self, lazy_have: bool) {
+        self.lazy_have = lazy_have;
+    }
+
+    pub fn bitfield(&self) -> &Bitfield {
+        &self.have
+    }
+
+    pub fn num_pieces(&self) -> usize {
+        self.have.len()
+    }
+
+    pub fn add_peer(&mut self, addr: SocketAddr) -> &mut Peer {
+        let num_pieces = self.num_pieces();
+        self.peers
+            .entry(addr)
+            .or_insert_with(|| Peer::new(addr, num_pieces))
+    }
+
+    pub fn remove_peer(&mut self, addr: SocketAddr) -> Option<Peer> {
+        self.peers.remove(&addr)
+    }
+
+    pub fn peer(&self, addr: SocketAddr) -> Option<&Peer> {
+        self.peers.get(&addr)
+    }
+
+    pub fn peer_mut(&mut self, addr: SocketAddr) -> Option<&mut Peer> {
+        self.peers.get_mut(&addr)
+    }
+
+    pub fn peers(&self) -> impl Iterator<Item = &Peer> {
+        self.peers.values()
+    }
+
+    pub fn len(&self) -> usize {
+        self.peers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.peers.is_empty()
+    }
+
+    pub fn peer_have(&mut self, addr: SocketAddr, piece: u32) -> bool {
+        self.peers
+            .get_mut(&addr)
+            .map(|peer| peer.has.set(piece as usize))
+            .unwrap_or(false)
+    }
+
+    // Marks a piece as verified and returns the peers a `have` should be sent
+    // to. Returns nothing if the piece was already ours.
+    pub fn piece_verified(&mut self, piece: u32) -> Vec<SocketAddr> {
+        if !self.have.set(piece as usize) {
+            return Vec::new();
+        }
+
+        self.peers
+            .values()
+            .filter(|peer| !self.lazy_have || !peer.has.get(piece as usize))
+            .map(|peer| peer.addr)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::swarm::Swarm;
+    use std::net::SocketAddr;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn test_have_broadcast() {
+        let mut swarm = Swarm::new(4);
+        swarm.add_peer(addr(1));
+        swarm.add_peer(addr(2));
+        swarm.peer_have(addr(2), 3);
+
+        let mut targets = swarm.piece_verified(3);
+        targets.sort();
+        assert_eq!(targets, vec![addr(1), addr(2)]);
+        assert!(swarm.bitfield().get(3));
+        assert!(swarm.piece_verified(3).is_empty());
+    }
+
+    #[test]
+    fn test_lazy_have() {
+        let mut swarm = Swarm::new(4);
+        swarm.set_lazy_have(true);
+        swarm.add_peer(addr(1));
+        swarm.add_peer(addr(2));
+        swarm.peer_have(addr(2), 0);
+
+        assert_eq!(swarm.piece_verified(0), vec![addr(1)]);
+        assert_eq!(swarm.bitfield().as_bytes(), &[0x80]);
+    }
+
+    #[test]
+    fn test_peer_client() {
+        let mut swarm = Swarm::new(1);
+        let peer = swarm.add_peer(addr(1));
+        peer.set_peer_id(*b"-DE2110-abcdefghijkl");
+
+        assert_eq!(peer.client.as_ref().unwrap().to_string(), "Deluge 2.1.1");
+    }
+}
@@ -0,0 +1,334 @@
+use crate::bitfield::Bitfield;
+use crate::hash::{HashJob, HashPool, HashResult};
+use crate::metainfo::Metainfo;
+use crate::storage::journal::{self, Journal};
+use crate::storage::resume::ResumeData;
+use crate::storage::{out_of_range, FileStorage, Storage};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::thread;
+
+// One torrent's content and which of its pieces we have. The content is
+// in files on disk unless another storage backend is given.
+pub struct Torrent<S: Storage = FileStorage> {
+    metainfo: Metainfo,
+    storage: S,
+    have: Bitfield
+}
+
+impl Torrent {
+    // `None` if the metainfo's pieces do not match its files. Files carry
+    // `PART_SUFFIX` until every piece of theirs is verified.
+    pub fn new(metainfo: Metainfo, root: impl Into<PathBuf>) -> Option<Self> {
+        let storage = FileStorage::new(root, metainfo.layout()?).with_part_files(true);
+        Self::with_storage(metainfo, storage)
+    }
+
+    // Like `recheck`, but takes the pieces `resume` had verified on trust
+    // where their files haven't changed since, and only hashes the rest.
+    pub fn resume(&mut self, resume: &ResumeData) -> &Bitfield {
+        if resume.info_hash != self.metainfo.info_hash {
+            return self.recheck();
+        }
+        let have = resume.verified_pieces(&self.storage);
+        self.verify_rest(have)
+    }
+
+    // Like `resume`, with the resume data at `resume_path` if there is any
+    // for this torrent, also trusting the pieces the journal next to it has
+    // recorded since. Their data was synced before they were, so the files
+    // they changed need no hashing for them.
+    pub fn resume_from(&mut self, resume_path: &Path) -> &Bitfield {
+        let mut have = match ResumeData::load(resume_path) {
+            Ok(resume) if resume.info_hash == self.metainfo.info_hash => resume.verified_pieces(&self.storage),
+            _ => Bitfield::new(self.metainfo.num_pieces())
+        };
+        // A journal that can't be read only costs the hashing.
+        let _ = Journal::replay(journal::path_for(resume_path), &mut have);
+        self.verify_rest(have)
+    }
+
+    // Hashes the pieces `have` doesn't vouch for.
+    fn verify_rest(&mut self, mut have: Bitfield) -> &Bitfield {
+        let unverified: Vec<_> = have.zeros().map(|piece| piece as u32).collect();
+        for piece in self.verify_pieces(unverified) {
+            have.set(piece as usize);
+        }
+        self.have = have;
+        &self.have
+    }
+}
+
+impl<S: Storage> Torrent<S> {
+    // `None` if the storage is not laid out for the metainfo's pieces.
+    pub fn with_storage(metainfo: Metainfo, storage: S) -> Option<Self> {
+        if storage.layout().geometry().num_pieces() as usize != metainfo.num_pieces() {
+            return None;
+        }
+        let have = Bitfield::new(metainfo.num_pieces());
+        Some(Self { metainfo, storage, have })
+    }
+
+    // Reads verified content by its offset in the torrent, such as for
+    // playing a file while the rest downloads. A range that is not fully
+    // verified yet fails with `WouldBlock`.
+    pub fn read_range(&self, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+        let geometry = self.storage.layout().geometry();
+        if offset.checked_add(len as u64).is_none_or(|end| end > geometry.total_length()) {
+            return Err(out_of_range());
+        }
+        let piece_length = geometry.piece_length() as u64;
+        let mut data = Vec::with_capacity(len);
+        while data.len() < len {
+            let at = offset + data.len() as u64;
+            let piece = (at / piece_length) as u32;
+            if !self.have.get(piece as usize) {
+                return Err(io::Error::new(io::ErrorKind::WouldBlock, "range not verified yet"));
+            }
+            let begin = (at % piece_length) as u32;
+            let chunk = (len - data.len()).min((piece_length - begin as u64) as usize);
+            data.extend(self.storage.read(piece, begin, chunk)?);
+        }
+        Ok(data)
+    }
+
+    pub fn metainfo(&self) -> &Metainfo {
+        &self.metainfo
+    }
+
+    pub fn storage(&self) -> &S {
+        &self.storage
+    }
+
+    pub fn storage_mut(&mut self) -> &mut S {
+        &mut self.storage
+    }
+
+    pub fn have(&self) -> &Bitfield {
+        &self.have
+    }
+
+    pub fn mark_have(&mut self, piece: u32) {
+        self.have.set(piece as usize);
+    }
+
+    pub fn set_have(&mut self, have: Bitfield) {
+        if have.len() == self.have.len() {
+            self.have = have;
+        }
+    }
+
+    // Missing or short files simply fail the check.
+    pub fn verify_piece(&self, piece: u32) -> bool {
+        let Some(expected) = self.metainfo.piece_hash(piece) else {
+            return false;
+        };
+        self.storage
+            .verify_piece(piece, &expected)
+            .unwrap_or(false)
+    }
+
+    // Hashes every piece on disk and rebuilds what we have from scratch,
+    // for when resume data is missing or cannot be trusted.
+    pub fn recheck(&mut self) -> &Bitfield {
+        let mut have = Bitfield::new(self.metainfo.num_pieces());
+        for piece in self.verify_pieces(0..have.len() as u32) {
+            have.set(piece as usize);
+        }
+        self.have = have;
+        &self.have
+    }
+
+    // The pieces that check out, hashed on every core while this thread
+    // reads ahead of them, a piece per core at most. Pieces that can't be
+    // read fail without being hashed. The storage is only read, so a
+    // backend's own `verify_piece` is left to single pieces.
+    fn verify_pieces(&self, pieces: impl IntoIterator<Item = u32>) -> Vec<u32> {
+        let workers = thread::available_parallelism().map_or(1, usize::from);
+        let pool = HashPool::new(workers, workers);
+        let (mut pending, mut valid) = (0, Vec::new());
+        let mut collect = |result: HashResult| {
+            if result.valid {
+                valid.push(result.piece);
+            }
+        };
+        for piece in pieces {
+            let (Some(expected), Ok(data)) = (self.metainfo.piece_hash(piece), self.storage.read_piece(piece)) else {
+                continue;
+            };
+            pool.submit(HashJob { piece, data, expected });
+            pending += 1;
+            pool.results().for_each(|result| {
+                pending -= 1;
+                collect(result);
+            });
+        }
+        for _ in 0..pending {
+            pool.wait_result().into_iter().for_each(&mut collect);
+        }
+        valid.sort_unstable();
+        valid
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::bencode::Value;
+    use crate::hash::sha1;
+    use crate::merkle::{root, sha256, Hash, BLOCK_LEN};
+    use crate::metainfo::Metainfo;
+    use crate::storage::allocate::Allocation;
+    use crate::storage::journal::{self, Journal, JournalEntry};
+    use crate::storage::mmap::MmapStorage;
+    use crate::storage::resume::ResumeData;
+    use crate::storage::Storage;
+    use crate::torrent::Torrent;
+    use std::fs;
+
+    #[test]
+    fn test_recheck() {
+        let data = b"0123456789";
+        let pieces: Vec<u8> = data.chunks(4).flat_map(sha1).collect();
+        let info = Value::dict([
+            ("name", "file".into()),
+            ("length", 10.into()),
+            ("piece length", 4.into()),
+            ("pieces", pieces.into())
+        ]);
+        let metainfo = Metainfo::from_bytes(&Value::dict([("info", info)]).encode()).unwrap();
+        let root = std::env::temp_dir().join(format!("recheck-{}", std::process::id()));
+        let mut torrent = Torrent::new(metainfo, &root).unwrap();
+
+        assert_eq!(torrent.recheck().count_ones(), 0);
+
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("file"), b"0123XXXX89").unwrap();
+        assert_eq!(torrent.recheck().ones().collect::<Vec<_>>(), vec![0, 2]);
+
+        fs::write(root.join("file"), data).unwrap();
+        assert!(torrent.recheck().is_complete());
+        assert_eq!(torrent.read_range(3, 6).unwrap(), b"345678");
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_recheck_v2_only() {
+        let a: Vec<u8> = (0..40_000u32).map(|i| (i % 251) as u8).collect();
+        let b = vec![7; 100];
+        let leaves: Vec<Hash> = a.chunks(BLOCK_LEN as usize).map(sha256).collect();
+        let layer = [root(&leaves[..2], 0, 2), root(&leaves[2..], 0, 2)];
+        let (a_root, b_root) = (root(&layer, 1, 2), sha256(&b));
+        let file = |length: usize, root: &Hash| Value::dict([("", Value::dict([("length", (length as i64).into()), ("pieces root", root[..].into())]))]);
+        let info = Value::dict([
+            ("name", "album".into()),
+            ("piece length", (2 * BLOCK_LEN as i64).into()),
+            ("meta version", 2.into()),
+            ("file tree", Value::dict([("a", file(a.len(), &a_root)), ("b", file(b.len(), &b_root))]))
+        ]);
+        let layers = Value::Dict([(a_root.to_vec(), Value::from(&layer.concat()[..]))].into());
+        let metainfo = Metainfo::from_bytes(&Value::dict([("info", info), ("piece layers", layers)]).encode()).unwrap();
+        let root = std::env::temp_dir().join(format!("recheck-v2-{}", std::process::id()));
+        let mut torrent = Torrent::new(metainfo, &root).unwrap();
+
+        // The padding after `a` is a file of its own, as in a hybrid.
+        fs::create_dir_all(root.join("album/.pad")).unwrap();
+        fs::write(root.join("album/a"), &a).unwrap();
+        fs::write(root.join("album/.pad/25536"), vec![0; 25536]).unwrap();
+        fs::write(root.join("album/b"), [0; 100]).unwrap();
+        assert_eq!(torrent.recheck().ones().collect::<Vec<_>>(), vec![0, 1]);
+
+        fs::write(root.join("album/b"), &b).unwrap();
+        assert!(torrent.recheck().is_complete());
+        assert!(torrent.verify_piece(2));
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_resume() {
+        let data = b"0123456789ab";
+        let pieces: Vec<u8> = data.chunks(4).flat_map(sha1).collect();
+        let info = Value::dict([
+            ("name", "file".into()),
+            ("length", 12.into()),
+            ("piece length", 4.into()),
+            ("pieces", pieces.into())
+        ]);
+        let metainfo = Metainfo::from_bytes(&Value::dict([("info", info)]).encode()).unwrap();
+        let root = std::env::temp_dir().join(format!("resume-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("file"), b"0123XXXX89ab").unwrap();
+        let mut torrent = Torrent::new(metainfo.clone(), &root).unwrap();
+        torrent.recheck();
+
+        // Trusted pieces aren't hashed again, so a lie in the resume data
+        // shows through while the file is unchanged.
+        let mut pieces = torrent.have().clone();
+        pieces.set(1);
+        pieces.clear(2);
+        let resume = ResumeData::capture(metainfo.info_hash, torrent.storage(), &pieces);
+        let mut resumed = Torrent::new(metainfo.clone(), &root).unwrap();
+        assert_eq!(resumed.resume(&resume).ones().collect::<Vec<_>>(), vec![0, 1, 2]);
+
+        let other = ResumeData { info_hash: [0; 20], ..resume };
+        assert_eq!(resumed.resume(&other).ones().collect::<Vec<_>>(), vec![0, 2]);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_resume_from() {
+        let data = b"0123456789ab";
+        let pieces: Vec<u8> = data.chunks(4).flat_map(sha1).collect();
+        let info = Value::dict([
+            ("name", "file".into()),
+            ("length", 12.into()),
+            ("piece length", 4.into()),
+            ("pieces", pieces.into())
+        ]);
+        let metainfo = Metainfo::from_bytes(&Value::dict([("info", info)]).encode()).unwrap();
+        let root = std::env::temp_dir().join(format!("resume-from-{}", std::process::id()));
+        let resume_path = root.join("file.resume");
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("file"), b"0123XXXXXXXX").unwrap();
+        let mut torrent = Torrent::new(metainfo.clone(), &root).unwrap();
+        assert_eq!(torrent.resume_from(&resume_path).ones().collect::<Vec<_>>(), vec![0]);
+        ResumeData::capture(metainfo.info_hash, torrent.storage(), torrent.have()).save(&resume_path).unwrap();
+
+        // The file changed since, so only the journal vouches for piece 2,
+        // and is taken at its word just as the resume data would be.
+        fs::write(root.join("file"), b"01234567XXXX").unwrap();
+        let mut journal = Journal::open(journal::path_for(&resume_path)).unwrap();
+        journal.append(JournalEntry::Verified(2)).unwrap();
+        let mut resumed = Torrent::new(metainfo, &root).unwrap();
+        assert_eq!(resumed.resume_from(&resume_path).ones().collect::<Vec<_>>(), vec![0, 1, 2]);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_other_storage() {
+        let data = b"01234567";
+        let pieces: Vec<u8> = data.chunks(4).flat_map(sha1).collect();
+        let info = Value::dict([
+            ("name", "file".into()),
+            ("length", 8.into()),
+            ("piece length", 4.into()),
+            ("pieces", pieces.into())
+        ]);
+        let metainfo = Metainfo::from_bytes(&Value::dict([("info", info)]).encode()).unwrap();
+        let root = std::env::temp_dir().join(format!("torrent-mmap-{}", std::process::id()));
+        let storage = MmapStorage::open(&root, metainfo.layout().unwrap(), Allocation::Sparse).unwrap();
+        let mut torrent = Torrent::with_storage(metainfo, storage).unwrap();
+
+        torrent.storage_mut().write(1, 0, b"4567").unwrap();
+        assert_eq!(torrent.recheck().ones().collect::<Vec<_>>(), vec![1]);
+        assert_eq!(torrent.read_range(5, 3).unwrap(), b"567");
+        assert_eq!(torrent.read_range(2, 4).unwrap_err().kind(), std::io::ErrorKind::WouldBlock);
+        assert_eq!(torrent.storage().size(), 8);
+
+        drop(torrent);
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
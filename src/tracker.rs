@@ -0,0 +1,445 @@
+use crate::bencode::{self, Value};
+use crate::compact::decode_peer;
+#[cfg(feature = "tracker-udp")]
+use crate::hash::random_bytes;
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::lookup_host;
+#[cfg(feature = "tracker-http")]
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+#[cfg(feature = "tracker-http")]
+use tokio::net::TcpStream;
+#[cfg(feature = "tracker-udp")]
+use tokio::net::UdpSocket;
+use tokio::time;
+
+pub const ANNOUNCE_TIMEOUT: Duration = Duration::from_secs(15);
+// Even a tracker handing out hundreds of non-compact peers stays well under.
+#[cfg(feature = "tracker-http")]
+const MAX_RESPONSE_LEN: usize = 1 << 20;
+// The magic number every BEP 15 connect request starts with.
+#[cfg(feature = "tracker-udp")]
+const UDP_PROTOCOL_ID: u64 = 0x417_2710_1980;
+#[cfg(feature = "tracker-udp")]
+const UDP_CONNECT: u32 = 0;
+const UDP_ANNOUNCE: u32 = 1;
+const UDP_ERROR: u32 = 3;
+#[cfg(feature = "tracker-udp")]
+const UDP_ATTEMPTS: u32 = 3;
+#[cfg(feature = "tracker-udp")]
+const UDP_RETRY: Duration = Duration::from_secs(5);
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AnnounceEvent {
+    Started,
+    Stopped,
+    Completed
+}
+
+impl AnnounceEvent {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Started => "started",
+            Self::Stopped => "stopped",
+            Self::Completed => "completed"
+        }
+    }
+
+    fn udp_code(event: Option<Self>) -> u32 {
+        match event {
+            None => 0,
+            Some(Self::Completed) => 1,
+            Some(Self::Started) => 2,
+            Some(Self::Stopped) => 3
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    Http,
+    Udp
+}
+
+// A tracker's announce URL, split into what connecting to it needs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tracker {
+    pub protocol: Protocol,
+    pub host: String,
+    pub port: u16,
+    // The path and any query of an HTTP tracker; empty for UDP.
+    pub path: String
+}
+
+impl Tracker {
+    // `None` for anything but `http://` and `udp://`; there is no TLS here.
+    pub fn parse(url: &str) -> Option<Self> {
+        let (protocol, rest, default_port) = match url.split_once("://")? {
+            ("http", rest) => (Protocol::Http, rest, Some(80)),
+            // UDP trackers have no well-known port.
+            ("udp", rest) => (Protocol::Udp, rest, None),
+            _ => return None
+        };
+        let (authority, path) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) if !port.contains(']') => (host, port.parse().ok()?),
+            _ => (authority, default_port?)
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() {
+            return None;
+        }
+        let path = match (protocol, path) {
+            (Protocol::Http, "") => "/".to_string(),
+            (Protocol::Http, path) => path.to_string(),
+            (Protocol::Udp, _) => String::new()
+        };
+        Some(Self { protocol, host: host.to_string(), port, path })
+    }
+}
+
+// Everything an announce tells the tracker.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Announce {
+    pub info_hash: [u8; 20],
+    pub peer_id: [u8; 20],
+    pub port: u16,
+    pub uploaded: u64,
+    pub downloaded: u64,
+    pub left: u64,
+    pub event: Option<AnnounceEvent>,
+    // How many peers to ask for; the tracker's default if `None`.
+    pub numwant: Option<u32>
+}
+
+// Percent-encodes everything outside the unreserved set of RFC 3986, as
+// binary info hashes and peer ids need.
+fn percent_encode(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|&byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (byte as char).to_string(),
+            byte => format!("%{:02X}", byte)
+        })
+        .collect()
+}
+
+impl Announce {
+    pub fn new(info_hash: [u8; 20], peer_id: [u8; 20], port: u16) -> Self {
+        Self { info_hash, peer_id, port, uploaded: 0, downloaded: 0, left: 0, event: None, numwant: None }
+    }
+
+    // The query an HTTP tracker takes, appended to whatever `path` has.
+    pub fn http_path(&self, path: &str) -> String {
+        let mut query = format!(
+            "info_hash={}&peer_id={}&port={}&uploaded={}&downloaded={}&left={}&compact=1",
+            percent_encode(&self.info_hash),
+            percent_encode(&self.peer_id),
+            self.port,
+            self.uploaded,
+            self.downloaded,
+            self.left
+        );
+        if let Some(event) = self.event {
+            query.push_str(&format!("&event={}", event.name()));
+        }
+        if let Some(numwant) = self.numwant {
+            query.push_str(&format!("&numwant={}", numwant));
+        }
+        let separator = if path.contains('?') { '&' } else { '?' };
+        format!("{}{}{}", path, separator, query)
+    }
+
+    // BEP 15's announce request, sent with the connection id a connect
+    // request got.
+    pub fn udp_packet(&self, connection_id: u64, transaction: u32, key: u32) -> Vec<u8> {
+        let mut packet = Vec::with_capacity(98);
+        packet.extend_from_slice(&connection_id.to_be_bytes());
+        packet.extend_from_slice(&UDP_ANNOUNCE.to_be_bytes());
+        packet.extend_from_slice(&transaction.to_be_bytes());
+        packet.extend_from_slice(&self.info_hash);
+        packet.extend_from_slice(&self.peer_id);
+        packet.extend_from_slice(&self.downloaded.to_be_bytes());
+        packet.extend_from_slice(&self.left.to_be_bytes());
+        packet.extend_from_slice(&self.uploaded.to_be_bytes());
+        packet.extend_from_slice(&AnnounceEvent::udp_code(self.event).to_be_bytes());
+        // The address the tracker sees us from.
+        packet.extend_from_slice(&0u32.to_be_bytes());
+        packet.extend_from_slice(&key.to_be_bytes());
+        packet.extend_from_slice(&self.numwant.map_or(-1, |numwant| numwant.min(i32::MAX as u32) as i32).to_be_bytes());
+        packet.extend_from_slice(&self.port.to_be_bytes());
+        packet
+    }
+}
+
+// What a tracker answered, over either protocol. A tracker that refused
+// the announce has only `failure` set.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AnnounceResponse {
+    pub failure: Option<String>,
+    pub warning: Option<String>,
+    pub interval: Option<u32>,
+    pub min_interval: Option<u32>,
+    pub tracker_id: Option<String>,
+    // Seeders and leechers.
+    pub complete: Option<u32>,
+    pub incomplete: Option<u32>,
+    pub peers: Vec<SocketAddr>
+}
+
+fn u32_at(bytes: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(bytes.get(at..at + 4)?.try_into().ok()?))
+}
+
+// `peers` either compact (BEP 23) or as a list of dictionaries.
+fn decode_peers(value: &Value) -> Vec<SocketAddr> {
+    match value {
+        Value::Bytes(bytes) => bytes.chunks_exact(6).filter_map(decode_peer).collect(),
+        Value::List(peers) => peers
+            .iter()
+            .filter_map(|peer| {
+                let ip = peer.get("ip")?.as_str()?.parse().ok()?;
+                let port = u16::try_from(peer.get("port")?.as_int()?).ok()?;
+                Some(SocketAddr::new(ip, port))
+            })
+            .collect(),
+        _ => Vec::new()
+    }
+}
+
+impl AnnounceResponse {
+    // The bencoded body of an HTTP tracker's answer.
+    pub fn from_http(body: &[u8]) -> Option<Self> {
+        let value = bencode::decode(body)?;
+        value.as_dict()?;
+        let text = |key: &str| value.get(key).and_then(Value::as_str).map(str::to_string);
+        let number = |key: &str| value.get(key).and_then(Value::as_int).and_then(|int| u32::try_from(int).ok());
+        let mut peers = value.get("peers").map(decode_peers).unwrap_or_default();
+        if let Some(peers6) = value.get("peers6").and_then(Value::as_bytes) {
+            peers.extend(peers6.chunks_exact(18).filter_map(decode_peer));
+        }
+        Some(Self {
+            failure: text("failure reason"),
+            warning: text("warning message"),
+            interval: number("interval"),
+            min_interval: number("min interval"),
+            tracker_id: text("tracker id"),
+            complete: number("complete"),
+            incomplete: number("incomplete"),
+            peers
+        })
+    }
+
+    // A UDP tracker's announce or error packet. Peers are as long as the
+    // addresses of the family the tracker was reached over.
+    pub fn from_udp(packet: &[u8], transaction: u32, ipv6: bool) -> Option<Self> {
+        if u32_at(packet, 4)? != transaction {
+            return None;
+        }
+        match u32_at(packet, 0)? {
+            UDP_ANNOUNCE => {
+                let peer_len = if ipv6 { 18 } else { 6 };
+                Some(Self {
+                    interval: Some(u32_at(packet, 8)?),
+                    incomplete: Some(u32_at(packet, 12)?),
+                    complete: Some(u32_at(packet, 16)?),
+                    peers: packet[20..].chunks_exact(peer_len).filter_map(decode_peer).collect(),
+                    ..Self::default()
+                })
+            },
+            UDP_ERROR => Some(Self { failure: Some(String::from_utf8_lossy(&packet[8..]).into_owned()), ..Self::default() }),
+            _ => None
+        }
+    }
+}
+
+// The tracker's answer as it arrived, and decoded if it could be.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Announced {
+    pub addr: SocketAddr,
+    // The HTTP body, or the UDP announce packet.
+    pub raw: Vec<u8>,
+    pub response: Option<AnnounceResponse>
+}
+
+async fn resolve(tracker: &Tracker) -> io::Result<SocketAddr> {
+    lookup_host((tracker.host.as_str(), tracker.port))
+        .await?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{} has no address", tracker.host)))
+}
+
+#[cfg(feature = "tracker-http")]
+async fn announce_http(tracker: &Tracker, request: &Announce) -> io::Result<Announced> {
+    let addr = resolve(tracker).await?;
+    // HTTP/1.0, so the body is never chunked.
+    let get = format!(
+        "GET {} HTTP/1.0\r\nHost: {}\r\nUser-Agent: bittorrent-rs\r\nConnection: close\r\n\r\n",
+        request.http_path(&tracker.path),
+        tracker.host
+    );
+    let mut stream = TcpStream::connect(addr).await?;
+    stream.write_all(get.as_bytes()).await?;
+    let mut response = Vec::new();
+    (&mut stream).take(MAX_RESPONSE_LEN as u64).read_to_end(&mut response).await?;
+    let split = response.windows(4).position(|window| window == b"\r\n\r\n").ok_or_else(|| invalid("bad HTTP response"))?;
+    let head = String::from_utf8_lossy(&response[..split]);
+    let status = head.split_whitespace().nth(1).ok_or_else(|| invalid("bad HTTP response"))?;
+    if status != "200" {
+        return Err(io::Error::other(format!("tracker answered {}", head.lines().next().unwrap_or(status))));
+    }
+    let raw = response[split + 4..].to_vec();
+    Ok(Announced { addr, response: AnnounceResponse::from_http(&raw), raw })
+}
+
+// Sends `packet` until an answer for `transaction` arrives, up to
+// `UDP_ATTEMPTS` times.
+#[cfg(feature = "tracker-udp")]
+async fn exchange(socket: &UdpSocket, packet: &[u8], transaction: u32) -> io::Result<Vec<u8>> {
+    let mut buf = vec![0; 65536];
+    for _ in 0..UDP_ATTEMPTS {
+        socket.send(packet).await?;
+        let answer = time::timeout(UDP_RETRY, async {
+            loop {
+                let len = socket.recv(&mut buf).await?;
+                if u32_at(&buf[..len], 4) == Some(transaction) {
+                    return Ok::<_, io::Error>(buf[..len].to_vec());
+                }
+            }
+        });
+        if let Ok(answer) = answer.await {
+            return answer;
+        }
+    }
+    Err(io::Error::new(io::ErrorKind::TimedOut, "tracker did not answer"))
+}
+
+#[cfg(feature = "tracker-udp")]
+async fn announce_udp(tracker: &Tracker, request: &Announce) -> io::Result<Announced> {
+    let addr = resolve(tracker).await?;
+    let socket = UdpSocket::bind(if addr.is_ipv6() { "[::]:0" } else { "0.0.0.0:0" }).await?;
+    socket.connect(addr).await?;
+
+    let transaction = u32::from_be_bytes(random_bytes());
+    let mut connect = UDP_PROTOCOL_ID.to_be_bytes().to_vec();
+    connect.extend_from_slice(&UDP_CONNECT.to_be_bytes());
+    connect.extend_from_slice(&transaction.to_be_bytes());
+    let answer = exchange(&socket, &connect, transaction).await?;
+    let connection_id = match u32_at(&answer, 0) {
+        Some(UDP_CONNECT) if answer.len() >= 16 => u64::from_be_bytes(answer[8..16].try_into().unwrap()),
+        Some(UDP_ERROR) => return Err(io::Error::other(String::from_utf8_lossy(&answer[8..]).into_owned())),
+        _ => return Err(invalid("bad connect response"))
+    };
+
+    let transaction = u32::from_be_bytes(random_bytes());
+    let packet = request.udp_packet(connection_id, transaction, u32::from_be_bytes(random_bytes()));
+    let raw = exchange(&socket, &packet, transaction).await?;
+    Ok(Announced { addr, response: AnnounceResponse::from_udp(&raw, transaction, addr.is_ipv6()), raw })
+}
+
+// One announce, giving up after `ANNOUNCE_TIMEOUT`. Only failing to reach
+// the tracker is an error; a refusal comes back as a response.
+pub async fn announce(tracker: &Tracker, request: &Announce) -> io::Result<Announced> {
+    let announced = async {
+        match tracker.protocol {
+            #[cfg(feature = "tracker-http")]
+            Protocol::Http => announce_http(tracker, request).await,
+            #[cfg(feature = "tracker-udp")]
+            Protocol::Udp => announce_udp(tracker, request).await,
+            // Built without the other protocol.
+            #[allow(unreachable_patterns)]
+            _ => Err(io::Error::new(io::ErrorKind::Unsupported, "this build has no trackers of that protocol"))
+        }
+    };
+    time::timeout(ANNOUNCE_TIMEOUT, announced)
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "tracker timed out"))?
+}
+
+#[cfg(test)]
+mod test {
+    use crate::bencode::Value;
+    use crate::tracker::{Announce, AnnounceEvent, AnnounceResponse, Protocol, Tracker};
+
+    #[test]
+    fn test_parse() {
+        let tracker = Tracker::parse("http://tracker.example:8080/announce?key=1").unwrap();
+        assert_eq!((tracker.protocol, tracker.host.as_str(), tracker.port, tracker.path.as_str()), (Protocol::Http, "tracker.example", 8080, "/announce?key=1"));
+        assert_eq!(Tracker::parse("http://tracker.example").unwrap().port, 80);
+        let tracker = Tracker::parse("udp://[::1]:6969/announce").unwrap();
+        assert_eq!((tracker.protocol, tracker.host.as_str(), tracker.port), (Protocol::Udp, "::1", 6969));
+        assert_eq!(Tracker::parse("udp://tracker.example/announce"), None);
+        assert_eq!(Tracker::parse("https://tracker.example/announce"), None);
+    }
+
+    #[test]
+    fn test_query() {
+        let mut request = Announce::new([0xab; 20], *b"-BR0001-abcdefghijkl", 6881);
+        request.event = Some(AnnounceEvent::Started);
+        request.numwant = Some(50);
+        let path = request.http_path("/announce?key=1");
+        assert!(path.starts_with("/announce?key=1&info_hash=%AB%AB"));
+        assert!(path.contains("&peer_id=-BR0001-abcdefghijkl&port=6881&"));
+        assert!(path.ends_with("&compact=1&event=started&numwant=50"));
+    }
+
+    #[test]
+    fn test_responses() {
+        let body = Value::dict([
+            ("interval", 1800.into()),
+            ("complete", 3.into()),
+            ("peers", Value::from(&[10, 0, 0, 1, 0x1a, 0xe1][..])),
+            ("peers6", Value::from(&[0; 18][..]))
+        ]);
+        let response = AnnounceResponse::from_http(&body.encode()).unwrap();
+        assert_eq!((response.interval, response.complete, response.incomplete), (Some(1800), Some(3), None));
+        assert_eq!(response.peers, ["10.0.0.1:6881".parse().unwrap(), "[::]:0".parse().unwrap()]);
+
+        let peers = Value::List(vec![Value::dict([("ip", "10.0.0.2".into()), ("port", 51413.into())])]);
+        let body = Value::dict([("peers", peers), ("failure reason", "unregistered torrent".into())]);
+        let response = AnnounceResponse::from_http(&body.encode()).unwrap();
+        assert_eq!(response.failure.as_deref(), Some("unregistered torrent"));
+        assert_eq!(response.peers, ["10.0.0.2:51413".parse().unwrap()]);
+        assert_eq!(AnnounceResponse::from_http(b"not bencode"), None);
+
+        let mut packet = vec![0, 0, 0, 1, 0, 0, 0, 7, 0, 0, 7, 8, 0, 0, 0, 2, 0, 0, 0, 5];
+        packet.extend_from_slice(&[10, 0, 0, 1, 0x1a, 0xe1]);
+        let response = AnnounceResponse::from_udp(&packet, 7, false).unwrap();
+        assert_eq!((response.interval, response.incomplete, response.complete), (Some(1800), Some(2), Some(5)));
+        assert_eq!(response.peers, ["10.0.0.1:6881".parse().unwrap()]);
+        assert_eq!(AnnounceResponse::from_udp(&packet, 8, false), None);
+        let error = [&[0, 0, 0, 3, 0, 0, 0, 7][..], b"go away"].concat();
+        assert_eq!(AnnounceResponse::from_udp(&error, 7, false).unwrap().failure.as_deref(), Some("go away"));
+    }
+
+    #[cfg(feature = "tracker-http")]
+    #[tokio::test]
+    async fn test_announce_http() {
+        use crate::tracker::announce;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        use tokio::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let body = Value::dict([("interval", 900.into()), ("peers", Value::from(&[10, 0, 0, 1, 0x1a, 0xe1][..]))]).encode();
+        let served = body.clone();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = vec![0; 4096];
+            let len = stream.read(&mut request).await.unwrap();
+            assert!(request[..len].starts_with(b"GET /announce?info_hash="));
+            stream.write_all(b"HTTP/1.0 200 OK\r\nContent-Type: text/plain\r\n\r\n").await.unwrap();
+            stream.write_all(&served).await.unwrap();
+        });
+
+        let tracker = Tracker::parse(&format!("http://{}/announce", addr)).unwrap();
+        let announced = announce(&tracker, &Announce::new([1; 20], [2; 20], 6881)).await.unwrap();
+        assert_eq!((announced.addr, &announced.raw), (addr, &body));
+        assert_eq!(announced.response.unwrap().peers, ["10.0.0.1:6881".parse().unwrap()]);
+    }
+}
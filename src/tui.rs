@@ -0,0 +1,503 @@
+use crate::progress::bytes;
+use bittorrent_rs::bitfield::Bitfield;
+use bittorrent_rs::engine::{PeerInfo, Session, TorrentHandle, TorrentStats};
+use bittorrent_rs::storage::selection::{FileSelection, Priority};
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Modifier, Style};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Paragraph, Row, Table, TableState, Tabs};
+use ratatui::{DefaultTerminal, Frame};
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::sync::mpsc::UnboundedReceiver;
+
+// How long to wait for a key before redrawing with fresh stats.
+const REFRESH: Duration = Duration::from_millis(250);
+
+const HELP: &str = "↑↓ torrent  tab pane  space pause/resume  [ ] file  +/- priority  q quit";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Pane {
+    Peers,
+    Trackers,
+    Files,
+    Pieces
+}
+
+impl Pane {
+    const ALL: [Pane; 4] = [Pane::Peers, Pane::Trackers, Pane::Files, Pane::Pieces];
+
+    fn index(self) -> usize {
+        Self::ALL.iter().position(|&pane| pane == self).unwrap_or(0)
+    }
+
+    fn title(self) -> &'static str {
+        match self {
+            Pane::Peers => "Peers",
+            Pane::Trackers => "Trackers",
+            Pane::Files => "Files",
+            Pane::Pieces => "Pieces"
+        }
+    }
+
+    fn next(self) -> Self {
+        Self::ALL[(self.index() + 1) % Self::ALL.len()]
+    }
+
+    fn previous(self) -> Self {
+        Self::ALL[(self.index() + Self::ALL.len() - 1) % Self::ALL.len()]
+    }
+}
+
+fn raise(priority: Priority) -> Priority {
+    match priority {
+        Priority::Skip => Priority::Low,
+        Priority::Low => Priority::Normal,
+        Priority::Normal | Priority::High => Priority::High
+    }
+}
+
+fn lower(priority: Priority) -> Priority {
+    match priority {
+        Priority::High => Priority::Normal,
+        Priority::Normal => Priority::Low,
+        Priority::Low | Priority::Skip => Priority::Skip
+    }
+}
+
+fn priority_name(priority: Priority) -> &'static str {
+    match priority {
+        Priority::Skip => "skip",
+        Priority::Low => "low",
+        Priority::Normal => "normal",
+        Priority::High => "high"
+    }
+}
+
+// A row of the piece map per character: full where every piece it covers
+// is verified, half where some are.
+fn piece_map(have: &Bitfield, cells: usize) -> String {
+    let pieces = have.len();
+    let cells = cells.min(pieces);
+    (0..cells)
+        .map(|cell| {
+            let (start, end) = (cell * pieces / cells, (cell + 1) * pieces / cells);
+            match (start..end).filter(|&piece| have.get(piece)).count() {
+                0 => '·',
+                done if done == end - start => '█',
+                _ => '▒'
+            }
+        })
+        .collect()
+}
+
+// D: downloading from the peer, d: it chokes us. U: uploading to it, u:
+// we choke it.
+fn flags(peer: &PeerInfo) -> String {
+    let mut flags = String::new();
+    if peer.am_interested {
+        flags.push(if peer.peer_choking { 'd' } else { 'D' });
+    }
+    if peer.peer_interested {
+        flags.push(if peer.am_choking { 'u' } else { 'U' });
+    }
+    flags
+}
+
+struct FileRow {
+    path: PathBuf,
+    length: u64,
+    // Fraction of the file's pieces verified.
+    done: f64,
+    priority: Priority
+}
+
+// What the dashboard shows of a torrent, taken afresh every frame.
+struct Snapshot {
+    info_hash: [u8; 20],
+    name: String,
+    stats: TorrentStats,
+    paused: bool,
+    peers: Vec<PeerInfo>,
+    have: Bitfield,
+    files: Vec<FileRow>,
+    trackers: Vec<String>
+}
+
+impl Snapshot {
+    fn new(handle: &TorrentHandle, entry: &Entry) -> Self {
+        let have = handle.have();
+        let layout = handle.layout();
+        let files = layout
+            .files()
+            .iter()
+            .enumerate()
+            .map(|(index, file)| {
+                let pieces = layout.file_pieces(index);
+                let total = pieces.len();
+                let done = pieces.filter(|&piece| have.get(piece as usize)).count();
+                FileRow {
+                    path: file.path.clone(),
+                    length: file.length,
+                    done: if total == 0 { 1.0 } else { done as f64 / total as f64 },
+                    priority: entry.selection.priority(index)
+                }
+            })
+            .collect();
+        Self {
+            info_hash: handle.info_hash(),
+            name: handle.name().to_string(),
+            stats: handle.stats(),
+            paused: handle.is_paused(),
+            peers: handle.peers(),
+            have,
+            files,
+            trackers: entry.trackers.clone()
+        }
+    }
+
+    fn state(&self) -> &'static str {
+        match (self.paused, self.stats.queued, self.stats.is_complete()) {
+            (true, _, _) => "paused",
+            (_, true, _) => "queued",
+            (_, _, true) => "seeding",
+            _ => "active"
+        }
+    }
+}
+
+// What the dashboard keeps of a torrent between frames.
+struct Entry {
+    info_hash: [u8; 20],
+    trackers: Vec<String>,
+    selection: FileSelection
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Action {
+    Quit,
+    TogglePause([u8; 20]),
+    // The torrent's file selection changed.
+    Prioritise([u8; 20])
+}
+
+// Torrents in the order they were added, and where the user is among them.
+pub struct App {
+    entries: Vec<Entry>,
+    selected: usize,
+    pane: Pane,
+    // The file picked in the files pane.
+    file: usize
+}
+
+impl App {
+    pub fn new() -> Self {
+        Self { entries: Vec::new(), selected: 0, pane: Pane::Peers, file: 0 }
+    }
+
+    pub fn add(&mut self, handle: &TorrentHandle, trackers: Vec<String>) {
+        let selection = FileSelection::all(handle.layout().files().len());
+        self.entries.push(Entry { info_hash: handle.info_hash(), trackers, selection });
+    }
+
+    fn snapshot(&self, session: &Session) -> Vec<Snapshot> {
+        self.entries
+            .iter()
+            .filter_map(|entry| Some(Snapshot::new(session.torrent(&entry.info_hash)?, entry)))
+            .collect()
+    }
+
+    fn prioritise(&mut self, info_hash: [u8; 20], change: fn(Priority) -> Priority) -> Option<Action> {
+        let entry = self.entries.iter_mut().find(|entry| entry.info_hash == info_hash)?;
+        let priority = entry.selection.priority(self.file);
+        entry.selection.set_priority(self.file, change(priority));
+        Some(Action::Prioritise(info_hash))
+    }
+
+    fn key(&mut self, key: KeyEvent, torrents: &[Snapshot]) -> Option<Action> {
+        if key.kind != KeyEventKind::Press {
+            return None;
+        }
+        let selected = torrents.get(self.selected);
+        let files = selected.map_or(0, |torrent| torrent.files.len());
+        match (key.code, self.pane) {
+            (KeyCode::Char('q') | KeyCode::Esc, _) => return Some(Action::Quit),
+            (KeyCode::Char('c'), _) if key.modifiers.contains(KeyModifiers::CONTROL) => return Some(Action::Quit),
+            (KeyCode::Down | KeyCode::Char('j'), _) => {
+                self.selected = (self.selected + 1).min(torrents.len().saturating_sub(1));
+                self.file = 0;
+            },
+            (KeyCode::Up | KeyCode::Char('k'), _) => {
+                self.selected = self.selected.saturating_sub(1);
+                self.file = 0;
+            },
+            (KeyCode::Tab, _) => self.pane = self.pane.next(),
+            (KeyCode::BackTab, _) => self.pane = self.pane.previous(),
+            (KeyCode::Char(' ' | 'p'), _) => return selected.map(|torrent| Action::TogglePause(torrent.info_hash)),
+            (KeyCode::Char(']'), Pane::Files) => self.file = (self.file + 1).min(files.saturating_sub(1)),
+            (KeyCode::Char('['), Pane::Files) => self.file = self.file.saturating_sub(1),
+            (KeyCode::Char('+' | '='), Pane::Files) => return self.prioritise(selected?.info_hash, raise),
+            (KeyCode::Char('-'), Pane::Files) => return self.prioritise(selected?.info_hash, lower),
+            _ => {}
+        }
+        None
+    }
+
+    fn draw(&self, frame: &mut Frame, torrents: &[Snapshot]) {
+        let area = frame.area();
+        let list_height = (torrents.len() as u16 + 3).min(area.height / 2);
+        let [list, detail, help] =
+            Layout::vertical([Constraint::Length(list_height), Constraint::Min(0), Constraint::Length(1)]).areas(area);
+        self.draw_list(frame, list, torrents);
+        if let Some(torrent) = torrents.get(self.selected) {
+            self.draw_detail(frame, detail, torrent);
+        }
+        frame.render_widget(Paragraph::new(HELP), help);
+    }
+
+    fn draw_list(&self, frame: &mut Frame, area: Rect, torrents: &[Snapshot]) {
+        let rows = torrents.iter().map(|torrent| {
+            Row::new([
+                torrent.name.clone(),
+                format!("{:5.1}%", torrent.stats.percent()),
+                bytes(torrent.stats.bytes_total),
+                format!("{}/s", bytes(torrent.stats.download_rate)),
+                format!("{}/s", bytes(torrent.stats.upload_rate)),
+                torrent.stats.connected_peers.to_string(),
+                torrent.state().to_string()
+            ])
+        });
+        let widths = [
+            Constraint::Fill(1),
+            Constraint::Length(6),
+            Constraint::Length(10),
+            Constraint::Length(12),
+            Constraint::Length(12),
+            Constraint::Length(5),
+            Constraint::Length(7)
+        ];
+        let table = Table::new(rows, widths)
+            .header(Row::new(["Name", "Done", "Size", "Down", "Up", "Peers", "State"]).style(Style::new().add_modifier(Modifier::BOLD)))
+            .row_highlight_style(Style::new().add_modifier(Modifier::REVERSED))
+            .block(Block::bordered().title("Torrents"));
+        let mut state = TableState::default().with_selected((!torrents.is_empty()).then_some(self.selected));
+        frame.render_stateful_widget(table, area, &mut state);
+    }
+
+    fn draw_detail(&self, frame: &mut Frame, area: Rect, torrent: &Snapshot) {
+        let block = Block::bordered().title(torrent.name.as_str());
+        let inner = block.inner(area);
+        frame.render_widget(block, area);
+        let [tabs, body] = Layout::vertical([Constraint::Length(1), Constraint::Min(0)]).areas(inner);
+        let titles = Pane::ALL.iter().map(|pane| pane.title());
+        frame.render_widget(Tabs::new(titles).select(self.pane.index()).highlight_style(Style::new().add_modifier(Modifier::REVERSED)), tabs);
+        let bold = Style::new().add_modifier(Modifier::BOLD);
+        match self.pane {
+            Pane::Peers => {
+                let pieces = torrent.have.len().max(1);
+                let rows = torrent.peers.iter().map(|peer| {
+                    Row::new([
+                        peer.addr.to_string(),
+                        peer.client.clone().unwrap_or_default(),
+                        peer.country.clone().unwrap_or_default(),
+                        format!("{}/s", bytes(peer.download_rate)),
+                        format!("{}/s", bytes(peer.upload_rate)),
+                        format!("{}%", peer.pieces * 100 / pieces),
+                        flags(peer)
+                    ])
+                });
+                let widths = [
+                    Constraint::Length(22),
+                    Constraint::Fill(1),
+                    Constraint::Length(7),
+                    Constraint::Length(12),
+                    Constraint::Length(12),
+                    Constraint::Length(4),
+                    Constraint::Length(5)
+                ];
+                let header = Row::new(["Address", "Client", "Country", "Down", "Up", "Has", "Flags"]).style(bold);
+                frame.render_widget(Table::new(rows, widths).header(header), body);
+            },
+            Pane::Trackers => {
+                let mut lines: Vec<Line> = torrent.trackers.iter().map(|tracker| Line::from(tracker.as_str())).collect();
+                lines.push(Line::from("Trackers aren't announced to; peers come from the DHT and --peer.").style(Style::new().add_modifier(Modifier::DIM)));
+                frame.render_widget(Paragraph::new(lines), body);
+            },
+            Pane::Files => {
+                let rows = torrent.files.iter().enumerate().map(|(index, file)| {
+                    Row::new([
+                        index.to_string(),
+                        file.path.display().to_string(),
+                        bytes(file.length),
+                        format!("{:5.1}%", file.done * 100.0),
+                        priority_name(file.priority).to_string()
+                    ])
+                });
+                let widths =
+                    [Constraint::Length(4), Constraint::Fill(1), Constraint::Length(10), Constraint::Length(6), Constraint::Length(8)];
+                let table = Table::new(rows, widths)
+                    .header(Row::new(["#", "Path", "Size", "Done", "Priority"]).style(bold))
+                    .row_highlight_style(Style::new().add_modifier(Modifier::REVERSED));
+                frame.render_stateful_widget(table, body, &mut TableState::default().with_selected(Some(self.file)));
+            },
+            Pane::Pieces => {
+                let [summary, map] = Layout::vertical([Constraint::Length(1), Constraint::Min(0)]).areas(body);
+                let have = &torrent.have;
+                let summary_text = format!("{} of {} pieces", have.count_ones(), have.len());
+                frame.render_widget(Paragraph::new(summary_text), summary);
+                let width = map.width.max(1) as usize;
+                let cells: Vec<char> = piece_map(have, width * map.height as usize).chars().collect();
+                let lines: Vec<Line> = cells.chunks(width).map(|row| Line::from(row.iter().collect::<String>())).collect();
+                frame.render_widget(Paragraph::new(lines), map);
+            }
+        }
+    }
+
+    fn run(
+        &mut self,
+        terminal: &mut DefaultTerminal,
+        session: &mut Session,
+        found: &mut UnboundedReceiver<([u8; 20], Vec<SocketAddr>)>
+    ) -> io::Result<()> {
+        loop {
+            while let Ok((info_hash, peers)) = found.try_recv() {
+                if let Some(handle) = session.torrent(&info_hash) {
+                    peers.into_iter().for_each(|peer| handle.add_peer(peer));
+                }
+            }
+            let torrents = self.snapshot(session);
+            terminal.draw(|frame| self.draw(frame, &torrents))?;
+            if !event::poll(REFRESH)? {
+                continue;
+            }
+            let Event::Key(key) = event::read()? else {
+                continue;
+            };
+            match self.key(key, &torrents) {
+                Some(Action::Quit) => return Ok(()),
+                Some(Action::TogglePause(info_hash)) => {
+                    if session.torrent(&info_hash).is_some_and(TorrentHandle::is_paused) {
+                        session.resume(&info_hash);
+                    } else {
+                        session.pause(&info_hash);
+                    }
+                },
+                Some(Action::Prioritise(info_hash)) => {
+                    let entry = self.entries.iter().find(|entry| entry.info_hash == info_hash);
+                    if let (Some(handle), Some(entry)) = (session.torrent(&info_hash), entry) {
+                        handle.set_file_priorities(&entry.selection);
+                    }
+                },
+                None => {}
+            }
+        }
+    }
+}
+
+// Takes over the terminal until the user quits. Peers `found` for a
+// torrent, as by a DHT lookup, are handed to it as they come.
+pub fn run(session: &mut Session, mut app: App, mut found: UnboundedReceiver<([u8; 20], Vec<SocketAddr>)>) -> io::Result<()> {
+    let mut terminal = ratatui::init();
+    let result = app.run(&mut terminal, session, &mut found);
+    ratatui::restore();
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use crate::tui::{piece_map, Action, App, Entry, FileRow, Pane, Snapshot};
+    use bittorrent_rs::bitfield::Bitfield;
+    use bittorrent_rs::engine::TorrentStats;
+    use bittorrent_rs::storage::selection::{FileSelection, Priority};
+    use ratatui::backend::TestBackend;
+    use ratatui::crossterm::event::{KeyCode, KeyEvent};
+    use ratatui::Terminal;
+
+    fn snapshot(info_hash: [u8; 20], name: &str) -> Snapshot {
+        let files = ["a", "b"]
+            .into_iter()
+            .map(|path| FileRow { path: format!("{}/{}", name, path).into(), length: 1024, done: 0.5, priority: Priority::Normal })
+            .collect();
+        Snapshot {
+            info_hash,
+            name: name.to_string(),
+            stats: TorrentStats { bytes_done: 1024, bytes_total: 2048, ..TorrentStats::default() },
+            paused: false,
+            peers: Vec::new(),
+            have: Bitfield::new(4),
+            files,
+            trackers: vec!["http://tracker.example/announce".to_string()]
+        }
+    }
+
+    fn app(torrents: &[Snapshot]) -> App {
+        let mut app = App::new();
+        for torrent in torrents {
+            let entry = Entry { info_hash: torrent.info_hash, trackers: Vec::new(), selection: FileSelection::all(torrent.files.len()) };
+            app.entries.push(entry);
+        }
+        app
+    }
+
+    fn press(app: &mut App, code: KeyCode, torrents: &[Snapshot]) -> Option<Action> {
+        app.key(KeyEvent::from(code), torrents)
+    }
+
+    #[test]
+    fn test_piece_map() {
+        let mut have = Bitfield::new(8);
+        [0, 1, 2, 5].into_iter().for_each(|piece| {
+            have.set(piece);
+        });
+        assert_eq!(piece_map(&have, 4), "█▒▒·");
+        assert_eq!(piece_map(&have, 100), "███··█··");
+        assert_eq!(piece_map(&Bitfield::new(0), 10), "");
+    }
+
+    #[test]
+    fn test_keys() {
+        let torrents = [snapshot([1; 20], "one"), snapshot([2; 20], "two")];
+        let mut app = app(&torrents);
+        assert_eq!(press(&mut app, KeyCode::Char(' '), &torrents), Some(Action::TogglePause([1; 20])));
+        press(&mut app, KeyCode::Down, &torrents);
+        press(&mut app, KeyCode::Down, &torrents);
+        assert_eq!(app.selected, 1);
+
+        // Priorities only change from the files pane.
+        assert_eq!(press(&mut app, KeyCode::Char('-'), &torrents), None);
+        press(&mut app, KeyCode::Tab, &torrents);
+        press(&mut app, KeyCode::Tab, &torrents);
+        assert_eq!(app.pane, Pane::Files);
+        press(&mut app, KeyCode::Char(']'), &torrents);
+        press(&mut app, KeyCode::Char(']'), &torrents);
+        assert_eq!(press(&mut app, KeyCode::Char('-'), &torrents), Some(Action::Prioritise([2; 20])));
+        press(&mut app, KeyCode::Char('-'), &torrents);
+        press(&mut app, KeyCode::Char('-'), &torrents);
+        assert_eq!(app.entries[1].selection.priority(1), Priority::Skip);
+        press(&mut app, KeyCode::Char('+'), &torrents);
+        assert_eq!(app.entries[1].selection.priority(1), Priority::Low);
+        assert_eq!(app.entries[0].selection.priority(1), Priority::Normal);
+
+        press(&mut app, KeyCode::BackTab, &torrents);
+        assert_eq!(app.pane, Pane::Trackers);
+        assert_eq!(press(&mut app, KeyCode::Char('q'), &torrents), Some(Action::Quit));
+    }
+
+    #[test]
+    fn test_draw() {
+        let torrents = [snapshot([1; 20], "one"), snapshot([2; 20], "two")];
+        let mut app = app(&torrents);
+        app.pane = Pane::Files;
+        let mut terminal = Terminal::new(TestBackend::new(100, 20)).unwrap();
+        terminal.draw(|frame| app.draw(frame, &torrents)).unwrap();
+        let screen: String = terminal.backend().buffer().content().iter().map(|cell| cell.symbol()).collect();
+        assert!(screen.contains("one"));
+        assert!(screen.contains("two"));
+        assert!(screen.contains(" 50.0%"));
+        assert!(screen.contains("one/b"));
+        assert!(screen.contains("normal"));
+    }
+}
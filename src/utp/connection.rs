@@ -0,0 +1,723 @@
+use crate::utp::packet::{Packet, PacketType};
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::sync::OnceLock;
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+
+// Payload bytes to a packet, which fits in any path's MTU with the headers.
+pub const MAX_PAYLOAD: usize = 1400;
+// Written and not sent yet, and received and not read yet.
+pub const SEND_BUFFER: usize = 1 << 20;
+pub const RECV_BUFFER: usize = 1 << 20;
+// LEDBAT aims to add this much queueing delay to the path, in
+// microseconds, and grows the window by at most this many bytes a round
+// trip while under it.
+const TARGET_DELAY: f64 = 100_000.0;
+const MAX_WINDOW_GAIN: f64 = 3000.0;
+const MIN_WINDOW: f64 = MAX_PAYLOAD as f64;
+const INITIAL_WINDOW: f64 = (10 * MAX_PAYLOAD) as f64;
+const INITIAL_TIMEOUT: Duration = Duration::from_secs(1);
+const MIN_TIMEOUT: Duration = Duration::from_millis(500);
+const MAX_TIMEOUT: Duration = Duration::from_secs(30);
+// Timeouts in a row before the peer is given up on; a SYN gets fewer, so
+// a peer without uTP is soon found out.
+const MAX_TIMEOUTS: u32 = 5;
+const MAX_SYN_TIMEOUTS: u32 = 2;
+// Acks that repeat themselves, or packets acked selectively past one that
+// isn't, before it's taken for lost.
+const DUPLICATE_ACKS: u32 = 3;
+// An idle connection still says something now and then, for the NATs.
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(29);
+// How far ahead of what's in order a packet may be and still be kept.
+const MAX_OUT_OF_ORDER: u16 = 1024;
+// How long the lowest delay seen is remembered for.
+const BASE_DELAY_WINDOW: Duration = Duration::from_secs(60);
+
+// Microseconds on our clock, as sent in the header. Only differences count.
+fn micros(now: Instant) -> u32 {
+    static START: OnceLock<Instant> = OnceLock::new();
+    now.saturating_duration_since(*START.get_or_init(Instant::now)).as_micros() as u32
+}
+
+// Whether `a` comes after `b`, sequence numbers wrapping around.
+fn after(a: u16, b: u16) -> bool {
+    a != b && a.wrapping_sub(b) < 0x8000
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    SynSent,
+    Connected,
+    Closed
+}
+
+struct Sent {
+    packet: Packet,
+    sent_at: Instant,
+    transmissions: u32,
+    // Acked selectively, ahead of the packets before it.
+    acked: bool
+}
+
+// The lowest one-way delay seen lately, which the queueing delay is taken
+// from. The two clocks differ, so delays are only good for comparing. The
+// lowest of the last minute and the one before is kept, so a route that
+// changed is found out in time.
+struct BaseDelay {
+    current: Option<u32>,
+    previous: Option<u32>,
+    since: Instant
+}
+
+impl BaseDelay {
+    fn new(now: Instant) -> Self {
+        Self { current: None, previous: None, since: now }
+    }
+
+    fn lowest(a: Option<u32>, b: u32) -> u32 {
+        match a {
+            Some(a) if (a.wrapping_sub(b) as i32) < 0 => a,
+            _ => b
+        }
+    }
+
+    fn add(&mut self, delay: u32, now: Instant) {
+        if now.duration_since(self.since) >= BASE_DELAY_WINDOW {
+            self.previous = self.current.take();
+            self.since = now;
+        }
+        self.current = Some(Self::lowest(self.current, delay));
+    }
+
+    // How much more than the lowest this delay is.
+    fn queueing(&self, delay: u32) -> u32 {
+        let base = match (self.current, self.previous) {
+            (Some(current), previous) => Self::lowest(previous, current),
+            (None, Some(previous)) => previous,
+            (None, None) => delay
+        };
+        match delay.wrapping_sub(base) as i32 {
+            queueing if queueing > 0 => queueing as u32,
+            _ => 0
+        }
+    }
+}
+
+// One uTP connection's state (BEP 29), apart from any socket: packets go in
+// through `on_packet` and come out of `take_outgoing`, and the owner calls
+// `on_timeout` once `deadline` is up. The window is LEDBAT's, which backs
+// off as soon as the delay grows, before other traffic on the link suffers.
+pub struct Connection {
+    state: State,
+    // The id our packets carry; the socket sorts theirs out.
+    send_id: u16,
+    // The number of our next packet, and of their last one in order.
+    seq_nr: u16,
+    ack_nr: u16,
+    send_buffer: VecDeque<u8>,
+    in_flight: VecDeque<Sent>,
+    // Bytes sent and not yet acked, and what LEDBAT and the peer allow.
+    cur_window: usize,
+    max_window: f64,
+    peer_window: usize,
+    rtt: Option<(Duration, Duration)>,
+    rto: Duration,
+    timeout_at: Option<Instant>,
+    timeouts: u32,
+    duplicate_acks: u32,
+    base_delay: BaseDelay,
+    // How long their last packet took to get here, echoed in ours.
+    reply_delay: u32,
+    last_sent: Instant,
+    recv_buffer: VecDeque<u8>,
+    out_of_order: HashMap<u16, Vec<u8>>,
+    // Their FIN's number once it's seen, and whether everything up to it
+    // has been read.
+    fin_nr: Option<u16>,
+    eof: bool,
+    // Asked to close, the number of our FIN once sent, and whether it was
+    // acked. A released connection has nobody to read it any more.
+    closing: bool,
+    fin_sent: Option<u16>,
+    fin_acked: bool,
+    released: bool,
+    error: Option<io::ErrorKind>,
+    outgoing: Vec<Packet>,
+    read_waker: Option<Waker>,
+    write_waker: Option<Waker>
+}
+
+impl Connection {
+    fn new(send_id: u16, seq_nr: u16, ack_nr: u16, now: Instant) -> Self {
+        Self {
+            state: State::SynSent,
+            send_id,
+            seq_nr,
+            ack_nr,
+            send_buffer: VecDeque::new(),
+            in_flight: VecDeque::new(),
+            cur_window: 0,
+            max_window: INITIAL_WINDOW,
+            peer_window: RECV_BUFFER,
+            rtt: None,
+            rto: INITIAL_TIMEOUT,
+            timeout_at: None,
+            timeouts: 0,
+            duplicate_acks: 0,
+            base_delay: BaseDelay::new(now),
+            reply_delay: 0,
+            last_sent: now,
+            recv_buffer: VecDeque::new(),
+            out_of_order: HashMap::new(),
+            fin_nr: None,
+            eof: false,
+            closing: false,
+            fin_sent: None,
+            fin_acked: false,
+            released: false,
+            error: None,
+            outgoing: Vec::new(),
+            read_waker: None,
+            write_waker: None
+        }
+    }
+
+    // Sends the SYN, under `recv_id`; the peer answers to `recv_id + 1`.
+    pub fn connect(recv_id: u16, now: Instant) -> Self {
+        let mut connection = Self::new(recv_id.wrapping_add(1), 1, 0, now);
+        let mut syn = connection.packet(PacketType::Syn, now);
+        syn.connection_id = recv_id;
+        connection.send_numbered(syn, now);
+        connection
+    }
+
+    // Answers a SYN, starting our packets from `seq_nr`.
+    pub fn accept(syn: &Packet, seq_nr: u16, now: Instant) -> Self {
+        let mut connection = Self::new(syn.connection_id, seq_nr, syn.seq_nr, now);
+        connection.state = State::Connected;
+        connection.on_header(syn, now);
+        connection.send_state(now);
+        connection
+    }
+
+    // What's left for the peer: what it sent and we haven't read, and what's
+    // still to come.
+    fn window(&self) -> usize {
+        let buffered = self.recv_buffer.len() + self.out_of_order.values().map(Vec::len).sum::<usize>();
+        RECV_BUFFER.saturating_sub(buffered)
+    }
+
+    fn packet(&self, packet_type: PacketType, now: Instant) -> Packet {
+        let mut packet = Packet::new(packet_type, self.send_id, self.seq_nr, self.ack_nr);
+        packet.timestamp = micros(now);
+        packet.timestamp_difference = self.reply_delay;
+        packet.wnd_size = self.window() as u32;
+        packet
+    }
+
+    // Which of the packets past the next one in order we have.
+    fn selective_ack(&self) -> Option<Vec<u8>> {
+        if self.out_of_order.is_empty() {
+            return None;
+        }
+        let mut mask = vec![0; 4];
+        for bit in 0..32 {
+            if self.out_of_order.contains_key(&self.ack_nr.wrapping_add(2 + bit as u16)) {
+                mask[bit / 8] |= 1 << (bit % 8);
+            }
+        }
+        Some(mask)
+    }
+
+    fn send_state(&mut self, now: Instant) {
+        let mut state = self.packet(PacketType::State, now);
+        state.selective_ack = self.selective_ack();
+        self.outgoing.push(state);
+        self.last_sent = now;
+    }
+
+    // A packet that takes up a sequence number, kept until it's acked.
+    fn send_numbered(&mut self, packet: Packet, now: Instant) {
+        self.seq_nr = self.seq_nr.wrapping_add(1);
+        self.cur_window += packet.payload.len();
+        self.outgoing.push(packet.clone());
+        self.in_flight.push_back(Sent { packet, sent_at: now, transmissions: 1, acked: false });
+        self.timeout_at.get_or_insert(now + self.rto);
+        self.last_sent = now;
+    }
+
+    fn resend(&mut self, index: usize, now: Instant) {
+        let (ack_nr, reply_delay, window) = (self.ack_nr, self.reply_delay, self.window() as u32);
+        let sent = &mut self.in_flight[index];
+        sent.packet.ack_nr = ack_nr;
+        sent.packet.timestamp = micros(now);
+        sent.packet.timestamp_difference = reply_delay;
+        sent.packet.wnd_size = window;
+        sent.sent_at = now;
+        sent.transmissions += 1;
+        self.outgoing.push(sent.packet.clone());
+        self.last_sent = now;
+    }
+
+    // Sends what the windows allow of what's been written, then the FIN
+    // once everything before it is out. With nothing in flight one packet
+    // always goes, so a peer with its window shut is asked again.
+    fn flush(&mut self, now: Instant) {
+        if self.state != State::Connected {
+            return;
+        }
+        let window = (self.max_window as usize).min(self.peer_window);
+        while !self.send_buffer.is_empty() {
+            let len = self.send_buffer.len().min(MAX_PAYLOAD);
+            if self.cur_window > 0 && self.cur_window + len > window {
+                break;
+            }
+            let mut packet = self.packet(PacketType::Data, now);
+            packet.payload = self.send_buffer.drain(..len).collect();
+            self.send_numbered(packet, now);
+        }
+        if self.closing && self.send_buffer.is_empty() && self.fin_sent.is_none() {
+            self.fin_sent = Some(self.seq_nr);
+            let fin = self.packet(PacketType::Fin, now);
+            self.send_numbered(fin, now);
+        }
+        if self.send_buffer.len() < SEND_BUFFER {
+            self.wake_writer();
+        }
+    }
+
+    fn wake_reader(&mut self) {
+        if let Some(waker) = self.read_waker.take() {
+            waker.wake();
+        }
+    }
+
+    fn wake_writer(&mut self) {
+        if let Some(waker) = self.write_waker.take() {
+            waker.wake();
+        }
+    }
+
+    fn fail(&mut self, error: io::ErrorKind) {
+        self.state = State::Closed;
+        self.error = Some(error);
+        self.timeout_at = None;
+        self.wake_reader();
+        self.wake_writer();
+    }
+
+    fn on_header(&mut self, packet: &Packet, now: Instant) {
+        if packet.timestamp != 0 {
+            self.reply_delay = micros(now).wrapping_sub(packet.timestamp);
+        }
+        self.peer_window = packet.wnd_size as usize;
+        self.timeouts = 0;
+    }
+
+    pub fn on_packet(&mut self, packet: Packet, now: Instant) {
+        if self.state == State::Closed {
+            return;
+        }
+        if packet.packet_type == PacketType::Reset {
+            return self.fail(io::ErrorKind::ConnectionReset);
+        }
+        self.on_header(&packet, now);
+        match (self.state, packet.packet_type) {
+            // The SYN is acked with their first packet's number.
+            (State::SynSent, PacketType::State) => {
+                self.state = State::Connected;
+                self.ack_nr = packet.seq_nr.wrapping_sub(1);
+                self.wake_writer();
+            },
+            (State::SynSent, _) => return,
+            // Our answer to their SYN went missing.
+            (_, PacketType::Syn) => return self.send_state(now),
+            _ => {}
+        }
+        self.on_ack(&packet, now);
+        match packet.packet_type {
+            PacketType::Data => self.receive(packet.seq_nr, packet.payload, now),
+            PacketType::Fin => {
+                self.fin_nr.get_or_insert(packet.seq_nr);
+                self.receive(packet.seq_nr, Vec::new(), now);
+            },
+            _ => {}
+        }
+        self.flush(now);
+    }
+
+    fn on_ack(&mut self, packet: &Packet, now: Instant) {
+        let mut bytes_acked = 0;
+        let mut acked_any = false;
+        while let Some(sent) = self.in_flight.front() {
+            if after(sent.packet.seq_nr, packet.ack_nr) {
+                break;
+            }
+            let sent = self.in_flight.pop_front().unwrap();
+            acked_any = true;
+            if !sent.acked {
+                bytes_acked += sent.packet.payload.len();
+                self.cur_window -= sent.packet.payload.len();
+            }
+            // Only a packet sent once says how long the round trip is.
+            if sent.transmissions == 1 {
+                self.measure(now.duration_since(sent.sent_at));
+            }
+            if self.fin_sent == Some(sent.packet.seq_nr) {
+                self.fin_acked = true;
+            }
+        }
+
+        let selected = packet.selectively_acked();
+        for sent in self.in_flight.iter_mut().filter(|sent| !sent.acked && selected.contains(&sent.packet.seq_nr)) {
+            sent.acked = true;
+            bytes_acked += sent.packet.payload.len();
+            self.cur_window -= sent.packet.payload.len();
+        }
+
+        let mut lost = Vec::new();
+        if acked_any {
+            self.duplicate_acks = 0;
+            self.timeout_at = (!self.in_flight.is_empty()).then(|| now + self.rto);
+        } else if packet.packet_type == PacketType::State && !self.in_flight.is_empty() {
+            self.duplicate_acks += 1;
+            if self.duplicate_acks == DUPLICATE_ACKS {
+                lost.push(0);
+            }
+        }
+        // Once enough packets past one are acked, it's taken for lost, the
+        // first time it's sent; after that only a timeout sends it again.
+        let mut past = 0;
+        for (index, sent) in self.in_flight.iter().enumerate().rev() {
+            if sent.acked {
+                past += 1;
+            } else if past >= DUPLICATE_ACKS && sent.transmissions == 1 && !lost.contains(&index) {
+                lost.push(index);
+            }
+        }
+        if !lost.is_empty() {
+            self.max_window = (self.max_window / 2.0).max(MIN_WINDOW);
+            lost.into_iter().for_each(|index| self.resend(index, now));
+        }
+
+        if bytes_acked > 0 {
+            if packet.timestamp_difference != 0 {
+                self.base_delay.add(packet.timestamp_difference, now);
+                self.grow(bytes_acked, self.base_delay.queueing(packet.timestamp_difference));
+            }
+            self.wake_writer();
+        }
+    }
+
+    // LEDBAT: the window grows while the delay we add is under target and
+    // shrinks once it's over, in proportion to how far off it is.
+    fn grow(&mut self, bytes_acked: usize, queueing: u32) {
+        let off_target = ((TARGET_DELAY - queueing as f64) / TARGET_DELAY).max(-1.0);
+        let gain = MAX_WINDOW_GAIN * off_target * bytes_acked as f64 / self.max_window;
+        self.max_window = (self.max_window + gain).max(MIN_WINDOW);
+    }
+
+    fn measure(&mut self, sample: Duration) {
+        let (rtt, variance) = match self.rtt {
+            None => (sample, sample / 2),
+            Some((rtt, variance)) => {
+                let delta = rtt.abs_diff(sample);
+                (rtt - rtt / 8 + sample / 8, variance - variance / 4 + delta / 4)
+            }
+        };
+        self.rtt = Some((rtt, variance));
+        self.rto = (rtt + variance * 4).clamp(MIN_TIMEOUT, MAX_TIMEOUT);
+    }
+
+    // Everything up to their FIN is taken, and acked, as long as it fits.
+    fn receive(&mut self, seq_nr: u16, payload: Vec<u8>, now: Instant) {
+        let distance = seq_nr.wrapping_sub(self.ack_nr);
+        let past_fin = self.fin_nr.is_some_and(|fin_nr| after(seq_nr, fin_nr));
+        let fits = payload.len() <= self.window();
+        if (1..=MAX_OUT_OF_ORDER).contains(&distance) && !past_fin && fits {
+            self.out_of_order.insert(seq_nr, payload);
+            let mut arrived = false;
+            while let Some(payload) = self.out_of_order.remove(&self.ack_nr.wrapping_add(1)) {
+                self.ack_nr = self.ack_nr.wrapping_add(1);
+                self.recv_buffer.extend(payload);
+                arrived = true;
+            }
+            if self.fin_nr == Some(self.ack_nr) {
+                self.eof = true;
+            }
+            if arrived {
+                self.wake_reader();
+            }
+        }
+        self.send_state(now);
+    }
+
+    pub fn on_timeout(&mut self, now: Instant) {
+        if self.state == State::Closed {
+            return;
+        }
+        match self.timeout_at {
+            Some(timeout_at) if timeout_at <= now => {},
+            // Nothing in flight.
+            _ => {
+                if self.state == State::Connected && now >= self.last_sent + KEEPALIVE_INTERVAL {
+                    self.send_state(now);
+                }
+                return;
+            }
+        }
+        self.timeouts += 1;
+        let limit = match self.state {
+            State::SynSent => MAX_SYN_TIMEOUTS,
+            _ => MAX_TIMEOUTS
+        };
+        if self.timeouts > limit {
+            return self.fail(io::ErrorKind::TimedOut);
+        }
+        // Loss this bad starts the window over.
+        self.max_window = MIN_WINDOW;
+        self.rto = (self.rto * 2).min(MAX_TIMEOUT);
+        self.timeout_at = Some(now + self.rto);
+        if let Some(index) = self.in_flight.iter().position(|sent| !sent.acked) {
+            self.resend(index, now);
+        }
+    }
+
+    // When `on_timeout` is next due.
+    pub fn deadline(&self) -> Option<Instant> {
+        match self.state {
+            State::Closed => None,
+            _ => Some(self.timeout_at.unwrap_or(self.last_sent + KEEPALIVE_INTERVAL))
+        }
+    }
+
+    pub fn take_outgoing(&mut self) -> Vec<Packet> {
+        std::mem::take(&mut self.outgoing)
+    }
+
+    pub fn poll_connected(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match (self.state, self.error) {
+            (_, Some(error)) => Poll::Ready(Err(error.into())),
+            (State::SynSent, _) => {
+                self.write_waker = Some(cx.waker().clone());
+                Poll::Pending
+            },
+            _ => Poll::Ready(Ok(()))
+        }
+    }
+
+    // What was read, with the peer told once there's room again.
+    pub fn poll_read(&mut self, cx: &mut Context<'_>, buf: &mut [u8], now: Instant) -> Poll<io::Result<usize>> {
+        if self.recv_buffer.is_empty() {
+            return match self.error {
+                _ if self.eof => Poll::Ready(Ok(0)),
+                Some(error) => Poll::Ready(Err(error.into())),
+                None => {
+                    self.read_waker = Some(cx.waker().clone());
+                    Poll::Pending
+                }
+            };
+        }
+        let was_shut = self.window() < MAX_PAYLOAD;
+        let len = buf.len().min(self.recv_buffer.len());
+        for (to, from) in buf.iter_mut().zip(self.recv_buffer.drain(..len)) {
+            *to = from;
+        }
+        if was_shut && self.window() >= MAX_PAYLOAD && self.state == State::Connected {
+            self.send_state(now);
+        }
+        Poll::Ready(Ok(len))
+    }
+
+    pub fn poll_write(&mut self, cx: &mut Context<'_>, data: &[u8], now: Instant) -> Poll<io::Result<usize>> {
+        if let Some(error) = self.error {
+            return Poll::Ready(Err(error.into()));
+        }
+        if self.closing {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        let room = SEND_BUFFER.saturating_sub(self.send_buffer.len());
+        if self.state == State::SynSent || room == 0 {
+            self.write_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        let len = data.len().min(room);
+        self.send_buffer.extend(&data[..len]);
+        self.flush(now);
+        Poll::Ready(Ok(len))
+    }
+
+    // The FIN goes once everything written is sent.
+    pub fn close(&mut self, now: Instant) {
+        self.closing = true;
+        self.flush(now);
+    }
+
+    // Nobody will read or write it any more; it only has to close.
+    pub fn release(&mut self, now: Instant) {
+        self.released = true;
+        self.close(now);
+    }
+
+    // Whether the socket can forget it: it failed, or our FIN was acked and
+    // theirs arrived or nobody cares to wait for it.
+    pub fn is_done(&self) -> bool {
+        self.state == State::Closed
+            || (self.fin_acked && (self.eof || self.released))
+            || (self.released && self.state == State::SynSent)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::utp::connection::{Connection, MAX_PAYLOAD, MIN_WINDOW};
+    use crate::utp::packet::{Packet, PacketType};
+    use std::io;
+    use std::task::{Context, Poll, Waker};
+    use std::time::{Duration, Instant};
+
+    fn cx() -> Context<'static> {
+        Context::from_waker(Waker::noop())
+    }
+
+    fn read_all(connection: &mut Connection, now: Instant) -> Vec<u8> {
+        let mut data = Vec::new();
+        let mut buf = [0; 4096];
+        while let Poll::Ready(Ok(len @ 1..)) = connection.poll_read(&mut cx(), &mut buf, now) {
+            data.extend_from_slice(&buf[..len]);
+        }
+        data
+    }
+
+    // Hands each side's packets to the other until neither has any more,
+    // losing those `lose` picks.
+    fn exchange(a: &mut Connection, b: &mut Connection, now: Instant, mut lose: impl FnMut(&Packet) -> bool) {
+        loop {
+            let (to_b, to_a) = (a.take_outgoing(), b.take_outgoing());
+            if to_b.is_empty() && to_a.is_empty() {
+                return;
+            }
+            to_b.into_iter().filter(|packet| !lose(packet)).for_each(|packet| b.on_packet(packet, now));
+            to_a.into_iter().filter(|packet| !lose(packet)).for_each(|packet| a.on_packet(packet, now));
+        }
+    }
+
+    fn connected(now: Instant) -> (Connection, Connection) {
+        let mut a = Connection::connect(100, now);
+        let syn = a.take_outgoing().remove(0);
+        assert_eq!((syn.packet_type, syn.connection_id, syn.seq_nr), (PacketType::Syn, 100, 1));
+        let mut b = Connection::accept(&syn, 5000, now);
+        assert_eq!(b.send_id, 100);
+        exchange(&mut a, &mut b, now, |_| false);
+        assert!(a.poll_connected(&mut cx()).is_ready());
+        (a, b)
+    }
+
+    #[test]
+    fn test_transfer() {
+        let now = Instant::now();
+        let (mut a, mut b) = connected(now);
+        let data: Vec<u8> = (0..100_000u32).map(|i| i as u8).collect();
+        let mut written = 0;
+        let mut received = Vec::new();
+        while received.len() < data.len() {
+            if let Poll::Ready(Ok(len)) = a.poll_write(&mut cx(), &data[written..], now) {
+                written += len;
+            }
+            exchange(&mut a, &mut b, now, |_| false);
+            received.extend(read_all(&mut b, now));
+        }
+        assert_eq!(received, data);
+
+        // Closing both ways ends the stream for each.
+        a.close(now);
+        exchange(&mut a, &mut b, now, |_| false);
+        assert!(matches!(b.poll_read(&mut cx(), &mut [0; 10], now), Poll::Ready(Ok(0))));
+        assert!(!a.is_done());
+        b.close(now);
+        exchange(&mut a, &mut b, now, |_| false);
+        assert!(a.is_done() && b.is_done());
+        assert!(matches!(a.poll_write(&mut cx(), b"late", now), Poll::Ready(Err(_))));
+    }
+
+    #[test]
+    fn test_loss() {
+        let start = Instant::now();
+        let (mut a, mut b) = connected(start);
+        let data: Vec<u8> = (0..20 * MAX_PAYLOAD as u32).map(|i| (i % 253) as u8).collect();
+        assert!(a.poll_write(&mut cx(), &data, start).is_ready());
+
+        // Every third data packet is lost the first time round; the rest are
+        // acked selectively, which has the lost ones sent again.
+        let mut seen = Vec::new();
+        exchange(&mut a, &mut b, start, |packet| {
+            let first = packet.packet_type == PacketType::Data && !seen.contains(&packet.seq_nr);
+            seen.push(packet.seq_nr);
+            first && packet.seq_nr % 3 == 0
+        });
+        let mut received = read_all(&mut b, start);
+        // Whatever's left comes once it times out.
+        let mut now = start;
+        while received.len() < data.len() {
+            now += Duration::from_secs(2);
+            a.on_timeout(now);
+            exchange(&mut a, &mut b, now, |_| false);
+            received.extend(read_all(&mut b, now));
+            assert!(now < start + Duration::from_secs(60));
+        }
+        assert_eq!(received, data);
+    }
+
+    #[test]
+    fn test_timeouts() {
+        let mut now = Instant::now();
+        let mut a = Connection::connect(7, now);
+        a.take_outgoing();
+        for _ in 0..2 {
+            now = a.deadline().unwrap();
+            a.on_timeout(now);
+            assert_eq!(a.take_outgoing()[0].packet_type, PacketType::Syn);
+        }
+        now = a.deadline().unwrap();
+        a.on_timeout(now);
+        let Poll::Ready(Err(err)) = a.poll_connected(&mut cx()) else {
+            panic!("still connecting");
+        };
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert!(a.is_done());
+    }
+
+    #[test]
+    fn test_reset() {
+        let now = Instant::now();
+        let (mut a, _) = connected(now);
+        a.on_packet(Packet::new(PacketType::Reset, 100, 0, 0), now);
+        let Poll::Ready(Err(err)) = a.poll_read(&mut cx(), &mut [0; 10], now) else {
+            panic!("not reset");
+        };
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+    }
+
+    #[test]
+    fn test_window() {
+        let now = Instant::now();
+        let (mut a, _) = connected(now);
+        let window = a.max_window;
+        // Acked with no queueing, the window grows; with twice the target's
+        // worth it shrinks, but never below a packet.
+        a.grow(10_000, 0);
+        assert!(a.max_window > window);
+        for _ in 0..1000 {
+            a.grow(10_000, 200_000);
+        }
+        assert_eq!(a.max_window, MIN_WINDOW);
+
+        // A peer that can't take more is still sent a packet now and then,
+        // to find out when it can.
+        a.max_window = 100.0 * MIN_WINDOW;
+        a.peer_window = 0;
+        assert!(a.poll_write(&mut cx(), &[1; 10 * MAX_PAYLOAD], now).is_ready());
+        assert_eq!(a.take_outgoing().len(), 1);
+    }
+}
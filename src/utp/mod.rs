@@ -0,0 +1,310 @@
+mod connection;
+pub mod packet;
+
+use crate::hash::random_bytes;
+use crate::utp::connection::Connection;
+use crate::utp::packet::{Packet, PacketType};
+use std::collections::HashMap;
+use std::future::poll_fn;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, Weak};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{ToSocketAddrs, UdpSocket};
+use tokio::sync::{mpsc, Notify};
+use tokio::task::JoinHandle;
+use tokio::time;
+use tracing::debug;
+
+pub use connection::MAX_PAYLOAD;
+
+// Peers that connected and haven't been accepted yet; more are reset.
+const ACCEPT_BACKLOG: usize = 64;
+// A closed connection still answers stray packets for a while, such as a
+// FIN sent again because our ack of it went missing.
+const LINGER: Duration = Duration::from_secs(2);
+
+// A connection as the socket and its stream share it.
+struct Entry {
+    addr: SocketAddr,
+    connection: Mutex<Connection>,
+    // Told whenever `deadline` may have moved.
+    changed: Notify
+}
+
+struct Shared {
+    udp: Arc<UdpSocket>,
+    // By the peer and the id its packets carry.
+    connections: Mutex<HashMap<(SocketAddr, u16), Arc<Entry>>>,
+    incoming: mpsc::Sender<UtpStream>,
+    receiver: JoinHandle<()>
+}
+
+// Nobody is left to read the socket for.
+impl Drop for Shared {
+    fn drop(&mut self) {
+        self.receiver.abort();
+    }
+}
+
+impl Shared {
+    fn send(&self, addr: SocketAddr, packets: Vec<Packet>) {
+        // A packet the socket has no room for is as good as lost, and is
+        // sent again as one would be.
+        for packet in packets {
+            let _ = self.udp.try_send_to(&packet.encode(), addr);
+        }
+    }
+
+    // Runs `f` on the connection, then sends what it has to say.
+    fn with<T>(&self, entry: &Entry, f: impl FnOnce(&mut Connection) -> T) -> T {
+        let (value, outgoing) = {
+            let mut connection = entry.connection.lock().unwrap();
+            let value = f(&mut connection);
+            (value, connection.take_outgoing())
+        };
+        self.send(entry.addr, outgoing);
+        entry.changed.notify_one();
+        value
+    }
+
+    fn register(self: &Arc<Self>, key: (SocketAddr, u16), connection: Connection) -> Arc<Entry> {
+        let entry = Arc::new(Entry { addr: key.0, connection: Mutex::new(connection), changed: Notify::new() });
+        self.connections.lock().unwrap().insert(key, entry.clone());
+        let outgoing = entry.connection.lock().unwrap().take_outgoing();
+        self.send(key.0, outgoing);
+        tokio::spawn(drive(self.clone(), entry.clone(), key));
+        entry
+    }
+
+    fn on_packet(self: &Arc<Self>, packet: Packet, from: SocketAddr) {
+        let entry = self.connections.lock().unwrap().get(&(from, packet.connection_id)).cloned();
+        if let Some(entry) = entry {
+            return self.with(&entry, |connection| connection.on_packet(packet, Instant::now()));
+        }
+        match packet.packet_type {
+            PacketType::Syn => self.on_syn(packet, from),
+            PacketType::Reset => {},
+            _ => self.reset(&packet, from)
+        }
+    }
+
+    fn on_syn(self: &Arc<Self>, syn: Packet, from: SocketAddr) {
+        let key = (from, syn.connection_id.wrapping_add(1));
+        let existing = self.connections.lock().unwrap().get(&key).cloned();
+        if let Some(entry) = existing {
+            return self.with(&entry, |connection| connection.on_packet(syn, Instant::now()));
+        }
+        let Ok(permit) = self.incoming.try_reserve() else {
+            debug!(addr = %from, "refused uTP peer, too many waiting to be accepted");
+            return self.reset(&syn, from);
+        };
+        let seq_nr = u16::from_be_bytes(random_bytes());
+        let entry = self.register(key, Connection::accept(&syn, seq_nr, Instant::now()));
+        permit.send(UtpStream { shared: self.clone(), entry });
+    }
+
+    fn reset(&self, packet: &Packet, to: SocketAddr) {
+        let reset = Packet::new(PacketType::Reset, packet.connection_id, 0, packet.seq_nr);
+        self.send(to, vec![reset]);
+    }
+}
+
+// Times a connection out, and has the socket forget it once it's done.
+async fn drive(shared: Arc<Shared>, entry: Arc<Entry>, key: (SocketAddr, u16)) {
+    loop {
+        let (done, deadline) = {
+            let connection = entry.connection.lock().unwrap();
+            (connection.is_done(), connection.deadline())
+        };
+        if done {
+            break;
+        }
+        tokio::select! {
+            _ = time::sleep_until(deadline.unwrap_or_else(Instant::now).into()) => {
+                shared.with(&entry, |connection| connection.on_timeout(Instant::now()));
+            },
+            _ = entry.changed.notified() => {}
+        }
+    }
+    time::sleep(LINGER).await;
+    shared.connections.lock().unwrap().remove(&key);
+}
+
+// Reads the socket for as long as anything still uses it.
+async fn receive(udp: Arc<UdpSocket>, shared: Weak<Shared>) {
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        // An ICMP error from some peer, on some systems.
+        let Ok((len, from)) = udp.recv_from(&mut buf).await else {
+            continue;
+        };
+        let Some(shared) = shared.upgrade() else {
+            return;
+        };
+        if let Some(packet) = Packet::decode(&buf[..len]) {
+            shared.on_packet(packet, from);
+        }
+    }
+}
+
+// uTP (BEP 29): reliable, ordered streams over UDP that back off as soon as
+// they start to add delay, so a client can upload without slowing down
+// everything else on the link. One socket carries any number of
+// connections, both ways.
+#[derive(Clone)]
+pub struct UtpSocket {
+    shared: Arc<Shared>,
+    accepted: Arc<tokio::sync::Mutex<mpsc::Receiver<UtpStream>>>
+}
+
+impl UtpSocket {
+    pub async fn bind(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        let udp = Arc::new(UdpSocket::bind(addr).await?);
+        let (incoming, accepted) = mpsc::channel(ACCEPT_BACKLOG);
+        let shared = Arc::new_cyclic(|weak: &Weak<Shared>| Shared {
+            udp: udp.clone(),
+            connections: Mutex::new(HashMap::new()),
+            incoming,
+            receiver: tokio::spawn(receive(udp.clone(), weak.clone()))
+        });
+        Ok(Self { shared, accepted: Arc::new(tokio::sync::Mutex::new(accepted)) })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.shared.udp.local_addr()
+    }
+
+    pub async fn connect(&self, addr: SocketAddr) -> io::Result<UtpStream> {
+        let recv_id = {
+            let connections = self.shared.connections.lock().unwrap();
+            loop {
+                let recv_id = u16::from_be_bytes(random_bytes());
+                if !connections.contains_key(&(addr, recv_id)) {
+                    break recv_id;
+                }
+            }
+        };
+        // Dropped before it connects, the stream takes the connection with it.
+        let stream = UtpStream { entry: self.shared.register((addr, recv_id), Connection::connect(recv_id, Instant::now())), shared: self.shared.clone() };
+        poll_fn(|cx| stream.entry.connection.lock().unwrap().poll_connected(cx)).await?;
+        Ok(stream)
+    }
+
+    pub async fn accept(&self) -> io::Result<(UtpStream, SocketAddr)> {
+        let stream = self.accepted.lock().await.recv().await.ok_or_else(|| io::Error::from(io::ErrorKind::NotConnected))?;
+        let addr = stream.peer_addr();
+        Ok((stream, addr))
+    }
+}
+
+// One connection, read and written like a `TcpStream`. Shutting it down
+// sends a FIN once everything written has gone; dropping it does the same.
+pub struct UtpStream {
+    shared: Arc<Shared>,
+    entry: Arc<Entry>
+}
+
+impl UtpStream {
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.entry.addr
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.shared.udp.local_addr()
+    }
+}
+
+impl AsyncRead for UtpStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let read = self.shared.with(&self.entry, |connection| connection.poll_read(cx, buf.initialize_unfilled(), Instant::now()));
+        read.map_ok(|len| buf.advance(len))
+    }
+}
+
+impl AsyncWrite for UtpStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, data: &[u8]) -> Poll<io::Result<usize>> {
+        self.shared.with(&self.entry, |connection| connection.poll_write(cx, data, Instant::now()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.shared.with(&self.entry, |connection| connection.close(Instant::now()));
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for UtpStream {
+    fn drop(&mut self) {
+        self.shared.with(&self.entry, |connection| connection.release(Instant::now()));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::utp::packet::{Packet, PacketType};
+    use crate::utp::{UtpSocket, MAX_PAYLOAD};
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::UdpSocket;
+    use tokio::time;
+
+    #[tokio::test]
+    async fn test_stream() {
+        let server = UtpSocket::bind("127.0.0.1:0").await.unwrap();
+        let client = UtpSocket::bind("127.0.0.1:0").await.unwrap();
+        let data: Vec<u8> = (0..200 * MAX_PAYLOAD as u32).map(|i| (i % 251) as u8).collect();
+
+        let addr = server.local_addr().unwrap();
+        let echo = tokio::spawn(async move {
+            let (mut stream, from) = server.accept().await.unwrap();
+            assert_eq!(from.port(), stream.peer_addr().port());
+            let mut received = Vec::new();
+            stream.read_to_end(&mut received).await.unwrap();
+            stream.write_all(&received).await.unwrap();
+            stream.shutdown().await.unwrap();
+        });
+
+        let mut stream = client.connect(addr).await.unwrap();
+        let (mut reader, mut writer) = tokio::io::split(&mut stream);
+        let write = async {
+            writer.write_all(&data).await.unwrap();
+            writer.shutdown().await.unwrap();
+        };
+        let mut echoed = Vec::new();
+        let read = reader.read_to_end(&mut echoed);
+        time::timeout(Duration::from_secs(10), async { tokio::join!(write, read) }).await.unwrap().1.unwrap();
+        assert_eq!(echoed, data);
+        echo.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_no_peer() {
+        // A packet for no connection is answered with a reset.
+        let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let client = UtpSocket::bind("127.0.0.1:0").await.unwrap();
+        let data = Packet::new(PacketType::Data, 999, 10, 20).encode();
+        silent.send_to(&data, client.local_addr().unwrap()).await.unwrap();
+        let mut buf = [0; 100];
+        let len = time::timeout(Duration::from_secs(5), silent.recv(&mut buf)).await.unwrap().unwrap();
+        let reset = Packet::decode(&buf[..len]).unwrap();
+        assert_eq!((reset.packet_type, reset.connection_id, reset.ack_nr), (PacketType::Reset, 999, 10));
+    }
+
+    #[tokio::test]
+    async fn test_drop_closes() {
+        let server = UtpSocket::bind("127.0.0.1:0").await.unwrap();
+        let client = UtpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut stream = client.connect(server.local_addr().unwrap()).await.unwrap();
+        let (accepted, _) = server.accept().await.unwrap();
+        drop(accepted);
+        let read = time::timeout(Duration::from_secs(5), stream.read(&mut [0; 10])).await.unwrap();
+        assert_eq!(read.unwrap(), 0);
+    }
+}
@@ -0,0 +1,174 @@
+pub const HEADER_LEN: usize = 20;
+pub const VERSION: u8 = 1;
+// The one extension we know: which packets past `ack_nr + 1` have arrived.
+const SELECTIVE_ACK: u8 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PacketType {
+    Data,
+    Fin,
+    State,
+    Reset,
+    Syn
+}
+
+impl PacketType {
+    fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(Self::Data),
+            1 => Some(Self::Fin),
+            2 => Some(Self::State),
+            3 => Some(Self::Reset),
+            4 => Some(Self::Syn),
+            _ => None
+        }
+    }
+
+    fn to_u8(self) -> u8 {
+        match self {
+            Self::Data => 0,
+            Self::Fin => 1,
+            Self::State => 2,
+            Self::Reset => 3,
+            Self::Syn => 4
+        }
+    }
+}
+
+// A uTP packet (BEP 29): the 20 byte header, any selective ack, and the
+// payload. Timestamps are in microseconds on the sender's clock.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Packet {
+    pub packet_type: PacketType,
+    pub connection_id: u16,
+    pub timestamp: u32,
+    pub timestamp_difference: u32,
+    pub wnd_size: u32,
+    pub seq_nr: u16,
+    pub ack_nr: u16,
+    // Bit `i` set says packet `ack_nr + 2 + i` arrived.
+    pub selective_ack: Option<Vec<u8>>,
+    pub payload: Vec<u8>
+}
+
+impl Packet {
+    pub fn new(packet_type: PacketType, connection_id: u16, seq_nr: u16, ack_nr: u16) -> Self {
+        Self {
+            packet_type,
+            connection_id,
+            timestamp: 0,
+            timestamp_difference: 0,
+            wnd_size: 0,
+            seq_nr,
+            ack_nr,
+            selective_ack: None,
+            payload: Vec::new()
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_LEN + self.payload.len() + 6);
+        bytes.push(self.packet_type.to_u8() << 4 | VERSION);
+        bytes.push(if self.selective_ack.is_some() { SELECTIVE_ACK } else { 0 });
+        bytes.extend_from_slice(&self.connection_id.to_be_bytes());
+        bytes.extend_from_slice(&self.timestamp.to_be_bytes());
+        bytes.extend_from_slice(&self.timestamp_difference.to_be_bytes());
+        bytes.extend_from_slice(&self.wnd_size.to_be_bytes());
+        bytes.extend_from_slice(&self.seq_nr.to_be_bytes());
+        bytes.extend_from_slice(&self.ack_nr.to_be_bytes());
+        if let Some(mask) = &self.selective_ack {
+            bytes.push(0);
+            bytes.push(mask.len() as u8);
+            bytes.extend_from_slice(mask);
+        }
+        bytes.extend_from_slice(&self.payload);
+        bytes
+    }
+
+    // Extensions we don't know are skipped over.
+    pub fn decode(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < HEADER_LEN || bytes[0] & 0x0f != VERSION {
+            return None;
+        }
+        let u16_at = |at: usize| u16::from_be_bytes([bytes[at], bytes[at + 1]]);
+        let u32_at = |at: usize| u32::from_be_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]]);
+        let mut packet = Self {
+            packet_type: PacketType::from_u8(bytes[0] >> 4)?,
+            connection_id: u16_at(2),
+            timestamp: u32_at(4),
+            timestamp_difference: u32_at(8),
+            wnd_size: u32_at(12),
+            seq_nr: u16_at(16),
+            ack_nr: u16_at(18),
+            selective_ack: None,
+            payload: Vec::new()
+        };
+        let (mut extension, mut at) = (bytes[1], HEADER_LEN);
+        while extension != 0 {
+            let next = *bytes.get(at)?;
+            let len = *bytes.get(at + 1)? as usize;
+            let data = bytes.get(at + 2..at + 2 + len)?;
+            if extension == SELECTIVE_ACK && len > 0 && len.is_multiple_of(4) {
+                packet.selective_ack = Some(data.to_vec());
+            }
+            (extension, at) = (next, at + 2 + len);
+        }
+        packet.payload = bytes[at..].to_vec();
+        Some(packet)
+    }
+
+    // The packets the selective ack says arrived.
+    pub fn selectively_acked(&self) -> Vec<u16> {
+        let Some(mask) = &self.selective_ack else {
+            return Vec::new();
+        };
+        (0..mask.len() * 8)
+            .filter(|&bit| mask[bit / 8] & (1 << (bit % 8)) != 0)
+            .map(|bit| self.ack_nr.wrapping_add(2 + bit as u16))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::utp::packet::{Packet, PacketType};
+
+    #[test]
+    fn test_round_trip() {
+        let mut packet = Packet::new(PacketType::Data, 1234, 10, 7);
+        packet.timestamp = 0xdeadbeef;
+        packet.timestamp_difference = 42;
+        packet.wnd_size = 1 << 20;
+        packet.payload = b"hello".to_vec();
+        let bytes = packet.encode();
+        assert_eq!(bytes.len(), 25);
+        assert_eq!(bytes[0], 0x01);
+        assert_eq!(Packet::decode(&bytes), Some(packet.clone()));
+
+        packet.selective_ack = Some(vec![0b0000_0101, 0, 0, 0b1000_0000]);
+        let decoded = Packet::decode(&packet.encode()).unwrap();
+        assert_eq!(decoded, packet);
+        assert_eq!(decoded.selectively_acked(), vec![9, 11, 40]);
+    }
+
+    #[test]
+    fn test_decode_skips_unknown_extensions() {
+        let mut bytes = Packet::new(PacketType::State, 1, 2, 3).encode();
+        bytes[1] = 7;
+        bytes.extend_from_slice(&[0, 2, 0xaa, 0xbb]);
+        bytes.extend_from_slice(b"data");
+        let packet = Packet::decode(&bytes).unwrap();
+        assert_eq!((packet.selective_ack, packet.payload), (None, b"data".to_vec()));
+
+        assert_eq!(Packet::decode(&bytes[..19]), None);
+        bytes[0] = 0x02;
+        assert_eq!(Packet::decode(&bytes), None);
+        bytes[0] = 0x51;
+        assert_eq!(Packet::decode(&bytes), None);
+        // An extension running past the end.
+        let mut bytes = Packet::new(PacketType::State, 1, 2, 3).encode();
+        bytes[1] = 1;
+        bytes.extend_from_slice(&[0, 4, 0xff]);
+        assert_eq!(Packet::decode(&bytes), None);
+    }
+}
@@ -0,0 +1,156 @@
+// JavaScript bindings to the parsing, built with the `wasm` feature:
+//
+//     cargo rustc --lib --release --target wasm32-unknown-unknown --no-default-features --features wasm --crate-type cdylib
+//     wasm-bindgen target/wasm32-unknown-unknown/release/bittorrent_rs.wasm --out-dir pkg
+//
+// Bencode goes to and from JSON text, as the `decode` and `encode`
+// commands print and read it; torrents and magnet links become objects
+// with a getter per field.
+
+use crate::bencode::{self, Value};
+use crate::hash::hex;
+use crate::magnet::Magnet;
+use crate::metainfo::Metainfo;
+use wasm_bindgen::prelude::*;
+
+// Bencoded bytes as JSON text, with binary strings as hex.
+#[wasm_bindgen(js_name = decodeBencode)]
+pub fn decode_bencode(bytes: &[u8]) -> Result<String, JsError> {
+    let value = bencode::decode(bytes).ok_or_else(|| JsError::new("not a bencoded value"))?;
+    Ok(value.to_json().to_string())
+}
+
+// JSON text as bencoded bytes. Bencode has no booleans, nulls or fractions.
+#[wasm_bindgen(js_name = encodeBencode)]
+pub fn encode_bencode(json: &str) -> Result<Vec<u8>, JsError> {
+    let json: serde_json::Value = serde_json::from_str(json)?;
+    let value = Value::from_json(&json).ok_or_else(|| JsError::new("JSON with no bencode equivalent"))?;
+    Ok(value.encode())
+}
+
+#[wasm_bindgen]
+pub struct TorrentFile {
+    metainfo: Metainfo
+}
+
+#[wasm_bindgen]
+impl TorrentFile {
+    #[wasm_bindgen(constructor)]
+    pub fn new(bytes: &[u8]) -> Result<TorrentFile, JsError> {
+        let metainfo = Metainfo::from_bytes(bytes).ok_or_else(|| JsError::new("not a valid torrent"))?;
+        Ok(Self { metainfo })
+    }
+
+    #[wasm_bindgen(getter, js_name = infoHash)]
+    pub fn info_hash(&self) -> String {
+        hex(&self.metainfo.info_hash)
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn name(&self) -> String {
+        self.metainfo.name.clone()
+    }
+
+    #[wasm_bindgen(getter, js_name = pieceLength)]
+    pub fn piece_length(&self) -> u32 {
+        self.metainfo.piece_length
+    }
+
+    #[wasm_bindgen(getter, js_name = pieceCount)]
+    pub fn piece_count(&self) -> usize {
+        self.metainfo.num_pieces()
+    }
+
+    // A number rather than a BigInt: exact up to 8 PiB.
+    #[wasm_bindgen(getter, js_name = totalLength)]
+    pub fn total_length(&self) -> f64 {
+        self.metainfo.total_length() as f64
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn private(&self) -> bool {
+        self.metainfo.private
+    }
+
+    // `announce`, then the announce-list's, without repeats.
+    #[wasm_bindgen(getter)]
+    pub fn trackers(&self) -> Vec<String> {
+        let mut trackers: Vec<String> = self.metainfo.announce.iter().cloned().collect();
+        for tracker in self.metainfo.announce_list.iter().flatten() {
+            if !trackers.contains(tracker) {
+                trackers.push(tracker.clone());
+            }
+        }
+        trackers
+    }
+
+    // JSON text: an array of `{ "path", "length" }`.
+    #[wasm_bindgen(getter)]
+    pub fn files(&self) -> String {
+        let files: Vec<_> = self.metainfo
+            .files
+            .iter()
+            .map(|file| serde_json::json!({ "path": file.path.to_string_lossy(), "length": file.length }))
+            .collect();
+        serde_json::Value::from(files).to_string()
+    }
+}
+
+#[wasm_bindgen]
+pub struct MagnetLink {
+    magnet: Magnet
+}
+
+#[wasm_bindgen]
+impl MagnetLink {
+    #[wasm_bindgen(constructor)]
+    pub fn new(link: &str) -> Result<MagnetLink, JsError> {
+        let magnet = Magnet::parse(link).ok_or_else(|| JsError::new("not a valid magnet link"))?;
+        Ok(Self { magnet })
+    }
+
+    #[wasm_bindgen(getter, js_name = infoHash)]
+    pub fn info_hash(&self) -> String {
+        hex(&self.magnet.info_hash)
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn name(&self) -> Option<String> {
+        self.magnet.name.clone()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn trackers(&self) -> Vec<String> {
+        self.magnet.trackers.clone()
+    }
+
+    // `x.pe` peers, as `host:port`.
+    #[wasm_bindgen(getter)]
+    pub fn peers(&self) -> Vec<String> {
+        self.magnet.peers.iter().map(ToString::to_string).collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::bencode::Value;
+    use crate::wasm::{decode_bencode, encode_bencode, MagnetLink, TorrentFile};
+
+    // Only what succeeds: building a `JsError` needs a JavaScript host.
+    #[test]
+    fn test_bindings() {
+        assert_eq!(decode_bencode(b"d3:cowl3:mooi1eee").ok(), Some(r#"{"cow":["moo",1]}"#.to_string()));
+        assert_eq!(encode_bencode(r#"{"cow":["moo",1]}"#).ok(), Some(b"d3:cowl3:mooi1eee".to_vec()));
+
+        let info = Value::dict([("name", "a.iso".into()), ("length", 4.into()), ("piece length", 4.into()), ("pieces", Value::from(&[0; 20][..]))]);
+        let file = Value::dict([("announce", "http://a".into()), ("info", info)]).encode();
+        let torrent = TorrentFile::new(&file).ok().unwrap();
+        assert_eq!((torrent.name(), torrent.piece_count(), torrent.total_length()), ("a.iso".to_string(), 1, 4.0));
+        assert_eq!(torrent.trackers(), ["http://a"]);
+        assert_eq!(torrent.files(), r#"[{"length":4,"path":"a.iso"}]"#);
+
+        let magnet = MagnetLink::new("magnet:?xt=urn:btih:c12fe1c06bba254a9dc9f519b335aa7c1367a88a&dn=a&tr=udp%3A%2F%2Fb%3A1").ok().unwrap();
+        assert_eq!(magnet.info_hash(), "c12fe1c06bba254a9dc9f519b335aa7c1367a88a");
+        assert_eq!((magnet.name(), magnet.trackers()), (Some("a".to_string()), vec!["udp://b:1".to_string()]));
+    }
+}